@@ -0,0 +1,137 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use once_cell::sync::OnceCell;
+
+use crate::*;
+
+/// A keycode table to QEMU key numbers (qnum), selectable at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Keymap {
+    /// macOS virtual keycodes.
+    Osx,
+    /// Windows virtual-key codes.
+    Win32,
+    /// X11 keysyms, as sent by the VNC clients.
+    X11,
+    /// X11 keycodes of the evdev driver, also used by Wayland.
+    XorgEvdev,
+    /// X11 keycodes of the legacy kbd driver.
+    XorgKbd,
+    /// X11 keycodes of XQuartz.
+    XorgXQuartz,
+    /// X11 keycodes of XWin (Cygwin/X).
+    XorgXWin,
+}
+
+impl Keymap {
+    pub const ALL: [Keymap; 7] = [
+        Keymap::Osx,
+        Keymap::Win32,
+        Keymap::X11,
+        Keymap::XorgEvdev,
+        Keymap::XorgKbd,
+        Keymap::XorgXQuartz,
+        Keymap::XorgXWin,
+    ];
+
+    /// The keymap of the host windowing system, for the backend name (x11, wayland,
+    /// win32, macos...).
+    pub fn for_backend(backend: &str) -> Option<Keymap> {
+        match backend.to_ascii_lowercase().as_str() {
+            "x11" | "wayland" | "evdev" => Some(Keymap::XorgEvdev),
+            "win32" | "windows" => Some(Keymap::Win32),
+            "macos" | "osx" | "quartz" => Some(Keymap::Osx),
+            _ => None,
+        }
+    }
+
+    /// The keymap of the host windowing system, for the target OS.
+    pub fn native() -> Keymap {
+        if cfg!(windows) {
+            Keymap::Win32
+        } else if cfg!(target_os = "macos") {
+            Keymap::Osx
+        } else {
+            Keymap::XorgEvdev
+        }
+    }
+
+    /// The name of the table, as in keycodemapdb (ex: "xorgevdev2qnum").
+    pub fn name(self) -> &'static str {
+        match self {
+            Keymap::Osx => "osx2qnum",
+            Keymap::Win32 => "win322qnum",
+            Keymap::X11 => "x112qnum",
+            Keymap::XorgEvdev => "xorgevdev2qnum",
+            Keymap::XorgKbd => "xorgkbd2qnum",
+            Keymap::XorgXQuartz => "xorgxquartz2qnum",
+            Keymap::XorgXWin => "xorgxwin2qnum",
+        }
+    }
+
+    /// The table, indexed by keycode. Unmapped keycodes are 0.
+    pub fn table(self) -> &'static [u16] {
+        match self {
+            Keymap::Osx => KEYMAP_OSX2QNUM,
+            Keymap::Win32 => KEYMAP_WIN322QNUM,
+            Keymap::X11 => KEYMAP_X112QNUM,
+            Keymap::XorgEvdev => KEYMAP_XORGEVDEV2QNUM,
+            Keymap::XorgKbd => KEYMAP_XORGKBD2QNUM,
+            Keymap::XorgXQuartz => KEYMAP_XORGXQUARTZ2QNUM,
+            Keymap::XorgXWin => KEYMAP_XORGXWIN2QNUM,
+        }
+    }
+
+    /// The qnum of a keycode.
+    pub fn qnum(self, keycode: u32) -> Option<u32> {
+        match self.table().get(keycode as usize) {
+            Some(0) | None => None,
+            Some(qnum) => Some(*qnum as u32),
+        }
+    }
+
+    /// The keycode of a qnum, the lowest one if several keycodes map to it.
+    pub fn keycode(self, qnum: u32) -> Option<u32> {
+        self.reverse().get(&qnum).copied()
+    }
+
+    fn reverse(self) -> &'static HashMap<u32, u32> {
+        static REVERSE: [OnceCell<HashMap<u32, u32>>; 7] = [
+            OnceCell::new(),
+            OnceCell::new(),
+            OnceCell::new(),
+            OnceCell::new(),
+            OnceCell::new(),
+            OnceCell::new(),
+            OnceCell::new(),
+        ];
+
+        REVERSE[self as usize].get_or_init(|| {
+            let mut map = HashMap::new();
+            for (keycode, qnum) in self.table().iter().enumerate() {
+                if *qnum != 0 {
+                    map.entry(*qnum as u32).or_insert(keycode as u32);
+                }
+            }
+            map
+        })
+    }
+}
+
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Keymap::ALL
+            .iter()
+            .copied()
+            .find(|k| k.name() == s || k.name().strip_suffix("2qnum") == Some(s))
+            .ok_or_else(|| format!("Unknown keymap '{}'", s))
+    }
+}
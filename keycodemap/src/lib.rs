@@ -1,3 +1,6 @@
+mod keymap;
+pub use keymap::*;
+
 include!("keymap_osx2qnum.rs");
 include!("keymap_win322qnum.rs");
 include!("keymap_x112qnum.rs");
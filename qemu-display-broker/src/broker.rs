@@ -0,0 +1,162 @@
+//! The VMs of the session bus, and the methods of the control socket.
+
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_io::Timer;
+use futures_util::{future, StreamExt};
+use image::{png::PngEncoder, ColorType};
+use qemu_display::{Display, Result};
+use serde_json::{json, Value};
+use zbus::{fdo, names::OwnedUniqueName, Connection};
+
+use crate::rpc::{Call, RpcError};
+
+// the VMs are also looked up periodically, they queue for "org.qemu" after connecting
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Vm {
+    owner: OwnedUniqueName,
+    display: Display<'static>,
+}
+
+/// The VMs owning or queued for "org.qemu", by name.
+#[derive(Clone)]
+pub struct Broker {
+    conn: Connection,
+    vms: Arc<Mutex<HashMap<String, Vm>>>,
+}
+
+impl Broker {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            vms: Default::default(),
+        }
+    }
+
+    // update the VMs, keeping the displays of those that didn't change
+    async fn refresh(&self) -> Result<()> {
+        let owners = Display::by_name(&self.conn).await?;
+        let mut added = vec![];
+        for (name, owner) in owners.iter() {
+            let known = self
+                .vms
+                .lock()
+                .unwrap()
+                .get(name)
+                .map(|vm| vm.owner.clone());
+            if known.as_ref() == Some(owner) {
+                continue;
+            }
+            match Display::new(&self.conn, Some(owner.to_string())).await {
+                Ok(display) => added.push((name.clone(), owner.clone(), display)),
+                Err(e) => eprintln!("Failed to get the display of VM '{}': {}", name, e),
+            }
+        }
+        let mut vms = self.vms.lock().unwrap();
+        vms.retain(|name, _| owners.contains_key(name));
+        for (name, owner, display) in added {
+            eprintln!("VM '{}' added", name);
+            vms.insert(name, Vm { owner, display });
+        }
+        Ok(())
+    }
+
+    /// Keep the VMs up to date, with the bus name changes.
+    pub async fn watch(self) -> Result<()> {
+        let mut changed = fdo::DBusProxy::new(&self.conn)
+            .await?
+            .receive_name_owner_changed()
+            .await?;
+        loop {
+            self.refresh().await?;
+            future::select(changed.next(), Timer::after(REFRESH_INTERVAL)).await;
+        }
+    }
+
+    fn display(&self, vm: &str) -> std::result::Result<Display<'static>, RpcError> {
+        self.vms
+            .lock()
+            .unwrap()
+            .get(vm)
+            .map(|vm| vm.display.clone())
+            .ok_or_else(|| RpcError::failed(format!("No VM '{}'", vm)))
+    }
+
+    async fn list(&self) -> Value {
+        let displays: Vec<_> = self
+            .vms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, vm)| (name.clone(), vm.display.clone()))
+            .collect();
+        let mut list = vec![];
+        for (name, display) in displays {
+            let consoles: Vec<_> = display
+                .consoles()
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .map(|c| {
+                    json!({
+                        "id": c.id,
+                        "label": c.label,
+                        "type": c.type_,
+                        "head": c.head,
+                        "width": c.width,
+                        "height": c.height,
+                    })
+                })
+                .collect();
+            list.push(json!({ "name": name, "consoles": consoles }));
+        }
+        list.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        Value::Array(list)
+    }
+
+    /// Run a method call.
+    pub async fn call(&self, call: Call) -> std::result::Result<Value, RpcError> {
+        match call {
+            Call::List => Ok(self.list().await),
+            Call::Screenshot { vm, console } => {
+                let console = self
+                    .display(&vm)?
+                    .console(console)
+                    .await
+                    .map_err(RpcError::failed)?;
+                let image = console.screenshot().await.map_err(RpcError::failed)?;
+                let mut png = Cursor::new(vec![]);
+                PngEncoder::new(&mut png)
+                    .encode(&image.data, image.width, image.height, ColorType::Rgba8)
+                    .map_err(RpcError::failed)?;
+                Ok(json!({
+                    "width": image.width,
+                    "height": image.height,
+                    "png": base64::encode(png.into_inner()),
+                }))
+            }
+            Call::Attach {
+                vm,
+                console,
+                socket,
+            } => {
+                let console = self
+                    .display(&vm)?
+                    .console(console)
+                    .await
+                    .map_err(RpcError::failed)?;
+                console
+                    .register_listener_socket(&socket)
+                    .await
+                    .map_err(RpcError::failed)?;
+                Ok(Value::Null)
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    borrow::Borrow,
+    env,
+    error::Error,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    thread,
+};
+
+use clap::Parser;
+
+mod broker;
+mod rpc;
+
+use broker::Broker;
+
+const SOCKET_NAME: &str = "qemu-display-broker.sock";
+
+/// Serve the VMs of the session bus on a control socket, with JSON-RPC 2.0 (one message per
+/// line). The methods are "list", "screenshot" {vm, console} and "attach" {vm, console,
+/// socket}, sending the console events to a listener socket.
+#[derive(Parser, Debug)]
+struct Cli {
+    #[clap(short, long)]
+    dbus_address: Option<String>,
+    /// The control socket, in $XDG_RUNTIME_DIR by default
+    #[clap(short, long)]
+    socket: Option<PathBuf>,
+}
+
+impl Cli {
+    fn socket(&self) -> PathBuf {
+        self.socket.clone().unwrap_or_else(|| {
+            env::var_os("XDG_RUNTIME_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(env::temp_dir)
+                .join(SOCKET_NAME)
+        })
+    }
+}
+
+async fn serve_client(broker: Broker, stream: UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let req = rpc::parse(&line);
+        let result = match req.call {
+            Ok(call) => broker.call(call).await,
+            Err(e) => Err(e),
+        };
+        if let Some(id) = req.id {
+            writer.write_all(rpc::response(id, result).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::parse();
+    let conn = if let Some(addr) = &args.dbus_address {
+        zbus::ConnectionBuilder::address(addr.borrow())?
+            .build()
+            .await
+    } else {
+        zbus::Connection::session().await
+    }?;
+
+    let path = args.socket();
+    // a socket left by a previous broker
+    if UnixStream::connect(&path).is_err() {
+        let _ = fs::remove_file(&path);
+    }
+    let listener = UnixListener::bind(&path)?;
+    println!("Serving on {}", path.display());
+
+    let broker = Broker::new(conn);
+    let watcher = broker.clone();
+    thread::spawn(move || {
+        if let Err(e) = async_io::block_on(watcher.watch()) {
+            eprintln!("Failed to watch the VMs: {}", e);
+            std::process::exit(1);
+        }
+    });
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let broker = broker.clone();
+        thread::spawn(move || {
+            if let Err(e) = async_io::block_on(serve_client(broker, stream)) {
+                eprintln!("Client error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = async_io::block_on(run()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
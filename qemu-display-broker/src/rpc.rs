@@ -0,0 +1,177 @@
+//! The JSON-RPC 2.0 protocol of the control socket, one message per line.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    /// A failure of a method, with the error message.
+    pub fn failed<E: ToString>(e: E) -> Self {
+        Self {
+            code: SERVER_ERROR,
+            message: e.to_string(),
+        }
+    }
+
+    fn new(code: i64, message: String) -> Self {
+        Self { code, message }
+    }
+}
+
+/// A method call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    /// The VMs and their consoles.
+    List,
+    /// A PNG screenshot of a console, encoded in base64.
+    Screenshot { vm: String, console: u32 },
+    /// Send the events of a console to the listener served at `socket`.
+    Attach {
+        vm: String,
+        console: u32,
+        socket: PathBuf,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct ConsoleParams {
+    vm: String,
+    #[serde(default)]
+    console: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct AttachParams {
+    vm: String,
+    #[serde(default)]
+    console: u32,
+    socket: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    jsonrpc: String,
+    // a notification doesn't have an id
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// A request read from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The id of the reply, none for a notification.
+    pub id: Option<Value>,
+    pub call: Result<Call, RpcError>,
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// Parse a request line. The invalid requests are answered with a null id.
+pub fn parse(line: &str) -> Request {
+    let value: Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(e) => {
+            return Request {
+                id: Some(Value::Null),
+                call: Err(RpcError::new(PARSE_ERROR, e.to_string())),
+            }
+        }
+    };
+    let msg = match serde_json::from_value::<Message>(value) {
+        Ok(msg) if msg.jsonrpc == "2.0" => msg,
+        Ok(_) => {
+            return Request {
+                id: Some(Value::Null),
+                call: Err(RpcError::new(INVALID_REQUEST, "Not JSON-RPC 2.0".into())),
+            }
+        }
+        Err(e) => {
+            return Request {
+                id: Some(Value::Null),
+                call: Err(RpcError::new(INVALID_REQUEST, e.to_string())),
+            }
+        }
+    };
+    let call = match msg.method.as_str() {
+        "list" => Ok(Call::List),
+        "screenshot" => params(msg.params).map(|p: ConsoleParams| Call::Screenshot {
+            vm: p.vm,
+            console: p.console,
+        }),
+        "attach" => params(msg.params).map(|p: AttachParams| Call::Attach {
+            vm: p.vm,
+            console: p.console,
+            socket: p.socket,
+        }),
+        method => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method '{}'", method),
+        )),
+    };
+    Request { id: msg.id, call }
+}
+
+/// The response line of a request.
+pub fn response(id: Value, result: Result<Value, RpcError>) -> String {
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    };
+    response.to_string() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        let req = parse(r#"{"jsonrpc": "2.0", "id": 1, "method": "list"}"#);
+        assert_eq!(req.id, Some(json!(1)));
+        assert_eq!(req.call, Ok(Call::List));
+
+        let req = parse(
+            r#"{"jsonrpc": "2.0", "id": "a", "method": "attach",
+                "params": {"vm": "win", "socket": "/run/l.sock"}}"#,
+        );
+        assert_eq!(
+            req.call,
+            Ok(Call::Attach {
+                vm: "win".into(),
+                console: 0,
+                socket: "/run/l.sock".into()
+            })
+        );
+
+        let req = parse(r#"{"jsonrpc": "2.0", "method": "screenshot", "params": {}}"#);
+        assert_eq!(req.id, None);
+        assert_eq!(req.call.unwrap_err().code, INVALID_PARAMS);
+        let req = parse(r#"{"jsonrpc": "2.0", "id": 2, "method": "reboot"}"#);
+        assert_eq!(req.call.unwrap_err().code, METHOD_NOT_FOUND);
+        let req = parse("{");
+        assert_eq!(req.id, Some(Value::Null));
+        assert_eq!(req.call.unwrap_err().code, PARSE_ERROR);
+
+        assert_eq!(
+            response(json!(2), Err(RpcError::failed("No VM 'win'"))),
+            "{\"error\":{\"code\":-32000,\"message\":\"No VM 'win'\"},\"id\":2,\"jsonrpc\":\"2.0\"}\n"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! A headless screenshot diff service, for CI.
+//!
+//! The consoles of the VMs on the session bus are captured periodically, and compared
+//! with baseline images (binary PPM files, named after the VMs). A missing baseline is
+//! created from the first capture. The results are served in plain text over HTTP, with a
+//! 503 status if any VM differs from its baseline.
+//!
+//! Usage: screenshot-diff BASELINE-DIR [HTTP-ADDR] [INTERVAL-SECS] [VM-NAME...]
+
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use async_io::Timer;
+use qemu_display::{Display, RgbaImage};
+
+// the color differences tolerated, for example with lossy scaling in the guest
+const TOLERANCE: u8 = 8;
+
+#[derive(Debug, Clone)]
+enum Status {
+    Match,
+    NewBaseline,
+    Differs(qemu_display::ImageDiff),
+    Failed(String),
+}
+
+type Results = Arc<Mutex<BTreeMap<String, Status>>>;
+
+fn write_ppm(path: &Path, image: &RgbaImage) -> std::io::Result<()> {
+    let mut data = format!("P6\n{} {}\n255\n", image.width, image.height).into_bytes();
+    for px in image.data.chunks_exact(4) {
+        data.extend_from_slice(&px[..3]);
+    }
+    fs::write(path, data)
+}
+
+fn read_ppm(path: &Path) -> Result<RgbaImage, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    // the header is made of 4 tokens: magic, width, height and maxval
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while data.get(pos).ok_or("truncated PPM")?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while !data.get(pos).ok_or("truncated PPM")?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        fields.push(std::str::from_utf8(&data[start..pos])?);
+    }
+    if fields[0] != "P6" || fields[3] != "255" {
+        return Err("unsupported PPM".into());
+    }
+    let width: u32 = fields[1].parse()?;
+    let height: u32 = fields[2].parse()?;
+    let rgb = data
+        .get(pos + 1..pos + 1 + width as usize * height as usize * 3)
+        .ok_or("truncated PPM")?;
+    let data = rgb
+        .chunks_exact(3)
+        .flat_map(|px| [px[0], px[1], px[2], 0xff])
+        .collect();
+    Ok(RgbaImage {
+        width,
+        height,
+        data,
+    })
+}
+
+async fn check(display: &Display<'_>, baseline: &Path) -> Result<Status, Box<dyn Error>> {
+    let image = display.console(0).await?.screenshot().await?;
+    if !baseline.exists() {
+        write_ppm(baseline, &image)?;
+        return Ok(Status::NewBaseline);
+    }
+    let diff = read_ppm(baseline)?.diff(&image, TOLERANCE)?;
+    if diff.is_empty() {
+        Ok(Status::Match)
+    } else {
+        write_ppm(&baseline.with_extension("actual.ppm"), &image)?;
+        Ok(Status::Differs(diff))
+    }
+}
+
+async fn check_all(
+    conn: &zbus::Connection,
+    dir: &Path,
+    names: &[String],
+    results: &Results,
+) -> Result<(), Box<dyn Error>> {
+    let vms = Display::by_name(conn).await?;
+    for name in names {
+        if !vms.contains_key(name) {
+            let status = Status::Failed("VM not found".into());
+            results.lock().unwrap().insert(name.clone(), status);
+        }
+    }
+    for (name, dest) in vms {
+        if !names.is_empty() && !names.contains(&name) {
+            continue;
+        }
+        let baseline = dir.join(format!("{}.ppm", name));
+        let status = match Display::new(conn, Some(dest)).await {
+            Ok(display) => check(&display, &baseline).await,
+            Err(e) => Err(e.into()),
+        }
+        .unwrap_or_else(|e| Status::Failed(e.to_string()));
+        results.lock().unwrap().insert(name, status);
+    }
+    Ok(())
+}
+
+fn serve(stream: TcpStream, results: &Results) -> std::io::Result<()> {
+    // the request is ignored, all the paths serve the same report
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+
+    let results = results.lock().unwrap().clone();
+    let failed = results
+        .values()
+        .any(|s| matches!(s, Status::Differs(_) | Status::Failed(_)));
+    let mut body = String::new();
+    for (name, status) in results {
+        let line = match status {
+            Status::Match => "ok".into(),
+            Status::NewBaseline => "ok (new baseline)".into(),
+            Status::Differs(diff) => {
+                let r = diff.bounds.unwrap_or_default();
+                format!(
+                    "differs: {} pixels in {}x{}+{}+{}",
+                    diff.pixels, r.width, r.height, r.x, r.y
+                )
+            }
+            Status::Failed(e) => format!("error: {}", e),
+        };
+        body += &format!("{}: {}\n", name, line);
+    }
+    let status = if failed {
+        "503 Service Unavailable"
+    } else {
+        "200 OK"
+    };
+    write!(
+        &stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let dir = PathBuf::from(args.next().expect("argument: baseline directory"));
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".into());
+    let interval = match args.next() {
+        Some(secs) => Duration::from_secs(secs.parse()?),
+        None => Duration::from_secs(60),
+    };
+    let names: Vec<String> = args.collect();
+    fs::create_dir_all(&dir)?;
+
+    let results = Results::default();
+    let listener = TcpListener::bind(&addr)?;
+    let served = results.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = serve(stream, &served) {
+                eprintln!("HTTP error: {}", e);
+            }
+        }
+    });
+
+    async_io::block_on(async move {
+        let conn = zbus::Connection::session().await?;
+        loop {
+            if let Err(e) = check_all(&conn, &dir, &names, &results).await {
+                eprintln!("Failed to list the VMs: {}", e);
+            }
+            Timer::after(interval).await;
+        }
+    })
+}
@@ -0,0 +1,37 @@
+//! Drive the input of a console from a SPICE client.
+//!
+//! The main and inputs channels are served on a TCP address, for example with:
+//! `remote-viewer spice://127.0.0.1:5930` (there is no display channel, the client shows
+//! no image).
+//!
+//! Usage: spice-inputs [ADDR] [VM-NAME]
+
+use std::{env, error::Error, net::TcpListener, sync::Arc, thread};
+
+use async_io::Async;
+use qemu_display::{AttachOptions, Display, SpiceInputs, Transport};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:5930".into());
+    let vm_name = args.next();
+
+    async_io::block_on(async move {
+        let options = AttachOptions::new(Transport::SessionBus).with_vm_name(vm_name);
+        let (_display, console) = Display::attach(options).await?;
+        let inputs = Arc::new(SpiceInputs::new(&console));
+
+        let listener = Async::<TcpListener>::bind(addr.parse::<std::net::SocketAddr>()?)?;
+        println!("Listening on spice://{}", listener.get_ref().local_addr()?);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let inputs = inputs.clone();
+            // the channels of a client are served concurrently
+            thread::spawn(move || {
+                if let Err(e) = async_io::block_on(inputs.serve(stream)) {
+                    eprintln!("SPICE client error: {}", e);
+                }
+            });
+        }
+    })
+}
@@ -0,0 +1,420 @@
+//! Runtime switching between the DMABUF and copy paths.
+//!
+//! With a GL display, QEMU shares its scanouts as DMABUF. A consumer importing them with
+//! EGL avoids any copy, but the import may fail, or the GPU may be too busy to keep up. The
+//! [`AdaptiveSink`] then reads the DMABUF and hands regular copies to the sink instead,
+//! and goes back to the DMABUF when possible.
+
+use async_broadcast::{broadcast, Receiver, Sender};
+use futures::Stream;
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    Cursor, FrameSink, MouseSet, RgbaImage, Scanout, ScanoutDMABUF, Update, UpdateDMABUF,
+    PIXMAN_X8R8G8B8,
+};
+
+/// How the DMABUF scanouts reach the sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FramePath {
+    /// The DMABUF is handed over, for a zero-copy import.
+    Dmabuf,
+    /// The DMABUF is read, and copied in x8r8g8b8 scanouts and updates.
+    Copy,
+}
+
+impl fmt::Display for FramePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dmabuf => "dmabuf",
+            Self::Copy => "copy",
+        })
+    }
+}
+
+/// Why the path changed, see [`FramePathEvent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramePathReason {
+    /// Requested with [`AdaptiveControl::set_mode`].
+    Requested,
+    /// The consumer failed to import a DMABUF, see [`AdaptiveControl::import_failed`].
+    ImportFailed,
+    /// The sink was too slow to handle the DMABUF updates.
+    Saturated,
+    /// The DMABUF path is tried again, after a saturation.
+    Retry,
+    /// The DMABUF can't be read (with a tiled layout for example), with the error message.
+    CopyFailed(String),
+}
+
+/// A change of the active path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FramePathEvent {
+    pub path: FramePath,
+    pub reason: FramePathReason,
+}
+
+/// The path selection of an [`AdaptiveSink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FramePathMode {
+    /// Prefer the DMABUF, with copies on failure or saturation.
+    #[default]
+    Auto,
+    /// Always the DMABUF path.
+    Dmabuf,
+    /// Always the copy path, for a consumer without zero-copy import.
+    Copy,
+}
+
+/// Options of an [`AdaptiveSink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramePathPolicy {
+    pub mode: FramePathMode,
+    /// An update handled slower than this counts as a slow update.
+    pub max_update_time: Duration,
+    /// The consecutive slow updates switching to copies, in auto mode.
+    pub max_slow_updates: u32,
+    /// The delay before trying the DMABUF path again, after a saturation.
+    pub retry_delay: Duration,
+}
+
+impl Default for FramePathPolicy {
+    fn default() -> Self {
+        Self {
+            mode: FramePathMode::Auto,
+            max_update_time: Duration::from_millis(50),
+            max_slow_updates: 10,
+            retry_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    policy: FramePathPolicy,
+    path: FramePath,
+    // the DMABUF path can't be used until the mode is set again
+    import_failed: bool,
+    // a switch not applied yet, by the sink
+    pending: Option<FramePathReason>,
+    events: Sender<FramePathEvent>,
+}
+
+impl State {
+    fn preferred(&self) -> FramePath {
+        match self.policy.mode {
+            FramePathMode::Auto if self.import_failed => FramePath::Copy,
+            FramePathMode::Auto | FramePathMode::Dmabuf => FramePath::Dmabuf,
+            FramePathMode::Copy => FramePath::Copy,
+        }
+    }
+}
+
+/// Controls an [`AdaptiveSink`], from the frontend.
+#[derive(Debug, Clone)]
+pub struct AdaptiveControl {
+    state: Arc<Mutex<State>>,
+    receiver: Receiver<FramePathEvent>,
+}
+
+impl AdaptiveControl {
+    /// The active path.
+    pub fn path(&self) -> FramePath {
+        self.state.lock().unwrap().path
+    }
+
+    /// Change the path selection.
+    pub fn set_mode(&self, mode: FramePathMode) {
+        let mut state = self.state.lock().unwrap();
+        state.policy.mode = mode;
+        state.import_failed = false;
+        state.pending = Some(FramePathReason::Requested);
+    }
+
+    /// Report a failed DMABUF import: in auto mode, the copy path is used from now on.
+    pub fn import_failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.import_failed = true;
+        state.pending = Some(FramePathReason::ImportFailed);
+    }
+
+    /// The changes of the active path.
+    pub fn receive_path_changed(&self) -> Pin<Box<dyn Stream<Item = FramePathEvent> + Send>> {
+        Box::pin(self.receiver.clone())
+    }
+}
+
+/// A [`FrameSink`] adapter, choosing between the DMABUF and copy paths at runtime.
+///
+/// In auto mode, the DMABUF path is used until the consumer reports an import failure, or
+/// handles the updates too slowly (the time spent in
+/// [`FrameSink::on_update_dmabuf`] is measured, it should include the rendering). The
+/// copies are read from linear DMABUFs only: with another layout, the DMABUF path is kept.
+/// The other events are passed through.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct AdaptiveSink<S: FrameSink> {
+    #[derivative(Debug = "ignore")]
+    sink: S,
+    state: Arc<Mutex<State>>,
+    // the current DMABUF scanout, kept to switch path or read the copies
+    dmabuf: Option<ScanoutDMABUF>,
+    // the sink has the current scanout, on the active path
+    sent: bool,
+    slow_updates: u32,
+    saturated_since: Option<Instant>,
+}
+
+impl<S: FrameSink> AdaptiveSink<S> {
+    pub fn new(sink: S, policy: FramePathPolicy) -> (Self, AdaptiveControl) {
+        let (mut events, receiver) = broadcast(4);
+        events.set_overflow(true);
+        let mut state = State {
+            policy,
+            path: FramePath::Dmabuf,
+            import_failed: false,
+            pending: None,
+            events,
+        };
+        state.path = state.preferred();
+        let state = Arc::new(Mutex::new(state));
+        let sink = Self {
+            sink,
+            state: state.clone(),
+            dmabuf: None,
+            sent: false,
+            slow_updates: 0,
+            saturated_since: None,
+        };
+        (sink, AdaptiveControl { state, receiver })
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    fn switch(&mut self, state: &mut State, path: FramePath, reason: FramePathReason) {
+        if state.path == path {
+            return;
+        }
+        log::debug!("Switching to the {} path: {:?}", path, reason);
+        state.path = path;
+        self.sent = false;
+        self.slow_updates = 0;
+        let _ = state.events.try_broadcast(FramePathEvent { path, reason });
+    }
+
+    // apply the control requests and the retry delay, and return the path to use
+    fn select_path(&mut self) -> FramePath {
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+        if let Some(reason) = state.pending.take() {
+            self.saturated_since = None;
+            let path = state.preferred();
+            self.switch(&mut state, path, reason);
+        }
+        let retry = self
+            .saturated_since
+            .filter(|since| since.elapsed() >= state.policy.retry_delay);
+        if retry.is_some() {
+            self.saturated_since = None;
+            let path = state.preferred();
+            self.switch(&mut state, path, FramePathReason::Retry);
+        }
+        state.path
+    }
+
+    fn set_path(&mut self, path: FramePath, reason: FramePathReason) {
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+        self.switch(&mut state, path, reason);
+    }
+
+    // hand the current DMABUF to the sink, on the active path
+    async fn send_scanout(&mut self) {
+        if self.select_path() == FramePath::Copy {
+            match self.scanout_copy().await {
+                Ok(()) => return,
+                Err(e) => self.copy_failed(e),
+            }
+        }
+        self.scanout_dmabuf().await;
+    }
+
+    async fn scanout_dmabuf(&mut self) {
+        let scanout = match self.dmabuf.as_ref().and_then(|s| s.try_clone()) {
+            Some(scanout) => scanout,
+            None => return,
+        };
+        self.sink.on_scanout_dmabuf(scanout).await;
+        self.sent = true;
+    }
+
+    fn copy_failed(&mut self, e: crate::Error) {
+        log::warn!("Failed to copy the DMABUF: {}", e);
+        self.set_path(
+            FramePath::Dmabuf,
+            FramePathReason::CopyFailed(e.to_string()),
+        );
+    }
+
+    async fn update_dmabuf(&mut self, update: UpdateDMABUF) {
+        if !self.sent {
+            self.scanout_dmabuf().await;
+            if !self.sent {
+                return;
+            }
+        }
+        let start = Instant::now();
+        self.sink.on_update_dmabuf(update).await;
+
+        let policy = self.state.lock().unwrap().policy;
+        if policy.mode != FramePathMode::Auto {
+            return;
+        }
+        if start.elapsed() <= policy.max_update_time {
+            self.slow_updates = 0;
+            return;
+        }
+        self.slow_updates += 1;
+        if self.slow_updates >= policy.max_slow_updates {
+            self.saturated_since = Some(Instant::now());
+            self.set_path(FramePath::Copy, FramePathReason::Saturated);
+        }
+    }
+
+    // read the whole DMABUF, as a regular scanout
+    async fn scanout_copy(&mut self) -> crate::Result<()> {
+        let scanout = match &self.dmabuf {
+            Some(scanout) => scanout,
+            None => return Ok(()),
+        };
+        let image = read_x8r8g8b8(scanout, 0, 0, scanout.width, scanout.height)?;
+        self.sent = true;
+        self.sink
+            .on_scanout(Scanout {
+                width: image.width,
+                height: image.height,
+                stride: image.width * 4,
+                format: PIXMAN_X8R8G8B8,
+                data: image.data,
+            })
+            .await;
+        Ok(())
+    }
+
+    // read only the updated region
+    async fn update_copy(&mut self, update: UpdateDMABUF) -> crate::Result<()> {
+        if !self.sent {
+            return self.scanout_copy().await;
+        }
+        let scanout = match &self.dmabuf {
+            Some(scanout) => scanout,
+            None => return Ok(()),
+        };
+        let (x, y) = (update.x.max(0) as u32, update.y.max(0) as u32);
+        let w = (update.w.max(0) as u32).min(scanout.width.saturating_sub(x));
+        let h = (update.h.max(0) as u32).min(scanout.height.saturating_sub(y));
+        if w == 0 || h == 0 {
+            return Ok(());
+        }
+        let image = read_x8r8g8b8(scanout, x, y, w, h)?;
+        self.sink
+            .on_update(Update {
+                x: x as _,
+                y: y as _,
+                w: w as _,
+                h: h as _,
+                stride: w * 4,
+                format: PIXMAN_X8R8G8B8,
+                data: image.data,
+            })
+            .await;
+        Ok(())
+    }
+}
+
+// a region of the DMABUF, in the little-endian x8r8g8b8 layout
+fn read_x8r8g8b8(
+    scanout: &ScanoutDMABUF,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+) -> crate::Result<RgbaImage> {
+    let mut image = RgbaImage::from_dmabuf_rect(scanout, x, y, w, h)?;
+    for px in image.data.chunks_exact_mut(4) {
+        px.swap(0, 2);
+    }
+    Ok(image)
+}
+
+// for the re-registrations of a ConsoleWatchdog, sharing the control
+impl<S: FrameSink + Clone> Clone for AdaptiveSink<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sink: self.sink.clone(),
+            state: self.state.clone(),
+            dmabuf: self.dmabuf.as_ref().and_then(|s| s.try_clone()),
+            sent: false,
+            slow_updates: 0,
+            saturated_since: self.saturated_since,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: FrameSink> FrameSink for AdaptiveSink<S> {
+    async fn on_resize(&mut self, width: u32, height: u32) {
+        self.sink.on_resize(width, height).await;
+    }
+
+    async fn on_scanout(&mut self, scanout: Scanout) {
+        self.dmabuf = None;
+        self.sink.on_scanout(scanout).await;
+    }
+
+    async fn on_update(&mut self, update: Update) {
+        self.sink.on_update(update).await;
+    }
+
+    async fn on_scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        self.dmabuf = Some(scanout);
+        self.sent = false;
+        // a static display may not be updated for a while
+        self.send_scanout().await;
+    }
+
+    async fn on_update_dmabuf(&mut self, update: UpdateDMABUF) {
+        match self.select_path() {
+            FramePath::Dmabuf => self.update_dmabuf(update).await,
+            FramePath::Copy => {
+                if let Err(e) = self.update_copy(update).await {
+                    self.copy_failed(e);
+                    self.update_dmabuf(update).await;
+                }
+            }
+        }
+    }
+
+    async fn on_cursor(&mut self, cursor: Cursor) {
+        self.sink.on_cursor(cursor).await;
+    }
+
+    async fn on_mouse_set(&mut self, set: MouseSet) {
+        self.sink.on_mouse_set(set).await;
+    }
+
+    fn on_disable(&mut self) {
+        self.sink.on_disable();
+    }
+
+    fn on_disconnected(&mut self) {
+        self.sink.on_disconnected();
+    }
+}
@@ -0,0 +1,167 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(feature = "qmp")]
+use std::path::PathBuf;
+#[cfg(windows)]
+use uds_windows::UnixStream;
+use zbus::{
+    names::{BusName, WellKnownName},
+    Connection, ConnectionBuilder,
+};
+
+use crate::{Console, Display, Error, Result};
+
+/// How to reach the display of a VM, for [`Display::attach`].
+#[derive(Debug)]
+pub enum Transport {
+    /// A VM on the session bus.
+    SessionBus,
+    /// A VM on the bus at a D-Bus address, such as the one of an
+    /// [`SshTunnel`](crate::SshTunnel).
+    BusAddress(String),
+    /// A peer-to-peer connection to QEMU, like a `-display dbus,p2p=yes` client socket.
+    P2P(UnixStream),
+    /// A VM started with `-display dbus,p2p=yes`, through its QMP socket.
+    #[cfg(feature = "qmp")]
+    Qmp(PathBuf),
+}
+
+pub(crate) type SpawnExecutor = Box<dyn FnOnce(Connection) + Send>;
+
+/// Options for [`Display::attach`].
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct AttachOptions {
+    pub transport: Transport,
+    /// The VM to look up on a bus, any VM by default. The other transports reach a single
+    /// VM, they fail with a name.
+    pub vm_name: Option<String>,
+    /// Wait until the VM appears on the bus.
+    pub wait: bool,
+    /// The index of the returned console.
+    pub console: u32,
+    /// Spawn the executor of the new connection, instead of the zbus executor thread.
+    ///
+    /// It is called once the connection is built, before any call, and is expected to
+    /// spawn [`run_executor`](crate::run_executor) on the runtime of the frontend.
+    #[derivative(Debug = "ignore")]
+    pub spawn_executor: Option<SpawnExecutor>,
+}
+
+impl AttachOptions {
+    pub fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            vm_name: None,
+            wait: false,
+            console: 0,
+            spawn_executor: None,
+        }
+    }
+
+    pub fn with_vm_name(mut self, name: Option<String>) -> Self {
+        self.vm_name = name;
+        self
+    }
+
+    pub fn with_wait(mut self, wait: bool) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    pub fn with_console(mut self, console: u32) -> Self {
+        self.console = console;
+        self
+    }
+
+    pub fn with_spawn_executor<F: FnOnce(Connection) + Send + 'static>(mut self, spawn: F) -> Self {
+        self.spawn_executor = Some(Box::new(spawn));
+        self
+    }
+}
+
+pub(crate) async fn connect(
+    builder: ConnectionBuilder<'_>,
+    spawn: Option<SpawnExecutor>,
+) -> Result<Connection> {
+    let conn = builder.internal_executor(spawn.is_none()).build().await?;
+    if let Some(spawn) = spawn {
+        spawn(conn.clone());
+    }
+    Ok(conn)
+}
+
+impl Display<'static> {
+    /// Connect to a VM, and get its display and a console.
+    ///
+    /// On a bus, the VM is looked up like with [`Display::lookup`].
+    pub async fn attach(options: AttachOptions) -> Result<(Self, Console)> {
+        let AttachOptions {
+            transport,
+            vm_name,
+            wait,
+            console,
+            spawn_executor,
+        } = options;
+        if let (Some(name), Transport::P2P(_)) = (&vm_name, &transport) {
+            return Err(Error::Failed(format!(
+                "No VM name lookup on a peer-to-peer connection: {}",
+                name
+            )));
+        }
+        #[cfg(feature = "qmp")]
+        if let (Some(name), Transport::Qmp(_)) = (&vm_name, &transport) {
+            return Err(Error::Failed(format!(
+                "No VM name lookup through a QMP socket: {}",
+                name
+            )));
+        }
+        let display = match transport {
+            Transport::SessionBus => {
+                let conn = connect(ConnectionBuilder::session()?, spawn_executor).await?;
+                Self::on_bus(&conn, vm_name.as_deref(), wait).await?
+            }
+            Transport::BusAddress(addr) => {
+                let builder = ConnectionBuilder::address(addr.as_str())?;
+                let conn = connect(builder, spawn_executor).await?;
+                Self::on_bus(&conn, vm_name.as_deref(), wait).await?
+            }
+            Transport::P2P(stream) => {
+                #[cfg(windows)]
+                let peer_pid = crate::win32::unix_stream_get_peer_pid(&stream)?;
+                let conn =
+                    connect(ConnectionBuilder::unix_stream(stream).p2p(), spawn_executor).await?;
+                Self::new(
+                    &conn,
+                    Option::<String>::None,
+                    #[cfg(windows)]
+                    peer_pid,
+                )
+                .await?
+            }
+            #[cfg(feature = "qmp")]
+            Transport::Qmp(path) => Self::new_qmp_spawn(path, spawn_executor).await?,
+        };
+        let console = display.console(console).await?;
+        Ok((display, console))
+    }
+
+    async fn on_bus(conn: &Connection, vm_name: Option<&str>, wait: bool) -> Result<Self> {
+        let dest = match Self::lookup(conn, wait, vm_name).await? {
+            Some(owner) => BusName::from(owner),
+            None => BusName::from(WellKnownName::from_static_str_unchecked("org.qemu")),
+        };
+        #[cfg(windows)]
+        let peer_pid = zbus::fdo::DBusProxy::new(conn)
+            .await?
+            .get_connection_unix_process_id(dest.clone())
+            .await?;
+        Self::new(
+            conn,
+            Some(dest),
+            #[cfg(windows)]
+            peer_pid,
+        )
+        .await
+    }
+}
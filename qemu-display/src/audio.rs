@@ -1,7 +1,14 @@
 #[cfg(windows)]
 use crate::win32::Fd;
+use async_broadcast::{broadcast, InactiveReceiver, Sender};
+use futures::Stream;
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
 #[cfg(windows)]
 use uds_windows::UnixStream;
 #[cfg(unix)]
@@ -9,9 +16,9 @@ use zbus::zvariant::Fd;
 use zbus::{dbus_interface, dbus_proxy, Connection};
 
 use crate::util;
-use crate::Result;
+use crate::{metrics, Error, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PCMInfo {
     pub bits: u8,
     pub is_signed: bool,
@@ -44,9 +51,23 @@ impl PCMInfo {
             rate = self.freq,
         )
     }
+
+    /// Replace the samples with silence.
+    pub fn silence(&self, data: &mut [u8]) {
+        data.fill(0);
+        if self.is_signed || self.is_float || self.bits < 8 {
+            return;
+        }
+        // unsigned samples are centered on their most significant bit
+        let size = self.bits as usize / 8;
+        let msb = if self.be { 0 } else { size - 1 };
+        for sample in data.chunks_exact_mut(size) {
+            sample[msb] = 0x80;
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Volume {
     pub mute: bool,
     pub volume: Vec<u8>,
@@ -65,13 +86,166 @@ trait Audio {
     fn register_in_listener(&self, listener: Fd) -> zbus::Result<()>;
 }
 
+/// The direction of an audio stream, from the guest point of view.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum AudioDirection {
+    Out,
+    In,
+}
+
+/// The state of a guest audio stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioStream {
+    pub id: u64,
+    pub direction: AudioDirection,
+    /// A label for the UI, such as "Playback 2".
+    ///
+    /// The D-Bus interface doesn't name the guest device: streams are numbered by
+    /// direction, in order of appearance, reusing the numbers of removed streams.
+    pub label: String,
+    pub info: PCMInfo,
+    pub enabled: bool,
+    /// The last volume set by the guest, if any.
+    pub volume: Option<Volume>,
+    /// Muted locally, with [`Audio::set_stream_muted`].
+    pub muted: bool,
+}
+
+/// A change of the audio streams, see [`Audio::receive_stream_changes`].
+#[derive(Debug, Clone)]
+pub enum AudioStreamChange {
+    Added(AudioStream),
+    Changed(AudioStream),
+    Removed(AudioDirection, u64),
+}
+
+// The stream states, updated by the listeners before calling the handlers.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct Streams {
+    streams: Mutex<HashMap<(AudioDirection, u64), AudioStream>>,
+    #[derivative(Debug = "ignore")]
+    sender: Sender<AudioStreamChange>,
+    #[derivative(Debug = "ignore")]
+    receiver: InactiveReceiver<AudioStreamChange>,
+}
+
+impl Streams {
+    fn new() -> Self {
+        let (mut sender, receiver) = broadcast(16);
+        sender.set_overflow(true);
+        Self {
+            streams: Default::default(),
+            sender,
+            receiver: receiver.deactivate(),
+        }
+    }
+
+    async fn init(&self, direction: AudioDirection, id: u64, info: &PCMInfo) {
+        let stream = {
+            let mut streams = self.streams.lock().unwrap();
+            let labels: Vec<_> = streams
+                .values()
+                .filter(|s| s.direction == direction && s.id != id)
+                .map(|s| s.label.clone())
+                .collect();
+            let kind = match direction {
+                AudioDirection::Out => "Playback",
+                AudioDirection::In => "Capture",
+            };
+            let label = (1..)
+                .map(|n| format!("{} {}", kind, n))
+                .find(|l| !labels.contains(l))
+                .unwrap();
+            let stream = AudioStream {
+                id,
+                direction,
+                label,
+                info: info.clone(),
+                enabled: false,
+                volume: None,
+                muted: false,
+            };
+            streams.insert((direction, id), stream.clone());
+            stream
+        };
+        let _ = self
+            .sender
+            .broadcast(AudioStreamChange::Added(stream))
+            .await;
+    }
+
+    async fn fini(&self, direction: AudioDirection, id: u64) {
+        if self
+            .streams
+            .lock()
+            .unwrap()
+            .remove(&(direction, id))
+            .is_some()
+        {
+            let _ = self
+                .sender
+                .broadcast(AudioStreamChange::Removed(direction, id))
+                .await;
+        }
+    }
+
+    // returns false if the stream is unknown
+    async fn update<F: FnOnce(&mut AudioStream)>(
+        &self,
+        direction: AudioDirection,
+        id: u64,
+        f: F,
+    ) -> bool {
+        let stream = match self.streams.lock().unwrap().get_mut(&(direction, id)) {
+            Some(stream) => {
+                f(stream);
+                stream.clone()
+            }
+            None => return false,
+        };
+        let _ = self
+            .sender
+            .broadcast(AudioStreamChange::Changed(stream))
+            .await;
+        true
+    }
+
+    // the silence format, if the stream is muted locally
+    fn muted(&self, direction: AudioDirection, id: u64) -> Option<PCMInfo> {
+        self.streams
+            .lock()
+            .unwrap()
+            .get(&(direction, id))
+            .filter(|s| s.muted)
+            .map(|s| s.info.clone())
+    }
+
+    // forget the streams of a replaced listener
+    async fn reset(&self, direction: AudioDirection) {
+        let ids: Vec<_> = self
+            .streams
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(d, _)| *d == direction)
+            .map(|(_, id)| *id)
+            .collect();
+        for id in ids {
+            self.fini(direction, id).await;
+        }
+    }
+}
+
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct Audio {
     #[derivative(Debug = "ignore")]
     pub proxy: AudioProxy<'static>,
-    out_listener: Option<Connection>,
-    in_listener: Option<Connection>,
+    // the listener connections, with a clone of their socket
+    out_listener: Option<(Connection, UnixStream)>,
+    in_listener: Option<(Connection, UnixStream)>,
+    streams: Arc<Streams>,
     #[cfg(windows)]
     peer_pid: u32,
 }
@@ -91,6 +265,7 @@ pub trait AudioOutHandler: 'static + Send + Sync {
 
 struct AudioOutListener<H: AudioOutHandler> {
     handler: H,
+    streams: Arc<Streams>,
 }
 
 #[dbus_interface(name = "org.qemu.Display1.AudioOutListener")]
@@ -108,49 +283,55 @@ impl<H: AudioOutHandler> AudioOutListener<H> {
         bytes_per_second: u32,
         be: bool,
     ) {
-        self.handler
-            .init(
-                id,
-                PCMInfo {
-                    bits,
-                    is_signed,
-                    is_float,
-                    freq,
-                    nchannels,
-                    bytes_per_frame,
-                    bytes_per_second,
-                    be,
-                },
-            )
-            .await
+        let info = PCMInfo {
+            bits,
+            is_signed,
+            is_float,
+            freq,
+            nchannels,
+            bytes_per_frame,
+            bytes_per_second,
+            be,
+        };
+        self.streams.init(AudioDirection::Out, id, &info).await;
+        self.handler.init(id, info).await
     }
 
     /// Fini method
     async fn fini(&mut self, id: u64) {
+        self.streams.fini(AudioDirection::Out, id).await;
         self.handler.fini(id).await
     }
 
     /// SetEnabled method
     async fn set_enabled(&mut self, id: u64, enabled: bool) {
+        self.streams
+            .update(AudioDirection::Out, id, |s| s.enabled = enabled)
+            .await;
         self.handler.set_enabled(id, enabled).await
     }
 
     /// SetVolume method
     async fn set_volume(&mut self, id: u64, mute: bool, volume: serde_bytes::ByteBuf) {
-        self.handler
-            .set_volume(
-                id,
-                Volume {
-                    mute,
-                    volume: volume.into_vec(),
-                },
-            )
-            .await
+        let volume = Volume {
+            mute,
+            volume: volume.into_vec(),
+        };
+        self.streams
+            .update(AudioDirection::Out, id, |s| s.volume = Some(volume.clone()))
+            .await;
+        self.handler.set_volume(id, volume).await
     }
 
     /// Write method
     async fn write(&mut self, id: u64, data: serde_bytes::ByteBuf) {
-        self.handler.write(id, data.into_vec()).await
+        let mut data = data.into_vec();
+        tracing::trace!(id, bytes = data.len(), "audio write");
+        metrics::AUDIO.write(data.len());
+        if let Some(info) = self.streams.muted(AudioDirection::Out, id) {
+            info.silence(&mut data);
+        }
+        self.handler.write(id, data).await
     }
 }
 
@@ -169,6 +350,7 @@ pub trait AudioInHandler: 'static + Send + Sync {
 
 struct AudioInListener<H: AudioInHandler> {
     handler: H,
+    streams: Arc<Streams>,
 }
 
 #[dbus_interface(name = "org.qemu.Display1.AudioInListener")]
@@ -186,51 +368,55 @@ impl<H: AudioInHandler> AudioInListener<H> {
         bytes_per_second: u32,
         be: bool,
     ) {
-        self.handler
-            .init(
-                id,
-                PCMInfo {
-                    bits,
-                    is_signed,
-                    is_float,
-                    freq,
-                    nchannels,
-                    bytes_per_frame,
-                    bytes_per_second,
-                    be,
-                },
-            )
-            .await
+        let info = PCMInfo {
+            bits,
+            is_signed,
+            is_float,
+            freq,
+            nchannels,
+            bytes_per_frame,
+            bytes_per_second,
+            be,
+        };
+        self.streams.init(AudioDirection::In, id, &info).await;
+        self.handler.init(id, info).await
     }
 
     /// Fini method
     async fn fini(&mut self, id: u64) {
+        self.streams.fini(AudioDirection::In, id).await;
         self.handler.fini(id).await
     }
 
     /// SetEnabled method
     async fn set_enabled(&mut self, id: u64, enabled: bool) {
+        self.streams
+            .update(AudioDirection::In, id, |s| s.enabled = enabled)
+            .await;
         self.handler.set_enabled(id, enabled).await
     }
 
     /// SetVolume method
     async fn set_volume(&mut self, id: u64, mute: bool, volume: serde_bytes::ByteBuf) {
-        self.handler
-            .set_volume(
-                id,
-                Volume {
-                    mute,
-                    volume: volume.into_vec(),
-                },
-            )
-            .await
+        let volume = Volume {
+            mute,
+            volume: volume.into_vec(),
+        };
+        self.streams
+            .update(AudioDirection::In, id, |s| s.volume = Some(volume.clone()))
+            .await;
+        self.handler.set_volume(id, volume).await
     }
 
     /// Read method
     async fn read(&mut self, id: u64, size: u64) -> Vec<u8> {
-        self.handler.read(id, size).await
-        // dbg!((id, size));
-        // vec![0; size as usize]
+        let mut data = self.handler.read(id, size).await;
+        tracing::trace!(id, bytes = data.len(), "audio read");
+        metrics::AUDIO.read(data.len());
+        if let Some(info) = self.streams.muted(AudioDirection::In, id) {
+            info.silence(&mut data);
+        }
+        data
     }
 }
 
@@ -241,6 +427,7 @@ impl Audio {
             proxy,
             in_listener: None,
             out_listener: None,
+            streams: Arc::new(Streams::new()),
             #[cfg(windows)]
             peer_pid,
         })
@@ -253,16 +440,24 @@ impl Audio {
             self.peer_pid,
             &p0,
         )?;
+        // QEMU replaces the previous listener, and will init the streams again
+        self.streams.reset(AudioDirection::Out).await;
         self.proxy.register_out_listener(p0).await?;
+        let socket = p1.try_clone()?;
         let c = zbus::ConnectionBuilder::unix_stream(p1)
             .p2p()
             .serve_at(
                 "/org/qemu/Display1/AudioOutListener",
-                AudioOutListener { handler },
+                AudioOutListener {
+                    handler,
+                    streams: self.streams.clone(),
+                },
             )?
             .build()
             .await?;
-        self.out_listener.replace(c);
+        if let Some((conn, socket)) = self.out_listener.replace((c, socket)) {
+            util::close_p2p(conn, &socket).await;
+        }
         Ok(())
     }
 
@@ -273,16 +468,79 @@ impl Audio {
             self.peer_pid,
             &p0,
         )?;
+        // QEMU replaces the previous listener, and will init the streams again
+        self.streams.reset(AudioDirection::In).await;
         self.proxy.register_in_listener(p0).await?;
+        let socket = p1.try_clone()?;
         let c = zbus::ConnectionBuilder::unix_stream(p1)
             .p2p()
             .serve_at(
                 "/org/qemu/Display1/AudioInListener",
-                AudioInListener { handler },
+                AudioInListener {
+                    handler,
+                    streams: self.streams.clone(),
+                },
             )?
             .build()
             .await?;
-        self.in_listener.replace(c);
+        if let Some((conn, socket)) = self.in_listener.replace((c, socket)) {
+            util::close_p2p(conn, &socket).await;
+        }
         Ok(())
     }
+
+    /// Disconnect the listeners, and wait until they are no longer served.
+    ///
+    /// The known streams are removed, as reported by [`Audio::receive_stream_changes`].
+    pub async fn close(mut self) {
+        for (direction, listener) in [
+            (AudioDirection::Out, self.out_listener.take()),
+            (AudioDirection::In, self.in_listener.take()),
+        ] {
+            if let Some((conn, socket)) = listener {
+                util::close_p2p(conn, &socket).await;
+                self.streams.reset(direction).await;
+            }
+        }
+    }
+
+    /// The current guest audio streams, in both directions.
+    ///
+    /// The streams are only known while a listener is registered for their direction.
+    pub fn streams(&self) -> Vec<AudioStream> {
+        let mut streams: Vec<_> = self
+            .streams
+            .streams
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        streams.sort_by_key(|s| (s.direction == AudioDirection::In, s.id));
+        streams
+    }
+
+    /// Mute a stream locally: silence is played or recorded instead, regardless of the guest
+    /// volume.
+    pub async fn set_stream_muted(
+        &self,
+        direction: AudioDirection,
+        id: u64,
+        muted: bool,
+    ) -> Result<()> {
+        if self
+            .streams
+            .update(direction, id, |s| s.muted = muted)
+            .await
+        {
+            Ok(())
+        } else {
+            Err(Error::Failed(format!("Unknown audio stream {}", id)))
+        }
+    }
+
+    /// A stream of the audio stream changes, to present a mixer for example.
+    pub fn receive_stream_changes(&self) -> Pin<Box<dyn Stream<Item = AudioStreamChange> + Send>> {
+        Box::pin(self.streams.receiver.activate_cloned())
+    }
 }
@@ -0,0 +1,144 @@
+//! Blocking wrappers, for simple programs and scripts.
+//!
+//! Like [`zbus::blocking`], the types wrap their asynchronous counterparts and block the
+//! calling thread until the operation completes. The D-Bus connection must be driven by its
+//! internal executor thread (the default), and the methods must not be called from an async
+//! context.
+
+use async_io::block_on;
+use std::convert::TryInto;
+use zbus::names::BusName;
+
+use crate::{Console, ConsoleInfo, Display, Error, KeyboardProxy, Result, RgbaImage};
+
+/// A blocking wrapper of [`Display`].
+#[derive(Clone)]
+pub struct BlockingDisplay {
+    inner: Display<'static>,
+}
+
+impl BlockingDisplay {
+    pub fn new<D>(
+        conn: &zbus::blocking::Connection,
+        dest: Option<D>,
+        #[cfg(windows)] peer_pid: u32,
+    ) -> Result<Self>
+    where
+        D: TryInto<BusName<'static>>,
+        D::Error: Into<Error>,
+    {
+        block_on(Display::new(
+            conn.inner(),
+            dest,
+            #[cfg(windows)]
+            peer_pid,
+        ))
+        .map(Self::from)
+    }
+
+    pub fn consoles(&self) -> Result<Vec<ConsoleInfo>> {
+        block_on(self.inner.consoles())
+    }
+
+    pub fn console(&self, idx: u32) -> Result<BlockingConsole> {
+        block_on(Console::new(
+            self.inner.connection(),
+            idx,
+            #[cfg(windows)]
+            self.inner.peer_pid(),
+        ))
+        .map(BlockingConsole::from)
+    }
+
+    pub fn inner(&self) -> &Display<'static> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> Display<'static> {
+        self.inner
+    }
+}
+
+impl From<Display<'static>> for BlockingDisplay {
+    fn from(inner: Display<'static>) -> Self {
+        Self { inner }
+    }
+}
+
+/// A blocking wrapper of [`Console`].
+#[derive(Debug)]
+pub struct BlockingConsole {
+    inner: Console,
+}
+
+impl BlockingConsole {
+    pub fn info(&self) -> Result<ConsoleInfo> {
+        block_on(self.inner.info())
+    }
+
+    pub fn width(&self) -> Result<u32> {
+        block_on(self.inner.width())
+    }
+
+    pub fn height(&self) -> Result<u32> {
+        block_on(self.inner.height())
+    }
+
+    /// Capture the current console content, see [`Console::screenshot`].
+    pub fn screenshot(&self) -> Result<RgbaImage> {
+        block_on(self.inner.screenshot())
+    }
+
+    pub fn keyboard(&self) -> BlockingKeyboard {
+        BlockingKeyboard::from(self.inner.keyboard.clone())
+    }
+
+    pub fn inner(&self) -> &Console {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> Console {
+        self.inner
+    }
+}
+
+impl From<Console> for BlockingConsole {
+    fn from(inner: Console) -> Self {
+        Self { inner }
+    }
+}
+
+/// A blocking wrapper of [`KeyboardProxy`].
+#[derive(Debug, Clone)]
+pub struct BlockingKeyboard {
+    inner: KeyboardProxy<'static>,
+}
+
+impl BlockingKeyboard {
+    pub fn press(&self, keycode: u32) -> Result<()> {
+        Ok(block_on(self.inner.press(keycode))?)
+    }
+
+    pub fn release(&self, keycode: u32) -> Result<()> {
+        Ok(block_on(self.inner.release(keycode))?)
+    }
+
+    /// Type the text with key presses, see [`KeyboardProxy::type_text`].
+    pub fn type_text(&self, text: &str) -> Result<()> {
+        block_on(self.inner.type_text(text))
+    }
+
+    pub fn inner(&self) -> &KeyboardProxy<'static> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> KeyboardProxy<'static> {
+        self.inner
+    }
+}
+
+impl From<KeyboardProxy<'static>> for BlockingKeyboard {
+    fn from(inner: KeyboardProxy<'static>) -> Self {
+        Self { inner }
+    }
+}
@@ -0,0 +1,412 @@
+use async_broadcast::{broadcast, Receiver, Sender};
+use futures::{Stream, StreamExt};
+#[cfg(unix)]
+use std::ptr;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    frame_size, Error, FrameSink, ListenerConnection, PixelFormat, Rect, Result, Scanout, Update,
+};
+#[cfg(unix)]
+use crate::{ScanoutDMABUF, UpdateDMABUF, PIXMAN_X8R8G8B8};
+#[cfg(windows)]
+use crate::{ScanoutMap, UpdateMap};
+
+#[cfg(unix)]
+const DRM_FORMAT_XRGB8888: u32 = 0x34325258;
+#[cfg(unix)]
+const DRM_FORMAT_ARGB8888: u32 = 0x34325241;
+#[cfg(unix)]
+const DRM_FORMAT_XBGR8888: u32 = 0x34324258;
+#[cfg(unix)]
+const DRM_FORMAT_ABGR8888: u32 = 0x34324241;
+#[cfg(unix)]
+const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+// The 32-bit DMABUF pixel layouts, from the most significant byte.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+enum Layout {
+    Xrgb,
+    Argb,
+    Xbgr,
+    Abgr,
+}
+
+#[cfg(unix)]
+impl Layout {
+    fn from_fourcc(fourcc: u32) -> Result<Self> {
+        Ok(match fourcc {
+            DRM_FORMAT_XRGB8888 => Self::Xrgb,
+            DRM_FORMAT_ARGB8888 => Self::Argb,
+            DRM_FORMAT_XBGR8888 => Self::Xbgr,
+            DRM_FORMAT_ABGR8888 => Self::Abgr,
+            _ => {
+                return Err(Error::Failed(format!(
+                    "Unsupported DMABUF format 0x{:x}",
+                    fourcc
+                )))
+            }
+        })
+    }
+
+    fn rgba(self, v: u32) -> [u8; 4] {
+        let [b3, b2, b1, b0] = v.to_be_bytes();
+        match self {
+            Self::Xrgb => [b2, b1, b0, 0xff],
+            Self::Argb => [b2, b1, b0, b3],
+            Self::Xbgr => [b0, b1, b2, 0xff],
+            Self::Abgr => [b0, b1, b2, b3],
+        }
+    }
+}
+
+/// An image with 8-bit R, G, B, A samples, without padding.
+#[derive(derivative::Derivative, Clone, PartialEq, Eq)]
+#[derivative(Debug)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    #[derivative(Debug = "ignore")]
+    pub data: Vec<u8>,
+}
+
+impl RgbaImage {
+    /// Convert a pixman frame, of the formats of [`PixelFormat`].
+    pub fn from_pixman(
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: u32,
+        data: &[u8],
+    ) -> Result<Self> {
+        let mut image = Self {
+            width,
+            height,
+            data: vec![0; width as usize * height as usize * 4],
+        };
+        image.blit(0, 0, width, height, stride, format, data)?;
+        Ok(image)
+    }
+
+    /// Convert the pixman pixels of a region.
+    #[allow(clippy::too_many_arguments)]
+    fn blit(
+        &mut self,
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+        stride: u32,
+        format: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        let pf = PixelFormat::from_pixman(format)?;
+        if frame_size(w, h, stride, format)? > data.len()
+            || x + w > self.width
+            || y + h > self.height
+        {
+            return Err(Error::Failed(format!(
+                "Invalid region {:?} of {}x{}",
+                (x, y, w, h),
+                self.width,
+                self.height
+            )));
+        }
+        for row in 0..h as usize {
+            let bpp = pf.bytes_per_pixel();
+            let src = &data[row * stride as usize..][..w as usize * bpp];
+            let dst_start = ((y as usize + row) * self.width as usize + x as usize) * 4;
+            let dst = &mut self.data[dst_start..dst_start + w as usize * 4];
+            for (s, d) in src.chunks_exact(bpp).zip(dst.chunks_exact_mut(4)) {
+                d.copy_from_slice(&pf.rgba(s));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare with another image of the same size, ignoring the alpha channel.
+    ///
+    /// The pixels differ if a sample differs by more than `tolerance`.
+    pub fn diff(&self, other: &RgbaImage, tolerance: u8) -> Result<ImageDiff> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return Err(Error::Failed(format!(
+                "Different image sizes: {}x{} and {}x{}",
+                self.width, self.height, other.width, other.height
+            )));
+        }
+        let mut diff = ImageDiff::default();
+        let pixels = self.data.chunks_exact(4).zip(other.data.chunks_exact(4));
+        for (i, (a, b)) in pixels.enumerate() {
+            if a[..3]
+                .iter()
+                .zip(&b[..3])
+                .all(|(a, b)| a.abs_diff(*b) <= tolerance)
+            {
+                continue;
+            }
+            let (x, y) = (i as u32 % self.width, i as u32 / self.width);
+            diff.pixels += 1;
+            diff.bounds = Some(match diff.bounds {
+                Some(bounds) => bounds.union(&Rect::new(x, y, 1, 1)),
+                None => Rect::new(x, y, 1, 1),
+            });
+        }
+        Ok(diff)
+    }
+
+    /// Read and convert a linear DMABUF.
+    #[cfg(unix)]
+    pub fn from_dmabuf(scanout: &ScanoutDMABUF) -> Result<Self> {
+        Self::from_dmabuf_rect(scanout, 0, 0, scanout.width, scanout.height)
+    }
+
+    /// Read and convert the region at `x`, `y` of size `w`×`h` of a linear DMABUF.
+    #[cfg(unix)]
+    pub fn from_dmabuf_rect(
+        scanout: &ScanoutDMABUF,
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+    ) -> Result<Self> {
+        let layout = Layout::from_fourcc(scanout.fourcc)?;
+        if scanout.modifier != DRM_FORMAT_MOD_LINEAR {
+            return Err(Error::Failed(format!(
+                "Unsupported DMABUF modifier 0x{:x}",
+                scanout.modifier
+            )));
+        }
+        let (width, height, stride) = (scanout.width, scanout.height, scanout.stride as usize);
+        let inside =
+            |pos: u32, size: u32, max: u32| pos.checked_add(size).is_some_and(|end| end <= max);
+        if !inside(x, w, width) || !inside(y, h, height) {
+            return Err(Error::Failed("DMABUF region out of bounds".into()));
+        }
+        let len = frame_size(width, height, scanout.stride, PIXMAN_X8R8G8B8)?;
+        let mut image = Self {
+            width: w,
+            height: h,
+            data: vec![0; w as usize * h as usize * 4],
+        };
+        if len == 0 || image.data.is_empty() {
+            return Ok(image);
+        }
+
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                scanout.fd,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        dmabuf_sync(scanout.fd, DMA_BUF_SYNC_START);
+        let src = unsafe { std::slice::from_raw_parts(map as *const u8, len) };
+        let row_len = w as usize * 4;
+        for row in 0..h as usize {
+            // the DMABUF rows may be bottom-up
+            let src_row = if scanout.y0_top {
+                y as usize + row
+            } else {
+                height as usize - 1 - (y as usize + row)
+            };
+            let src = &src[src_row * stride + x as usize * 4..][..row_len];
+            let dst = &mut image.data[row * row_len..][..row_len];
+            for (s, d) in src.chunks_exact(4).zip(dst.chunks_exact_mut(4)) {
+                // DRM formats are little-endian
+                d.copy_from_slice(&layout.rgba(u32::from_le_bytes([s[0], s[1], s[2], s[3]])));
+            }
+        }
+        dmabuf_sync(scanout.fd, DMA_BUF_SYNC_END);
+        unsafe { libc::munmap(map, len) };
+        Ok(image)
+    }
+}
+
+#[cfg(unix)]
+const DMA_BUF_SYNC_START: u64 = 0;
+#[cfg(unix)]
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+// Bracket the CPU access, for the caches coherency.
+#[cfg(unix)]
+fn dmabuf_sync(fd: std::os::unix::io::RawFd, flags: u64) {
+    #[cfg(target_os = "linux")]
+    {
+        const DMA_BUF_SYNC_READ: u64 = 1;
+        // _IOW('b', 0, struct dma_buf_sync)
+        const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x40086200;
+
+        let sync = flags | DMA_BUF_SYNC_READ;
+        // failure is not fatal, some exporters don't implement it
+        unsafe { libc::ioctl(fd, DMA_BUF_IOCTL_SYNC as _, &sync) };
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (fd, flags);
+}
+
+/// The differences between two images, see [`RgbaImage::diff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImageDiff {
+    /// The number of differing pixels.
+    pub pixels: u64,
+    /// The smallest rectangle containing the differing pixels.
+    pub bounds: Option<Rect>,
+}
+
+impl ImageDiff {
+    pub fn is_empty(&self) -> bool {
+        self.pixels == 0
+    }
+}
+
+/// A frame of a [`Recording`].
+#[derive(Debug, Clone)]
+pub struct RecordedFrame {
+    /// The time since the recording started.
+    pub timestamp: Duration,
+    pub image: RgbaImage,
+}
+
+// errors are sent as strings, the channel items must be Clone
+type CaptureResult = std::result::Result<RecordedFrame, String>;
+
+/// Keeps a copy of the console content, and sends it on each change.
+pub(crate) struct CaptureSink {
+    start: Instant,
+    frame: Option<RgbaImage>,
+    // the current scanout, read again on its updates
+    #[cfg(unix)]
+    dmabuf: Option<ScanoutDMABUF>,
+    sender: Option<Sender<CaptureResult>>,
+}
+
+impl CaptureSink {
+    pub(crate) fn new() -> (Self, Receiver<CaptureResult>) {
+        // only the last frame is kept, for the slow consumers
+        let (mut sender, receiver) = broadcast(1);
+        sender.set_overflow(true);
+        let sink = Self {
+            start: Instant::now(),
+            frame: None,
+            #[cfg(unix)]
+            dmabuf: None,
+            sender: Some(sender),
+        };
+        (sink, receiver)
+    }
+
+    fn send(&mut self, res: Result<()>) {
+        let res = match res {
+            Ok(()) => match &self.frame {
+                Some(image) => Ok(RecordedFrame {
+                    timestamp: self.start.elapsed(),
+                    image: image.clone(),
+                }),
+                None => return,
+            },
+            Err(e) => Err(e.to_string()),
+        };
+        if let Some(sender) = &self.sender {
+            if sender.try_broadcast(res).is_err() {
+                // no more receivers
+                self.sender = None;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameSink for CaptureSink {
+    async fn on_scanout(&mut self, s: Scanout) {
+        let res = RgbaImage::from_pixman(s.width, s.height, s.stride, s.format, &s.data)
+            .map(|image| self.frame = Some(image));
+        self.send(res);
+    }
+
+    async fn on_update(&mut self, u: Update) {
+        let res = match &mut self.frame {
+            Some(frame) if u.x >= 0 && u.y >= 0 && u.w >= 0 && u.h >= 0 => frame.blit(
+                u.x as _, u.y as _, u.w as _, u.h as _, u.stride, u.format, &u.data,
+            ),
+            Some(_) => Err(Error::Failed(format!("Invalid update: {:?}", u))),
+            None => return,
+        };
+        self.send(res);
+    }
+
+    #[cfg(windows)]
+    async fn on_scanout_map(&mut self, _scanout: ScanoutMap) {
+        self.frame = None;
+        self.send(Err(Error::Failed("Can't capture a mapped scanout".into())));
+    }
+
+    #[cfg(windows)]
+    async fn on_update_map(&mut self, _update: UpdateMap) {}
+
+    #[cfg(unix)]
+    async fn on_scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        // read it right away: a static display may not be updated for a while
+        let res = RgbaImage::from_dmabuf(&scanout).map(|image| self.frame = Some(image));
+        if res.is_err() {
+            self.frame = None;
+        }
+        self.dmabuf = Some(scanout);
+        self.send(res);
+    }
+
+    #[cfg(unix)]
+    async fn on_update_dmabuf(&mut self, _update: UpdateDMABUF) {
+        let res = match &self.dmabuf {
+            Some(scanout) => RgbaImage::from_dmabuf(scanout).map(|image| self.frame = Some(image)),
+            None => return,
+        };
+        self.send(res);
+    }
+
+    fn on_disconnected(&mut self) {
+        self.sender = None;
+    }
+}
+
+/// The frames of a console, see [`Console::record`](crate::Console::record).
+///
+/// The stream ends when the listener is disconnected. A slow consumer only gets the latest
+/// frame.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Recording {
+    #[derivative(Debug = "ignore")]
+    receiver: Receiver<CaptureResult>,
+    listener: ListenerConnection,
+}
+
+impl Recording {
+    pub(crate) fn new(receiver: Receiver<CaptureResult>, listener: ListenerConnection) -> Self {
+        Self { receiver, listener }
+    }
+
+    pub fn listener(&self) -> &ListenerConnection {
+        &self.listener
+    }
+}
+
+impl Stream for Recording {
+    type Item = Result<RecordedFrame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver
+            .poll_next_unpin(cx)
+            .map(|res| res.map(|res| res.map_err(Error::Failed)))
+    }
+}
@@ -1,16 +1,35 @@
 #[cfg(windows)]
 use crate::win32::Fd;
-use std::convert::TryFrom;
+use async_io::Async;
+use futures::{
+    channel::mpsc,
+    io::{AsyncRead, AsyncWrite},
+    Stream, StreamExt,
+};
+#[cfg(unix)]
+use std::os::unix::{
+    io::{AsRawFd, IntoRawFd, RawFd},
+    net::UnixStream,
+};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(windows)]
+use uds_windows::UnixStream;
 #[cfg(unix)]
 use zbus::zvariant::Fd;
-use zbus::{dbus_proxy, zvariant::ObjectPath};
+use zbus::{dbus_proxy, zvariant::ObjectPath, PropertyChanged, Task};
 
-use crate::Result;
+use crate::{util, Error, Result, RetryPolicy};
 
 #[dbus_proxy(default_service = "org.qemu", interface = "org.qemu.Display1.Chardev")]
 pub trait Chardev {
     /// Register method
-    #[cfg(unix)]
     fn register(&self, stream: Fd) -> zbus::Result<()>;
 
     /// SendBreak method
@@ -37,12 +56,249 @@ pub trait Chardev {
 #[derivative(Debug)]
 pub struct Chardev {
     pub proxy: ChardevProxy<'static>,
+    #[cfg(windows)]
+    pub(crate) peer_pid: u32,
 }
 
 impl Chardev {
-    pub async fn new(conn: &zbus::Connection, id: &str) -> Result<Self> {
+    pub async fn new(
+        conn: &zbus::Connection,
+        id: &str,
+        #[cfg(windows)] peer_pid: u32,
+    ) -> Result<Self> {
         let obj_path = ObjectPath::try_from(format!("/org/qemu/Display1/Chardev_{}", id))?;
         let proxy = ChardevProxy::builder(conn).path(&obj_path)?.build().await?;
-        Ok(Self { proxy })
+        Ok(Self {
+            proxy,
+            #[cfg(windows)]
+            peer_pid,
+        })
+    }
+
+    /// Connect to the chardev, replacing the current client, if any.
+    ///
+    /// One end of a socket pair is handed to QEMU (on Windows, as a socket duplicated for
+    /// the QEMU process), the other end is returned.
+    pub async fn open_stream(&self) -> Result<ChardevStream> {
+        let stream = register_stream(
+            &self.proxy,
+            #[cfg(windows)]
+            self.peer_pid,
+        )
+        .await?;
+        Ok(ChardevStream { stream })
+    }
+
+    /// Connect to the chardev, with an asynchronous stream.
+    ///
+    /// Fails with [`Error::InUse`] if another D-Bus client owns the chardev. The connection
+    /// is registered again each time the frontend is re-opened (when the guest re-opens a
+    /// serial port for example), so that it survives the frontend resets. A failed
+    /// re-registration is retried a few times.
+    pub async fn connect(&self) -> Result<ChardevConnection> {
+        let policy = RetryPolicy::default().with_max_attempts(5);
+        self.connect_with_policy(policy).await
+    }
+
+    /// Like [`Chardev::connect`], retrying the re-registrations with `policy`.
+    pub async fn connect_with_policy(&self, policy: RetryPolicy) -> Result<ChardevConnection> {
+        let owner = self.proxy.owner().await?;
+        let ours = self.proxy.connection().unique_name().map(|n| n.as_str());
+        if !owner.is_empty() && Some(owner.as_str()) != ours {
+            return Err(Error::InUse(owner));
+        }
+
+        // subscribe before registering, to not miss a re-open
+        let changes = self.proxy.receive_fe_opened_changed().await;
+        let opened = self.proxy.fe_opened().await?;
+        let stream = register_stream(
+            &self.proxy,
+            #[cfg(windows)]
+            self.peer_pid,
+        )
+        .await?;
+        let (sender, streams) = mpsc::unbounded();
+        let task = self.proxy.connection().executor().spawn(reconnect_on_open(
+            self.proxy.clone(),
+            #[cfg(windows)]
+            self.peer_pid,
+            opened,
+            changes,
+            sender,
+            policy,
+        ));
+        Ok(ChardevConnection {
+            stream: Async::new(stream)?,
+            streams,
+            _task: task,
+        })
+    }
+}
+
+async fn register_stream(
+    proxy: &ChardevProxy<'_>,
+    #[cfg(windows)] peer_pid: u32,
+) -> Result<UnixStream> {
+    let (p0, p1) = UnixStream::pair()?;
+    let fd = util::prepare_uds_pass(
+        #[cfg(windows)]
+        peer_pid,
+        &p1,
+    )?;
+    proxy.register(fd).await?;
+    Ok(p0)
+}
+
+async fn reconnect_on_open(
+    proxy: ChardevProxy<'static>,
+    #[cfg(windows)] peer_pid: u32,
+    mut opened: bool,
+    mut changes: impl Stream<Item = PropertyChanged<'static, bool>> + Send + Unpin,
+    sender: mpsc::UnboundedSender<Async<UnixStream>>,
+    policy: RetryPolicy,
+) {
+    while let Some(change) = changes.next().await {
+        let now = match change.get().await {
+            Ok(now) => now,
+            Err(e) => {
+                log::warn!("Failed to get the chardev FEOpened: {}", e);
+                continue;
+            }
+        };
+        if now && !opened {
+            log::debug!("Chardev frontend re-opened, reconnecting");
+            let stream = policy
+                .retry("chardev reconnection", || async {
+                    let stream = register_stream(
+                        &proxy,
+                        #[cfg(windows)]
+                        peer_pid,
+                    )
+                    .await?;
+                    Ok(Async::new(stream)?)
+                })
+                .await;
+            match stream {
+                Ok(stream) => {
+                    if sender.unbounded_send(stream).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => log::warn!("Failed to reconnect the chardev: {}", e),
+            }
+        }
+        opened = now;
+    }
+}
+
+/// An asynchronous chardev connection, see [`Chardev::connect`].
+///
+/// On reconnection, the pending reads and writes continue on the new stream.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ChardevConnection {
+    stream: Async<UnixStream>,
+    #[derivative(Debug = "ignore")]
+    streams: mpsc::UnboundedReceiver<Async<UnixStream>>,
+    #[derivative(Debug = "ignore")]
+    _task: Task<()>,
+}
+
+impl ChardevConnection {
+    fn poll_reconnected(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some(stream)) = self.streams.poll_next_unpin(cx) {
+            self.stream = stream;
+        }
+    }
+}
+
+impl AsyncRead for ChardevConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_reconnected(cx);
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ChardevConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_reconnected(cx);
+        Pin::new(&mut this.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_close(cx)
+    }
+}
+
+/// A chardev connection, see [`Chardev::open_stream`].
+#[derive(Debug)]
+pub struct ChardevStream {
+    stream: UnixStream,
+}
+
+impl ChardevStream {
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+        })
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        Ok(self.stream.set_nonblocking(nonblocking)?)
+    }
+
+    pub fn into_inner(self) -> UnixStream {
+        self.stream
+    }
+}
+
+impl Read for ChardevStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for ChardevStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for ChardevStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for ChardevStream {
+    fn into_raw_fd(self) -> RawFd {
+        self.stream.into_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for ChardevStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.stream.as_raw_socket()
     }
 }
@@ -1,9 +1,14 @@
+use futures::future::BoxFuture;
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use std::convert::TryFrom;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+};
 use zbus::{dbus_interface, dbus_proxy, zvariant::ObjectPath};
 use zvariant::Type;
 
-use crate::Result;
+use crate::{Error, GuestDefaults, Result};
 
 #[repr(u32)]
 #[derive(Deserialize_repr, Serialize_repr, Type, Debug, Hash, PartialEq, Eq, Clone, Copy)]
@@ -51,6 +56,56 @@ pub trait ClipboardHandler: 'static + Send + Sync {
     ) -> Result<(String, Vec<u8>)>;
 }
 
+/// A hook to confirm the guest may read the host clipboard.
+#[async_trait::async_trait]
+pub trait ClipboardConfirm: 'static + Send + Sync {
+    async fn confirm(&mut self, selection: ClipboardSelection, mimes: &[String]) -> bool;
+}
+
+/// Paste on demand: clipboard data is only handed to the guest when the request is confirmed
+/// (typically, by asking the user), instead of on every guest request.
+#[derive(Debug)]
+pub struct PasteOnDemand<H: ClipboardHandler, C: ClipboardConfirm> {
+    handler: H,
+    confirm: C,
+}
+
+impl<H: ClipboardHandler, C: ClipboardConfirm> PasteOnDemand<H, C> {
+    pub fn new(handler: H, confirm: C) -> Self {
+        Self { handler, confirm }
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: ClipboardHandler, C: ClipboardConfirm> ClipboardHandler for PasteOnDemand<H, C> {
+    async fn register(&mut self) {
+        self.handler.register().await;
+    }
+
+    async fn unregister(&mut self) {
+        self.handler.unregister().await;
+    }
+
+    async fn grab(&mut self, selection: ClipboardSelection, serial: u32, mimes: Vec<String>) {
+        self.handler.grab(selection, serial, mimes).await;
+    }
+
+    async fn release(&mut self, selection: ClipboardSelection) {
+        self.handler.release(selection).await;
+    }
+
+    async fn request(
+        &mut self,
+        selection: ClipboardSelection,
+        mimes: Vec<String>,
+    ) -> Result<(String, Vec<u8>)> {
+        if !self.confirm.confirm(selection, &mimes).await {
+            return Err(Error::Failed("Clipboard request denied".into()));
+        }
+        self.handler.request(selection, mimes).await
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct ClipboardListener<H: ClipboardHandler> {
     handler: H,
@@ -86,12 +141,27 @@ impl<H: ClipboardHandler> ClipboardListener<H> {
     }
 }
 
+// removes the listener of a handler type from the object server
+type RemoveListenerFn = fn(zbus::Connection) -> BoxFuture<'static, zbus::Result<bool>>;
+
+fn remove_listener<H: ClipboardHandler>(
+    conn: zbus::Connection,
+) -> BoxFuture<'static, zbus::Result<bool>> {
+    Box::pin(async move {
+        conn.object_server()
+            .remove::<ClipboardListener<H>, _>("/org/qemu/Display1/Clipboard")
+            .await
+    })
+}
+
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct Clipboard {
     #[derivative(Debug = "ignore")]
     pub proxy: ClipboardProxy<'static>,
     conn: zbus::Connection,
+    #[derivative(Debug = "ignore")]
+    remove_listener: Mutex<Option<RemoveListenerFn>>,
 }
 
 impl Clipboard {
@@ -104,6 +174,7 @@ impl Clipboard {
         Ok(Self {
             proxy,
             conn: conn.clone(),
+            remove_listener: Mutex::new(None),
         })
     }
 
@@ -116,6 +187,310 @@ impl Clipboard {
             )
             .await
             .unwrap();
+        self.remove_listener
+            .lock()
+            .unwrap()
+            .replace(remove_listener::<H>);
         Ok(self.proxy.register().await?)
     }
+
+    /// Unregister from QEMU, and remove the listener from the connection.
+    pub async fn close(self) -> Result<()> {
+        let remove = self.remove_listener.lock().unwrap().take();
+        if let Some(remove) = remove {
+            let res = self.proxy.unregister().await;
+            remove(self.conn.clone()).await?;
+            res?;
+        }
+        Ok(())
+    }
+}
+
+const TEXT_MIMES: &[&str] = &[
+    "text/plain;charset=utf-8",
+    "text/plain",
+    "UTF8_STRING",
+    "STRING",
+    "TEXT",
+];
+const URI_LIST_MIME: &str = "text/uri-list";
+const PNG_MIME: &str = "image/png";
+
+/// The kind of clipboard content a mime type carries, in priority order.
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum ClipboardContent {
+    Text,
+    Image,
+    UriList,
+}
+
+impl ClipboardContent {
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        if mime == URI_LIST_MIME {
+            Some(Self::UriList)
+        } else if TEXT_MIMES.contains(&mime) {
+            Some(Self::Text)
+        } else if mime.starts_with("image/") {
+            Some(Self::Image)
+        } else {
+            None
+        }
+    }
+}
+
+// rank of a mime within its content kind, lower is preferred
+fn mime_rank(mime: &str) -> usize {
+    TEXT_MIMES
+        .iter()
+        .position(|m| *m == mime)
+        .unwrap_or_else(|| if mime == PNG_MIME { 0 } else { 1 })
+}
+
+/// Keep the supported mime types (text, image and uri-list), ordered by preference.
+pub fn filter_mimes<S: AsRef<str>>(mimes: &[S]) -> Vec<String> {
+    let mut res: Vec<_> = mimes
+        .iter()
+        .map(AsRef::as_ref)
+        .filter_map(|m| ClipboardContent::from_mime(m).map(|c| (c, mime_rank(m), m)))
+        .collect();
+    res.sort();
+    res.dedup();
+    res.into_iter().map(|(_, _, m)| m.to_string()).collect()
+}
+
+#[derive(Debug, Default)]
+struct SelectionState {
+    serial: u32,
+    // the mimes of the current peer grab
+    peer_mimes: Option<Vec<String>>,
+}
+
+/// Clipboard bookkeeping common to the frontends.
+///
+/// The manager tracks the grab serials of each selection, so that stale peer grabs are
+/// ignored, and a local grab that merely re-advertises the peer content is not sent back.
+/// The [`ClipboardHandler`] should call [`reset`], [`peer_grab`] and [`peer_release`].
+///
+/// [`reset`]: ClipboardManager::reset
+/// [`peer_grab`]: ClipboardManager::peer_grab
+/// [`peer_release`]: ClipboardManager::peer_release
+#[derive(derivative::Derivative, Clone)]
+#[derivative(Debug)]
+pub struct ClipboardManager {
+    #[derivative(Debug = "ignore")]
+    proxy: ClipboardProxy<'static>,
+    selections: Arc<Mutex<HashMap<ClipboardSelection, SelectionState>>>,
+}
+
+impl ClipboardManager {
+    pub fn new(clipboard: &Clipboard) -> Self {
+        Self {
+            proxy: clipboard.proxy.clone(),
+            selections: Default::default(),
+        }
+    }
+
+    /// Forget the grabs and serials, when the peer (un)registers.
+    pub fn reset(&self) {
+        self.selections.lock().unwrap().clear();
+    }
+
+    /// Record a peer grab, and return whether it should be handled.
+    pub fn peer_grab(&self, selection: ClipboardSelection, serial: u32, mimes: &[String]) -> bool {
+        let mut selections = self.selections.lock().unwrap();
+        let state = selections.entry(selection).or_default();
+        if serial < state.serial {
+            log::debug!("Ignored peer grab: {} < {}", serial, state.serial);
+            return false;
+        }
+        state.serial = serial;
+        state.peer_mimes = Some(filter_mimes(mimes));
+        true
+    }
+
+    pub fn peer_release(&self, selection: ClipboardSelection) {
+        if let Some(state) = self.selections.lock().unwrap().get_mut(&selection) {
+            state.peer_mimes = None;
+        }
+    }
+
+    /// The supported mimes of the current peer grab, by preference.
+    pub fn peer_mimes(&self, selection: ClipboardSelection) -> Option<Vec<String>> {
+        self.selections
+            .lock()
+            .unwrap()
+            .get(&selection)
+            .and_then(|s| s.peer_mimes.clone())
+    }
+
+    /// Grab the selection for the local content, or release it if no mime is supported.
+    pub async fn grab<S: AsRef<str>>(
+        &self,
+        selection: ClipboardSelection,
+        mimes: &[S],
+    ) -> Result<()> {
+        let mimes = filter_mimes(mimes);
+        if mimes.is_empty() {
+            return self.release(selection).await;
+        }
+        let serial = {
+            let mut selections = self.selections.lock().unwrap();
+            let state = selections.entry(selection).or_default();
+            if let Some(peer) = &state.peer_mimes {
+                if *peer == mimes {
+                    log::debug!("Ignored self-grab of {:?}", selection);
+                    return Ok(());
+                }
+            }
+            state.peer_mimes = None;
+            state.serial += 1;
+            state.serial - 1
+        };
+        let mimes: Vec<_> = mimes.iter().map(|s| s.as_str()).collect();
+        Ok(self.proxy.grab(selection, serial, &mimes).await?)
+    }
+
+    pub async fn release(&self, selection: ClipboardSelection) -> Result<()> {
+        if let Some(state) = self.selections.lock().unwrap().get_mut(&selection) {
+            state.peer_mimes = None;
+        }
+        Ok(self.proxy.release(selection).await?)
+    }
+
+    /// Request the peer content, in one of the given mimes.
+    pub async fn request<S: AsRef<str>>(
+        &self,
+        selection: ClipboardSelection,
+        mimes: &[S],
+    ) -> Result<(String, Vec<u8>)> {
+        let mimes: Vec<_> = mimes.iter().map(AsRef::as_ref).collect();
+        Ok(self.proxy.request(selection, &mimes).await?)
+    }
+
+    pub async fn request_text(&self, selection: ClipboardSelection) -> Result<String> {
+        let (_, data) = self.request(selection, TEXT_MIMES).await?;
+        String::from_utf8(data).map_err(|e| Error::Failed(format!("Invalid clipboard text: {}", e)))
+    }
+
+    /// Request an image, preferably in PNG. Returns the mime type and the data.
+    pub async fn request_image(&self, selection: ClipboardSelection) -> Result<(String, Vec<u8>)> {
+        let mimes: Vec<_> = self
+            .peer_mimes(selection)
+            .unwrap_or_else(|| vec![PNG_MIME.into()])
+            .into_iter()
+            .filter(|m| ClipboardContent::from_mime(m) == Some(ClipboardContent::Image))
+            .collect();
+        if mimes.is_empty() {
+            return Err(Error::Failed("No clipboard image".into()));
+        }
+        self.request(selection, &mimes).await
+    }
+}
+
+// the mimes of the client text, advertised to the guest
+const CLIENT_TEXT_MIMES: &[&str] = &["text/plain;charset=utf-8", "UTF8_STRING", "text/plain"];
+
+/// The text clipboard of the remote clients, bridged to the guest clipboard.
+///
+/// The text of a client grabs the guest clipboard, and is handed to the guest on request.
+/// The text grabbed by the guest is given to the callback, for the clients. Only the
+/// clipboard selection is bridged, the protocols have no primary selection.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct TextClipboard {
+    // kept registered with QEMU
+    _clipboard: Clipboard,
+    manager: ClipboardManager,
+    #[derivative(Debug = "ignore")]
+    text: Arc<Mutex<Option<String>>>,
+}
+
+impl TextClipboard {
+    pub async fn new<F>(clipboard: Clipboard, guest: GuestDefaults, guest_text: F) -> Result<Self>
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        let manager = ClipboardManager::new(&clipboard);
+        let text = Arc::new(Mutex::new(None));
+        let handler = TextHandler {
+            manager: manager.clone(),
+            text: text.clone(),
+            guest,
+            guest_text: Box::new(guest_text),
+        };
+        clipboard.register(handler).await?;
+        Ok(Self {
+            _clipboard: clipboard,
+            manager,
+            text,
+        })
+    }
+
+    /// Grab the guest clipboard, with the text of a client.
+    pub async fn client_text(&self, text: String) -> Result<()> {
+        self.text.lock().unwrap().replace(text);
+        self.manager
+            .grab(ClipboardSelection::Clipboard, CLIENT_TEXT_MIMES)
+            .await
+    }
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct TextHandler {
+    manager: ClipboardManager,
+    text: Arc<Mutex<Option<String>>>,
+    guest: GuestDefaults,
+    #[derivative(Debug = "ignore")]
+    guest_text: Box<dyn Fn(String) + Send + Sync>,
+}
+
+#[async_trait::async_trait]
+impl ClipboardHandler for TextHandler {
+    async fn register(&mut self) {
+        self.manager.reset();
+    }
+
+    async fn unregister(&mut self) {
+        self.manager.reset();
+    }
+
+    async fn grab(&mut self, selection: ClipboardSelection, serial: u32, mimes: Vec<String>) {
+        if selection != ClipboardSelection::Clipboard
+            || !self.manager.peer_grab(selection, serial, &mimes)
+        {
+            return;
+        }
+        self.text.lock().unwrap().take();
+        let has_text = mimes
+            .iter()
+            .any(|m| ClipboardContent::from_mime(m) == Some(ClipboardContent::Text));
+        if !has_text {
+            return;
+        }
+        match self.manager.request_text(selection).await {
+            Ok(text) => (self.guest_text)(text),
+            Err(e) => log::warn!("Failed to read the guest clipboard: {}", e),
+        }
+    }
+
+    async fn release(&mut self, selection: ClipboardSelection) {
+        self.manager.peer_release(selection);
+    }
+
+    async fn request(
+        &mut self,
+        selection: ClipboardSelection,
+        mimes: Vec<String>,
+    ) -> Result<(String, Vec<u8>)> {
+        let text = match (selection, &*self.text.lock().unwrap()) {
+            (ClipboardSelection::Clipboard, Some(text)) => self.guest.clipboard_text(text),
+            _ => return Err(Error::Failed("No client text".into())),
+        };
+        let mime = mimes
+            .into_iter()
+            .find(|m| CLIENT_TEXT_MIMES.contains(&m.as_str()))
+            .ok_or_else(|| Error::Failed("Unsupported clipboard mimes".into()))?;
+        Ok((mime, text.into_bytes()))
+    }
 }
@@ -0,0 +1,374 @@
+use futures::{
+    channel::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+    StreamExt,
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use zbus::{Executor, Task};
+
+use crate::{
+    console_listener::pixman_bpp, ConsoleListenerHandler, Cursor, MouseSet, Scanout, Update,
+};
+#[cfg(unix)]
+use crate::{ScanoutDMABUF, UpdateDMABUF};
+#[cfg(windows)]
+use crate::{ScanoutMap, UpdateMap};
+
+// above this number of pending rectangles, they are merged in a single one
+const MAX_PENDING_RECTS: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Rect {
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+}
+
+impl Rect {
+    // the updates are checked within the frame, but the accumulated region must not overflow
+    fn right(&self) -> i32 {
+        self.x.saturating_add(self.w)
+    }
+
+    fn bottom(&self) -> i32 {
+        self.y.saturating_add(self.h)
+    }
+
+    fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.right()
+            && other.x < self.right()
+            && self.y < other.bottom()
+            && other.y < self.bottom()
+    }
+
+    fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            w: self.right().max(other.right()).saturating_sub(x),
+            h: self.bottom().max(other.bottom()).saturating_sub(y),
+        }
+    }
+}
+
+enum Event {
+    // the shadow frame, as it is at delivery time
+    Scanout,
+    // a region of the shadow frame
+    Damage(Rect),
+    // an update that doesn't fit the shadow frame
+    Update(Update),
+    #[cfg(windows)]
+    ScanoutMap(ScanoutMap),
+    #[cfg(windows)]
+    UpdateMap(UpdateMap),
+    #[cfg(unix)]
+    ScanoutDMABUF(ScanoutDMABUF),
+    #[cfg(unix)]
+    UpdateDMABUF(UpdateDMABUF, oneshot::Sender<()>),
+    MouseSet(MouseSet),
+    Cursor(Cursor),
+    Disable,
+    Disconnected,
+}
+
+impl Event {
+    fn is_frame(&self) -> bool {
+        match self {
+            Event::Scanout | Event::Damage(_) | Event::Update(_) => true,
+            #[cfg(windows)]
+            Event::ScanoutMap(_) | Event::UpdateMap(_) => true,
+            #[cfg(unix)]
+            Event::ScanoutDMABUF(_) | Event::UpdateDMABUF(..) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    frame: Option<Scanout>,
+    queue: VecDeque<Event>,
+}
+
+impl Pending {
+    fn push_scanout(&mut self, scanout: Scanout) {
+        // a new scanout supersedes any pending frame update
+        self.queue
+            .retain(|e| !matches!(e, Event::Scanout | Event::Damage(_) | Event::Update(_)));
+        self.frame = Some(scanout);
+        self.queue.push_back(Event::Scanout);
+    }
+
+    fn push_update(&mut self, update: Update) {
+        let frame = match self.frame.as_mut() {
+            Some(frame)
+                if frame.format == update.format
+                    && update.x >= 0
+                    && update.y >= 0
+                    && update.w >= 0
+                    && update.h >= 0
+                    && update.x.saturating_add(update.w) as u32 <= frame.width
+                    && update.y.saturating_add(update.h) as u32 <= frame.height =>
+            {
+                frame
+            }
+            _ => {
+                self.queue.push_back(Event::Update(update));
+                return;
+            }
+        };
+
+        let bpp = pixman_bpp(update.format) / 8;
+        let len = update.w as usize * bpp;
+        for row in 0..update.h as usize {
+            let src = row * update.stride as usize;
+            let dst = (update.y as usize + row) * frame.stride as usize + update.x as usize * bpp;
+            if let (Some(src), Some(dst)) = (
+                update.data.get(src..src + len),
+                frame.data.get_mut(dst..dst + len),
+            ) {
+                dst.copy_from_slice(src);
+            }
+        }
+
+        let mut rect = Rect {
+            x: update.x,
+            y: update.y,
+            w: update.w,
+            h: update.h,
+        };
+        // a pending scanout will carry the update
+        if self.queue.iter().any(|e| matches!(e, Event::Scanout)) {
+            return;
+        }
+        loop {
+            let n = self.queue.len();
+            self.queue.retain(|e| match e {
+                Event::Damage(r) if r.intersects(&rect) => {
+                    rect = rect.union(r);
+                    false
+                }
+                _ => true,
+            });
+            if n == self.queue.len() {
+                break;
+            }
+        }
+        if self
+            .queue
+            .iter()
+            .filter(|e| matches!(e, Event::Damage(_)))
+            .count()
+            >= MAX_PENDING_RECTS
+        {
+            self.queue.retain(|e| match e {
+                Event::Damage(r) => {
+                    rect = rect.union(r);
+                    false
+                }
+                _ => true,
+            });
+        }
+        self.queue.push_back(Event::Damage(rect));
+    }
+
+    fn push_mouse_set(&mut self, set: MouseSet) {
+        // at most one pending position per frame: the last one, with its on/off state
+        let frame = self
+            .queue
+            .iter()
+            .rposition(Event::is_frame)
+            .map_or(0, |i| i + 1);
+        let mut i = 0;
+        self.queue.retain(|e| {
+            i += 1;
+            i <= frame || !matches!(e, Event::MouseSet(_))
+        });
+        self.queue.push_back(Event::MouseSet(set));
+    }
+
+    fn pop(&mut self) -> Option<Event> {
+        self.queue.pop_front()
+    }
+
+    fn damage_update(&self, rect: Rect) -> Option<Update> {
+        let frame = self.frame.as_ref()?;
+        let bpp = pixman_bpp(frame.format) / 8;
+        let len = rect.w as usize * bpp;
+        let mut data = Vec::with_capacity(len * rect.h as usize);
+        for row in 0..rect.h as usize {
+            let src = (rect.y as usize + row) * frame.stride as usize + rect.x as usize * bpp;
+            data.extend_from_slice(frame.data.get(src..src + len)?);
+        }
+        Some(Update {
+            x: rect.x,
+            y: rect.y,
+            w: rect.w,
+            h: rect.h,
+            stride: len as u32,
+            format: frame.format,
+            data,
+        })
+    }
+}
+
+/// A handler wrapper that queues events and coalesces frame updates while the wrapped
+/// handler is busy.
+///
+/// Updates are applied to a copy of the current scanout, and pending overlapping rectangles
+/// are merged. A new scanout drops any pending update (and DMABUF scanout), and a mouse
+/// position replaces the pending one that wasn't followed by a frame.
+///
+/// The wrapped handler is called from a task of the connection `executor`.
+pub(crate) struct CoalescingHandler {
+    pending: Arc<Mutex<Pending>>,
+    doorbell: Sender<()>,
+}
+
+impl CoalescingHandler {
+    pub(crate) fn new<H: ConsoleListenerHandler>(handler: H, executor: &Executor<'static>) -> Self {
+        let pending: Arc<Mutex<Pending>> = Default::default();
+        let (doorbell, receiver) = mpsc::channel(1);
+        // a task is cancelled with its handle, and the handler is dropped right after its
+        // disconnection: the task keeps its handle, until the queued events are delivered
+        let slot: Arc<Mutex<Option<Task<()>>>> = Default::default();
+        let task = executor.spawn({
+            let (pending, slot) = (pending.clone(), slot.clone());
+            async move {
+                run(handler, pending, receiver).await;
+                slot.lock().unwrap().take();
+            }
+        });
+        // the task runs until the doorbell is dropped, with the handler
+        *slot.lock().unwrap() = Some(task);
+        Self { pending, doorbell }
+    }
+
+    fn push<F: FnOnce(&mut Pending)>(&mut self, f: F) {
+        f(&mut self.pending.lock().unwrap());
+        // it's fine if the doorbell is already ringing
+        let _ = self.doorbell.try_send(());
+    }
+}
+
+async fn run<H: ConsoleListenerHandler>(
+    mut handler: H,
+    pending: Arc<Mutex<Pending>>,
+    mut doorbell: Receiver<()>,
+) {
+    loop {
+        let (event, update) = {
+            let mut pending = pending.lock().unwrap();
+            let event = pending.pop();
+            let update = match &event {
+                Some(Event::Damage(r)) => pending.damage_update(*r),
+                _ => None,
+            };
+            (event, update)
+        };
+        let event = match event {
+            Some(event) => event,
+            None => {
+                if doorbell.next().await.is_none() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        match event {
+            Event::Scanout => {
+                let scanout = pending.lock().unwrap().frame.clone();
+                if let Some(scanout) = scanout {
+                    handler.scanout(scanout).await;
+                }
+            }
+            Event::Damage(_) => {
+                if let Some(update) = update {
+                    handler.update(update).await;
+                }
+            }
+            Event::Update(update) => handler.update(update).await,
+            #[cfg(windows)]
+            Event::ScanoutMap(scanout) => handler.scanout_map(scanout).await,
+            #[cfg(windows)]
+            Event::UpdateMap(update) => handler.update_map(update).await,
+            #[cfg(unix)]
+            Event::ScanoutDMABUF(scanout) => handler.scanout_dmabuf(scanout).await,
+            #[cfg(unix)]
+            Event::UpdateDMABUF(update, done) => {
+                handler.update_dmabuf(update).await;
+                let _ = done.send(());
+            }
+            Event::MouseSet(set) => handler.mouse_set(set).await,
+            Event::Cursor(cursor) => handler.cursor_define(cursor).await,
+            Event::Disable => handler.disable(),
+            Event::Disconnected => handler.disconnected(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ConsoleListenerHandler for CoalescingHandler {
+    async fn scanout(&mut self, scanout: Scanout) {
+        self.push(|p| p.push_scanout(scanout));
+    }
+
+    async fn update(&mut self, update: Update) {
+        self.push(|p| p.push_update(update));
+    }
+
+    #[cfg(windows)]
+    async fn scanout_map(&mut self, scanout: ScanoutMap) {
+        self.push(|p| p.queue.push_back(Event::ScanoutMap(scanout)));
+    }
+
+    #[cfg(windows)]
+    async fn update_map(&mut self, update: UpdateMap) {
+        self.push(|p| p.queue.push_back(Event::UpdateMap(update)));
+    }
+
+    #[cfg(unix)]
+    async fn scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        self.push(|p| {
+            // the pending DMABUF are stale: close them now rather than when delivered, and
+            // reply to their updates
+            p.queue
+                .retain(|e| !matches!(e, Event::ScanoutDMABUF(_) | Event::UpdateDMABUF(..)));
+            p.queue.push_back(Event::ScanoutDMABUF(scanout));
+        });
+    }
+
+    #[cfg(unix)]
+    async fn update_dmabuf(&mut self, update: UpdateDMABUF) {
+        // QEMU waits for the reply before reusing the buffer: keep that synchronization
+        let (done, wait) = oneshot::channel();
+        self.push(|p| p.queue.push_back(Event::UpdateDMABUF(update, done)));
+        let _ = wait.await;
+    }
+
+    async fn mouse_set(&mut self, set: MouseSet) {
+        self.push(|p| p.push_mouse_set(set));
+    }
+
+    async fn cursor_define(&mut self, cursor: Cursor) {
+        self.push(|p| p.queue.push_back(Event::Cursor(cursor)));
+    }
+
+    fn disable(&mut self) {
+        self.push(|p| p.queue.push_back(Event::Disable));
+    }
+
+    fn disconnected(&mut self) {
+        self.push(|p| p.queue.push_back(Event::Disconnected));
+    }
+}
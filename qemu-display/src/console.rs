@@ -1,15 +1,33 @@
 #[cfg(windows)]
 use crate::win32::Fd;
+use async_broadcast::{broadcast, Receiver};
+use futures::{Future, StreamExt};
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
-use std::{cell::RefCell, convert::TryFrom};
+use std::{
+    collections::HashMap, convert::TryFrom, net::Shutdown, path::Path, sync::Mutex, time::Duration,
+};
+use tracing::Instrument;
 #[cfg(windows)]
 use uds_windows::UnixStream;
 #[cfg(unix)]
 use zbus::zvariant::Fd;
-use zbus::{dbus_proxy, zvariant::ObjectPath, Connection};
+use zbus::{
+    dbus_proxy,
+    names::{BusName, WellKnownName},
+    zvariant::{ObjectPath, OwnedValue},
+    CacheProperties, Connection, MessageStream, Task,
+};
 
-use crate::{util, ConsoleListener, ConsoleListenerHandler, KeyboardProxy, MouseProxy, Result};
+#[cfg(unix)]
+use crate::console_listener::ConsoleListenerUnixMap;
+use crate::{
+    capture::CaptureSink, coalesce::CoalescingHandler, console_listener::LISTENER_PATH,
+    timeout::CallOptions, util, CancellationToken, ConsoleListener, ConsoleListenerHandler, Error,
+    FrameSinkListener, KeyboardProxy, MouseProxy, Recording, Result, RgbaImage,
+};
+#[cfg(all(unix, feature = "video-encode"))]
+use crate::{encode::VideoEncoder, EncodedVideo, EncoderConfig};
 
 #[dbus_proxy(default_service = "org.qemu", interface = "org.qemu.Display1.Console")]
 pub trait Console {
@@ -37,11 +55,119 @@ pub trait Console {
     #[dbus_proxy(property)]
     fn type_(&self) -> zbus::Result<String>;
 
+    /// The address of the console device (ex: "pci/0000/02.0"), missing on the older
+    /// versions.
+    #[dbus_proxy(property)]
+    fn device_address(&self) -> zbus::Result<String>;
+
     #[dbus_proxy(property)]
     fn width(&self) -> zbus::Result<u32>;
 
     #[dbus_proxy(property)]
     fn height(&self) -> zbus::Result<u32>;
+
+    /// The extra interfaces supported by QEMU, missing on the older versions.
+    #[dbus_proxy(property)]
+    fn interfaces(&self) -> zbus::Result<Vec<String>>;
+}
+
+// the longest wait for the frame of a screenshot
+const SCREENSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const CONSOLE_PATH_PREFIX: &str = "/org/qemu/Display1/Console_";
+
+pub(crate) fn console_id(path: &str) -> Option<u32> {
+    path.strip_prefix(CONSOLE_PATH_PREFIX)?.parse().ok()
+}
+
+/// Console properties, as exposed by QEMU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsoleInfo {
+    pub id: u32,
+    pub label: String,
+    pub head: u32,
+    pub type_: String,
+    /// Empty when QEMU doesn't tell.
+    pub device_address: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ConsoleInfo {
+    pub(crate) fn from_properties(id: u32, props: &HashMap<String, OwnedValue>) -> Self {
+        fn prop<T: TryFrom<OwnedValue> + Default>(
+            props: &HashMap<String, OwnedValue>,
+            name: &str,
+        ) -> T {
+            props
+                .get(name)
+                .cloned()
+                .and_then(|v| T::try_from(v).ok())
+                .unwrap_or_default()
+        }
+
+        Self {
+            id,
+            label: prop(props, "Label"),
+            head: prop(props, "Head"),
+            type_: prop(props, "Type"),
+            device_address: prop(props, "DeviceAddress"),
+            width: prop(props, "Width"),
+            height: prop(props, "Height"),
+        }
+    }
+}
+
+/// Options for [`Console::register_listener_with_opts`].
+#[derive(Debug, Clone, Default)]
+pub struct ListenerOptions {
+    /// Queue events and coalesce frame updates and mouse positions while the handler is
+    /// busy, instead of blocking the listener connection.
+    pub coalesce: bool,
+}
+
+/// A console listener registration.
+///
+/// Each registration gets its own peer-to-peer connection, serving the listener interface
+/// for a single console. Dropping it disconnects the listener.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ListenerConnection {
+    console_id: u32,
+    conn: Connection,
+    // a clone of the connection socket, to close it
+    socket: UnixStream,
+    closed: Receiver<()>,
+    #[derivative(Debug = "ignore")]
+    _task: Task<()>,
+}
+
+impl ListenerConnection {
+    /// The console this listener is registered on.
+    pub fn console_id(&self) -> u32 {
+        self.console_id
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Resolves when QEMU closes the listener connection.
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closed = self.closed.clone();
+        async move {
+            // nothing is ever sent: the channel is closed with the connection
+            let _ = closed.recv().await;
+        }
+    }
+
+    /// Close the connection, and wait until the listener is no longer served.
+    pub async fn close(self) {
+        if let Err(e) = self.socket.shutdown(Shutdown::Both) {
+            log::debug!("Failed to shutdown the listener socket: {}", e);
+        }
+        self.closed().await;
+    }
 }
 
 #[derive(derivative::Derivative)]
@@ -53,60 +179,369 @@ pub struct Console {
     pub keyboard: KeyboardProxy<'static>,
     #[derivative(Debug = "ignore")]
     pub mouse: MouseProxy<'static>,
-    listener: RefCell<Option<Connection>>,
+    listener: Mutex<Option<ListenerConnection>>,
+    options: CallOptions,
     #[cfg(windows)]
     peer_pid: u32,
 }
 
 impl Console {
     pub async fn new(conn: &Connection, idx: u32, #[cfg(windows)] peer_pid: u32) -> Result<Self> {
-        let obj_path = ObjectPath::try_from(format!("/org/qemu/Display1/Console_{}", idx))?;
-        let proxy = ConsoleProxy::builder(conn).path(&obj_path)?.build().await?;
+        Self::with_destination(
+            conn,
+            None,
+            idx,
+            CallOptions::default(),
+            #[cfg(windows)]
+            peer_pid,
+        )
+        .await
+    }
+
+    /// Like [`Console::new`], for the VM owning `dest` instead of "org.qemu".
+    pub(crate) async fn with_destination(
+        conn: &Connection,
+        dest: Option<BusName<'static>>,
+        idx: u32,
+        options: CallOptions,
+        #[cfg(windows)] peer_pid: u32,
+    ) -> Result<Self> {
+        options
+            .clone()
+            .call(
+                "console creation",
+                Self::build(
+                    conn,
+                    dest,
+                    idx,
+                    options,
+                    #[cfg(windows)]
+                    peer_pid,
+                ),
+            )
+            .await
+    }
+
+    async fn build(
+        conn: &Connection,
+        dest: Option<BusName<'static>>,
+        idx: u32,
+        options: CallOptions,
+        #[cfg(windows)] peer_pid: u32,
+    ) -> Result<Self> {
+        let obj_path = ObjectPath::try_from(format!("{}{}", CONSOLE_PATH_PREFIX, idx))?;
+        let dest = dest
+            .unwrap_or_else(|| BusName::from(WellKnownName::from_static_str_unchecked("org.qemu")));
+        // prefetch the properties with a single GetAll, they are kept up to date with
+        // PropertiesChanged
+        let proxy = ConsoleProxy::builder(conn)
+            .destination(dest.clone())?
+            .path(&obj_path)?
+            .cache_properties(CacheProperties::Yes)
+            .build()
+            .await?;
         let keyboard = KeyboardProxy::builder(conn)
+            .destination(dest.clone())?
+            .path(&obj_path)?
+            .build()
+            .await?;
+        let mouse = MouseProxy::builder(conn)
+            .destination(dest)?
             .path(&obj_path)?
             .build()
             .await?;
-        let mouse = MouseProxy::builder(conn).path(&obj_path)?.build().await?;
         Ok(Self {
             proxy,
             keyboard,
             mouse,
-            listener: RefCell::new(None),
+            listener: Mutex::new(None),
+            options,
             #[cfg(windows)]
             peer_pid,
         })
     }
 
+    /// Set the timeout of the console calls, `None` to wait forever.
+    ///
+    /// It is the [`default_timeout`](crate::default_timeout), or the one of the display
+    /// the console is from. A call that times out fails with [`Error::Timeout`].
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    /// Fail the console calls with [`Error::Cancelled`] once `token` is cancelled.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = Some(token);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.options.timeout
+    }
+
+    /// The console index.
+    pub fn id(&self) -> u32 {
+        // the path is built from the index in new()
+        console_id(self.proxy.path().as_str()).unwrap()
+    }
+
+    /// All the console properties at once, served from the proxy cache.
+    pub async fn info(&self) -> Result<ConsoleInfo> {
+        self.options
+            .call("console properties", async {
+                Ok(ConsoleInfo {
+                    id: self.id(),
+                    label: self.proxy.label().await?,
+                    head: self.proxy.head().await?,
+                    type_: self.proxy.type_().await?,
+                    device_address: self.proxy.device_address().await.unwrap_or_default(),
+                    width: self.proxy.width().await?,
+                    height: self.proxy.height().await?,
+                })
+            })
+            .await
+    }
+
     pub async fn label(&self) -> Result<String> {
-        Ok(self.proxy.label().await?)
+        self.options
+            .call("console label", async { Ok(self.proxy.label().await?) })
+            .await
     }
 
     pub async fn width(&self) -> Result<u32> {
-        Ok(self.proxy.width().await?)
+        self.options
+            .call("console width", async { Ok(self.proxy.width().await?) })
+            .await
     }
 
     pub async fn height(&self) -> Result<u32> {
-        Ok(self.proxy.height().await?)
+        self.options
+            .call("console height", async { Ok(self.proxy.height().await?) })
+            .await
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn peer_pid(&self) -> u32 {
+        self.peer_pid
     }
 
+    /// Register a listener for the console events.
+    ///
+    /// This can be called again on the same console, for example after QEMU dropped the
+    /// listener (see [`Console::listener_closed`]): the previous listener is then replaced.
     pub async fn register_listener<H: ConsoleListenerHandler>(&self, handler: H) -> Result<()> {
-        let (p0, p1) = UnixStream::pair()?;
-        let p0 = util::prepare_uds_pass(
+        let c = self.register_mirror(handler).await?;
+        if let Some(old) = self.listener.lock().unwrap().replace(c) {
+            log::debug!("Console {}: replaced listener", old.console_id());
+        }
+        Ok(())
+    }
+
+    /// Whether a listener is registered with [`Console::register_listener`].
+    pub fn has_listener(&self) -> bool {
+        self.listener.lock().unwrap().is_some()
+    }
+
+    pub async fn register_listener_with_opts<H: ConsoleListenerHandler>(
+        &self,
+        handler: H,
+        opts: ListenerOptions,
+    ) -> Result<()> {
+        if opts.coalesce {
+            let executor = self.proxy.connection().executor();
+            self.register_listener(CoalescingHandler::new(handler, executor))
+                .await
+        } else {
+            self.register_listener(handler).await
+        }
+    }
+
+    pub fn unregister_listener(&self) {
+        self.listener.lock().unwrap().take();
+    }
+
+    /// Disconnect the listener, and wait until it is no longer served.
+    ///
+    /// Unlike dropping the console, the listener connection is closed even if it is still
+    /// referenced, for example by a handler call in progress.
+    pub async fn close(self) {
+        if let Some(listener) = self.listener.into_inner().unwrap() {
+            listener.close().await;
+        }
+    }
+
+    /// Capture the current console content.
+    ///
+    /// A temporary listener is registered, the console listener (if any) is left in place.
+    /// DMABUF scanouts are read if they are linear. It fails with [`Error::Timeout`] if no
+    /// frame is received within the call timeout, at most 10 seconds.
+    pub async fn screenshot(&self) -> Result<RgbaImage> {
+        let mut recording = self.record().await?;
+        let opts = CallOptions {
+            timeout: Some(
+                self.options
+                    .timeout
+                    .map_or(SCREENSHOT_TIMEOUT, |t| t.min(SCREENSHOT_TIMEOUT)),
+            ),
+            cancel: self.options.cancel.clone(),
+        };
+        opts.call("console screenshot", async {
+            match recording.next().await {
+                Some(frame) => Ok(frame?.image),
+                None => Err(Error::Failed("Console listener disconnected".into())),
+            }
+        })
+        .await
+    }
+
+    /// Record the console frames, with their timestamps.
+    ///
+    /// Like [`Console::screenshot`], a listener is registered for the recording.
+    pub async fn record(&self) -> Result<Recording> {
+        let (sink, receiver) = CaptureSink::new();
+        // QEMU sends the current scanout on registration
+        let listener = self.register_mirror(FrameSinkListener::new(sink)).await?;
+        Ok(Recording::new(receiver, listener))
+    }
+
+    /// Encode the console frames in a video bitstream, with GStreamer.
+    ///
+    /// Like [`Console::record`], a listener is registered for the encoding.
+    #[cfg(all(unix, feature = "video-encode"))]
+    pub async fn encode(&self, config: EncoderConfig) -> Result<EncodedVideo> {
+        let encoder = VideoEncoder::new(config)?;
+        let listener = self
+            .register_mirror(FrameSinkListener::new(encoder.sink()))
+            .await?;
+        Ok(encoder.start(listener))
+    }
+
+    // a listener left to the caller, also for the additional ones of Session::mirror
+    pub(crate) async fn register_mirror<H: ConsoleListenerHandler>(
+        &self,
+        handler: H,
+    ) -> Result<ListenerConnection> {
+        self.options
+            .call(
+                "listener registration",
+                register_listener(
+                    &self.proxy,
+                    #[cfg(windows)]
+                    self.peer_pid,
+                    handler,
+                ),
+            )
+            .await
+    }
+
+    /// Register the listener served by another process, on the UNIX socket at `path`.
+    ///
+    /// The socket is connected, and its descriptor handed to QEMU: the listener serving the
+    /// other end (with a [`ListenerSocket`](crate::ListenerSocket)) then gets the console
+    /// events. On Linux, a path starting with `@` is an abstract socket name. The console
+    /// listener (if any) is left in place.
+    pub async fn register_listener_socket<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let stream = crate::listener_socket::connect(path.as_ref())?;
+        let fd = util::prepare_uds_pass(
             #[cfg(windows)]
             self.peer_pid,
-            &p0,
+            &stream,
         )?;
-        self.proxy.register_listener(p0).await?;
-        let c = zbus::ConnectionBuilder::unix_stream(p1)
-            .p2p()
-            .serve_at("/org/qemu/Display1/Listener", ConsoleListener::new(handler))?
-            .build()
+        self.options
+            .call("listener registration", async {
+                Ok(self.proxy.register_listener(fd).await?)
+            })
             .await?;
-        self.listener.replace(Some(c));
+        log::debug!(
+            "Console {}: registered listener at {}",
+            self.id(),
+            path.as_ref().display()
+        );
         Ok(())
     }
 
-    pub fn unregister_listener(&mut self) {
-        self.listener.replace(None);
+    /// A future resolving when QEMU closes the current listener connection, or `None` if no
+    /// listener is registered.
+    ///
+    /// It is not affected by a later registration.
+    pub fn listener_closed(&self) -> Option<impl Future<Output = ()> + Send + 'static> {
+        self.listener.lock().unwrap().as_ref().map(|l| l.closed())
+    }
+}
+
+pub(crate) async fn register_listener<H: ConsoleListenerHandler>(
+    proxy: &ConsoleProxy<'_>,
+    #[cfg(windows)] peer_pid: u32,
+    handler: H,
+) -> Result<ListenerConnection> {
+    serve_listener(
+        proxy,
+        #[cfg(windows)]
+        peer_pid,
+        |id| ConsoleListener::new(id, handler),
+    )
+    .await
+}
+
+async fn serve_listener<H: ConsoleListenerHandler>(
+    proxy: &ConsoleProxy<'_>,
+    #[cfg(windows)] peer_pid: u32,
+    listener: impl FnOnce(u32) -> ConsoleListener<H>,
+) -> Result<ListenerConnection> {
+    let path = proxy.path();
+    let console_id = console_id(path.as_str())
+        .ok_or_else(|| Error::Failed(format!("Invalid console path: {}", path)))?;
+    let listener = listener(console_id);
+    let span = tracing::debug_span!("register_listener", console = console_id);
+    async move {
+        let (p0, p1) = UnixStream::pair()?;
+        let p0 = util::prepare_uds_pass(
+            #[cfg(windows)]
+            peer_pid,
+            &p0,
+        )?;
+        proxy
+            .register_listener(p0)
+            .await
+            .map_err(|e| Error::method_call(proxy.inner(), "RegisterListener", e))?;
+        serve_connection(p1, listener).await
     }
+    .instrument(span)
+    .await
+    .map_err(|e| Error::ListenerSetup {
+        source: Box::new(e),
+    })
+}
+
+/// Serve a listener on a socket connected to QEMU.
+pub(crate) async fn serve_connection<H: ConsoleListenerHandler>(
+    p1: UnixStream,
+    listener: ConsoleListener<H>,
+) -> Result<ListenerConnection> {
+    let console_id = listener.console_id();
+    let socket = p1.try_clone()?;
+    let builder = zbus::ConnectionBuilder::unix_stream(p1)
+        .p2p()
+        .serve_at(LISTENER_PATH, listener)?;
+    #[cfg(unix)]
+    let builder = builder.serve_at(LISTENER_PATH, ConsoleListenerUnixMap::<H>::new())?;
+    let conn = builder.build().await?;
+    log::debug!("Console {}: registered listener", console_id);
+
+    let (sender, closed) = broadcast(1);
+    let mut stream = MessageStream::from(&conn);
+    let task = conn.executor().spawn(async move {
+        // the stream ends when the socket is closed
+        while stream.next().await.is_some() {}
+        log::debug!("Console {}: listener connection closed", console_id);
+        drop(sender);
+    });
+    Ok(ListenerConnection {
+        console_id,
+        conn,
+        socket,
+        closed,
+        _task: task,
+    })
 }
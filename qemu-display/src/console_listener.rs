@@ -1,14 +1,73 @@
 #[cfg(windows)]
 use crate::win32::Fd;
 use derivative::Derivative;
-use std::ops::Drop;
+#[cfg(unix)]
+use std::marker::PhantomData;
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::{ops::Drop, sync::Arc};
 use zbus::dbus_interface;
 #[cfg(unix)]
-use zbus::zvariant::Fd;
+use zbus::{zvariant::Fd, InterfaceRef, ObjectServer};
 
-#[derive(Derivative)]
+use crate::{
+    metrics::{self, ConsoleCounters},
+    Error, Result,
+};
+#[cfg(unix)]
+use crate::{ScanoutMapped, LISTENER_UNIX_MAP_INTERFACE};
+
+/// The object path of the console listeners.
+pub(crate) const LISTENER_PATH: &str = "/org/qemu/Display1/Listener";
+
+/// The maximum supported display width, in pixels.
+pub const MAX_WIDTH: u32 = 16384;
+/// The maximum supported display height, in pixels.
+pub const MAX_HEIGHT: u32 = 16384;
+
+// bits per pixel, from the pixman format code
+/// The pixman formats of the usual 32-bit frames.
+pub const PIXMAN_X8R8G8B8: u32 = 0x20020888;
+pub const PIXMAN_A8R8G8B8: u32 = 0x20028888;
+pub const PIXMAN_X8B8G8R8: u32 = 0x20030888;
+pub const PIXMAN_A8B8G8R8: u32 = 0x20038888;
+
+pub(crate) fn pixman_bpp(format: u32) -> usize {
+    (format >> 24) as usize
+}
+
+/// Check the display dimensions are supported.
+pub fn check_dimensions(width: u32, height: u32) -> Result<()> {
+    if width > MAX_WIDTH || height > MAX_HEIGHT {
+        return Err(Error::TooLarge { width, height });
+    }
+    Ok(())
+}
+
+/// The size of a frame buffer, checking the dimensions and stride.
+///
+/// The last row is only required to hold the pixels, not a whole stride.
+pub fn frame_size(width: u32, height: u32, stride: u32, format: u32) -> Result<usize> {
+    check_dimensions(width, height)?;
+    let row = (width as usize)
+        .checked_mul(pixman_bpp(format) / 8)
+        .ok_or(Error::TooLarge { width, height })?;
+    if (stride as usize) < row {
+        return Err(Error::Failed(format!(
+            "Invalid stride {} for width {}",
+            stride, width
+        )));
+    }
+    if height == 0 {
+        return Ok(0);
+    }
+    (stride as usize)
+        .checked_mul(height as usize - 1)
+        .and_then(|s| s.checked_add(row))
+        .ok_or(Error::TooLarge { width, height })
+}
+
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct Scanout {
     pub width: u32,
@@ -19,7 +78,22 @@ pub struct Scanout {
     pub data: Vec<u8>,
 }
 
-#[derive(Derivative)]
+impl Scanout {
+    /// Check the dimensions are supported and the data holds the whole frame.
+    pub fn validate(&self) -> Result<()> {
+        let size = frame_size(self.width, self.height, self.stride, self.format)?;
+        if self.data.len() < size {
+            return Err(Error::Failed(format!(
+                "Scanout data too short: {} < {}",
+                self.data.len(),
+                size
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct Update {
     pub x: i32,
@@ -32,6 +106,37 @@ pub struct Update {
     pub data: Vec<u8>,
 }
 
+impl Update {
+    /// Check the update is within the supported dimensions and the data holds the region.
+    pub fn validate(&self) -> Result<()> {
+        if self.x < 0 || self.y < 0 || self.w < 0 || self.h < 0 {
+            return Err(Error::Failed(format!(
+                "Invalid update region: {:?}",
+                (self.x, self.y, self.w, self.h)
+            )));
+        }
+        let (x, y, w, h) = (self.x as u32, self.y as u32, self.w as u32, self.h as u32);
+        match (x.checked_add(w), y.checked_add(h)) {
+            (Some(right), Some(bottom)) => check_dimensions(right, bottom)?,
+            _ => {
+                return Err(Error::TooLarge {
+                    width: x,
+                    height: y,
+                })
+            }
+        }
+        let size = frame_size(w, h, self.stride, self.format)?;
+        if self.data.len() < size {
+            return Err(Error::Failed(format!(
+                "Update data too short: {} < {}",
+                self.data.len(),
+                size
+            )));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct ScanoutMap {
     pub handle: u64,
@@ -66,7 +171,7 @@ pub struct ScanoutDMABUF {
 #[derive(Debug)]
 pub struct ScanoutDMABUF {}
 
-#[derive(Derivative)]
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct Cursor {
     pub width: i32,
@@ -84,13 +189,34 @@ impl Drop for ScanoutDMABUF {
             unsafe {
                 libc::close(self.fd);
             }
+            crate::fd::release_dmabuf();
         }
     }
 }
 
+#[cfg(unix)]
+impl ScanoutDMABUF {
+    /// Duplicate the scanout, with its own file descriptor.
+    pub fn try_clone(&self) -> Option<Self> {
+        let fd = crate::fd::dup_dmabuf(self.fd).ok()?;
+        Some(Self {
+            fd,
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+            fourcc: self.fourcc,
+            modifier: self.modifier,
+            y0_top: self.y0_top,
+        })
+    }
+}
+
 #[cfg(unix)]
 impl IntoRawFd for ScanoutDMABUF {
     fn into_raw_fd(mut self) -> RawFd {
+        if self.fd >= 0 {
+            crate::fd::release_dmabuf();
+        }
         std::mem::replace(&mut self.fd, -1)
     }
 }
@@ -128,16 +254,41 @@ pub trait ConsoleListenerHandler: 'static + Send + Sync {
     #[cfg(unix)]
     async fn update_dmabuf(&mut self, update: UpdateDMABUF);
 
+    /// A scanout in shared memory. By default, it is copied to a regular scanout.
+    #[cfg(unix)]
+    async fn scanout_mapped(&mut self, scanout: ScanoutMapped) {
+        self.scanout(scanout.to_scanout()).await
+    }
+
+    /// An update of the shared memory scanout. By default, the region is copied to a regular
+    /// update.
+    #[cfg(unix)]
+    async fn update_mapped(&mut self, scanout: &ScanoutMapped, update: UpdateMap) {
+        match scanout.to_update(&update) {
+            Ok(update) => self.update(update).await,
+            Err(e) => log::warn!("Invalid shared map update: {}", e),
+        }
+    }
+
     async fn mouse_set(&mut self, set: MouseSet);
 
     async fn cursor_define(&mut self, cursor: Cursor);
 
+    /// The guest disabled the display, until the next scanout.
+    ///
+    /// Served on every listener, ignored by default.
+    fn disable(&mut self) {}
+
     fn disconnected(&mut self);
 }
 
-#[derive(Debug)]
+#[derive(Derivative)]
+#[derivative(Debug)]
 pub(crate) struct ConsoleListener<H: ConsoleListenerHandler> {
+    console_id: u32,
     handler: H,
+    #[derivative(Debug = "ignore")]
+    counters: Arc<ConsoleCounters>,
 }
 
 #[dbus_interface(name = "org.qemu.Display1.Listener")]
@@ -149,16 +300,21 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
         stride: u32,
         format: u32,
         data: serde_bytes::ByteBuf,
-    ) {
-        self.handler
-            .scanout(Scanout {
-                width,
-                height,
-                stride,
-                format,
-                data: data.into_vec(),
-            })
-            .await;
+    ) -> zbus::fdo::Result<()> {
+        let scanout = Scanout {
+            width,
+            height,
+            stride,
+            format,
+            data: data.into_vec(),
+        };
+        self.check(scanout.validate())?;
+        let bytes = scanout.data.len();
+        tracing::trace!(console = self.console_id, width, height, bytes, "scanout");
+        self.counters.scanout(bytes);
+        let _handling = self.counters.handling();
+        self.handler.scanout(scanout).await;
+        Ok(())
     }
 
     async fn update(
@@ -170,18 +326,23 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
         stride: u32,
         format: u32,
         data: serde_bytes::ByteBuf,
-    ) {
-        self.handler
-            .update(Update {
-                x,
-                y,
-                w,
-                h,
-                stride,
-                format,
-                data: data.into_vec(),
-            })
-            .await;
+    ) -> zbus::fdo::Result<()> {
+        let update = Update {
+            x,
+            y,
+            w,
+            h,
+            stride,
+            format,
+            data: data.into_vec(),
+        };
+        self.check(update.validate())?;
+        let bytes = update.data.len();
+        tracing::trace!(console = self.console_id, x, y, w, h, bytes, "update");
+        self.counters.update(bytes);
+        let _handling = self.counters.handling();
+        self.handler.update(update).await;
+        Ok(())
     }
 
     #[cfg(windows)]
@@ -194,6 +355,7 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
         stride: u32,
         format: u32,
     ) -> zbus::fdo::Result<()> {
+        self.check(frame_size(width, height, stride, format))?;
         let map = ScanoutMap {
             handle,
             offset,
@@ -202,6 +364,9 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
             stride,
             format,
         };
+        tracing::trace!(console = self.console_id, width, height, "scanout map");
+        self.counters.scanout(0);
+        let _handling = self.counters.handling();
         self.handler.scanout_map(map).await;
         Ok(())
     }
@@ -224,6 +389,9 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
     #[cfg(windows)]
     async fn update_map(&mut self, x: i32, y: i32, w: i32, h: i32) -> zbus::fdo::Result<()> {
         let up = UpdateMap { x, y, w, h };
+        tracing::trace!(console = self.console_id, x, y, w, h, "update map");
+        self.counters.update(0);
+        let _handling = self.counters.handling();
         self.handler.update_map(up).await;
         Ok(())
     }
@@ -264,7 +432,11 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
         modifier: u64,
         y0_top: bool,
     ) -> zbus::fdo::Result<()> {
-        let fd = unsafe { libc::dup(fd.as_raw_fd()) };
+        self.check(check_dimensions(width, height))?;
+        let fd = self.check(crate::fd::dup_dmabuf(fd.as_raw_fd()))?;
+        tracing::trace!(console = self.console_id, width, height, "scanout dmabuf");
+        self.counters.scanout(0);
+        let _handling = self.counters.handling();
         self.handler
             .scanout_dmabuf(ScanoutDMABUF {
                 fd,
@@ -290,6 +462,9 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
     #[cfg(unix)]
     #[dbus_interface(name = "UpdateDMABUF")]
     async fn update_dmabuf(&mut self, x: i32, y: i32, w: i32, h: i32) -> zbus::fdo::Result<()> {
+        tracing::trace!(console = self.console_id, x, y, w, h, "update dmabuf");
+        self.counters.update(0);
+        let _handling = self.counters.handling();
         self.handler
             .update_dmabuf(UpdateDMABUF { x, y, w, h })
             .await;
@@ -318,16 +493,136 @@ impl<H: ConsoleListenerHandler> ConsoleListener<H> {
             })
             .await;
     }
+
+    // Disable is part of the org.qemu.Display1.Listener interface, QEMU doesn't version it
+    // (nor advertise a Listener.V2): all the listeners serve it, there is nothing to negotiate.
+    fn disable(&mut self) {
+        self.handler.disable();
+    }
+
+    /// The extra interfaces served by the listener.
+    #[dbus_interface(property)]
+    fn interfaces(&self) -> Vec<String> {
+        #[cfg(unix)]
+        return vec![LISTENER_UNIX_MAP_INTERFACE.into()];
+        #[cfg(not(unix))]
+        vec![]
+    }
+}
+
+/// The shared memory scanouts, served next to the [`ConsoleListener`] of the handler.
+#[cfg(unix)]
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub(crate) struct ConsoleListenerUnixMap<H: ConsoleListenerHandler> {
+    map: Option<ScanoutMapped>,
+    #[derivative(Debug = "ignore")]
+    _handler: PhantomData<fn() -> H>,
+}
+
+#[cfg(unix)]
+impl<H: ConsoleListenerHandler> ConsoleListenerUnixMap<H> {
+    pub(crate) fn new() -> Self {
+        Self {
+            map: None,
+            _handler: PhantomData,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn listener<H: ConsoleListenerHandler>(
+    server: &ObjectServer,
+) -> zbus::fdo::Result<InterfaceRef<ConsoleListener<H>>> {
+    server
+        .interface(LISTENER_PATH)
+        .await
+        .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+}
+
+#[cfg(unix)]
+#[dbus_interface(name = "org.qemu.Display1.Listener.Unix.Map")]
+impl<H: ConsoleListenerHandler> ConsoleListenerUnixMap<H> {
+    async fn scanout_map(
+        &mut self,
+        #[zbus(object_server)] server: &ObjectServer,
+        fd: Fd,
+        offset: u32,
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: u32,
+    ) -> zbus::fdo::Result<()> {
+        let listener = listener::<H>(server).await?;
+        let mut listener = listener.get_mut().await;
+        let map = listener.check(ScanoutMapped::new(
+            fd.as_raw_fd(),
+            offset,
+            width,
+            height,
+            stride,
+            format,
+        ))?;
+        self.map = Some(map.clone());
+        tracing::trace!(console = listener.console_id, width, height, "scanout map");
+        listener.counters.scanout(0);
+        let counters = listener.counters.clone();
+        let _handling = counters.handling();
+        listener.handler.scanout_mapped(map).await;
+        Ok(())
+    }
+
+    async fn update_map(
+        &mut self,
+        #[zbus(object_server)] server: &ObjectServer,
+        x: i32,
+        y: i32,
+        w: i32,
+        h: i32,
+    ) -> zbus::fdo::Result<()> {
+        let map = self.map.as_ref().ok_or_else(|| {
+            zbus::fdo::Error::Failed("Update without a shared map scanout".into())
+        })?;
+        let listener = listener::<H>(server).await?;
+        let mut listener = listener.get_mut().await;
+        tracing::trace!(console = listener.console_id, x, y, w, h, "update map");
+        listener.counters.update(0);
+        let counters = listener.counters.clone();
+        let _handling = counters.handling();
+        listener
+            .handler
+            .update_mapped(map, UpdateMap { x, y, w, h })
+            .await;
+        Ok(())
+    }
 }
 
 impl<H: ConsoleListenerHandler> ConsoleListener<H> {
-    pub(crate) fn new(handler: H) -> Self {
-        Self { handler }
+    pub(crate) fn new(console_id: u32, handler: H) -> Self {
+        Self {
+            console_id,
+            handler,
+            counters: metrics::console_counters(console_id),
+        }
+    }
+
+    pub(crate) fn console_id(&self) -> u32 {
+        self.console_id
+    }
+
+    // frames that are too large, or inconsistent, are dropped before reaching the handler
+    fn check<T>(&self, res: Result<T>) -> zbus::fdo::Result<T> {
+        res.map_err(|e| {
+            log::warn!("Console {}: dropped frame: {}", self.console_id, e);
+            self.counters.dropped();
+            zbus::fdo::Error::InvalidArgs(e.to_string())
+        })
     }
 }
 
 impl<H: ConsoleListenerHandler> Drop for ConsoleListener<H> {
     fn drop(&mut self) {
+        log::debug!("Console {}: listener disconnected", self.console_id);
         self.handler.disconnected();
     }
 }
@@ -0,0 +1,183 @@
+use std::borrow::Cow;
+
+use crate::Cursor;
+
+// the cursor pixels are 32-bit pixman a8r8g8b8, in native endianness
+#[cfg(target_endian = "little")]
+const ALPHA: usize = 3;
+#[cfg(target_endian = "little")]
+const COLORS: [usize; 3] = [0, 1, 2];
+#[cfg(target_endian = "big")]
+const ALPHA: usize = 0;
+#[cfg(target_endian = "big")]
+const COLORS: [usize; 3] = [1, 2, 3];
+
+/// How the cursor colour channels relate to the alpha channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorAlpha {
+    /// The colours are independent of the alpha (as expected by gdk-pixbuf).
+    Straight,
+    /// The colours are multiplied by the alpha (as sent by some guest drivers).
+    Premultiplied,
+}
+
+impl Cursor {
+    /// Guess whether the cursor data is premultiplied.
+    ///
+    /// The data can only be premultiplied if no colour channel exceeds the alpha. It is then
+    /// considered premultiplied if it has some translucent colour, the only case where the
+    /// two interpretations differ.
+    pub fn alpha(&self) -> CursorAlpha {
+        let mut translucent = false;
+        for pixel in self.data.chunks_exact(4) {
+            let a = pixel[ALPHA];
+            if COLORS.iter().any(|c| pixel[*c] > a) {
+                return CursorAlpha::Straight;
+            }
+            if a > 0 && a < 255 && COLORS.iter().any(|c| pixel[*c] > 0) {
+                translucent = true;
+            }
+        }
+        if translucent {
+            CursorAlpha::Premultiplied
+        } else {
+            CursorAlpha::Straight
+        }
+    }
+
+    /// The cursor data with straight alpha.
+    pub fn straight_data(&self) -> Cow<'_, [u8]> {
+        if self.alpha() == CursorAlpha::Straight {
+            return Cow::Borrowed(&self.data);
+        }
+        let mut data = self.data.clone();
+        for pixel in data.chunks_exact_mut(4) {
+            let a = pixel[ALPHA] as u32;
+            for c in COLORS {
+                pixel[c] = match a {
+                    0 => 0,
+                    a => ((pixel[c] as u32 * 255 + a / 2) / a).min(255) as u8,
+                };
+            }
+        }
+        Cow::Owned(data)
+    }
+
+    /// The cursor data with premultiplied alpha.
+    pub fn premultiplied_data(&self) -> Cow<'_, [u8]> {
+        if self.alpha() == CursorAlpha::Premultiplied {
+            return Cow::Borrowed(&self.data);
+        }
+        let mut data = self.data.clone();
+        for pixel in data.chunks_exact_mut(4) {
+            let a = pixel[ALPHA] as u32;
+            for c in COLORS {
+                pixel[c] = ((pixel[c] as u32 * a + 127) / 255) as u8;
+            }
+        }
+        Cow::Owned(data)
+    }
+}
+
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+
+    const T: [u8; 4] = [0, 0, 0, 0];
+    const B: [u8; 4] = [0, 0, 0, 255];
+    const W: [u8; 4] = [255, 255, 255, 255];
+
+    fn cursor(width: i32, pixels: &[[u8; 4]]) -> Cursor {
+        Cursor {
+            width,
+            height: pixels.len() as i32 / width,
+            hot_x: 0,
+            hot_y: 0,
+            data: pixels.concat(),
+        }
+    }
+
+    #[test]
+    fn opaque_arrow() {
+        #[rustfmt::skip]
+        let c = cursor(3, &[
+            B, T, T,
+            B, W, T,
+            B, W, B,
+            B, B, T,
+        ]);
+        assert_eq!(c.alpha(), CursorAlpha::Straight);
+        assert_eq!(c.straight_data(), c.data);
+        assert_eq!(c.premultiplied_data(), c.data);
+    }
+
+    #[test]
+    fn hidden() {
+        let c = cursor(2, &[T, T, T, T]);
+        assert_eq!(c.alpha(), CursorAlpha::Straight);
+        assert_eq!(c.premultiplied_data(), c.data);
+    }
+
+    #[test]
+    fn straight_antialiased_ibeam() {
+        // white with translucent edges, a tinted shadow
+        let edge = [255, 255, 255, 128];
+        let shadow = [40, 20, 200, 64];
+        #[rustfmt::skip]
+        let c = cursor(3, &[
+            edge, W, edge,
+            T, W, shadow,
+            edge, W, edge,
+        ]);
+        assert_eq!(c.alpha(), CursorAlpha::Straight);
+        assert_eq!(c.straight_data(), c.data);
+
+        let edge = [128, 128, 128, 128];
+        let shadow = [10, 5, 50, 64];
+        #[rustfmt::skip]
+        let golden = cursor(3, &[
+            edge, W, edge,
+            T, W, shadow,
+            edge, W, edge,
+        ]);
+        assert_eq!(c.premultiplied_data(), golden.data);
+    }
+
+    #[test]
+    fn premultiplied_arrow() {
+        // a grey arrow with a translucent black shadow, premultiplied
+        let edge = [64, 64, 64, 128];
+        let shadow = [0, 0, 0, 64];
+        #[rustfmt::skip]
+        let c = cursor(3, &[
+            W, T, T,
+            W, edge, T,
+            W, W, edge,
+            shadow, shadow, shadow,
+        ]);
+        assert_eq!(c.alpha(), CursorAlpha::Premultiplied);
+        assert_eq!(c.premultiplied_data(), c.data);
+
+        let edge = [128, 128, 128, 128];
+        #[rustfmt::skip]
+        let golden = cursor(3, &[
+            W, T, T,
+            W, edge, T,
+            W, W, edge,
+            shadow, shadow, shadow,
+        ]);
+        assert_eq!(c.straight_data(), golden.data);
+    }
+
+    #[test]
+    fn round_trip() {
+        let c = cursor(2, &[[30, 60, 90, 100], [1, 2, 3, 4], [200, 0, 100, 200], W]);
+        assert_eq!(c.alpha(), CursorAlpha::Premultiplied);
+        let straight = Cursor {
+            data: c.straight_data().into_owned(),
+            ..c.clone()
+        };
+        assert_eq!(straight.alpha(), CursorAlpha::Straight);
+        assert_eq!(straight.premultiplied_data(), c.data);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{Rect, Update};
+
+// above this number of regions, the closest ones are merged
+const DEFAULT_MAX_RECTS: usize = 16;
+
+/// The regions of a frame changed since the last flush.
+///
+/// The regions are kept disjoint: the overlapping ones, and the neighbours that don't cover
+/// more than the regions themselves, are merged. Above the maximum number of regions, the
+/// pair adding the least area is merged, trading some pixels for fewer rectangles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamageTracker {
+    rects: Vec<Rect>,
+    max_rects: usize,
+}
+
+impl Default for DamageTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DamageTracker {
+    pub fn new() -> Self {
+        Self::with_max_rects(DEFAULT_MAX_RECTS)
+    }
+
+    /// A tracker keeping at most `max_rects` regions (at least one).
+    pub fn with_max_rects(max_rects: usize) -> Self {
+        Self {
+            rects: vec![],
+            max_rects: max_rects.max(1),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// The damaged regions.
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    /// The smallest rectangle containing all the regions.
+    pub fn bounds(&self) -> Rect {
+        self.rects
+            .iter()
+            .fold(Rect::default(), |acc, r| acc.union(r))
+    }
+
+    /// Add a damaged region.
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() || self.rects.iter().any(|r| contains(r, &rect)) {
+            return;
+        }
+        let mut rect = rect;
+        // a merge may reach other regions, until nothing changes
+        loop {
+            let n = self.rects.len();
+            self.rects.retain(|r| {
+                if !worth_merging(r, &rect) {
+                    return true;
+                }
+                rect = rect.union(r);
+                false
+            });
+            if n == self.rects.len() {
+                break;
+            }
+        }
+        self.rects.push(rect);
+        while self.rects.len() > self.max_rects {
+            self.merge_closest();
+        }
+    }
+
+    /// Add the region of an update, ignoring its negative part.
+    pub fn add_update(&mut self, update: &Update) {
+        let (x, y) = (update.x.max(0), update.y.max(0));
+        let right = update.x.saturating_add(update.w);
+        let bottom = update.y.saturating_add(update.h);
+        if right <= x || bottom <= y {
+            return;
+        }
+        self.add(Rect::new(
+            x as u32,
+            y as u32,
+            (right - x) as u32,
+            (bottom - y) as u32,
+        ));
+    }
+
+    /// Damage the whole frame, dropping the other regions.
+    pub fn add_all(&mut self, frame: Rect) {
+        self.rects.clear();
+        self.add(frame);
+    }
+
+    /// Take the regions, leaving the tracker empty, after a flush.
+    pub fn take(&mut self) -> Vec<Rect> {
+        std::mem::take(&mut self.rects)
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+
+    fn merge_closest(&mut self) {
+        let mut best = (0, 1, u64::MAX);
+        for i in 0..self.rects.len() {
+            for j in i + 1..self.rects.len() {
+                let (a, b) = (&self.rects[i], &self.rects[j]);
+                let waste = area(&a.union(b)) - area(a) - area(b);
+                if waste < best.2 {
+                    best = (i, j, waste);
+                }
+            }
+        }
+        let (i, j, _) = best;
+        let b = self.rects.swap_remove(j);
+        let merged = self.rects.swap_remove(i).union(&b);
+        // the merged region may overlap others
+        self.add(merged);
+    }
+}
+
+fn area(r: &Rect) -> u64 {
+    r.width as u64 * r.height as u64
+}
+
+fn contains(outer: &Rect, inner: &Rect) -> bool {
+    outer.x <= inner.x
+        && outer.y <= inner.y
+        && outer.right() >= inner.right()
+        && outer.bottom() >= inner.bottom()
+}
+
+// the regions overlap, or their union doesn't cover more pixels than them
+fn worth_merging(a: &Rect, b: &Rect) -> bool {
+    !a.intersect(b).is_empty() || area(&a.union(b)) <= area(a) + area(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disjoint() {
+        let mut d = DamageTracker::new();
+        d.add(Rect::new(0, 0, 10, 10));
+        d.add(Rect::new(100, 100, 10, 10));
+        d.add(Rect::new(0, 0, 0, 10));
+        assert_eq!(d.rects().len(), 2);
+        assert_eq!(d.bounds(), Rect::new(0, 0, 110, 110));
+    }
+
+    #[test]
+    fn overlap_and_containment() {
+        let mut d = DamageTracker::new();
+        d.add(Rect::new(0, 0, 10, 10));
+        d.add(Rect::new(2, 2, 4, 4));
+        assert_eq!(d.rects(), &[Rect::new(0, 0, 10, 10)]);
+        d.add(Rect::new(5, 5, 10, 10));
+        assert_eq!(d.rects(), &[Rect::new(0, 0, 15, 15)]);
+        d.add(Rect::new(0, 0, 20, 20));
+        assert_eq!(d.rects(), &[Rect::new(0, 0, 20, 20)]);
+    }
+
+    #[test]
+    fn adjacent() {
+        let mut d = DamageTracker::new();
+        // the rows of a scroll, merged in a single region
+        for y in 0..10 {
+            d.add(Rect::new(0, y * 4, 100, 4));
+        }
+        assert_eq!(d.rects(), &[Rect::new(0, 0, 100, 40)]);
+    }
+
+    #[test]
+    fn chained_merge() {
+        let mut d = DamageTracker::new();
+        d.add(Rect::new(0, 0, 10, 10));
+        d.add(Rect::new(20, 0, 10, 10));
+        assert_eq!(d.rects().len(), 2);
+        // bridging both
+        d.add(Rect::new(5, 0, 20, 10));
+        assert_eq!(d.rects(), &[Rect::new(0, 0, 30, 10)]);
+    }
+
+    #[test]
+    fn max_rects() {
+        let mut d = DamageTracker::with_max_rects(3);
+        d.add(Rect::new(0, 0, 1, 1));
+        d.add(Rect::new(100, 0, 1, 1));
+        d.add(Rect::new(0, 100, 1, 1));
+        d.add(Rect::new(3, 0, 1, 1));
+        assert_eq!(d.rects().len(), 3);
+        // the two closest were merged
+        assert!(d.rects().contains(&Rect::new(0, 0, 4, 1)));
+        for r in [
+            Rect::new(0, 0, 1, 1),
+            Rect::new(100, 0, 1, 1),
+            Rect::new(0, 100, 1, 1),
+        ] {
+            assert!(d.rects().iter().any(|d| contains(d, &r)));
+        }
+    }
+
+    #[test]
+    fn updates_and_flush() {
+        let mut d = DamageTracker::new();
+        let update = |x, y, w, h| Update {
+            x,
+            y,
+            w,
+            h,
+            stride: 0,
+            format: 0,
+            data: vec![],
+        };
+        d.add_update(&update(-5, -5, 10, 10));
+        d.add_update(&update(0, 0, 0, 10));
+        assert_eq!(d.rects(), &[Rect::new(0, 0, 5, 5)]);
+        d.add_all(Rect::new(0, 0, 640, 480));
+        assert_eq!(d.take(), vec![Rect::new(0, 0, 640, 480)]);
+        assert!(d.is_empty());
+    }
+}
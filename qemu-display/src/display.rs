@@ -1,21 +1,41 @@
-use futures::stream::{self, StreamExt};
+use async_broadcast::{broadcast, InactiveReceiver, Sender};
+use async_io::Timer;
+use futures::{
+    future,
+    stream::{self, StreamExt},
+    Stream,
+};
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
-    sync::Arc,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 use zbus::{
     fdo,
     fdo::ManagedObjects,
-    names::{BusName, OwnedUniqueName, UniqueName, WellKnownName},
-    Connection, OwnerChangedStream,
+    names::{
+        BusName, InterfaceName, OwnedInterfaceName, OwnedUniqueName, UniqueName, WellKnownName,
+    },
+    Connection, OwnerChangedStream, Task,
 };
-use zvariant::OwnedObjectPath;
+use zvariant::{OwnedObjectPath, OwnedValue};
 
-#[cfg(unix)]
-use crate::UsbRedir;
-use crate::{Audio, Chardev, Clipboard, Error, Result, VMProxy};
+use crate::{
+    console, timeout::CallOptions, Audio, CancellationToken, Chardev, Clipboard, Console,
+    ConsoleInfo, Error, FileTransfer, Result, RetryPolicy, UsbRedir, VMProxy, VDAGENT_CHARDEV_NAME,
+};
 
+#[cfg(feature = "qmp")]
+use crate::{
+    attach::{self, SpawnExecutor},
+    Qmp,
+};
+#[cfg(feature = "qga")]
+use crate::{GuestOs, QGA_CHARDEV_NAME};
+#[cfg(feature = "qmp")]
+use async_io::Async;
 #[cfg(all(unix, feature = "qmp"))]
 use std::os::unix::net::UnixStream;
 #[cfg(all(windows, feature = "qmp"))]
@@ -24,44 +44,140 @@ use uds_windows::UnixStream;
 struct Inner<'d> {
     proxy: fdo::ObjectManagerProxy<'d>,
     conn: Connection,
-    objects: ManagedObjects,
+    // kept up to date with the ObjectManager signals
+    objects: Arc<Mutex<ManagedObjects>>,
+    changes: InactiveReceiver<InterfaceChange>,
+    _task: Task<()>,
     #[cfg(windows)]
     peer_pid: u32,
 }
 
+const CONSOLE_INTERFACE: &str = "org.qemu.Display1.Console";
+
+/// A D-Bus interface exposed by the display, see [`Display::supports`].
+pub trait DisplayInterface {
+    const INTERFACE: &'static str;
+}
+
+impl DisplayInterface for Audio {
+    const INTERFACE: &'static str = "org.qemu.Display1.Audio";
+}
+
+impl DisplayInterface for Chardev {
+    const INTERFACE: &'static str = "org.qemu.Display1.Chardev";
+}
+
+impl DisplayInterface for Clipboard {
+    const INTERFACE: &'static str = "org.qemu.Display1.Clipboard";
+}
+
+impl DisplayInterface for Console {
+    const INTERFACE: &'static str = CONSOLE_INTERFACE;
+}
+
+/// An interface appearing or disappearing from an object of the display.
+#[derive(Debug, Clone)]
+pub enum InterfaceChange {
+    Added(OwnedObjectPath, OwnedInterfaceName),
+    Removed(OwnedObjectPath, OwnedInterfaceName),
+}
+
+/// A console appearing or disappearing from the display.
+#[derive(Debug, Clone)]
+pub enum ConsoleChange {
+    Added(ConsoleInfo),
+    Removed(u32),
+}
+
 #[derive(Clone)]
 pub struct Display<'d> {
     inner: Arc<Inner<'d>>,
+    options: CallOptions,
 }
 
 impl<'d> Display<'d> {
+    /// Look up a VM by name, or any VM. With `wait`, the lookup is retried until it is found.
     pub async fn lookup(
         conn: &Connection,
         wait: bool,
         name: Option<&str>,
+    ) -> Result<Option<OwnedUniqueName>> {
+        let policy = if wait {
+            RetryPolicy::default()
+        } else {
+            RetryPolicy::never()
+        };
+        Self::lookup_with_policy(conn, &policy, name).await
+    }
+
+    /// Look up a VM by name, or any VM, retried with `policy` until it is found.
+    ///
+    /// Besides the backoff delays, the lookup is retried when a bus name changes.
+    pub async fn lookup_with_policy(
+        conn: &Connection,
+        policy: &RetryPolicy,
+        name: Option<&str>,
+    ) -> Result<Option<OwnedUniqueName>> {
+        Self::lookup_loop(conn, policy, name).await
+    }
+
+    /// Like [`Display::lookup_with_policy`], failing with [`Error::Cancelled`] once `token`
+    /// is cancelled, to stop waiting for a VM.
+    pub async fn lookup_with_cancel(
+        conn: &Connection,
+        policy: &RetryPolicy,
+        name: Option<&str>,
+        token: &CancellationToken,
+    ) -> Result<Option<OwnedUniqueName>> {
+        let options = CallOptions {
+            timeout: None,
+            cancel: Some(token.clone()),
+        };
+        options
+            .call("VM lookup", Self::lookup_loop(conn, policy, name))
+            .await
+    }
+
+    async fn lookup_loop(
+        conn: &Connection,
+        policy: &RetryPolicy,
+        name: Option<&str>,
     ) -> Result<Option<OwnedUniqueName>> {
         let mut changed = fdo::DBusProxy::new(conn)
             .await?
             .receive_name_owner_changed()
             .await?;
+        let mut backoff = policy.backoff("VM lookup");
         loop {
             let list = Display::by_name(conn).await?;
             if let Some(name) = name {
                 let res = list.get(name);
                 if res.is_some() {
+                    backoff.reset();
                     return Ok(res.cloned());
                 }
             } else if !list.is_empty() {
+                backoff.reset();
                 return Ok(None);
             }
-            if !wait {
-                return Err(Error::Failed("Can't find VM".into()));
-            };
-            let _ = changed.next().await;
+            let delay = backoff
+                .next_delay()
+                .ok_or_else(|| Error::Failed("Can't find VM".into()))?;
+            future::select(changed.next(), Timer::after(delay)).await;
         }
     }
 
+    /// The VMs owning or queued for "org.qemu", by name.
+    ///
+    /// A VM that doesn't reply within the [`default_timeout`](crate::default_timeout) fails
+    /// the lookup with [`Error::Timeout`].
     pub async fn by_name(conn: &Connection) -> Result<HashMap<String, OwnedUniqueName>> {
+        CallOptions::default()
+            .call("VM names", Self::names(conn))
+            .await
+    }
+
+    async fn names(conn: &Connection) -> Result<HashMap<String, OwnedUniqueName>> {
         let mut hm = HashMap::new();
         let list = match fdo::DBusProxy::new(conn)
             .await?
@@ -84,6 +200,10 @@ impl<'d> Display<'d> {
         Ok(hm)
     }
 
+    /// The display of the VM owning `dest`, or "org.qemu" by default.
+    ///
+    /// The display calls, and the creation, time out after the
+    /// [`default_timeout`](crate::default_timeout), see [`Display::with_timeout`].
     pub async fn new<D>(
         conn: &Connection,
         dest: Option<D>,
@@ -93,110 +213,216 @@ impl<'d> Display<'d> {
         D: TryInto<BusName<'d>>,
         D::Error: Into<Error>,
     {
+        let dest = dest.map(|d| d.try_into().map_err(Into::into)).transpose()?;
+        let options = CallOptions::default();
+        options
+            .call(
+                "display creation",
+                Self::build(
+                    conn,
+                    dest,
+                    options.clone(),
+                    #[cfg(windows)]
+                    peer_pid,
+                ),
+            )
+            .await
+    }
+
+    async fn build(
+        conn: &Connection,
+        dest: Option<BusName<'d>>,
+        options: CallOptions,
+        #[cfg(windows)] peer_pid: u32,
+    ) -> Result<Display<'d>> {
         let builder = fdo::ObjectManagerProxy::builder(conn);
         let builder = if let Some(dest) = dest {
-            let dest = dest.try_into().map_err(Into::into)?;
             builder.destination(dest)?
         } else {
             builder
         };
         let proxy = builder.path("/org/qemu/Display1")?.build().await?;
-        let objects = proxy.get_managed_objects().await?;
-        // TODO: listen for changes
+        // subscribe before fetching the objects, to not miss any change
+        let watch = fdo::ObjectManagerProxy::builder(conn)
+            .destination(proxy.destination().to_owned())?
+            .path("/org/qemu/Display1")?
+            .build()
+            .await?;
+        let added = watch.receive_interfaces_added().await?;
+        let removed = watch.receive_interfaces_removed().await?;
+        let objects = Arc::new(Mutex::new(proxy.get_managed_objects().await?));
+
+        let (mut sender, changes) = broadcast(16);
+        sender.set_overflow(true);
+        let task = conn
+            .executor()
+            .spawn(watch_objects(objects.clone(), added, removed, sender));
         let inner = Inner {
             // owner_changed,
             proxy,
             conn: conn.clone(),
             objects,
+            changes: changes.deactivate(),
+            _task: task,
             #[cfg(windows)]
             peer_pid,
         };
 
         Ok(Self {
             inner: Arc::new(inner),
+            options,
         })
     }
 
+    /// Set the timeout of the display calls, `None` to wait forever.
+    ///
+    /// The consoles of the display get the same timeout. A call that times out fails with
+    /// [`Error::Timeout`].
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    /// Fail the display calls, and those of its consoles, with [`Error::Cancelled`] once
+    /// `token` is cancelled.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = Some(token);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.options.timeout
+    }
+
     pub fn connection(&self) -> &Connection {
         &self.inner.conn
     }
 
+    pub(crate) fn destination(&self) -> BusName<'static> {
+        self.inner.proxy.destination().to_owned()
+    }
+
     #[cfg(windows)]
     pub fn peer_pid(&self) -> u32 {
         self.inner.peer_pid
     }
 
+    /// Connect to a VM started with `-display dbus,p2p=yes`, through its QMP socket.
+    ///
+    /// A D-Bus socket is added as a display client, the connection doesn't need a bus.
     #[cfg(feature = "qmp")]
     pub async fn new_qmp<P: AsRef<std::path::Path>>(path: P) -> Result<Display<'d>> {
-        use qapi::{qmp, Qmp};
-
-        let stream = UnixStream::connect(path)?;
-        let mut qmp = Qmp::from_stream(&stream);
-        let _info = qmp.handshake()?;
-
-        let (p0, p1) = UnixStream::pair()?;
+        Self::new_qmp_spawn(path, None).await
+    }
 
+    // like new_qmp, with the executor of the connection spawned by `spawn`, if any
+    #[cfg(feature = "qmp")]
+    pub(crate) async fn new_qmp_spawn<P: AsRef<std::path::Path>>(
+        path: P,
+        spawn: Option<SpawnExecutor>,
+    ) -> Result<Display<'d>> {
         #[cfg(unix)]
         {
-            // FIXME: no ancillary fd API at this point
-            // https://github.com/rust-lang/rust/issues/76915
-            qmp.execute(&qmp::getfd {
-                fdname: "fdname".into(),
-            })?;
+            Ok(Self::unix_socket_with_qmp(path, spawn).await?.0)
         }
+
         #[cfg(windows)]
-        let pid = {
+        {
             use crate::win32::{duplicate_socket, unix_stream_get_peer_pid};
-            use serde::{Deserialize, Serialize};
             use std::os::windows::io::AsRawSocket;
             use windows::Win32::Networking::WinSock::SOCKET;
 
-            #[allow(non_camel_case_types)]
-            #[derive(Debug, Clone, Serialize, Deserialize)]
-            pub struct get_win32_socket {
-                #[serde(rename = "info")]
-                pub info: ::std::string::String,
-                #[serde(rename = "fdname")]
-                pub fdname: ::std::string::String,
-            }
-
-            impl qmp::QmpCommand for get_win32_socket {}
-            impl qapi::Command for get_win32_socket {
-                const NAME: &'static str = "get-win32-socket";
-                const ALLOW_OOB: bool = false;
+            let stream = Async::new(UnixStream::connect(path)?)?;
+            let pid = unix_stream_get_peer_pid(stream.get_ref())?;
+            let mut qmp = Qmp::new(stream).await?;
 
-                type Ok = qapi::Empty;
-            }
-
-            let pid = unix_stream_get_peer_pid(&stream)?;
+            let (p0, p1) = UnixStream::pair()?;
             let info = duplicate_socket(pid, SOCKET(p0.as_raw_socket() as _))?;
             let info = base64::encode(info);
-            qmp.execute(&get_win32_socket {
-                info,
-                fdname: "fdname".into(),
-            })?;
-            pid
-        };
+            qmp.get_win32_socket(&info, "fdname").await?;
+            qmp.add_client("@dbus-display", "fdname").await?;
 
-        qmp.execute(&qmp::add_client {
-            skipauth: None,
-            tls: None,
-            protocol: "@dbus-display".into(),
-            fdname: "fdname".into(),
-        })?;
+            let conn =
+                attach::connect(zbus::ConnectionBuilder::unix_stream(p1).p2p(), spawn).await?;
+            Self::new(&conn, Option::<String>::None, pid).await
+        }
+    }
 
-        let conn = zbus::ConnectionBuilder::unix_stream(p1)
-            .p2p()
-            .build()
-            .await?;
+    /// Connect to a VM started with `-display dbus,p2p=yes`, through its QMP unix socket.
+    ///
+    /// The D-Bus socket is passed with `getfd` (SCM_RIGHTS), and added as a display client.
+    #[cfg(all(unix, feature = "qmp"))]
+    pub async fn new_unix_socket<P: AsRef<std::path::Path>>(path: P) -> Result<Display<'d>> {
+        Ok(Self::new_unix_socket_with_qmp(path).await?.0)
+    }
 
-        Self::new(
-            &conn,
-            Option::<String>::None,
-            #[cfg(windows)]
-            pid,
-        )
-        .await
+    /// Like [`Display::new_unix_socket`], keeping the QMP connection, for example for a
+    /// [`VmControl`](crate::VmControl).
+    #[cfg(all(unix, feature = "qmp"))]
+    pub async fn new_unix_socket_with_qmp<P: AsRef<std::path::Path>>(
+        path: P,
+    ) -> Result<(Display<'d>, Qmp<Async<UnixStream>>)> {
+        Self::unix_socket_with_qmp(path, None).await
+    }
+
+    #[cfg(all(unix, feature = "qmp"))]
+    async fn unix_socket_with_qmp<P: AsRef<std::path::Path>>(
+        path: P,
+        spawn: Option<SpawnExecutor>,
+    ) -> Result<(Display<'d>, Qmp<Async<UnixStream>>)> {
+        use std::os::unix::io::AsRawFd;
+
+        let stream = Async::new(UnixStream::connect(path)?)?;
+        let mut qmp = Qmp::new(stream).await?;
+
+        let (p0, p1) = UnixStream::pair()?;
+        qmp.getfd("fdname", p0.as_raw_fd()).await?;
+        qmp.add_client("@dbus-display", "fdname").await?;
+        // QEMU has its own copy now
+        drop(p0);
+
+        let conn = attach::connect(zbus::ConnectionBuilder::unix_stream(p1).p2p(), spawn).await?;
+        let display = Self::new(&conn, Option::<String>::None).await?;
+        Ok((display, qmp))
+    }
+
+    /// The interfaces of each display object, as currently known.
+    pub fn interfaces(&self) -> HashMap<OwnedObjectPath, Vec<OwnedInterfaceName>> {
+        self.inner
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, ifaces)| (path.clone(), ifaces.keys().cloned().collect()))
+            .collect()
+    }
+
+    /// Whether an object of the display implements the interface of `T`.
+    pub fn supports<T: DisplayInterface>(&self) -> bool {
+        self.has_interface(T::INTERFACE)
+    }
+
+    fn has_interface(&self, iface: &str) -> bool {
+        self.inner
+            .objects
+            .lock()
+            .unwrap()
+            .values()
+            .any(|ifaces| ifaces.keys().any(|i| i.as_str() == iface))
+    }
+
+    fn object_has_interface(&self, path: &str, iface: &str) -> bool {
+        self.inner
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(p, ifaces)| p.as_str() == path && ifaces.keys().any(|i| i.as_str() == iface))
+    }
+
+    /// A stream of the interfaces added or removed, after the display creation.
+    pub fn receive_interface_changes(&self) -> Pin<Box<dyn Stream<Item = InterfaceChange> + Send>> {
+        Box::pin(self.inner.changes.activate_cloned())
     }
 
     pub async fn receive_owner_changed(&self) -> Result<OwnerChangedStream<'_>> {
@@ -204,49 +430,162 @@ impl<'d> Display<'d> {
     }
 
     pub async fn audio(&self) -> Result<Option<Audio>> {
-        if !self
-            .inner
-            .objects
-            .contains_key(&OwnedObjectPath::try_from("/org/qemu/Display1/Audio").unwrap())
-        {
+        if !self.object_has_interface("/org/qemu/Display1/Audio", Audio::INTERFACE) {
             return Ok(None);
         }
 
-        Ok(Some(
-            Audio::new(
-                &self.inner.conn,
-                #[cfg(windows)]
-                self.peer_pid(),
+        let audio = self
+            .options
+            .call(
+                "audio creation",
+                Audio::new(
+                    &self.inner.conn,
+                    #[cfg(windows)]
+                    self.peer_pid(),
+                ),
             )
-            .await?,
-        ))
+            .await?;
+        Ok(Some(audio))
     }
 
     pub async fn clipboard(&self) -> Result<Option<Clipboard>> {
-        if !self
-            .inner
-            .objects
-            .contains_key(&OwnedObjectPath::try_from("/org/qemu/Display1/Clipboard").unwrap())
-        {
+        if !self.object_has_interface("/org/qemu/Display1/Clipboard", Clipboard::INTERFACE) {
             return Ok(None);
         }
 
-        Ok(Some(Clipboard::new(&self.inner.conn).await?))
+        let clipboard = self
+            .options
+            .call("clipboard creation", Clipboard::new(&self.inner.conn))
+            .await?;
+        Ok(Some(clipboard))
     }
 
-    pub async fn chardevs(&self) -> Vec<Chardev> {
-        stream::iter(&self.inner.objects)
-            .filter_map(|(p, _ifaces)| async move {
-                match p.strip_prefix("/org/qemu/Display1/Chardev_") {
-                    Some(id) => Chardev::new(&self.inner.conn, id).await.ok(),
+    /// The name of the VM.
+    pub async fn name(&self) -> Result<String> {
+        let name = async {
+            let vm = VMProxy::builder(&self.inner.conn)
+                .destination(self.destination())?
+                .build()
+                .await?;
+            Ok(vm.name().await?)
+        };
+        self.options.call("VM name", name).await
+    }
+
+    /// The UUID of the VM, to keep settings by VM between the runs.
+    pub async fn uuid(&self) -> Result<String> {
+        let uuid = async {
+            let vm = VMProxy::builder(&self.inner.conn)
+                .destination(self.destination())?
+                .build()
+                .await?;
+            Ok(vm.uuid().await?)
+        };
+        self.options.call("VM UUID", uuid).await
+    }
+
+    /// Get a console of this VM.
+    ///
+    /// Unlike [`Console::new`], the console of a VM that doesn't own "org.qemu" can be used.
+    /// Fails with [`Error::ObjectNotFound`] if the VM has no such console.
+    pub async fn console(&self, idx: u32) -> Result<Console> {
+        let path = format!("{}{}", console::CONSOLE_PATH_PREFIX, idx);
+        if !self.object_has_interface(&path, CONSOLE_INTERFACE) {
+            return Err(Error::ObjectNotFound { path });
+        }
+        Console::with_destination(
+            &self.inner.conn,
+            Some(self.destination()),
+            idx,
+            self.options.clone(),
+            #[cfg(windows)]
+            self.inner.peer_pid,
+        )
+        .await
+    }
+
+    pub async fn consoles(&self) -> Result<Vec<ConsoleInfo>> {
+        let objects = self.inner.objects.lock().unwrap().clone();
+        let mut consoles: Vec<_> = objects
+            .iter()
+            .filter_map(|(p, ifaces)| {
+                let id = console::console_id(p.as_str())?;
+                let props = ifaces.iter().find_map(|(name, props)| {
+                    if name.as_str() == CONSOLE_INTERFACE {
+                        Some(props)
+                    } else {
+                        None
+                    }
+                })?;
+                Some(ConsoleInfo::from_properties(id, props))
+            })
+            .collect();
+        consoles.sort_by_key(|c| c.id);
+        Ok(consoles)
+    }
+
+    /// A stream of the consoles added or removed, after the display creation.
+    ///
+    /// The changes are emitted once the display objects are updated: an added console can
+    /// be opened with [`Display::console`], for example a head enabled by the guest.
+    pub async fn receive_console_changes(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = ConsoleChange> + Send + 'd>>> {
+        let objects = self.inner.objects.clone();
+        let changes = self.receive_interface_changes().filter_map(move |change| {
+            let objects = objects.clone();
+            async move {
+                match change {
+                    InterfaceChange::Added(path, iface) if iface.as_str() == CONSOLE_INTERFACE => {
+                        let id = console::console_id(path.as_str())?;
+                        // gone if it was removed since
+                        let objects = objects.lock().unwrap();
+                        let props = objects.get(&path)?.get(&iface)?;
+                        Some(ConsoleChange::Added(ConsoleInfo::from_properties(
+                            id, props,
+                        )))
+                    }
+                    InterfaceChange::Removed(path, iface)
+                        if iface.as_str() == CONSOLE_INTERFACE =>
+                    {
+                        console::console_id(path.as_str()).map(ConsoleChange::Removed)
+                    }
                     _ => None,
                 }
+            }
+        });
+        Ok(Box::pin(changes))
+    }
+
+    pub async fn chardevs(&self) -> Vec<Chardev> {
+        let ids: Vec<_> = self
+            .inner
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|p| p.strip_prefix("/org/qemu/Display1/Chardev_"))
+            .map(String::from)
+            .collect();
+        stream::iter(ids)
+            .filter_map(|id| async move {
+                self.options
+                    .call(
+                        "chardev creation",
+                        Chardev::new(
+                            &self.inner.conn,
+                            &id,
+                            #[cfg(windows)]
+                            self.peer_pid(),
+                        ),
+                    )
+                    .await
+                    .ok()
             })
             .collect()
             .await
     }
 
-    #[cfg(unix)]
     pub async fn usbredir(&self) -> UsbRedir {
         let chardevs = stream::iter(self.chardevs().await)
             .filter_map(|c| async move {
@@ -261,4 +600,94 @@ impl<'d> Display<'d> {
 
         UsbRedir::new(chardevs)
     }
+
+    /// The file transfer channel, if a chardev is connected to the guest agent port.
+    pub async fn file_transfer(&self) -> Result<Option<FileTransfer>> {
+        for c in self.chardevs().await {
+            if c.proxy.name().await.ok().as_deref() == Some(VDAGENT_CHARDEV_NAME) {
+                let transfer = self
+                    .options
+                    .call("file transfer creation", FileTransfer::new(&c))
+                    .await?;
+                return Ok(Some(transfer));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The guest OS, from the guest agent, if a chardev is connected to its port.
+    #[cfg(feature = "qga")]
+    pub async fn guest_os(&self) -> Result<Option<GuestOs>> {
+        for c in self.chardevs().await {
+            if c.proxy.name().await.ok().as_deref() == Some(QGA_CHARDEV_NAME) {
+                let os = self
+                    .options
+                    .call("guest OS query", crate::guest::query_os(&c))
+                    .await?;
+                return Ok(Some(os));
+            }
+        }
+        Ok(None)
+    }
+}
+
+async fn watch_objects(
+    objects: Arc<Mutex<ManagedObjects>>,
+    added: fdo::InterfacesAddedStream<'static>,
+    removed: fdo::InterfacesRemovedStream<'static>,
+    sender: Sender<InterfaceChange>,
+) {
+    enum Signal {
+        Added(fdo::InterfacesAdded),
+        Removed(fdo::InterfacesRemoved),
+    }
+
+    let mut signals = stream::select(added.map(Signal::Added), removed.map(Signal::Removed));
+    while let Some(signal) = signals.next().await {
+        let mut changes = vec![];
+        match signal {
+            Signal::Added(signal) => {
+                let args = match signal.args() {
+                    Ok(args) => args,
+                    Err(_) => continue,
+                };
+                let path = OwnedObjectPath::from(args.object_path().to_owned());
+                let mut objects = objects.lock().unwrap();
+                let ifaces = objects.entry(path.clone()).or_default();
+                for (iface, props) in args.interfaces_and_properties() {
+                    let name = match InterfaceName::try_from(*iface) {
+                        Ok(name) => OwnedInterfaceName::from(name.to_owned()),
+                        Err(_) => continue,
+                    };
+                    let props = props
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), OwnedValue::from(v)))
+                        .collect();
+                    ifaces.insert(name.clone(), props);
+                    changes.push(InterfaceChange::Added(path.clone(), name));
+                }
+            }
+            Signal::Removed(signal) => {
+                let args = match signal.args() {
+                    Ok(args) => args,
+                    Err(_) => continue,
+                };
+                let path = OwnedObjectPath::from(args.object_path().to_owned());
+                let mut objects = objects.lock().unwrap();
+                if let Some(ifaces) = objects.get_mut(&path) {
+                    for iface in args.interfaces() {
+                        if let Some((name, _)) = ifaces.remove_entry(*iface) {
+                            changes.push(InterfaceChange::Removed(path.clone(), name));
+                        }
+                    }
+                    if ifaces.is_empty() {
+                        objects.remove(&path);
+                    }
+                }
+            }
+        }
+        for change in changes {
+            let _ = sender.broadcast(change).await;
+        }
+    }
 }
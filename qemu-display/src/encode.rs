@@ -0,0 +1,425 @@
+//! Video encoding of the console frames, with a GStreamer pipeline.
+//!
+//! The frames are encoded by `gst-launch-1.0`, fed with the raw x8r8g8b8 frames on its
+//! standard input at a constant rate, and the bitstream is read from its standard output.
+//! Running the pipeline in a process keeps GStreamer out of the dependencies, and any
+//! installed encoder can be used, hardware ones included. The pipeline is split in
+//! arguments like a shell would, the quoted property values may have spaces.
+
+use async_broadcast::{broadcast, Receiver, Sender};
+use futures::{Stream, StreamExt};
+use std::{
+    io::{Read, Write},
+    pin::Pin,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    AdaptiveSink, Error, FramePathMode, FramePathPolicy, Framebuffer, FramebufferEvent,
+    FramebufferSink, ListenerConnection, Rect, Result, SharedFramebuffer,
+};
+
+const GST_LAUNCH: &str = "gst-launch-1.0";
+// the encoded chunks queued for the consumer, the encoder waits beyond
+const MAX_QUEUED: usize = 64;
+
+/// The codec of an [`EncodedVideo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    /// H.264, in an Annex B byte-stream, with the parameter sets on each key frame.
+    H264,
+    /// VP8, in a streaming WebM.
+    Vp8,
+}
+
+/// Options of [`Console::encode`](crate::Console::encode).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderConfig {
+    pub codec: VideoCodec,
+    /// The frames per second, the frames are repeated while the console doesn't change.
+    pub framerate: u32,
+    /// The target bitrate, in kbit/s.
+    pub bitrate: u32,
+    /// The GStreamer encoder of the codec, in gst-launch syntax (for example
+    /// `vaapih264enc rate-control=cbr`), instead of x264enc or vp8enc. The bitrate is not
+    /// applied to it.
+    pub encoder: Option<String>,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            codec: VideoCodec::H264,
+            framerate: 30,
+            bitrate: 2000,
+            encoder: None,
+        }
+    }
+}
+
+impl EncoderConfig {
+    // the gst-launch arguments, for frames of width x height
+    fn args(&self, width: u32, height: u32) -> Result<Vec<String>> {
+        shell_words::split(&self.pipeline(width, height))
+            .map_err(|e| Error::Failed(format!("Invalid encoder pipeline: {}", e)))
+    }
+
+    // the gst-launch pipeline, for frames of width x height
+    fn pipeline(&self, width: u32, height: u32) -> String {
+        // a key frame every 2 seconds, for the consumers joining late or losing data
+        let keyframes = self.framerate * 2;
+        let encoder = match (&self.encoder, self.codec) {
+            (Some(encoder), _) => encoder.clone(),
+            (None, VideoCodec::H264) => format!(
+                "x264enc tune=zerolatency speed-preset=ultrafast bitrate={} key-int-max={}",
+                self.bitrate, keyframes
+            ),
+            (None, VideoCodec::Vp8) => format!(
+                "vp8enc deadline=1 target-bitrate={} keyframe-max-dist={}",
+                self.bitrate * 1000,
+                keyframes
+            ),
+        };
+        let output = match self.codec {
+            VideoCodec::H264 => {
+                "h264parse config-interval=-1 ! video/x-h264,stream-format=byte-stream,alignment=au"
+            }
+            VideoCodec::Vp8 => "webmmux streaming=true",
+        };
+        format!(
+            "fdsrc fd=0 blocksize={} ! rawvideoparse width={} height={} format=bgrx framerate={}/1 \
+             ! videoconvert ! {} ! {} ! fdsink fd=1 sync=false",
+            width as usize * height as usize * 4,
+            width,
+            height,
+            self.framerate,
+            encoder,
+            output
+        )
+    }
+}
+
+/// An event of an [`EncodedVideo`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodedEvent {
+    /// A new bitstream starts, with frames of the given size. The previous one ended, the
+    /// console was resized.
+    Started { width: u32, height: u32 },
+    /// The next bytes of the current bitstream.
+    Data(Vec<u8>),
+}
+
+// errors are sent as strings, the channel items must be Clone
+type EncodeResult = std::result::Result<EncodedEvent, String>;
+
+// a running gst-launch
+struct Pipeline {
+    size: (u32, u32),
+    child: Child,
+    stdin: Option<ChildStdin>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Pipeline {
+    fn spawn(
+        config: &EncoderConfig,
+        size: (u32, u32),
+        sender: Sender<EncodeResult>,
+    ) -> Result<Self> {
+        let mut child = Command::new(GST_LAUNCH)
+            .arg("-q")
+            .args(config.args(size.0, size.1)?)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Failed(format!("Failed to run {}: {}", GST_LAUNCH, e)))?;
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = thread::spawn(move || {
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = match stdout.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => n,
+                };
+                let data = EncodedEvent::Data(buf[..n].to_vec());
+                if async_io::block_on(sender.broadcast(Ok(data))).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            size,
+            child,
+            stdin,
+            reader: Some(reader),
+        })
+    }
+
+    fn write(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => stdin.write_all(frame),
+            None => Ok(()),
+        }
+    }
+
+    // end the input, and wait for the rest of the bitstream
+    fn finish(mut self) -> Result<()> {
+        self.stdin.take();
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(Error::Failed(format!(
+                "{} exited with {}",
+                GST_LAUNCH, status
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Feeds the frames of a [`SharedFramebuffer`] to the pipeline.
+pub(crate) struct VideoEncoder {
+    config: EncoderConfig,
+    framebuffer: SharedFramebuffer,
+    // the region changed since the last frame was copied
+    damage: Arc<Mutex<Rect>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl VideoEncoder {
+    pub(crate) fn new(config: EncoderConfig) -> Result<Self> {
+        if config.framerate == 0 {
+            return Err(Error::Failed("Invalid framerate 0".into()));
+        }
+        Ok(Self {
+            config,
+            // resized by the first scanout
+            framebuffer: SharedFramebuffer::new(0, 0)?,
+            damage: Default::default(),
+            stop: Default::default(),
+        })
+    }
+
+    /// The sink keeping the frames, the DMABUF scanouts are read back.
+    pub(crate) fn sink(&self) -> AdaptiveSink<FramebufferSink> {
+        let (damage, stop) = (self.damage.clone(), self.stop.clone());
+        let sink = self.framebuffer.sink(move |event| match event {
+            FramebufferEvent::Damage(rect) => {
+                let mut damage = damage.lock().unwrap();
+                *damage = damage.union(&rect);
+            }
+            FramebufferEvent::Disconnected => stop.store(true, Ordering::Relaxed),
+            // a resize is noticed from the size, and copied entirely
+            _ => {}
+        });
+        let policy = FramePathPolicy {
+            mode: FramePathMode::Copy,
+            ..Default::default()
+        };
+        AdaptiveSink::new(sink, policy).0
+    }
+
+    /// Start encoding the frames of the registered sink.
+    pub(crate) fn start(self, listener: ListenerConnection) -> EncodedVideo {
+        let (sender, receiver) = broadcast(MAX_QUEUED);
+        let stop = self.stop.clone();
+        thread::spawn(move || self.run(sender));
+        EncodedVideo {
+            receiver,
+            stop,
+            listener,
+        }
+    }
+
+    fn run(self, sender: Sender<EncodeResult>) {
+        let interval = Duration::from_secs(1) / self.config.framerate;
+        let mut pipeline: Option<Pipeline> = None;
+        let mut next = Instant::now();
+        // the frame written to the pipeline, updated with the damaged region only
+        let (mut frame, mut frame_size) = (Vec::new(), (0, 0));
+        let res = loop {
+            if self.stop.load(Ordering::Relaxed) {
+                break Ok(());
+            }
+            // taken first, the damage notified later is copied with the next frame
+            let damage = std::mem::take(&mut *self.damage.lock().unwrap());
+            let size = {
+                let state = self.framebuffer.lock();
+                let fb = &state.framebuffer;
+                let size = (fb.width(), fb.height());
+                if size != frame_size {
+                    frame.clear();
+                    frame.extend_from_slice(fb.data());
+                    frame_size = size;
+                } else {
+                    copy_region(fb, damage, &mut frame);
+                }
+                size
+            };
+            if pipeline.as_ref().map(|p| p.size) != Some(size) {
+                if let Some(Err(e)) = pipeline.take().map(Pipeline::finish) {
+                    break Err(e);
+                }
+                if size.0 > 0 && size.1 > 0 {
+                    let started = EncodedEvent::Started {
+                        width: size.0,
+                        height: size.1,
+                    };
+                    if async_io::block_on(sender.broadcast(Ok(started))).is_err() {
+                        break Ok(());
+                    }
+                    match Pipeline::spawn(&self.config, size, sender.clone()) {
+                        Ok(p) => pipeline = Some(p),
+                        Err(e) => break Err(e),
+                    }
+                }
+            }
+            if let Some(p) = &mut pipeline {
+                if let Err(e) = p.write(&frame) {
+                    // the encoder failed, its exit status tells more
+                    let res = pipeline.take().unwrap().finish();
+                    break res.and(Err(e.into()));
+                }
+            }
+
+            next += interval;
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            } else {
+                // too slow, don't catch up
+                next = now;
+            }
+        };
+        let res = match (res, pipeline.map(Pipeline::finish)) {
+            (Err(e), _) | (Ok(()), Some(Err(e))) => Err(e),
+            _ => Ok(()),
+        };
+        if let Err(e) = res {
+            let _ = async_io::block_on(sender.broadcast(Err(e.to_string())));
+        }
+    }
+}
+
+// copy a region of the framebuffer to a frame of the same size
+fn copy_region(fb: &Framebuffer, rect: Rect, frame: &mut [u8]) {
+    let rect = rect.intersect(&fb.rect());
+    if rect.is_empty() {
+        return;
+    }
+    let stride = fb.stride() as usize;
+    let row = rect.width as usize * 4;
+    for y in rect.y as usize..rect.bottom() as usize {
+        let start = y * stride + rect.x as usize * 4;
+        frame[start..start + row].copy_from_slice(&fb.data()[start..start + row]);
+    }
+}
+
+/// The encoded frames of a console, see [`Console::encode`](crate::Console::encode).
+///
+/// The stream ends when the listener is disconnected, or with an error of the encoder. A
+/// slow consumer slows down the encoder, which skips frames: the bitstream is not altered.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct EncodedVideo {
+    #[derivative(Debug = "ignore")]
+    receiver: Receiver<EncodeResult>,
+    stop: Arc<AtomicBool>,
+    listener: ListenerConnection,
+}
+
+impl EncodedVideo {
+    pub fn listener(&self) -> &ListenerConnection {
+        &self.listener
+    }
+}
+
+impl Drop for EncodedVideo {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Stream for EncodedVideo {
+    type Item = Result<EncodedEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver
+            .poll_next_unpin(cx)
+            .map(|res| res.map(|res| res.map_err(Error::Failed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipeline() {
+        let config = EncoderConfig {
+            codec: VideoCodec::Vp8,
+            framerate: 10,
+            bitrate: 500,
+            encoder: None,
+        };
+        let pipeline = config.pipeline(640, 480);
+        assert!(pipeline.starts_with(
+            "fdsrc fd=0 blocksize=1228800 ! rawvideoparse width=640 height=480 format=bgrx \
+             framerate=10/1 ! videoconvert ! vp8enc deadline=1 target-bitrate=500000 \
+             keyframe-max-dist=20 ! webmmux"
+        ));
+
+        let config = EncoderConfig {
+            encoder: Some("vaapih264enc".into()),
+            ..Default::default()
+        };
+        assert!(config
+            .pipeline(640, 480)
+            .contains("! vaapih264enc ! h264parse config-interval=-1 !"));
+
+        // the quoted values are single arguments
+        let config = EncoderConfig {
+            encoder: Some(r#"x264enc name="my  encoder" ! video/x-h264,profile=high"#.into()),
+            ..Default::default()
+        };
+        let args = config.args(640, 480).unwrap();
+        assert!(args.contains(&"name=my  encoder".to_string()));
+        assert!(args.contains(&"video/x-h264,profile=high".to_string()));
+        let config = EncoderConfig {
+            encoder: Some(r#"x264enc name="unterminated"#.into()),
+            ..Default::default()
+        };
+        assert!(config.args(640, 480).is_err());
+    }
+
+    #[test]
+    fn region() {
+        let mut fb = Framebuffer::new(4, 3).unwrap();
+        let mut frame = fb.data().to_vec();
+        let update = crate::Update {
+            x: 1,
+            y: 1,
+            w: 2,
+            h: 1,
+            stride: 8,
+            format: crate::PIXMAN_X8R8G8B8,
+            data: vec![0xff; 8],
+        };
+        fb.update(&update).unwrap();
+        copy_region(&fb, Rect::new(1, 1, 2, 1), &mut frame);
+        assert_eq!(frame, fb.data());
+        // clipped to the framebuffer
+        copy_region(&fb, Rect::new(3, 2, 10, 10), &mut frame);
+        assert_eq!(frame, fb.data());
+    }
+}
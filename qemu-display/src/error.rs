@@ -1,58 +1,141 @@
-use std::{convert::Infallible, error, fmt, io};
+use std::{convert::Infallible, io, time::Duration};
 
 use usbredirhost::rusb;
 
 #[cfg(feature = "qmp")]
 use qapi::ExecuteError;
 
-#[derive(Debug)]
+const UNKNOWN_OBJECT: &str = "org.freedesktop.DBus.Error.UnknownObject";
+
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    Io(io::Error),
-    Zbus(zbus::Error),
-    Rusb(rusb::Error),
-    Usbredir(usbredirhost::Error),
+    #[error("IO error: {0}")]
+    Io(#[source] io::Error),
+    #[error("zbus error: {0}")]
+    Zbus(#[source] zbus::Error),
+    /// A D-Bus method call failed, with the interface and member that were called.
+    #[error("{interface}.{member} failed: {source}")]
+    MethodCall {
+        interface: String,
+        member: String,
+        #[source]
+        source: zbus::Error,
+    },
+    /// There is no D-Bus object at the path, for example a console that was removed.
+    #[error("no D-Bus object at {path}")]
+    ObjectNotFound { path: String },
+    /// Registering a console listener failed, with the cause.
+    #[error("failed to set up the console listener: {source}")]
+    ListenerSetup {
+        #[source]
+        source: Box<Error>,
+    },
+    /// The peer sent an invalid or unexpected message.
+    #[error("protocol error: {msg}")]
+    Protocol { msg: String },
+    /// A call didn't complete in time, see [`set_default_timeout`](crate::set_default_timeout).
+    #[error("{what} timed out after {timeout:?}")]
+    Timeout {
+        what: &'static str,
+        timeout: Duration,
+    },
+    /// A call was cancelled with a [`CancellationToken`](crate::CancellationToken).
+    #[error("cancelled")]
+    Cancelled,
+    #[error("rusb error: {0}")]
+    Rusb(#[source] rusb::Error),
+    #[error("usbredir error: {0}")]
+    Usbredir(#[source] usbredirhost::Error),
+    #[error("{0}")]
     Failed(String),
+    /// The resource is used by another D-Bus client, with the given name.
+    #[error("in use by {0}")]
+    InUse(String),
+    /// The display dimensions exceed [`MAX_WIDTH`](crate::MAX_WIDTH) x
+    /// [`MAX_HEIGHT`](crate::MAX_HEIGHT), or the frame size overflows.
+    #[error(
+        "unsupported display size {width}x{height} (max {}x{})",
+        crate::MAX_WIDTH,
+        crate::MAX_HEIGHT
+    )]
+    TooLarge { width: u32, height: u32 },
+    /// The process ran out of file descriptors, with its usage when known (see
+    /// [`fd_usage`](crate::fd_usage)).
+    #[error(
+        "too many open files{}: raise the limit (ulimit -n, or LimitNOFILE= for a systemd \
+         service), or look for leaked listeners and DMABUF scanouts",
+        fd_count(*open, *limit)
+    )]
+    FdLimit {
+        open: Option<usize>,
+        limit: Option<u64>,
+    },
     #[cfg(feature = "qmp")]
-    Qmp(ExecuteError),
+    #[error("qmp error: {0}")]
+    Qmp(#[source] ExecuteError),
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Error::Io(e) => write!(f, "IO error: {}", e),
-            Error::Zbus(e) => write!(f, "zbus error: {}", e),
-            Error::Rusb(e) => write!(f, "rusb error: {}", e),
-            Error::Usbredir(e) => write!(f, "usbredir error: {}", e),
-            Error::Failed(e) => write!(f, "{}", e),
-            #[cfg(feature = "qmp")]
-            Error::Qmp(e) => write!(f, "qmp error: {}", e),
-        }
+fn fd_count(open: Option<usize>, limit: Option<u64>) -> String {
+    match (open, limit) {
+        (Some(open), Some(limit)) => format!(" ({} of {})", open, limit),
+        _ => String::new(),
     }
 }
 
-impl error::Error for Error {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+impl Error {
+    /// A protocol error, with the message.
+    pub fn protocol<S: Into<String>>(msg: S) -> Self {
+        Error::Protocol { msg: msg.into() }
+    }
+
+    /// The error of a `member` call on `proxy`, keeping the called object.
+    pub(crate) fn method_call(proxy: &zbus::Proxy<'_>, member: &str, e: zbus::Error) -> Self {
+        match e {
+            zbus::Error::MethodError(name, _, _) if name.as_str() == UNKNOWN_OBJECT => {
+                Error::ObjectNotFound {
+                    path: proxy.path().to_string(),
+                }
+            }
+            zbus::Error::FDO(e) if matches!(*e, zbus::fdo::Error::UnknownObject(_)) => {
+                Error::ObjectNotFound {
+                    path: proxy.path().to_string(),
+                }
+            }
+            zbus::Error::Io(e) => e.into(),
+            e => Error::MethodCall {
+                interface: proxy.interface().to_string(),
+                member: member.into(),
+                source: e,
+            },
+        }
+    }
+
+    /// The D-Bus error, if any, also within a method call or listener setup.
+    pub fn zbus(&self) -> Option<&zbus::Error> {
         match self {
-            Error::Io(e) => Some(e),
-            Error::Zbus(e) => Some(e),
-            Error::Rusb(e) => Some(e),
-            Error::Usbredir(e) => Some(e),
-            Error::Failed(_) => None,
-            #[cfg(feature = "qmp")]
-            Error::Qmp(e) => Some(e),
+            Error::Zbus(e) | Error::MethodCall { source: e, .. } => Some(e),
+            Error::ListenerSetup { source } => source.zbus(),
+            _ => None,
         }
     }
 }
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
+        #[cfg(unix)]
+        if crate::fd::is_exhaustion(&e) {
+            return crate::fd::limit_error();
+        }
         Error::Io(e)
     }
 }
 
 impl From<zbus::Error> for Error {
     fn from(e: zbus::Error) -> Self {
-        Error::Zbus(e)
+        match e {
+            zbus::Error::Io(e) => e.into(),
+            e => Error::Zbus(e),
+        }
     }
 }
 
@@ -100,3 +183,32 @@ impl From<Infallible> for Error {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn context() {
+        let e = Error::ListenerSetup {
+            source: Box::new(Error::protocol("Unknown handoff message 9")),
+        };
+        assert_eq!(
+            e.to_string(),
+            "failed to set up the console listener: protocol error: Unknown handoff message 9"
+        );
+        assert_eq!(
+            e.source().unwrap().to_string(),
+            "protocol error: Unknown handoff message 9"
+        );
+
+        let e = Error::FdLimit {
+            open: Some(1024),
+            limit: Some(1024),
+        };
+        assert!(e
+            .to_string()
+            .starts_with("too many open files (1024 of 1024): "));
+    }
+}
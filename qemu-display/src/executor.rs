@@ -0,0 +1,84 @@
+use futures::{channel::oneshot, future, FutureExt};
+use std::{
+    any::Any,
+    panic::AssertUnwindSafe,
+    thread::{self, JoinHandle},
+};
+
+use crate::Result;
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Run the executor of a connection built with `internal_executor(false)`.
+///
+/// A panicking task is logged, without stopping the other tasks. The future never completes:
+/// drop it to stop the executor. It can be spawned on the runtime of the frontend, such as
+/// the GLib main loop.
+pub async fn run_executor(conn: zbus::Connection) {
+    let executor = conn.executor();
+    loop {
+        if let Err(panic) = AssertUnwindSafe(executor.tick()).catch_unwind().await {
+            log::error!(
+                "A D-Bus connection task panicked: {}",
+                panic_message(&*panic)
+            );
+        }
+    }
+}
+
+/// Runs the executor of a connection on a dedicated thread, see [`run_executor`].
+///
+/// The thread is stopped and joined when dropped.
+#[derive(Debug)]
+pub struct ExecutorThread {
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ExecutorThread {
+    pub fn spawn(conn: &zbus::Connection) -> Result<Self> {
+        let (stop, stopped) = oneshot::channel();
+        let conn = conn.clone();
+        let thread = thread::Builder::new()
+            .name("zbus-executor".into())
+            .spawn(move || {
+                let run = Box::pin(run_executor(conn));
+                async_io::block_on(future::select(run, stopped));
+            })?;
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop the executor, and wait for the thread to finish the current task.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        // the thread may already be gone, if the receiver was dropped
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("The D-Bus executor thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for ExecutorThread {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
@@ -0,0 +1,80 @@
+//! File descriptor accounting.
+//!
+//! A session uses several descriptors: the D-Bus sockets of the listeners and chardevs, and
+//! a DMABUF per GL scanout. A long session with many reconnections may reach the process
+//! limit, [`fd_usage`] helps to tell a leak from a limit too low.
+
+use std::{
+    fs, io, mem,
+    os::unix::io::RawFd,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{Error, Result};
+
+// the DMABUF descriptors held by the ScanoutDMABUF of this process
+static DMABUFS: AtomicUsize = AtomicUsize::new(0);
+
+/// The file descriptors of the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdUsage {
+    /// The open descriptors, if they can be listed.
+    pub open: Option<usize>,
+    /// The soft limit (`RLIMIT_NOFILE`), if any.
+    pub limit: Option<u64>,
+    /// The descriptors held by DMABUF scanouts.
+    pub dmabufs: usize,
+}
+
+/// The current file descriptor usage.
+pub fn fd_usage() -> FdUsage {
+    // the directory has its own descriptor while it is read
+    let open = ["/proc/self/fd", "/dev/fd"]
+        .iter()
+        .find_map(|dir| fs::read_dir(dir).ok())
+        .map(|entries| entries.count().saturating_sub(1));
+
+    let mut rlim: libc::rlimit = unsafe { mem::zeroed() };
+    let limit = if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } == 0
+        && rlim.rlim_cur != libc::RLIM_INFINITY
+    {
+        Some(rlim.rlim_cur as u64)
+    } else {
+        None
+    };
+
+    FdUsage {
+        open,
+        limit,
+        dmabufs: DMABUFS.load(Ordering::SeqCst),
+    }
+}
+
+pub(crate) fn is_exhaustion(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+pub(crate) fn limit_error() -> Error {
+    let usage = fd_usage();
+    log::warn!("File descriptor limit reached: {:?}", usage);
+    Error::FdLimit {
+        open: usage.open,
+        limit: usage.limit,
+    }
+}
+
+/// Duplicate a DMABUF descriptor, counted in [`FdUsage::dmabufs`].
+pub(crate) fn dup_dmabuf(fd: RawFd) -> Result<RawFd> {
+    let fd = unsafe { libc::dup(fd) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    DMABUFS.fetch_add(1, Ordering::SeqCst);
+    Ok(fd)
+}
+
+/// Forget a DMABUF descriptor, closed or handed over.
+pub(crate) fn release_dmabuf() {
+    // ScanoutDMABUF can be built outside of the crate, without being counted
+    let _ = DMABUFS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+}
@@ -0,0 +1,291 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{
+    check_dimensions, frame_size, Cursor, Error, FrameSink, MouseSet, PixelFormat, Result, Scanout,
+    Update, PIXMAN_A8R8G8B8, PIXMAN_X8R8G8B8,
+};
+#[cfg(unix)]
+use crate::{ScanoutDMABUF, UpdateDMABUF};
+
+/// A rectangle of a frame, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> u32 {
+        self.x.saturating_add(self.width)
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.y.saturating_add(self.height)
+    }
+
+    /// The smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        Rect::new(
+            x,
+            y,
+            self.right().max(other.right()) - x,
+            self.bottom().max(other.bottom()) - y,
+        )
+    }
+
+    /// The part of the rectangle within `bounds`, which may be empty.
+    pub fn intersect(&self, bounds: &Rect) -> Rect {
+        let (x, y) = (self.x.max(bounds.x), self.y.max(bounds.y));
+        let right = self.right().min(bounds.right());
+        let bottom = self.bottom().min(bounds.bottom());
+        Rect::new(x, y, right.saturating_sub(x), bottom.saturating_sub(y))
+    }
+}
+
+/// A copy of the console content, in the [`PIXMAN_X8R8G8B8`] format without padding.
+///
+/// The 32-bit scanouts and updates are converted to this format.
+#[derive(derivative::Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    #[derivative(Debug = "ignore")]
+    data: Vec<u8>,
+}
+
+impl Framebuffer {
+    /// A black framebuffer.
+    pub fn new(width: u32, height: u32) -> Result<Self> {
+        check_dimensions(width, height)?;
+        Ok(Self {
+            width,
+            height,
+            data: vec![0; width as usize * height as usize * 4],
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.width * 4
+    }
+
+    pub fn format(&self) -> u32 {
+        PIXMAN_X8R8G8B8
+    }
+
+    pub fn rect(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Replace the content with the scanout, returning the damaged region.
+    pub fn scanout(&mut self, scanout: &Scanout) -> Result<Rect> {
+        scanout.validate()?;
+        if (scanout.width, scanout.height) != (self.width, self.height) {
+            *self = Self::new(scanout.width, scanout.height)?;
+        }
+        let rect = self.rect();
+        self.blit(rect, scanout.stride, scanout.format, &scanout.data)?;
+        Ok(rect)
+    }
+
+    /// Apply the update, returning the damaged region.
+    pub fn update(&mut self, update: &Update) -> Result<Rect> {
+        update.validate()?;
+        let rect = Rect::new(update.x as _, update.y as _, update.w as _, update.h as _);
+        self.blit(rect, update.stride, update.format, &update.data)?;
+        Ok(rect)
+    }
+
+    fn blit(&mut self, rect: Rect, stride: u32, format: u32, data: &[u8]) -> Result<()> {
+        if rect.intersect(&self.rect()) != rect
+            || frame_size(rect.width, rect.height, stride, format)? > data.len()
+        {
+            return Err(Error::Failed(format!(
+                "Invalid region {:?} of {}x{}",
+                rect, self.width, self.height
+            )));
+        }
+        let row = rect.width as usize * 4;
+        let pf = match format {
+            // same layout, without conversion
+            PIXMAN_X8R8G8B8 | PIXMAN_A8R8G8B8 => None,
+            _ => Some(PixelFormat::from_pixman(format)?),
+        };
+        for y in 0..rect.height as usize {
+            let start = ((rect.y as usize + y) * self.width as usize + rect.x as usize) * 4;
+            let dst = &mut self.data[start..start + row];
+            match pf {
+                None => dst.copy_from_slice(&data[y * stride as usize..][..row]),
+                Some(pf) => {
+                    let bpp = pf.bytes_per_pixel();
+                    let src = &data[y * stride as usize..][..rect.width as usize * bpp];
+                    for (s, d) in src.chunks_exact(bpp).zip(dst.chunks_exact_mut(4)) {
+                        d.copy_from_slice(&pf.xrgb(s).to_ne_bytes());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The state of a [`SharedFramebuffer`].
+#[derive(Debug)]
+pub struct FramebufferState {
+    pub framebuffer: Framebuffer,
+    pub cursor: Option<Cursor>,
+    /// The cursor position, when shown by the guest.
+    pub mouse: Option<(i32, i32)>,
+}
+
+/// A change of a [`SharedFramebuffer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferEvent {
+    /// The framebuffer was replaced, with new dimensions.
+    Resized { width: u32, height: u32 },
+    /// A region of the framebuffer changed.
+    Damage(Rect),
+    /// The cursor shape changed.
+    Cursor,
+    /// The cursor moved, or was shown or hidden.
+    Mouse,
+    /// The console listener was disconnected.
+    Disconnected,
+}
+
+/// A console framebuffer, shared between the listener and its consumers.
+///
+/// It is kept up to date by the [`FramebufferSink`] of [`SharedFramebuffer::sink`], which
+/// can be combined with other sinks.
+#[derive(Debug, Clone)]
+pub struct SharedFramebuffer {
+    state: Arc<Mutex<FramebufferState>>,
+}
+
+impl SharedFramebuffer {
+    pub fn new(width: u32, height: u32) -> Result<Self> {
+        Ok(Self {
+            state: Arc::new(Mutex::new(FramebufferState {
+                framebuffer: Framebuffer::new(width, height)?,
+                cursor: None,
+                mouse: None,
+            })),
+        })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, FramebufferState> {
+        self.state.lock().unwrap()
+    }
+
+    /// A sink updating the framebuffer, and calling `notify` on each change.
+    ///
+    /// `notify` is called with the framebuffer unlocked.
+    pub fn sink<F>(&self, notify: F) -> FramebufferSink
+    where
+        F: Fn(FramebufferEvent) + Send + Sync + 'static,
+    {
+        FramebufferSink {
+            framebuffer: self.clone(),
+            notify: Box::new(notify),
+        }
+    }
+}
+
+/// Keeps a [`SharedFramebuffer`] up to date, see [`SharedFramebuffer::sink`].
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct FramebufferSink {
+    framebuffer: SharedFramebuffer,
+    #[derivative(Debug = "ignore")]
+    notify: Box<dyn Fn(FramebufferEvent) + Send + Sync>,
+}
+
+#[async_trait::async_trait]
+impl FrameSink for FramebufferSink {
+    async fn on_scanout(&mut self, scanout: Scanout) {
+        let event = {
+            let mut state = self.framebuffer.lock();
+            let size = (state.framebuffer.width(), state.framebuffer.height());
+            match state.framebuffer.scanout(&scanout) {
+                Ok(_) if size != (scanout.width, scanout.height) => FramebufferEvent::Resized {
+                    width: scanout.width,
+                    height: scanout.height,
+                },
+                Ok(rect) => FramebufferEvent::Damage(rect),
+                Err(e) => {
+                    log::warn!("Invalid scanout: {}", e);
+                    return;
+                }
+            }
+        };
+        (self.notify)(event);
+    }
+
+    async fn on_update(&mut self, update: Update) {
+        let res = self.framebuffer.lock().framebuffer.update(&update);
+        match res {
+            Ok(rect) => (self.notify)(FramebufferEvent::Damage(rect)),
+            Err(e) => log::warn!("Invalid update: {}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    async fn on_scanout_dmabuf(&mut self, _scanout: ScanoutDMABUF) {
+        log::warn!("DMABUF scanouts are not supported by the framebuffer");
+    }
+
+    #[cfg(unix)]
+    async fn on_update_dmabuf(&mut self, _update: UpdateDMABUF) {}
+
+    async fn on_cursor(&mut self, cursor: Cursor) {
+        self.framebuffer.lock().cursor = Some(cursor);
+        (self.notify)(FramebufferEvent::Cursor);
+    }
+
+    async fn on_mouse_set(&mut self, set: MouseSet) {
+        self.framebuffer.lock().mouse = if set.on != 0 {
+            Some((set.x, set.y))
+        } else {
+            None
+        };
+        (self.notify)(FramebufferEvent::Mouse);
+    }
+
+    fn on_disconnected(&mut self) {
+        (self.notify)(FramebufferEvent::Disconnected);
+    }
+}
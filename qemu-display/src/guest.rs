@@ -0,0 +1,174 @@
+use std::{fmt, str::FromStr};
+
+use crate::{Error, Result};
+
+/// The name of the chardev connected to the QEMU guest agent (qemu-ga).
+pub const QGA_CHARDEV_NAME: &str = "org.qemu.guest_agent.0";
+
+const QNUM_ALT_L: u32 = 0x38;
+const QNUM_ALT_R: u32 = 0xb8;
+const QNUM_META_L: u32 = 0xdb;
+const QNUM_META_R: u32 = 0xdc;
+
+/// The operating system of the guest.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GuestOs {
+    Linux,
+    Windows,
+    MacOs,
+    /// Another system, with the name reported by the guest agent.
+    Other(String),
+}
+
+impl GuestOs {
+    /// The OS of the guest-get-osinfo reply of the guest agent, from its "id" and
+    /// "kernel-name" members.
+    pub fn from_osinfo(id: Option<&str>, kernel_name: Option<&str>) -> Self {
+        match (id, kernel_name) {
+            (Some("mswindows"), _) => Self::Windows,
+            (_, Some(kernel)) if kernel.eq_ignore_ascii_case("linux") => Self::Linux,
+            (_, Some(kernel)) if kernel.eq_ignore_ascii_case("darwin") => Self::MacOs,
+            (Some(id), _) | (None, Some(id)) => Self::from_name(id),
+            (None, None) => Self::Other(String::new()),
+        }
+    }
+
+    fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "linux" => Self::Linux,
+            "windows" | "mswindows" | "win32" => Self::Windows,
+            "macos" | "osx" | "darwin" => Self::MacOs,
+            _ => Self::Other(name.to_string()),
+        }
+    }
+
+    /// The frontend defaults suited to the guest.
+    pub fn defaults(&self) -> GuestDefaults {
+        match self {
+            Self::Linux => GuestDefaults::default(),
+            Self::Windows => GuestDefaults {
+                clipboard_crlf: true,
+                ..Default::default()
+            },
+            Self::MacOs => GuestDefaults {
+                swap_alt_meta: true,
+                ..Default::default()
+            },
+            // the older or more exotic systems may lack a tablet driver
+            Self::Other(_) => GuestDefaults {
+                absolute_mouse: false,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl FromStr for GuestOs {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(Error::Failed("Empty guest OS name".into()));
+        }
+        Ok(Self::from_name(s))
+    }
+}
+
+impl fmt::Display for GuestOs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Linux => "linux",
+            Self::Windows => "windows",
+            Self::MacOs => "macos",
+            Self::Other(name) => name,
+        })
+    }
+}
+
+/// Frontend defaults adapted to the guest OS, see [`GuestOs::defaults`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestDefaults {
+    /// The guest handles an absolute pointer (tablet) well, relative motion is a fallback.
+    pub absolute_mouse: bool,
+    /// Swap the Alt and Meta (Command) keys, for a guest expecting a Mac keyboard.
+    pub swap_alt_meta: bool,
+    /// Convert the line endings of the clipboard text to CRLF.
+    pub clipboard_crlf: bool,
+}
+
+impl Default for GuestDefaults {
+    fn default() -> Self {
+        Self {
+            absolute_mouse: true,
+            swap_alt_meta: false,
+            clipboard_crlf: false,
+        }
+    }
+}
+
+impl GuestDefaults {
+    /// The key number to send to the guest, for a host key number.
+    pub fn map_qnum(&self, qnum: u32) -> u32 {
+        if !self.swap_alt_meta {
+            return qnum;
+        }
+        match qnum {
+            QNUM_ALT_L => QNUM_META_L,
+            QNUM_ALT_R => QNUM_META_R,
+            QNUM_META_L => QNUM_ALT_L,
+            QNUM_META_R => QNUM_ALT_R,
+            qnum => qnum,
+        }
+    }
+
+    /// The clipboard text to send to the guest.
+    pub fn clipboard_text(&self, text: &str) -> String {
+        if !self.clipboard_crlf {
+            return text.to_string();
+        }
+        text.replace("\r\n", "\n").replace('\n', "\r\n")
+    }
+}
+
+#[cfg(feature = "qga")]
+pub(crate) async fn query_os(chardev: &crate::Chardev) -> Result<GuestOs> {
+    use async_io::Timer;
+    use futures::{
+        future::{self, Either},
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    };
+    use std::time::Duration;
+
+    // without agent in the guest, the chardev doesn't reply
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    let mut conn = BufReader::new(chardev.connect().await?);
+    conn.get_mut()
+        .write_all(b"{\"execute\": \"guest-get-osinfo\"}\n")
+        .await?;
+    let reply = async {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if conn.read_line(&mut line).await? == 0 {
+                return Err(Error::Failed("The guest agent disconnected".into()));
+            }
+            let msg: serde_json::Value = match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            if let Some(err) = msg.get("error") {
+                return Err(Error::Failed(format!("Guest agent error: {}", err)));
+            }
+            if let Some(info) = msg.get("return") {
+                let member = |name| info.get(name).and_then(|v| v.as_str());
+                return Ok(GuestOs::from_osinfo(member("id"), member("kernel-name")));
+            }
+        }
+    };
+    futures::pin_mut!(reply);
+    match future::select(reply, Timer::after(TIMEOUT)).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Err(Error::Failed("The guest agent doesn't reply".into())),
+    }
+}
@@ -0,0 +1,345 @@
+//! Frame handoff to local viewers.
+//!
+//! A bridge (like qemu-vnc) can share its frames with the viewers running on the same host,
+//! without encoding them. The frame is kept in a memfd, mapped by the viewers, and a small
+//! control socket announces the frame changes.
+//!
+//! Each control message is 6 native-endian u32: the kind, and 5 arguments.
+//!
+//! - `0` scanout: `width`, `height`, `stride`, `format`, `0`, with the new memfd attached.
+//! - `1` update: `x`, `y`, `w`, `h`, `0`, for a region of the current frame.
+//!
+//! The frame is written in place, a viewer may see a partial update until it is notified.
+
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    ptr, slice,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::{frame_size, pixman_bpp, util, Error, FrameSink, Result, Scanout, Update};
+
+const MSG_SIZE: usize = 24;
+const MSG_SCANOUT: u32 = 0;
+const MSG_UPDATE: u32 = 1;
+
+/// The layout of a shared frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub format: u32,
+}
+
+/// A frame change, see [`HandoffViewer::recv`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandoffEvent {
+    /// A new frame, the whole content changed.
+    Scanout(FrameInfo),
+    /// A region of the frame changed.
+    Update { x: u32, y: u32, w: u32, h: u32 },
+}
+
+fn encode(kind: u32, args: [u32; 5]) -> [u8; MSG_SIZE] {
+    let mut msg = [0; MSG_SIZE];
+    for (i, v) in std::iter::once(kind).chain(args).enumerate() {
+        msg[i * 4..i * 4 + 4].copy_from_slice(&v.to_ne_bytes());
+    }
+    msg
+}
+
+fn decode(msg: &[u8; MSG_SIZE]) -> (u32, [u32; 5]) {
+    let word = |i: usize| u32::from_ne_bytes(msg[i * 4..i * 4 + 4].try_into().unwrap());
+    (word(0), [word(1), word(2), word(3), word(4), word(5)])
+}
+
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is only accessed through its owner
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize, writable: bool) -> io::Result<Self> {
+        if len == 0 {
+            return Ok(Self {
+                ptr: ptr::null_mut(),
+                len,
+            });
+        }
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr: ptr as _, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    // only for writable mappings
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        if self.len == 0 {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr as _, self.len) };
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SharedFrame {
+    info: FrameInfo,
+    file: File,
+    map: Mapping,
+}
+
+impl SharedFrame {
+    fn new(info: FrameInfo) -> Result<Self> {
+        let len = frame_size(info.width, info.height, info.stride, info.format)?;
+        let fd =
+            unsafe { libc::memfd_create(b"qemu-display-frame\0".as_ptr() as _, libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(len as _)?;
+        let map = Mapping::new(&file, len, true)?;
+        Ok(Self { info, file, map })
+    }
+
+    fn announce(&self, viewer: &UnixStream) -> io::Result<()> {
+        let FrameInfo {
+            width,
+            height,
+            stride,
+            format,
+        } = self.info;
+        let msg = encode(MSG_SCANOUT, [width, height, stride, format, 0]);
+        if util::send_fd(viewer, &msg, self.file.as_raw_fd())? != MSG_SIZE {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct HandoffState {
+    frame: Option<SharedFrame>,
+    viewers: Vec<UnixStream>,
+}
+
+impl HandoffState {
+    // drop the viewers that fail, or are too slow to read the messages
+    fn broadcast(&mut self, send: impl Fn(&UnixStream) -> io::Result<()>) {
+        self.viewers.retain(|v| match send(v) {
+            Ok(()) => true,
+            Err(e) => {
+                log::debug!("Dropping a handoff viewer: {}", e);
+                false
+            }
+        });
+    }
+}
+
+/// Shares the frames of a console with the local viewers, see the [module](self) documentation.
+#[derive(Debug, Clone)]
+pub struct FrameHandoff {
+    state: Arc<Mutex<HandoffState>>,
+}
+
+impl FrameHandoff {
+    /// Listen for viewers on the unix socket `path`.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        let state = Arc::new(Mutex::new(HandoffState::default()));
+        let weak = Arc::downgrade(&state);
+        thread::spawn(move || {
+            for viewer in listener.incoming() {
+                let state = match weak.upgrade() {
+                    Some(state) => state,
+                    None => return,
+                };
+                let viewer = match viewer.and_then(|v| v.set_nonblocking(true).map(|_| v)) {
+                    Ok(viewer) => viewer,
+                    Err(e) => {
+                        log::warn!("Failed to accept a handoff viewer: {}", e);
+                        continue;
+                    }
+                };
+                let mut state = state.lock().unwrap();
+                if let Some(frame) = &state.frame {
+                    if let Err(e) = frame.announce(&viewer) {
+                        log::warn!("Failed to setup a handoff viewer: {}", e);
+                        continue;
+                    }
+                }
+                state.viewers.push(viewer);
+            }
+        });
+        Ok(Self { state })
+    }
+
+    /// Share a new frame.
+    pub fn scanout(&self, scanout: &Scanout) -> Result<()> {
+        scanout.validate()?;
+        let mut frame = SharedFrame::new(FrameInfo {
+            width: scanout.width,
+            height: scanout.height,
+            stride: scanout.stride,
+            format: scanout.format,
+        })?;
+        let len = frame.map.len;
+        frame
+            .map
+            .as_mut_slice()
+            .copy_from_slice(&scanout.data[..len]);
+
+        let mut state = self.state.lock().unwrap();
+        state.broadcast(|v| frame.announce(v));
+        state.frame = Some(frame);
+        Ok(())
+    }
+
+    /// Update a region of the shared frame.
+    pub fn update(&self, update: &Update) -> Result<()> {
+        update.validate()?;
+        let mut state = self.state.lock().unwrap();
+        let frame = match &mut state.frame {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let info = frame.info;
+        let (x, y, w, h) = (
+            update.x as u32,
+            update.y as u32,
+            update.w as u32,
+            update.h as u32,
+        );
+        if update.format != info.format || x + w > info.width || y + h > info.height {
+            return Err(Error::protocol(format!(
+                "Update {:?} doesn't match the shared frame {:?}",
+                update, info
+            )));
+        }
+        let bytes = pixman_bpp(info.format) / 8;
+        let row = w as usize * bytes;
+        let data = frame.map.as_mut_slice();
+        for i in 0..h as usize {
+            let dst = (y as usize + i) * info.stride as usize + x as usize * bytes;
+            let src = i * update.stride as usize;
+            data[dst..dst + row].copy_from_slice(&update.data[src..src + row]);
+        }
+
+        let msg = encode(MSG_UPDATE, [x, y, w, h, 0]);
+        state.broadcast(|mut v: &UnixStream| v.write_all(&msg));
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameSink for FrameHandoff {
+    async fn on_scanout(&mut self, scanout: Scanout) {
+        if let Err(e) = self.scanout(&scanout) {
+            log::warn!("Failed to share the scanout: {}", e);
+        }
+    }
+
+    async fn on_update(&mut self, update: Update) {
+        if let Err(e) = self.update(&update) {
+            log::warn!("Failed to share the update: {}", e);
+        }
+    }
+}
+
+/// A local viewer of a [`FrameHandoff`].
+#[derive(Debug)]
+pub struct HandoffViewer {
+    stream: UnixStream,
+    frame: Option<(FrameInfo, File, Mapping)>,
+}
+
+impl HandoffViewer {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(path)?,
+            frame: None,
+        })
+    }
+
+    /// Wait for the next frame change.
+    pub fn recv(&mut self) -> Result<HandoffEvent> {
+        let mut msg = [0; MSG_SIZE];
+        let (n, fd) = util::recv_fd(&self.stream, &mut msg)?;
+        let file = fd.map(|fd| unsafe { File::from_raw_fd(fd) });
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.stream.read_exact(&mut msg[n..])?;
+
+        match decode(&msg) {
+            (MSG_SCANOUT, [width, height, stride, format, _]) => {
+                let info = FrameInfo {
+                    width,
+                    height,
+                    stride,
+                    format,
+                };
+                let file = file.ok_or_else(|| Error::protocol("Missing the frame memfd"))?;
+                let len = frame_size(width, height, stride, format)?;
+                if file.metadata()?.len() < len as u64 {
+                    return Err(Error::protocol("The frame memfd is too small"));
+                }
+                let map = Mapping::new(&file, len, false)?;
+                self.frame = Some((info, file, map));
+                Ok(HandoffEvent::Scanout(info))
+            }
+            (MSG_UPDATE, [x, y, w, h, _]) => Ok(HandoffEvent::Update { x, y, w, h }),
+            (kind, _) => Err(Error::protocol(format!("Unknown handoff message {}", kind))),
+        }
+    }
+
+    /// The current frame, if any.
+    pub fn frame(&self) -> Option<(FrameInfo, &[u8])> {
+        self.frame
+            .as_ref()
+            .map(|(info, _, map)| (*info, map.as_slice()))
+    }
+}
@@ -1,8 +1,19 @@
 use enumflags2::{bitflags, BitFlags};
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use zbus::dbus_proxy;
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use zbus::{dbus_proxy, Task};
 use zvariant::Type;
 
+use crate::{Error, Result};
+
 #[bitflags]
 #[repr(u32)]
 #[derive(Type, Debug, PartialEq, Copy, Clone, Eq, Serialize, Deserialize)]
@@ -23,3 +34,275 @@ pub trait Keyboard {
     #[dbus_proxy(property)]
     fn modifiers(&self) -> zbus::Result<BitFlags<KeyboardModifiers>>;
 }
+
+pub(crate) const QNUM_LSHIFT: u32 = 0x2a;
+const QNUM_CAPS_LOCK: u32 = 0x3a;
+const QNUM_NUM_LOCK: u32 = 0x45;
+const QNUM_SCROLL_LOCK: u32 = 0x46;
+
+impl KeyboardModifiers {
+    /// The qnum keycode of the lock key toggling the modifier.
+    pub fn lock_key(self) -> u32 {
+        match self {
+            Self::Scroll => QNUM_SCROLL_LOCK,
+            Self::Num => QNUM_NUM_LOCK,
+            Self::Caps => QNUM_CAPS_LOCK,
+        }
+    }
+
+    /// Whether the qnum keycode is a lock key.
+    pub fn is_lock_key(qnum: u32) -> bool {
+        matches!(qnum, QNUM_CAPS_LOCK | QNUM_NUM_LOCK | QNUM_SCROLL_LOCK)
+    }
+}
+
+/// The state of the keyboard lock LEDs, a typed view of [`KeyboardModifiers`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct KeyboardLeds {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl From<BitFlags<KeyboardModifiers>> for KeyboardLeds {
+    fn from(modifiers: BitFlags<KeyboardModifiers>) -> Self {
+        Self {
+            caps_lock: modifiers.contains(KeyboardModifiers::Caps),
+            num_lock: modifiers.contains(KeyboardModifiers::Num),
+            scroll_lock: modifiers.contains(KeyboardModifiers::Scroll),
+        }
+    }
+}
+
+impl From<KeyboardLeds> for BitFlags<KeyboardModifiers> {
+    fn from(leds: KeyboardLeds) -> Self {
+        let mut modifiers = BitFlags::empty();
+        if leds.caps_lock {
+            modifiers |= KeyboardModifiers::Caps;
+        }
+        if leds.num_lock {
+            modifiers |= KeyboardModifiers::Num;
+        }
+        if leds.scroll_lock {
+            modifiers |= KeyboardModifiers::Scroll;
+        }
+        modifiers
+    }
+}
+
+static KEY_DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable the key translation reports, see [`KeyboardProxy::trace_key`].
+pub fn set_key_debug(enabled: bool) {
+    KEY_DEBUG.store(enabled, Ordering::Relaxed);
+}
+
+pub fn key_debug() -> bool {
+    KEY_DEBUG.load(Ordering::Relaxed)
+}
+
+/// The translation of a host key event to a QEMU keycode (qnum).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyTranslation {
+    pub press: bool,
+    /// The host key value (keysym), if known.
+    pub keyval: Option<u32>,
+    /// The host keycode.
+    pub keycode: u32,
+    /// The name of the keymap table used.
+    pub keymap: &'static str,
+    /// The keycode sent to QEMU, if the key is mapped.
+    pub qnum: Option<u32>,
+}
+
+impl fmt::Display for KeyTranslation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", if self.press { "press" } else { "release" })?;
+        if let Some(keyval) = self.keyval {
+            write!(f, "keyval 0x{:x} ", keyval)?;
+        }
+        write!(f, "keycode {} -> {} -> ", self.keycode, self.keymap)?;
+        match self.qnum {
+            Some(qnum) => write!(f, "qnum 0x{:x}", qnum),
+            None => write!(f, "unmapped"),
+        }
+    }
+}
+
+// The qnum keycode of a character on a US keyboard, and whether shift is needed.
+fn char_to_qnum(c: char) -> Option<(u32, bool)> {
+    const ROWS: &[(u32, &str, &str)] = &[
+        (0x02, "1234567890-=", "!@#$%^&*()_+"),
+        (0x10, "qwertyuiop[]", "QWERTYUIOP{}"),
+        (0x1e, "asdfghjkl;'`", "ASDFGHJKL:\"~"),
+        (0x2b, "\\zxcvbnm,./", "|ZXCVBNM<>?"),
+    ];
+
+    match c {
+        '\n' => return Some((0x1c, false)),
+        '\t' => return Some((0x0f, false)),
+        ' ' => return Some((0x39, false)),
+        _ => {}
+    }
+    ROWS.iter().find_map(|(start, plain, shifted)| {
+        if let Some(pos) = plain.chars().position(|p| p == c) {
+            Some((start + pos as u32, false))
+        } else {
+            shifted
+                .chars()
+                .position(|p| p == c)
+                .map(|pos| (start + pos as u32, true))
+        }
+    })
+}
+
+impl<'p> KeyboardProxy<'p> {
+    /// The guest lock LEDs.
+    pub async fn leds(&self) -> Result<KeyboardLeds> {
+        Ok(self.modifiers().await?.into())
+    }
+
+    /// The changes of the guest lock LEDs, from the `Modifiers` property.
+    pub async fn receive_leds_changed(
+        &self,
+    ) -> Pin<Box<dyn Stream<Item = KeyboardLeds> + Send + 'p>> {
+        let changes = self.receive_modifiers_changed().await;
+        Box::pin(
+            changes.filter_map(
+                |change| async move { change.get().await.ok().map(KeyboardLeds::from) },
+            ),
+        )
+    }
+
+    /// Report a key translation with the guest modifiers, if enabled with [`set_key_debug`].
+    ///
+    /// The report is logged, and returned to be shown by the caller.
+    pub async fn trace_key(&self, translation: &KeyTranslation) -> Option<String> {
+        if !key_debug() {
+            return None;
+        }
+        let modifiers = match self.modifiers().await {
+            Ok(m) => format!("{:?}", m),
+            Err(e) => format!("unknown ({})", e),
+        };
+        let report = format!("{} (guest modifiers: {})", translation, modifiers);
+        log::info!("{}", report);
+        Some(report)
+    }
+
+    /// Type the text with key presses, assuming a US keyboard layout in the guest.
+    ///
+    /// Fails before sending any key if a character can't be typed.
+    pub async fn type_text(&self, text: &str) -> Result<()> {
+        let keys = text
+            .chars()
+            .map(|c| char_to_qnum(c).ok_or_else(|| Error::Failed(format!("Can't type {:?}", c))))
+            .collect::<Result<Vec<_>>>()?;
+        for (keycode, shift) in keys {
+            if shift {
+                self.press(QNUM_LSHIFT).await?;
+            }
+            self.press(keycode).await?;
+            self.release(keycode).await?;
+            if shift {
+                self.release(QNUM_LSHIFT).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the guest lock keys (Caps, Num and Scroll) in sync with the host.
+///
+/// The guest state is watched from the keyboard `Modifiers` property. The frontend provides
+/// the host state, and the tracker toggles the lock keys that differ.
+///
+/// QEMU can't set the guest LEDs directly, so a mismatch is fixed by injecting a press and
+/// release of the lock key. The frontends should sync when they gain the keyboard focus,
+/// and before forwarding a key that isn't a lock key ([`KeyboardModifiers::is_lock_key`]):
+/// a lock key toggles both states, which then stay in sync. The guest state is assumed
+/// toggled until it reports the change, so the keys are not injected twice.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ModifierTracker {
+    #[derivative(Debug = "ignore")]
+    keyboard: KeyboardProxy<'static>,
+    guest: Arc<Mutex<BitFlags<KeyboardModifiers>>>,
+    #[derivative(Debug = "ignore")]
+    _task: Task<()>,
+}
+
+impl ModifierTracker {
+    pub async fn new(keyboard: &KeyboardProxy<'static>) -> Result<Self> {
+        let mut changed = keyboard.receive_modifiers_changed().await;
+        let guest = Arc::new(Mutex::new(keyboard.modifiers().await?));
+        let weak = Arc::downgrade(&guest);
+        let task = keyboard.inner().connection().executor().spawn(async move {
+            while let Some(change) = changed.next().await {
+                let guest = match weak.upgrade() {
+                    Some(guest) => guest,
+                    None => return,
+                };
+                if let Ok(modifiers) = change.get().await {
+                    *guest.lock().unwrap() = modifiers;
+                }
+            }
+        });
+        Ok(Self {
+            keyboard: keyboard.clone(),
+            guest,
+            _task: task,
+        })
+    }
+
+    /// The guest modifiers, as last reported (or assumed after a sync).
+    pub fn guest(&self) -> BitFlags<KeyboardModifiers> {
+        *self.guest.lock().unwrap()
+    }
+
+    /// The lock keys to press and release, for the `mask` modifiers of the guest to match
+    /// the host.
+    pub fn lock_keys(
+        &self,
+        host: BitFlags<KeyboardModifiers>,
+        mask: BitFlags<KeyboardModifiers>,
+    ) -> Vec<u32> {
+        ((self.guest() ^ host) & mask)
+            .iter()
+            .map(KeyboardModifiers::lock_key)
+            .collect()
+    }
+
+    /// Synchronize all the lock LEDs of the guest with the host.
+    pub async fn sync_leds(&self, host: KeyboardLeds) -> Result<()> {
+        self.sync(host.into(), BitFlags::all()).await
+    }
+
+    /// Synchronize the `mask` modifiers of the guest with the host.
+    ///
+    /// Use [`BitFlags::all`] for the mask if the whole host state is known. It shouldn't be
+    /// called while handling a lock key, since the host state is already toggled.
+    pub async fn sync(
+        &self,
+        host: BitFlags<KeyboardModifiers>,
+        mask: BitFlags<KeyboardModifiers>,
+    ) -> Result<()> {
+        let keys = self.lock_keys(host, mask);
+        if keys.is_empty() {
+            return Ok(());
+        }
+        log::debug!(
+            "Syncing the guest modifiers {:?} with {:?}",
+            self.guest(),
+            host
+        );
+        for key in keys {
+            self.keyboard.press(key).await?;
+            self.keyboard.release(key).await?;
+        }
+        // don't toggle again until the guest reports its state
+        let mut guest = self.guest.lock().unwrap();
+        *guest = (*guest & !mask) | (host & mask);
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(clippy::too_many_arguments)]
 
+pub mod blocking;
 pub mod util;
 #[cfg(windows)]
 mod win32;
@@ -7,12 +8,28 @@ mod win32;
 mod error;
 pub use error::*;
 
+#[cfg(unix)]
+mod adaptive;
+#[cfg(unix)]
+pub use adaptive::*;
+
+#[cfg(unix)]
+mod fd;
+#[cfg(unix)]
+pub use fd::{fd_usage, FdUsage};
+
 mod vm;
 pub use vm::*;
 
+mod attach;
+pub use attach::*;
+
 mod audio;
 pub use audio::*;
 
+mod capture;
+pub use capture::*;
+
 mod chardev;
 pub use chardev::*;
 
@@ -25,6 +42,91 @@ pub use console::*;
 mod console_listener;
 pub use console_listener::*;
 
+mod cursor;
+pub use cursor::*;
+
+mod damage;
+pub use damage::*;
+
+mod coalesce;
+
+mod executor;
+pub use executor::*;
+
+mod framebuffer;
+pub use framebuffer::*;
+
+#[cfg(target_os = "linux")]
+mod handoff;
+#[cfg(target_os = "linux")]
+pub use handoff::*;
+
+mod listener_socket;
+pub use listener_socket::*;
+
+mod multiplex;
+pub use multiplex::*;
+
+mod pixel;
+pub use pixel::*;
+
+mod metrics;
+pub use metrics::{metrics, AudioMetrics, ConsoleMetrics, Metrics};
+
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "prometheus")]
+pub use prometheus::*;
+
+mod reconnect;
+pub use reconnect::*;
+
+mod relay;
+pub use relay::*;
+
+mod retry;
+pub use retry::*;
+
+mod guest;
+pub use guest::*;
+
+#[cfg(unix)]
+mod shared_map;
+#[cfg(unix)]
+pub use shared_map::*;
+
+mod session;
+pub use session::*;
+
+mod sink;
+pub use sink::*;
+
+mod spice;
+pub use spice::*;
+
+mod state;
+pub use state::*;
+
+mod stats;
+pub use stats::*;
+
+#[cfg(all(unix, feature = "ssh"))]
+mod ssh;
+#[cfg(all(unix, feature = "ssh"))]
+pub use ssh::*;
+
+mod timeout;
+pub use timeout::{default_timeout, set_default_timeout, CancellationToken};
+
+mod transform;
+pub use transform::*;
+
+mod transfer;
+pub use transfer::*;
+
+mod watchdog;
+pub use watchdog::*;
+
 mod keyboard;
 pub use keyboard::*;
 
@@ -34,10 +136,21 @@ pub use mouse::*;
 mod display;
 pub use display::*;
 
-#[cfg(unix)]
+#[cfg(all(unix, feature = "video-encode"))]
+mod encode;
+#[cfg(all(unix, feature = "video-encode"))]
+pub use encode::*;
+
+#[cfg(feature = "qmp")]
+mod qmp;
+#[cfg(feature = "qmp")]
+pub use qmp::*;
+
 mod usbredir;
-#[cfg(unix)]
-pub use usbredir::UsbRedir;
+pub use usbredir::{
+    UsbAutoRedirect, UsbDeviceChange, UsbDeviceChanges, UsbDeviceInfo, UsbFilter, UsbRedir,
+    UsbRedirEvent, UsbRule, UsbStreamRedirect,
+};
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,91 @@
+//! Console listeners living in another process than the registrar.
+//!
+//! The listener process binds a [`ListenerSocket`], and the registrar connects to it with
+//! [`Console::register_listener_socket`](crate::Console::register_listener_socket), handing
+//! the connected socket to QEMU. Only a socket path is shared between the processes, which
+//! is easier to set up than passing a descriptor, across containers for example.
+
+use async_io::Async;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(target_os = "linux")]
+use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+#[cfg(windows)]
+use uds_windows::{UnixListener, UnixStream};
+
+use crate::{
+    console::serve_connection, ConsoleListener, ConsoleListenerHandler, ListenerConnection, Result,
+};
+
+// "@name" is an abstract socket, without a file
+#[cfg(target_os = "linux")]
+fn abstract_name(path: &Path) -> Option<&[u8]> {
+    path.to_str()?.strip_prefix('@').map(str::as_bytes)
+}
+
+pub(crate) fn connect(path: &Path) -> Result<UnixStream> {
+    #[cfg(target_os = "linux")]
+    if let Some(name) = abstract_name(path) {
+        let addr = SocketAddr::from_abstract_name(name)?;
+        return Ok(UnixStream::connect_addr(&addr)?);
+    }
+    Ok(UnixStream::connect(path)?)
+}
+
+/// A UNIX socket accepting the listener registrations of
+/// [`Console::register_listener_socket`](crate::Console::register_listener_socket).
+///
+/// The socket file is removed when dropped.
+#[derive(Debug)]
+pub struct ListenerSocket {
+    listener: Async<UnixListener>,
+    // the socket file to remove, None with an abstract socket
+    path: Option<PathBuf>,
+}
+
+impl ListenerSocket {
+    /// Listen at `path`, or at the abstract name following `@` on Linux.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        #[cfg(target_os = "linux")]
+        if let Some(name) = abstract_name(path) {
+            let addr = SocketAddr::from_abstract_name(name)?;
+            return Ok(Self {
+                listener: Async::new(UnixListener::bind_addr(&addr)?)?,
+                path: None,
+            });
+        }
+        Ok(Self {
+            listener: Async::new(UnixListener::bind(path)?)?,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Wait for a registration, and serve `handler` on it.
+    ///
+    /// The console isn't known from this side: `console_id` identifies the listener, in
+    /// the logs and the returned connection.
+    pub async fn accept<H: ConsoleListenerHandler>(
+        &self,
+        console_id: u32,
+        handler: H,
+    ) -> Result<ListenerConnection> {
+        let (stream, _) = self.listener.read_with(|l| l.accept()).await?;
+        log::debug!("Console {}: accepted listener connection", console_id);
+        serve_connection(stream, ConsoleListener::new(console_id, handler)).await
+    }
+}
+
+impl Drop for ListenerSocket {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::remove_file(path) {
+                log::debug!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Counters of the listener events, to monitor the console throughput and latency.
+//!
+//! The console listeners count their frames and bytes by console index, and the time their
+//! handler takes, the audio listeners count their samples. The counters are shared by the
+//! process, and read with [`metrics`].
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+pub(crate) struct ConsoleCounters {
+    scanouts: AtomicU64,
+    updates: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+    handler_nanos: AtomicU64,
+}
+
+impl ConsoleCounters {
+    pub(crate) fn scanout(&self, bytes: usize) {
+        self.scanouts.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn update(&self, bytes: usize) {
+        self.updates.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Measure the handler time, until the guard is dropped.
+    pub(crate) fn handling(&self) -> HandlerTimer<'_> {
+        HandlerTimer {
+            counters: self,
+            start: Instant::now(),
+        }
+    }
+
+    fn metrics(&self) -> ConsoleMetrics {
+        ConsoleMetrics {
+            scanouts: self.scanouts.load(Ordering::Relaxed),
+            updates: self.updates.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            handler_time: Duration::from_nanos(self.handler_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+pub(crate) struct HandlerTimer<'a> {
+    counters: &'a ConsoleCounters,
+    start: Instant,
+}
+
+impl Drop for HandlerTimer<'_> {
+    fn drop(&mut self) {
+        let nanos = self.start.elapsed().as_nanos() as u64;
+        self.counters
+            .handler_nanos
+            .fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+static CONSOLES: Lazy<Mutex<BTreeMap<u32, Arc<ConsoleCounters>>>> = Lazy::new(Default::default);
+
+/// The counters of a console, kept by its listeners.
+pub(crate) fn console_counters(console_id: u32) -> Arc<ConsoleCounters> {
+    CONSOLES
+        .lock()
+        .unwrap()
+        .entry(console_id)
+        .or_default()
+        .clone()
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct AudioCounters {
+    out_packets: AtomicU64,
+    out_bytes: AtomicU64,
+    in_packets: AtomicU64,
+    in_bytes: AtomicU64,
+}
+
+impl AudioCounters {
+    pub(crate) fn write(&self, bytes: usize) {
+        self.out_packets.fetch_add(1, Ordering::Relaxed);
+        self.out_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read(&self, bytes: usize) {
+        self.in_packets.fetch_add(1, Ordering::Relaxed);
+        self.in_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+pub(crate) static AUDIO: Lazy<AudioCounters> = Lazy::new(Default::default);
+
+/// The counters of a console listener.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConsoleMetrics {
+    /// The new frames: scanouts, with data, shared memory or DMABUF.
+    pub scanouts: u64,
+    /// The updates of the current frame.
+    pub updates: u64,
+    /// The frame bytes received through the listener messages.
+    pub bytes: u64,
+    /// The invalid frames, which didn't reach the handler.
+    pub dropped: u64,
+    /// The time spent in the handler, for the scanouts and updates.
+    pub handler_time: Duration,
+}
+
+/// The counters of the audio listeners.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AudioMetrics {
+    pub out_packets: u64,
+    pub out_bytes: u64,
+    pub in_packets: u64,
+    pub in_bytes: u64,
+}
+
+// a console counter, as a Prometheus sample value
+type ConsoleValue = fn(&ConsoleMetrics) -> String;
+
+/// The listener counters of the process, since it started.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    /// The consoles, by index.
+    pub consoles: BTreeMap<u32, ConsoleMetrics>,
+    pub audio: AudioMetrics,
+}
+
+impl Metrics {
+    /// The Prometheus text exposition of the counters.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let consoles: [(&str, &str, ConsoleValue); 5] = [
+            ("scanouts", "The console scanouts", |c| {
+                c.scanouts.to_string()
+            }),
+            ("updates", "The console updates", |c| c.updates.to_string()),
+            ("bytes", "The console frame bytes", |c| c.bytes.to_string()),
+            ("dropped", "The invalid console frames", |c| {
+                c.dropped.to_string()
+            }),
+            ("handler_seconds", "The time of the console handlers", |c| {
+                c.handler_time.as_secs_f64().to_string()
+            }),
+        ];
+        for (name, help, value) in consoles {
+            let _ = writeln!(out, "# HELP qemu_display_console_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE qemu_display_console_{}_total counter", name);
+            for (id, console) in &self.consoles {
+                let _ = writeln!(
+                    out,
+                    "qemu_display_console_{}_total{{console=\"{}\"}} {}",
+                    name,
+                    id,
+                    value(console)
+                );
+            }
+        }
+        let audio = [
+            (
+                "out_packets",
+                "The audio output packets",
+                self.audio.out_packets,
+            ),
+            ("out_bytes", "The audio output bytes", self.audio.out_bytes),
+            (
+                "in_packets",
+                "The audio input packets",
+                self.audio.in_packets,
+            ),
+            ("in_bytes", "The audio input bytes", self.audio.in_bytes),
+        ];
+        for (name, help, value) in audio {
+            let _ = writeln!(out, "# HELP qemu_display_audio_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE qemu_display_audio_{}_total counter", name);
+            let _ = writeln!(out, "qemu_display_audio_{}_total {}", name, value);
+        }
+        out
+    }
+}
+
+/// The listener counters of the process.
+pub fn metrics() -> Metrics {
+    let consoles = CONSOLES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, c)| (*id, c.metrics()))
+        .collect();
+    Metrics {
+        consoles,
+        audio: AudioMetrics {
+            out_packets: AUDIO.out_packets.load(Ordering::Relaxed),
+            out_bytes: AUDIO.out_bytes.load(Ordering::Relaxed),
+            in_packets: AUDIO.in_packets.load(Ordering::Relaxed),
+            in_bytes: AUDIO.in_bytes.load(Ordering::Relaxed),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus() {
+        let counters = ConsoleCounters::default();
+        counters.scanout(400);
+        counters.update(16);
+        counters.dropped();
+        drop(counters.handling());
+        let mut metrics = Metrics::default();
+        metrics.consoles.insert(1, counters.metrics());
+        let console = &metrics.consoles[&1];
+        assert_eq!(
+            (console.scanouts, console.updates, console.bytes),
+            (1, 1, 416)
+        );
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains(
+            "# TYPE qemu_display_console_bytes_total counter\n\
+             qemu_display_console_bytes_total{console=\"1\"} 416\n"
+        ));
+        assert!(text.ends_with("qemu_display_audio_in_bytes_total 0\n"));
+    }
+}
@@ -12,6 +12,8 @@ pub enum MouseButton {
     WheelDown,
     Side,
     Extra,
+    WheelLeft,
+    WheelRight,
 }
 
 #[dbus_proxy(default_service = "org.qemu", interface = "org.qemu.Display1.Mouse")]
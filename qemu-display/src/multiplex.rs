@@ -0,0 +1,304 @@
+//! One console listener, shared by several consumers of the same process.
+//!
+//! QEMU sends the events of a listener at the pace of its slowest consumer. With a
+//! [`ConsoleMultiplexer`], the events update a shared framebuffer, and each subscriber only
+//! keeps track of the regions that changed since it last looked: a slow subscriber gets
+//! fewer and larger updates, without holding back the listener or the other subscribers.
+
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use std::{
+    mem,
+    sync::{Arc, Mutex, Weak},
+};
+use zbus::{Connection, Task};
+
+#[cfg(unix)]
+use crate::{AdaptiveSink, FramePathMode, FramePathPolicy};
+use crate::{
+    Console, DamageTracker, FrameSink, FrameSinkListener, FramebufferEvent, FramebufferState,
+    ListenerConnection, MouseSet, Rect, Result, Scanout, SharedFramebuffer, Update,
+};
+
+/// The changes of the console since the last [`ConsoleSubscriber::changed`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsoleChanges {
+    /// The framebuffer was replaced (or the subscriber is new): it should be read entirely.
+    pub resized: Option<(u32, u32)>,
+    /// The regions that changed, when not resized.
+    pub damage: Vec<Rect>,
+    /// The cursor shape changed.
+    pub cursor: bool,
+    /// The cursor moved, or was shown or hidden.
+    pub mouse: bool,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    changes: ConsoleChanges,
+    // the damage of the changes, when not resized
+    damage: DamageTracker,
+    disconnected: bool,
+    // a wakeup is in the channel, not consumed yet
+    notified: bool,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    pending: Mutex<Pending>,
+    wake: UnboundedSender<()>,
+}
+
+impl Subscription {
+    fn notify(&self, f: impl FnOnce(&mut Pending)) {
+        let mut pending = self.pending.lock().unwrap();
+        f(&mut pending);
+        if !mem::replace(&mut pending.notified, true) {
+            let _ = self.wake.unbounded_send(());
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Subscriptions {
+    list: Vec<Weak<Subscription>>,
+    // the framebuffer got its first scanout
+    ready: bool,
+    disconnected: bool,
+}
+
+impl Subscriptions {
+    fn event(&mut self, framebuffer: &SharedFramebuffer, event: FramebufferEvent) {
+        let event = match event {
+            FramebufferEvent::Damage(_) if !self.ready => {
+                let state = framebuffer.lock();
+                FramebufferEvent::Resized {
+                    width: state.framebuffer.width(),
+                    height: state.framebuffer.height(),
+                }
+            }
+            event => event,
+        };
+        match event {
+            FramebufferEvent::Resized { .. } => self.ready = true,
+            FramebufferEvent::Disconnected => self.disconnected = true,
+            _ => {}
+        }
+        self.list.retain(|s| match s.upgrade() {
+            Some(s) => {
+                s.notify(|p| match event {
+                    FramebufferEvent::Resized { width, height } => {
+                        p.changes.resized = Some((width, height));
+                        p.damage.clear();
+                    }
+                    FramebufferEvent::Damage(rect) if p.changes.resized.is_none() => {
+                        p.damage.add(rect)
+                    }
+                    FramebufferEvent::Damage(_) => {}
+                    FramebufferEvent::Cursor => p.changes.cursor = true,
+                    FramebufferEvent::Mouse => p.changes.mouse = true,
+                    FramebufferEvent::Disconnected => p.disconnected = true,
+                });
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+/// A console listener, fanned out to several subscribers.
+///
+/// The listener is registered next to the console listener (if any), with its own
+/// connection. The content is kept in a [`SharedFramebuffer`]: on unix, the DMABUF scanouts
+/// are read with an [`AdaptiveSink`] in copy mode, if they are linear. Dropping the
+/// multiplexer unregisters the listener, the subscribers are then disconnected.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ConsoleMultiplexer {
+    framebuffer: SharedFramebuffer,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    #[derivative(Debug = "ignore")]
+    conn: Connection,
+    listener: ListenerConnection,
+}
+
+impl ConsoleMultiplexer {
+    pub async fn new(console: &Console) -> Result<Self> {
+        let framebuffer = SharedFramebuffer::new(1, 1)?;
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+        let fb = framebuffer.clone();
+        let subs = subscriptions.clone();
+        let sink = framebuffer.sink(move |event| subs.lock().unwrap().event(&fb, event));
+        #[cfg(unix)]
+        let sink = AdaptiveSink::new(
+            sink,
+            FramePathPolicy {
+                mode: FramePathMode::Copy,
+                ..Default::default()
+            },
+        )
+        .0;
+        let listener = console
+            .register_mirror(FrameSinkListener::new(sink))
+            .await?;
+
+        Ok(Self {
+            framebuffer,
+            subscriptions,
+            conn: console.proxy.connection().clone(),
+            listener,
+        })
+    }
+
+    pub fn console_id(&self) -> u32 {
+        self.listener.console_id()
+    }
+
+    /// The console content, as last received.
+    pub fn framebuffer(&self) -> &SharedFramebuffer {
+        &self.framebuffer
+    }
+
+    /// A new subscriber, starting with the whole framebuffer once a scanout was received.
+    pub fn subscribe(&self) -> ConsoleSubscriber {
+        let (wake, receiver) = mpsc::unbounded();
+        let subscription = Arc::new(Subscription {
+            pending: Default::default(),
+            wake,
+        });
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.ready || subscriptions.disconnected {
+            let state = self.framebuffer.lock();
+            let ready = subscriptions.ready;
+            let disconnected = subscriptions.disconnected;
+            subscription.notify(|p| {
+                if ready {
+                    p.changes.resized =
+                        Some((state.framebuffer.width(), state.framebuffer.height()));
+                    p.changes.cursor = state.cursor.is_some();
+                    p.changes.mouse = state.mouse.is_some();
+                }
+                p.disconnected = disconnected;
+            });
+        }
+        subscriptions.list.push(Arc::downgrade(&subscription));
+        ConsoleSubscriber {
+            framebuffer: self.framebuffer.clone(),
+            subscription,
+            receiver,
+        }
+    }
+
+    /// Feed a sink from a new subscriber, until the multiplexer or the returned task is
+    /// dropped.
+    ///
+    /// The sink gets a scanout with each resize, and one update per changed region. The
+    /// regions changed while the sink handles an event are merged.
+    pub fn attach_sink<S: FrameSink>(&self, sink: S) -> Task<()> {
+        let subscriber = self.subscribe();
+        self.conn.executor().spawn(subscriber.feed(sink))
+    }
+}
+
+/// A subscriber of a [`ConsoleMultiplexer`].
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ConsoleSubscriber {
+    framebuffer: SharedFramebuffer,
+    subscription: Arc<Subscription>,
+    #[derivative(Debug = "ignore")]
+    receiver: UnboundedReceiver<()>,
+}
+
+impl ConsoleSubscriber {
+    /// The console content, to read the regions of the changes.
+    pub fn framebuffer(&self) -> &SharedFramebuffer {
+        &self.framebuffer
+    }
+
+    /// Wait for changes, and take them. `None` once the listener is disconnected.
+    pub async fn changed(&mut self) -> Option<ConsoleChanges> {
+        loop {
+            {
+                let mut pending = self.subscription.pending.lock().unwrap();
+                pending.notified = false;
+                let mut changes = mem::take(&mut pending.changes);
+                changes.damage = pending.damage.take();
+                if changes != ConsoleChanges::default() {
+                    return Some(changes);
+                }
+                if pending.disconnected {
+                    return None;
+                }
+            }
+            self.receiver.next().await?;
+        }
+    }
+
+    async fn feed<S: FrameSink>(mut self, mut sink: S) {
+        while let Some(changes) = self.changed().await {
+            let (scanout, updates, cursor, mouse) = {
+                let state = self.framebuffer.lock();
+                let scanout = changes.resized.map(|_| full_scanout(&state));
+                let updates: Vec<_> = changes
+                    .damage
+                    .iter()
+                    .map(|r| r.intersect(&state.framebuffer.rect()))
+                    .filter(|r| !r.is_empty())
+                    .map(|r| region(&state, r))
+                    .collect();
+                let cursor = state.cursor.clone().filter(|_| changes.cursor);
+                let mouse = state.mouse;
+                (scanout, updates, cursor, mouse)
+            };
+            if let Some(scanout) = scanout {
+                sink.on_resize(scanout.width, scanout.height).await;
+                sink.on_scanout(scanout).await;
+            }
+            for update in updates {
+                sink.on_update(update).await;
+            }
+            if let Some(cursor) = cursor {
+                sink.on_cursor(cursor).await;
+            }
+            if changes.mouse {
+                let (x, y) = mouse.unwrap_or_default();
+                let on = mouse.is_some() as i32;
+                sink.on_mouse_set(MouseSet { x, y, on }).await;
+            }
+        }
+        sink.on_disconnected();
+    }
+}
+
+fn full_scanout(state: &FramebufferState) -> Scanout {
+    let fb = &state.framebuffer;
+    Scanout {
+        width: fb.width(),
+        height: fb.height(),
+        stride: fb.stride(),
+        format: fb.format(),
+        data: fb.data().to_vec(),
+    }
+}
+
+fn region(state: &FramebufferState, rect: Rect) -> Update {
+    let fb = &state.framebuffer;
+    let row = rect.width as usize * 4;
+    let mut data = Vec::with_capacity(row * rect.height as usize);
+    for y in rect.y..rect.bottom() {
+        let start = (y * fb.stride() + rect.x * 4) as usize;
+        data.extend_from_slice(&fb.data()[start..start + row]);
+    }
+    Update {
+        x: rect.x as _,
+        y: rect.y as _,
+        w: rect.width as _,
+        h: rect.height as _,
+        stride: rect.width * 4,
+        format: fb.format(),
+        data,
+    }
+}
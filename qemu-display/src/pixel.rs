@@ -0,0 +1,237 @@
+//! The pixman formats of the frames, and their conversion to BGRA8888.
+//!
+//! QEMU gives the frames in the pixman format of the guest framebuffer, in host byte order:
+//! usually x8r8g8b8, but also 16 or 24-bit formats, and the byte-swapped layouts of
+//! big-endian guests (b8g8r8x8 on a little-endian host).
+
+use crate::{console_listener::pixman_bpp, frame_size, Error, Result, PIXMAN_A8R8G8B8};
+
+/// The pixman formats of the other frames QEMU may give.
+pub const PIXMAN_B8G8R8X8: u32 = 0x20080888;
+pub const PIXMAN_B8G8R8A8: u32 = 0x20088888;
+pub const PIXMAN_R8G8B8X8: u32 = 0x20090888;
+pub const PIXMAN_R8G8B8A8: u32 = 0x20098888;
+pub const PIXMAN_R8G8B8: u32 = 0x18020888;
+pub const PIXMAN_B8G8R8: u32 = 0x18030888;
+pub const PIXMAN_R5G6B5: u32 = 0x10020565;
+pub const PIXMAN_B5G6R5: u32 = 0x10030565;
+pub const PIXMAN_X1R5G5B5: u32 = 0x10020555;
+
+/// The pixman format of the BGRA8888 bytes, on this host.
+#[cfg(target_endian = "little")]
+pub const PIXMAN_BGRA8888: u32 = PIXMAN_A8R8G8B8;
+#[cfg(target_endian = "big")]
+pub const PIXMAN_BGRA8888: u32 = PIXMAN_B8G8R8A8;
+
+// the pixman format types
+const TYPE_ARGB: u32 = 2;
+const TYPE_ABGR: u32 = 3;
+const TYPE_BGRA: u32 = 8;
+const TYPE_RGBA: u32 = 9;
+
+// A channel of a pixel value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Channel {
+    shift: u32,
+    bits: u32,
+}
+
+impl Channel {
+    // the 8-bit sample, or `missing` for a channel without bits
+    fn sample(self, pixel: u32, missing: u8) -> u8 {
+        if self.bits == 0 {
+            return missing;
+        }
+        let max = (1u32 << self.bits) - 1;
+        let v = (pixel >> self.shift) & max;
+        ((v * 255 + max / 2) / max) as u8
+    }
+}
+
+/// A pixman format, decoded from its code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    code: u32,
+    bpp: u32,
+    a: Channel,
+    r: Channel,
+    g: Channel,
+    b: Channel,
+}
+
+impl PixelFormat {
+    /// Decode a pixman format code, of the 8 to 32 bits per pixel RGB formats.
+    pub fn from_pixman(format: u32) -> Result<Self> {
+        let bpp = pixman_bpp(format) as u32;
+        let ty = (format >> 16) & 0xff;
+        let bits = |shift: u32| (format >> shift) & 0xf;
+        let (a, r, g, b) = (bits(12), bits(8), bits(4), bits(0));
+        let unsupported = || {
+            Err(Error::Failed(format!(
+                "Unsupported pixman format 0x{:x}",
+                format
+            )))
+        };
+        if ![8, 16, 24, 32].contains(&bpp) || a + r + g + b > bpp {
+            return unsupported();
+        }
+        let channel = |shift, bits| Channel { shift, bits };
+        // from the least significant bits
+        let (a, r, g, b) = match ty {
+            TYPE_ARGB => (
+                channel(bpp - a, a),
+                channel(g + b, r),
+                channel(b, g),
+                channel(0, b),
+            ),
+            TYPE_ABGR => (
+                channel(bpp - a, a),
+                channel(0, r),
+                channel(r, g),
+                channel(r + g, b),
+            ),
+            TYPE_BGRA => (
+                channel(0, a),
+                channel(bpp - b - g - r, r),
+                channel(bpp - b - g, g),
+                channel(bpp - b, b),
+            ),
+            TYPE_RGBA => (
+                channel(0, a),
+                channel(bpp - r, r),
+                channel(bpp - r - g, g),
+                channel(bpp - r - g - b, b),
+            ),
+            _ => return unsupported(),
+        };
+        Ok(Self {
+            code: format,
+            bpp,
+            a,
+            r,
+            g,
+            b,
+        })
+    }
+
+    /// The pixman format code.
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bpp as usize / 8
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.a.bits > 0
+    }
+
+    // the pixel value, from its bytes in host order
+    fn value(&self, pixel: &[u8]) -> u32 {
+        match *pixel {
+            [p0, p1, p2, p3, ..] if self.bpp == 32 => u32::from_ne_bytes([p0, p1, p2, p3]),
+            [p0, p1, p2, ..] if self.bpp == 24 => {
+                if cfg!(target_endian = "little") {
+                    u32::from_le_bytes([p0, p1, p2, 0])
+                } else {
+                    u32::from_be_bytes([0, p0, p1, p2])
+                }
+            }
+            [p0, p1, ..] if self.bpp == 16 => u16::from_ne_bytes([p0, p1]) as u32,
+            [p0, ..] => p0 as u32,
+            [] => 0,
+        }
+    }
+
+    /// The 8-bit R, G, B, A samples of a pixel, opaque without an alpha channel.
+    pub fn rgba(&self, pixel: &[u8]) -> [u8; 4] {
+        let v = self.value(pixel);
+        [
+            self.r.sample(v, 0),
+            self.g.sample(v, 0),
+            self.b.sample(v, 0),
+            self.a.sample(v, 0xff),
+        ]
+    }
+
+    /// A pixel in the BGRA8888 byte order.
+    pub fn bgra(&self, pixel: &[u8]) -> [u8; 4] {
+        let [r, g, b, a] = self.rgba(pixel);
+        [b, g, r, a]
+    }
+
+    /// A pixel as an a8r8g8b8 value, the pixman x8r8g8b8 layout in host order.
+    pub fn xrgb(&self, pixel: &[u8]) -> u32 {
+        let [r, g, b, a] = self.rgba(pixel);
+        u32::from_be_bytes([a, r, g, b])
+    }
+}
+
+/// Convert the pixels of a frame to BGRA8888, without padding.
+///
+/// The result has the [`PIXMAN_BGRA8888`] format, and a stride of `width * 4`.
+pub fn to_bgra(width: u32, height: u32, stride: u32, format: u32, data: &[u8]) -> Result<Vec<u8>> {
+    let pf = PixelFormat::from_pixman(format)?;
+    if frame_size(width, height, stride, format)? > data.len() {
+        return Err(Error::Failed(format!(
+            "Invalid frame of {}x{}, stride {}, with {} bytes",
+            width,
+            height,
+            stride,
+            data.len()
+        )));
+    }
+    let bpp = pf.bytes_per_pixel();
+    let row = width as usize * 4;
+    let mut out = vec![0; row * height as usize];
+    for (y, dst) in out.chunks_exact_mut(row.max(1)).enumerate() {
+        let src = &data[y * stride as usize..][..width as usize * bpp];
+        for (s, d) in src.chunks_exact(bpp).zip(dst.chunks_exact_mut(4)) {
+            d.copy_from_slice(&pf.bgra(s));
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PIXMAN_X8B8G8R8, PIXMAN_X8R8G8B8};
+
+    #[test]
+    fn convert() {
+        // a red pixel, in each format
+        let red = 0xff0000u32;
+        let frames: [(u32, Vec<u8>); 7] = [
+            (PIXMAN_X8R8G8B8, red.to_ne_bytes().to_vec()),
+            (PIXMAN_X8B8G8R8, 0xffu32.to_ne_bytes().to_vec()),
+            (PIXMAN_B8G8R8X8, 0xff00u32.to_ne_bytes().to_vec()),
+            (PIXMAN_R8G8B8A8, 0xff0000ffu32.to_ne_bytes().to_vec()),
+            (PIXMAN_R5G6B5, 0xf800u16.to_ne_bytes().to_vec()),
+            (PIXMAN_X1R5G5B5, 0x7c00u16.to_ne_bytes().to_vec()),
+            (
+                PIXMAN_R8G8B8,
+                if cfg!(target_endian = "little") {
+                    vec![0, 0, 0xff]
+                } else {
+                    vec![0xff, 0, 0]
+                },
+            ),
+        ];
+        for (format, mut data) in frames {
+            let bpp = PixelFormat::from_pixman(format).unwrap().bytes_per_pixel();
+            // a second row, after a padded stride
+            data.resize(bpp + 4, 0);
+            data.extend_from_within(..bpp);
+            let bgra = to_bgra(1, 2, bpp as u32 + 4, format, &data).unwrap();
+            assert_eq!(bgra, [0, 0, 0xff, 0xff, 0, 0, 0xff, 0xff], "0x{:x}", format);
+        }
+
+        let pf = PixelFormat::from_pixman(PIXMAN_A8R8G8B8).unwrap();
+        assert!(pf.has_alpha());
+        assert_eq!(pf.xrgb(&0x80102030u32.to_ne_bytes()), 0x80102030);
+        assert!(PixelFormat::from_pixman(0x20010000).is_err());
+        assert!(to_bgra(2, 2, 8, PIXMAN_X8R8G8B8, &[0; 8]).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! A Prometheus exporter of the listener [`metrics`](crate::metrics).
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+
+use crate::{metrics, Result};
+
+// the scrapers are served one at a time: a stalled one must not block the others
+const TIMEOUT: Duration = Duration::from_secs(5);
+// the largest request read
+const MAX_REQUEST: u64 = 16 * 1024;
+
+fn serve(stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST));
+    // the request is ignored, any path gets the metrics
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+    let body = metrics().to_prometheus();
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// Serve the metrics over HTTP at `addr`, in a thread, for the Prometheus scrapers.
+///
+/// Returns the bound address.
+pub fn serve_prometheus<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = serve(stream) {
+                log::debug!("Metrics client error: {}", e);
+            }
+        }
+    });
+    Ok(addr)
+}
@@ -0,0 +1,285 @@
+#[cfg(unix)]
+use async_io::Async;
+use futures::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    stream::{self, Stream},
+    StreamExt,
+};
+use qapi::{qmp, Command, ExecuteError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+#[cfg(unix)]
+use std::os::unix::{io::RawFd, net::UnixStream};
+use std::{collections::VecDeque, io};
+
+#[cfg(unix)]
+use crate::util;
+use crate::{Result, RunState};
+
+pub use qapi::qmp::{Event as QmpEvent, StatusInfo};
+
+/// A QMP client, over a QEMU monitor socket or a chardev connection.
+///
+/// The events received while executing a command are queued, and returned by
+/// [`Qmp::next_event`] or [`Qmp::events`].
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Qmp<S> {
+    #[derivative(Debug = "ignore")]
+    stream: BufReader<S>,
+    id: u64,
+    #[derivative(Debug = "ignore")]
+    events: VecDeque<QmpEvent>,
+    // the line being read, kept if the read is cancelled
+    #[derivative(Debug = "ignore")]
+    line: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct Request<'a, C> {
+    execute: &'static str,
+    arguments: &'a C,
+    id: u64,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Qmp<S> {
+    /// Wait for the QMP greeting, and negotiate the capabilities.
+    pub async fn new(stream: S) -> Result<Self> {
+        let mut qmp = Self {
+            stream: BufReader::new(stream),
+            id: 0,
+            events: VecDeque::new(),
+            line: Vec::new(),
+        };
+        while qmp.read_message().await?.get("QMP").is_none() {}
+        qmp.execute(&qmp::qmp_capabilities { enable: None }).await?;
+        Ok(qmp)
+    }
+
+    async fn read_message(&mut self) -> Result<Value> {
+        self.stream.read_until(b'\n', &mut self.line).await?;
+        if self.line.last() != Some(&b'\n') {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let msg = serde_json::from_slice(&self.line).map_err(io::Error::from);
+        self.line.clear();
+        Ok(msg?)
+    }
+
+    fn queue_event(&mut self, msg: Value) {
+        match serde_json::from_value(msg) {
+            Ok(event) => self.events.push_back(event),
+            Err(e) => log::debug!("Ignoring QMP event: {}", e),
+        }
+    }
+
+    fn request<C: Command>(&mut self, command: &C) -> Result<(u64, Vec<u8>)> {
+        self.id += 1;
+        let mut msg = serde_json::to_vec(&Request {
+            execute: C::NAME,
+            arguments: command,
+            id: self.id,
+        })
+        .map_err(io::Error::from)?;
+        msg.push(b'\n');
+        Ok((self.id, msg))
+    }
+
+    /// Execute a command, and wait for its result.
+    pub async fn execute<C: Command>(&mut self, command: &C) -> Result<C::Ok> {
+        let (id, msg) = self.request(command)?;
+        self.stream.write_all(&msg).await?;
+        self.reply::<C>(id).await
+    }
+
+    async fn reply<C: Command>(&mut self, id: u64) -> Result<C::Ok> {
+        loop {
+            let mut msg = self.read_message().await?;
+            if msg.get("event").is_some() {
+                self.queue_event(msg);
+                continue;
+            }
+            // the reply of a cancelled command
+            if msg.get("id") != Some(&Value::from(id)) {
+                continue;
+            }
+            if let Some(err) = msg.get_mut("error") {
+                let err = serde_json::from_value(err.take()).map_err(io::Error::from)?;
+                return Err(ExecuteError::Qapi(err).into());
+            }
+            let ret = msg.get_mut("return").map(Value::take).unwrap_or_default();
+            return Ok(serde_json::from_value(ret).map_err(io::Error::from)?);
+        }
+    }
+
+    /// The next event, waiting for it if none is queued.
+    ///
+    /// It can be cancelled (dropped) without losing a message, to execute a command.
+    pub async fn next_event(&mut self) -> Result<QmpEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            let msg = self.read_message().await?;
+            if msg.get("event").is_some() {
+                self.queue_event(msg);
+            }
+        }
+    }
+
+    /// The stream of events, ending on the first error.
+    pub fn events(&mut self) -> impl Stream<Item = Result<QmpEvent>> + '_ {
+        stream::try_unfold(self, |qmp| async move {
+            let event = qmp.next_event().await?;
+            Ok(Some((event, qmp)))
+        })
+    }
+
+    pub async fn query_status(&mut self) -> Result<StatusInfo> {
+        self.execute(&qmp::query_status {}).await
+    }
+
+    /// The run state of the VM, from [`Qmp::query_status`].
+    pub async fn run_state(&mut self) -> Result<RunState> {
+        let info = self.query_status().await?;
+        let status = serde_json::to_value(&info.status).map_err(io::Error::from)?;
+        Ok(RunState::from_status(status.as_str().unwrap_or_default()))
+    }
+
+    /// The run state changes, from the events. The stream ends on the first error.
+    pub fn run_state_changes(&mut self) -> impl Stream<Item = Result<RunState>> + '_ {
+        self.events()
+            .filter_map(|event| async move { event.map(|e| run_state_event(&e)).transpose() })
+    }
+
+    /// Ask the guest to power down, like an ACPI power button press.
+    pub async fn system_powerdown(&mut self) -> Result<()> {
+        self.execute(&qmp::system_powerdown {}).await?;
+        Ok(())
+    }
+
+    pub async fn system_reset(&mut self) -> Result<()> {
+        self.execute(&qmp::system_reset {}).await?;
+        Ok(())
+    }
+
+    /// Pause the VM.
+    pub async fn stop(&mut self) -> Result<()> {
+        self.execute(&qmp::stop {}).await?;
+        Ok(())
+    }
+
+    /// Resume a paused VM.
+    pub async fn cont(&mut self) -> Result<()> {
+        self.execute(&qmp::cont {}).await?;
+        Ok(())
+    }
+
+    /// Add a client to a display protocol (for example `@dbus-display`), with a socket
+    /// previously passed as `fdname`.
+    pub async fn add_client(&mut self, protocol: &str, fdname: &str) -> Result<()> {
+        self.execute(&qmp::add_client {
+            skipauth: None,
+            tls: None,
+            protocol: protocol.into(),
+            fdname: fdname.into(),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Save a console image to a file, on the QEMU host.
+    ///
+    /// `format` is `ppm` (the default) or `png`, if the QEMU version supports it.
+    pub async fn screendump(
+        &mut self,
+        filename: &str,
+        device: Option<&str>,
+        head: Option<u32>,
+        format: Option<&str>,
+    ) -> Result<()> {
+        self.execute(&screendump {
+            filename: filename.into(),
+            device: device.map(Into::into),
+            head,
+            format: format.map(Into::into),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Pass a socket to QEMU, as `fdname`, with the information of
+    /// [`WSADuplicateSocket`](https://learn.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-wsaduplicatesocketw),
+    /// base64 encoded.
+    #[cfg(windows)]
+    pub async fn get_win32_socket(&mut self, info: &str, fdname: &str) -> Result<()> {
+        self.execute(&get_win32_socket {
+            info: info.into(),
+            fdname: fdname.into(),
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Qmp<Async<UnixStream>> {
+    /// Pass a file descriptor to QEMU, as `fdname`, for the commands that take a file
+    /// descriptor name (like [`Qmp::add_client`]).
+    pub async fn getfd(&mut self, fdname: &str, fd: RawFd) -> Result<()> {
+        let (id, msg) = self.request(&qmp::getfd {
+            fdname: fdname.into(),
+        })?;
+        // the descriptor is attached to the first bytes of the command
+        let socket = self.stream.get_ref();
+        let sent = socket.write_with(|s| util::send_fd(s, &msg, fd)).await?;
+        self.stream.write_all(&msg[sent..]).await?;
+        self.reply::<qmp::getfd>(id).await?;
+        Ok(())
+    }
+}
+
+/// The new run state announced by an event, if any.
+pub(crate) fn run_state_event(event: &QmpEvent) -> Option<RunState> {
+    let event = serde_json::to_value(event).ok()?;
+    RunState::from_event(event.get("event")?.as_str()?)
+}
+
+// defined here, for the optional arguments of the recent QEMU versions
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct screendump {
+    filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    head: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+}
+
+impl qmp::QmpCommand for screendump {}
+impl Command for screendump {
+    const NAME: &'static str = "screendump";
+    const ALLOW_OOB: bool = false;
+
+    type Ok = qapi::Empty;
+}
+
+#[cfg(windows)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct get_win32_socket {
+    info: String,
+    fdname: String,
+}
+
+#[cfg(windows)]
+impl qmp::QmpCommand for get_win32_socket {}
+#[cfg(windows)]
+impl Command for get_win32_socket {
+    const NAME: &'static str = "get-win32-socket";
+    const ALLOW_OOB: bool = false;
+
+    type Ok = qapi::Empty;
+}
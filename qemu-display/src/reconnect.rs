@@ -0,0 +1,206 @@
+use async_broadcast::{broadcast, Receiver, Sender};
+use futures::{Stream, StreamExt};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+#[cfg(windows)]
+use zbus::names::BusName;
+use zbus::{fdo, names::OwnedUniqueName, Connection, Task};
+
+use crate::{Audio, Clipboard, Console, Display, Error, Result, RetryPolicy, VMProxy};
+
+/// A change of a display supervised with [`Display::watch`].
+#[derive(derivative::Derivative, Clone)]
+#[derivative(Debug)]
+pub enum DisplayEvent {
+    /// The VM left the bus, its consoles and other objects are gone.
+    Disconnected,
+    /// A VM with the same name is back. The objects are fetched again, and the consoles,
+    /// audio and clipboard should be taken from the new display.
+    Reconnected(#[derivative(Debug = "ignore")] Display<'static>),
+    /// The reconnection failed, the display isn't watched anymore.
+    Failed(String),
+}
+
+/// A display supervised across the restarts of its VM, see [`Display::watch`].
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct DisplayWatch {
+    #[derivative(Debug = "ignore")]
+    display: Arc<Mutex<Display<'static>>>,
+    connected: Arc<AtomicBool>,
+    receiver: Receiver<DisplayEvent>,
+    #[derivative(Debug = "ignore")]
+    _task: Task<()>,
+}
+
+impl DisplayWatch {
+    /// The current display: the last reconnected one, or the watched one.
+    pub fn display(&self) -> Display<'static> {
+        self.display.lock().unwrap().clone()
+    }
+
+    /// Whether the VM is on the bus.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Get a console of the current display.
+    pub async fn console(&self, idx: u32) -> Result<Console> {
+        self.display().console(idx).await
+    }
+
+    pub async fn audio(&self) -> Result<Option<Audio>> {
+        self.display().audio().await
+    }
+
+    pub async fn clipboard(&self) -> Result<Option<Clipboard>> {
+        self.display().clipboard().await
+    }
+
+    pub fn receive_events(&self) -> Pin<Box<dyn Stream<Item = DisplayEvent> + Send>> {
+        Box::pin(self.receiver.clone())
+    }
+}
+
+impl Display<'static> {
+    /// Supervise the display, to follow the restarts of its VM.
+    ///
+    /// When the VM process leaves the bus, [`DisplayEvent::Disconnected`] is emitted, and a
+    /// VM of the same name is looked up with `policy`. Once found, its display is created
+    /// again, with a fresh copy of the objects, and [`DisplayEvent::Reconnected`] is
+    /// emitted. A bus connection is required: a peer-to-peer connection is gone with its VM.
+    pub async fn watch(&self, policy: RetryPolicy) -> Result<DisplayWatch> {
+        let conn = self.connection().clone();
+        if conn.unique_name().is_none() {
+            return Err(Error::Failed(
+                "A peer-to-peer display can't be watched".into(),
+            ));
+        }
+        let dbus = fdo::DBusProxy::new(&conn).await?;
+        // subscribe before resolving the owner, to not miss its departure
+        let changed = dbus.receive_name_owner_changed().await?;
+        let dest = self.destination();
+        let owner = dbus.get_name_owner(dest.clone()).await?;
+        let name = VMProxy::builder(&conn)
+            .destination(dest)?
+            .build()
+            .await?
+            .name()
+            .await?;
+
+        let display = Arc::new(Mutex::new(self.clone()));
+        let connected = Arc::new(AtomicBool::new(true));
+        let (mut sender, receiver) = broadcast(4);
+        sender.set_overflow(true);
+        let supervisor = Supervisor {
+            conn: conn.clone(),
+            #[cfg(windows)]
+            dbus,
+            name,
+            policy,
+            display: display.clone(),
+            connected: connected.clone(),
+            sender,
+        };
+        let task = conn.executor().spawn(supervisor.run(changed, owner));
+
+        Ok(DisplayWatch {
+            display,
+            connected,
+            receiver,
+            _task: task,
+        })
+    }
+}
+
+struct Supervisor {
+    conn: Connection,
+    #[cfg(windows)]
+    dbus: fdo::DBusProxy<'static>,
+    name: String,
+    policy: RetryPolicy,
+    display: Arc<Mutex<Display<'static>>>,
+    connected: Arc<AtomicBool>,
+    sender: Sender<DisplayEvent>,
+}
+
+impl Supervisor {
+    async fn reconnect(&self) -> Result<(OwnedUniqueName, Display<'static>)> {
+        self.policy
+            .retry("display reconnection", || async {
+                let dest = Display::by_name(&self.conn)
+                    .await?
+                    .remove(&self.name)
+                    .ok_or_else(|| Error::Failed(format!("Can't find VM '{}'", self.name)))?;
+                #[cfg(windows)]
+                let peer_pid = self
+                    .dbus
+                    .get_connection_unix_process_id(BusName::from(dest.clone().into_inner()))
+                    .await?;
+                let display = Display::new(
+                    &self.conn,
+                    Some(dest.clone()),
+                    #[cfg(windows)]
+                    peer_pid,
+                )
+                .await?;
+                Ok((dest, display))
+            })
+            .await
+    }
+
+    async fn run(
+        self,
+        mut changed: fdo::NameOwnerChangedStream<'static>,
+        mut owner: OwnedUniqueName,
+    ) {
+        loop {
+            // a unique name is released when the process leaves the bus
+            loop {
+                let signal = match changed.next().await {
+                    Some(signal) => signal,
+                    None => return,
+                };
+                let args = match signal.args() {
+                    Ok(args) => args,
+                    Err(e) => {
+                        log::debug!("Invalid NameOwnerChanged signal: {}", e);
+                        continue;
+                    }
+                };
+                if args.name().as_str() == owner.as_str() && args.new_owner().is_none() {
+                    break;
+                }
+            }
+            log::info!("VM '{}' disconnected", self.name);
+            self.connected.store(false, Ordering::SeqCst);
+            let _ = self.sender.broadcast(DisplayEvent::Disconnected).await;
+
+            match self.reconnect().await {
+                Ok((dest, display)) => {
+                    log::info!("VM '{}' reconnected", self.name);
+                    owner = dest;
+                    *self.display.lock().unwrap() = display.clone();
+                    self.connected.store(true, Ordering::SeqCst);
+                    let _ = self
+                        .sender
+                        .broadcast(DisplayEvent::Reconnected(display))
+                        .await;
+                }
+                Err(e) => {
+                    log::warn!("Failed to reconnect VM '{}': {}", self.name, e);
+                    let _ = self
+                        .sender
+                        .broadcast(DisplayEvent::Failed(e.to_string()))
+                        .await;
+                    return;
+                }
+            }
+        }
+    }
+}
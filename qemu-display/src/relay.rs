@@ -0,0 +1,137 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Shutdown, TcpListener, TcpStream},
+    thread,
+};
+#[cfg(feature = "tls")]
+use std::{
+    io::prelude::*,
+    sync::{Arc, Mutex},
+};
+
+/// A connected pair of loopback streams.
+///
+/// The servers taking a plain [`TcpStream`] are given one end, while the other one is
+/// relayed to the client connection, once its security is negotiated.
+pub fn loopback_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let relay = TcpStream::connect(listener.local_addr()?)?;
+    let (local, addr) = listener.accept()?;
+    if addr != relay.local_addr()? {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Unexpected loopback peer",
+        ));
+    }
+    Ok((local, relay))
+}
+
+/// Relay the client `tcp` connection and the `relay` end of a [`loopback_pair`], until the
+/// client is gone.
+pub fn relay_plain(mut tcp: TcpStream, mut relay: TcpStream) -> io::Result<()> {
+    let writer = {
+        let mut tcp = tcp.try_clone()?;
+        let mut relay = relay.try_clone()?;
+        thread::spawn(move || -> io::Result<()> {
+            io::copy(&mut relay, &mut tcp)?;
+            let _ = tcp.shutdown(Shutdown::Write);
+            Ok(())
+        })
+    };
+    io::copy(&mut tcp, &mut relay)?;
+    let _ = relay.shutdown(Shutdown::Write);
+
+    writer.join().unwrap()
+}
+
+/// Like [`relay_plain`], decrypting the client records of the established TLS `conn`.
+#[cfg(feature = "tls")]
+pub fn relay_tls(
+    conn: rustls::ServerConnection,
+    mut tcp: TcpStream,
+    mut relay: TcpStream,
+) -> io::Result<()> {
+    let conn = Arc::new(Mutex::new(conn));
+    let writer = {
+        let conn = conn.clone();
+        let mut tcp = tcp.try_clone()?;
+        let mut relay = relay.try_clone()?;
+        thread::spawn(move || -> io::Result<()> {
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = relay.read(&mut buf)?;
+                let mut conn = conn.lock().unwrap();
+                if n == 0 {
+                    conn.send_close_notify();
+                } else {
+                    conn.writer().write_all(&buf[..n])?;
+                }
+                while conn.wants_write() {
+                    conn.write_tls(&mut tcp)?;
+                }
+                if n == 0 {
+                    let _ = tcp.shutdown(Shutdown::Write);
+                    return Ok(());
+                }
+            }
+        })
+    };
+
+    // read the client records without holding the lock, so the writer isn't blocked
+    let mut buf = vec![0; 64 * 1024];
+    let mut plain = vec![0; 64 * 1024];
+    loop {
+        let n = tcp.read(&mut buf)?;
+        if n == 0 {
+            let _ = relay.shutdown(Shutdown::Write);
+            break;
+        }
+        let mut conn = conn.lock().unwrap();
+        let mut records = &buf[..n];
+        while !records.is_empty() {
+            conn.read_tls(&mut records)?;
+            conn.process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            loop {
+                match conn.reader().read(&mut plain) {
+                    Ok(0) => break,
+                    Ok(n) => relay.write_all(&plain[..n])?,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut tcp)?;
+        }
+    }
+
+    writer.join().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn plain() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (tcp, _) = listener.accept().unwrap();
+        let (mut local, relay) = loopback_pair().unwrap();
+        let relay = thread::spawn(move || relay_plain(tcp, relay));
+
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        local.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        local.write_all(b"pong").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        drop(local);
+        client.shutdown(Shutdown::Write).unwrap();
+        relay.join().unwrap().unwrap();
+    }
+}
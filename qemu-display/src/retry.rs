@@ -0,0 +1,192 @@
+use async_io::Timer;
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::Result;
+
+/// A retry, reported to the metrics hook of a [`RetryPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryEvent {
+    /// Attempt number `attempt` failed, the next one is in `delay`.
+    Retry {
+        what: &'static str,
+        attempt: u32,
+        delay: Duration,
+    },
+    /// The operation succeeded after `attempts` attempts.
+    Success { what: &'static str, attempts: u32 },
+    /// The operation failed `attempts` times, and isn't retried anymore.
+    GaveUp { what: &'static str, attempts: u32 },
+}
+
+type MetricsFn = Arc<dyn Fn(RetryEvent) + Send + Sync>;
+
+/// How the reconnections are retried: an exponential backoff with jitter, and an optional
+/// maximum number of attempts.
+///
+/// The same policy is used by the lookup of [`Display::lookup_with_policy`], the
+/// re-registrations of [`ConsoleWatchdog`] and the chardev reconnections of
+/// [`Chardev::connect_with_policy`].
+///
+/// [`Display::lookup_with_policy`]: crate::Display::lookup_with_policy
+/// [`ConsoleWatchdog`]: crate::ConsoleWatchdog
+/// [`Chardev::connect_with_policy`]: crate::Chardev::connect_with_policy
+#[derive(derivative::Derivative, Clone)]
+#[derivative(Debug)]
+pub struct RetryPolicy {
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+    /// The maximum delay between two attempts.
+    pub max_delay: Duration,
+    /// The delay factor, from one retry to the next.
+    pub multiplier: f64,
+    /// The random part of the delays, from 0.0 (none) to 1.0 (up to the full delay).
+    pub jitter: f64,
+    /// The maximum number of attempts, `None` to retry forever.
+    pub max_attempts: Option<u32>,
+    #[derivative(Debug = "ignore")]
+    metrics: Option<MetricsFn>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+            metrics: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy without retry.
+    pub fn never() -> Self {
+        Self::default().with_max_attempts(1)
+    }
+
+    /// Give up after `max` attempts.
+    pub fn with_max_attempts(mut self, max: u32) -> Self {
+        self.max_attempts = Some(max);
+        self
+    }
+
+    /// Report the retries to `metrics`.
+    pub fn with_metrics<F>(mut self, metrics: F) -> Self
+    where
+        F: Fn(RetryEvent) + Send + Sync + 'static,
+    {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// The retry state of an operation, `what` names it in the metrics.
+    pub fn backoff(&self, what: &'static str) -> Backoff {
+        Backoff {
+            policy: self.clone(),
+            what,
+            attempts: 0,
+        }
+    }
+
+    /// Run `op` until it succeeds, or the attempts are exhausted.
+    pub async fn retry<T, F, Fut>(&self, what: &'static str, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut backoff = self.backoff(what);
+        loop {
+            match op().await {
+                Ok(res) => {
+                    backoff.reset();
+                    return Ok(res);
+                }
+                Err(e) => {
+                    log::debug!("{} failed: {}", what, e);
+                    if !backoff.wait().await {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn report(&self, event: RetryEvent) {
+        if let Some(metrics) = &self.metrics {
+            metrics(event);
+        }
+    }
+}
+
+/// The retry state of an operation, see [`RetryPolicy::backoff`].
+#[derive(Debug)]
+pub struct Backoff {
+    policy: RetryPolicy,
+    what: &'static str,
+    attempts: u32,
+}
+
+fn random() -> f64 {
+    let n = RandomState::new().build_hasher().finish();
+    (n >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Backoff {
+    /// The failed attempts since the last success.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Record a failed attempt, and return the delay before the next one, or `None` if the
+    /// attempts are exhausted.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts += 1;
+        let policy = &self.policy;
+        if matches!(policy.max_attempts, Some(max) if self.attempts >= max) {
+            policy.report(RetryEvent::GaveUp {
+                what: self.what,
+                attempts: self.attempts,
+            });
+            return None;
+        }
+        let exp = policy.multiplier.max(1.0).powi(self.attempts as i32 - 1);
+        let secs = (policy.initial_delay.as_secs_f64() * exp).min(policy.max_delay.as_secs_f64());
+        let delay =
+            Duration::from_secs_f64(secs * (1.0 - policy.jitter.clamp(0.0, 1.0) * random()));
+        policy.report(RetryEvent::Retry {
+            what: self.what,
+            attempt: self.attempts,
+            delay,
+        });
+        Some(delay)
+    }
+
+    /// Record a failed attempt, and wait before the next one. Returns `false` if the
+    /// attempts are exhausted.
+    pub async fn wait(&mut self) -> bool {
+        match self.next_delay() {
+            Some(delay) => {
+                Timer::after(delay).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record a success: the next failure starts over from the initial delay.
+    pub fn reset(&mut self) {
+        self.policy.report(RetryEvent::Success {
+            what: self.what,
+            attempts: self.attempts + 1,
+        });
+        self.attempts = 0;
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    fmt,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use futures::{Future, Stream, StreamExt};
+
+use crate::{
+    keyboard::QNUM_LSHIFT, state::StateTracker, Console, ConsoleWatchdog, Cursor, Display,
+    DisplayEvent, DisplayWatch, Error, FrameSink, FrameSinkListener, GuestDefaults, GuestOs,
+    ListenerConnection, MouseSet, Result, RetryPolicy, Scanout, SessionState, Subsystem, Update,
+    VMProxy,
+};
+#[cfg(unix)]
+use crate::{ScanoutDMABUF, UpdateDMABUF};
+#[cfg(windows)]
+use crate::{ScanoutMap, UpdateMap};
+
+/// How to wake the guest display, when a viewer connects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WakeMethod {
+    /// Leave the display as it is.
+    #[default]
+    None,
+    /// A relative mouse motion, back and forth. With an absolute mouse, the cursor position
+    /// isn't known and a key is used instead.
+    Mouse,
+    /// A shift key press and release.
+    Key,
+}
+
+impl FromStr for WakeMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "mouse" => Ok(Self::Mouse),
+            "key" => Ok(Self::Key),
+            _ => Err(Error::Failed(format!(
+                "Invalid wake method '{}' (none, mouse or key)",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for WakeMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Mouse => "mouse",
+            Self::Key => "key",
+        })
+    }
+}
+
+/// Options of a [`Session`].
+#[derive(Debug, Clone, Default)]
+pub struct SessionOptions {
+    /// Nudge the guest display when a viewer connects, in case it was blanked (DPMS).
+    pub wake: WakeMethod,
+    /// The guest OS, instead of asking the guest agent.
+    pub guest_os: Option<GuestOs>,
+}
+
+/// The display of a VM, shared by the viewers of a frontend.
+#[derive(derivative::Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Session {
+    #[derivative(Debug = "ignore")]
+    display: Display<'static>,
+    opts: SessionOptions,
+    guest_os: Arc<Mutex<Option<GuestOs>>>,
+    state: Arc<StateTracker>,
+}
+
+impl Session {
+    pub fn new(display: Display<'static>, opts: SessionOptions) -> Self {
+        let guest_os = Arc::new(Mutex::new(opts.guest_os.clone()));
+        Self {
+            display,
+            opts,
+            guest_os,
+            state: StateTracker::new(),
+        }
+    }
+
+    pub fn display(&self) -> &Display<'static> {
+        &self.display
+    }
+
+    pub fn options(&self) -> &SessionOptions {
+        &self.opts
+    }
+
+    /// The current state, [`SessionState::Connecting`] until [`Session::supervise`].
+    pub fn state(&self) -> SessionState {
+        self.state.state()
+    }
+
+    /// The states of the session, starting with the current one.
+    ///
+    /// The display connection, the watched consoles and the subsystems reported by the
+    /// frontend are aggregated in this single stream, to drive the frontend UI.
+    pub fn receive_state(&self) -> Pin<Box<dyn Stream<Item = SessionState> + Send>> {
+        self.state.receive()
+    }
+
+    /// Identify the VM, and follow its restarts with `policy`.
+    ///
+    /// The session is [`SessionState::Connected`] once the VM is identified. With a bus
+    /// connection, the display is watched: the returned [`DisplayWatch`] gives the
+    /// reconnected displays, and must be kept for the session to follow them. A
+    /// peer-to-peer display isn't watched: it is gone with its VM.
+    pub async fn supervise(&self, policy: RetryPolicy) -> Result<Option<DisplayWatch>> {
+        let conn = self.display.connection();
+        let vm = VMProxy::builder(conn)
+            .destination(self.display.destination())?
+            .build()
+            .await?;
+        let name = vm.name().await?;
+        self.state.connected(name.clone());
+
+        if conn.unique_name().is_none() {
+            return Ok(None);
+        }
+
+        let watch = self.display.watch(policy).await?;
+        let mut events = watch.receive_events();
+        let state = self.state.clone();
+        self.state.add_task(conn.executor().spawn(async move {
+            while let Some(event) = events.next().await {
+                state.display_event(&event);
+                if let DisplayEvent::Reconnected(_) = event {
+                    state.connected(name.clone());
+                }
+            }
+        }));
+        Ok(Some(watch))
+    }
+
+    /// Report the health of a console listener in the session state.
+    pub fn track_console(&self, console_id: u32, watchdog: &ConsoleWatchdog) {
+        let mut health = watchdog.receive_health();
+        let state = self.state.clone();
+        self.state
+            .add_task(self.display.connection().executor().spawn(async move {
+                while let Some(health) = health.next().await {
+                    state.console_health(console_id, &health);
+                }
+            }));
+    }
+
+    /// Report a failed subsystem: the session is [`SessionState::Degraded`] until it
+    /// recovers.
+    pub fn report_degraded(&self, subsystem: Subsystem, reason: impl Into<String>) {
+        let reason = reason.into();
+        log::warn!("Session {} degraded: {}", subsystem, reason);
+        self.state.degraded(subsystem, reason);
+    }
+
+    /// Report a subsystem working again.
+    pub fn report_recovered(&self, subsystem: &Subsystem) {
+        self.state.recovered(subsystem);
+    }
+
+    /// Close the session. It stays [`SessionState::Closed`], whatever happens next.
+    pub fn close(&self, reason: impl Into<String>) {
+        self.state.closed(reason.into());
+    }
+
+    /// Get a console of the display.
+    pub async fn console(&self, id: u32) -> Result<Console> {
+        self.display.console(id).await
+    }
+
+    /// Attach a sink to a console, next to the frontend listener.
+    ///
+    /// The sink gets its own listener registration, so it can be attached and detached at
+    /// any time (to record while viewing, or to bridge a running session to VNC for
+    /// example), independently of the other viewers. It starts with a full scanout: the
+    /// updates received before it are dropped. With a GL display, the scanouts are DMABUF,
+    /// an [`AdaptiveSink`](crate::AdaptiveSink) in copy mode reads them for a sink
+    /// handling regular scanouts only.
+    pub async fn mirror<S: FrameSink>(&self, console_id: u32, sink: S) -> Result<Mirror> {
+        let console = self.console(console_id).await?;
+        let listener = console.register_mirror(MirrorSink::new(sink)).await?;
+        log::debug!("Console {}: mirror attached", console_id);
+        Ok(Mirror { listener })
+    }
+
+    /// The guest OS, from [`SessionOptions::guest_os`] or the guest agent (with the "qga"
+    /// feature).
+    pub async fn guest_os(&self) -> Option<GuestOs> {
+        if let Some(os) = self.guest_os.lock().unwrap().clone() {
+            return Some(os);
+        }
+        #[cfg(feature = "qga")]
+        match self.display.guest_os().await {
+            Ok(Some(os)) => {
+                log::debug!("Guest OS: {}", os);
+                *self.guest_os.lock().unwrap() = Some(os.clone());
+                return Some(os);
+            }
+            Ok(None) => {}
+            Err(e) => log::debug!("Failed to get the guest OS: {}", e),
+        }
+        None
+    }
+
+    /// The frontend defaults for the guest OS, or the generic ones if it's unknown.
+    pub async fn guest_defaults(&self) -> GuestDefaults {
+        self.guest_os()
+            .await
+            .map(|os| os.defaults())
+            .unwrap_or_default()
+    }
+
+    /// To be called when a viewer of the console connects.
+    ///
+    /// The guest display is woken, according to [`SessionOptions::wake`].
+    pub async fn viewer_connected(&self, console: &Console) -> Result<()> {
+        self.wake(console, self.opts.wake).await
+    }
+
+    /// Wake the guest display, with synthesized input that shouldn't affect the guest.
+    pub async fn wake(&self, console: &Console, method: WakeMethod) -> Result<()> {
+        let method = match method {
+            WakeMethod::Mouse if console.mouse.is_absolute().await.unwrap_or(true) => {
+                WakeMethod::Key
+            }
+            m => m,
+        };
+        log::debug!("Console {}: wake with {}", console.id(), method);
+        match method {
+            WakeMethod::None => {}
+            WakeMethod::Mouse => {
+                console.mouse.rel_motion(1, 0).await?;
+                console.mouse.rel_motion(-1, 0).await?;
+            }
+            WakeMethod::Key => {
+                console.keyboard.press(QNUM_LSHIFT).await?;
+                console.keyboard.release(QNUM_LSHIFT).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A sink attached with [`Session::mirror`], detached when dropped.
+#[derive(Debug)]
+pub struct Mirror {
+    listener: ListenerConnection,
+}
+
+impl Mirror {
+    pub fn console_id(&self) -> u32 {
+        self.listener.console_id()
+    }
+
+    /// Resolves when QEMU closes the mirror connection.
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.listener.closed()
+    }
+
+    /// Detach the sink, and wait until it is no longer called.
+    pub async fn detach(self) {
+        let id = self.console_id();
+        self.listener.close().await;
+        log::debug!("Console {}: mirror detached", id);
+    }
+}
+
+// holds the updates until the sink has a full scanout
+struct MirrorSink<S> {
+    sink: S,
+    ready: bool,
+}
+
+impl<S: FrameSink> MirrorSink<S> {
+    fn new(sink: S) -> FrameSinkListener<Self> {
+        FrameSinkListener::new(Self { sink, ready: false })
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: FrameSink> FrameSink for MirrorSink<S> {
+    async fn on_resize(&mut self, width: u32, height: u32) {
+        self.sink.on_resize(width, height).await;
+    }
+
+    async fn on_scanout(&mut self, scanout: Scanout) {
+        self.ready = true;
+        self.sink.on_scanout(scanout).await;
+    }
+
+    async fn on_update(&mut self, update: Update) {
+        if self.ready {
+            self.sink.on_update(update).await;
+        }
+    }
+
+    #[cfg(windows)]
+    async fn on_scanout_map(&mut self, scanout: ScanoutMap) {
+        self.ready = true;
+        self.sink.on_scanout_map(scanout).await;
+    }
+
+    #[cfg(windows)]
+    async fn on_update_map(&mut self, update: UpdateMap) {
+        if self.ready {
+            self.sink.on_update_map(update).await;
+        }
+    }
+
+    #[cfg(unix)]
+    async fn on_scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        self.ready = true;
+        self.sink.on_scanout_dmabuf(scanout).await;
+    }
+
+    #[cfg(unix)]
+    async fn on_update_dmabuf(&mut self, update: UpdateDMABUF) {
+        if self.ready {
+            self.sink.on_update_dmabuf(update).await;
+        }
+    }
+
+    async fn on_cursor(&mut self, cursor: Cursor) {
+        self.sink.on_cursor(cursor).await;
+    }
+
+    async fn on_mouse_set(&mut self, set: MouseSet) {
+        self.sink.on_mouse_set(set).await;
+    }
+
+    fn on_disable(&mut self) {
+        self.ready = false;
+        self.sink.on_disable();
+    }
+
+    fn on_disconnected(&mut self) {
+        self.sink.on_disconnected();
+    }
+}
@@ -0,0 +1,153 @@
+//! Scanouts in shared memory, on unix.
+//!
+//! With the `org.qemu.Display1.Listener.Unix.Map` interface, QEMU shares the console
+//! surface as a memfd once per scanout, and only notifies the updated regions: the pixels
+//! don't go through the D-Bus socket anymore.
+
+use std::{io, mem, os::unix::io::RawFd, ptr, slice, sync::Arc};
+
+use crate::{frame_size, Error, Result, Scanout, Update, UpdateMap};
+
+/// The capability advertised by the listeners handling the shared memory scanouts.
+pub const LISTENER_UNIX_MAP_INTERFACE: &str = "org.qemu.Display1.Listener.Unix.Map";
+
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is read-only
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as _, self.len) };
+    }
+}
+
+/// A console surface shared by QEMU, mapped read-only.
+///
+/// The mapping outlives the scanout: it is kept alive by its clones. QEMU keeps drawing in
+/// the surface, the content of a region is only consistent when it was just updated.
+#[derive(derivative::Derivative, Clone)]
+#[derivative(Debug)]
+pub struct ScanoutMapped {
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub format: u32,
+    #[derivative(Debug = "ignore")]
+    map: Arc<Mapping>,
+    offset: usize,
+    size: usize,
+}
+
+impl ScanoutMapped {
+    /// Map the frame at `offset` of the shared memory `fd`.
+    ///
+    /// The file must hold the whole frame: accessing past its end would fault.
+    pub fn new(
+        fd: RawFd,
+        offset: u32,
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: u32,
+    ) -> Result<Self> {
+        let size = frame_size(width, height, stride, format)?;
+        let offset = offset as usize;
+        let len = offset
+            .checked_add(size)
+            .ok_or(Error::TooLarge { width, height })?;
+
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if (stat.st_size as u64) < len as u64 {
+            return Err(Error::Failed(format!(
+                "Shared map too short: {} < {}",
+                stat.st_size, len
+            )));
+        }
+        // the offset may not be page-aligned, the mapping starts at 0
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len.max(1),
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            width,
+            height,
+            stride,
+            format,
+            map: Arc::new(Mapping {
+                ptr: ptr as _,
+                len: len.max(1),
+            }),
+            offset,
+            size,
+        })
+    }
+
+    /// The frame pixels, starting with the first row.
+    pub fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.map.ptr.add(self.offset), self.size) }
+    }
+
+    /// A copy of the frame, as a regular scanout.
+    pub fn to_scanout(&self) -> Scanout {
+        Scanout {
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+            format: self.format,
+            data: self.data().to_vec(),
+        }
+    }
+
+    /// A copy of the updated region, as a regular update.
+    pub fn to_update(&self, update: &UpdateMap) -> Result<Update> {
+        let (x, y, w, h) = (update.x, update.y, update.w, update.h);
+        if x < 0
+            || y < 0
+            || w < 0
+            || h < 0
+            || x as u32 + w as u32 > self.width
+            || y as u32 + h as u32 > self.height
+        {
+            return Err(Error::Failed(format!(
+                "Invalid update region {:?} of {}x{}",
+                (x, y, w, h),
+                self.width,
+                self.height
+            )));
+        }
+        let bpp = crate::console_listener::pixman_bpp(self.format) / 8;
+        let row = w as usize * bpp;
+        let mut data = Vec::with_capacity(row * h as usize);
+        for line in y as usize..(y + h) as usize {
+            let start = line * self.stride as usize + x as usize * bpp;
+            data.extend_from_slice(&self.data()[start..start + row]);
+        }
+        Ok(Update {
+            x,
+            y,
+            w,
+            h,
+            stride: row as u32,
+            format: self.format,
+            data,
+        })
+    }
+}
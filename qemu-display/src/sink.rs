@@ -0,0 +1,189 @@
+use crate::{ConsoleListenerHandler, Cursor, MouseSet, Scanout, Update};
+#[cfg(unix)]
+use crate::{ScanoutDMABUF, UpdateDMABUF};
+#[cfg(windows)]
+use crate::{ScanoutMap, UpdateMap};
+
+/// A consumer of console frames.
+///
+/// Unlike [`ConsoleListenerHandler`], only the scanout and update events are mandatory, and
+/// resizes are reported explicitly. Use [`FrameSinkListener`] to register a sink on a console.
+#[async_trait::async_trait]
+pub trait FrameSink: 'static + Send + Sync {
+    async fn on_resize(&mut self, _width: u32, _height: u32) {}
+
+    async fn on_scanout(&mut self, scanout: Scanout);
+
+    async fn on_update(&mut self, update: Update);
+
+    #[cfg(windows)]
+    async fn on_scanout_map(&mut self, _scanout: ScanoutMap) {}
+
+    #[cfg(windows)]
+    async fn on_update_map(&mut self, _update: UpdateMap) {}
+
+    #[cfg(unix)]
+    async fn on_scanout_dmabuf(&mut self, _scanout: ScanoutDMABUF) {}
+
+    #[cfg(unix)]
+    async fn on_update_dmabuf(&mut self, _update: UpdateDMABUF) {}
+
+    async fn on_cursor(&mut self, _cursor: Cursor) {}
+
+    async fn on_mouse_set(&mut self, _set: MouseSet) {}
+
+    /// The guest disabled the display, until the next scanout.
+    fn on_disable(&mut self) {}
+
+    fn on_disconnected(&mut self) {}
+}
+
+#[async_trait::async_trait]
+impl FrameSink for Vec<Box<dyn FrameSink>> {
+    async fn on_resize(&mut self, width: u32, height: u32) {
+        for s in self.iter_mut() {
+            s.on_resize(width, height).await;
+        }
+    }
+
+    async fn on_scanout(&mut self, scanout: Scanout) {
+        for s in self.iter_mut() {
+            s.on_scanout(scanout.clone()).await;
+        }
+    }
+
+    async fn on_update(&mut self, update: Update) {
+        for s in self.iter_mut() {
+            s.on_update(update.clone()).await;
+        }
+    }
+
+    #[cfg(windows)]
+    async fn on_scanout_map(&mut self, scanout: ScanoutMap) {
+        // the map handle is owned by the receiver, it can't be shared
+        if let Some(s) = self.first_mut() {
+            s.on_scanout_map(scanout).await;
+        }
+    }
+
+    #[cfg(windows)]
+    async fn on_update_map(&mut self, update: UpdateMap) {
+        if let Some(s) = self.first_mut() {
+            s.on_update_map(update).await;
+        }
+    }
+
+    #[cfg(unix)]
+    async fn on_scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        for s in self.iter_mut() {
+            if let Some(scanout) = scanout.try_clone() {
+                s.on_scanout_dmabuf(scanout).await;
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    async fn on_update_dmabuf(&mut self, update: UpdateDMABUF) {
+        for s in self.iter_mut() {
+            s.on_update_dmabuf(update).await;
+        }
+    }
+
+    async fn on_cursor(&mut self, cursor: Cursor) {
+        for s in self.iter_mut() {
+            s.on_cursor(cursor.clone()).await;
+        }
+    }
+
+    async fn on_mouse_set(&mut self, set: MouseSet) {
+        for s in self.iter_mut() {
+            s.on_mouse_set(set).await;
+        }
+    }
+
+    fn on_disable(&mut self) {
+        for s in self.iter_mut() {
+            s.on_disable();
+        }
+    }
+
+    fn on_disconnected(&mut self) {
+        for s in self.iter_mut() {
+            s.on_disconnected();
+        }
+    }
+}
+
+/// Adapts a [`FrameSink`] to a [`ConsoleListenerHandler`].
+#[derive(Debug, Clone)]
+pub struct FrameSinkListener<S: FrameSink> {
+    sink: S,
+    size: Option<(u32, u32)>,
+}
+
+impl<S: FrameSink> FrameSinkListener<S> {
+    pub fn new(sink: S) -> Self {
+        Self { sink, size: None }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    async fn resize(&mut self, width: u32, height: u32) {
+        if self.size != Some((width, height)) {
+            self.size = Some((width, height));
+            self.sink.on_resize(width, height).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: FrameSink> ConsoleListenerHandler for FrameSinkListener<S> {
+    async fn scanout(&mut self, scanout: Scanout) {
+        self.resize(scanout.width, scanout.height).await;
+        self.sink.on_scanout(scanout).await;
+    }
+
+    async fn update(&mut self, update: Update) {
+        self.sink.on_update(update).await;
+    }
+
+    #[cfg(windows)]
+    async fn scanout_map(&mut self, scanout: ScanoutMap) {
+        self.resize(scanout.width, scanout.height).await;
+        self.sink.on_scanout_map(scanout).await;
+    }
+
+    #[cfg(windows)]
+    async fn update_map(&mut self, update: UpdateMap) {
+        self.sink.on_update_map(update).await;
+    }
+
+    #[cfg(unix)]
+    async fn scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        self.resize(scanout.width, scanout.height).await;
+        self.sink.on_scanout_dmabuf(scanout).await;
+    }
+
+    #[cfg(unix)]
+    async fn update_dmabuf(&mut self, update: UpdateDMABUF) {
+        self.sink.on_update_dmabuf(update).await;
+    }
+
+    async fn mouse_set(&mut self, set: MouseSet) {
+        self.sink.on_mouse_set(set).await;
+    }
+
+    async fn cursor_define(&mut self, cursor: Cursor) {
+        self.sink.on_cursor(cursor).await;
+    }
+
+    fn disable(&mut self) {
+        self.sink.on_disable();
+    }
+
+    fn disconnected(&mut self) {
+        self.sink.on_disconnected();
+    }
+}
@@ -0,0 +1,477 @@
+use async_lock::Mutex;
+use enumflags2::BitFlags;
+use futures::{
+    future,
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    StreamExt,
+};
+use std::{
+    convert::TryInto,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{Console, Error, KeyboardProxy, ModifierTracker, MouseButton, MouseProxy, Result};
+
+// from spice-protocol protocol.h and enums.h
+const SPICE_MAGIC: u32 = u32::from_le_bytes(*b"REDQ");
+const SPICE_VERSION_MAJOR: u32 = 2;
+const SPICE_VERSION_MINOR: u32 = 2;
+const SPICE_TICKET_PUBKEY_BYTES: usize = 162;
+const SPICE_TICKET_BYTES: usize = 128;
+
+const SPICE_LINK_ERR_OK: u32 = 0;
+const SPICE_LINK_ERR_INVALID_DATA: u32 = 3;
+const SPICE_LINK_ERR_VERSION_MISMATCH: u32 = 4;
+const SPICE_LINK_ERR_CHANNEL_NOT_AVAILABLE: u32 = 9;
+
+const SPICE_CHANNEL_MAIN: u8 = 1;
+const SPICE_CHANNEL_INPUTS: u8 = 3;
+
+const SPICE_MSG_MAIN_INIT: u16 = 103;
+const SPICE_MSG_MAIN_CHANNELS_LIST: u16 = 104;
+const SPICE_MSG_MAIN_MOUSE_MODE: u16 = 105;
+const SPICE_MSGC_MAIN_ATTACH_CHANNELS: u16 = 104;
+const SPICE_MSGC_MAIN_MOUSE_MODE_REQUEST: u16 = 105;
+
+const SPICE_MOUSE_MODE_SERVER: u16 = 1;
+const SPICE_MOUSE_MODE_CLIENT: u16 = 2;
+
+const SPICE_MSG_INPUTS_INIT: u16 = 101;
+const SPICE_MSG_INPUTS_KEY_MODIFIERS: u16 = 102;
+const SPICE_MSG_INPUTS_MOUSE_MOTION_ACK: u16 = 111;
+const SPICE_MSGC_INPUTS_KEY_DOWN: u16 = 101;
+const SPICE_MSGC_INPUTS_KEY_UP: u16 = 102;
+const SPICE_MSGC_INPUTS_KEY_MODIFIERS: u16 = 103;
+const SPICE_MSGC_INPUTS_MOUSE_MOTION: u16 = 111;
+const SPICE_MSGC_INPUTS_MOUSE_POSITION: u16 = 112;
+const SPICE_MSGC_INPUTS_MOUSE_PRESS: u16 = 113;
+const SPICE_MSGC_INPUTS_MOUSE_RELEASE: u16 = 114;
+// the client waits for an ack after this many motion messages
+const SPICE_INPUT_MOTION_ACK_BUNCH: u32 = 4;
+
+const LINK_HEADER_SIZE: usize = 16;
+const LINK_MESS_SIZE: usize = 18;
+const LINK_REPLY_SIZE: usize = 4 + SPICE_TICKET_PUBKEY_BYTES + 12;
+// without the mini header capability: serial, type, size and sub_list
+const DATA_HEADER_SIZE: usize = 18;
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// The client encrypts its ticket with this 1024-bit RSA key (DER SubjectPublicKeyInfo). The
+// private key was thrown away: the tickets are never decrypted.
+const PUBKEY: [u8; SPICE_TICKET_PUBKEY_BYTES] = [
+    0x30, 0x81, 0x9f, 0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01,
+    0x05, 0x00, 0x03, 0x81, 0x8d, 0x00, 0x30, 0x81, 0x89, 0x02, 0x81, 0x81, 0x00, 0xe6, 0x35, 0xe2,
+    0x98, 0x16, 0x61, 0x50, 0x8e, 0x94, 0x08, 0xdb, 0xaa, 0x93, 0xbe, 0xc3, 0x59, 0xa4, 0x5d, 0x70,
+    0xfe, 0xaa, 0xef, 0x5a, 0xf0, 0xc4, 0xfb, 0x73, 0x40, 0xed, 0xdd, 0x9d, 0xdd, 0xed, 0xcf, 0x8a,
+    0x3c, 0xb8, 0x3d, 0x7e, 0xe3, 0x5e, 0x8c, 0xbe, 0xf4, 0x3b, 0x7a, 0xb8, 0x38, 0x8c, 0x33, 0x83,
+    0x70, 0x78, 0xe4, 0x36, 0x8e, 0x1b, 0x2a, 0xb8, 0x06, 0xec, 0x1e, 0x6c, 0x19, 0x5a, 0xca, 0xf0,
+    0x2c, 0x98, 0x66, 0x20, 0x90, 0xf7, 0x0f, 0x56, 0x9d, 0x79, 0xb5, 0x48, 0xf7, 0xdc, 0xf0, 0x7a,
+    0x5e, 0xd1, 0xc9, 0x9c, 0x15, 0x47, 0xa5, 0x53, 0x07, 0x4a, 0x1f, 0xab, 0xdc, 0x32, 0x0c, 0x19,
+    0xa7, 0xc8, 0x81, 0xb2, 0xe0, 0x61, 0xec, 0x25, 0x66, 0xf2, 0xef, 0x5f, 0x5c, 0x6e, 0x59, 0xa8,
+    0x65, 0x96, 0xe5, 0xde, 0xa5, 0x93, 0x44, 0x53, 0x03, 0xac, 0xa8, 0xb7, 0xc3, 0x02, 0x03, 0x01,
+    0x00, 0x01,
+];
+
+// the qnum of the Pause key, sent by the clients as a 0xe1 sequence
+const QNUM_PAUSE: u32 = 0xc6;
+
+/// The input of a console, served to the SPICE clients (spice-gtk, remote-viewer).
+///
+/// Only the main and inputs channels are implemented: the clients drive the keyboard and
+/// mouse, without a display. The link tickets aren't checked and the connections aren't
+/// encrypted, so only trusted clients should be able to connect, on a local or unix socket.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct SpiceInputs {
+    #[derivative(Debug = "ignore")]
+    keyboard: KeyboardProxy<'static>,
+    #[derivative(Debug = "ignore")]
+    mouse: MouseProxy<'static>,
+    sessions: AtomicU32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Link {
+    connection_id: u32,
+    channel_type: u8,
+    channel_id: u8,
+}
+
+impl SpiceInputs {
+    pub fn new(console: &Console) -> Self {
+        Self {
+            keyboard: console.keyboard.clone(),
+            mouse: console.mouse.clone(),
+            sessions: AtomicU32::new(0),
+        }
+    }
+
+    /// Serve a connection of a client, until it is closed.
+    ///
+    /// A client connects each of its channels: the main channel, then the inputs channel
+    /// it is told about.
+    pub async fn serve<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (mut reader, mut writer) = stream.split();
+        let link = match link(&mut reader, &mut writer).await? {
+            Some(link) => link,
+            None => return Ok(()),
+        };
+        log::debug!(
+            "SPICE channel {}:{} linked, session {}",
+            link.channel_type,
+            link.channel_id,
+            link.connection_id
+        );
+        let writer = Mutex::new(Writer {
+            stream: writer,
+            serial: 0,
+        });
+        match link.channel_type {
+            SPICE_CHANNEL_MAIN => self.main_channel(reader, writer).await,
+            _ => self.inputs_channel(reader, writer).await,
+        }
+    }
+
+    async fn mouse_mode(&self) -> Result<u16> {
+        Ok(if self.mouse.is_absolute().await? {
+            SPICE_MOUSE_MODE_CLIENT
+        } else {
+            SPICE_MOUSE_MODE_SERVER
+        })
+    }
+
+    async fn main_channel<R, W>(&self, mut reader: R, writer: Mutex<Writer<W>>) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let session_id = self.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+        let supported_modes = SPICE_MOUSE_MODE_SERVER | SPICE_MOUSE_MODE_CLIENT;
+        let mut init = vec![];
+        init.extend(session_id.to_le_bytes());
+        // no display channel
+        init.extend(0u32.to_le_bytes());
+        init.extend((supported_modes as u32).to_le_bytes());
+        init.extend((self.mouse_mode().await? as u32).to_le_bytes());
+        // agent_connected, agent_tokens, multi_media_time, ram_hint
+        init.extend([0; 16]);
+        writer.lock().await.send(SPICE_MSG_MAIN_INIT, &init).await?;
+
+        let mouse_mode = |mode: u16| [supported_modes.to_le_bytes(), mode.to_le_bytes()].concat();
+        let messages = async {
+            while let Some((kind, _)) = read_message(&mut reader).await? {
+                match kind {
+                    SPICE_MSGC_MAIN_ATTACH_CHANNELS => {
+                        let mut list = vec![];
+                        list.extend(1u32.to_le_bytes());
+                        list.extend([SPICE_CHANNEL_INPUTS, 0]);
+                        let mut writer = writer.lock().await;
+                        writer.send(SPICE_MSG_MAIN_CHANNELS_LIST, &list).await?;
+                    }
+                    // the guest decides, with its absolute or relative device
+                    SPICE_MSGC_MAIN_MOUSE_MODE_REQUEST => {
+                        let mode = mouse_mode(self.mouse_mode().await?);
+                        writer
+                            .lock()
+                            .await
+                            .send(SPICE_MSG_MAIN_MOUSE_MODE, &mode)
+                            .await?;
+                    }
+                    _ => log::debug!("Ignored SPICE main message {}", kind),
+                }
+            }
+            Ok(())
+        };
+        let modes = async {
+            let mut changes = self.mouse.receive_is_absolute_changed().await;
+            while let Some(change) = changes.next().await {
+                let mode = if change.get().await? {
+                    SPICE_MOUSE_MODE_CLIENT
+                } else {
+                    SPICE_MOUSE_MODE_SERVER
+                };
+                let mode = mouse_mode(mode);
+                writer
+                    .lock()
+                    .await
+                    .send(SPICE_MSG_MAIN_MOUSE_MODE, &mode)
+                    .await?;
+            }
+            Ok(())
+        };
+        let (res, _) = future::select(Box::pin(messages), Box::pin(modes))
+            .await
+            .factor_first();
+        res
+    }
+
+    async fn inputs_channel<R, W>(&self, mut reader: R, writer: Mutex<Writer<W>>) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let tracker = ModifierTracker::new(&self.keyboard).await?;
+        let modifiers = (tracker.guest().bits() as u16).to_le_bytes();
+        writer
+            .lock()
+            .await
+            .send(SPICE_MSG_INPUTS_INIT, &modifiers)
+            .await?;
+
+        let messages = async {
+            let mut motions = 0;
+            while let Some((kind, body)) = read_message(&mut reader).await? {
+                match kind {
+                    SPICE_MSGC_INPUTS_KEY_DOWN | SPICE_MSGC_INPUTS_KEY_UP => {
+                        let code = u32::from_le_bytes(field(&body, 0)?);
+                        match scancode_qnum(code) {
+                            Some(qnum) if kind == SPICE_MSGC_INPUTS_KEY_DOWN => {
+                                self.keyboard.press(qnum).await?
+                            }
+                            Some(qnum) => self.keyboard.release(qnum).await?,
+                            None => log::debug!("Ignored SPICE key code {:#x}", code),
+                        }
+                    }
+                    SPICE_MSGC_INPUTS_KEY_MODIFIERS => {
+                        let modifiers = u16::from_le_bytes(field(&body, 0)?);
+                        let host = BitFlags::from_bits_truncate(modifiers as u32);
+                        tracker.sync(host, BitFlags::all()).await?;
+                    }
+                    SPICE_MSGC_INPUTS_MOUSE_MOTION | SPICE_MSGC_INPUTS_MOUSE_POSITION => {
+                        let x = field(&body, 0)?;
+                        let y = field(&body, 4)?;
+                        if kind == SPICE_MSGC_INPUTS_MOUSE_MOTION {
+                            let (dx, dy) = (i32::from_le_bytes(x), i32::from_le_bytes(y));
+                            self.mouse.rel_motion(dx, dy).await?;
+                        } else {
+                            let (x, y) = (u32::from_le_bytes(x), u32::from_le_bytes(y));
+                            self.mouse.set_abs_position(x, y).await?;
+                        }
+                        motions += 1;
+                        if motions % SPICE_INPUT_MOTION_ACK_BUNCH == 0 {
+                            let mut writer = writer.lock().await;
+                            writer.send(SPICE_MSG_INPUTS_MOUSE_MOTION_ACK, &[]).await?;
+                        }
+                    }
+                    SPICE_MSGC_INPUTS_MOUSE_PRESS | SPICE_MSGC_INPUTS_MOUSE_RELEASE => {
+                        let [button] = field(&body, 0)?;
+                        match mouse_button(button) {
+                            Some(b) if kind == SPICE_MSGC_INPUTS_MOUSE_PRESS => {
+                                self.mouse.press(b).await?
+                            }
+                            Some(b) => self.mouse.release(b).await?,
+                            None => log::debug!("Ignored SPICE mouse button {}", button),
+                        }
+                    }
+                    _ => log::debug!("Ignored SPICE inputs message {}", kind),
+                }
+            }
+            Ok(())
+        };
+        let leds = async {
+            let mut changes = self.keyboard.receive_modifiers_changed().await;
+            while let Some(change) = changes.next().await {
+                let modifiers = (change.get().await?.bits() as u16).to_le_bytes();
+                let mut writer = writer.lock().await;
+                writer
+                    .send(SPICE_MSG_INPUTS_KEY_MODIFIERS, &modifiers)
+                    .await?;
+            }
+            Ok(())
+        };
+        let (res, _) = future::select(Box::pin(messages), Box::pin(leds))
+            .await
+            .factor_first();
+        res
+    }
+}
+
+struct Writer<W> {
+    stream: W,
+    serial: u64,
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    async fn send(&mut self, kind: u16, body: &[u8]) -> Result<()> {
+        self.serial += 1;
+        let mut msg = Vec::with_capacity(DATA_HEADER_SIZE + body.len());
+        msg.extend(self.serial.to_le_bytes());
+        msg.extend(kind.to_le_bytes());
+        msg.extend((body.len() as u32).to_le_bytes());
+        // sub_list
+        msg.extend(0u32.to_le_bytes());
+        msg.extend(body);
+        self.stream.write_all(&msg).await?;
+        Ok(())
+    }
+}
+
+fn field<const N: usize>(body: &[u8], offset: usize) -> Result<[u8; N]> {
+    body.get(offset..offset + N)
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| Error::protocol("Truncated SPICE message"))
+}
+
+// None when the client closed the connection
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<(u16, Vec<u8>)>> {
+    let mut header = [0; DATA_HEADER_SIZE];
+    match reader.read_exact(&mut header).await {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let kind = u16::from_le_bytes(field(&header, 8)?);
+    let size = u32::from_le_bytes(field(&header, 10)?) as usize;
+    if size > MAX_MESSAGE_SIZE {
+        return Err(Error::protocol(format!(
+            "SPICE message too large: {}",
+            size
+        )));
+    }
+    let mut body = vec![0; size];
+    reader.read_exact(&mut body).await?;
+    Ok(Some((kind, body)))
+}
+
+// The link handshake, accepting any ticket. None when the client closed the connection.
+async fn link<R, W>(reader: &mut R, writer: &mut W) -> Result<Option<Link>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut header = [0; LINK_HEADER_SIZE];
+    match reader.read_exact(&mut header).await {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    if u32::from_le_bytes(field(&header, 0)?) != SPICE_MAGIC {
+        return Err(Error::protocol("Invalid SPICE link magic"));
+    }
+    let major = u32::from_le_bytes(field(&header, 4)?);
+    let size = u32::from_le_bytes(field(&header, 12)?) as usize;
+    if size > MAX_MESSAGE_SIZE {
+        return Err(Error::protocol(format!("SPICE link too large: {}", size)));
+    }
+    let mut mess = vec![0; size];
+    reader.read_exact(&mut mess).await?;
+
+    let error = if major != SPICE_VERSION_MAJOR {
+        SPICE_LINK_ERR_VERSION_MISMATCH
+    } else if size < LINK_MESS_SIZE {
+        SPICE_LINK_ERR_INVALID_DATA
+    } else if !matches!(mess[4], SPICE_CHANNEL_MAIN | SPICE_CHANNEL_INPUTS) || mess[5] != 0 {
+        SPICE_LINK_ERR_CHANNEL_NOT_AVAILABLE
+    } else {
+        SPICE_LINK_ERR_OK
+    };
+    let mut reply = vec![];
+    reply.extend(SPICE_MAGIC.to_le_bytes());
+    reply.extend(SPICE_VERSION_MAJOR.to_le_bytes());
+    reply.extend(SPICE_VERSION_MINOR.to_le_bytes());
+    reply.extend((LINK_REPLY_SIZE as u32).to_le_bytes());
+    reply.extend(error.to_le_bytes());
+    reply.extend(PUBKEY);
+    // no common or channel capabilities, at the end of the reply
+    reply.extend(0u32.to_le_bytes());
+    reply.extend(0u32.to_le_bytes());
+    reply.extend((LINK_REPLY_SIZE as u32).to_le_bytes());
+    writer.write_all(&reply).await?;
+    if error != SPICE_LINK_ERR_OK {
+        return Err(Error::protocol(format!("SPICE link error {}", error)));
+    }
+
+    // without the auth selection capability, the client sends its encrypted ticket
+    let mut ticket = [0; SPICE_TICKET_BYTES];
+    reader.read_exact(&mut ticket).await?;
+    writer.write_all(&SPICE_LINK_ERR_OK.to_le_bytes()).await?;
+    Ok(Some(Link {
+        connection_id: u32::from_le_bytes(field(&mess, 0)?),
+        channel_type: mess[4],
+        channel_id: mess[5],
+    }))
+}
+
+// The PC/XT scancodes of the key messages: the 0xe0 prefix of the extended keys comes in
+// the low byte, and the release bit is set in the key up messages.
+fn scancode_qnum(code: u32) -> Option<u32> {
+    match code.to_le_bytes() {
+        [0xe0, key, ..] if key & 0x7f != 0 => Some(0x80 | (key & 0x7f) as u32),
+        [0xe1, ..] => Some(QNUM_PAUSE),
+        [key, ..] if key & 0x7f != 0 && key != 0xe0 => Some((key & 0x7f) as u32),
+        _ => None,
+    }
+}
+
+fn mouse_button(button: u8) -> Option<MouseButton> {
+    Some(match button {
+        1 => MouseButton::Left,
+        2 => MouseButton::Middle,
+        3 => MouseButton::Right,
+        4 => MouseButton::WheelUp,
+        5 => MouseButton::WheelDown,
+        6 => MouseButton::Side,
+        7 => MouseButton::Extra,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+
+    #[test]
+    fn scancodes() {
+        // A
+        assert_eq!(scancode_qnum(0x1e), Some(0x1e));
+        assert_eq!(scancode_qnum(0x9e), Some(0x1e));
+        // right Ctrl
+        assert_eq!(scancode_qnum(0x1de0), Some(0x9d));
+        assert_eq!(scancode_qnum(0x9de0), Some(0x9d));
+        assert_eq!(scancode_qnum(0x451de1), Some(QNUM_PAUSE));
+        assert_eq!(scancode_qnum(0), None);
+        assert_eq!(mouse_button(4), Some(MouseButton::WheelUp));
+        assert_eq!(mouse_button(0), None);
+    }
+
+    #[test]
+    fn link_handshake() {
+        let mut client = vec![];
+        client.extend(SPICE_MAGIC.to_le_bytes());
+        client.extend(2u32.to_le_bytes());
+        client.extend(2u32.to_le_bytes());
+        client.extend((LINK_MESS_SIZE as u32 + 4).to_le_bytes());
+        client.extend(42u32.to_le_bytes());
+        client.extend([SPICE_CHANNEL_INPUTS, 0]);
+        // one common capability
+        client.extend(1u32.to_le_bytes());
+        client.extend(0u32.to_le_bytes());
+        client.extend((LINK_MESS_SIZE as u32).to_le_bytes());
+        client.extend(0xbu32.to_le_bytes());
+        client.extend([0; SPICE_TICKET_BYTES]);
+        let mut reader = Cursor::new(client);
+        let mut reply = vec![];
+
+        let linked = futures::executor::block_on(link(&mut reader, &mut reply)).unwrap();
+        assert_eq!(
+            linked,
+            Some(Link {
+                connection_id: 42,
+                channel_type: SPICE_CHANNEL_INPUTS,
+                channel_id: 0,
+            })
+        );
+        assert_eq!(reply.len(), LINK_HEADER_SIZE + LINK_REPLY_SIZE + 4);
+        assert_eq!(&reply[..4], b"REDQ");
+        assert_eq!(&reply[LINK_HEADER_SIZE..LINK_HEADER_SIZE + 4], &[0; 4]);
+        assert_eq!(&reply[reply.len() - 4..], &[0; 4]);
+
+        let mut reader = Cursor::new(vec![]);
+        assert_eq!(
+            futures::executor::block_on(link(&mut reader, &mut vec![])).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use async_io::Timer;
+use std::{
+    fs::{self, DirBuilder},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{Error, Result};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A unix socket of a remote host, forwarded locally by the OpenSSH client.
+///
+/// The `ssh` command runs until the tunnel is dropped. The authentication must not prompt on
+/// the terminal: use an agent, or keys without passphrase.
+///
+/// The D-Bus bus sockets can be forwarded, but not the QMP socket of
+/// [`Display::new_qmp`](crate::Display::new_qmp): the file descriptors it passes can't go
+/// through SSH.
+#[derive(Debug)]
+pub struct SshTunnel {
+    child: Child,
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+fn private_dir() -> Result<PathBuf> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "qemu-display-ssh-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+impl SshTunnel {
+    /// Forward the `remote` socket of `destination` (`[user@]host`, or an ssh config alias).
+    pub async fn new(destination: &str, remote: &Path) -> Result<Self> {
+        let dir = private_dir()?;
+        let path = dir.join("socket");
+        let child = Command::new("ssh")
+            .args([
+                "-N",
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ExitOnForwardFailure=yes",
+            ])
+            .arg("-L")
+            .arg(format!("{}:{}", path.display(), remote.display()))
+            .arg("--")
+            .arg(destination)
+            .stdin(Stdio::null())
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                let _ = fs::remove_dir(&dir);
+                return Err(e.into());
+            }
+        };
+        let mut tunnel = Self { child, dir, path };
+        tunnel.wait_ready().await?;
+        Ok(tunnel)
+    }
+
+    /// Forward the D-Bus session bus of the remote user.
+    pub async fn session_bus(destination: &str) -> Result<Self> {
+        let output = Command::new("ssh")
+            .args(["-o", "BatchMode=yes", "--", destination])
+            .arg("echo ${XDG_RUNTIME_DIR:-/run/user/$(id -u)}/bus")
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::Failed(format!(
+                "Failed to find the session bus of {}: {}",
+                destination,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let remote = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Self::new(destination, Path::new(&remote)).await
+    }
+
+    async fn wait_ready(&mut self) -> Result<()> {
+        let start = Instant::now();
+        while !self.path.exists() {
+            if let Some(status) = self.child.try_wait()? {
+                return Err(Error::Failed(format!("ssh exited with {}", status)));
+            }
+            if start.elapsed() > CONNECT_TIMEOUT {
+                return Err(Error::Failed("Timeout waiting for the ssh tunnel".into()));
+            }
+            Timer::after(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+
+    /// The local socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The D-Bus address of the local socket.
+    pub fn address(&self) -> String {
+        format!("unix:path={}", self.path.display())
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
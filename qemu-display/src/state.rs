@@ -0,0 +1,267 @@
+use async_broadcast::{broadcast, Receiver, Sender};
+use futures::{stream, Stream, StreamExt};
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+use zbus::Task;
+
+use crate::{ConsoleHealth, DisplayEvent};
+
+/// A part of a session that can fail on its own, the display staying usable.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+    /// The listener of a console, watched by a [`ConsoleWatchdog`](crate::ConsoleWatchdog).
+    Console(u32),
+    Audio,
+    Clipboard,
+    Usbredir,
+    /// A subsystem of the frontend.
+    Other(String),
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Console(id) => write!(f, "console {}", id),
+            Self::Audio => f.write_str("audio"),
+            Self::Clipboard => f.write_str("clipboard"),
+            Self::Usbredir => f.write_str("USB redirection"),
+            Self::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+/// The state of a [`Session`](crate::Session), see [`Session::receive_state`].
+///
+/// [`Session::receive_state`]: crate::Session::receive_state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionState {
+    /// The VM isn't identified yet.
+    Connecting,
+    /// The VM is connected, and all the subsystems are working.
+    Connected { vm: String },
+    /// The VM is connected, but a subsystem failed. The first failing one is reported.
+    Degraded {
+        subsystem: Subsystem,
+        reason: String,
+    },
+    /// The VM left the bus, and is looked up again.
+    Reconnecting,
+    /// The session is over.
+    Closed { reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Link {
+    Connecting,
+    Connected,
+    Reconnecting,
+    Closed(String),
+}
+
+#[derive(Debug)]
+struct Inner {
+    vm: String,
+    link: Link,
+    // in failure order
+    degraded: Vec<(Subsystem, String)>,
+    state: SessionState,
+}
+
+impl Inner {
+    fn compute(&self) -> SessionState {
+        match &self.link {
+            Link::Connecting => SessionState::Connecting,
+            Link::Reconnecting => SessionState::Reconnecting,
+            Link::Closed(reason) => SessionState::Closed {
+                reason: reason.clone(),
+            },
+            Link::Connected => match self.degraded.first() {
+                Some((subsystem, reason)) => SessionState::Degraded {
+                    subsystem: subsystem.clone(),
+                    reason: reason.clone(),
+                },
+                None => SessionState::Connected {
+                    vm: self.vm.clone(),
+                },
+            },
+        }
+    }
+}
+
+/// The state machine of a session, shared by its clones and its tasks.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub(crate) struct StateTracker {
+    inner: Mutex<Inner>,
+    #[derivative(Debug = "ignore")]
+    sender: Sender<SessionState>,
+    #[derivative(Debug = "ignore")]
+    receiver: Receiver<SessionState>,
+    #[derivative(Debug = "ignore")]
+    tasks: Mutex<Vec<Task<()>>>,
+}
+
+impl StateTracker {
+    pub(crate) fn new() -> Arc<Self> {
+        let (mut sender, receiver) = broadcast(8);
+        sender.set_overflow(true);
+        Arc::new(Self {
+            inner: Mutex::new(Inner {
+                vm: String::new(),
+                link: Link::Connecting,
+                degraded: vec![],
+                state: SessionState::Connecting,
+            }),
+            sender,
+            receiver,
+            tasks: Default::default(),
+        })
+    }
+
+    pub(crate) fn state(&self) -> SessionState {
+        self.inner.lock().unwrap().state.clone()
+    }
+
+    pub(crate) fn receive(&self) -> Pin<Box<dyn Stream<Item = SessionState> + Send>> {
+        // subscribe before reading the current state, to not miss a change
+        let receiver = self.receiver.clone();
+        let current = self.state();
+        Box::pin(stream::once(async { current }).chain(receiver))
+    }
+
+    pub(crate) fn add_task(&self, task: Task<()>) {
+        self.tasks.lock().unwrap().push(task);
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Inner)) {
+        let mut inner = self.inner.lock().unwrap();
+        if matches!(inner.link, Link::Closed(_)) {
+            return;
+        }
+        f(&mut inner);
+        let state = inner.compute();
+        if state == inner.state {
+            return;
+        }
+        log::debug!("Session state: {:?}", state);
+        inner.state = state.clone();
+        // with overflow, the oldest state is dropped instead of blocking
+        let _ = self.sender.try_broadcast(state);
+    }
+
+    pub(crate) fn connected(&self, vm: String) {
+        self.update(|inner| {
+            inner.vm = vm;
+            inner.link = Link::Connected;
+        });
+    }
+
+    pub(crate) fn degraded(&self, subsystem: Subsystem, reason: String) {
+        self.update(
+            |inner| match inner.degraded.iter_mut().find(|(s, _)| *s == subsystem) {
+                Some((_, r)) => *r = reason,
+                None => inner.degraded.push((subsystem, reason)),
+            },
+        );
+    }
+
+    pub(crate) fn recovered(&self, subsystem: &Subsystem) {
+        self.update(|inner| inner.degraded.retain(|(s, _)| s != subsystem));
+    }
+
+    pub(crate) fn closed(&self, reason: String) {
+        self.update(|inner| inner.link = Link::Closed(reason));
+    }
+
+    pub(crate) fn display_event(&self, event: &DisplayEvent) {
+        match event {
+            DisplayEvent::Disconnected => self.update(|inner| {
+                inner.link = Link::Reconnecting;
+                // the subsystems of the previous VM are gone with it
+                inner.degraded.clear();
+            }),
+            DisplayEvent::Reconnected(_) => self.update(|inner| inner.link = Link::Connected),
+            DisplayEvent::Failed(e) => self.closed(format!("Reconnection failed: {}", e)),
+        }
+    }
+
+    pub(crate) fn console_health(&self, console_id: u32, health: &ConsoleHealth) {
+        let subsystem = Subsystem::Console(console_id);
+        match health {
+            ConsoleHealth::Stalled => self.degraded(subsystem, "Listener stalled".into()),
+            ConsoleHealth::Failed(e) => self.degraded(subsystem, e.clone()),
+            ConsoleHealth::Recovered => self.recovered(&subsystem),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let t = StateTracker::new();
+        assert_eq!(t.state(), SessionState::Connecting);
+        t.connected("vm".into());
+        let connected = SessionState::Connected { vm: "vm".into() };
+        assert_eq!(t.state(), connected);
+
+        t.console_health(0, &ConsoleHealth::Stalled);
+        t.degraded(Subsystem::Audio, "gone".into());
+        assert_eq!(
+            t.state(),
+            SessionState::Degraded {
+                subsystem: Subsystem::Console(0),
+                reason: "Listener stalled".into()
+            }
+        );
+        t.console_health(0, &ConsoleHealth::Recovered);
+        assert!(matches!(
+            t.state(),
+            SessionState::Degraded {
+                subsystem: Subsystem::Audio,
+                ..
+            }
+        ));
+
+        t.display_event(&DisplayEvent::Disconnected);
+        assert_eq!(t.state(), SessionState::Reconnecting);
+        t.connected("vm".into());
+        assert_eq!(t.state(), connected);
+
+        t.display_event(&DisplayEvent::Failed("timeout".into()));
+        assert!(matches!(t.state(), SessionState::Closed { .. }));
+        t.connected("vm".into());
+        assert!(matches!(t.state(), SessionState::Closed { .. }));
+    }
+
+    #[test]
+    fn stream() {
+        let t = StateTracker::new();
+        let mut states = t.receive();
+        t.connected("vm".into());
+        t.connected("vm".into());
+        t.closed("bye".into());
+        let states: Vec<_> = futures::executor::block_on(async {
+            let mut v = vec![];
+            for _ in 0..3 {
+                v.push(states.next().await.unwrap());
+            }
+            v
+        });
+        assert_eq!(
+            states,
+            vec![
+                SessionState::Connecting,
+                SessionState::Connected { vm: "vm".into() },
+                SessionState::Closed {
+                    reason: "bye".into()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! The frame statistics of a console handler, to debug the performance of a frontend.
+
+use async_lock::Mutex;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[cfg(windows)]
+use crate::ScanoutMap;
+use crate::{ConsoleListenerHandler, Cursor, MouseSet, Scanout, Update, UpdateMap};
+#[cfg(unix)]
+use crate::{ScanoutDMABUF, ScanoutMapped, UpdateDMABUF};
+
+// the frames without data are measured as 32 bits pixels
+const BPP: u64 = 4;
+
+/// The frames handled since the statistics were created or reset.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub elapsed: Duration,
+    /// The scanouts, switching to a new frame.
+    pub scanouts: u64,
+    pub updates: u64,
+    /// The bytes of the scanouts and updates.
+    pub bytes: u64,
+    /// The bytes of the updates only.
+    pub update_bytes: u64,
+}
+
+impl FrameStats {
+    fn per_sec(&self, n: u64) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            n as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// The scanouts and updates per second.
+    pub fn frames_per_sec(&self) -> f64 {
+        self.per_sec(self.scanouts + self.updates)
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.per_sec(self.bytes)
+    }
+
+    /// The mean size of an update, in bytes.
+    pub fn mean_update_size(&self) -> f64 {
+        if self.updates > 0 {
+            self.update_bytes as f64 / self.updates as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Counters {
+    since: Instant,
+    scanouts: u64,
+    updates: u64,
+    bytes: u64,
+    update_bytes: u64,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            since: Instant::now(),
+            scanouts: 0,
+            updates: 0,
+            bytes: 0,
+            update_bytes: 0,
+        }
+    }
+}
+
+/// A collector of the frame statistics of console handlers.
+///
+/// The handlers are wrapped with [`ConsoleStats::wrap`], and the collector is cloned to
+/// read the statistics while they run.
+#[derive(Debug, Default, Clone)]
+pub struct ConsoleStats {
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl ConsoleStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wrap a handler, its frames are counted by this collector.
+    pub fn wrap<H: ConsoleListenerHandler>(&self, handler: H) -> StatsHandler<H> {
+        StatsHandler {
+            handler,
+            stats: self.clone(),
+        }
+    }
+
+    /// The frames handled since the collector was created or reset.
+    pub async fn stats(&self) -> FrameStats {
+        let counters = self.counters.lock().await;
+        FrameStats {
+            elapsed: counters.since.elapsed(),
+            scanouts: counters.scanouts,
+            updates: counters.updates,
+            bytes: counters.bytes,
+            update_bytes: counters.update_bytes,
+        }
+    }
+
+    /// Start counting again.
+    pub async fn reset(&self) {
+        *self.counters.lock().await = Counters::default();
+    }
+
+    async fn scanout(&self, bytes: u64) {
+        let mut counters = self.counters.lock().await;
+        counters.scanouts += 1;
+        counters.bytes += bytes;
+    }
+
+    async fn update(&self, bytes: u64) {
+        let mut counters = self.counters.lock().await;
+        counters.updates += 1;
+        counters.bytes += bytes;
+        counters.update_bytes += bytes;
+    }
+}
+
+fn region_bytes(w: i32, h: i32) -> u64 {
+    w.max(0) as u64 * h.max(0) as u64 * BPP
+}
+
+/// A console handler counting its frames in a [`ConsoleStats`].
+#[derive(Debug)]
+pub struct StatsHandler<H: ConsoleListenerHandler> {
+    handler: H,
+    stats: ConsoleStats,
+}
+
+impl<H: ConsoleListenerHandler> StatsHandler<H> {
+    pub fn into_inner(self) -> H {
+        self.handler
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: ConsoleListenerHandler> ConsoleListenerHandler for StatsHandler<H> {
+    async fn scanout(&mut self, scanout: Scanout) {
+        self.stats.scanout(scanout.data.len() as u64).await;
+        self.handler.scanout(scanout).await
+    }
+
+    async fn update(&mut self, update: Update) {
+        self.stats.update(update.data.len() as u64).await;
+        self.handler.update(update).await
+    }
+
+    #[cfg(windows)]
+    async fn scanout_map(&mut self, scanout: ScanoutMap) {
+        self.stats
+            .scanout(scanout.stride as u64 * scanout.height as u64)
+            .await;
+        self.handler.scanout_map(scanout).await
+    }
+
+    #[cfg(windows)]
+    async fn update_map(&mut self, update: UpdateMap) {
+        self.stats.update(region_bytes(update.w, update.h)).await;
+        self.handler.update_map(update).await
+    }
+
+    #[cfg(unix)]
+    async fn scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        self.stats
+            .scanout(scanout.stride as u64 * scanout.height as u64)
+            .await;
+        self.handler.scanout_dmabuf(scanout).await
+    }
+
+    #[cfg(unix)]
+    async fn update_dmabuf(&mut self, update: UpdateDMABUF) {
+        self.stats.update(region_bytes(update.w, update.h)).await;
+        self.handler.update_dmabuf(update).await
+    }
+
+    #[cfg(unix)]
+    async fn scanout_mapped(&mut self, scanout: ScanoutMapped) {
+        self.stats
+            .scanout(scanout.stride as u64 * scanout.height as u64)
+            .await;
+        self.handler.scanout_mapped(scanout).await
+    }
+
+    #[cfg(unix)]
+    async fn update_mapped(&mut self, scanout: &ScanoutMapped, update: UpdateMap) {
+        self.stats.update(region_bytes(update.w, update.h)).await;
+        self.handler.update_mapped(scanout, update).await
+    }
+
+    async fn mouse_set(&mut self, set: MouseSet) {
+        self.handler.mouse_set(set).await
+    }
+
+    async fn cursor_define(&mut self, cursor: Cursor) {
+        self.handler.cursor_define(cursor).await
+    }
+
+    fn disable(&mut self) {
+        self.handler.disable()
+    }
+
+    fn disconnected(&mut self) {
+        self.handler.disconnected()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Handler {
+        updates: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl ConsoleListenerHandler for Handler {
+        async fn scanout(&mut self, _scanout: Scanout) {}
+
+        async fn update(&mut self, _update: Update) {
+            self.updates += 1;
+        }
+
+        #[cfg(windows)]
+        async fn scanout_map(&mut self, _scanout: ScanoutMap) {}
+
+        #[cfg(windows)]
+        async fn update_map(&mut self, _update: UpdateMap) {}
+
+        #[cfg(unix)]
+        async fn scanout_dmabuf(&mut self, _scanout: ScanoutDMABUF) {}
+
+        #[cfg(unix)]
+        async fn update_dmabuf(&mut self, _update: UpdateDMABUF) {}
+
+        async fn mouse_set(&mut self, _set: MouseSet) {}
+
+        async fn cursor_define(&mut self, _cursor: Cursor) {}
+
+        fn disconnected(&mut self) {}
+    }
+
+    fn update(w: i32, h: i32) -> Update {
+        Update {
+            x: 0,
+            y: 0,
+            w,
+            h,
+            stride: w as u32 * 4,
+            format: 0x20020888,
+            data: vec![0; (w * h * 4) as usize],
+        }
+    }
+
+    #[test]
+    fn stats() {
+        async_io::block_on(async {
+            let stats = ConsoleStats::new();
+            let mut handler = stats.wrap(Handler::default());
+            handler
+                .scanout(Scanout {
+                    width: 4,
+                    height: 4,
+                    stride: 16,
+                    format: 0x20020888,
+                    data: vec![0; 64],
+                })
+                .await;
+            handler.update(update(1, 1)).await;
+            handler.update(update(2, 2)).await;
+            assert_eq!(handler.handler.updates, 2);
+
+            let s = stats.stats().await;
+            assert_eq!((s.scanouts, s.updates, s.bytes), (1, 2, 84));
+            assert_eq!(s.mean_update_size(), 10.0);
+            assert!(s.frames_per_sec() > 0.0);
+
+            stats.reset().await;
+            let s = stats.stats().await;
+            assert_eq!((s.scanouts, s.updates, s.bytes), (0, 0, 0));
+            assert_eq!(s.mean_update_size(), 0.0);
+        })
+    }
+}
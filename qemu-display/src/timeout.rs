@@ -0,0 +1,175 @@
+use async_broadcast::{broadcast, InactiveReceiver, Sender};
+use async_io::Timer;
+use futures::{future, pin_mut};
+use std::{
+    fmt,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{Error, Result};
+
+// in milliseconds, NO_TIMEOUT to wait forever
+static DEFAULT_TIMEOUT: AtomicU64 = AtomicU64::new(25_000);
+const NO_TIMEOUT: u64 = u64::MAX;
+
+/// The timeout of the calls of the displays and consoles created from now on, 25 seconds
+/// like the D-Bus default.
+///
+/// `None` waits forever. A [`Display`](crate::Display) or [`Console`](crate::Console) can
+/// have its own, with `with_timeout()`.
+pub fn set_default_timeout(timeout: Option<Duration>) {
+    let ms = timeout.map_or(NO_TIMEOUT, |t| {
+        t.as_millis().min(NO_TIMEOUT as u128 - 1) as u64
+    });
+    DEFAULT_TIMEOUT.store(ms, Ordering::Relaxed);
+}
+
+/// See [`set_default_timeout`].
+pub fn default_timeout() -> Option<Duration> {
+    match DEFAULT_TIMEOUT.load(Ordering::Relaxed) {
+        NO_TIMEOUT => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// A token to cancel the pending calls of the displays and consoles holding it.
+///
+/// The calls fail with [`Error::Cancelled`], once the token (or any of its clones) is
+/// cancelled. A cancelled token can't be reset.
+#[derive(Clone)]
+pub struct CancellationToken {
+    // closed on cancellation
+    sender: Sender<()>,
+    receiver: InactiveReceiver<()>,
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, receiver) = broadcast(1);
+        Self {
+            sender,
+            receiver: receiver.deactivate(),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.sender.close();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Resolves once the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.activate_cloned();
+        // nothing is ever sent, recv() fails when the channel is closed
+        while receiver.recv().await.is_ok() {}
+    }
+}
+
+/// The timeout and cancellation of the calls of a display or console.
+#[derive(Debug, Clone)]
+pub(crate) struct CallOptions {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) cancel: Option<CancellationToken>,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        Self {
+            timeout: default_timeout(),
+            cancel: None,
+        }
+    }
+}
+
+impl CallOptions {
+    /// Run `fut`, failing with [`Error::Timeout`] or [`Error::Cancelled`].
+    pub(crate) async fn call<T, F>(&self, what: &'static str, fut: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let timeout = self.timeout;
+        let cancel = self.cancel.as_ref();
+        if cancel.is_some_and(|c| c.is_cancelled()) {
+            return Err(Error::Cancelled);
+        }
+        let expired = async {
+            match timeout {
+                Some(timeout) => {
+                    Timer::after(timeout).await;
+                    Error::Timeout { what, timeout }
+                }
+                None => future::pending().await,
+            }
+        };
+        let cancelled = async {
+            match cancel {
+                Some(cancel) => {
+                    cancel.cancelled().await;
+                    Error::Cancelled
+                }
+                None => future::pending().await,
+            }
+        };
+        pin_mut!(fut, expired, cancelled);
+        match future::select(fut, future::select(expired, cancelled)).await {
+            future::Either::Left((res, _)) => res,
+            future::Either::Right((aborted, _)) => Err(aborted.factor_first().0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call() {
+        async_io::block_on(async {
+            let mut opts = CallOptions {
+                timeout: Some(Duration::from_millis(10)),
+                cancel: None,
+            };
+            assert_eq!(opts.call("ready", async { Ok(1) }).await.unwrap(), 1);
+            let err = opts
+                .call("stall", future::pending::<Result<()>>())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Timeout { what: "stall", .. }));
+
+            let token = CancellationToken::new();
+            opts.timeout = None;
+            opts.cancel = Some(token.clone());
+            let stall = opts.call("stall", future::pending::<Result<()>>());
+            let cancel = async {
+                Timer::after(Duration::from_millis(10)).await;
+                token.cancel();
+            };
+            let (res, _) = future::join(stall, cancel).await;
+            assert!(matches!(res, Err(Error::Cancelled)));
+            assert!(token.is_cancelled());
+            assert!(matches!(
+                opts.call("ready", async { Ok(()) }).await,
+                Err(Error::Cancelled)
+            ));
+        })
+    }
+}
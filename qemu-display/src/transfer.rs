@@ -0,0 +1,271 @@
+use async_lock::Mutex;
+use futures::io::{AllowStdIo, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use std::{convert::TryInto, fs::File, path::Path, sync::Arc};
+
+use crate::{Chardev, ChardevConnection, Error, Result};
+
+/// The name of the chardev connected to the SPICE agent port of the guest.
+pub const VDAGENT_CHARDEV_NAME: &str = "com.redhat.spice.0";
+
+// from spice-protocol vd_agent.h
+const VDP_CLIENT_PORT: u32 = 1;
+const VD_AGENT_PROTOCOL: u32 = 1;
+const VD_AGENT_MAX_DATA_SIZE: usize = 2048;
+const VD_AGENT_FILE_XFER_START: u32 = 11;
+const VD_AGENT_FILE_XFER_STATUS: u32 = 12;
+const VD_AGENT_FILE_XFER_DATA: u32 = 13;
+
+const CHUNK_HEADER_SIZE: usize = 8;
+const MESSAGE_HEADER_SIZE: usize = 20;
+// the largest message accepted from the guest, the others are only clipboard data
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+// as spice-gtk, the agent handles the data messages of this size
+const FILE_XFER_DATA_SIZE: usize = VD_AGENT_MAX_DATA_SIZE * 32;
+
+/// The status of a file transfer, as reported by the guest agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    CanSendData,
+    Cancelled,
+    Error,
+    Success,
+    NotEnoughSpace,
+    SessionLocked,
+    AgentNotConnected,
+    Disabled,
+    Unknown(u32),
+}
+
+impl From<u32> for TransferStatus {
+    fn from(v: u32) -> Self {
+        match v {
+            0 => Self::CanSendData,
+            1 => Self::Cancelled,
+            2 => Self::Error,
+            3 => Self::Success,
+            4 => Self::NotEnoughSpace,
+            5 => Self::SessionLocked,
+            6 => Self::AgentNotConnected,
+            7 => Self::Disabled,
+            v => Self::Unknown(v),
+        }
+    }
+}
+
+impl From<TransferStatus> for u32 {
+    fn from(s: TransferStatus) -> Self {
+        match s {
+            TransferStatus::CanSendData => 0,
+            TransferStatus::Cancelled => 1,
+            TransferStatus::Error => 2,
+            TransferStatus::Success => 3,
+            TransferStatus::NotEnoughSpace => 4,
+            TransferStatus::SessionLocked => 5,
+            TransferStatus::AgentNotConnected => 6,
+            TransferStatus::Disabled => 7,
+            TransferStatus::Unknown(v) => v,
+        }
+    }
+}
+
+/// The progress of a file transfer, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub sent: u64,
+    pub total: u64,
+}
+
+/// File transfer to the guest, through the SPICE agent (spice-vdagent).
+///
+/// The frontends share the channel: it can be cloned, and the transfers are sent one after
+/// the other.
+#[derive(Debug, Clone)]
+pub struct FileTransfer {
+    agent: Arc<Mutex<Agent>>,
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct Agent {
+    #[derivative(Debug = "ignore")]
+    conn: ChardevConnection,
+    next_id: u32,
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+// a GKeyFile string value
+fn escape_value(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Agent {
+    async fn write_message(&mut self, type_: u32, data: &[u8]) -> Result<()> {
+        let mut msg = Vec::with_capacity(MESSAGE_HEADER_SIZE + data.len());
+        msg.extend_from_slice(&VD_AGENT_PROTOCOL.to_le_bytes());
+        msg.extend_from_slice(&type_.to_le_bytes());
+        msg.extend_from_slice(&0u64.to_le_bytes());
+        msg.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        msg.extend_from_slice(data);
+        for chunk in msg.chunks(VD_AGENT_MAX_DATA_SIZE) {
+            let mut buf = Vec::with_capacity(CHUNK_HEADER_SIZE + chunk.len());
+            buf.extend_from_slice(&VDP_CLIENT_PORT.to_le_bytes());
+            buf.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            buf.extend_from_slice(chunk);
+            self.conn.write_all(&buf).await?;
+        }
+        Ok(())
+    }
+
+    // a message type and its data, reassembled from the chunks
+    async fn read_message(&mut self) -> Result<(u32, Vec<u8>)> {
+        let mut msg = Vec::new();
+        loop {
+            let mut header = [0; CHUNK_HEADER_SIZE];
+            self.conn.read_exact(&mut header).await?;
+            let size = u32_at(&header, 4) as usize;
+            if size > VD_AGENT_MAX_DATA_SIZE {
+                return Err(Error::Failed(format!("Agent chunk too large: {}", size)));
+            }
+            let mut chunk = [0; VD_AGENT_MAX_DATA_SIZE];
+            self.conn.read_exact(&mut chunk[..size]).await?;
+            let port = u32_at(&header, 0);
+            if port != VDP_CLIENT_PORT {
+                log::debug!("Ignoring agent chunk for port {}", port);
+                continue;
+            }
+            msg.extend_from_slice(&chunk[..size]);
+            if msg.len() < MESSAGE_HEADER_SIZE {
+                continue;
+            }
+            let len = u32_at(&msg, 16) as usize;
+            if len > MAX_MESSAGE_SIZE {
+                return Err(Error::Failed(format!("Agent message too large: {}", len)));
+            }
+            if msg.len() >= MESSAGE_HEADER_SIZE + len {
+                let type_ = u32_at(&msg, 4);
+                msg.truncate(MESSAGE_HEADER_SIZE + len);
+                msg.drain(..MESSAGE_HEADER_SIZE);
+                return Ok((type_, msg));
+            }
+        }
+    }
+
+    async fn write_status(&mut self, id: u32, status: TransferStatus) -> Result<()> {
+        let mut data = id.to_le_bytes().to_vec();
+        data.extend_from_slice(&u32::from(status).to_le_bytes());
+        self.write_message(VD_AGENT_FILE_XFER_STATUS, &data).await
+    }
+
+    // the other agent messages are ignored
+    async fn read_status(&mut self, id: u32) -> Result<TransferStatus> {
+        loop {
+            let (type_, data) = self.read_message().await?;
+            if type_ == VD_AGENT_FILE_XFER_STATUS && data.len() >= 8 && u32_at(&data, 0) == id {
+                return Ok(u32_at(&data, 4).into());
+            }
+            log::debug!("Ignoring agent message {}", type_);
+        }
+    }
+
+    async fn expect_status(&mut self, id: u32, expected: TransferStatus) -> Result<()> {
+        match self.read_status(id).await? {
+            status if status == expected => Ok(()),
+            status => Err(Error::Failed(format!("File transfer failed: {:?}", status))),
+        }
+    }
+}
+
+impl FileTransfer {
+    /// Connect to the agent chardev, see [`VDAGENT_CHARDEV_NAME`].
+    pub async fn new(chardev: &Chardev) -> Result<Self> {
+        let conn = chardev.connect().await?;
+        Ok(Self {
+            agent: Arc::new(Mutex::new(Agent { conn, next_id: 0 })),
+        })
+    }
+
+    /// Send `size` bytes from `reader`, as a file named `name` on the guest.
+    ///
+    /// The guest agent saves the file in its download directory. `progress` is called as
+    /// the data is sent, and the transfer is cancelled if reading fails.
+    pub async fn send<R, F>(
+        &self,
+        name: &str,
+        size: u64,
+        mut reader: R,
+        mut progress: F,
+    ) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(TransferProgress),
+    {
+        let mut agent = self.agent.lock().await;
+        let id = agent.next_id;
+        agent.next_id = agent.next_id.wrapping_add(1);
+
+        let mut start = id.to_le_bytes().to_vec();
+        let info = format!(
+            "[vdagent-file-xfer]\nname={}\nsize={}\n\0",
+            escape_value(name),
+            size
+        );
+        start.extend_from_slice(info.as_bytes());
+        agent
+            .write_message(VD_AGENT_FILE_XFER_START, &start)
+            .await?;
+        agent.expect_status(id, TransferStatus::CanSendData).await?;
+
+        let mut buf = vec![0; FILE_XFER_DATA_SIZE];
+        let mut sent = 0;
+        progress(TransferProgress { sent, total: size });
+        while sent < size {
+            let len = buf.len().min((size - sent) as usize);
+            let n = match reader.read(&mut buf[..len]).await {
+                Ok(n) if n > 0 => n,
+                res => {
+                    agent.write_status(id, TransferStatus::Cancelled).await?;
+                    return Err(match res {
+                        Err(e) => e.into(),
+                        Ok(_) => Error::Failed("Unexpected end of file".into()),
+                    });
+                }
+            };
+            let mut data = Vec::with_capacity(12 + n);
+            data.extend_from_slice(&id.to_le_bytes());
+            data.extend_from_slice(&(n as u64).to_le_bytes());
+            data.extend_from_slice(&buf[..n]);
+            agent.write_message(VD_AGENT_FILE_XFER_DATA, &data).await?;
+            sent += n as u64;
+            progress(TransferProgress { sent, total: size });
+        }
+        agent.expect_status(id, TransferStatus::Success).await
+    }
+
+    /// Send a local file, keeping its name.
+    pub async fn send_file<F>(&self, path: &Path, progress: F) -> Result<()>
+    where
+        F: FnMut(TransferProgress),
+    {
+        let name = path
+            .file_name()
+            .ok_or_else(|| Error::Failed(format!("Invalid file path: {}", path.display())))?
+            .to_string_lossy();
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        self.send(&name, size, AllowStdIo::new(file), progress)
+            .await
+    }
+}
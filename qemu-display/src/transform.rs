@@ -0,0 +1,411 @@
+use crate::Rect;
+
+/// A clockwise rotation of the guest display in the view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    None,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    fn swaps_axes(self) -> bool {
+        matches!(self, Self::Rotate90 | Self::Rotate270)
+    }
+}
+
+/// How the guest display is scaled in the view.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ScaleMode {
+    /// The largest size fitting the view, keeping the aspect ratio, centered with borders
+    /// (letterboxing).
+    #[default]
+    Fit,
+    /// The view size, without borders.
+    Stretch,
+    /// A fixed factor, from the top-left corner of the view.
+    Factor(f64),
+}
+
+/// The mapping between the view of a frontend and the guest display.
+///
+/// The guest display is cropped (optionally), rotated, then scaled and positioned in the
+/// view. The positions go through the same pipeline both ways: the view positions of the
+/// input events are mapped to the guest, and the guest positions (of the cursor) to the
+/// view. Pixel positions are mapped from pixel center to pixel center.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewTransform {
+    guest: (u32, u32),
+    view: (u32, u32),
+    crop: Option<Rect>,
+    rotation: Rotation,
+    mode: ScaleMode,
+}
+
+impl ViewTransform {
+    /// The transform of a guest display of `guest` size, fitted in a view of `view` size.
+    pub fn new(guest: (u32, u32), view: (u32, u32)) -> Self {
+        Self {
+            guest,
+            view,
+            crop: None,
+            rotation: Rotation::None,
+            mode: ScaleMode::Fit,
+        }
+    }
+
+    pub fn with_scale_mode(mut self, mode: ScaleMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Only show the `crop` region of the guest display, clipped to the display.
+    pub fn with_crop(mut self, crop: Rect) -> Self {
+        self.crop = Some(crop);
+        self
+    }
+
+    // the shown region of the guest display
+    fn source(&self) -> Rect {
+        let display = Rect::new(0, 0, self.guest.0, self.guest.1);
+        match self.crop {
+            Some(crop) => crop.intersect(&display),
+            None => display,
+        }
+    }
+
+    /// The size of the shown region, once rotated.
+    pub fn content_size(&self) -> (u32, u32) {
+        let src = self.source();
+        if self.rotation.swaps_axes() {
+            (src.height, src.width)
+        } else {
+            (src.width, src.height)
+        }
+    }
+
+    /// The horizontal and vertical scale factors, from the rotated content to the view.
+    pub fn scale(&self) -> (f64, f64) {
+        let (cw, ch) = self.content_size();
+        if cw == 0 || ch == 0 {
+            return (0.0, 0.0);
+        }
+        let (sx, sy) = (
+            self.view.0 as f64 / cw as f64,
+            self.view.1 as f64 / ch as f64,
+        );
+        match self.mode {
+            ScaleMode::Fit => {
+                let s = sx.min(sy);
+                (s, s)
+            }
+            ScaleMode::Stretch => (sx, sy),
+            ScaleMode::Factor(f) => (f, f),
+        }
+    }
+
+    // the top-left corner of the content in the view
+    fn offset(&self) -> (f64, f64) {
+        match self.mode {
+            ScaleMode::Fit => {
+                let (cw, ch) = self.content_size();
+                let (s, _) = self.scale();
+                (
+                    (self.view.0 as f64 - cw as f64 * s) / 2.0,
+                    (self.view.1 as f64 - ch as f64 * s) / 2.0,
+                )
+            }
+            ScaleMode::Stretch | ScaleMode::Factor(_) => (0.0, 0.0),
+        }
+    }
+
+    /// The view pixels showing the guest display (their center is on it), the rest is
+    /// borders.
+    pub fn viewport(&self) -> Rect {
+        let (cw, ch) = self.content_size();
+        let (sx, sy) = self.scale();
+        let (ox, oy) = self.offset();
+        let span = |start: f64, len: f64, view: u32| {
+            let first = (start - 0.5).ceil().max(0.0) as u32;
+            let end = ((start + len - 0.5).ceil().max(0.0) as u32).min(view);
+            (first.min(end), end)
+        };
+        let (x0, x1) = span(ox, cw as f64 * sx, self.view.0);
+        let (y0, y1) = span(oy, ch as f64 * sy, self.view.1);
+        Rect::new(x0, y0, x1 - x0, y1 - y0)
+    }
+
+    // a guest pixel to a pixel of the rotated content
+    fn content_pixel(&self, x: i64, y: i64) -> (i64, i64) {
+        let src = self.source();
+        let (w, h) = (src.width as i64, src.height as i64);
+        let (x, y) = (x - src.x as i64, y - src.y as i64);
+        match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Rotate90 => (h - 1 - y, x),
+            Rotation::Rotate180 => (w - 1 - x, h - 1 - y),
+            Rotation::Rotate270 => (y, w - 1 - x),
+        }
+    }
+
+    // a pixel of the rotated content to a guest pixel
+    fn guest_pixel(&self, x: i64, y: i64) -> (u32, u32) {
+        let src = self.source();
+        let (w, h) = (src.width as i64, src.height as i64);
+        let (x, y) = match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Rotate90 => (y, h - 1 - x),
+            Rotation::Rotate180 => (w - 1 - x, h - 1 - y),
+            Rotation::Rotate270 => (w - 1 - y, x),
+        };
+        ((x + src.x as i64) as u32, (y + src.y as i64) as u32)
+    }
+
+    // a continuous view position to a pixel of the rotated content, maybe out of it
+    fn view_to_content(&self, x: f64, y: f64) -> Option<(i64, i64)> {
+        let (sx, sy) = self.scale();
+        if !(sx > 0.0 && sy > 0.0) {
+            return None;
+        }
+        let (ox, oy) = self.offset();
+        Some((
+            ((x - ox) / sx).floor() as i64,
+            ((y - oy) / sy).floor() as i64,
+        ))
+    }
+
+    /// Map a view position to a guest pixel, or `None` in the borders.
+    ///
+    /// The position is continuous: the center of the view pixel `(x, y)` is at
+    /// `(x + 0.5, y + 0.5)`.
+    pub fn to_guest(&self, x: f64, y: f64) -> Option<(u32, u32)> {
+        let (cx, cy) = self.view_to_content(x, y)?;
+        let (cw, ch) = self.content_size();
+        if cx < 0 || cy < 0 || cx >= cw as i64 || cy >= ch as i64 {
+            return None;
+        }
+        Some(self.guest_pixel(cx, cy))
+    }
+
+    /// Map a view position to the nearest guest pixel of the shown region, even in the
+    /// borders, or `None` if nothing is shown.
+    pub fn to_guest_clamped(&self, x: f64, y: f64) -> Option<(u32, u32)> {
+        let (cx, cy) = self.view_to_content(x, y)?;
+        let (cw, ch) = self.content_size();
+        Some(self.guest_pixel(cx.clamp(0, cw as i64 - 1), cy.clamp(0, ch as i64 - 1)))
+    }
+
+    /// Map a guest pixel (the cursor position for example) to a view pixel.
+    ///
+    /// The pixel may be out of the view, or in the cropped region.
+    pub fn to_view(&self, x: i32, y: i32) -> (i32, i32) {
+        let (cx, cy) = self.content_pixel(x as _, y as _);
+        let (sx, sy) = self.scale();
+        let (ox, oy) = self.offset();
+        (
+            ((cx as f64 + 0.5) * sx + ox).floor() as i32,
+            ((cy as f64 + 0.5) * sy + oy).floor() as i32,
+        )
+    }
+
+    /// Map a relative motion of the view to the guest.
+    pub fn to_guest_delta(&self, dx: f64, dy: f64) -> (i32, i32) {
+        let (sx, sy) = self.scale();
+        if !(sx > 0.0 && sy > 0.0) {
+            return (0, 0);
+        }
+        let (dx, dy) = (dx / sx, dy / sy);
+        let (dx, dy) = match self.rotation {
+            Rotation::None => (dx, dy),
+            Rotation::Rotate90 => (dy, -dx),
+            Rotation::Rotate180 => (-dx, -dy),
+            Rotation::Rotate270 => (-dy, dx),
+        };
+        (dx.round() as i32, dy.round() as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROTATIONS: [Rotation; 4] = [
+        Rotation::None,
+        Rotation::Rotate90,
+        Rotation::Rotate180,
+        Rotation::Rotate270,
+    ];
+
+    fn center(view: (i32, i32)) -> (f64, f64) {
+        (view.0 as f64 + 0.5, view.1 as f64 + 0.5)
+    }
+
+    #[test]
+    fn identity() {
+        let t = ViewTransform::new((640, 480), (640, 480));
+        assert_eq!(t.scale(), (1.0, 1.0));
+        assert_eq!(t.viewport(), Rect::new(0, 0, 640, 480));
+        assert_eq!(t.to_guest(0.0, 0.0), Some((0, 0)));
+        assert_eq!(t.to_guest(639.9, 479.9), Some((639, 479)));
+        assert_eq!(t.to_guest(640.0, 0.0), None);
+        assert_eq!(t.to_guest(-0.1, 0.0), None);
+        assert_eq!(t.to_view(10, 20), (10, 20));
+        assert_eq!(t.to_guest_delta(3.0, -4.0), (3, -4));
+    }
+
+    #[test]
+    fn letterbox() {
+        // a 4:3 display in a 16:9 view: borders on the sides
+        let t = ViewTransform::new((800, 600), (1920, 1080));
+        assert_eq!(t.scale(), (1.8, 1.8));
+        assert_eq!(t.viewport(), Rect::new(240, 0, 1440, 1080));
+        assert_eq!(t.to_guest(239.0, 500.0), None);
+        assert_eq!(t.to_guest(240.0, 0.0), Some((0, 0)));
+        assert_eq!(t.to_guest(1679.9, 1079.9), Some((799, 599)));
+        assert_eq!(t.to_guest(1680.0, 0.0), None);
+        assert_eq!(t.to_guest_clamped(0.0, 500.0), Some((0, 277)));
+        assert_eq!(t.to_guest_clamped(1919.0, -5.0), Some((799, 0)));
+        assert_eq!(t.to_view(0, 0), (240, 0));
+        assert_eq!(t.to_view(799, 599), (1679, 1079));
+
+        // a wide display in a tall view: borders on top and bottom
+        let t = ViewTransform::new((1000, 500), (500, 500));
+        assert_eq!(t.viewport(), Rect::new(0, 125, 500, 250));
+        assert_eq!(t.to_guest(250.0, 124.0), None);
+        assert_eq!(t.to_guest(250.0, 125.0), Some((500, 0)));
+    }
+
+    #[test]
+    fn stretch() {
+        let t = ViewTransform::new((800, 600), (1600, 900)).with_scale_mode(ScaleMode::Stretch);
+        assert_eq!(t.scale(), (2.0, 1.5));
+        assert_eq!(t.viewport(), Rect::new(0, 0, 1600, 900));
+        assert_eq!(t.to_guest(1599.0, 899.0), Some((799, 599)));
+        assert_eq!(t.to_view(400, 300), (801, 450));
+        assert_eq!(t.to_guest_delta(4.0, 3.0), (2, 2));
+    }
+
+    #[test]
+    fn factor() {
+        let t = ViewTransform::new((100, 100), (50, 50)).with_scale_mode(ScaleMode::Factor(0.5));
+        assert_eq!(t.viewport(), Rect::new(0, 0, 50, 50));
+        assert_eq!(t.to_guest(0.5, 0.5), Some((1, 1)));
+        assert_eq!(t.to_guest(200.0, 0.5), None);
+        assert_eq!(t.to_guest_clamped(200.0, 0.5), Some((99, 1)));
+        assert_eq!(t.to_view(2, 2), (1, 1));
+        assert_eq!(t.to_guest_delta(3.0, -3.0), (6, -6));
+    }
+
+    #[test]
+    fn rotation() {
+        // a 4x2 display, rotated in a 2x4 view
+        let t = ViewTransform::new((4, 2), (2, 4)).with_rotation(Rotation::Rotate90);
+        assert_eq!(t.content_size(), (2, 4));
+        assert_eq!(t.scale(), (1.0, 1.0));
+        // the top-left guest corner is at the top-right of the view
+        assert_eq!(t.to_view(0, 0), (1, 0));
+        assert_eq!(t.to_guest(1.5, 0.5), Some((0, 0)));
+        assert_eq!(t.to_view(3, 1), (0, 3));
+        assert_eq!(t.to_guest(0.5, 3.5), Some((3, 1)));
+        // moving down the view moves right in the guest
+        assert_eq!(t.to_guest_delta(0.0, 1.0), (1, 0));
+        assert_eq!(t.to_guest_delta(1.0, 0.0), (0, -1));
+
+        let t = ViewTransform::new((4, 2), (4, 2)).with_rotation(Rotation::Rotate180);
+        assert_eq!(t.to_view(0, 0), (3, 1));
+        assert_eq!(t.to_guest(3.5, 1.5), Some((0, 0)));
+        assert_eq!(t.to_guest_delta(1.0, 1.0), (-1, -1));
+
+        let t = ViewTransform::new((4, 2), (2, 4)).with_rotation(Rotation::Rotate270);
+        assert_eq!(t.to_view(0, 0), (0, 3));
+        assert_eq!(t.to_guest(0.5, 3.5), Some((0, 0)));
+        assert_eq!(t.to_guest_delta(0.0, 1.0), (-1, 0));
+    }
+
+    #[test]
+    fn crop() {
+        let crop = Rect::new(100, 50, 200, 100);
+        let t = ViewTransform::new((640, 480), (400, 200)).with_crop(crop);
+        assert_eq!(t.content_size(), (200, 100));
+        assert_eq!(t.scale(), (2.0, 2.0));
+        assert_eq!(t.to_guest(0.0, 0.0), Some((100, 50)));
+        assert_eq!(t.to_guest(399.0, 199.0), Some((299, 149)));
+        assert_eq!(t.to_guest_clamped(-10.0, 500.0), Some((100, 149)));
+        assert_eq!(t.to_view(100, 50), (1, 1));
+        // outside of the cropped region
+        assert_eq!(t.to_view(0, 0), (-199, -99));
+
+        // the crop is clipped to the display
+        let t = ViewTransform::new((640, 480), (100, 100)).with_crop(Rect::new(600, 400, 100, 100));
+        assert_eq!(t.content_size(), (40, 80));
+    }
+
+    #[test]
+    fn round_trip() {
+        let crop = Rect::new(3, 5, 17, 11);
+        for rotation in ROTATIONS {
+            for mode in [ScaleMode::Fit, ScaleMode::Stretch, ScaleMode::Factor(2.5)] {
+                for view in [(17, 11), (40, 25), (100, 37)] {
+                    let t = ViewTransform::new((32, 24), view)
+                        .with_crop(crop)
+                        .with_rotation(rotation)
+                        .with_scale_mode(mode);
+                    // upscaling: every guest pixel has its own view pixel
+                    if t.scale().0 < 1.0 || t.scale().1 < 1.0 {
+                        continue;
+                    }
+                    for x in crop.x..crop.right() {
+                        for y in crop.y..crop.bottom() {
+                            let v = t.to_view(x as _, y as _);
+                            assert_eq!(
+                                t.to_guest(center(v).0, center(v).1),
+                                Some((x, y)),
+                                "{:?} {:?} {:?}",
+                                rotation,
+                                mode,
+                                view
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn view_covered() {
+        // every view pixel of the viewport maps to the guest, in any configuration
+        for rotation in ROTATIONS {
+            for view in [(7, 13), (13, 7), (64, 48)] {
+                let t = ViewTransform::new((16, 9), view).with_rotation(rotation);
+                let vp = t.viewport();
+                for x in vp.x..vp.right() {
+                    for y in vp.y..vp.bottom() {
+                        let (gx, gy) = t.to_guest(x as f64 + 0.5, y as f64 + 0.5).unwrap();
+                        assert!(gx < 16 && gy < 9);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let t = ViewTransform::new((0, 0), (100, 100));
+        assert_eq!(t.scale(), (0.0, 0.0));
+        assert_eq!(t.to_guest(1.0, 1.0), None);
+        assert_eq!(t.to_guest_clamped(1.0, 1.0), None);
+        assert_eq!(t.to_guest_delta(1.0, 1.0), (0, 0));
+
+        let t = ViewTransform::new((100, 100), (0, 0));
+        assert_eq!(t.to_guest(0.0, 0.0), None);
+        assert_eq!(t.viewport(), Rect::new(0, 0, 0, 0));
+    }
+}
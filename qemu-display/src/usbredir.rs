@@ -1,80 +1,73 @@
 use async_broadcast::{broadcast, Receiver, Sender};
+use async_io::Async;
 use async_lock::RwLock;
-use futures::Stream;
-#[cfg(unix)]
-use std::os::unix::{
-    io::{AsRawFd, RawFd},
-    net::UnixStream,
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite},
+    Future, Stream, StreamExt,
 };
+#[cfg(unix)]
+use std::os::unix::{io::AsRawFd, net::UnixStream};
 use std::{
     collections::HashMap,
     default::Default,
     io::{Read, Write},
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
     thread::JoinHandle,
+    time::Duration,
 };
 #[cfg(windows)]
 use uds_windows::UnixStream;
 use usbredirhost::{
-    rusb::{self, UsbContext},
+    rusb::{self, Hotplug, HotplugBuilder, UsbContext},
     Device, DeviceHandler, LogLevel,
 };
 
-use crate::{Chardev, Error, Result};
+use zbus::Task;
 
-#[derive(Debug)]
-struct InnerHandler {
-    #[allow(unused)] // keep the device opened, as rusb doesn't take it
-    #[cfg(unix)]
-    device_fd: Option<zvariant::OwnedFd>,
-    stream: UnixStream,
-    ctxt: rusb::Context,
-    ctxt_thread: Option<JoinHandle<()>>,
-    event: (UnixStream, UnixStream),
-    quit: bool,
-}
+use crate::{util, Chardev, ChardevConnection, Error, Result};
 
-#[derive(Clone, Debug)]
+// Forwards the usbredir protocol of a device to its chardev stream.
+//
+// The stream is non-blocking: the reads and writes that would block are retried by the
+// device task, once the stream is ready.
+#[derive(Debug)]
 struct Handler {
-    inner: Arc<Mutex<InnerHandler>>,
+    stream: Arc<Async<UnixStream>>,
+    flush: mpsc::UnboundedSender<()>,
 }
 
 impl DeviceHandler for Handler {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut inner = self.inner.lock().unwrap();
-        let read = match fd_poll_readable(inner.stream.as_raw_fd(), None) {
-            Ok(true) => {
-                let read = inner.stream.read(buf);
-                if let Ok(0) = read {
-                    Err(std::io::Error::new(
-                        std::io::ErrorKind::BrokenPipe,
-                        "disconnected",
-                    ))
-                } else {
-                    read
-                }
-            }
-            Ok(false) => Ok(0),
-            Err(e) => Err(e),
-        };
-
-        inner.quit = read.is_err();
-        read
+        match self.stream.get_ref().read(buf) {
+            Ok(0) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "disconnected",
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
     }
 
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut inner = self.inner.lock().unwrap();
-        let write = inner.stream.write_all(buf);
-        inner.quit = write.is_err();
-        write?;
-        Ok(buf.len())
+        match self.stream.get_ref().write(buf) {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
     }
 
     fn log(&mut self, _level: LogLevel, _msg: &str) {}
 
-    fn flush_writes(&mut self) {}
+    // called from the libusb events, when data is queued for the guest
+    fn flush_writes(&mut self) {
+        let _ = self.flush.unbounded_send(());
+    }
 }
 
 #[cfg(unix)]
@@ -87,88 +80,139 @@ trait SystemHelper {
     fn open_bus_dev(&self, bus: u8, dev: u8) -> zbus::fdo::Result<zbus::zvariant::OwnedFd>;
 }
 
-impl Handler {
-    async fn new(device: &rusb::Device<rusb::Context>, chardev: &Chardev) -> Result<Self> {
-        let ctxt = device.context().clone();
-
-        let (dev, device_fd) = match device.open() {
-            Ok(it) => (it, None),
-            #[cfg(unix)]
-            Err(rusb::Error::Access) => {
-                let (bus, dev) = (device.bus_number(), device.address());
-                let sysbus = zbus::Connection::system().await?;
-                let fd = SystemHelperProxy::new(&sysbus)
-                    .await?
-                    .open_bus_dev(bus, dev)
-                    .await?;
-                unsafe { (ctxt.open_device_with_fd(fd.as_raw_fd())?, Some(fd)) }
-            }
-            Err(e) => {
-                return Err(e.into());
-            }
-        };
-
-        let (stream, peer) = UnixStream::pair()?;
-        chardev.proxy.register(peer.as_raw_fd().into()).await?;
-
-        let c = ctxt.clone();
-        let stream_fd = stream.as_raw_fd();
-        // really annoying libusb/usbredir APIs...
-        let event = UnixStream::pair()?;
-        let event_fd = event.1.as_raw_fd();
-        std::thread::spawn(move || loop {
-            let ret = fd_poll_readable(stream_fd, Some(event_fd));
-            c.interrupt_handle_events();
-            if ret.is_err() {
-                break;
-            }
-        });
+// An opened device, closed before its file descriptor.
+struct OpenedDevice {
+    device: Device<rusb::Context, Handler>,
+    #[allow(unused)] // keep the device opened, as rusb doesn't take it
+    #[cfg(unix)]
+    fd: Option<zvariant::OwnedFd>,
+}
 
-        let handler = Self {
-            inner: Arc::new(Mutex::new(InnerHandler {
-                #[cfg(unix)]
-                device_fd,
-                stream,
-                event,
-                quit: false,
-                ctxt: ctxt.clone(),
-                ctxt_thread: Default::default(),
-            })),
-        };
+async fn open_device(
+    device: &rusb::Device<rusb::Context>,
+    handler: Handler,
+) -> Result<OpenedDevice> {
+    let ctxt = device.context().clone();
+    #[cfg(unix)]
+    let mut fd = None;
+    let handle = match device.open() {
+        Ok(it) => it,
+        #[cfg(unix)]
+        Err(rusb::Error::Access) => {
+            let (bus, dev) = (device.bus_number(), device.address());
+            let sysbus = zbus::Connection::system().await?;
+            let helper_fd = SystemHelperProxy::new(&sysbus)
+                .await?
+                .open_bus_dev(bus, dev)
+                .await?;
+            let handle = unsafe { ctxt.open_device_with_fd(helper_fd.as_raw_fd())? };
+            fd = Some(helper_fd);
+            handle
+        }
+        Err(e) => {
+            return Err(e.into());
+        }
+    };
+    let device = Device::new(&ctxt, Some(handle), handler, LogLevel::None as _)?;
+    Ok(OpenedDevice {
+        device,
+        #[cfg(unix)]
+        fd,
+    })
+}
 
-        let redirdev = Device::new(&ctxt, Some(dev), handler.clone(), LogLevel::None as _)?;
-        let c = ctxt.clone();
-        let inner = handler.inner.clone();
-        let ctxt_thread = std::thread::spawn(move || loop {
-            if inner.lock().unwrap().quit {
-                break;
-            }
-            if let Ok(true) = fd_poll_readable(stream_fd, None) {
-                redirdev.read_peer().unwrap();
+// Exchange the usbredir data between the device and the chardev, until either fails.
+async fn run_device(
+    opened: OpenedDevice,
+    stream: Arc<Async<UnixStream>>,
+    mut flush: mpsc::UnboundedReceiver<()>,
+) -> Result<()> {
+    let device = &opened.device;
+    loop {
+        if device.has_data_to_write() > 0 {
+            device.write_peer()?;
+        }
+        // wait for the writes that would block too
+        let pending = device.has_data_to_write() > 0;
+        let writable = async {
+            if pending {
+                stream.writable().await
+            } else {
+                future::pending().await
             }
-            if redirdev.has_data_to_write() > 0 {
-                redirdev.write_peer().unwrap();
+        };
+        let readable = stream.readable();
+        let flushed = flush.next();
+        futures::pin_mut!(readable, writable);
+        match future::select(readable, future::select(writable, flushed)).await {
+            Either::Left((res, _)) => {
+                res?;
+                device.read_peer()?;
             }
-            c.handle_events(None).unwrap();
-        });
-        handler
-            .inner
-            .lock()
-            .unwrap()
-            .ctxt_thread
-            .replace(ctxt_thread);
+            Either::Right((Either::Left((res, _)), _)) => res?,
+            Either::Right((Either::Right(_), _)) => {}
+        }
+    }
+}
+
+// Handles the libusb events of a context, to complete the transfers of its devices.
+//
+// libusb has no portable asynchronous interface: a thread is shared by the devices.
+#[derive(Debug)]
+struct EventThread {
+    ctxt: rusb::Context,
+    quit: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
 
-        Ok(handler)
+impl EventThread {
+    fn new(ctxt: &rusb::Context) -> Result<Self> {
+        let quit = Arc::new(AtomicBool::new(false));
+        let (c, q) = (ctxt.clone(), quit.clone());
+        let thread = std::thread::Builder::new()
+            .name("usbredir-events".into())
+            .spawn(move || {
+                while !q.load(Ordering::SeqCst) {
+                    match c.handle_events(Some(DEVICE_POLL_PERIOD)) {
+                        Ok(()) | Err(rusb::Error::Interrupted) => {}
+                        Err(e) => {
+                            log::warn!("Failed to handle the USB events: {}", e);
+                            break;
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            ctxt: ctxt.clone(),
+            quit,
+            thread: Some(thread),
+        })
     }
 }
 
-impl Drop for Handler {
+impl Drop for EventThread {
     fn drop(&mut self) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.quit = true;
-        inner.ctxt.interrupt_handle_events();
-        // stream will be dropped and stream_thread will kick context_thread
-        inner.event.0.write_all(&[0]).unwrap();
+        self.quit.store(true, Ordering::SeqCst);
+        self.ctxt.interrupt_handle_events();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// A redirected device, its task is cancelled when dropped.
+#[derive(Debug)]
+struct Redirection {
+    task: Task<()>,
+    // closed when the task future is dropped
+    done: oneshot::Receiver<()>,
+}
+
+impl Redirection {
+    // cancel the task, and wait until the device is closed
+    async fn close(self) {
+        drop(self.task);
+        let _ = self.done.await;
     }
 }
 
@@ -186,11 +230,46 @@ enum Event {
     NFreeChannels(i32),
 }
 
+/// The identification of a USB device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UsbDeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl UsbDeviceInfo {
+    pub fn from_device(device: &rusb::Device<rusb::Context>) -> Self {
+        let (vendor_id, product_id) = device
+            .device_descriptor()
+            .map(|d| (d.vendor_id(), d.product_id()))
+            .unwrap_or_default();
+        Self {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id,
+            product_id,
+        }
+    }
+}
+
+/// A change of the redirected devices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsbRedirEvent {
+    Attached(UsbDeviceInfo),
+    Detached(UsbDeviceInfo),
+    /// The redirection of the device failed, with the error message.
+    Failed(UsbDeviceInfo, String),
+}
+
 #[derive(Debug)]
 struct Inner {
     chardevs: Vec<Chardev>,
-    handlers: HashMap<Key, Handler>,
+    handlers: HashMap<Key, (Redirection, UsbDeviceInfo)>,
+    event_threads: Vec<EventThread>,
     channel: (Sender<Event>, Receiver<Event>),
+    events: (Sender<UsbRedirEvent>, Receiver<UsbRedirEvent>),
 }
 
 impl Inner {
@@ -224,11 +303,15 @@ impl UsbRedir {
     pub fn new(chardevs: Vec<Chardev>) -> Self {
         let mut channel = broadcast(1);
         channel.0.set_overflow(true);
+        let mut events = broadcast(16);
+        events.0.set_overflow(true);
         Self {
             inner: Arc::new(RwLock::new(Inner {
                 chardevs,
                 channel,
+                events,
                 handlers: Default::default(),
+                event_threads: Default::default(),
             })),
         }
     }
@@ -247,16 +330,31 @@ impl UsbRedir {
 
         match (state, handled) {
             (true, false) => {
-                let chardev = inner
-                    .first_available_chardev()
-                    .await
-                    .ok_or_else(|| Error::Failed("There are no free USB channels".into()))?;
-                let handler = Handler::new(device, chardev).await?;
-                inner.handlers.insert(key, handler);
+                let info = UsbDeviceInfo::from_device(device);
+                let handler = match self.redirect(&mut inner, device, key).await {
+                    Ok(handler) => handler,
+                    Err(e) => {
+                        let event = UsbRedirEvent::Failed(info, e.to_string());
+                        let _ = inner.events.0.broadcast(event).await;
+                        return Err(e);
+                    }
+                };
+                inner.handlers.insert(key, (handler, info));
                 nfree -= 1;
+                let _ = inner
+                    .events
+                    .0
+                    .broadcast(UsbRedirEvent::Attached(info))
+                    .await;
             }
             (false, true) => {
-                inner.handlers.remove(&key);
+                if let Some((_, info)) = inner.handlers.remove(&key) {
+                    let _ = inner
+                        .events
+                        .0
+                        .broadcast(UsbRedirEvent::Detached(info))
+                        .await;
+                }
                 nfree += 1;
             }
             _ => {
@@ -269,6 +367,94 @@ impl UsbRedir {
         Ok(state)
     }
 
+    /// Disconnect all the devices, and wait until their threads are finished.
+    ///
+    /// This affects the clones of the `UsbRedir` too.
+    pub async fn close(self) {
+        let mut inner = self.inner.write().await;
+        let handlers: Vec<_> = inner.handlers.drain().map(|(_, h)| h).collect();
+        for (redirection, info) in handlers {
+            redirection.close().await;
+            let _ = inner
+                .events
+                .0
+                .broadcast(UsbRedirEvent::Detached(info))
+                .await;
+        }
+        inner.event_threads.clear();
+        let nfree = inner.n_available_chardev().await as _;
+        let _ = inner.channel.0.broadcast(Event::NFreeChannels(nfree)).await;
+    }
+
+    // open the device, and forward it to a free chardev from a new task
+    async fn redirect(
+        &self,
+        inner: &mut Inner,
+        device: &rusb::Device<rusb::Context>,
+        key: Key,
+    ) -> Result<Redirection> {
+        let ctxt = device.context();
+        if !inner
+            .event_threads
+            .iter()
+            .any(|t| t.ctxt.as_raw() == ctxt.as_raw())
+        {
+            inner.event_threads.push(EventThread::new(ctxt)?);
+        }
+        let chardev = inner
+            .first_available_chardev()
+            .await
+            .ok_or_else(|| Error::Failed("There are no free USB channels".into()))?;
+
+        let (stream, peer) = UnixStream::pair()?;
+        let stream = Arc::new(Async::new(stream)?);
+        let (flush, flushed) = mpsc::unbounded();
+        let handler = Handler {
+            stream: stream.clone(),
+            flush,
+        };
+        let opened = open_device(device, handler).await?;
+        let fd = util::prepare_uds_pass(
+            #[cfg(windows)]
+            chardev.peer_pid,
+            &peer,
+        )?;
+        chardev.proxy.register(fd).await?;
+
+        let (done_tx, done) = oneshot::channel::<()>();
+        let weak = Arc::downgrade(&self.inner);
+        let task = chardev.proxy.connection().executor().spawn(async move {
+            let res = run_device(opened, stream, flushed).await;
+            drop(done_tx);
+            if let Some(inner) = weak.upgrade() {
+                UsbRedir { inner }.device_finished(key, res).await;
+            }
+        });
+        Ok(Redirection { task, done })
+    }
+
+    // a device task stopped by itself: the device or the chardev disconnected
+    async fn device_finished(&self, key: Key, res: Result<()>) {
+        let mut inner = self.inner.write().await;
+        // the task is dropped last, it is the current task
+        let (_redirection, info) = match inner.handlers.remove(&key) {
+            Some(handler) => handler,
+            None => return,
+        };
+        if let Err(e) = res {
+            log::warn!("The redirection of the USB device failed: {}", e);
+            let event = UsbRedirEvent::Failed(info, e.to_string());
+            let _ = inner.events.0.broadcast(event).await;
+        }
+        let _ = inner
+            .events
+            .0
+            .broadcast(UsbRedirEvent::Detached(info))
+            .await;
+        let nfree = inner.n_available_chardev().await as _;
+        let _ = inner.channel.0.broadcast(Event::NFreeChannels(nfree)).await;
+    }
+
     pub async fn is_device_connected(&self, device: &rusb::Device<rusb::Context>) -> bool {
         let inner = self.inner.read().await;
 
@@ -288,6 +474,307 @@ impl UsbRedir {
             receiver: inner.channel.1.clone(),
         })
     }
+
+    /// The devices attached and detached, manually or by [`UsbRedir::auto_redirect`].
+    pub async fn receive_events(&self) -> Pin<Box<dyn Stream<Item = UsbRedirEvent> + Send>> {
+        let inner = self.inner.read().await;
+
+        Box::pin(inner.events.1.clone())
+    }
+
+    /// Redirect the devices allowed by `filter` when they are plugged, including the devices
+    /// already plugged, to the free channels. The unplugged devices are detached.
+    ///
+    /// The redirection stops when the returned [`UsbAutoRedirect`] is dropped, without
+    /// detaching the devices.
+    pub fn auto_redirect(
+        &self,
+        ctxt: &rusb::Context,
+        filter: UsbFilter,
+    ) -> Result<UsbAutoRedirect> {
+        let (watch, receiver) = DeviceWatch::new(ctxt)?;
+        let usbredir = self.clone();
+        std::thread::spawn(move || async_io::block_on(auto_redirect(usbredir, filter, receiver)));
+        Ok(UsbAutoRedirect { _watch: watch })
+    }
+
+    /// The USB devices plugged and unplugged on the host, starting with the devices already
+    /// plugged.
+    ///
+    /// The changes come from the libusb hotplug events, or from polling the device list
+    /// where hotplug isn't supported. The watch stops when the stream is dropped.
+    pub fn receive_device_changes(&self, ctxt: &rusb::Context) -> Result<UsbDeviceChanges> {
+        let (watch, receiver) = DeviceWatch::new(ctxt)?;
+        Ok(UsbDeviceChanges {
+            _watch: watch,
+            receiver,
+        })
+    }
+
+    /// Forward a usbredir protocol stream, such as a TCP connection to `usbredirserver`, to
+    /// a free channel.
+    ///
+    /// The bytes are copied both ways on the executor of the D-Bus connection, until either
+    /// side disconnects or the returned [`UsbStreamRedirect`] is dropped.
+    pub async fn attach_stream<S>(&self, stream: S) -> Result<UsbStreamRedirect>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let inner = self.inner.write().await;
+        let chardev = inner
+            .first_available_chardev()
+            .await
+            .ok_or_else(|| Error::Failed("There are no free USB channels".into()))?;
+        let executor = chardev.proxy.connection().executor().clone();
+        let conn = chardev.connect().await?;
+        let task = executor.spawn(forward_stream(self.clone(), conn, stream));
+        let nfree = inner.n_available_chardev().await as _;
+        let _ = inner.channel.0.broadcast(Event::NFreeChannels(nfree)).await;
+        Ok(UsbStreamRedirect { task })
+    }
+}
+
+/// A usbredir stream forwarded to a chardev, see [`UsbRedir::attach_stream`].
+///
+/// The forwarding stops when dropped. As a future, it completes when either side closes
+/// the connection.
+#[derive(Debug)]
+pub struct UsbStreamRedirect {
+    task: Task<Result<()>>,
+}
+
+impl Future for UsbStreamRedirect {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+async fn forward_stream<S>(usbredir: UsbRedir, chardev: ChardevConnection, stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (chardev_read, mut chardev_write) = chardev.split();
+    let (stream_read, mut stream_write) = stream.split();
+    let res = future::select(
+        io::copy(chardev_read, &mut stream_write),
+        io::copy(stream_read, &mut chardev_write),
+    )
+    .await
+    .factor_first()
+    .0;
+    drop(chardev_write);
+    let inner = usbredir.inner.read().await;
+    let nfree = inner.n_available_chardev().await as _;
+    let _ = inner.channel.0.broadcast(Event::NFreeChannels(nfree)).await;
+    res.map(|_| ()).map_err(Into::into)
+}
+
+/// A rule of a [`UsbFilter`]. The fields set to `None` match any device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsbRule {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    /// The class of the device, or of one of its interfaces.
+    pub class: Option<u8>,
+    /// Redirect the matching devices, or not.
+    pub allow: bool,
+}
+
+impl UsbRule {
+    pub fn matches(&self, device: &rusb::Device<rusb::Context>) -> bool {
+        let desc = match device.device_descriptor() {
+            Ok(desc) => desc,
+            Err(_) => return false,
+        };
+        if matches!(self.vendor_id, Some(v) if v != desc.vendor_id())
+            || matches!(self.product_id, Some(p) if p != desc.product_id())
+        {
+            return false;
+        }
+        let class = match self.class {
+            Some(class) => class,
+            None => return true,
+        };
+        if desc.class_code() == class {
+            return true;
+        }
+        (0..desc.num_configurations())
+            .filter_map(|i| device.config_descriptor(i).ok())
+            .any(|config| {
+                config
+                    .interfaces()
+                    .flat_map(|i| i.descriptors())
+                    .any(|d| d.class_code() == class)
+            })
+    }
+}
+
+/// The auto-redirection rules: the first matching rule applies, and the devices without a
+/// matching rule aren't redirected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsbFilter {
+    pub rules: Vec<UsbRule>,
+}
+
+impl UsbFilter {
+    pub fn allows(&self, device: &rusb::Device<rusb::Context>) -> bool {
+        matches!(self.rules.iter().find(|r| r.matches(device)), Some(r) if r.allow)
+    }
+}
+
+/// A change of the USB devices of the host, see [`UsbRedir::receive_device_changes`].
+#[derive(Debug, Clone)]
+pub enum UsbDeviceChange {
+    Added(rusb::Device<rusb::Context>, UsbDeviceInfo),
+    Removed(rusb::Device<rusb::Context>, UsbDeviceInfo),
+}
+
+// the descriptors can't be read from the hotplug callbacks: forward the devices
+type DeviceSender = mpsc::UnboundedSender<(bool, rusb::Device<rusb::Context>)>;
+type DeviceReceiver = mpsc::UnboundedReceiver<(bool, rusb::Device<rusb::Context>)>;
+
+const DEVICE_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+struct HotplugSender(DeviceSender);
+
+impl Hotplug<rusb::Context> for HotplugSender {
+    fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+        let _ = self.0.unbounded_send((true, device));
+    }
+
+    fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
+        let _ = self.0.unbounded_send((false, device));
+    }
+}
+
+// send the device list changes, until the receiver or the watch is dropped
+fn poll_devices(ctxt: rusb::Context, sender: DeviceSender, quit: Arc<AtomicBool>) {
+    let mut known: HashMap<Key, rusb::Device<rusb::Context>> = HashMap::new();
+    while !quit.load(Ordering::SeqCst) {
+        let devices = match ctxt.devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                log::warn!("Failed to list the USB devices: {}", e);
+                break;
+            }
+        };
+        let current: HashMap<_, _> = devices.iter().map(|d| (Key::from_device(&d), d)).collect();
+        let removed = known
+            .iter()
+            .filter(|(key, _)| !current.contains_key(key))
+            .map(|(_, device)| (false, device.clone()));
+        let added = current
+            .iter()
+            .filter(|(key, _)| !known.contains_key(key))
+            .map(|(_, device)| (true, device.clone()));
+        let changes: Vec<_> = removed.chain(added).collect();
+        known = current;
+        for change in changes {
+            if sender.unbounded_send(change).is_err() {
+                return;
+            }
+        }
+        std::thread::sleep(DEVICE_POLL_PERIOD);
+    }
+}
+
+// the hotplug registration, or the polling thread
+#[derive(Debug)]
+struct DeviceWatch {
+    ctxt: rusb::Context,
+    quit: Arc<AtomicBool>,
+    registration: Option<rusb::Registration<rusb::Context>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DeviceWatch {
+    fn new(ctxt: &rusb::Context) -> Result<(Self, DeviceReceiver)> {
+        let (sender, receiver) = mpsc::unbounded();
+        let quit = Arc::new(AtomicBool::new(false));
+        let (c, q) = (ctxt.clone(), quit.clone());
+        let (registration, thread) = if rusb::has_hotplug() {
+            let registration = HotplugBuilder::new()
+                .enumerate(true)
+                .register(ctxt, Box::new(HotplugSender(sender)))?;
+            let thread = std::thread::spawn(move || {
+                while !q.load(Ordering::SeqCst) {
+                    if let Err(e) = c.handle_events(Some(DEVICE_POLL_PERIOD)) {
+                        log::warn!("Failed to handle the USB events: {}", e);
+                        break;
+                    }
+                }
+            });
+            (Some(registration), thread)
+        } else {
+            log::debug!("USB hotplug isn't supported, polling the devices");
+            (None, std::thread::spawn(move || poll_devices(c, sender, q)))
+        };
+        let watch = Self {
+            ctxt: ctxt.clone(),
+            quit,
+            registration,
+            thread: Some(thread),
+        };
+        Ok((watch, receiver))
+    }
+}
+
+impl Drop for DeviceWatch {
+    fn drop(&mut self) {
+        // the device sender is dropped with the registration, or the polling thread
+        self.registration.take();
+        self.quit.store(true, Ordering::SeqCst);
+        self.ctxt.interrupt_handle_events();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The stream of [`UsbRedir::receive_device_changes`].
+#[derive(Debug)]
+pub struct UsbDeviceChanges {
+    _watch: DeviceWatch,
+    receiver: DeviceReceiver,
+}
+
+impl Stream for UsbDeviceChanges {
+    type Item = UsbDeviceChange;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Stream::poll_next(Pin::new(&mut self.receiver), cx).map(|change| {
+            change.map(|(added, device)| {
+                let info = UsbDeviceInfo::from_device(&device);
+                if added {
+                    UsbDeviceChange::Added(device, info)
+                } else {
+                    UsbDeviceChange::Removed(device, info)
+                }
+            })
+        })
+    }
+}
+
+async fn auto_redirect(usbredir: UsbRedir, filter: UsbFilter, mut devices: DeviceReceiver) {
+    while let Some((arrived, device)) = devices.next().await {
+        if arrived && !filter.allows(&device) {
+            continue;
+        }
+        if !arrived && !usbredir.is_device_connected(&device).await {
+            continue;
+        }
+        if let Err(e) = usbredir.set_device_state(&device, arrived).await {
+            log::warn!("Failed to auto-redirect the USB device: {}", e);
+        }
+    }
+}
+
+/// The automatic redirection of [`UsbRedir::auto_redirect`], stopped when dropped.
+#[derive(Debug)]
+pub struct UsbAutoRedirect {
+    _watch: DeviceWatch,
 }
 
 #[derive(Debug)]
@@ -308,37 +795,3 @@ impl Stream for NFreeChannelsStream {
         }
     }
 }
-
-#[cfg(unix)]
-fn fd_poll_readable(fd: RawFd, wait: Option<RawFd>) -> std::io::Result<bool> {
-    let mut fds = vec![libc::pollfd {
-        fd,
-        events: libc::POLLIN | libc::POLLHUP,
-        revents: 0,
-    }];
-    if let Some(wait) = wait {
-        fds.push(libc::pollfd {
-            fd: wait,
-            events: libc::POLLIN | libc::POLLHUP,
-            revents: 0,
-        });
-    }
-    let ret = unsafe {
-        libc::poll(
-            fds.as_mut_ptr(),
-            fds.len() as _,
-            if wait.is_some() { -1 } else { 0 },
-        )
-    };
-    if ret < 0 {
-        Err(std::io::Error::last_os_error())
-    } else if ret == 0 {
-        Ok(false)
-    } else if fds[0].revents & libc::POLLHUP != 0
-        || (wait.is_some() && fds[1].revents & libc::POLLIN != 0)
-    {
-        Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "hup"))
-    } else {
-        Ok(fds[0].revents & libc::POLLIN != 0)
-    }
-}
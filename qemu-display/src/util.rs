@@ -1,7 +1,15 @@
 use crate::Result;
+use futures::StreamExt;
+use std::net::Shutdown;
+use zbus::{Connection, MessageStream};
 
 #[cfg(unix)]
-use std::os::unix::{io::AsRawFd, net::UnixStream};
+use std::os::unix::{
+    io::{AsRawFd, RawFd},
+    net::UnixStream,
+};
+#[cfg(unix)]
+use std::{io, mem};
 #[cfg(windows)]
 use win32::Fd;
 #[cfg(unix)]
@@ -30,3 +38,80 @@ pub fn prepare_uds_pass(#[cfg(windows)] peer_pid: u32, us: &UnixStream) -> Resul
         p.duplicate_socket(SOCKET(us.as_raw_socket() as _))
     }
 }
+
+/// Close a peer-to-peer connection, and wait until it stopped reading its socket.
+///
+/// `socket` is a clone of the connection socket, which is shut down: the peer sees the
+/// connection closed, even if the connection is still referenced.
+pub(crate) async fn close_p2p(conn: Connection, socket: &UnixStream) {
+    let mut stream = MessageStream::from(&conn);
+    if let Err(e) = socket.shutdown(Shutdown::Both) {
+        log::debug!("Failed to shutdown the connection socket: {}", e);
+    }
+    // the stream ends when the socket is closed
+    while stream.next().await.is_some() {}
+}
+
+/// Send `data` on a unix socket, with a file descriptor as ancillary data.
+#[cfg(unix)]
+pub fn send_fd(us: &UnixStream, data: &[u8], fd: RawFd) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as _) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as _;
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as _) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+    let n = unsafe { libc::sendmsg(us.as_raw_fd(), &msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Receive data from a unix socket, with the file descriptor passed along, if any.
+///
+/// The received descriptor is owned by the caller, and is close-on-exec.
+#[cfg(unix)]
+pub fn recv_fd(us: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Option<RawFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut _,
+        iov_len: buf.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as _) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as _;
+    msg.msg_controllen = space as _;
+    let n = unsafe { libc::recvmsg(us.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fd = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let received = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+                libc::fcntl(received, libc::F_SETFD, libc::FD_CLOEXEC);
+                fd = Some(received);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((n as usize, fd))
+}
@@ -14,3 +14,219 @@ pub trait VM {
     #[dbus_proxy(property)]
     fn uuid(&self) -> zbus::Result<String>;
 }
+
+/// The run state of a VM, as reported by QMP.
+///
+/// The `org.qemu.Display1.VM` interface only has the VM properties, the state is followed
+/// and changed over QMP, see [`VmControl`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    Paused,
+    Suspended,
+    Shutdown,
+    /// Another QEMU run state, like "inmigrate" or "guest-panicked".
+    Other(String),
+}
+
+impl RunState {
+    /// The state of a `query-status` status.
+    pub fn from_status(status: &str) -> Self {
+        match status {
+            "running" => Self::Running,
+            "paused" => Self::Paused,
+            "suspended" => Self::Suspended,
+            "shutdown" => Self::Shutdown,
+            status => Self::Other(status.into()),
+        }
+    }
+
+    /// The new state announced by a QMP event, if it changes the state.
+    pub fn from_event(event: &str) -> Option<Self> {
+        match event {
+            "STOP" => Some(Self::Paused),
+            "RESUME" | "WAKEUP" => Some(Self::Running),
+            "SUSPEND" => Some(Self::Suspended),
+            "SHUTDOWN" => Some(Self::Shutdown),
+            _ => None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        *self == Self::Running
+    }
+}
+
+#[cfg(feature = "qmp")]
+mod control {
+    use async_broadcast::{broadcast, InactiveReceiver, Sender};
+    use futures::{
+        channel::{mpsc, oneshot},
+        future::{self, Either},
+        io::{AsyncRead, AsyncWrite},
+        pin_mut, Future, Stream, StreamExt,
+    };
+
+    use crate::{qmp::run_state_event, Error, Qmp, Result, RunState};
+
+    #[derive(Debug, Clone, Copy)]
+    enum Command {
+        Powerdown,
+        Reset,
+        Pause,
+        Resume,
+        Status,
+    }
+
+    struct Request {
+        command: Command,
+        // the run state, for Command::Status
+        reply: oneshot::Sender<Result<Option<RunState>>>,
+    }
+
+    impl std::fmt::Debug for Request {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.command)
+        }
+    }
+
+    async fn execute<S>(qmp: &mut Qmp<S>, command: Command) -> Result<Option<RunState>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match command {
+            Command::Powerdown => qmp.system_powerdown().await?,
+            Command::Reset => qmp.system_reset().await?,
+            Command::Pause => qmp.stop().await?,
+            Command::Resume => qmp.cont().await?,
+            Command::Status => return qmp.run_state().await.map(Some),
+        }
+        Ok(None)
+    }
+
+    async fn serve<S>(
+        mut qmp: Qmp<S>,
+        mut requests: mpsc::UnboundedReceiver<Request>,
+        changes: Sender<RunState>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            // the event read is dropped for the commands, Qmp::next_event() allows it
+            let next = {
+                let event = qmp.next_event();
+                pin_mut!(event);
+                match future::select(requests.next(), event).await {
+                    Either::Left((request, _)) => Either::Left(request),
+                    Either::Right((event, _)) => Either::Right(event),
+                }
+            };
+            match next {
+                // the controls are gone
+                Either::Left(None) => break,
+                Either::Left(Some(request)) => {
+                    let res = execute(&mut qmp, request.command).await;
+                    let _ = request.reply.send(res);
+                }
+                Either::Right(Ok(event)) => {
+                    if let Some(state) = run_state_event(&event) {
+                        // without active receivers, the change is dropped
+                        let _ = changes.try_broadcast(state);
+                    }
+                }
+                Either::Right(Err(e)) => {
+                    log::debug!("QMP connection error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Power and run state control of a VM, over its QMP connection.
+    ///
+    /// QEMU doesn't expose these on D-Bus: the QMP connection used to set up the display
+    /// (see [`Display::new_unix_socket_with_qmp`](crate::Display::new_unix_socket_with_qmp))
+    /// can be kept for them, instead of a separate one.
+    #[derive(Debug, Clone)]
+    pub struct VmControl {
+        requests: mpsc::UnboundedSender<Request>,
+        changes: InactiveReceiver<RunState>,
+    }
+
+    impl VmControl {
+        /// Control the VM of `qmp`.
+        ///
+        /// The returned future serves the connection, it must be spawned, for example on
+        /// the runtime of the frontend. It completes when the connection is closed, or all
+        /// the controls are dropped. The calls then fail.
+        pub fn new<S>(qmp: Qmp<S>) -> (Self, impl Future<Output = ()>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+        {
+            let (requests, receiver) = mpsc::unbounded();
+            let (mut changes, inactive) = broadcast(8);
+            changes.set_overflow(true);
+            let control = Self {
+                requests,
+                changes: inactive.deactivate(),
+            };
+            (control, serve(qmp, receiver, changes))
+        }
+
+        async fn call(&self, command: Command) -> Result<Option<RunState>> {
+            let (reply, res) = oneshot::channel();
+            let closed = || Error::Failed("The QMP connection is closed".into());
+            self.requests
+                .unbounded_send(Request { command, reply })
+                .map_err(|_| closed())?;
+            res.await.map_err(|_| closed())?
+        }
+
+        /// Ask the guest to power down, like an ACPI power button press.
+        pub async fn powerdown(&self) -> Result<()> {
+            self.call(Command::Powerdown).await.map(drop)
+        }
+
+        pub async fn reset(&self) -> Result<()> {
+            self.call(Command::Reset).await.map(drop)
+        }
+
+        pub async fn pause(&self) -> Result<()> {
+            self.call(Command::Pause).await.map(drop)
+        }
+
+        pub async fn resume(&self) -> Result<()> {
+            self.call(Command::Resume).await.map(drop)
+        }
+
+        pub async fn status(&self) -> Result<RunState> {
+            self.call(Command::Status)
+                .await?
+                .ok_or_else(|| Error::Failed("No QMP status".into()))
+        }
+
+        /// A stream of the run state changes, from now on.
+        pub fn receive_status_changed(&self) -> impl Stream<Item = RunState> {
+            self.changes.activate_cloned()
+        }
+    }
+}
+
+#[cfg(feature = "qmp")]
+pub use control::VmControl;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_state() {
+        assert_eq!(RunState::from_status("paused"), RunState::Paused);
+        assert_eq!(
+            RunState::from_status("inmigrate"),
+            RunState::Other("inmigrate".into())
+        );
+        assert_eq!(RunState::from_event("RESUME"), Some(RunState::Running));
+        assert_eq!(RunState::from_event("RESET"), None);
+    }
+}
@@ -0,0 +1,411 @@
+use async_broadcast::{broadcast, Receiver, Sender};
+use async_io::Timer;
+use futures::{
+    future::{self, Either},
+    Stream,
+};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use zbus::Task;
+
+use crate::{
+    console, Console, ConsoleListenerHandler, ConsoleProxy, Cursor, ListenerConnection, MouseSet,
+    Result, RetryPolicy, RunState, Scanout, Update,
+};
+#[cfg(unix)]
+use crate::{ScanoutDMABUF, UpdateDMABUF};
+#[cfg(windows)]
+use crate::{ScanoutMap, UpdateMap};
+
+// the most idle periods between two probes of a quiet console
+const MAX_PROBE_DELAY: u32 = 32;
+
+/// Health of a watched console listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleHealth {
+    /// No event was received, even after re-registering the listener (only with
+    /// [`WatchdogOptions::probe_idle`], while the VM is running).
+    Stalled,
+    /// Events are flowing again after a stall.
+    Recovered,
+    /// Re-registering the listener failed.
+    Failed(String),
+}
+
+#[derive(Debug)]
+struct WatchedHandler<H: ConsoleListenerHandler> {
+    handler: H,
+    events: Arc<AtomicUsize>,
+    replaced: Arc<AtomicBool>,
+}
+
+impl<H: ConsoleListenerHandler> WatchedHandler<H> {
+    fn event(&self) {
+        self.events.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: ConsoleListenerHandler> ConsoleListenerHandler for WatchedHandler<H> {
+    async fn scanout(&mut self, scanout: Scanout) {
+        self.event();
+        self.handler.scanout(scanout).await
+    }
+
+    async fn update(&mut self, update: Update) {
+        self.event();
+        self.handler.update(update).await
+    }
+
+    #[cfg(windows)]
+    async fn scanout_map(&mut self, scanout: ScanoutMap) {
+        self.event();
+        self.handler.scanout_map(scanout).await
+    }
+
+    #[cfg(windows)]
+    async fn update_map(&mut self, update: UpdateMap) {
+        self.event();
+        self.handler.update_map(update).await
+    }
+
+    #[cfg(unix)]
+    async fn scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        self.event();
+        self.handler.scanout_dmabuf(scanout).await
+    }
+
+    #[cfg(unix)]
+    async fn update_dmabuf(&mut self, update: UpdateDMABUF) {
+        self.event();
+        self.handler.update_dmabuf(update).await
+    }
+
+    async fn mouse_set(&mut self, set: MouseSet) {
+        self.event();
+        self.handler.mouse_set(set).await
+    }
+
+    async fn cursor_define(&mut self, cursor: Cursor) {
+        self.event();
+        self.handler.cursor_define(cursor).await
+    }
+
+    fn disable(&mut self) {
+        self.event();
+        self.handler.disable()
+    }
+
+    fn disconnected(&mut self) {
+        // the watchdog dropped this listener in favour of a new one
+        if !self.replaced.load(Ordering::SeqCst) {
+            self.handler.disconnected();
+        }
+    }
+}
+
+struct Watched<H: ConsoleListenerHandler + Clone> {
+    proxy: ConsoleProxy<'static>,
+    #[cfg(windows)]
+    peer_pid: u32,
+    handler: H,
+    events: Arc<AtomicUsize>,
+    listener: Option<(ListenerConnection, Arc<AtomicBool>)>,
+    retry: RetryPolicy,
+}
+
+impl<H: ConsoleListenerHandler + Clone> Watched<H> {
+    async fn register(&mut self) -> Result<()> {
+        let replaced = Arc::new(AtomicBool::new(false));
+        let handler = WatchedHandler {
+            handler: self.handler.clone(),
+            events: self.events.clone(),
+            replaced: replaced.clone(),
+        };
+        let conn = console::register_listener(
+            &self.proxy,
+            #[cfg(windows)]
+            self.peer_pid,
+            handler,
+        )
+        .await?;
+        if let Some((_conn, replaced)) = self.listener.replace((conn, replaced)) {
+            replaced.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    // register again, retrying with the policy, and report the failures
+    async fn reregister(&mut self, sender: &Sender<ConsoleHealth>) -> bool {
+        let mut backoff = self.retry.backoff("console listener registration");
+        loop {
+            match self.register().await {
+                Ok(()) => return true,
+                Err(e) => {
+                    log::warn!("Failed to re-register console listener: {}", e);
+                    let _ = sender.broadcast(ConsoleHealth::Failed(e.to_string())).await;
+                    if !backoff.wait().await {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run(
+        mut self,
+        period: Duration,
+        probe_idle: bool,
+        running: Arc<AtomicBool>,
+        sender: Sender<ConsoleHealth>,
+    ) {
+        let mut probe = Probe::default();
+
+        loop {
+            let closed = match &self.listener {
+                Some((conn, _)) => Either::Left(conn.closed()),
+                None => Either::Right(future::pending()),
+            };
+            let closed = match future::select(Timer::after(period), Box::pin(closed)).await {
+                Either::Left(_) => self.listener.is_none(),
+                Either::Right(_) => true,
+            };
+            if closed {
+                // QEMU dropped the listener: a new one gets a scanout, like a probe
+                if !self.reregister(&sender).await {
+                    // don't spin on the closed connection, retry on the next period
+                    self.listener = None;
+                }
+                probe.probing();
+                continue;
+            }
+
+            let events = self.events.load(Ordering::SeqCst);
+            let running = probe_idle && running.load(Ordering::SeqCst);
+            let (health, reregister) = probe.period(events, running);
+            if let Some(health) = health {
+                let _ = sender.broadcast(health).await;
+            }
+            if reregister {
+                self.reregister(&sender).await;
+            }
+        }
+    }
+}
+
+// the stall detection of a watched console, stepped every period
+#[derive(Debug)]
+struct Probe {
+    last: usize,
+    probing: bool,
+    stalled: bool,
+    // the idle periods before the next probe, doubled by each probe
+    delay: u32,
+    idle: u32,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Self {
+            last: 0,
+            probing: false,
+            stalled: false,
+            delay: 1,
+            idle: 0,
+        }
+    }
+}
+
+impl Probe {
+    fn probing(&mut self) {
+        self.probing = true;
+        self.idle = 0;
+    }
+
+    // the health change after a period with `events` so far, and whether to probe
+    fn period(&mut self, events: usize, running: bool) -> (Option<ConsoleHealth>, bool) {
+        if events != self.last {
+            self.last = events;
+            if !self.probing {
+                // the guest is active
+                self.delay = 1;
+            }
+            self.probing = false;
+            self.idle = 0;
+            if self.stalled {
+                self.stalled = false;
+                return (Some(ConsoleHealth::Recovered), false);
+            }
+            return (None, false);
+        }
+        if !running {
+            // a stopped VM has no update: forget the pending probe
+            self.probing = false;
+            self.idle = 0;
+            return (None, false);
+        }
+
+        // A quiet guest is legit, so re-register first: QEMU replies with a new scanout.
+        // Only report a stall if that probe doesn't bring any event either.
+        let mut health = None;
+        if self.probing && !self.stalled {
+            self.stalled = true;
+            health = Some(ConsoleHealth::Stalled);
+        }
+        self.idle += 1;
+        if self.idle < self.delay {
+            return (health, false);
+        }
+        // each probe costs a full scanout: back off while the console stays quiet
+        self.idle = 0;
+        self.delay = (self.delay * 2).min(MAX_PROBE_DELAY);
+        self.probing = true;
+        (health, true)
+    }
+}
+
+/// The options of a [`ConsoleWatchdog`].
+#[derive(Debug, Clone)]
+pub struct WatchdogOptions {
+    /// The retry policy of the failed re-registrations, before the next period.
+    pub retry: RetryPolicy,
+    /// Re-register the listener of a console idle for a period, to tell a stalled listener
+    /// from a quiet guest, while the VM is running (see [`ConsoleWatchdog::set_run_state`]).
+    /// Each probe costs a full scanout, so they are less and less frequent while the
+    /// console stays idle. Enabled by default.
+    pub probe_idle: bool,
+}
+
+impl Default for WatchdogOptions {
+    fn default() -> Self {
+        Self {
+            retry: RetryPolicy::default().with_max_attempts(3),
+            probe_idle: true,
+        }
+    }
+}
+
+/// A console listener registration monitored for stalls.
+///
+/// The listener is registered again when QEMU drops it. With
+/// [`WatchdogOptions::probe_idle`], it is also registered again when no event is received
+/// for `period` while the VM is running, and a [`ConsoleHealth`] event is emitted if QEMU
+/// stays silent. Dropping the watchdog unregisters the listener.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ConsoleWatchdog {
+    receiver: Receiver<ConsoleHealth>,
+    running: Arc<AtomicBool>,
+    #[derivative(Debug = "ignore")]
+    _task: Task<()>,
+}
+
+impl ConsoleWatchdog {
+    pub async fn new<H: ConsoleListenerHandler + Clone>(
+        console: &Console,
+        handler: H,
+        period: Duration,
+    ) -> Result<Self> {
+        Self::new_with_options(console, handler, period, WatchdogOptions::default()).await
+    }
+
+    /// Like [`ConsoleWatchdog::new`], retrying the failed re-registrations with `policy`,
+    /// before the next period.
+    pub async fn new_with_policy<H: ConsoleListenerHandler + Clone>(
+        console: &Console,
+        handler: H,
+        period: Duration,
+        policy: RetryPolicy,
+    ) -> Result<Self> {
+        let opts = WatchdogOptions {
+            retry: policy,
+            ..Default::default()
+        };
+        Self::new_with_options(console, handler, period, opts).await
+    }
+
+    /// Like [`ConsoleWatchdog::new`], with `opts`.
+    pub async fn new_with_options<H: ConsoleListenerHandler + Clone>(
+        console: &Console,
+        handler: H,
+        period: Duration,
+        opts: WatchdogOptions,
+    ) -> Result<Self> {
+        let mut watched = Watched {
+            proxy: console.proxy.clone(),
+            #[cfg(windows)]
+            peer_pid: console.peer_pid(),
+            handler,
+            events: Default::default(),
+            listener: None,
+            retry: opts.retry,
+        };
+        watched.register().await?;
+
+        let (mut sender, receiver) = broadcast(4);
+        sender.set_overflow(true);
+        let running = Arc::new(AtomicBool::new(true));
+        let task = console.proxy.connection().executor().spawn(watched.run(
+            period,
+            opts.probe_idle,
+            running.clone(),
+            sender,
+        ));
+
+        Ok(Self {
+            receiver,
+            running,
+            _task: task,
+        })
+    }
+
+    /// Set the run state of the VM, running by default.
+    ///
+    /// The D-Bus display doesn't tell it: frontends following it over QMP (see
+    /// `VmControl::receive_status_changed`) should forward it, so that a stopped VM isn't
+    /// probed.
+    pub fn set_run_state(&self, state: &RunState) {
+        self.running.store(state.is_running(), Ordering::SeqCst);
+    }
+
+    pub fn receive_health(&self) -> Pin<Box<dyn Stream<Item = ConsoleHealth> + Send>> {
+        Box::pin(self.receiver.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_running_stalls() {
+        let mut probe = Probe::default();
+        // the first idle period probes, the next one reports the stall
+        assert_eq!(probe.period(0, true), (None, true));
+        assert_eq!(probe.period(0, true), (Some(ConsoleHealth::Stalled), false));
+        // backing off
+        assert_eq!(probe.period(0, true), (None, true));
+        assert_eq!(
+            probe.period(1, true),
+            (Some(ConsoleHealth::Recovered), false)
+        );
+    }
+
+    #[test]
+    fn idle_paused() {
+        let mut probe = Probe::default();
+        for _ in 0..4 {
+            assert_eq!(probe.period(0, false), (None, false));
+        }
+        // a probe pending when the VM stops isn't reported
+        assert_eq!(probe.period(0, true), (None, true));
+        assert_eq!(probe.period(0, false), (None, false));
+        assert_eq!(probe.period(0, true), (None, false));
+    }
+}
@@ -0,0 +1,172 @@
+//! The graphics updates: uncompressed bitmaps, and the pointer shapes.
+
+use qemu_display::{Cursor, Rect};
+
+const UPDATETYPE_BITMAP: u16 = 1;
+const BITMAP_DATA_HEADER_SIZE: usize = 18;
+// the largest pointer fitting in a single update, the larger are not fragmented
+const MAX_POINTER_SIZE: i32 = 64;
+
+/// The side of the bitmap tiles.
+pub const TILE_SIZE: u32 = 64;
+/// The largest update data, within the fast-path and slow-path PDU limits.
+pub const MAX_UPDATE_SIZE: usize = 0x7f00;
+
+// The tiles of a rectangle, up to TILE_SIZE.
+fn tiles(rect: &Rect) -> impl Iterator<Item = Rect> + '_ {
+    (rect.y..rect.bottom())
+        .step_by(TILE_SIZE as usize)
+        .flat_map(move |y| {
+            (rect.x..rect.right())
+                .step_by(TILE_SIZE as usize)
+                .map(move |x| {
+                    let width = TILE_SIZE.min(rect.right() - x);
+                    let height = TILE_SIZE.min(rect.bottom() - y);
+                    Rect::new(x, y, width, height)
+                })
+        })
+}
+
+// A TS_BITMAP_DATA of a frame tile, bottom-up and opaque.
+fn bitmap_data(frame: &[u8], stride: usize, tile: &Rect) -> Vec<u8> {
+    let size = tile.width as usize * tile.height as usize * 4;
+    let mut data = Vec::with_capacity(BITMAP_DATA_HEADER_SIZE + size);
+    let (right, bottom) = (tile.right() - 1, tile.bottom() - 1);
+    for v in [
+        tile.x,
+        tile.y,
+        right,
+        bottom,
+        tile.width,
+        tile.height,
+        32,
+        0,
+    ] {
+        data.extend((v as u16).to_le_bytes());
+    }
+    data.extend((size as u16).to_le_bytes());
+    for y in (tile.y..tile.bottom()).rev() {
+        let start = y as usize * stride + tile.x as usize * 4;
+        for pixel in frame[start..start + tile.width as usize * 4].chunks_exact(4) {
+            data.extend([pixel[0], pixel[1], pixel[2], 0xff]);
+        }
+    }
+    data
+}
+
+/// The bitmap updates (TS_UPDATE_BITMAP_DATA) of the damaged regions of a frame, in the
+/// pixman x8r8g8b8 format.
+///
+/// The regions are split in tiles, packed in updates up to [`MAX_UPDATE_SIZE`].
+pub fn bitmap_updates(frame: &[u8], width: u32, damage: &[Rect]) -> Vec<Vec<u8>> {
+    let stride = width as usize * 4;
+    let mut updates = vec![];
+    let mut rects: Vec<Vec<u8>> = vec![];
+    let mut size = 4;
+    for tile in damage.iter().flat_map(tiles) {
+        let rect = bitmap_data(frame, stride, &tile);
+        if size + rect.len() > MAX_UPDATE_SIZE {
+            updates.push(bitmap_update(&rects));
+            rects.clear();
+            size = 4;
+        }
+        size += rect.len();
+        rects.push(rect);
+    }
+    if !rects.is_empty() {
+        updates.push(bitmap_update(&rects));
+    }
+    updates
+}
+
+fn bitmap_update(rects: &[Vec<u8>]) -> Vec<u8> {
+    let mut update = UPDATETYPE_BITMAP.to_le_bytes().to_vec();
+    update.extend((rects.len() as u16).to_le_bytes());
+    update.extend(rects.concat());
+    update
+}
+
+/// A TS_POINTERATTRIBUTE of the cursor, with its alpha channel, if it isn't too large.
+pub fn pointer_attribute(cursor: &Cursor) -> Option<Vec<u8>> {
+    let (width, height) = (cursor.width, cursor.height);
+    if width <= 0 || height <= 0 || width > MAX_POINTER_SIZE || height > MAX_POINTER_SIZE {
+        return None;
+    }
+    let (width, height) = (width as usize, height as usize);
+    let pixels = cursor.data.get(..width * height * 4)?;
+    let mask_stride = width.div_ceil(16) * 2;
+    let mut xor: Vec<u8> = Vec::with_capacity(width * height * 4);
+    // the transparent pixels leave the screen unchanged
+    let mut and = vec![0; mask_stride * height];
+    for (row, y) in (0..height).rev().enumerate() {
+        let line = &pixels[y * width * 4..(y + 1) * width * 4];
+        xor.extend(line);
+        for (x, pixel) in line.chunks_exact(4).enumerate() {
+            if pixel[3] == 0 {
+                and[row * mask_stride + x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+
+    // 32 bits per pixel, in the cache entry 0
+    let mut attribute = vec![32, 0, 0, 0];
+    let hot_x = cursor.hot_x.clamp(0, cursor.width - 1);
+    let hot_y = cursor.hot_y.clamp(0, cursor.height - 1);
+    for v in [
+        hot_x as usize,
+        hot_y as usize,
+        width,
+        height,
+        and.len(),
+        xor.len(),
+    ] {
+        attribute.extend((v as u16).to_le_bytes());
+    }
+    attribute.extend(xor);
+    attribute.extend(and);
+    Some(attribute)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates() {
+        let (width, height) = (200, 100);
+        let mut frame = vec![0; width as usize * height as usize * 4];
+        // a blue pixel at the bottom left of the first tile
+        frame[63 * width as usize * 4] = 0xff;
+        let updates = bitmap_updates(&frame, width, &[Rect::new(0, 0, width, height)]);
+        // 8 tiles, the largest don't fit by 2 in an update
+        assert_eq!(updates.len(), 4);
+        assert!(updates.iter().all(|u| u.len() <= MAX_UPDATE_SIZE));
+        let update = &updates[0];
+        assert_eq!(update[..4], [1, 0, 1, 0]);
+        // the destination rectangle, inclusive
+        assert_eq!(update[4..12], [0, 0, 0, 0, 63, 0, 63, 0]);
+        assert_eq!(update[22..26], [0xff, 0, 0, 0xff]);
+
+        let last = &updates[3];
+        assert_eq!(last[2..4], [3, 0]);
+        assert_eq!(last[4..16], [64, 0, 64, 0, 127, 0, 99, 0, 64, 0, 36, 0]);
+    }
+
+    #[test]
+    fn pointer() {
+        let cursor = Cursor {
+            width: 2,
+            height: 1,
+            hot_x: 1,
+            hot_y: 0,
+            data: vec![0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff],
+        };
+        let attribute = pointer_attribute(&cursor).unwrap();
+        assert_eq!(
+            attribute[..16],
+            [32, 0, 0, 0, 1, 0, 0, 0, 2, 0, 1, 0, 2, 0, 8, 0]
+        );
+        // the first pixel is transparent
+        assert_eq!(attribute[24..], [0x80, 0]);
+    }
+}
@@ -0,0 +1,195 @@
+//! The clipboard virtual channel ([MS-RDPECLIP]), for the text.
+
+use std::io;
+
+use crate::pdu::Reader;
+
+/// The name of the static virtual channel.
+pub const CHANNEL_NAME: &str = "cliprdr";
+
+const CB_MONITOR_READY: u16 = 1;
+const CB_FORMAT_LIST: u16 = 2;
+const CB_FORMAT_LIST_RESPONSE: u16 = 3;
+const CB_FORMAT_DATA_REQUEST: u16 = 4;
+const CB_FORMAT_DATA_RESPONSE: u16 = 5;
+const CB_CLIP_CAPS: u16 = 7;
+
+const CB_RESPONSE_OK: u16 = 1;
+const CB_RESPONSE_FAIL: u16 = 2;
+
+const CB_CAPSTYPE_GENERAL: u16 = 1;
+const CB_CAPS_VERSION_2: u32 = 2;
+const CB_USE_LONG_FORMAT_NAMES: u32 = 0x2;
+
+const CF_UNICODETEXT: u32 = 13;
+// the size of the short format names
+const SHORT_FORMAT_NAME_SIZE: usize = 32;
+
+fn pdu(msg_type: u16, flags: u16, data: &[u8]) -> Vec<u8> {
+    let mut pdu = msg_type.to_le_bytes().to_vec();
+    pdu.extend(flags.to_le_bytes());
+    pdu.extend((data.len() as u32).to_le_bytes());
+    pdu.extend(data);
+    pdu
+}
+
+// The text of a format data response, in UTF-16 with the Windows line endings.
+fn text_data(text: &str) -> Vec<u8> {
+    text.replace("\r\n", "\n")
+        .replace('\n', "\r\n")
+        .encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+fn data_text(data: &[u8]) -> String {
+    let text: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf16_lossy(&text).replace("\r\n", "\n")
+}
+
+/// The clipboard channel of a client, exchanging text with the guest.
+#[derive(Debug, Default)]
+pub struct Cliprdr {
+    // whether the format lists have long names, as negotiated with the client
+    long_names: bool,
+    // the guest text, offered to the client
+    text: Option<String>,
+}
+
+impl Cliprdr {
+    /// The PDUs starting the channel: the server capabilities, and the monitor ready.
+    pub fn start(&self) -> Vec<Vec<u8>> {
+        let mut caps = 1u16.to_le_bytes().to_vec();
+        caps.extend([0, 0]);
+        caps.extend(CB_CAPSTYPE_GENERAL.to_le_bytes());
+        caps.extend(12u16.to_le_bytes());
+        caps.extend(CB_CAPS_VERSION_2.to_le_bytes());
+        caps.extend(CB_USE_LONG_FORMAT_NAMES.to_le_bytes());
+        vec![pdu(CB_CLIP_CAPS, 0, &caps), pdu(CB_MONITOR_READY, 0, &[])]
+    }
+
+    /// Offer the guest text to the client, returning the format list PDU.
+    pub fn offer(&mut self, text: String) -> Vec<u8> {
+        self.text = Some(text);
+        let mut list = CF_UNICODETEXT.to_le_bytes().to_vec();
+        if self.long_names {
+            list.extend([0, 0]);
+        } else {
+            list.extend([0; SHORT_FORMAT_NAME_SIZE]);
+        }
+        pdu(CB_FORMAT_LIST, 0, &list)
+    }
+
+    fn has_text(&self, list: &[u8]) -> io::Result<bool> {
+        let mut r = Reader::new(list);
+        while !r.remaining().is_empty() {
+            let format = r.u32()?;
+            if format == CF_UNICODETEXT {
+                return Ok(true);
+            }
+            if self.long_names {
+                while r.u16()? != 0 {}
+            } else {
+                r.skip(SHORT_FORMAT_NAME_SIZE)?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Handle a PDU of the client, returning the replies, and the client text when it was
+    /// received.
+    pub fn receive(&mut self, pdu_data: &[u8]) -> io::Result<(Vec<Vec<u8>>, Option<String>)> {
+        let mut r = Reader::new(pdu_data);
+        let msg_type = r.u16()?;
+        let flags = r.u16()?;
+        let len = r.u32()? as usize;
+        let data = r.bytes(len)?;
+        let mut replies = vec![];
+        let mut text = None;
+        match msg_type {
+            CB_CLIP_CAPS => {
+                let mut r = Reader::new(data);
+                let count = r.u16()?;
+                r.skip(2)?;
+                for _ in 0..count {
+                    let cap_type = r.u16()?;
+                    let len = (r.u16()? as usize).saturating_sub(4);
+                    let mut cap = Reader::new(r.bytes(len)?);
+                    if cap_type == CB_CAPSTYPE_GENERAL {
+                        cap.skip(4)?;
+                        self.long_names = cap.u32()? & CB_USE_LONG_FORMAT_NAMES != 0;
+                    }
+                }
+            }
+            CB_FORMAT_LIST => {
+                replies.push(pdu(CB_FORMAT_LIST_RESPONSE, CB_RESPONSE_OK, &[]));
+                // the client owns the clipboard, the text is requested right away
+                self.text = None;
+                if self.has_text(data)? {
+                    replies.push(pdu(
+                        CB_FORMAT_DATA_REQUEST,
+                        0,
+                        &CF_UNICODETEXT.to_le_bytes(),
+                    ));
+                }
+            }
+            CB_FORMAT_DATA_REQUEST => {
+                let format = Reader::new(data).u32()?;
+                replies.push(match &self.text {
+                    Some(text) if format == CF_UNICODETEXT => {
+                        pdu(CB_FORMAT_DATA_RESPONSE, CB_RESPONSE_OK, &text_data(text))
+                    }
+                    _ => pdu(CB_FORMAT_DATA_RESPONSE, CB_RESPONSE_FAIL, &[]),
+                });
+            }
+            CB_FORMAT_DATA_RESPONSE if flags & CB_RESPONSE_OK != 0 => {
+                text = Some(data_text(data));
+            }
+            _ => {}
+        }
+        Ok((replies, text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        let mut cliprdr = Cliprdr::default();
+        let mut caps = vec![1, 0, 0, 0, 1, 0, 12, 0, 2, 0, 0, 0];
+        caps.extend(CB_USE_LONG_FORMAT_NAMES.to_le_bytes());
+        cliprdr.receive(&pdu(CB_CLIP_CAPS, 0, &caps)).unwrap();
+
+        // a list with HTML and text, the text is requested
+        let mut list = 0xc0a0u32.to_le_bytes().to_vec();
+        list.extend(b"H\0T\0M\0L\0\0\0");
+        list.extend(CF_UNICODETEXT.to_le_bytes());
+        list.extend([0, 0]);
+        let (replies, _) = cliprdr.receive(&pdu(CB_FORMAT_LIST, 0, &list)).unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1], pdu(CB_FORMAT_DATA_REQUEST, 0, &[13, 0, 0, 0]));
+
+        let data = text_data("a\nb");
+        assert_eq!(data, b"a\0\r\0\n\0b\0\0\0");
+        let response = pdu(CB_FORMAT_DATA_RESPONSE, CB_RESPONSE_OK, &data);
+        let (_, text) = cliprdr.receive(&response).unwrap();
+        assert_eq!(text.as_deref(), Some("a\nb"));
+
+        // the guest text, on request
+        assert_eq!(
+            cliprdr.offer("c".into()),
+            pdu(CB_FORMAT_LIST, 0, &[13, 0, 0, 0, 0, 0])
+        );
+        let request = pdu(CB_FORMAT_DATA_REQUEST, 0, &[13, 0, 0, 0]);
+        let (replies, _) = cliprdr.receive(&request).unwrap();
+        let response = pdu(CB_FORMAT_DATA_RESPONSE, CB_RESPONSE_OK, &text_data("c"));
+        assert_eq!(replies, [response]);
+    }
+}
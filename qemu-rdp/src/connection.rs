@@ -0,0 +1,578 @@
+//! The connection sequence of a client, up to the capabilities exchange ([MS-RDPBCGR] 1.3.1.1).
+//!
+//! Only the TLS security is supported: the client is told so in the negotiation failure, if
+//! it didn't request it. The client is authenticated by the password of its client info.
+
+use std::{error::Error, io::prelude::*, net::TcpStream, time::Duration};
+
+use crate::{
+    pdu::{self, Frame, Reader},
+    tls::TlsConfig,
+};
+
+const TYPE_RDP_NEG_REQ: u8 = 1;
+const TYPE_RDP_NEG_RSP: u8 = 2;
+const TYPE_RDP_NEG_FAILURE: u8 = 3;
+const PROTOCOL_SSL: u32 = 1;
+const SSL_REQUIRED_BY_SERVER: u32 = 1;
+
+const CS_CORE: u16 = 0xc001;
+const CS_NET: u16 = 0xc003;
+const SC_CORE: u16 = 0x0c01;
+const SC_SECURITY: u16 = 0x0c02;
+const SC_NET: u16 = 0x0c03;
+
+const SEC_INFO_PKT: u16 = 0x0040;
+const SEC_LICENSE_PKT: u16 = 0x0080;
+const INFO_UNICODE: u32 = 0x0010;
+
+const CAPSTYPE_GENERAL: u16 = 1;
+const CAPSTYPE_BITMAP: u16 = 2;
+const CAPSTYPE_ORDER: u16 = 3;
+const CAPSTYPE_POINTER: u16 = 8;
+const CAPSTYPE_SHARE: u16 = 9;
+const CAPSTYPE_INPUT: u16 = 13;
+const CAPSTYPE_FONT: u16 = 14;
+const CAPSTYPE_VIRTUALCHANNEL: u16 = 20;
+
+const FASTPATH_OUTPUT_SUPPORTED: u16 = 0x0001;
+const LONG_CREDENTIALS_SUPPORTED: u16 = 0x0004;
+const NO_BITMAP_COMPRESSION_HDR: u16 = 0x0400;
+
+const INPUT_FLAG_SCANCODES: u16 = 0x0001;
+const INPUT_FLAG_MOUSEX: u16 = 0x0004;
+const INPUT_FLAG_FASTPATH_INPUT: u16 = 0x0008;
+const INPUT_FLAG_UNICODE: u16 = 0x0010;
+const INPUT_FLAG_FASTPATH_INPUT2: u16 = 0x0020;
+
+// the connection sequence is automated by the client, the credentials are entered before
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CTRLACTION_REQUEST_CONTROL: u16 = 1;
+const CTRLACTION_GRANTED_CONTROL: u16 = 2;
+pub const CTRLACTION_COOPERATE: u16 = 4;
+
+/// A client, as described in the connection sequence.
+#[derive(Debug, Clone, Default)]
+pub struct ClientSettings {
+    pub name: String,
+    /// The desktop size requested by the client.
+    pub size: (u16, u16),
+    /// The static virtual channels, with their ids.
+    pub channels: Vec<(String, u16)>,
+}
+
+impl ClientSettings {
+    pub fn channel_id(&self, name: &str) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, id)| *id)
+    }
+}
+
+/// The capabilities of a client, from its confirm active PDU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientCapabilities {
+    pub fastpath_output: bool,
+    pub desktop_resize: bool,
+    pub color_pointer: bool,
+}
+
+fn set_timeouts(stream: &TcpStream, timeout: Option<Duration>) -> std::io::Result<()> {
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)
+}
+
+fn read_slow(stream: &mut TcpStream) -> Result<Vec<u8>, Box<dyn Error>> {
+    match pdu::read_frame(stream)? {
+        Some(Frame::Slow(tpdu)) => Ok(tpdu),
+        Some(Frame::Fast { .. }) => Err("Unexpected fast-path PDU".into()),
+        None => Err("Client disconnected".into()),
+    }
+}
+
+// The protocols of the negotiation request of an X.224 connection request.
+fn requested_protocols(tpdu: &[u8]) -> Option<u32> {
+    if tpdu.get(1) != Some(&pdu::X224_CONNECTION_REQUEST) {
+        return None;
+    }
+    let mut data = tpdu.get(7..)?;
+    // the routing token or cookie, ended by CR LF
+    if data.starts_with(b"Cookie:") || data.starts_with(b"\x03\x00") {
+        let end = data.windows(2).position(|w| w == b"\r\n")?;
+        data = &data[end + 2..];
+    }
+    let mut r = Reader::new(data);
+    match r.u8() {
+        Ok(TYPE_RDP_NEG_REQ) => {
+            r.skip(3).ok()?;
+            r.u32().ok()
+        }
+        // a legacy client, with the standard RDP security
+        _ => Some(0),
+    }
+}
+
+/// Negotiate the TLS security with the client, and return the plain stream carrying the
+/// rest of the session, with the protocols requested by the client.
+///
+/// A stalled client times out.
+pub fn negotiate(
+    mut stream: TcpStream,
+    tls: &TlsConfig,
+) -> Result<(TcpStream, u32), Box<dyn Error>> {
+    set_timeouts(&stream, Some(HANDSHAKE_TIMEOUT))?;
+    let tpdu = read_slow(&mut stream)?;
+    let protocols = requested_protocols(&tpdu).ok_or("Invalid X.224 connection request")?;
+    if protocols & PROTOCOL_SSL == 0 {
+        let mut failure = vec![TYPE_RDP_NEG_FAILURE, 0, 8, 0];
+        failure.extend(SSL_REQUIRED_BY_SERVER.to_le_bytes());
+        stream.write_all(&pdu::connection_confirm(&failure))?;
+        return Err(format!(
+            "The client doesn't support TLS (protocols {:#x})",
+            protocols
+        )
+        .into());
+    }
+    let mut response = vec![TYPE_RDP_NEG_RSP, 0, 8, 0];
+    response.extend(PROTOCOL_SSL.to_le_bytes());
+    stream.write_all(&pdu::connection_confirm(&response))?;
+    Ok((tls.accept(stream)?, protocols))
+}
+
+// The client data blocks of an MCS connect initial PDU.
+fn parse_connect_initial(mcs: &[u8]) -> Result<ClientSettings, Box<dyn Error>> {
+    let (tag, content) = Reader::new(mcs).ber()?;
+    if tag != 0x7f {
+        return Err("Not an MCS connect initial PDU".into());
+    }
+    let mut r = Reader::new(content);
+    // the domain selectors, upward flag and domain parameters come before the user data
+    let mut user_data = &[][..];
+    for _ in 0..7 {
+        user_data = r.ber()?.1;
+    }
+    // the GCC conference create request, with the client data after its H.221 key
+    let start = user_data
+        .windows(4)
+        .position(|w| w == b"Duca")
+        .ok_or("No GCC client data")?;
+    let mut r = Reader::new(&user_data[start + 4..]);
+    let len = r.per_length()?;
+    let mut r = Reader::new(r.bytes(len)?);
+
+    let mut settings = ClientSettings::default();
+    while !r.remaining().is_empty() {
+        let block_type = r.u16()?;
+        let len = (r.u16()? as usize)
+            .checked_sub(4)
+            .ok_or("Invalid client data block")?;
+        let mut block = Reader::new(r.bytes(len)?);
+        match block_type {
+            CS_CORE => {
+                block.skip(4)?;
+                settings.size = (block.u16()?, block.u16()?);
+                block.skip(12)?;
+                let name: Vec<u16> = block
+                    .bytes(32)?
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect();
+                settings.name = String::from_utf16_lossy(&name);
+            }
+            CS_NET => {
+                let count = block.u32()?;
+                for id in (pdu::FIRST_VIRTUAL_CHANNEL..).take(count as usize) {
+                    let name = block.bytes(8)?;
+                    block.skip(4)?;
+                    let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                    settings
+                        .channels
+                        .push((String::from_utf8_lossy(name).into_owned(), id));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(settings)
+}
+
+fn data_block(block_type: u16, data: &[u8]) -> Vec<u8> {
+    let mut block = block_type.to_le_bytes().to_vec();
+    block.extend((data.len() as u16 + 4).to_le_bytes());
+    block.extend(data);
+    block
+}
+
+fn ber_integer(n: u32) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take(3).take_while(|b| **b == 0).count();
+    // keep the value positive
+    let skip = if skip > 0 && bytes[skip] & 0x80 != 0 {
+        skip - 1
+    } else {
+        skip
+    };
+    pdu::ber(&[0x02], &bytes[skip..])
+}
+
+fn connect_response(settings: &ClientSettings, protocols: u32) -> Vec<u8> {
+    let mut core = 0x0008_0004u32.to_le_bytes().to_vec();
+    core.extend(protocols.to_le_bytes());
+    core.extend(0u32.to_le_bytes());
+    // no encryption, it's done by TLS
+    let security = [0; 8];
+    let mut net = pdu::IO_CHANNEL.to_le_bytes().to_vec();
+    net.extend((settings.channels.len() as u16).to_le_bytes());
+    for (_, id) in &settings.channels {
+        net.extend(id.to_le_bytes());
+    }
+    if !settings.channels.len().is_multiple_of(2) {
+        net.extend([0, 0]);
+    }
+    let blocks = [
+        data_block(SC_CORE, &core),
+        data_block(SC_SECURITY, &security),
+        data_block(SC_NET, &net),
+    ]
+    .concat();
+
+    // the GCC conference create response
+    let mut conference = vec![0x14, 0x76, 0x0a, 0x01, 0x01, 0x00, 0x01, 0xc0, 0x00];
+    conference.extend(b"McDn");
+    conference.extend(pdu::per_length(blocks.len()));
+    conference.extend(blocks);
+    let mut gcc = vec![0x00, 0x05, 0x00, 0x14, 0x7c, 0x00, 0x01];
+    gcc.extend(pdu::per_length(conference.len()));
+    gcc.extend(conference);
+
+    let parameters: Vec<u8> = [34, 3, 0, 1, 0, 1, 0xfff8, 2]
+        .iter()
+        .flat_map(|n| ber_integer(*n))
+        .collect();
+    let response = [
+        pdu::ber(&[0x0a], &[0]),
+        pdu::ber(&[0x02], &[0]),
+        pdu::ber(&[0x30], &parameters),
+        pdu::ber(&[0x04], &gcc),
+    ]
+    .concat();
+    pdu::x224_data(&pdu::ber(&[0x7f, 0x66], &response))
+}
+
+// The password of a client info PDU, after its security header.
+fn client_password(data: &[u8]) -> Result<String, Box<dyn Error>> {
+    let mut r = Reader::new(data);
+    // the security header and the code page
+    r.skip(8)?;
+    let flags = r.u32()?;
+    let (domain, user, password) = (r.u16()? as usize, r.u16()? as usize, r.u16()? as usize);
+    // the alternate shell and working directory lengths
+    r.skip(4)?;
+    // the strings are null-terminated, out of their lengths
+    let nul = if flags & INFO_UNICODE != 0 { 2 } else { 1 };
+    r.skip(domain + nul)?;
+    r.skip(user + nul)?;
+    let password = r.bytes(password)?;
+    Ok(if flags & INFO_UNICODE != 0 {
+        let password: Vec<u16> = password
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&password)
+    } else {
+        String::from_utf8_lossy(password).into_owned()
+    })
+}
+
+// compare all the bytes, to not tell how much of the password is right
+fn same_password(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// There is no licensing, the client is told it has a valid license.
+fn license_error() -> Vec<u8> {
+    let mut pdu = SEC_LICENSE_PKT.to_le_bytes().to_vec();
+    pdu.extend([0, 0]);
+    // ERROR_ALERT, PREAMBLE_VERSION_3_0, STATUS_VALID_CLIENT, ST_NO_TRANSITION, and an
+    // empty BB_ERROR_BLOB
+    pdu.extend([0xff, 0x03, 0x10, 0x00]);
+    for n in [7u32, 2, 4] {
+        pdu.extend(n.to_le_bytes());
+    }
+    pdu::send_data_indication(pdu::IO_CHANNEL, &pdu)
+}
+
+/// Accept the client on the plain stream: the MCS domain, the channels, and the client info,
+/// up to the licensing.
+///
+/// With a `password`, the client info must have the same one. A stalled client times out.
+pub fn accept(
+    stream: &mut TcpStream,
+    protocols: u32,
+    password: Option<&str>,
+) -> Result<ClientSettings, Box<dyn Error>> {
+    set_timeouts(stream, Some(HANDSHAKE_TIMEOUT))?;
+    let tpdu = read_slow(stream)?;
+    let settings = parse_connect_initial(pdu::mcs_pdu(&tpdu)?)?;
+    stream.write_all(&connect_response(&settings, protocols))?;
+
+    loop {
+        let tpdu = read_slow(stream)?;
+        let mcs = pdu::mcs_pdu(&tpdu)?;
+        match mcs.first().map(|b| b >> 2) {
+            Some(pdu::MCS_ERECT_DOMAIN_REQUEST) => {}
+            Some(pdu::MCS_ATTACH_USER_REQUEST) => stream.write_all(&pdu::attach_user_confirm())?,
+            Some(pdu::MCS_CHANNEL_JOIN_REQUEST) => {
+                let mut r = Reader::new(mcs);
+                r.skip(3)?;
+                let channel = r.u16_be()?;
+                let joined = channel == pdu::USER_CHANNEL
+                    || channel == pdu::IO_CHANNEL
+                    || settings.channels.iter().any(|(_, id)| *id == channel);
+                stream.write_all(&pdu::channel_join_confirm(channel, joined))?;
+            }
+            Some(pdu::MCS_SEND_DATA_REQUEST) => {
+                let (_, data) = pdu::send_data_request(mcs)?;
+                let flags = Reader::new(data).u16()?;
+                if flags & SEC_INFO_PKT == 0 {
+                    return Err("Expected the client info PDU".into());
+                }
+                // the user name and domain are ignored, like the VNC authentication
+                match password {
+                    Some(password) if !same_password(&client_password(data)?, password) => {
+                        return Err("Client authentication failed".into());
+                    }
+                    _ => break,
+                }
+            }
+            Some(pdu::MCS_DISCONNECT_PROVIDER_ULTIMATUM) => {
+                return Err("Client disconnected".into());
+            }
+            _ => return Err("Unexpected MCS PDU".into()),
+        }
+    }
+    stream.write_all(&license_error())?;
+    set_timeouts(stream, None)?;
+    Ok(settings)
+}
+
+fn capability(cap_type: u16, data: &[u8]) -> Vec<u8> {
+    let mut cap = cap_type.to_le_bytes().to_vec();
+    cap.extend((data.len() as u16 + 4).to_le_bytes());
+    cap.extend(data);
+    cap
+}
+
+fn u16s(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// The demand active PDU, with the server capabilities for a desktop size.
+pub fn demand_active(width: u16, height: u16) -> Vec<u8> {
+    let general = [
+        // Windows NT, protocol version 2
+        &u16s(&[1, 3, 0x200, 0, 0])[..],
+        &u16s(&[
+            FASTPATH_OUTPUT_SUPPORTED | LONG_CREDENTIALS_SUPPORTED | NO_BITMAP_COMPRESSION_HDR,
+            0,
+            0,
+            0,
+        ]),
+        // refresh rect and suppress output
+        &[1, 1],
+    ]
+    .concat();
+    // 32 bits per pixel, resizable, multiple rectangles
+    let bitmap = u16s(&[32, 1, 1, 1, width, height, 0, 1, 1, 0, 1, 0]);
+    let mut order = vec![0; 20];
+    order.extend(u16s(&[1, 20, 0, 1, 0, 0x22]));
+    order.extend([0; 32]);
+    order.extend(u16s(&[0, 0, 0, 0]));
+    order.extend((480u32 * 480).to_le_bytes());
+    order.extend([0; 8]);
+    let pointer = u16s(&[1, 25, 25]);
+    let share = u16s(&[pdu::SERVER_CHANNEL, 0]);
+    let font = u16s(&[1, 0]);
+    let mut input = u16s(&[
+        INPUT_FLAG_SCANCODES
+            | INPUT_FLAG_MOUSEX
+            | INPUT_FLAG_FASTPATH_INPUT
+            | INPUT_FLAG_UNICODE
+            | INPUT_FLAG_FASTPATH_INPUT2,
+        0,
+    ]);
+    input.extend([0; 80]);
+    let mut virtual_channel = 0u32.to_le_bytes().to_vec();
+    virtual_channel.extend((pdu::CHANNEL_CHUNK_LENGTH as u32).to_le_bytes());
+    let caps = [
+        capability(CAPSTYPE_GENERAL, &general),
+        capability(CAPSTYPE_BITMAP, &bitmap),
+        capability(CAPSTYPE_ORDER, &order),
+        capability(CAPSTYPE_POINTER, &pointer),
+        capability(CAPSTYPE_INPUT, &input),
+        capability(CAPSTYPE_VIRTUALCHANNEL, &virtual_channel),
+        capability(CAPSTYPE_SHARE, &share),
+        capability(CAPSTYPE_FONT, &font),
+    ];
+
+    let source = b"RDP\0";
+    let mut data = pdu::SHARE_ID.to_le_bytes().to_vec();
+    data.extend((source.len() as u16).to_le_bytes());
+    data.extend((caps.iter().map(Vec::len).sum::<usize>() as u16 + 4).to_le_bytes());
+    data.extend(source);
+    data.extend(u16s(&[caps.len() as u16, 0]));
+    data.extend(caps.concat());
+    // the session id
+    data.extend(0u32.to_le_bytes());
+    pdu::share_control(pdu::PDUTYPE_DEMANDACTIVEPDU, &data)
+}
+
+/// The deactivate all PDU, before a new demand active PDU.
+pub fn deactivate_all() -> Vec<u8> {
+    let mut data = pdu::SHARE_ID.to_le_bytes().to_vec();
+    data.extend(u16s(&[1]));
+    data.push(0);
+    pdu::share_control(pdu::PDUTYPE_DEACTIVATEALLPDU, &data)
+}
+
+/// The capabilities of a confirm active PDU.
+pub fn confirm_active(data: &[u8]) -> Result<ClientCapabilities, Box<dyn Error>> {
+    let mut r = Reader::new(data);
+    r.skip(6)?;
+    let source_len = r.u16()? as usize;
+    r.skip(2 + source_len)?;
+    let count = r.u16()?;
+    r.skip(2)?;
+    let mut caps = ClientCapabilities::default();
+    for _ in 0..count {
+        let cap_type = r.u16()?;
+        let len = (r.u16()? as usize)
+            .checked_sub(4)
+            .ok_or("Invalid capability set")?;
+        let mut cap = Reader::new(r.bytes(len)?);
+        match cap_type {
+            CAPSTYPE_GENERAL => {
+                cap.skip(10)?;
+                caps.fastpath_output = cap.u16()? & FASTPATH_OUTPUT_SUPPORTED != 0;
+            }
+            CAPSTYPE_BITMAP => {
+                cap.skip(14)?;
+                caps.desktop_resize = cap.u16()? != 0;
+            }
+            CAPSTYPE_POINTER => caps.color_pointer = cap.u16()? != 0,
+            _ => {}
+        }
+    }
+    Ok(caps)
+}
+
+/// The synchronize PDU of the server.
+pub fn synchronize() -> Vec<u8> {
+    pdu::share_data(pdu::PDUTYPE2_SYNCHRONIZE, &u16s(&[1, pdu::SERVER_CHANNEL]))
+}
+
+/// The control PDU answering a control PDU of the client, if any.
+pub fn control(action: u16) -> Option<Vec<u8>> {
+    let mut data = match action {
+        CTRLACTION_COOPERATE => u16s(&[CTRLACTION_COOPERATE, 0]),
+        CTRLACTION_REQUEST_CONTROL => u16s(&[CTRLACTION_GRANTED_CONTROL, pdu::USER_CHANNEL]),
+        _ => return None,
+    };
+    let control_id = if action == CTRLACTION_COOPERATE {
+        0
+    } else {
+        pdu::SERVER_CHANNEL as u32
+    };
+    data.extend(control_id.to_le_bytes());
+    Some(pdu::share_data(pdu::PDUTYPE2_CONTROL, &data))
+}
+
+/// The font map PDU, the last of the connection sequence.
+pub fn font_map() -> Vec<u8> {
+    // no entries, first and last, of 4 bytes
+    pdu::share_data(pdu::PDUTYPE2_FONTMAP, &u16s(&[0, 0, 3, 4]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_request() {
+        let mut tpdu = vec![0x00, 0xe0, 0, 0, 0, 0, 0];
+        tpdu.extend(b"Cookie: mstshash=user\r\n");
+        tpdu.extend([0x01, 0x00, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00]);
+        assert_eq!(requested_protocols(&tpdu), Some(3));
+        assert_eq!(requested_protocols(&tpdu[..7]), Some(0));
+        assert_eq!(requested_protocols(&[0x00, 0xf0]), None);
+    }
+
+    #[test]
+    fn connect_initial() {
+        let mut core = vec![0; 4];
+        core.extend(u16s(&[1024, 768]));
+        core.extend([0; 12]);
+        core.extend(u16s(&"host".encode_utf16().collect::<Vec<_>>()));
+        core.extend([0; 24]);
+        let mut net = 2u32.to_le_bytes().to_vec();
+        net.extend(b"rdpdr\0\0\0\x00\x00\x80\x80cliprdr\0\x00\x00\xa0\xc0");
+        let blocks = [data_block(CS_CORE, &core), data_block(CS_NET, &net)].concat();
+        let mut gcc = vec![0x00, 0x05, 0x00, 0x14, 0x7c, 0x00, 0x01, 0x81, 0x00];
+        gcc.extend(b"\x08\x00\x10\x00\x01\xc0\x00Duca");
+        gcc.extend(pdu::per_length(blocks.len()));
+        gcc.extend(blocks);
+        let parameters = pdu::ber(&[0x30], &ber_integer(34));
+        let initial = [
+            pdu::ber(&[0x04], &[1]),
+            pdu::ber(&[0x04], &[1]),
+            pdu::ber(&[0x01], &[0xff]),
+            parameters.clone(),
+            parameters.clone(),
+            parameters,
+            pdu::ber(&[0x04], &gcc),
+        ]
+        .concat();
+        let settings = parse_connect_initial(&pdu::ber(&[0x7f, 0x65], &initial)).unwrap();
+        assert_eq!(settings.name, "host");
+        assert_eq!(settings.size, (1024, 768));
+        assert_eq!(settings.channel_id("cliprdr"), Some(1005));
+
+        let response = connect_response(&settings, 3);
+        assert_eq!(response[7..9], [0x7f, 0x66]);
+        // the channel ids, at the end of the server network data
+        assert_eq!(
+            response[response.len() - 8..],
+            [0xeb, 0x03, 2, 0, 0xec, 0x03, 0xed, 0x03]
+        );
+    }
+
+    #[test]
+    fn password() {
+        let mut info = vec![0x40, 0, 0, 0, 0, 0, 0, 0];
+        info.extend(INFO_UNICODE.to_le_bytes());
+        info.extend(u16s(&[0, 8, 6, 0, 0]));
+        info.extend([0, 0]);
+        info.extend(u16s(&"user".encode_utf16().collect::<Vec<_>>()));
+        info.extend([0, 0]);
+        info.extend(u16s(&"pwd".encode_utf16().collect::<Vec<_>>()));
+        info.extend([0; 6]);
+        assert_eq!(client_password(&info).unwrap(), "pwd");
+        assert!(client_password(&info[..24]).is_err());
+        assert!(same_password("pwd", "pwd"));
+        assert!(!same_password("pwd", "pw"));
+        assert!(!same_password("pwd", "pwe"));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(ber_integer(2), [0x02, 0x01, 0x02]);
+        assert_eq!(ber_integer(0xfff8), [0x02, 0x03, 0x00, 0xff, 0xf8]);
+    }
+}
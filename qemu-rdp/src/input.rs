@@ -0,0 +1,297 @@
+//! The input events of a client, in fast-path and slow-path PDUs.
+
+use std::io;
+
+use enumflags2::BitFlags;
+use qemu_display::{KeyboardModifiers, MouseButton};
+
+use crate::pdu::Reader;
+
+const FASTPATH_INPUT_EVENT_SCANCODE: u8 = 0;
+const FASTPATH_INPUT_EVENT_MOUSE: u8 = 1;
+const FASTPATH_INPUT_EVENT_MOUSEX: u8 = 2;
+const FASTPATH_INPUT_EVENT_SYNC: u8 = 3;
+const FASTPATH_INPUT_EVENT_UNICODE: u8 = 4;
+const FASTPATH_INPUT_EVENT_QOE_TIMESTAMP: u8 = 6;
+
+const FASTPATH_INPUT_KBDFLAGS_RELEASE: u8 = 0x01;
+const FASTPATH_INPUT_KBDFLAGS_EXTENDED: u8 = 0x02;
+const FASTPATH_INPUT_KBDFLAGS_EXTENDED1: u8 = 0x04;
+
+const INPUT_EVENT_SYNC: u16 = 0x0000;
+const INPUT_EVENT_SCANCODE: u16 = 0x0004;
+const INPUT_EVENT_UNICODE: u16 = 0x0005;
+const INPUT_EVENT_MOUSE: u16 = 0x8001;
+const INPUT_EVENT_MOUSEX: u16 = 0x8002;
+
+const KBDFLAGS_EXTENDED: u16 = 0x0100;
+const KBDFLAGS_EXTENDED1: u16 = 0x0200;
+const KBDFLAGS_RELEASE: u16 = 0x8000;
+
+const PTRFLAGS_HWHEEL: u16 = 0x0400;
+const PTRFLAGS_WHEEL: u16 = 0x0200;
+const PTRFLAGS_WHEEL_NEGATIVE: u16 = 0x0100;
+const PTRFLAGS_MOVE: u16 = 0x0800;
+const PTRFLAGS_DOWN: u16 = 0x8000;
+const PTRFLAGS_BUTTON1: u16 = 0x1000;
+const PTRFLAGS_BUTTON2: u16 = 0x2000;
+const PTRFLAGS_BUTTON3: u16 = 0x4000;
+const PTRXFLAGS_BUTTON1: u16 = 0x0001;
+const PTRXFLAGS_BUTTON2: u16 = 0x0002;
+
+// the scancodes of the Pause key, sent with the EXTENDED1 flag
+const SCANCODE_PAUSE: u8 = 0x1d;
+const SCANCODE_PAUSE_NUMLOCK: u8 = 0x45;
+const QNUM_PAUSE: u32 = 0xc6;
+
+/// An input event of the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Scancode {
+        code: u8,
+        extended: bool,
+        extended1: bool,
+        down: bool,
+    },
+    Unicode {
+        code: u16,
+        down: bool,
+    },
+    Mouse(MouseEvent),
+    /// The client lock keys state.
+    Sync(BitFlags<KeyboardModifiers>),
+}
+
+/// A pointer event, at an absolute position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub x: u16,
+    pub y: u16,
+    pub moved: bool,
+    /// The pressed or released button, or the wheel rotation (a press and release).
+    pub button: Option<(MouseButton, bool)>,
+}
+
+impl MouseEvent {
+    fn new(flags: u16, x: u16, y: u16) -> Self {
+        let down = flags & PTRFLAGS_DOWN != 0;
+        let negative = flags & PTRFLAGS_WHEEL_NEGATIVE != 0;
+        let button = if flags & PTRFLAGS_WHEEL != 0 {
+            let button = if negative {
+                MouseButton::WheelDown
+            } else {
+                MouseButton::WheelUp
+            };
+            Some((button, true))
+        } else if flags & PTRFLAGS_HWHEEL != 0 {
+            let button = if negative {
+                MouseButton::WheelLeft
+            } else {
+                MouseButton::WheelRight
+            };
+            Some((button, true))
+        } else if flags & PTRFLAGS_BUTTON1 != 0 {
+            Some((MouseButton::Left, down))
+        } else if flags & PTRFLAGS_BUTTON2 != 0 {
+            Some((MouseButton::Right, down))
+        } else if flags & PTRFLAGS_BUTTON3 != 0 {
+            Some((MouseButton::Middle, down))
+        } else {
+            None
+        };
+        Self {
+            x,
+            y,
+            moved: flags & PTRFLAGS_MOVE != 0,
+            button,
+        }
+    }
+
+    fn extended(flags: u16, x: u16, y: u16) -> Self {
+        let button = if flags & PTRXFLAGS_BUTTON1 != 0 {
+            Some(MouseButton::Side)
+        } else if flags & PTRXFLAGS_BUTTON2 != 0 {
+            Some(MouseButton::Extra)
+        } else {
+            None
+        };
+        Self {
+            x,
+            y,
+            moved: false,
+            button: button.map(|b| (b, flags & PTRFLAGS_DOWN != 0)),
+        }
+    }
+
+    /// Whether the button is a wheel rotation, pressed and released at once.
+    pub fn is_wheel(&self) -> bool {
+        matches!(
+            self.button,
+            Some((
+                MouseButton::WheelUp
+                    | MouseButton::WheelDown
+                    | MouseButton::WheelLeft
+                    | MouseButton::WheelRight,
+                _
+            ))
+        )
+    }
+}
+
+fn lock_keys(flags: u32) -> BitFlags<KeyboardModifiers> {
+    // the same bits as the TS_SYNC_EVENT toggle flags, without Kana Lock
+    BitFlags::from_bits_truncate(flags)
+}
+
+/// The events of a fast-path input PDU, with its header byte.
+pub fn fastpath_events(header: u8, data: &[u8]) -> io::Result<Vec<InputEvent>> {
+    let mut r = Reader::new(data);
+    let count = match (header >> 2) & 0xf {
+        0 => r.u8()?,
+        n => n,
+    };
+    let mut events = vec![];
+    for _ in 0..count {
+        let event = r.u8()?;
+        let flags = event & 0x1f;
+        match event >> 5 {
+            FASTPATH_INPUT_EVENT_SCANCODE => events.push(InputEvent::Scancode {
+                code: r.u8()?,
+                extended: flags & FASTPATH_INPUT_KBDFLAGS_EXTENDED != 0,
+                extended1: flags & FASTPATH_INPUT_KBDFLAGS_EXTENDED1 != 0,
+                down: flags & FASTPATH_INPUT_KBDFLAGS_RELEASE == 0,
+            }),
+            FASTPATH_INPUT_EVENT_MOUSE => {
+                let (flags, x, y) = (r.u16()?, r.u16()?, r.u16()?);
+                events.push(InputEvent::Mouse(MouseEvent::new(flags, x, y)));
+            }
+            FASTPATH_INPUT_EVENT_MOUSEX => {
+                let (flags, x, y) = (r.u16()?, r.u16()?, r.u16()?);
+                events.push(InputEvent::Mouse(MouseEvent::extended(flags, x, y)));
+            }
+            FASTPATH_INPUT_EVENT_SYNC => events.push(InputEvent::Sync(lock_keys(flags as u32))),
+            FASTPATH_INPUT_EVENT_UNICODE => events.push(InputEvent::Unicode {
+                code: r.u16()?,
+                down: flags & FASTPATH_INPUT_KBDFLAGS_RELEASE == 0,
+            }),
+            FASTPATH_INPUT_EVENT_QOE_TIMESTAMP => r.skip(4)?,
+            code => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unsupported fast-path input event {}", code),
+                ))
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// The events of a slow-path input PDU.
+pub fn input_events(data: &[u8]) -> io::Result<Vec<InputEvent>> {
+    let mut r = Reader::new(data);
+    let count = r.u16()?;
+    r.skip(2)?;
+    let mut events = vec![];
+    for _ in 0..count {
+        // the event time
+        r.skip(4)?;
+        let message_type = r.u16()?;
+        let (a, b, c) = (r.u16()?, r.u16()?, r.u16()?);
+        match message_type {
+            INPUT_EVENT_SYNC => {
+                events.push(InputEvent::Sync(lock_keys((c as u32) << 16 | b as u32)))
+            }
+            INPUT_EVENT_SCANCODE => events.push(InputEvent::Scancode {
+                code: b as u8,
+                extended: a & KBDFLAGS_EXTENDED != 0,
+                extended1: a & KBDFLAGS_EXTENDED1 != 0,
+                down: a & KBDFLAGS_RELEASE == 0,
+            }),
+            INPUT_EVENT_UNICODE => events.push(InputEvent::Unicode {
+                code: b,
+                down: a & KBDFLAGS_RELEASE == 0,
+            }),
+            INPUT_EVENT_MOUSE => events.push(InputEvent::Mouse(MouseEvent::new(a, b, c))),
+            INPUT_EVENT_MOUSEX => events.push(InputEvent::Mouse(MouseEvent::extended(a, b, c))),
+            _ => {}
+        }
+    }
+    Ok(events)
+}
+
+/// Translates the client scancodes to qnum (the QEMU scancodes).
+#[derive(Debug, Default)]
+pub struct Scancodes {
+    // the Pause key is sent as Ctrl and Num Lock scancodes
+    pause: bool,
+}
+
+impl Scancodes {
+    pub fn qnum(&mut self, code: u8, extended: bool, extended1: bool) -> Option<u32> {
+        if extended1 {
+            self.pause = code == SCANCODE_PAUSE;
+            return Some(QNUM_PAUSE).filter(|_| self.pause);
+        }
+        if std::mem::take(&mut self.pause) && code == SCANCODE_PAUSE_NUMLOCK {
+            return None;
+        }
+        if code == 0 || code >= 0x80 {
+            return None;
+        }
+        Some(if extended {
+            code as u32 | 0x80
+        } else {
+            code as u32
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scancodes() {
+        let mut scancodes = Scancodes::default();
+        // a, Right Ctrl
+        assert_eq!(scancodes.qnum(0x1e, false, false), Some(0x1e));
+        assert_eq!(scancodes.qnum(0x1d, true, false), Some(0x9d));
+        // Pause
+        assert_eq!(scancodes.qnum(0x1d, false, true), Some(QNUM_PAUSE));
+        assert_eq!(scancodes.qnum(0x45, false, false), None);
+        // Num Lock
+        assert_eq!(scancodes.qnum(0x45, false, false), Some(0x45));
+    }
+
+    #[test]
+    fn fastpath() {
+        // a release of Right Ctrl, a left click, a wheel rotation down, and the Caps Lock
+        let data = b"\x03\x1d\x20\x00\x90\x0a\x00\x14\x00\x20\x88\x03\x00\x00\x00\x00\x64";
+        let events = fastpath_events(0x10, data).unwrap();
+        assert_eq!(
+            events,
+            [
+                InputEvent::Scancode {
+                    code: 0x1d,
+                    extended: true,
+                    extended1: false,
+                    down: false,
+                },
+                InputEvent::Mouse(MouseEvent {
+                    x: 10,
+                    y: 20,
+                    moved: false,
+                    button: Some((MouseButton::Left, true)),
+                }),
+                InputEvent::Mouse(MouseEvent {
+                    x: 0,
+                    y: 0,
+                    moved: false,
+                    button: Some((MouseButton::WheelDown, true)),
+                }),
+                InputEvent::Sync(KeyboardModifiers::Caps.into()),
+            ]
+        );
+        assert!(fastpath_events(0x04, &[0xe0]).is_err());
+    }
+}
@@ -0,0 +1,828 @@
+use std::{
+    borrow::Borrow,
+    error::Error,
+    io::prelude::*,
+    net::{Shutdown, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{mpsc, Arc},
+    thread, time,
+};
+
+use clap::Parser;
+use cliprdr::Cliprdr;
+use connection::{ClientCapabilities, ClientSettings};
+use enumflags2::BitFlags;
+use futures_util::{lock::Mutex, StreamExt};
+use input::{InputEvent, MouseEvent, Scancodes};
+use pdu::{ChannelReader, Frame, Reader, Update};
+use qemu_display::{
+    AdaptiveSink, Console, DamageTracker, Display, FramePathMode, FramePathPolicy,
+    FrameSinkListener, FramebufferEvent, GuestDefaults, GuestOs, KeyTranslation, KeyboardLeds,
+    ModifierTracker, Session, SessionOptions, SharedFramebuffer, SshTunnel, TextClipboard, VMProxy,
+    WakeMethod,
+};
+use tls::TlsConfig;
+
+mod bitmap;
+mod cliprdr;
+mod connection;
+mod input;
+mod pdu;
+mod tls;
+
+#[derive(Parser, Debug)]
+struct Cli {
+    /// IP address, on loopback unless the clients are authenticated with --password-file
+    #[clap(short, long, default_value = "127.0.0.1")]
+    address: std::net::IpAddr,
+    /// IP port number
+    #[clap(short, long, default_value = "3389")]
+    port: u16,
+    #[clap(short, long)]
+    dbus_address: Option<String>,
+    /// Connect to the session bus of a remote host ([user@]host), through ssh
+    #[clap(long, conflicts_with = "dbus-address")]
+    ssh: Option<String>,
+    /// VM name
+    #[clap(long)]
+    vm_name: Option<String>,
+    /// Wait for the VM to be available
+    #[clap(short, long)]
+    wait: bool,
+    /// Console index
+    #[clap(short, long, default_value = "0")]
+    console: u32,
+    /// TLS certificate (PEM), a self-signed certificate is generated by default
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// TLS private key (PEM)
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// File containing the password the clients must give, with any user name
+    #[clap(long)]
+    password_file: Option<PathBuf>,
+    /// Report the key translations
+    #[clap(long)]
+    debug_keys: bool,
+    /// Wake the guest display when a client connects: none, mouse or key
+    #[clap(long, default_value = "none")]
+    wake: WakeMethod,
+    /// The guest OS (linux, windows, macos...), for the keyboard and mouse defaults. By
+    /// default, it is asked to the guest agent
+    #[clap(long)]
+    guest_os: Option<GuestOs>,
+    /// The maximum screen updates per second, 0 for no limit
+    #[clap(long, default_value = "60")]
+    max_fps: u32,
+}
+
+#[derive(Debug)]
+enum Event {
+    ConsoleUpdate(qemu_display::Rect),
+    // the cursor shape changed
+    Cursor,
+    // the cursor moved, or was shown or hidden
+    Mouse,
+    Leds(KeyboardLeds),
+    // the guest clipboard text
+    GuestText(String),
+    // a new client, past the connection sequence: it replaces the current one
+    Connected(TcpStream, ClientSettings),
+    Rdp(Frame),
+    Disconnected,
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct Client {
+    #[derivative(Debug = "ignore")]
+    server: Server,
+    stream: TcpStream,
+    settings: ClientSettings,
+    caps: ClientCapabilities,
+    // the connection is finalized, the updates can be sent
+    active: bool,
+    // the client doesn't want updates, while minimized
+    suppressed: bool,
+    damage: DamageTracker,
+    last_update: Option<time::Instant>,
+    scancodes: Scancodes,
+    last_pointer: Option<(u16, u16)>,
+    // the cursor visibility sent to the client
+    pointer_visible: Option<bool>,
+    clipboard_channel: Option<u16>,
+    channel_reader: ChannelReader,
+    cliprdr: Cliprdr,
+    dimensions: (u16, u16),
+}
+
+impl Client {
+    fn new(
+        server: Server,
+        stream: TcpStream,
+        settings: ClientSettings,
+        dimensions: (u16, u16),
+    ) -> Self {
+        let clipboard_channel = settings
+            .channel_id(cliprdr::CHANNEL_NAME)
+            .filter(|_| server.clipboard.is_some());
+        Self {
+            server,
+            stream,
+            settings,
+            caps: Default::default(),
+            active: false,
+            suppressed: false,
+            damage: DamageTracker::new(),
+            last_update: None,
+            scancodes: Scancodes::default(),
+            last_pointer: None,
+            pointer_visible: None,
+            clipboard_channel,
+            channel_reader: ChannelReader::default(),
+            cliprdr: Cliprdr::default(),
+            dimensions,
+        }
+    }
+
+    fn update_pending(&self) -> bool {
+        self.active && !self.suppressed && !self.damage.is_empty()
+    }
+
+    // the time to wait before sending the pending update
+    fn update_delay(&self) -> Option<time::Duration> {
+        let max_fps = self.server.max_fps;
+        let last = self.last_update.filter(|_| max_fps > 0)?;
+        let next = last + time::Duration::from_secs(1) / max_fps;
+        next.checked_duration_since(time::Instant::now())
+    }
+
+    fn send_update(&mut self, update: Update) -> Result<(), Box<dyn Error>> {
+        let pdu = if self.caps.fastpath_output {
+            update.fastpath()
+        } else {
+            update.slowpath()
+        };
+        Ok(self.stream.write_all(&pdu)?)
+    }
+
+    fn send_channel(&mut self, channel: u16, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        for pdu in pdu::channel_pdus(channel, data) {
+            self.stream.write_all(&pdu)?;
+        }
+        Ok(())
+    }
+
+    // the connection is finalized, or the client reactivated after a resize
+    async fn activate(&mut self) -> Result<(), Box<dyn Error>> {
+        let first = !self.active && self.last_update.is_none();
+        self.active = true;
+        let rect = self.server.framebuffer.lock().framebuffer.rect();
+        self.damage.add_all(rect);
+        self.pointer_visible = None;
+        self.send_pointer(true)?;
+        let leds = self.server.inner.lock().await.console.keyboard.leds().await;
+        if let Ok(leds) = leds {
+            self.send_leds(leds)?;
+        }
+        if let (true, Some(channel)) = (first, self.clipboard_channel) {
+            for pdu in self.cliprdr.start() {
+                self.send_channel(channel, &pdu)?;
+            }
+        }
+        Ok(())
+    }
+
+    // reactivate the client with the new desktop size, if it supports it
+    fn desktop_resize(&mut self) -> Result<bool, Box<dyn Error>> {
+        let (width, height) = self.server.dimensions();
+        if (width, height) == self.dimensions || !self.caps.desktop_resize {
+            return Ok(false);
+        }
+        self.dimensions = (width, height);
+        self.active = false;
+        self.stream.write_all(&connection::deactivate_all())?;
+        self.stream
+            .write_all(&connection::demand_active(width, height))?;
+        Ok(true)
+    }
+
+    fn send_framebuffer_update(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.update_pending() || self.update_delay().is_some() || self.desktop_resize()? {
+            return Ok(());
+        }
+        let damage = self.damage.take();
+        let (width, height) = self.dimensions;
+        let updates = {
+            let state = self.server.framebuffer.lock();
+            let fb = &state.framebuffer;
+            // the client may have kept its size
+            let bounds =
+                fb.rect()
+                    .intersect(&qemu_display::Rect::new(0, 0, width as _, height as _));
+            let damage: Vec<_> = damage
+                .iter()
+                .map(|r| r.intersect(&bounds))
+                .filter(|r| !r.is_empty())
+                .collect();
+            bitmap::bitmap_updates(fb.data(), fb.width(), &damage)
+        };
+        for update in updates {
+            self.send_update(Update::Bitmap(&update))?;
+        }
+        self.last_update = Some(time::Instant::now());
+        Ok(())
+    }
+
+    // send the guest cursor: the shape when it changed, or when the cursor is shown or hidden
+    fn send_pointer(&mut self, shape_changed: bool) -> Result<(), Box<dyn Error>> {
+        if !self.active {
+            return Ok(());
+        }
+        let attribute = {
+            let state = self.server.framebuffer.lock();
+            let visible = state.mouse.is_some();
+            if !shape_changed && self.pointer_visible == Some(visible) {
+                return Ok(());
+            }
+            self.pointer_visible = Some(visible);
+            match (&state.cursor, visible) {
+                (_, false) => None,
+                (Some(cursor), true) => Some(bitmap::pointer_attribute(cursor)),
+                (None, true) => Some(None),
+            }
+        };
+        match attribute {
+            None => self.send_update(Update::PointerHidden),
+            Some(Some(attribute)) => self.send_update(Update::Pointer(&attribute)),
+            Some(None) => self.send_update(Update::PointerDefault),
+        }
+    }
+
+    fn send_leds(&mut self, leds: KeyboardLeds) -> Result<(), Box<dyn Error>> {
+        if !self.active {
+            return Ok(());
+        }
+        let flags =
+            leds.scroll_lock as u8 | (leds.num_lock as u8) << 1 | (leds.caps_lock as u8) << 2;
+        let pdu = pdu::share_data(pdu::PDUTYPE2_SET_KEYBOARD_INDICATORS, &[0, 0, flags, 0]);
+        Ok(self.stream.write_all(&pdu)?)
+    }
+
+    fn send_guest_text(&mut self, text: String) -> Result<(), Box<dyn Error>> {
+        match self.clipboard_channel {
+            Some(channel) if self.last_update.is_some() => {
+                let pdu = self.cliprdr.offer(text);
+                self.send_channel(channel, &pdu)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn clipboard_data(&mut self, channel: u16, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let pdu = match self.channel_reader.push(data)? {
+            Some(pdu) => pdu,
+            None => return Ok(()),
+        };
+        let (replies, text) = self.cliprdr.receive(&pdu)?;
+        for reply in replies {
+            self.send_channel(channel, &reply)?;
+        }
+        if let (Some(text), Some(clipboard)) = (text, &self.server.clipboard) {
+            clipboard.client_text(text).await?;
+        }
+        Ok(())
+    }
+
+    async fn key_event(&mut self, code: u8, extended: bool, extended1: bool, down: bool) {
+        let qnum = self.scancodes.qnum(code, extended, extended1);
+        let inner = self.server.inner.lock().await;
+        let keyboard = &inner.console.keyboard;
+        let translation = KeyTranslation {
+            press: down,
+            keyval: None,
+            keycode: code as u32 | if extended { 0xe000 } else { 0 },
+            keymap: "rdp",
+            qnum,
+        };
+        if let Some(report) = keyboard.trace_key(&translation).await {
+            eprintln!("{}", report);
+        }
+        let qnum = match qnum {
+            Some(qnum) => self.server.guest.map_qnum(qnum),
+            None => return,
+        };
+        let res = if down {
+            keyboard.press(qnum).await
+        } else {
+            keyboard.release(qnum).await
+        };
+        if let Err(e) = res {
+            eprintln!("Failed to send the key {:#x}: {}", qnum, e);
+        }
+    }
+
+    async fn mouse_event(&mut self, event: MouseEvent) -> Result<(), Box<dyn Error>> {
+        let inner = self.server.inner.lock().await;
+        let mouse = &inner.console.mouse;
+        let absolute = mouse.is_absolute().await.unwrap_or(true);
+        let (x, y) = (event.x, event.y);
+        if absolute && self.last_pointer != Some((x, y)) {
+            let (width, height) = {
+                let state = self.server.framebuffer.lock();
+                (state.framebuffer.width(), state.framebuffer.height())
+            };
+            let (x, y) = (
+                (x as u32).min(width.saturating_sub(1)),
+                (y as u32).min(height.saturating_sub(1)),
+            );
+            if let Err(err) = mouse.set_abs_position(x, y).await {
+                eprintln!("Error setting mouse position: {}", err);
+            }
+        } else if let (false, Some((last_x, last_y))) = (absolute, self.last_pointer) {
+            let (dx, dy) = (x as i32 - last_x as i32, y as i32 - last_y as i32);
+            if (dx, dy) != (0, 0) {
+                if let Err(err) = mouse.rel_motion(dx, dy).await {
+                    eprintln!("Error moving the mouse: {}", err);
+                }
+            }
+        }
+        self.last_pointer = Some((x, y));
+        match event.button {
+            Some((button, _)) if event.is_wheel() => {
+                mouse.press(button).await?;
+                mouse.release(button).await?;
+            }
+            Some((button, true)) => mouse.press(button).await?,
+            Some((button, false)) => mouse.release(button).await?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    async fn input_event(&mut self, event: InputEvent) -> Result<(), Box<dyn Error>> {
+        match event {
+            InputEvent::Scancode {
+                code,
+                extended,
+                extended1,
+                down,
+            } => self.key_event(code, extended, extended1, down).await,
+            InputEvent::Unicode { code, down: true } => {
+                let inner = self.server.inner.lock().await;
+                if let Some(c) = char::from_u32(code as u32) {
+                    if let Err(e) = inner.console.keyboard.type_text(&c.to_string()).await {
+                        eprintln!("Failed to type {:?}: {}", c, e);
+                    }
+                }
+            }
+            InputEvent::Unicode { down: false, .. } => {}
+            InputEvent::Mouse(event) => self.mouse_event(event).await?,
+            InputEvent::Sync(host) => {
+                let inner = self.server.inner.lock().await;
+                inner.modifiers.sync(host, BitFlags::all()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_share_data(&mut self, data: &[u8]) -> Result<bool, Box<dyn Error>> {
+        let (pdu_type2, data) = pdu::read_share_data(data)?;
+        let mut r = Reader::new(data);
+        match pdu_type2 {
+            pdu::PDUTYPE2_SYNCHRONIZE => self.stream.write_all(&connection::synchronize())?,
+            pdu::PDUTYPE2_CONTROL => {
+                if let Some(reply) = connection::control(r.u16()?) {
+                    self.stream.write_all(&reply)?;
+                }
+            }
+            pdu::PDUTYPE2_FONTLIST => {
+                self.stream.write_all(&connection::font_map())?;
+                self.activate().await?;
+            }
+            pdu::PDUTYPE2_INPUT => {
+                for event in input::input_events(data)? {
+                    self.input_event(event).await?;
+                }
+            }
+            pdu::PDUTYPE2_REFRESH_RECT => {
+                for _ in 0..r.u8()? {
+                    r.skip(3)?;
+                    let (left, top) = (r.u16()? as u32, r.u16()? as u32);
+                    let (right, bottom) = (r.u16()? as u32, r.u16()? as u32);
+                    let rect = qemu_display::Rect::new(
+                        left,
+                        top,
+                        (right + 1).saturating_sub(left),
+                        (bottom + 1).saturating_sub(top),
+                    );
+                    self.damage.add(rect);
+                }
+            }
+            pdu::PDUTYPE2_SUPPRESS_OUTPUT => {
+                self.suppressed = r.u8()? == 0;
+                if !self.suppressed {
+                    let rect = self.server.framebuffer.lock().framebuffer.rect();
+                    self.damage.add_all(rect);
+                }
+            }
+            pdu::PDUTYPE2_SHUTDOWN_REQUEST => return Ok(false),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    async fn handle_frame(&mut self, frame: Frame) -> Result<bool, Box<dyn Error>> {
+        let tpdu = match frame {
+            Frame::Fast { header, data } => {
+                for event in input::fastpath_events(header, &data)? {
+                    self.input_event(event).await?;
+                }
+                return Ok(true);
+            }
+            Frame::Slow(tpdu) => tpdu,
+        };
+        let mcs = pdu::mcs_pdu(&tpdu)?;
+        match mcs.first().map(|b| b >> 2) {
+            Some(pdu::MCS_SEND_DATA_REQUEST) => {}
+            Some(pdu::MCS_DISCONNECT_PROVIDER_ULTIMATUM) => return Ok(false),
+            _ => return Ok(true),
+        }
+        let (channel, data) = pdu::send_data_request(mcs)?;
+        if Some(channel) == self.clipboard_channel {
+            self.clipboard_data(channel, data).await?;
+            return Ok(true);
+        }
+        if channel != pdu::IO_CHANNEL {
+            return Ok(true);
+        }
+        match pdu::read_share_control(data)? {
+            (pdu::PDUTYPE_CONFIRMACTIVEPDU, data) => {
+                self.caps = connection::confirm_active(data)?;
+                if !self.caps.fastpath_output {
+                    println!("The client doesn't support the fast-path updates");
+                }
+            }
+            (pdu::PDUTYPE_DATAPDU, data) => return self.handle_share_data(data).await,
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    async fn handle_event(&mut self, event: Option<Event>) -> Result<bool, Box<dyn Error>> {
+        match event {
+            Some(Event::Rdp(frame)) => return self.handle_frame(frame).await,
+            Some(Event::ConsoleUpdate(rect)) => self.damage.add(rect),
+            Some(Event::Cursor) => self.send_pointer(true)?,
+            Some(Event::Mouse) => self.send_pointer(false)?,
+            Some(Event::Leds(leds)) => self.send_leds(leds)?,
+            Some(Event::GuestText(text)) => self.send_guest_text(text)?,
+            // handled by the client loop
+            Some(Event::Connected(..)) => {}
+            Some(Event::Disconnected) => return Ok(false),
+            None => self.send_framebuffer_update()?,
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug)]
+struct ServerInner {
+    console: Console,
+    modifiers: ModifierTracker,
+    _leds_task: zbus::Task<()>,
+}
+
+#[derive(Clone, Debug)]
+struct Server {
+    session: Session,
+    max_fps: u32,
+    guest: GuestDefaults,
+    clipboard: Option<Arc<TextClipboard>>,
+    framebuffer: SharedFramebuffer,
+    tx: mpsc::Sender<Event>,
+    inner: Arc<Mutex<ServerInner>>,
+}
+
+impl Server {
+    async fn new(
+        session: Session,
+        console: Console,
+        max_fps: u32,
+        tx: mpsc::Sender<Event>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let width = console.width().await?;
+        let height = console.height().await?;
+        let framebuffer = SharedFramebuffer::new(width, height)?;
+        let modifiers = ModifierTracker::new(&console.keyboard).await?;
+        let guest = session.guest_defaults().await;
+        let mut leds = console.keyboard.receive_leds_changed().await;
+        let leds_tx = tx.clone();
+        let leds_task = console
+            .keyboard
+            .inner()
+            .connection()
+            .executor()
+            .spawn(async move {
+                while let Some(leds) = leds.next().await {
+                    if leds_tx.send(Event::Leds(leds)).is_err() {
+                        return;
+                    }
+                }
+            });
+        let clipboard = match session.display().clipboard().await {
+            Ok(Some(clipboard)) => {
+                let tx = tx.clone();
+                let guest_text = move |text| {
+                    let _ = tx.send(Event::GuestText(text));
+                };
+                match TextClipboard::new(clipboard, guest, guest_text).await {
+                    Ok(clipboard) => Some(Arc::new(clipboard)),
+                    Err(e) => {
+                        eprintln!("Failed to register the clipboard: {}", e);
+                        None
+                    }
+                }
+            }
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Failed to get the clipboard: {}", e);
+                None
+            }
+        };
+        Ok(Self {
+            session,
+            max_fps,
+            guest,
+            clipboard,
+            framebuffer,
+            tx,
+            inner: Arc::new(Mutex::new(ServerInner {
+                console,
+                modifiers,
+                _leds_task: leds_task,
+            })),
+        })
+    }
+
+    async fn stop_console(&self) {
+        self.inner.lock().await.console.unregister_listener();
+    }
+
+    async fn run_console(&self) -> Result<(), Box<dyn Error>> {
+        let inner = self.inner.lock().await;
+        let tx = self.tx.clone();
+        let sink = self
+            .framebuffer
+            .sink(move |event| framebuffer_event(&tx, event));
+        // the GL displays are read back, the clients get regular frames
+        let (sink, _control) = AdaptiveSink::new(
+            sink,
+            FramePathPolicy {
+                mode: FramePathMode::Copy,
+                ..Default::default()
+            },
+        );
+        inner
+            .console
+            .register_listener(FrameSinkListener::new(sink))
+            .await?;
+        Ok(())
+    }
+
+    // the desktop size, limited to the 16-bit coordinates
+    fn dimensions(&self) -> (u16, u16) {
+        let state = self.framebuffer.lock();
+        let fb = &state.framebuffer;
+        let max = u16::MAX as u32;
+        (fb.width().min(max) as u16, fb.height().min(max) as u16)
+    }
+
+    // serve the client until it leaves, or another one connects: it is returned then
+    async fn serve_client(
+        &self,
+        mut stream: TcpStream,
+        settings: ClientSettings,
+        rx: &mpsc::Receiver<Event>,
+    ) -> Result<Option<(TcpStream, ClientSettings)>, Box<dyn Error>> {
+        println!("Client {:?} connected", settings.name);
+        let (width, height) = self.dimensions();
+        stream.write_all(&connection::demand_active(width, height))?;
+
+        let tx = self.tx.clone();
+        let mut reader = stream.try_clone()?;
+        let reader_thread = thread::spawn(move || loop {
+            match pdu::read_frame(&mut reader) {
+                Ok(Some(frame)) => {
+                    if tx.send(Event::Rdp(frame)).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => {
+                    let _ = tx.send(Event::Disconnected);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Client read error: {}", e);
+                    let _ = tx.send(Event::Disconnected);
+                    return;
+                }
+            }
+        });
+
+        let mut client = Client::new(self.clone(), stream, settings, (width, height));
+        self.run_console().await?;
+        {
+            let inner = self.inner.lock().await;
+            if let Err(e) = self.session.viewer_connected(&inner.console).await {
+                eprintln!("Failed to wake the display: {}", e);
+            }
+        }
+        let res = client_loop(&mut client, rx).await;
+        self.stop_console().await;
+
+        // the events of this client are dropped, the next one starts afresh
+        let _ = client.stream.shutdown(Shutdown::Both);
+        let _ = reader_thread.join();
+        let mut next = res?;
+        while let Ok(event) = rx.try_recv() {
+            if let Event::Connected(stream, settings) = event {
+                next = Some((stream, settings));
+            }
+        }
+        Ok(next)
+    }
+}
+
+fn framebuffer_event(tx: &mpsc::Sender<Event>, event: FramebufferEvent) {
+    let event = match event {
+        FramebufferEvent::Resized { width, height } => {
+            Event::ConsoleUpdate(qemu_display::Rect::new(0, 0, width, height))
+        }
+        FramebufferEvent::Damage(rect) => Event::ConsoleUpdate(rect),
+        FramebufferEvent::Cursor => Event::Cursor,
+        FramebufferEvent::Mouse => Event::Mouse,
+        FramebufferEvent::Disconnected => return,
+    };
+    let _ = tx.send(event);
+}
+
+// the client events, until it disconnects or a new client replaces it
+async fn client_loop(
+    client: &mut Client,
+    rx: &mpsc::Receiver<Event>,
+) -> Result<Option<(TcpStream, ClientSettings)>, Box<dyn Error>> {
+    loop {
+        let ev = if client.update_pending() {
+            // the update is deferred to the maximum rate, the events are handled in the
+            // meantime
+            let delay = client.update_delay().unwrap_or_default();
+            match rx.recv_timeout(delay) {
+                Ok(e) => Some(e),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(e) => {
+                    return Err(e.into());
+                }
+            }
+        } else {
+            Some(rx.recv()?)
+        };
+        if let Some(Event::Connected(stream, settings)) = ev {
+            println!(
+                "Client {:?} disconnected by a new client",
+                client.settings.name
+            );
+            return Ok(Some((stream, settings)));
+        }
+        if !client.handle_event(ev).await? {
+            return Ok(None);
+        }
+    }
+}
+
+// the connection sequence of a client, on its own thread so a stalled client doesn't block
+// the others
+fn handshake(
+    stream: TcpStream,
+    tls: &TlsConfig,
+    password: Option<&str>,
+    tx: &mpsc::Sender<Event>,
+) -> Result<(), Box<dyn Error>> {
+    let (mut stream, protocols) = connection::negotiate(stream, tls)?;
+    let settings = connection::accept(&mut stream, protocols, password)?;
+    let _ = tx.send(Event::Connected(stream, settings));
+    Ok(())
+}
+
+fn accept_clients(
+    listener: TcpListener,
+    tls: TlsConfig,
+    password: Option<Arc<str>>,
+    tx: mpsc::Sender<Event>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a client: {}", e);
+                continue;
+            }
+        };
+        let (tls, password, tx) = (tls.clone(), password.clone(), tx.clone());
+        thread::spawn(move || {
+            if let Err(e) = handshake(stream, &tls, password.as_deref(), &tx) {
+                eprintln!("Client connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::parse();
+    let password: Option<Arc<str>> = match &args.password_file {
+        Some(path) => {
+            let password = std::fs::read_to_string(path)?;
+            Some(password.lines().next().unwrap_or_default().into())
+        }
+        None => None,
+    };
+    // there is no NLA (CredSSP), only the password of the client info
+    if password.is_none() && !args.address.is_loopback() {
+        return Err(format!(
+            "The RDP clients aren't authenticated, refusing to listen on {}: use a loopback \
+             address and tunnel the remote clients (ex: with ssh -L), or --password-file",
+            args.address
+        )
+        .into());
+    }
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => TlsConfig::new(cert, key)?,
+        _ => TlsConfig::self_signed("localhost")?,
+    };
+    qemu_display::set_key_debug(args.debug_keys);
+
+    // the tunnel is kept open until the server exits
+    let tunnel = match &args.ssh {
+        Some(destination) => Some(SshTunnel::session_bus(destination).await?),
+        None => None,
+    };
+    let dbus_address = tunnel
+        .as_ref()
+        .map(SshTunnel::address)
+        .or(args.dbus_address);
+    let dbus = if let Some(addr) = dbus_address {
+        zbus::ConnectionBuilder::address(addr.borrow())?
+            .build()
+            .await
+    } else {
+        zbus::Connection::session().await
+    }?;
+
+    let dest = Display::lookup(&dbus, args.wait, args.vm_name.as_deref())
+        .await?
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| "org.qemu".into());
+    let vm_name = VMProxy::builder(&dbus)
+        .destination(dest.as_str())?
+        .build()
+        .await?
+        .name()
+        .await?;
+    let listener = TcpListener::bind((args.address, args.port))?;
+
+    let display = Display::new(&dbus, Some(dest)).await?;
+    let opts = SessionOptions {
+        wake: args.wake,
+        guest_os: args.guest_os,
+    };
+    let session = Session::new(display, opts);
+    let console = session.console(args.console).await?;
+    let (tx, rx) = mpsc::channel();
+    let server = Server::new(session, console, args.max_fps, tx.clone()).await?;
+    println!("Serving {} on {}", vm_name, listener.local_addr()?);
+    thread::spawn(move || accept_clients(listener, tls, password, tx));
+
+    // the clients share the console: the last one connected is served
+    let mut next = None;
+    loop {
+        let (stream, settings) = match next.take() {
+            Some(client) => client,
+            None => match rx.recv()? {
+                Event::Connected(stream, settings) => (stream, settings),
+                // the console events without a client
+                _ => continue,
+            },
+        };
+        match server.serve_client(stream, settings, &rx).await {
+            Ok(client) => next = client,
+            Err(e) => eprintln!("Client error: {}", e),
+        }
+    }
+}
+
+fn main() {
+    if let Err(e) = async_io::block_on(run()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,505 @@
+//! The RDP PDUs: the slow-path framing (TPKT, X.224 and MCS), the fast-path framing, and
+//! the share control and data headers ([MS-RDPBCGR]).
+
+use std::{
+    convert::TryInto,
+    io::{self, Read},
+};
+
+const TPKT_VERSION: u8 = 3;
+// the X.224 data TPDU header
+const X224_DATA: [u8; 3] = [0x02, 0xf0, 0x80];
+pub const X224_CONNECTION_REQUEST: u8 = 0xe0;
+const X224_CONNECTION_CONFIRM: u8 = 0xd0;
+
+// the MCS domain PDUs, in the 6 upper bits of the first byte
+pub const MCS_ERECT_DOMAIN_REQUEST: u8 = 1;
+pub const MCS_DISCONNECT_PROVIDER_ULTIMATUM: u8 = 8;
+pub const MCS_ATTACH_USER_REQUEST: u8 = 10;
+const MCS_ATTACH_USER_CONFIRM: u8 = 11;
+pub const MCS_CHANNEL_JOIN_REQUEST: u8 = 14;
+const MCS_CHANNEL_JOIN_CONFIRM: u8 = 15;
+pub const MCS_SEND_DATA_REQUEST: u8 = 25;
+const MCS_SEND_DATA_INDICATION: u8 = 26;
+
+/// The first channel id, the user ids and channel ids are offsets from it in the PDUs.
+pub const MCS_BASE_CHANNEL: u16 = 1001;
+/// The channel of the server, the source of its PDUs.
+pub const SERVER_CHANNEL: u16 = 1002;
+/// The I/O channel, carrying the share PDUs.
+pub const IO_CHANNEL: u16 = 1003;
+/// The user id of the client, and its channel.
+pub const USER_CHANNEL: u16 = 1007;
+/// The ids of the static virtual channels start after the I/O channel.
+pub const FIRST_VIRTUAL_CHANNEL: u16 = 1004;
+
+pub const SHARE_ID: u32 = 0x0001_0000 | SERVER_CHANNEL as u32;
+
+pub const PDUTYPE_DEMANDACTIVEPDU: u16 = 0x1;
+pub const PDUTYPE_CONFIRMACTIVEPDU: u16 = 0x3;
+pub const PDUTYPE_DEACTIVATEALLPDU: u16 = 0x6;
+pub const PDUTYPE_DATAPDU: u16 = 0x7;
+
+pub const PDUTYPE2_UPDATE: u8 = 0x02;
+pub const PDUTYPE2_CONTROL: u8 = 0x14;
+pub const PDUTYPE2_INPUT: u8 = 0x1c;
+pub const PDUTYPE2_SYNCHRONIZE: u8 = 0x1f;
+pub const PDUTYPE2_REFRESH_RECT: u8 = 0x21;
+pub const PDUTYPE2_SUPPRESS_OUTPUT: u8 = 0x23;
+pub const PDUTYPE2_SHUTDOWN_REQUEST: u8 = 0x24;
+pub const PDUTYPE2_POINTER: u8 = 0x1b;
+pub const PDUTYPE2_FONTLIST: u8 = 0x27;
+pub const PDUTYPE2_FONTMAP: u8 = 0x28;
+pub const PDUTYPE2_SET_KEYBOARD_INDICATORS: u8 = 0x29;
+
+const CHANNEL_FLAG_FIRST: u32 = 0x1;
+const CHANNEL_FLAG_LAST: u32 = 0x2;
+/// The largest chunk of the static virtual channels.
+pub const CHANNEL_CHUNK_LENGTH: usize = 1600;
+
+const FASTPATH_OUTPUT_ACTION_FASTPATH: u8 = 0;
+const FASTPATH_UPDATETYPE_BITMAP: u8 = 0x1;
+const FASTPATH_UPDATETYPE_PTR_NULL: u8 = 0x5;
+const FASTPATH_UPDATETYPE_PTR_DEFAULT: u8 = 0x6;
+const FASTPATH_UPDATETYPE_POINTER: u8 = 0xb;
+
+const TS_PTRMSGTYPE_SYSTEM: u16 = 0x1;
+const TS_PTRMSGTYPE_POINTER: u16 = 0x8;
+const SYSPTR_NULL: u32 = 0;
+const SYSPTR_DEFAULT: u32 = 0x7f00;
+
+// the fast-path PDUs are limited to a 15-bit length
+const FASTPATH_MAX_SIZE: usize = 0x7fff;
+const FASTPATH_UPDATE_HEADER_SIZE: usize = 3;
+const FASTPATH_HEADER_SIZE: usize = 3;
+// the largest update data of a fast-path PDU
+const FASTPATH_MAX_UPDATE_SIZE: usize =
+    FASTPATH_MAX_SIZE - FASTPATH_HEADER_SIZE - FASTPATH_UPDATE_HEADER_SIZE;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads the fields of a PDU, little-endian unless told otherwise.
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(invalid_data("Truncated PDU"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn skip(&mut self, len: usize) -> io::Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u16_be(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    /// A PER length, on one or two bytes.
+    pub fn per_length(&mut self) -> io::Result<usize> {
+        let len = self.u8()? as usize;
+        if len & 0x80 == 0 {
+            return Ok(len);
+        }
+        Ok((len & 0x7f) << 8 | self.u8()? as usize)
+    }
+
+    /// A BER element, returning its tag (the first byte) and content.
+    pub fn ber(&mut self) -> io::Result<(u8, &'a [u8])> {
+        let tag = self.u8()?;
+        if tag & 0x1f == 0x1f {
+            // the high tag numbers
+            while self.u8()? & 0x80 != 0 {}
+        }
+        let len = self.u8()? as usize;
+        let len = if len & 0x80 == 0 {
+            len
+        } else {
+            let mut n = 0;
+            for b in self.bytes(len & 0x7f)? {
+                n = n << 8 | *b as usize;
+            }
+            n
+        };
+        Ok((tag, self.bytes(len)?))
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// A PDU of the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// A slow-path PDU, the X.224 TPDU of a TPKT.
+    Slow(Vec<u8>),
+    /// A fast-path input PDU, with the header byte (the action, the number of events and
+    /// the flags).
+    Fast { header: u8, data: Vec<u8> },
+}
+
+/// Read a PDU, `None` when the client closed the connection.
+pub fn read_frame(stream: &mut impl Read) -> io::Result<Option<Frame>> {
+    let mut header = [0; 1];
+    match stream.read_exact(&mut header) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    if header[0] == TPKT_VERSION {
+        let mut tpkt = [0; 3];
+        stream.read_exact(&mut tpkt)?;
+        let len = (u16::from_be_bytes([tpkt[1], tpkt[2]]) as usize)
+            .checked_sub(4)
+            .ok_or_else(|| invalid_data("Invalid TPKT length"))?;
+        let mut tpdu = vec![0; len];
+        stream.read_exact(&mut tpdu)?;
+        return Ok(Some(Frame::Slow(tpdu)));
+    }
+    let mut len = [0; 1];
+    stream.read_exact(&mut len)?;
+    let (len, header_len) = if len[0] & 0x80 == 0 {
+        (len[0] as usize, 2)
+    } else {
+        let mut low = [0; 1];
+        stream.read_exact(&mut low)?;
+        (((len[0] & 0x7f) as usize) << 8 | low[0] as usize, 3)
+    };
+    let len = len
+        .checked_sub(header_len)
+        .ok_or_else(|| invalid_data("Invalid fast-path length"))?;
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data)?;
+    Ok(Some(Frame::Fast {
+        header: header[0],
+        data,
+    }))
+}
+
+pub fn per_length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        vec![len as u8]
+    } else {
+        (0x8000 | len as u16).to_be_bytes().to_vec()
+    }
+}
+
+pub fn ber(tag: &[u8], content: &[u8]) -> Vec<u8> {
+    let len = content.len();
+    let mut element = tag.to_vec();
+    if len < 0x80 {
+        element.push(len as u8);
+    } else if len < 0x100 {
+        element.extend([0x81, len as u8]);
+    } else {
+        element.push(0x82);
+        element.extend((len as u16).to_be_bytes());
+    }
+    element.extend(content);
+    element
+}
+
+fn tpkt(tpdu: &[u8]) -> Vec<u8> {
+    let mut pdu = vec![TPKT_VERSION, 0];
+    pdu.extend((tpdu.len() as u16 + 4).to_be_bytes());
+    pdu.extend(tpdu);
+    pdu
+}
+
+/// An X.224 connection confirm, with a negotiation response or failure.
+pub fn connection_confirm(negotiation: &[u8]) -> Vec<u8> {
+    let mut tpdu = vec![6 + negotiation.len() as u8, X224_CONNECTION_CONFIRM];
+    // dst-ref, src-ref, class 0
+    tpdu.extend([0, 0, 0x12, 0x34, 0]);
+    tpdu.extend(negotiation);
+    tpkt(&tpdu)
+}
+
+/// An X.224 data TPDU, carrying an MCS PDU.
+pub fn x224_data(mcs: &[u8]) -> Vec<u8> {
+    tpkt(&[&X224_DATA[..], mcs].concat())
+}
+
+/// The MCS PDU of an X.224 data TPDU.
+pub fn mcs_pdu(tpdu: &[u8]) -> io::Result<&[u8]> {
+    match tpdu {
+        [2, 0xf0, _, mcs @ ..] => Ok(mcs),
+        _ => Err(invalid_data("Not an X.224 data TPDU")),
+    }
+}
+
+pub fn attach_user_confirm() -> Vec<u8> {
+    let mut mcs = vec![MCS_ATTACH_USER_CONFIRM << 2 | 2, 0];
+    mcs.extend((USER_CHANNEL - MCS_BASE_CHANNEL).to_be_bytes());
+    x224_data(&mcs)
+}
+
+pub fn channel_join_confirm(channel: u16, joined: bool) -> Vec<u8> {
+    let mut mcs = vec![MCS_CHANNEL_JOIN_CONFIRM << 2 | 2];
+    // rt-successful or rt-no-such-channel
+    mcs.push(if joined { 0 } else { 14 });
+    mcs.extend((USER_CHANNEL - MCS_BASE_CHANNEL).to_be_bytes());
+    mcs.extend(channel.to_be_bytes());
+    mcs.extend(channel.to_be_bytes());
+    x224_data(&mcs)
+}
+
+/// The data of the server on a channel.
+pub fn send_data_indication(channel: u16, data: &[u8]) -> Vec<u8> {
+    let mut mcs = vec![MCS_SEND_DATA_INDICATION << 2];
+    mcs.extend((SERVER_CHANNEL - MCS_BASE_CHANNEL).to_be_bytes());
+    mcs.extend(channel.to_be_bytes());
+    // high priority, begin and end segmentation
+    mcs.push(0x70);
+    mcs.extend(per_length(data.len()));
+    mcs.extend(data);
+    x224_data(&mcs)
+}
+
+/// The channel and data of a send data request of the client.
+pub fn send_data_request(mcs: &[u8]) -> io::Result<(u16, &[u8])> {
+    let mut r = Reader::new(mcs);
+    r.skip(3)?;
+    let channel = r.u16_be()?;
+    r.skip(1)?;
+    let len = r.per_length()?;
+    Ok((channel, r.bytes(len)?))
+}
+
+/// A share control PDU on the I/O channel.
+pub fn share_control(pdu_type: u16, data: &[u8]) -> Vec<u8> {
+    let mut pdu = vec![];
+    pdu.extend((data.len() as u16 + 6).to_le_bytes());
+    // protocol version 1
+    pdu.extend((pdu_type | 0x10).to_le_bytes());
+    pdu.extend(SERVER_CHANNEL.to_le_bytes());
+    pdu.extend(data);
+    send_data_indication(IO_CHANNEL, &pdu)
+}
+
+/// A share data PDU on the I/O channel.
+pub fn share_data(pdu_type2: u8, data: &[u8]) -> Vec<u8> {
+    let mut pdu = vec![];
+    pdu.extend(SHARE_ID.to_le_bytes());
+    // pad, STREAM_LOW
+    pdu.extend([0, 1]);
+    pdu.extend((data.len() as u16 + 4).to_le_bytes());
+    pdu.push(pdu_type2);
+    // no compression
+    pdu.extend([0, 0, 0]);
+    pdu.extend(data);
+    share_control(PDUTYPE_DATAPDU, &pdu)
+}
+
+/// A share control PDU of the client: its type and data.
+pub fn read_share_control(data: &[u8]) -> io::Result<(u16, &[u8])> {
+    let mut r = Reader::new(data);
+    let len = r.u16()?;
+    // a flow control PDU
+    if len == 0x8000 {
+        return Ok((0, &[]));
+    }
+    let pdu_type = r.u16()? & 0xf;
+    r.skip(2)?;
+    Ok((pdu_type, r.remaining()))
+}
+
+/// A share data PDU of the client: its type and data.
+pub fn read_share_data(data: &[u8]) -> io::Result<(u8, &[u8])> {
+    let mut r = Reader::new(data);
+    r.skip(8)?;
+    let pdu_type2 = r.u8()?;
+    r.skip(3)?;
+    Ok((pdu_type2, r.remaining()))
+}
+
+/// The PDUs of the data on a static virtual channel, in chunks.
+pub fn channel_pdus(channel: u16, data: &[u8]) -> Vec<Vec<u8>> {
+    let chunks: Vec<_> = data.chunks(CHANNEL_CHUNK_LENGTH).collect();
+    let last = chunks.len().saturating_sub(1);
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut flags = 0;
+            if i == 0 {
+                flags |= CHANNEL_FLAG_FIRST;
+            }
+            if i == last {
+                flags |= CHANNEL_FLAG_LAST;
+            }
+            let mut pdu = (data.len() as u32).to_le_bytes().to_vec();
+            pdu.extend(flags.to_le_bytes());
+            pdu.extend(*chunk);
+            send_data_indication(channel, &pdu)
+        })
+        .collect()
+}
+
+/// Reassembles the chunks of a static virtual channel.
+#[derive(Debug, Default)]
+pub struct ChannelReader {
+    data: Vec<u8>,
+}
+
+impl ChannelReader {
+    /// Add a chunk, returning the data once complete.
+    pub fn push(&mut self, pdu: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut r = Reader::new(pdu);
+        let len = r.u32()? as usize;
+        let flags = r.u32()?;
+        if flags & CHANNEL_FLAG_FIRST != 0 {
+            self.data.clear();
+        }
+        self.data.extend(r.remaining());
+        if flags & CHANNEL_FLAG_LAST == 0 {
+            return Ok(None);
+        }
+        let data = std::mem::take(&mut self.data);
+        if data.len() != len {
+            return Err(invalid_data("Invalid virtual channel data length"));
+        }
+        Ok(Some(data))
+    }
+}
+
+/// A graphics update of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Update<'a> {
+    /// A TS_UPDATE_BITMAP_DATA.
+    Bitmap(&'a [u8]),
+    PointerHidden,
+    PointerDefault,
+    /// A TS_POINTERATTRIBUTE.
+    Pointer(&'a [u8]),
+}
+
+impl Update<'_> {
+    pub fn fastpath(&self) -> Vec<u8> {
+        match self {
+            Self::Bitmap(data) => fastpath_update(FASTPATH_UPDATETYPE_BITMAP, data),
+            Self::PointerHidden => fastpath_update(FASTPATH_UPDATETYPE_PTR_NULL, &[]),
+            Self::PointerDefault => fastpath_update(FASTPATH_UPDATETYPE_PTR_DEFAULT, &[]),
+            Self::Pointer(data) => fastpath_update(FASTPATH_UPDATETYPE_POINTER, data),
+        }
+    }
+
+    pub fn slowpath(&self) -> Vec<u8> {
+        let pointer = |msg_type: u16, data: &[u8]| {
+            let mut pdu = msg_type.to_le_bytes().to_vec();
+            pdu.extend([0, 0]);
+            pdu.extend(data);
+            share_data(PDUTYPE2_POINTER, &pdu)
+        };
+        match self {
+            Self::Bitmap(data) => share_data(PDUTYPE2_UPDATE, data),
+            Self::PointerHidden => pointer(TS_PTRMSGTYPE_SYSTEM, &SYSPTR_NULL.to_le_bytes()),
+            Self::PointerDefault => pointer(TS_PTRMSGTYPE_SYSTEM, &SYSPTR_DEFAULT.to_le_bytes()),
+            Self::Pointer(data) => pointer(TS_PTRMSGTYPE_POINTER, data),
+        }
+    }
+}
+
+// A fast-path PDU of a single update.
+fn fastpath_update(code: u8, data: &[u8]) -> Vec<u8> {
+    assert!(data.len() <= FASTPATH_MAX_UPDATE_SIZE);
+    let len = FASTPATH_HEADER_SIZE + FASTPATH_UPDATE_HEADER_SIZE + data.len();
+    let mut pdu = vec![FASTPATH_OUTPUT_ACTION_FASTPATH];
+    pdu.extend((0x8000 | len as u16).to_be_bytes());
+    // a single fragment, without compression
+    pdu.push(code & 0xf);
+    pdu.extend((data.len() as u16).to_le_bytes());
+    pdu.extend(data);
+    pdu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        let slow = x224_data(&[0x04, 0x01, 0x00, 0x01, 0x00]);
+        let fast = [0x04, 0x06, 0x00, 0x1e, 0x01, 0x1e];
+        let mut stream = &[&slow[..], &fast].concat()[..];
+        let tpdu = match read_frame(&mut stream).unwrap() {
+            Some(Frame::Slow(tpdu)) => tpdu,
+            frame => panic!("Unexpected frame {:?}", frame),
+        };
+        assert_eq!(mcs_pdu(&tpdu).unwrap()[0] >> 2, MCS_ERECT_DOMAIN_REQUEST);
+        assert_eq!(
+            read_frame(&mut stream).unwrap(),
+            Some(Frame::Fast {
+                header: 0x04,
+                data: vec![0x00, 0x1e, 0x01, 0x1e],
+            })
+        );
+        assert_eq!(read_frame(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(per_length(0x10), [0x10]);
+        assert_eq!(per_length(0x1234), [0x92, 0x34]);
+        assert_eq!(Reader::new(&[0x92, 0x34]).per_length().unwrap(), 0x1234);
+        let element = ber(&[0x04], &[0; 300]);
+        assert_eq!(element[..4], [0x04, 0x82, 0x01, 0x2c]);
+        let (tag, content) = Reader::new(&element).ber().unwrap();
+        assert_eq!((tag, content.len()), (0x04, 300));
+
+        let indication = send_data_indication(IO_CHANNEL, &[1, 2, 3]);
+        assert_eq!(
+            send_data_request(&indication[7..]).unwrap(),
+            (IO_CHANNEL, &[1, 2, 3][..])
+        );
+        assert_eq!(
+            indication[7..],
+            [0x68, 0x00, 0x01, 0x03, 0xeb, 0x70, 3, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn channel_chunks() {
+        let data: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        let pdus = channel_pdus(FIRST_VIRTUAL_CHANNEL, &data);
+        assert_eq!(pdus.len(), 3);
+        let mut reader = ChannelReader::default();
+        let mut received = None;
+        for pdu in &pdus {
+            let (channel, chunk) = send_data_request(&pdu[7..]).unwrap();
+            assert_eq!(channel, FIRST_VIRTUAL_CHANNEL);
+            assert!(received.is_none());
+            received = reader.push(chunk).unwrap();
+        }
+        assert_eq!(received, Some(data));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{error::Error, fs::File, io::BufReader, net::TcpStream, path::Path, sync::Arc, thread};
+
+use qemu_display::{loopback_pair, relay_tls};
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection};
+
+/// TLS settings for the RDP security (the only one supported, the standard RDP security is
+/// obsolete).
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path.display()).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(format!("No private key found in {}", path.display()).into()),
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Load the server certificate and key.
+    pub fn new(cert: &Path, key: &Path) -> Result<Self, Box<dyn Error>> {
+        Self::with_cert(read_certs(cert)?, read_key(key)?)
+    }
+
+    /// Generate a self-signed certificate, the clients will ask to trust it.
+    pub fn self_signed(name: &str) -> Result<Self, Box<dyn Error>> {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()])?;
+        Self::with_cert(
+            vec![Certificate(cert.serialize_der()?)],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    fn with_cert(certs: Vec<Certificate>, key: PrivateKey) -> Result<Self, Box<dyn Error>> {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Do the TLS handshake with the client, and return a plain stream carrying the rest of
+    /// the session.
+    ///
+    /// The returned stream is the local end of a loopback connection, relayed to the client,
+    /// so it can be cloned for the reader thread. The timeouts of `stream` apply to the
+    /// handshake only.
+    pub fn accept(&self, mut stream: TcpStream) -> Result<TcpStream, Box<dyn Error>> {
+        let mut conn = ServerConnection::new(self.config.clone())?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        let (local, relay) = loopback_pair()?;
+        thread::spawn(move || {
+            if let Err(e) = relay_tls(conn, stream, relay) {
+                eprintln!("TLS relay error: {}", e);
+            }
+        });
+        Ok(local)
+    }
+}
@@ -0,0 +1,17 @@
+use std::{env, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-changed=data");
+    // for running without the schema installed, see settings.rs
+    let out_dir = env::var("OUT_DIR").unwrap();
+    match Command::new("glib-compile-schemas")
+        .args(&["--strict", "--targetdir", &out_dir, "data"])
+        .status()
+    {
+        Ok(status) if status.success() => {}
+        res => println!(
+            "cargo:warning=Failed to compile the GSettings schema ({:?}), the preferences won't be saved",
+            res
+        ),
+    }
+}
@@ -1,5 +1,8 @@
-use std::{error::Error, result::Result};
+use std::{collections::HashMap, error::Error, result::Result, str::FromStr};
 
+use futures::StreamExt;
+use gst::prelude::*;
+use gst_app::{AppSink, AppSinkStream};
 use qemu_display::{Audio, AudioInHandler, AudioOutHandler};
 
 #[derive(Debug)]
@@ -48,42 +51,160 @@ impl AudioOutHandler for OutListener {
     }
 }
 
-#[derive(Debug, Default)]
+// A capture pipeline for a guest input stream.
+struct InStream {
+    pipeline: gst::Pipeline,
+    volume: gst::Element,
+    samples: AppSinkStream,
+    enabled: bool,
+    // captured data not yet read by the guest
+    pending: Vec<u8>,
+}
+
+impl InStream {
+    fn new(device: Option<&str>, caps: &str) -> Result<Self, Box<dyn Error>> {
+        let caps = gst::Caps::from_str(caps)?;
+        let pipeline = gst::Pipeline::new(None);
+        let src = capture_source(device)?;
+        let convert = gst::ElementFactory::make("audioconvert").build()?;
+        let volume = gst::ElementFactory::make("volume").build()?;
+        let convert_out = gst::ElementFactory::make("audioconvert").build()?;
+        let resample = gst::ElementFactory::make("audioresample").build()?;
+        let sink = AppSink::builder().caps(&caps).max_buffers(32).build();
+        let elements = [
+            &src,
+            &convert,
+            &volume,
+            &convert_out,
+            &resample,
+            sink.upcast_ref(),
+        ];
+        pipeline.add_many(&elements)?;
+        gst::Element::link_many(&elements)?;
+
+        Ok(Self {
+            pipeline,
+            volume,
+            samples: sink.stream(),
+            enabled: false,
+            pending: vec![],
+        })
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), Box<dyn Error>> {
+        // release the device while disabled
+        let state = if enabled {
+            gst::State::Playing
+        } else {
+            gst::State::Null
+        };
+        self.pipeline.set_state(state)?;
+        self.enabled = enabled;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn set_volume(&self, mute: bool, volume: f64) {
+        self.volume.set_property("mute", mute);
+        self.volume.set_property("volume", volume);
+    }
+
+    async fn read(&mut self, size: usize) -> Vec<u8> {
+        if !self.enabled {
+            return vec![0; size];
+        }
+        while self.pending.len() < size {
+            let sample = match self.samples.next().await {
+                Some(sample) => sample,
+                None => break,
+            };
+            if let Some(map) = sample.buffer().and_then(|b| b.map_readable().ok()) {
+                self.pending.extend_from_slice(&map);
+            }
+        }
+        let len = size.min(self.pending.len());
+        self.pending.drain(..len).collect()
+    }
+}
+
+impl Drop for InStream {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+// The source element of the capture device with the given name, or the default device.
+fn capture_source(device: Option<&str>) -> Result<gst::Element, Box<dyn Error>> {
+    if let Some(name) = device {
+        let monitor = gst::DeviceMonitor::new();
+        monitor.add_filter(Some("Audio/Source"), None);
+        monitor.start()?;
+        let devices = monitor.devices();
+        monitor.stop();
+        if let Some(dev) = devices.iter().find(|d| d.display_name() == name) {
+            return Ok(dev.create_element(None)?);
+        }
+        let names: Vec<_> = devices.iter().map(|d| d.display_name()).collect();
+        log::warn!(
+            "Audio input device {:?} not found (available: {:?}), using the default",
+            name,
+            names
+        );
+    }
+    Ok(gst::ElementFactory::make("autoaudiosrc").build()?)
+}
+
 struct InListener {
-    gst: rdw::GstAudio,
+    device: Option<String>,
+    // the capture gain, applied on top of the guest volume
+    level: f64,
+    streams: HashMap<u64, InStream>,
+}
+
+impl InListener {
+    fn stream(&mut self, id: u64) -> Result<&mut InStream, Box<dyn Error>> {
+        self.streams
+            .get_mut(&id)
+            .ok_or_else(|| format!("Unknown input stream {}", id).into())
+    }
 }
 
 #[async_trait::async_trait]
 impl AudioInHandler for InListener {
     async fn init(&mut self, id: u64, info: qemu_display::PCMInfo) {
-        if let Err(e) = self.gst.init_in(id, &info.gst_caps()) {
-            log::warn!("Failed to initialize audio input stream: {}", e);
+        match InStream::new(self.device.as_deref(), &info.gst_caps()) {
+            Ok(stream) => {
+                stream.set_volume(false, self.level);
+                self.streams.insert(id, stream);
+            }
+            Err(e) => log::warn!("Failed to initialize audio input stream: {}", e),
         }
     }
 
     async fn fini(&mut self, id: u64) {
-        self.gst.fini_in(id);
+        self.streams.remove(&id);
     }
 
     async fn set_enabled(&mut self, id: u64, enabled: bool) {
-        if let Err(e) = self.gst.set_enabled_in(id, enabled) {
+        if let Err(e) = self.stream(id).and_then(|s| s.set_enabled(enabled)) {
             log::warn!("Failed to set enabled audio input stream: {}", e);
         }
     }
 
     async fn set_volume(&mut self, id: u64, volume: qemu_display::Volume) {
-        if let Err(e) = self.gst.set_volume_in(
-            id,
-            volume.mute,
-            volume.volume.first().map(|v| *v as f64 / 255f64),
-        ) {
-            log::warn!("Failed to set audio input volume: {}", e);
+        let level = self.level;
+        match self.stream(id) {
+            Ok(stream) => stream.set_volume(
+                volume.mute,
+                volume.volume.first().map_or(1.0, |v| *v as f64 / 255f64) * level,
+            ),
+            Err(e) => log::warn!("Failed to set audio input volume: {}", e),
         }
     }
 
     async fn read(&mut self, id: u64, size: u64) -> Vec<u8> {
-        match self.gst.read_in(id, size).await {
-            Ok(d) => d,
+        match self.stream(id) {
+            Ok(stream) => stream.read(size as usize).await,
             Err(e) => {
                 log::warn!("Failed to read from input stream: {}", e);
                 vec![]
@@ -93,11 +214,22 @@ impl AudioInHandler for InListener {
 }
 
 impl Handler {
-    pub async fn new(mut audio: Audio) -> Result<Handler, Box<dyn Error>> {
+    /// `in_device` is the name of the capture device, and `in_level` the capture gain.
+    pub async fn new(
+        mut audio: Audio,
+        in_device: Option<String>,
+        in_level: f64,
+    ) -> Result<Handler, Box<dyn Error>> {
         let gst = rdw::GstAudio::new()?;
         audio.register_out_listener(OutListener { gst }).await?;
-        let gst = rdw::GstAudio::new()?;
-        audio.register_in_listener(InListener { gst }).await?;
+        gst::init()?;
+        audio
+            .register_in_listener(InListener {
+                device: in_device,
+                level: in_level,
+                streams: HashMap::new(),
+            })
+            .await?;
         Ok(Handler { audio })
     }
 }
@@ -1,18 +1,14 @@
-use std::{
-    error::Error,
-    result::Result,
-    sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc,
-    },
-};
+use std::{error::Error, result::Result};
 
 use glib::{clone, SignalHandlerId};
 use gtk::{
     gdk, gio, glib,
     prelude::{DisplayExt, *},
 };
-use qemu_display::{Clipboard, ClipboardHandler, ClipboardProxy, ClipboardSelection};
+use qemu_display::{
+    Clipboard, ClipboardConfirm, ClipboardHandler, ClipboardManager, ClipboardSelection,
+    PasteOnDemand,
+};
 use rdw::gtk;
 
 #[derive(Debug)]
@@ -25,38 +21,27 @@ pub struct Handler {
 
 #[derive(Debug)]
 struct InnerHandler {
-    proxy: ClipboardProxy<'static>,
-    serials: Arc<[AtomicU32; 2]>,
-}
-
-impl InnerHandler {
-    fn reset_serials(&mut self) {
-        self.serials[0].store(0, Ordering::SeqCst);
-        self.serials[1].store(0, Ordering::SeqCst);
-    }
+    manager: ClipboardManager,
 }
 
 #[async_trait::async_trait]
 impl ClipboardHandler for InnerHandler {
     async fn register(&mut self) {
-        self.reset_serials();
+        self.manager.reset();
     }
 
     async fn unregister(&mut self) {
-        self.reset_serials();
+        self.manager.reset();
     }
 
     async fn grab(&mut self, selection: ClipboardSelection, serial: u32, mimes: Vec<String>) {
-        if let Some((clipboard, idx)) = clipboard_from_selection(selection) {
-            let cur_serial = self.serials[idx].load(Ordering::SeqCst);
-            if serial < cur_serial {
-                log::debug!("Ignored peer grab: {} < {}", serial, cur_serial);
+        if let Some(clipboard) = clipboard_from_selection(selection) {
+            if !self.manager.peer_grab(selection, serial, &mimes) {
                 return;
             }
 
-            self.serials[idx].store(serial, Ordering::SeqCst);
             let m: Vec<_> = mimes.iter().map(|s| s.as_str()).collect();
-            let p = self.proxy.clone();
+            let p = self.manager.clone();
             let content = rdw::ContentProvider::new(&m, move |mime, stream, prio| {
                 log::debug!("content-provider-write: {:?}", (mime, stream));
 
@@ -86,7 +71,8 @@ impl ClipboardHandler for InnerHandler {
     }
 
     async fn release(&mut self, selection: ClipboardSelection) {
-        if let Some((clipboard, _)) = clipboard_from_selection(selection) {
+        self.manager.peer_release(selection);
+        if let Some(clipboard) = clipboard_from_selection(selection) {
             // TODO: track if the outside/app changed the clipboard
             if let Err(e) = clipboard.set_content(gdk::ContentProvider::NONE) {
                 log::warn!("Failed to release clipboard: {}", e);
@@ -102,7 +88,7 @@ impl ClipboardHandler for InnerHandler {
         let (sender, receiver) = futures::channel::oneshot::channel();
         glib::MainContext::default().invoke(move || {
             glib::MainContext::default().spawn_local(async move {
-                let res = if let Some((clipboard, _)) = clipboard_from_selection(selection) {
+                let res = if let Some(clipboard) = clipboard_from_selection(selection) {
                     let m: Vec<_> = mimes.iter().map(|s| s.as_str()).collect();
                     let res = clipboard.read_future(&m, glib::Priority::default()).await;
                     log::debug!("clipboard-read: {}", res.is_ok());
@@ -146,21 +132,49 @@ impl ClipboardHandler for InnerHandler {
     }
 }
 
+// Asks the user before the guest reads the host clipboard.
+#[derive(Debug)]
+struct ConfirmDialog;
+
+#[async_trait::async_trait]
+impl ClipboardConfirm for ConfirmDialog {
+    async fn confirm(&mut self, selection: ClipboardSelection, mimes: &[String]) -> bool {
+        log::debug!("clipboard-confirm({:?}): {:?}", selection, mimes);
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        glib::MainContext::default().invoke(move || {
+            glib::MainContext::default().spawn_local(async move {
+                let app = gio::Application::default()
+                    .and_then(|app| app.downcast::<gtk::Application>().ok());
+                let dialog = gtk::MessageDialog::new(
+                    app.and_then(|app| app.active_window()).as_ref(),
+                    gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+                    gtk::MessageType::Question,
+                    gtk::ButtonsType::YesNo,
+                    "Allow the guest to paste the clipboard content?",
+                );
+                let res = dialog.run_future().await;
+                dialog.close();
+                let _ = sender.send(res == gtk::ResponseType::Yes);
+            });
+        });
+
+        receiver.await.unwrap_or(false)
+    }
+}
+
 impl Handler {
-    pub async fn new(clipboard: Clipboard) -> Result<Handler, Box<dyn Error>> {
-        let proxy = clipboard.proxy.clone();
-        let serials = Arc::new([AtomicU32::new(0), AtomicU32::new(0)]);
-        let cb_handler = watch_clipboard(
-            clipboard.proxy.clone(),
-            ClipboardSelection::Clipboard,
-            serials.clone(),
-        );
-        let cb_primary_handler = watch_clipboard(
-            clipboard.proxy.clone(),
-            ClipboardSelection::Primary,
-            serials.clone(),
-        );
-        clipboard.register(InnerHandler { proxy, serials }).await?;
+    pub async fn new(clipboard: Clipboard, confirm: bool) -> Result<Handler, Box<dyn Error>> {
+        let manager = ClipboardManager::new(&clipboard);
+        let cb_handler = watch_clipboard(manager.clone(), ClipboardSelection::Clipboard);
+        let cb_primary_handler = watch_clipboard(manager.clone(), ClipboardSelection::Primary);
+        let handler = InnerHandler { manager };
+        if confirm {
+            clipboard
+                .register(PasteOnDemand::new(handler, ConfirmDialog))
+                .await?;
+        } else {
+            clipboard.register(handler).await?;
+        }
         Ok(Handler {
             clipboard,
             cb_handler,
@@ -174,24 +188,21 @@ impl Drop for Handler {
         if let Some(id) = self.cb_primary_handler.take() {
             clipboard_from_selection(ClipboardSelection::Primary)
                 .unwrap()
-                .0
                 .disconnect(id);
         }
         if let Some(id) = self.cb_handler.take() {
             clipboard_from_selection(ClipboardSelection::Clipboard)
                 .unwrap()
-                .0
                 .disconnect(id);
         }
     }
 }
 
 fn watch_clipboard(
-    proxy: ClipboardProxy<'static>,
+    manager: ClipboardManager,
     selection: ClipboardSelection,
-    serials: Arc<[AtomicU32; 2]>,
 ) -> Option<SignalHandlerId> {
-    let (clipboard, idx) = match clipboard_from_selection(selection) {
+    let clipboard = match clipboard_from_selection(selection) {
         Some(it) => it,
         None => return None,
     };
@@ -204,31 +215,30 @@ fn watch_clipboard(
         let formats = clipboard.formats();
         let types = formats.mime_types();
         log::debug!(">clipboard-changed({:?}): {:?}", selection, types);
-        let proxy = proxy.clone();
-        let serials = serials.clone();
+        let manager = manager.clone();
         glib::MainContext::default().spawn_local(async move {
-            if types.is_empty() {
-                let _ = proxy.release(selection).await;
+            let res = if types.is_empty() {
+                manager.release(selection).await
             } else {
-                let mimes: Vec<_> = types.iter().map(|s| s.as_str()).collect();
-                let ser = serials[idx].load(Ordering::SeqCst);
-                let _ = proxy.grab(selection, ser, &mimes).await;
-                serials[idx].store(ser + 1, Ordering::SeqCst);
+                manager.grab(selection, &types).await
+            };
+            if let Err(e) = res {
+                log::warn!("Failed to update clipboard grab: {}", e);
             }
         });
     });
     Some(id)
 }
 
-fn clipboard_from_selection(selection: ClipboardSelection) -> Option<(gdk::Clipboard, usize)> {
+fn clipboard_from_selection(selection: ClipboardSelection) -> Option<gdk::Clipboard> {
     let display = match gdk::Display::default() {
         Some(display) => display,
         None => return None,
     };
 
     match selection {
-        ClipboardSelection::Clipboard => Some((display.clipboard(), 0)),
-        ClipboardSelection::Primary => Some((display.primary_clipboard(), 1)),
+        ClipboardSelection::Clipboard => Some(display.clipboard()),
+        ClipboardSelection::Primary => Some(display.primary_clipboard()),
         _ => {
             log::warn!("Unsupport clipboard selection: {:?}", selection);
             None
@@ -1,19 +1,26 @@
 use futures_util::StreamExt;
 use glib::{clone, subclass::prelude::*, MainContext};
 use gtk::glib;
+use keycodemap::Keymap;
 use once_cell::sync::OnceCell;
-use qemu_display::{Console, ConsoleListenerHandler};
+use qemu_display::{
+    Console, ConsoleHealth, ConsoleWatchdog, FrameSink, FrameSinkListener, KeyTranslation,
+    KeyboardLeds, KeyboardModifiers, ModifierTracker, PixelFormat, PIXMAN_BGRA8888,
+};
+#[cfg(unix)]
+use qemu_display::{AdaptiveSink, FramePathPolicy};
 use rdw::{gtk, DisplayExt};
-use std::cell::Cell;
 #[cfg(unix)]
 use std::os::unix::io::IntoRawFd;
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
 
 mod imp {
     use super::*;
     use gtk::subclass::prelude::*;
     #[cfg(windows)]
-    use std::cell::RefCell;
-    #[cfg(windows)]
     use std::ffi::c_void;
     #[cfg(windows)]
     use windows::Win32::Foundation::{CloseHandle, HANDLE};
@@ -77,9 +84,17 @@ mod imp {
     #[derive(Debug, Default)]
     pub struct Display {
         pub(crate) console: OnceCell<Console>,
-        keymap: Cell<Option<&'static [u16]>>,
+        watchdog: RefCell<Option<ConsoleWatchdog>>,
+        modifiers: OnceCell<ModifierTracker>,
+        keymap: Cell<Option<Keymap>>,
         #[cfg(windows)]
-        scanout_map: RefCell<Option<(MemoryMap, u32)>>,
+        scanout_map: RefCell<Option<(MemoryMap, u32, PixelFormat)>>,
+        // a new scanout, shown once its content is ready
+        #[cfg(unix)]
+        pending_dmabuf: RefCell<Option<qemu_display::ScanoutDMABUF>>,
+        // shows the pending scanout if no update follows
+        #[cfg(unix)]
+        pending_timeout: RefCell<Option<glib::SourceId>>,
     }
 
     #[glib::object_subclass]
@@ -99,18 +114,42 @@ mod imp {
 
             self.obj().connect_key_event(
                 clone!(@weak self as this => move |_, keyval, keycode, event| {
-                    let mapped = this.keymap.get().and_then(|m| m.get(keycode as usize)).map(|x| *x as u32);
+                    let keymap = this.keymap.get().unwrap_or_else(Keymap::native);
+                    let mapped = keymap.qnum(keycode);
                     log::debug!("key-{event:?}: {keyval} {keycode} -> {mapped:?}");
-                    if let Some(qnum) = mapped {
-                        MainContext::default().spawn_local(clone!(@weak this => async move {
-                            if event.contains(rdw::KeyEvent::PRESS) {
-                                let _ = this.obj().console().keyboard.press(qnum).await;
+                    if mapped.is_none() && !qemu_display::key_debug() {
+                        return;
+                    }
+                    MainContext::default().spawn_local(clone!(@weak this => async move {
+                        for (press, flag) in [(true, rdw::KeyEvent::PRESS), (false, rdw::KeyEvent::RELEASE)] {
+                            if !event.contains(flag) {
+                                continue;
                             }
-                            if event.contains(rdw::KeyEvent::RELEASE) {
-                                let _ = this.obj().console().keyboard.release(qnum).await;
+                            let translation = KeyTranslation {
+                                press,
+                                keyval: Some(keyval),
+                                keycode,
+                                keymap: keymap.name(),
+                                qnum: mapped,
+                            };
+                            if let Some(report) = this.obj().console().keyboard.trace_key(&translation).await {
+                                log::info!("{}", report);
+                                this.show_key_report(&report);
                             }
-                        }));
-                    }
+                            match (mapped, press) {
+                                (Some(qnum), true) => {
+                                    if !KeyboardModifiers::is_lock_key(qnum) {
+                                        this.sync_modifiers().await;
+                                    }
+                                    let _ = this.obj().console().keyboard.press(qnum).await;
+                                }
+                                (Some(qnum), false) => {
+                                    let _ = this.obj().console().keyboard.release(qnum).await;
+                                }
+                                _ => {}
+                            }
+                        }
+                    }));
                 }),
             );
 
@@ -162,6 +201,8 @@ mod imp {
                     let button = match scroll {
                         rdw::Scroll::Up => MouseButton::WheelUp,
                         rdw::Scroll::Down => MouseButton::WheelDown,
+                        rdw::Scroll::Left => MouseButton::WheelLeft,
+                        rdw::Scroll::Right => MouseButton::WheelRight,
                         _ => {
                             log::warn!("not yet implemented");
                             return;
@@ -186,41 +227,73 @@ mod imp {
         fn realize(&self) {
             self.parent_realize();
 
-            self.keymap.set(rdw::keymap_qnum());
+            // the keycodes of the windowing system in use
+            let backend = self.obj().display().type_().name();
+            let backend = backend
+                .trim_start_matches("Gdk")
+                .trim_end_matches("Display");
+            self.keymap.set(Keymap::for_backend(backend));
 
             MainContext::default().spawn_local(clone!(@weak self as this => async move {
                 let console = this.console.get().unwrap();
+                match ModifierTracker::new(&console.keyboard).await {
+                    Ok(modifiers) => {
+                        let _ = this.modifiers.set(modifiers);
+                    }
+                    Err(e) => log::warn!("Failed to track the keyboard modifiers: {}", e),
+                }
                 // we have to use a channel, because widget is not Send..
                 let (sender, mut receiver) = futures::channel::mpsc::unbounded();
-                console.register_listener(ConsoleHandler { sender }).await.unwrap();
+                let handler = ConsoleHandler { sender };
+                // fall back to copies when the DMABUF updates are too slow
+                #[cfg(unix)]
+                let handler = {
+                    let (sink, control) = AdaptiveSink::new(handler, FramePathPolicy::default());
+                    let mut changes = control.receive_path_changed();
+                    MainContext::default().spawn_local(async move {
+                        while let Some(e) = changes.next().await {
+                            log::info!("Using the {} display path: {:?}", e.path, e.reason);
+                        }
+                    });
+                    sink
+                };
+                let watchdog = ConsoleWatchdog::new(console, FrameSinkListener::new(handler), WATCHDOG_PERIOD).await.unwrap();
+                let mut health = watchdog.receive_health();
+                this.watchdog.replace(Some(watchdog));
+                MainContext::default().spawn_local(async move {
+                    while let Some(h) = health.next().await {
+                        match h {
+                            ConsoleHealth::Stalled => log::warn!("Console stalled, no update from QEMU"),
+                            ConsoleHealth::Recovered => log::info!("Console recovered"),
+                            ConsoleHealth::Failed(e) => log::warn!("Console listener recovery failed: {}", e),
+                        }
+                    }
+                });
                 MainContext::default().spawn_local(clone!(@weak this => async move {
                     while let Some(e) = receiver.next().await {
                         use ConsoleEvent::*;
                         match e {
                             Scanout(s) => {
-                                if s.format != 0x20020888 {
-                                    log::warn!("Format not yet supported: {:X}", s.format);
-                                    continue;
-                                }
+                                #[cfg(unix)]
+                                this.cancel_pending_dmabuf();
                                 this.obj().set_display_size(Some((s.width as _, s.height as _)));
-                                this.obj().update_area(0, 0, s.width as _, s.height as _, s.stride as _, &s.data);
+                                this.update_area(0, 0, s.width, s.height, s.stride, s.format, &s.data);
                             }
                             Update(u) => {
-                                if u.format != 0x20020888 {
-                                    log::warn!("Format not yet supported: {:X}", u.format);
-                                    continue;
-                                }
-                                this.obj().update_area(u.x as _, u.y as _, u.w as _, u.h as _, u.stride as _, &u.data);
+                                this.update_area(u.x, u.y, u.w as _, u.h as _, u.stride, u.format, &u.data);
                             }
                             #[cfg(windows)]
                             ScanoutMap(s) => {
                                 use windows::Win32::System::Memory::{FILE_MAP_READ, MapViewOfFile};
 
                                 log::debug!("{s:?}");
-                                if s.format != 0x20020888 {
-                                    log::warn!("Format not yet supported: {:X}", s.format);
-                                    continue;
-                                }
+                                let pf = match PixelFormat::from_pixman(s.format) {
+                                    Ok(pf) => pf,
+                                    Err(e) => {
+                                        log::warn!("{}", e);
+                                        continue;
+                                    }
+                                };
 
                                 let handle = HANDLE(s.handle as _);
                                 let size = s.height as usize * s.stride as usize;
@@ -233,36 +306,46 @@ mod imp {
 
                                 let map = MemoryMap { ptr, handle, offset, size };
                                 this.obj().set_display_size(Some((s.width as _, s.height as _)));
-                                this.obj().update_area(0, 0, s.width as _, s.height as _, s.stride as _, map.as_bytes());
-                                this.scanout_map.replace(Some((map, s.stride)));
+                                this.update_area(0, 0, s.width, s.height, s.stride, s.format, map.as_bytes());
+                                this.scanout_map.replace(Some((map, s.stride, pf)));
                             }
                             #[cfg(windows)]
                             UpdateMap(u) => {
                                 log::debug!("{u:?}");
                                 let scanout_map = this.scanout_map.borrow();
-                                let Some((map, stride)) = scanout_map.as_ref() else {
+                                let Some((map, stride, pf)) = scanout_map.as_ref() else {
                                     log::warn!("No mapped scanout!");
                                     continue;
                                 };
                                 let stride = *stride;
                                 let bytes = map.as_bytes();
-                                this.obj().update_area(u.x as _, u.y as _, u.w as _, u.h as _, stride as _, &bytes[u.y as usize * stride as usize + u.x as usize * 4..]);
+                                let start = u.y as usize * stride as usize + u.x as usize * pf.bytes_per_pixel();
+                                this.update_area(u.x, u.y, u.w as _, u.h as _, stride, pf.code(), &bytes[start..]);
                             }
                             #[cfg(unix)]
                             ScanoutDMABUF(s) => {
-                                this.obj().set_display_size(Some((s.width as _, s.height as _)));
-                                this.obj().set_dmabuf_scanout(rdw::RdwDmabufScanout {
-                                    width: s.width,
-                                    height: s.height,
-                                    stride: s.stride,
-                                    fourcc: s.fourcc,
-                                    y0_top: s.y0_top,
-                                    modifier: s.modifier,
-                                    fd: s.into_raw_fd(),
-                                });
+                                // the buffer content is only valid after the first update:
+                                // keep showing the current scanout until then, to avoid
+                                // flashing garbage on resolution change
+                                this.cancel_pending_dmabuf();
+                                this.pending_dmabuf.replace(Some(s));
+                                // but a guest may not update a static display right away
+                                let id = glib::timeout_add_local_once(
+                                    PENDING_DMABUF_TIMEOUT,
+                                    clone!(@weak this => move || {
+                                        this.pending_timeout.replace(None);
+                                        this.show_pending_dmabuf();
+                                        this.obj().render();
+                                    }),
+                                );
+                                this.pending_timeout.replace(Some(id));
                             }
                             #[cfg(unix)]
                             UpdateDMABUF { wait_tx, .. } => {
+                                if let Some(id) = this.pending_timeout.take() {
+                                    id.remove();
+                                }
+                                this.show_pending_dmabuf();
                                 this.obj().render();
                                 let _ = wait_tx.send(());
                             }
@@ -272,7 +355,7 @@ mod imp {
                             CursorDefine(c) => {
                                 log::debug!("{c:?}");
                                 let cursor = rdw::Display::make_cursor(
-                                    &c.data,
+                                    &c.straight_data(),
                                     c.width,
                                     c.height,
                                     c.hot_x,
@@ -305,6 +388,81 @@ mod imp {
     }
 
     impl rdw::DisplayImpl for Display {}
+
+    impl Display {
+        // toggle the guest lock keys to match the host keyboard
+        async fn sync_modifiers(&self) {
+            let tracker = match self.modifiers.get() {
+                Some(tracker) => tracker,
+                None => return,
+            };
+            let device = match self
+                .obj()
+                .display()
+                .default_seat()
+                .and_then(|s| s.keyboard())
+            {
+                Some(device) => device,
+                None => return,
+            };
+            let host = KeyboardLeds {
+                caps_lock: device.caps_lock_state(),
+                num_lock: device.num_lock_state(),
+                scroll_lock: device.scroll_lock_state(),
+            };
+            if let Err(e) = tracker.sync_leds(host).await {
+                log::warn!("Failed to sync the keyboard modifiers: {}", e);
+            }
+        }
+
+        #[cfg(unix)]
+        fn show_pending_dmabuf(&self) {
+            if let Some(s) = self.pending_dmabuf.take() {
+                self.obj().set_display_size(Some((s.width as _, s.height as _)));
+                self.obj().set_dmabuf_scanout(rdw::RdwDmabufScanout {
+                    width: s.width,
+                    height: s.height,
+                    stride: s.stride,
+                    fourcc: s.fourcc,
+                    y0_top: s.y0_top,
+                    modifier: s.modifier,
+                    fd: s.into_raw_fd(),
+                });
+            }
+        }
+
+        #[cfg(unix)]
+        fn cancel_pending_dmabuf(&self) {
+            if let Some(id) = self.pending_timeout.take() {
+                id.remove();
+            }
+            self.pending_dmabuf.replace(None);
+        }
+
+        // draw a region, converted to BGRA8888 when QEMU gives another format
+        #[allow(clippy::too_many_arguments)]
+        fn update_area(&self, x: i32, y: i32, w: u32, h: u32, stride: u32, format: u32, data: &[u8]) {
+            if format == PIXMAN_BGRA8888 {
+                self.obj().update_area(x as _, y as _, w as _, h as _, stride as _, data);
+                return;
+            }
+            match qemu_display::to_bgra(w, h, stride, format, data) {
+                Ok(data) => self.obj().update_area(x as _, y as _, w as _, h as _, (w * 4) as _, &data),
+                Err(e) => log::warn!("Failed to convert the frame: {}", e),
+            }
+        }
+
+        // shown in the window title, over the VM name
+        fn show_key_report(&self, report: &str) {
+            if let Some(window) = self
+                .obj()
+                .root()
+                .and_then(|r| r.downcast::<gtk::Window>().ok())
+            {
+                window.set_title(Some(report));
+            }
+        }
+    }
 }
 
 glib::wrapper! {
@@ -313,7 +471,7 @@ glib::wrapper! {
 
 impl Display {
     pub fn new(console: Console) -> Self {
-        let obj = glib::Object::new(&[]);
+        let obj = glib::Object::new(&[("grab-shortcut", &crate::settings::grab_shortcut())]);
         let self_ = imp::Display::from_instance(&obj);
         self_.console.set(console).unwrap();
         obj
@@ -325,6 +483,11 @@ impl Display {
     }
 }
 
+const WATCHDOG_PERIOD: Duration = Duration::from_secs(10);
+// the delay before showing a DMABUF scanout without update
+#[cfg(unix)]
+const PENDING_DMABUF_TIMEOUT: Duration = Duration::from_millis(100);
+
 #[derive(Debug)]
 enum ConsoleEvent {
     Scanout(qemu_display::Scanout),
@@ -345,6 +508,7 @@ enum ConsoleEvent {
     Disconnected,
 }
 
+#[derive(Clone)]
 struct ConsoleHandler {
     sender: futures::channel::mpsc::UnboundedSender<ConsoleEvent>,
 }
@@ -358,32 +522,32 @@ impl ConsoleHandler {
 }
 
 #[async_trait::async_trait]
-impl ConsoleListenerHandler for ConsoleHandler {
-    async fn scanout(&mut self, scanout: qemu_display::Scanout) {
+impl FrameSink for ConsoleHandler {
+    async fn on_scanout(&mut self, scanout: qemu_display::Scanout) {
         self.send(ConsoleEvent::Scanout(scanout));
     }
 
-    async fn update(&mut self, update: qemu_display::Update) {
+    async fn on_update(&mut self, update: qemu_display::Update) {
         self.send(ConsoleEvent::Update(update));
     }
 
     #[cfg(windows)]
-    async fn scanout_map(&mut self, scanout: qemu_display::ScanoutMap) {
+    async fn on_scanout_map(&mut self, scanout: qemu_display::ScanoutMap) {
         self.send(ConsoleEvent::ScanoutMap(scanout));
     }
 
     #[cfg(windows)]
-    async fn update_map(&mut self, update: qemu_display::UpdateMap) {
+    async fn on_update_map(&mut self, update: qemu_display::UpdateMap) {
         self.send(ConsoleEvent::UpdateMap(update));
     }
 
     #[cfg(unix)]
-    async fn scanout_dmabuf(&mut self, scanout: qemu_display::ScanoutDMABUF) {
+    async fn on_scanout_dmabuf(&mut self, scanout: qemu_display::ScanoutDMABUF) {
         self.send(ConsoleEvent::ScanoutDMABUF(scanout));
     }
 
     #[cfg(unix)]
-    async fn update_dmabuf(&mut self, _update: qemu_display::UpdateDMABUF) {
+    async fn on_update_dmabuf(&mut self, _update: qemu_display::UpdateDMABUF) {
         let (wait_tx, wait_rx) = futures::channel::oneshot::channel();
         self.send(ConsoleEvent::UpdateDMABUF { _update, wait_tx });
         if let Err(e) = wait_rx.await {
@@ -391,15 +555,15 @@ impl ConsoleListenerHandler for ConsoleHandler {
         }
     }
 
-    async fn mouse_set(&mut self, set: qemu_display::MouseSet) {
+    async fn on_mouse_set(&mut self, set: qemu_display::MouseSet) {
         self.send(ConsoleEvent::MouseSet(set));
     }
 
-    async fn cursor_define(&mut self, cursor: qemu_display::Cursor) {
+    async fn on_cursor(&mut self, cursor: qemu_display::Cursor) {
         self.send(ConsoleEvent::CursorDefine(cursor));
     }
 
-    fn disconnected(&mut self) {
+    fn on_disconnected(&mut self) {
         self.send(ConsoleEvent::Disconnected);
     }
 }
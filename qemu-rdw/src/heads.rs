@@ -0,0 +1,304 @@
+//! A window for each head of the VM, besides the main window of the first console.
+//!
+//! The heads can be disabled from the "Heads" menu: the window is closed, and the guest
+//! is told to disable the head with an empty `SetUIInfo`. The window sizes are kept in
+//! `heads.ini` of the user configuration directory.
+
+use futures_util::StreamExt;
+use glib::MainContext;
+use gtk::{gio, glib, prelude::*};
+use qemu_display::{ConsoleChange, ConsoleInfo, Display};
+use rdw::gtk;
+use std::{cell::RefCell, collections::BTreeMap, path::PathBuf, rc::Rc};
+
+use crate::{display, settings, window_title};
+
+// The window sizes, by head.
+struct Geometry {
+    path: PathBuf,
+    file: glib::KeyFile,
+}
+
+impl Geometry {
+    fn load() -> Self {
+        let path = glib::user_config_dir().join("qemu-rdw").join("heads.ini");
+        let file = glib::KeyFile::new();
+        // missing on the first run
+        let _ = file.load_from_file(&path, glib::KeyFileFlags::NONE);
+        Self { path, file }
+    }
+
+    // the console index may change between the runs, the device label and head don't
+    fn group(info: &ConsoleInfo) -> String {
+        format!("{} {}", info.label, info.head)
+    }
+
+    fn size(&self, info: &ConsoleInfo) -> (i32, i32, bool) {
+        let group = Self::group(info);
+        match (
+            self.file.integer(&group, "width"),
+            self.file.integer(&group, "height"),
+        ) {
+            (Ok(width), Ok(height)) => (
+                width,
+                height,
+                self.file.boolean(&group, "maximized").unwrap_or(false),
+            ),
+            _ => (
+                (info.width as i32).clamp(640, 1024),
+                (info.height as i32).clamp(480, 768),
+                false,
+            ),
+        }
+    }
+
+    fn save(&self, info: &ConsoleInfo, window: &gtk::ApplicationWindow) {
+        let group = Self::group(info);
+        let (width, height) = window.default_size();
+        self.file.set_integer(&group, "width", width);
+        self.file.set_integer(&group, "height", height);
+        self.file
+            .set_boolean(&group, "maximized", window.is_maximized());
+        if let Some(dir) = self.path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        if let Err(e) = self.file.save_to_file(&self.path) {
+            log::warn!("Failed to save the window sizes: {}", e);
+        }
+    }
+}
+
+fn is_head(info: &ConsoleInfo) -> bool {
+    info.type_ == "Graphic"
+}
+
+struct Head {
+    info: ConsoleInfo,
+    action: gio::SimpleAction,
+    window: Option<gtk::ApplicationWindow>,
+}
+
+struct Inner {
+    app: gtk::Application,
+    display: Display<'static>,
+    vm_name: Option<String>,
+    menu: gio::Menu,
+    heads: RefCell<BTreeMap<u32, Head>>,
+    geometry: Geometry,
+}
+
+#[derive(Clone)]
+pub struct Heads {
+    inner: Rc<Inner>,
+}
+
+impl Heads {
+    /// The heads are listed in `menu`, with an `app.head-<id>` action each. The window titles
+    /// are the `vm_name` and the console label.
+    pub fn new(
+        app: &gtk::Application,
+        display: Display<'static>,
+        menu: gio::Menu,
+        vm_name: Option<String>,
+    ) -> Self {
+        Self {
+            inner: Rc::new(Inner {
+                app: app.clone(),
+                display,
+                vm_name,
+                menu,
+                heads: Default::default(),
+                geometry: Geometry::load(),
+            }),
+        }
+    }
+
+    /// Open the graphic consoles other than `main`, and follow the added and removed ones.
+    pub async fn open(&self, main: u32) {
+        match self.inner.display.consoles().await {
+            Ok(consoles) => {
+                for info in consoles {
+                    if info.id != main && is_head(&info) {
+                        self.add(info);
+                    }
+                }
+            }
+            Err(e) => log::warn!("Failed to list the consoles: {}", e),
+        }
+
+        let this = self.clone();
+        MainContext::default().spawn_local(async move {
+            let mut changes = match this.inner.display.receive_console_changes().await {
+                Ok(changes) => changes,
+                Err(e) => {
+                    log::warn!("Failed to watch the consoles: {}", e);
+                    return;
+                }
+            };
+            while let Some(change) = changes.next().await {
+                match change {
+                    ConsoleChange::Added(info) if info.id != main && is_head(&info) => {
+                        this.add(info)
+                    }
+                    ConsoleChange::Added(_) => {}
+                    ConsoleChange::Removed(id) => this.remove(id),
+                }
+            }
+        });
+    }
+
+    fn add(&self, info: ConsoleInfo) {
+        let id = info.id;
+        let action =
+            gio::SimpleAction::new_stateful(&format!("head-{}", id), None, &true.to_variant());
+        let weak = Rc::downgrade(&self.inner);
+        action.connect_change_state(move |action, state| {
+            let enabled = state.and_then(|s| s.get::<bool>()).unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            if let Some(inner) = weak.upgrade() {
+                Heads { inner }.set_enabled(id, enabled);
+            }
+        });
+        self.inner.app.add_action(&action);
+        let old = self.inner.heads.borrow_mut().insert(
+            id,
+            Head {
+                info,
+                action,
+                window: None,
+            },
+        );
+        // the console was replaced, before we could see it removed
+        if let Some(window) = old.and_then(|old| old.window) {
+            window.close();
+        }
+        self.update_menu();
+        self.open_window(id);
+    }
+
+    fn remove(&self, id: u32) {
+        let head = self.inner.heads.borrow_mut().remove(&id);
+        if let Some(head) = head {
+            self.inner.app.remove_action(&format!("head-{}", id));
+            if let Some(window) = head.window {
+                self.inner.geometry.save(&head.info, &window);
+                window.close();
+            }
+            self.update_menu();
+        }
+    }
+
+    fn update_menu(&self) {
+        let menu = &self.inner.menu;
+        menu.remove_all();
+        for (id, head) in self.inner.heads.borrow().iter() {
+            let info = &head.info;
+            let label = if info.device_address.is_empty() {
+                format!("#{} {} (head {})", id, info.label, info.head)
+            } else {
+                format!(
+                    "#{} {} (head {}, {})",
+                    id, info.label, info.head, info.device_address
+                )
+            };
+            menu.append(Some(&label), Some(&format!("app.head-{}", id)));
+        }
+    }
+
+    /// The title of a head window, `None` for the other windows.
+    pub fn title(&self, window: &gtk::Window) -> Option<String> {
+        self.inner
+            .heads
+            .borrow()
+            .values()
+            .find(|head| {
+                head.window.as_ref().map(|w| w.upcast_ref::<gtk::Window>()) == Some(window)
+            })
+            .map(|head| window_title(self.inner.vm_name.as_deref(), &head.info.label))
+    }
+
+    fn set_enabled(&self, id: u32, enabled: bool) {
+        let window = match self.inner.heads.borrow().get(&id) {
+            Some(head) => head.window.clone(),
+            None => return,
+        };
+        match (enabled, window) {
+            (true, None) => self.open_window(id),
+            // the guest is told on close
+            (false, Some(window)) => window.close(),
+            _ => {}
+        }
+    }
+
+    fn open_window(&self, id: u32) {
+        let this = self.clone();
+        MainContext::default().spawn_local(async move {
+            let console = match this.inner.display.console(id).await {
+                Ok(console) => console,
+                Err(e) => {
+                    log::warn!("Failed to get console {}: {}", id, e);
+                    return;
+                }
+            };
+            let mut heads = this.inner.heads.borrow_mut();
+            let head = match heads.get_mut(&id) {
+                Some(head) if head.window.is_none() => head,
+                // removed or opened meanwhile
+                _ => return,
+            };
+            let (width, height, maximized) = this.inner.geometry.size(&head.info);
+            let window = gtk::ApplicationWindow::builder()
+                .application(&this.inner.app)
+                .title(&window_title(
+                    this.inner.vm_name.as_deref(),
+                    &head.info.label,
+                ))
+                .default_width(width)
+                .default_height(height)
+                .maximized(maximized)
+                .child(&display::Display::new(console))
+                .build();
+            settings::add_shortcuts(&window);
+            let weak = Rc::downgrade(&this.inner);
+            window.connect_close_request(move |window| {
+                if let Some(inner) = weak.upgrade() {
+                    Heads { inner }.closed(id, window);
+                }
+                gtk::Inhibit(false)
+            });
+            head.window = Some(window.clone());
+            head.action.set_state(&true.to_variant());
+            drop(heads);
+            window.show();
+        });
+    }
+
+    // Closed by the user or from the menu: keep the size, and disable the head.
+    fn closed(&self, id: u32, window: &gtk::ApplicationWindow) {
+        let mut heads = self.inner.heads.borrow_mut();
+        let head = match heads.get_mut(&id) {
+            Some(head) if head.window.as_ref() == Some(window) => head,
+            // removed by the VM
+            _ => return,
+        };
+        head.window = None;
+        head.action.set_state(&false.to_variant());
+        self.inner.geometry.save(&head.info, window);
+        drop(heads);
+
+        let display = self.inner.display.clone();
+        MainContext::default().spawn_local(async move {
+            let res = match display.console(id).await {
+                Ok(console) => console
+                    .proxy
+                    .set_ui_info(0, 0, 0, 0, 0, 0)
+                    .await
+                    .map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                log::warn!("Failed to disable head {}: {}", id, e);
+            }
+        });
+    }
+}
@@ -2,7 +2,7 @@ use futures_util::StreamExt;
 use gio::ApplicationFlags;
 use glib::MainContext;
 use gtk::{gio, glib, prelude::*};
-use qemu_display::{util, Chardev, Console, Display};
+use qemu_display::{Chardev, Console, Display};
 use rdw::gtk;
 use std::{cell::RefCell, convert::TryFrom, sync::Arc};
 use zbus::names::BusName;
@@ -10,15 +10,36 @@ use zbus::names::BusName;
 mod audio;
 mod clipboard;
 mod display;
+mod heads;
+#[cfg(unix)]
+mod serial;
+mod settings;
+mod transfer;
 #[cfg(unix)]
 mod usbredir;
 
+// as in main.ui
+const TITLE: &str = "qemu-rdw demo";
+
+// The VM name and the console label, in the window titles.
+fn window_title(vm_name: Option<&str>, label: &str) -> String {
+    match (vm_name, label) {
+        (name, "") => name.unwrap_or(TITLE).to_string(),
+        (name, label) => format!("{} - {}", name.unwrap_or(TITLE), label),
+    }
+}
+
 struct Inner {
     app: gtk::Application,
     #[cfg(unix)]
     usbredir: RefCell<Option<usbredir::Handler>>,
     audio: RefCell<Option<audio::Handler>>,
     clipboard: RefCell<Option<clipboard::Handler>>,
+    heads: RefCell<Option<heads::Heads>>,
+    #[cfg(unix)]
+    serial: RefCell<Option<serial::Serial>>,
+    // of the main window, shown again after the key reports
+    title: RefCell<String>,
 }
 
 #[derive(Clone)]
@@ -30,10 +51,16 @@ struct App {
 struct AppOptions {
     vm_name: Option<String>,
     address: Option<String>,
+    #[cfg(feature = "ssh")]
+    ssh: Option<String>,
     #[cfg(feature = "qmp")]
     qmp: Option<String>,
     list: bool,
     wait: bool,
+    clipboard_confirm: bool,
+    tile: bool,
+    audio_in_device: Option<String>,
+    audio_in_level: Option<f64>,
 }
 
 async fn display_from_opt(opt: Arc<RefCell<AppOptions>>) -> Option<Display<'static>> {
@@ -41,7 +68,23 @@ async fn display_from_opt(opt: Arc<RefCell<AppOptions>>) -> Option<Display<'stat
     if let Some(qmp_addr) = &opt.borrow().qmp {
         return Some(Display::new_qmp(qmp_addr).await.unwrap());
     }
-    let builder = if let Some(addr) = &opt.borrow().address {
+    #[cfg(feature = "ssh")]
+    let tunnel = match &opt.borrow().ssh {
+        Some(destination) => Some(
+            qemu_display::SshTunnel::session_bus(destination)
+                .await
+                .expect("Failed to open the ssh tunnel"),
+        ),
+        None => None,
+    };
+    #[cfg(feature = "ssh")]
+    let address = tunnel
+        .as_ref()
+        .map(qemu_display::SshTunnel::address)
+        .or_else(|| opt.borrow().address.clone());
+    #[cfg(not(feature = "ssh"))]
+    let address = opt.borrow().address.clone();
+    let builder = if let Some(addr) = &address {
         zbus::ConnectionBuilder::address(addr.as_str())
     } else {
         zbus::ConnectionBuilder::session()
@@ -53,12 +96,14 @@ async fn display_from_opt(opt: Arc<RefCell<AppOptions>>) -> Option<Display<'stat
         .await
         .expect("Failed to connect to DBus");
 
-    let conn_clone = conn.clone();
-    MainContext::default().spawn_local(async move {
-        loop {
-            conn_clone.executor().tick().await;
-        }
-    });
+    let executor = qemu_display::run_executor(conn.clone());
+    #[cfg(feature = "ssh")]
+    let executor = async move {
+        // the tunnel is kept open with the connection
+        let _tunnel = tunnel;
+        executor.await
+    };
+    MainContext::default().spawn_local(executor);
 
     if opt.borrow().list {
         let list = Display::by_name(&conn).await.unwrap();
@@ -108,6 +153,15 @@ impl App {
             "D-Bus bus address",
             None,
         );
+        #[cfg(feature = "ssh")]
+        app.add_main_option(
+            "ssh",
+            glib::Char(0),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::String,
+            "Connect to the session bus of a remote host, through ssh",
+            Some("[USER@]HOST"),
+        );
         #[cfg(feature = "qmp")]
         app.add_main_option(
             "qmp",
@@ -133,6 +187,55 @@ impl App {
             "Wait for display to be available",
             None,
         );
+        app.add_main_option(
+            "clipboard-confirm",
+            glib::Char(0),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::None,
+            "Ask before pasting the host clipboard in the guest",
+            None,
+        );
+        app.add_main_option(
+            "tile",
+            glib::Char(0),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::None,
+            "Show all the consoles tiled, to debug multi-head guests",
+            None,
+        );
+        app.add_main_option(
+            "audio-in-device",
+            glib::Char(0),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::String,
+            "Audio capture device name",
+            Some("NAME"),
+        );
+        app.add_main_option(
+            "audio-in-level",
+            glib::Char(0),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::Double,
+            "Audio capture level (default: 1.0)",
+            Some("LEVEL"),
+        );
+        app.add_main_option(
+            "debug-keys",
+            glib::Char(0),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::None,
+            "Report the key translations, in the window and the info log (also in the menu)",
+            None,
+        );
+        #[cfg(feature = "prometheus")]
+        app.add_main_option(
+            "metrics-address",
+            glib::Char(0),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::String,
+            "Serve the listener metrics to Prometheus, over HTTP",
+            Some("ADDRESS:PORT"),
+        );
         app.add_main_option(
             "version",
             glib::Char(0),
@@ -153,6 +256,10 @@ impl App {
             if let Some(arg) = opt.lookup_value("address", None) {
                 app_opt.address = arg.get::<String>();
             }
+            #[cfg(feature = "ssh")]
+            if let Some(arg) = opt.lookup_value("ssh", None) {
+                app_opt.ssh = arg.get::<String>();
+            }
             #[cfg(feature = "qmp")]
             if let Some(arg) = opt.lookup_value("qmp", None) {
                 app_opt.qmp = arg.get::<String>();
@@ -163,6 +270,34 @@ impl App {
             if opt.lookup_value("wait", None).is_some() {
                 app_opt.wait = true;
             }
+            if opt.lookup_value("clipboard-confirm", None).is_some() {
+                app_opt.clipboard_confirm = true;
+            }
+            if opt.lookup_value("tile", None).is_some() {
+                app_opt.tile = true;
+            }
+            if opt.lookup_value("debug-keys", None).is_some() {
+                qemu_display::set_key_debug(true);
+            }
+            if let Some(arg) = opt.lookup_value("audio-in-device", None) {
+                app_opt.audio_in_device = arg.get::<String>();
+            }
+            if let Some(arg) = opt.lookup_value("audio-in-level", None) {
+                app_opt.audio_in_level = arg.get::<f64>();
+            }
+            #[cfg(feature = "prometheus")]
+            if let Some(address) = opt
+                .lookup_value("metrics-address", None)
+                .and_then(|arg| arg.get::<String>())
+            {
+                match qemu_display::serve_prometheus(address.as_str()) {
+                    Ok(address) => println!("Metrics at http://{}/metrics", address),
+                    Err(e) => {
+                        eprintln!("Failed to serve the metrics: {}", e);
+                        return 1;
+                    }
+                }
+            }
             app_opt.vm_name = opt
                 .lookup_value(&glib::OPTION_REMAINING, None)
                 .and_then(|args| args.child_value(0).get::<String>());
@@ -176,6 +311,10 @@ impl App {
                 usbredir: Default::default(),
                 audio: Default::default(),
                 clipboard: Default::default(),
+                heads: Default::default(),
+                #[cfg(unix)]
+                serial: Default::default(),
+                title: RefCell::new(TITLE.to_string()),
             }),
         };
 
@@ -188,10 +327,22 @@ impl App {
                 .expect("Couldn't add from string");
             let window: gtk::ApplicationWindow =
                 builder.object("window").expect("Couldn't get window");
+            let heads_menu: gio::Menu = builder.object("heads_menu").expect("Couldn't get menu");
+            #[cfg(unix)]
+            let serial_menu: gio::Menu = builder.object("serial_menu").expect("Couldn't get menu");
             window.set_application(Some(app));
+            settings::add_shortcuts(&window);
+            if let Some(action) = app.lookup_action("debug-keys") {
+                action.change_state(&qemu_display::key_debug().to_variant());
+            }
 
             let app_clone = app_clone.clone();
             let opt_clone = opt.clone();
+            let clipboard_confirm = opt.borrow().clipboard_confirm;
+            let tile = opt.borrow().tile;
+            let audio_in_device = opt.borrow().audio_in_device.clone();
+            // the range of the gstreamer volume element
+            let audio_in_level = opt.borrow().audio_in_level.unwrap_or(1.0).clamp(0.0, 10.0);
             MainContext::default().spawn_local(async move {
                 let display = match display_from_opt(opt_clone).await {
                     Some(d) => d,
@@ -208,27 +359,72 @@ impl App {
                     }
                 });
 
-                let console = Console::new(
-                    display.connection(),
-                    0,
-                    #[cfg(windows)]
-                    display.peer_pid(),
-                )
-                .await
-                .expect("Failed to get the QEMU console");
-                let rdw = display::Display::new(console);
+                let vm_name = display.name().await.ok();
+                let (child, title): (gtk::Widget, _) = if tile {
+                    let title = window_title(vm_name.as_deref(), "");
+                    (tile_consoles(&display).await.upcast(), title)
+                } else {
+                    let console = Console::new(
+                        display.connection(),
+                        0,
+                        #[cfg(windows)]
+                        display.peer_pid(),
+                    )
+                    .await
+                    .expect("Failed to get the QEMU console");
+                    let label = console.label().await.unwrap_or_default();
+                    let title = window_title(vm_name.as_deref(), &label);
+                    (display::Display::new(console).upcast(), title)
+                };
+                window.set_title(Some(&title));
+                app_clone.inner.title.replace(title);
+                match display.file_transfer().await {
+                    Ok(Some(transfer)) => {
+                        child.add_controller(
+                            &transfer::Handler::new(transfer).drop_target(window.upcast_ref()),
+                        );
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Failed to setup file transfer: {}", e),
+                }
+                // the serial consoles are opened in tabs
+                #[cfg(unix)]
+                let child = {
+                    let notebook = gtk::Notebook::builder()
+                        .show_tabs(false)
+                        .show_border(false)
+                        .build();
+                    notebook.append_page(&child, Some(&gtk::Label::new(Some("Display"))));
+                    let serial = serial::Serial::new(display.clone(), notebook.clone());
+                    serial.fill_menu(&serial_menu).await;
+                    app_clone.set_serial(serial);
+                    notebook.upcast::<gtk::Widget>()
+                };
                 app_clone
                     .inner
                     .app
                     .active_window()
                     .unwrap()
-                    .set_child(Some(&rdw));
+                    .set_child(Some(&child));
+                if !tile {
+                    let heads = heads::Heads::new(
+                        &app_clone.inner.app,
+                        display.clone(),
+                        heads_menu,
+                        vm_name,
+                    );
+                    heads.open(0).await;
+                    app_clone.set_heads(heads);
+                }
 
                 #[cfg(unix)]
-                app_clone.set_usbredir(usbredir::Handler::new(display.usbredir().await));
+                app_clone.set_usbredir(usbredir::Handler::new(
+                    display.usbredir().await,
+                    display.uuid().await.ok(),
+                ));
 
                 if let Ok(Some(audio)) = display.audio().await {
-                    match audio::Handler::new(audio).await {
+                    match audio::Handler::new(audio, audio_in_device, audio_in_level).await {
                         Ok(handler) => app_clone.set_audio(handler),
                         Err(e) => {
                             log::warn!("Failed to setup audio handler: {}", e);
@@ -237,7 +433,7 @@ impl App {
                 }
 
                 if let Ok(Some(clipboard)) = display.clipboard().await {
-                    match clipboard::Handler::new(clipboard).await {
+                    match clipboard::Handler::new(clipboard, clipboard_confirm).await {
                         Ok(handler) => app_clone.set_clipboard(handler),
                         Err(e) => {
                             log::warn!("Failed to setup clipboard handler: {}", e);
@@ -245,22 +441,18 @@ impl App {
                     }
                 }
 
-                if let Ok(c) = Chardev::new(display.connection(), "qmp").await {
-                    use std::io::{prelude::*, BufReader};
-                    #[cfg(unix)]
-                    use std::os::unix::net::UnixStream;
+                if let Ok(c) = Chardev::new(
+                    display.connection(),
+                    "qmp",
                     #[cfg(windows)]
-                    use uds_windows::UnixStream;
+                    display.peer_pid(),
+                )
+                .await
+                {
+                    use std::io::{prelude::*, BufReader};
 
-                    let (p0, p1) = UnixStream::pair().unwrap();
-                    let fd = util::prepare_uds_pass(
-                        #[cfg(windows)]
-                        display.peer_pid(),
-                        &p1,
-                    )
-                    .unwrap();
-                    if c.proxy.register(fd).await.is_ok() {
-                        let mut reader = BufReader::new(p0.try_clone().unwrap());
+                    if let Ok(stream) = c.open_stream().await {
+                        let mut reader = BufReader::new(stream);
                         let mut line = String::new();
                         std::thread::spawn(move || loop {
                             if reader.read_line(&mut line).unwrap() > 0 {
@@ -274,6 +466,37 @@ impl App {
             });
         });
 
+        let action_debug_keys =
+            gio::SimpleAction::new_stateful("debug-keys", None, &false.to_variant());
+        let app_clone = app.clone();
+        action_debug_keys.connect_activate(move |action, _| {
+            let enabled = !qemu_display::key_debug();
+            action.set_state(&enabled.to_variant());
+            qemu_display::set_key_debug(enabled);
+            if !enabled {
+                if let Some(window) = app_clone.inner.app.active_window() {
+                    let title = app_clone
+                        .inner
+                        .heads
+                        .borrow()
+                        .as_ref()
+                        .and_then(|h| h.title(&window));
+                    let title = title.unwrap_or_else(|| app_clone.inner.title.borrow().clone());
+                    window.set_title(Some(&title));
+                }
+            }
+        });
+        app.inner.app.add_action(&action_debug_keys);
+
+        let action_preferences = gio::SimpleAction::new("preferences", None);
+        let app_clone = app.clone();
+        action_preferences.connect_activate(move |_, _| {
+            let dialog = settings::dialog();
+            dialog.set_transient_for(app_clone.inner.app.active_window().as_ref());
+            dialog.show();
+        });
+        app.inner.app.add_action(&action_preferences);
+
         #[cfg(unix)]
         {
             let action_usb = gio::SimpleAction::new("usb", None);
@@ -281,13 +504,25 @@ impl App {
             action_usb.connect_activate(move |_, _| {
                 let usbredir = app_clone.inner.usbredir.borrow();
                 if let Some(usbredir) = usbredir.as_ref() {
-                    let dialog = gtk::Dialog::new();
+                    let dialog = gtk::Dialog::builder().title("USB devices").build();
                     dialog.set_transient_for(app_clone.inner.app.active_window().as_ref());
                     dialog.set_child(Some(&usbredir.widget()));
                     dialog.show();
                 }
             });
             app.inner.app.add_action(&action_usb);
+
+            let action_serial = gio::SimpleAction::new("serial", Some(glib::VariantTy::STRING));
+            let app_clone = app.clone();
+            action_serial.connect_activate(move |_, name| {
+                let serial = app_clone.inner.serial.borrow();
+                if let (Some(serial), Some(name)) =
+                    (serial.as_ref(), name.and_then(|n| n.get::<String>()))
+                {
+                    serial.open(&name);
+                }
+            });
+            app.inner.app.add_action(&action_serial);
         }
 
         app
@@ -298,6 +533,11 @@ impl App {
         self.inner.usbredir.replace(Some(usbredir));
     }
 
+    #[cfg(unix)]
+    fn set_serial(&self, serial: serial::Serial) {
+        self.inner.serial.replace(Some(serial));
+    }
+
     fn set_audio(&self, audio: audio::Handler) {
         self.inner.audio.replace(Some(audio));
     }
@@ -306,11 +546,77 @@ impl App {
         self.inner.clipboard.replace(Some(cb));
     }
 
+    fn set_heads(&self, heads: heads::Heads) {
+        self.inner.heads.replace(Some(heads));
+    }
+
     fn run(&self) -> i32 {
         self.inner.app.run()
     }
 }
 
+// A grid of all the consoles, each with a label. Input goes to the focused tile.
+async fn tile_consoles(display: &Display<'_>) -> gtk::Grid {
+    let grid = gtk::Grid::builder()
+        .row_homogeneous(true)
+        .column_homogeneous(true)
+        .row_spacing(4)
+        .column_spacing(4)
+        .build();
+    let consoles = match display.consoles().await {
+        Ok(consoles) => consoles,
+        Err(e) => {
+            log::warn!("Failed to list the consoles: {}", e);
+            return grid;
+        }
+    };
+    let columns = (consoles.len() as f64).sqrt().ceil().max(1.0) as usize;
+    for (i, info) in consoles.iter().enumerate() {
+        let console = match Console::new(
+            display.connection(),
+            info.id,
+            #[cfg(windows)]
+            display.peer_pid(),
+        )
+        .await
+        {
+            Ok(console) => console,
+            Err(e) => {
+                log::warn!("Failed to get console {}: {}", info.id, e);
+                continue;
+            }
+        };
+
+        let mut text = format!(
+            "#{} {} ({}, head {}",
+            info.id, info.label, info.type_, info.head
+        );
+        if !info.device_address.is_empty() {
+            text.push_str(&format!(", {}", info.device_address));
+        }
+        text.push(')');
+        let label = gtk::Label::new(Some(&text));
+        let rdw = display::Display::new(console);
+        rdw.set_hexpand(true);
+        rdw.set_vexpand(true);
+        // highlight the tile receiving the input
+        let focus = gtk::EventControllerFocus::new();
+        focus.connect_enter(glib::clone!(@weak label => move |_| {
+            label.add_css_class("heading");
+        }));
+        focus.connect_leave(glib::clone!(@weak label => move |_| {
+            label.remove_css_class("heading");
+        }));
+        rdw.add_controller(&focus);
+
+        let tile = gtk::Box::new(gtk::Orientation::Vertical, 2);
+        tile.append(&label);
+        tile.append(&rdw);
+        grid.attach(&tile, (i % columns) as _, (i / columns) as _, 1, 1);
+    }
+    grid
+}
+
 fn main() {
     pretty_env_logger::init();
     tracing_subscriber::fmt::init();
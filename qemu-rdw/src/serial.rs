@@ -0,0 +1,163 @@
+//! The serial consoles of the VM, in terminal tabs next to the display.
+
+use futures::{channel::oneshot, future, prelude::*};
+use glib::MainContext;
+use gtk::{gio, glib, prelude::*};
+use qemu_display::{Chardev, Display};
+use rdw::gtk;
+use std::{cell::RefCell, rc::Rc};
+use vte::prelude::*;
+
+struct Inner {
+    display: Display<'static>,
+    notebook: gtk::Notebook,
+    // the terminals, by chardev name
+    tabs: RefCell<Vec<(String, vte::Terminal)>>,
+}
+
+#[derive(Clone)]
+pub struct Serial {
+    inner: Rc<Inner>,
+}
+
+impl Serial {
+    /// The terminals are added to `notebook`, the first page being the display.
+    pub fn new(display: Display<'static>, notebook: gtk::Notebook) -> Self {
+        Self {
+            inner: Rc::new(Inner {
+                display,
+                notebook,
+                tabs: Default::default(),
+            }),
+        }
+    }
+
+    /// List the serial chardevs in `menu`, with the `app.serial` action.
+    pub async fn fill_menu(&self, menu: &gio::Menu) {
+        let mut names = vec![];
+        for c in self.inner.display.chardevs().await {
+            match c.proxy.name().await {
+                Ok(name) if name.starts_with("serial") => names.push(name),
+                _ => {}
+            }
+        }
+        names.sort();
+        for name in names {
+            let item = gio::MenuItem::new(Some(&name), None);
+            item.set_action_and_target_value(Some("app.serial"), Some(&name.to_variant()));
+            menu.append_item(&item);
+        }
+    }
+
+    /// Show the terminal of a chardev, connecting it first.
+    pub fn open(&self, name: &str) {
+        let notebook = &self.inner.notebook;
+        if let Some((_, term)) = self.inner.tabs.borrow().iter().find(|(n, _)| n == name) {
+            notebook.set_current_page(notebook.page_num(term));
+            return;
+        }
+
+        let term = vte::Terminal::new();
+        let label = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        label.append(&gtk::Label::new(Some(name)));
+        let close = gtk::Button::from_icon_name("window-close-symbolic");
+        close.add_css_class("flat");
+        label.append(&close);
+        let page = notebook.append_page(&term, Some(&label));
+        notebook.set_show_tabs(true);
+        notebook.set_current_page(Some(page));
+        self.inner
+            .tabs
+            .borrow_mut()
+            .push((name.to_string(), term.clone()));
+
+        let weak = Rc::downgrade(&self.inner);
+        let closed_term = term.clone();
+        close.connect_clicked(move |_| {
+            if let Some(inner) = weak.upgrade() {
+                Serial { inner }.close(&closed_term);
+            }
+        });
+
+        let display = self.inner.display.clone();
+        let name = name.to_string();
+        let weak_term = term.downgrade();
+        MainContext::default().spawn_local(async move {
+            let res = match find_chardev(&display, &name).await {
+                Some(chardev) => forward(chardev, weak_term.clone()).await,
+                None => Err(qemu_display::Error::Failed(format!("No chardev {}", name))),
+            };
+            log::debug!("Serial console {} disconnected: {:?}", name, res);
+            // the tab is kept, to see the error or the last output
+            if let (Err(e), Some(term)) = (res, weak_term.upgrade()) {
+                term.feed(format!("\r\n[{}]\r\n", e).as_bytes());
+            }
+        });
+    }
+
+    fn close(&self, term: &vte::Terminal) {
+        let notebook = &self.inner.notebook;
+        if let Some(page) = notebook.page_num(term) {
+            notebook.remove_page(Some(page));
+        }
+        self.inner.tabs.borrow_mut().retain(|(_, t)| t != term);
+        notebook.set_show_tabs(notebook.n_pages() > 1);
+    }
+}
+
+async fn find_chardev(display: &Display<'_>, name: &str) -> Option<Chardev> {
+    for c in display.chardevs().await {
+        if c.proxy.name().await.ok().as_deref() == Some(name) {
+            return Some(c);
+        }
+    }
+    None
+}
+
+// Copy between the chardev and the terminal, until either closes.
+async fn forward(chardev: Chardev, term: glib::WeakRef<vte::Terminal>) -> qemu_display::Result<()> {
+    let conn = chardev.connect().await?;
+    let (mut read, mut write) = conn.split();
+
+    let (sender, mut receiver) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+    let (closed_tx, closed_rx) = oneshot::channel::<()>();
+    let closed_tx = RefCell::new(Some(closed_tx));
+    match term.upgrade() {
+        Some(term) => {
+            term.connect_commit(move |_, text, _| {
+                let _res = sender.unbounded_send(text.as_bytes().to_vec());
+            });
+            term.connect_destroy(move |_| {
+                if let Some(tx) = closed_tx.take() {
+                    let _ = tx.send(());
+                }
+            });
+        }
+        None => return Ok(()),
+    }
+
+    let input = async move {
+        while let Some(text) = receiver.next().await {
+            write.write_all(&text).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let output = async move {
+        let mut buffer = [0u8; 8192];
+        loop {
+            let len = read.read(&mut buffer[..]).await?;
+            match term.upgrade() {
+                Some(term) if len > 0 => term.feed(&buffer[..len]),
+                _ => return Ok::<_, std::io::Error>(()),
+            }
+        }
+    };
+    futures::pin_mut!(input, output);
+    let io = future::select(input, output).map(|either| either.factor_first().0);
+    futures::pin_mut!(io);
+    match future::select(io, closed_rx).await {
+        future::Either::Left((res, _)) => Ok(res?),
+        // the tab was closed
+        future::Either::Right(_) => Ok(()),
+    }
+}
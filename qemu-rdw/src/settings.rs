@@ -0,0 +1,184 @@
+//! The preferences, in GSettings: the fullscreen and grab shortcuts, and the USB devices
+//! redirected to each VM.
+//!
+//! The `org.qemu.rdw.demo` schema is looked up in the installed ones, then in the one
+//! compiled by the build. Without it, the defaults are used and nothing is saved.
+
+use glib::clone;
+use gtk::{gio, glib, prelude::*};
+use rdw::gtk;
+use std::collections::HashMap;
+
+const SCHEMA_ID: &str = "org.qemu.rdw.demo";
+const FULLSCREEN_KEY: &str = "fullscreen-shortcut";
+const GRAB_KEY: &str = "grab-shortcut";
+const USB_DEVICES_KEY: &str = "usb-devices";
+// as in the schema
+const DEFAULT_FULLSCREEN: &str = "<Control><Alt>f";
+const DEFAULT_GRAB: &str = "<Control><Alt>g";
+
+thread_local! {
+    static SETTINGS: Option<gio::Settings> = load();
+}
+
+fn load() -> Option<gio::Settings> {
+    let default = gio::SettingsSchemaSource::default();
+    let schema = default
+        .as_ref()
+        .and_then(|source| source.lookup(SCHEMA_ID, true))
+        .or_else(|| {
+            gio::SettingsSchemaSource::from_directory(env!("OUT_DIR"), default.as_ref(), false)
+                .ok()?
+                .lookup(SCHEMA_ID, false)
+        });
+    match schema {
+        Some(schema) => Some(gio::Settings::new_full(
+            &schema,
+            None::<&gio::SettingsBackend>,
+            None,
+        )),
+        None => {
+            log::info!("The {} schema is missing, using the defaults", SCHEMA_ID);
+            None
+        }
+    }
+}
+
+fn settings() -> Option<gio::Settings> {
+    SETTINGS.with(Clone::clone)
+}
+
+fn shortcut(key: &str, default: &str) -> gtk::ShortcutTrigger {
+    settings()
+        .and_then(|s| gtk::ShortcutTrigger::parse_string(&s.string(key)))
+        .unwrap_or_else(|| gtk::ShortcutTrigger::parse_string(default).unwrap())
+}
+
+/// The trigger to grab and release the input, given to the displays when created.
+pub fn grab_shortcut() -> gtk::ShortcutTrigger {
+    shortcut(GRAB_KEY, DEFAULT_GRAB)
+}
+
+/// Add the window shortcuts, handled before the display widget sees the keys.
+pub fn add_shortcuts(window: &gtk::ApplicationWindow) {
+    let fullscreen = gtk::Shortcut::new(
+        Some(&shortcut(FULLSCREEN_KEY, DEFAULT_FULLSCREEN)),
+        Some(&gtk::CallbackAction::new(|widget, _| {
+            if let Some(window) = widget.downcast_ref::<gtk::Window>() {
+                window.set_fullscreened(!window.is_fullscreen());
+            }
+            true
+        })),
+    );
+    if let Some(settings) = settings() {
+        settings.connect_changed(
+            Some(FULLSCREEN_KEY),
+            clone!(@weak fullscreen => move |_, _| {
+                fullscreen.set_trigger(Some(&shortcut(FULLSCREEN_KEY, DEFAULT_FULLSCREEN)));
+            }),
+        );
+    }
+
+    let controller = gtk::ShortcutController::new();
+    controller.set_propagation_phase(gtk::PropagationPhase::Capture);
+    controller.add_shortcut(&fullscreen);
+    window.add_controller(&controller);
+}
+
+fn parse_usb_ids(ids: &str) -> Option<(u16, u16)> {
+    let (vendor, product) = ids.split_once(':')?;
+    Some((
+        u16::from_str_radix(vendor, 16).ok()?,
+        u16::from_str_radix(product, 16).ok()?,
+    ))
+}
+
+fn usb_device_map(settings: &gio::Settings) -> HashMap<String, Vec<String>> {
+    settings.value(USB_DEVICES_KEY).get().unwrap_or_default()
+}
+
+/// The vendor and product IDs of the USB devices redirected to the VM with `uuid`.
+pub fn usb_devices(uuid: &str) -> Vec<(u16, u16)> {
+    settings()
+        .and_then(|s| usb_device_map(&s).remove(uuid))
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|ids| parse_usb_ids(ids))
+        .collect()
+}
+
+/// Remember or forget a USB device redirected to the VM with `uuid`.
+pub fn set_usb_device(uuid: &str, (vendor_id, product_id): (u16, u16), redirected: bool) {
+    let settings = match settings() {
+        Some(settings) => settings,
+        None => return,
+    };
+    let mut vms = usb_device_map(&settings);
+    let devices = vms.entry(uuid.to_string()).or_default();
+    let ids = format!("{:04x}:{:04x}", vendor_id, product_id);
+    devices.retain(|d| *d != ids);
+    if redirected {
+        devices.push(ids);
+    } else if devices.is_empty() {
+        vms.remove(uuid);
+    }
+    if let Err(e) = settings.set_value(USB_DEVICES_KEY, &vms.to_variant()) {
+        log::warn!("Failed to save the USB devices: {}", e);
+    }
+}
+
+fn shortcut_entry(settings: &gio::Settings, key: &str) -> gtk::Entry {
+    let entry = gtk::Entry::builder().hexpand(true).build();
+    settings.bind(key, &entry, "text").build();
+    // an invalid trigger is saved, but the default is used
+    let check = |entry: &gtk::Entry| {
+        if gtk::ShortcutTrigger::parse_string(&entry.text()).is_some() {
+            entry.remove_css_class("error");
+        } else {
+            entry.add_css_class("error");
+        }
+    };
+    check(&entry);
+    entry.connect_changed(check);
+    entry
+}
+
+/// The preferences dialog, saving the changes right away.
+pub fn dialog() -> gtk::Dialog {
+    let dialog = gtk::Dialog::builder().title("Preferences").build();
+    let settings = match settings() {
+        Some(settings) => settings,
+        None => {
+            dialog.set_child(Some(&gtk::Label::new(Some(
+                "The preferences can't be changed, their GSettings schema is missing.",
+            ))));
+            return dialog;
+        }
+    };
+
+    let grid = gtk::Grid::builder()
+        .row_spacing(6)
+        .column_spacing(12)
+        .margin_top(12)
+        .margin_bottom(12)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+    let rows = [
+        ("_Fullscreen", FULLSCREEN_KEY),
+        ("_Grab input (next windows)", GRAB_KEY),
+    ];
+    for (row, (label, key)) in rows.iter().enumerate() {
+        let entry = shortcut_entry(&settings, key);
+        let label = gtk::Label::builder()
+            .label(*label)
+            .use_underline(true)
+            .mnemonic_widget(&entry)
+            .xalign(0.0)
+            .build();
+        grid.attach(&label, 0, row as _, 1, 1);
+        grid.attach(&entry, 1, row as _, 1, 1);
+    }
+    dialog.set_child(Some(&grid));
+    dialog
+}
@@ -0,0 +1,57 @@
+use glib::{clone, MainContext};
+use gtk::{gdk, glib, prelude::*};
+use qemu_display::{FileTransfer, TransferProgress};
+use rdw::gtk;
+
+#[derive(Clone, Debug)]
+pub struct Handler {
+    transfer: FileTransfer,
+}
+
+impl Handler {
+    pub fn new(transfer: FileTransfer) -> Self {
+        Self { transfer }
+    }
+
+    /// Send the files dropped on the widget, with the progress in the window title.
+    pub fn drop_target(&self, window: &gtk::Window) -> gtk::DropTarget {
+        let target = gtk::DropTarget::new(gdk::FileList::static_type(), gdk::DragAction::COPY);
+        let transfer = self.transfer.clone();
+        target.connect_drop(
+            clone!(@weak window => @default-return false, move |_, value, _, _| {
+                let files = match value.get::<gdk::FileList>() {
+                    Ok(list) => list.files(),
+                    Err(_) => return false,
+                };
+                let transfer = transfer.clone();
+                MainContext::default().spawn_local(clone!(@weak window => async move {
+                    // the progress is shown in the title meanwhile
+                    let title = window.title();
+                    for file in files {
+                        let path = match file.path() {
+                            Some(path) => path,
+                            None => {
+                                log::warn!("Only local files can be sent: {}", file.uri());
+                                continue;
+                            }
+                        };
+                        let name = path.display().to_string();
+                        let res = transfer
+                            .send_file(&path, |TransferProgress { sent, total }| {
+                                let percent = if total > 0 { sent * 100 / total } else { 100 };
+                                window.set_title(Some(&format!("Sending {}: {}%", name, percent)));
+                            })
+                            .await;
+                        match res {
+                            Ok(()) => log::info!("Sent {}", name),
+                            Err(e) => log::warn!("Failed to send {}: {}", name, e),
+                        }
+                    }
+                    window.set_title(title.as_deref());
+                }));
+                true
+            }),
+        );
+        target
+    }
+}
@@ -1,68 +1,242 @@
 use glib::{clone, MainContext};
 use gtk::{glib, prelude::*};
-use qemu_display::UsbRedir;
+use qemu_display::{UsbAutoRedirect, UsbDeviceChange, UsbDeviceInfo, UsbFilter, UsbRedir, UsbRule};
 use rdw::gtk;
+use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::Arc};
+use usbredirhost::rusb;
+
+use crate::settings;
+
+// hidden by default, as they are seldom worth redirecting
+const CLASS_HID: u8 = 0x03;
+const CLASS_HUB: u8 = 0x09;
 
 #[derive(Clone, Debug)]
 pub struct Handler {
     usbredir: UsbRedir,
+    uuid: Option<String>,
+    // the devices redirected in the previous runs, attached again when plugged
+    _auto: Option<Arc<UsbAutoRedirect>>,
 }
 
 impl Handler {
-    pub fn new(usbredir: UsbRedir) -> Self {
-        Self { usbredir }
+    /// The redirected devices are remembered by VM `uuid`.
+    pub fn new(usbredir: UsbRedir, uuid: Option<String>) -> Self {
+        let rules: Vec<_> = uuid
+            .as_deref()
+            .map(settings::usb_devices)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(vendor_id, product_id)| UsbRule {
+                vendor_id: Some(vendor_id),
+                product_id: Some(product_id),
+                class: None,
+                allow: true,
+            })
+            .collect();
+        let auto = if rules.is_empty() {
+            None
+        } else {
+            match rusb::Context::new()
+                .map_err(Into::into)
+                .and_then(|ctxt| usbredir.auto_redirect(&ctxt, UsbFilter { rules }))
+            {
+                Ok(auto) => Some(Arc::new(auto)),
+                Err(e) => {
+                    log::warn!("Failed to redirect the saved USB devices: {}", e);
+                    None
+                }
+            }
+        };
+        Self {
+            usbredir,
+            uuid,
+            _auto: auto,
+        }
     }
 
-    pub fn widget(&self) -> rdw::UsbRedir {
-        let widget = rdw::UsbRedir::new();
+    pub fn widget(&self) -> gtk::Widget {
+        let search = gtk::SearchEntry::new();
+        let show_all = gtk::CheckButton::with_mnemonic("Show _hubs and input devices");
+        let list = gtk::ListBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .build();
+        let error = gtk::Label::builder().wrap(true).visible(false).build();
+        error.add_css_class("error");
+        let free = gtk::Label::builder().xalign(0.0).build();
+        free.add_css_class("dim-label");
 
-        let usbredir = self.usbredir.clone();
-        widget
-            .model()
-            .connect_items_changed(clone!(@weak widget => move |model, pos, _rm, add| {
-                let usbredir = usbredir.clone();
-                MainContext::default().spawn_local(clone!(@weak model => async move {
-                    for pos in pos..pos + add {
-                        let item = model.item(pos).unwrap();
-                        if let Some(dev) = item.downcast_ref::<rdw::UsbDevice>().unwrap().device() {
-                            item.set_property("active", usbredir.is_device_connected(&dev).await);
-                        }
-                    }
-                }));
-            }));
+        let rows: Rc<RefCell<HashMap<UsbDeviceInfo, DeviceRow>>> = Default::default();
+        list.set_filter_func(
+            clone!(@weak search, @weak show_all, @strong rows => @default-return true, move |row| {
+                let rows = rows.borrow();
+                let device = match rows.values().find(|d| &d.row == row) {
+                    Some(device) => device,
+                    None => return true,
+                };
+                (show_all.is_active() || !device.hub_or_hid)
+                    && device.text.contains(&search.text().to_lowercase())
+            }),
+        );
+        search.connect_search_changed(clone!(@weak list => move |_| list.invalidate_filter()));
+        show_all.connect_toggled(clone!(@weak list => move |_| list.invalidate_filter()));
 
-        let usbredir = self.usbredir.clone();
-        widget.connect_device_state_set(move |widget, item, state| {
-            let device = match item.device() {
-                Some(it) => it,
-                _ => return,
+        // the watch stops with the next change after the widget is gone
+        let this = self.clone();
+        let (weak_list, weak_error) = (list.downgrade(), error.downgrade());
+        MainContext::default().spawn_local(async move {
+            use futures::stream::StreamExt; // for `next`
+            let changes = rusb::Context::new()
+                .map_err(Into::into)
+                .and_then(|ctxt| this.usbredir.receive_device_changes(&ctxt));
+            let mut changes = match (changes, weak_error.upgrade()) {
+                (Ok(changes), _) => changes,
+                (Err(e), Some(error)) => {
+                    error.set_text(&format!("Failed to list the USB devices: {}", e));
+                    error.show();
+                    return;
+                }
+                (Err(_), None) => return,
             };
-
-            let usbredir = usbredir.clone();
-            MainContext::default().spawn_local(clone!(@weak item, @weak widget => async move {
-                match usbredir.set_device_state(&device, state).await {
-                    Ok(active) => item.set_property("active", active),
-                    Err(e) => {
-                        if state {
-                            item.set_property("active", false);
+            while let Some(change) = changes.next().await {
+                let (list, error) = match (weak_list.upgrade(), weak_error.upgrade()) {
+                    (Some(list), Some(error)) => (list, error),
+                    _ => break,
+                };
+                match change {
+                    UsbDeviceChange::Added(device, info) => {
+                        let row = this.device_row(device, &info, &error);
+                        list.append(&row.row);
+                        rows.borrow_mut().insert(info, row);
+                    }
+                    UsbDeviceChange::Removed(_, info) => {
+                        if let Some(row) = rows.borrow_mut().remove(&info) {
+                            list.remove(&row.row);
                         }
-                        widget.emit_by_name::<bool>("show-error", &[&e.to_string()]);
-                    },
+                    }
                 }
-            }));
+            }
         });
 
         let usbredir = self.usbredir.clone();
-        MainContext::default().spawn_local(clone!(@weak widget => async move {
+        MainContext::default().spawn_local(clone!(@weak free => async move {
             use futures::stream::StreamExt; // for `next`
-            widget
-                .set_property("free-channels", usbredir.n_free_channels().await);
+            let set_free = |n: i32| free.set_text(&format!("Free channels: {}", n));
+            set_free(usbredir.n_free_channels().await);
             let mut n = usbredir.receive_n_free_channels().await;
             while let Some(n) = n.next().await {
-                widget.set_property("free-channels", n);
+                set_free(n);
             }
         }));
 
-        widget
+        let scrolled = gtk::ScrolledWindow::builder()
+            .child(&list)
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .min_content_height(240)
+            .vexpand(true)
+            .build();
+        let vbox = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(6)
+            .margin_top(12)
+            .margin_bottom(12)
+            .margin_start(12)
+            .margin_end(12)
+            .build();
+        vbox.append(&search);
+        vbox.append(&show_all);
+        vbox.append(&scrolled);
+        vbox.append(&error);
+        vbox.append(&free);
+        vbox.upcast()
+    }
+
+    fn device_row(
+        &self,
+        device: rusb::Device<rusb::Context>,
+        info: &UsbDeviceInfo,
+        error: &gtk::Label,
+    ) -> DeviceRow {
+        let description = description(&device, info);
+        let label = gtk::Label::builder()
+            .label(&description)
+            .xalign(0.0)
+            .hexpand(true)
+            .ellipsize(gtk::pango::EllipsizeMode::End)
+            .build();
+        let switch = gtk::Switch::builder().valign(gtk::Align::Center).build();
+        let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 12);
+        hbox.append(&label);
+        hbox.append(&switch);
+        let row = gtk::ListBoxRow::builder().child(&hbox).build();
+        let hub_or_hid = [CLASS_HUB, CLASS_HID].iter().any(|class| {
+            UsbRule {
+                class: Some(*class),
+                ..Default::default()
+            }
+            .matches(&device)
+        });
+
+        let this = self.clone();
+        let ids = (info.vendor_id, info.product_id);
+        MainContext::default().spawn_local(clone!(@weak switch, @weak error => async move {
+            // before handling the changes, to not redirect it again
+            switch.set_active(this.usbredir.is_device_connected(&device).await);
+            switch.connect_state_set(clone!(@weak error => @default-return gtk::Inhibit(true), move |switch, state| {
+                // set back, after the redirection changed
+                if state == switch.state() {
+                    return gtk::Inhibit(false);
+                }
+                let this = this.clone();
+                let device = device.clone();
+                MainContext::default().spawn_local(clone!(@weak switch, @weak error => async move {
+                    match this.usbredir.set_device_state(&device, state).await {
+                        Ok(active) => {
+                            switch.set_state(active);
+                            if let Some(uuid) = &this.uuid {
+                                settings::set_usb_device(uuid, ids, active);
+                            }
+                            error.hide();
+                        }
+                        Err(e) => {
+                            switch.set_state(false);
+                            error.set_text(&e.to_string());
+                            error.show();
+                        }
+                    }
+                }));
+                gtk::Inhibit(true)
+            }));
+        }));
+
+        DeviceRow {
+            row,
+            text: description.to_lowercase(),
+            hub_or_hid,
+        }
+    }
+}
+
+struct DeviceRow {
+    row: gtk::ListBoxRow,
+    // lowercase, for the search
+    text: String,
+    hub_or_hid: bool,
+}
+
+// The product name and IDs, when the device can be opened.
+fn description(device: &rusb::Device<rusb::Context>, info: &UsbDeviceInfo) -> String {
+    let ids = format!("{:04x}:{:04x}", info.vendor_id, info.product_id);
+    let name = device.device_descriptor().ok().and_then(|desc| {
+        let handle = device.open().ok()?;
+        let product = handle.read_product_string_ascii(&desc).ok()?;
+        Some(match handle.read_manufacturer_string_ascii(&desc) {
+            Ok(manufacturer) => format!("{} {}", manufacturer, product),
+            Err(_) => product,
+        })
+    });
+    match name {
+        Some(name) => format!("{} ({})", name, ids),
+        None => format!("{} (bus {} address {})", ids, info.bus, info.address),
     }
 }
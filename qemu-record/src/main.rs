@@ -0,0 +1,160 @@
+use std::{error::Error, path::PathBuf, sync::mpsc, time::Instant};
+
+use clap::Parser;
+use qemu_display::{
+    AdaptiveSink, AttachOptions, Display, FramePathMode, FramePathPolicy, FrameSinkListener,
+    FramebufferEvent, Rect, SharedFramebuffer, SshTunnel, Transport,
+};
+
+mod record;
+mod y4m;
+
+use record::{Format, RecordConfig, Recorder};
+
+/// Record a console to a video file, until Ctrl-C or the console is disconnected.
+#[derive(Parser, Debug)]
+struct Cli {
+    #[clap(short, long)]
+    dbus_address: Option<String>,
+    /// Connect to the session bus of a remote host ([user@]host), through ssh
+    #[clap(long, conflicts_with = "dbus-address")]
+    ssh: Option<String>,
+    /// VM name
+    #[clap(long)]
+    vm_name: Option<String>,
+    /// Wait for the VM to be available
+    #[clap(short, long)]
+    wait: bool,
+    /// Console index
+    #[clap(short, long, default_value = "0")]
+    console: u32,
+    /// Video format, from the file extension by default: webm (VP9, with GStreamer) or y4m
+    #[clap(short, long)]
+    format: Option<Format>,
+    /// Frames per second
+    #[clap(long, default_value = "30")]
+    framerate: u32,
+    /// The VP9 target bitrate, in kbit/s
+    #[clap(long, default_value = "2000")]
+    bitrate: u32,
+    /// The GStreamer encoder, in gst-launch syntax (for example "vp8enc deadline=1"),
+    /// instead of vp9enc
+    #[clap(long)]
+    encoder: Option<String>,
+    /// Output file, the console resizes continue in numbered files (out-1.webm...)
+    output: PathBuf,
+}
+
+impl Cli {
+    fn format(&self) -> Format {
+        self.format
+            .unwrap_or_else(|| match self.output.extension().and_then(|e| e.to_str()) {
+                Some("y4m") => Format::Y4m,
+                _ => Format::Webm,
+            })
+    }
+}
+
+#[derive(Debug)]
+enum Event {
+    Update(Rect),
+    Disconnected,
+    Interrupted,
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::parse();
+    let mut recorder = Recorder::new(RecordConfig {
+        format: args.format(),
+        path: args.output.clone(),
+        framerate: args.framerate,
+        bitrate: args.bitrate,
+        encoder: args.encoder.clone(),
+    })?;
+    let tunnel = match &args.ssh {
+        Some(destination) => Some(SshTunnel::session_bus(destination).await?),
+        None => None,
+    };
+    let dbus_address = tunnel
+        .as_ref()
+        .map(SshTunnel::address)
+        .or_else(|| args.dbus_address.clone());
+    let transport = match dbus_address {
+        Some(addr) => Transport::BusAddress(addr),
+        None => Transport::SessionBus,
+    };
+    let options = AttachOptions::new(transport)
+        .with_vm_name(args.vm_name.clone())
+        .with_wait(args.wait)
+        .with_console(args.console);
+    let (_display, mut console) = Display::attach(options).await?;
+
+    let (tx, rx) = mpsc::channel();
+    let interrupt_tx = tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = interrupt_tx.send(Event::Interrupted);
+    })?;
+    let framebuffer = SharedFramebuffer::new(console.width().await?, console.height().await?)?;
+    let sink = framebuffer.sink(move |event| {
+        let _ = tx.send(match event {
+            FramebufferEvent::Resized { width, height } => {
+                Event::Update(Rect::new(0, 0, width, height))
+            }
+            FramebufferEvent::Damage(rect) => Event::Update(rect),
+            FramebufferEvent::Disconnected => Event::Disconnected,
+            FramebufferEvent::Cursor | FramebufferEvent::Mouse => return,
+        });
+    });
+    // the GL displays are read back, the frames are encoded from memory
+    let (sink, _control) = AdaptiveSink::new(
+        sink,
+        FramePathPolicy {
+            mode: FramePathMode::Copy,
+            ..Default::default()
+        },
+    );
+    console
+        .register_listener(FrameSinkListener::new(sink))
+        .await?;
+
+    {
+        let fb = &framebuffer.lock().framebuffer;
+        recorder.update(fb, fb.rect(), Instant::now())?;
+    }
+    let res = loop {
+        let event = match recorder.deadline() {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match rx.recv_timeout(timeout) {
+                    Ok(event) => Some(event),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => Some(Event::Disconnected),
+                }
+            }
+            None => Some(rx.recv().unwrap_or(Event::Disconnected)),
+        };
+        let now = Instant::now();
+        let fb = &framebuffer.lock().framebuffer;
+        let res = match event {
+            Some(Event::Update(damage)) => recorder.update(fb, damage, now),
+            None => recorder.flush(fb, now),
+            Some(Event::Disconnected) | Some(Event::Interrupted) => {
+                break recorder.finish(Some(fb), now)
+            }
+        };
+        if let Err(e) = res {
+            // the recording is still finalized
+            let _ = recorder.finish(None, now);
+            break Err(e);
+        }
+    };
+    console.unregister_listener();
+    Ok(res?)
+}
+
+fn main() {
+    if let Err(e) = async_io::block_on(run()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
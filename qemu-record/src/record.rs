@@ -0,0 +1,358 @@
+//! The recording of the console frames, at a constant frame rate.
+//!
+//! The frames are sampled at the time of the console updates: the changed region of a
+//! frame is converted when its period ends after an update, and the frame is repeated
+//! while the console doesn't change. A
+//! recording has a single size, the console resizes start a new segment, in a file
+//! numbered after the first one (`out.webm`, `out-1.webm`...).
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use qemu_display::{Framebuffer, Rect};
+
+use crate::y4m;
+
+const GST_LAUNCH: &str = "gst-launch-1.0";
+
+/// The format of the recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// VP9 in WebM, encoded with GStreamer.
+    Webm,
+    /// The raw YUV4MPEG2 frames.
+    Y4m,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "webm" => Ok(Self::Webm),
+            "y4m" => Ok(Self::Y4m),
+            _ => Err(format!("Invalid format '{}' (webm or y4m)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordConfig {
+    pub format: Format,
+    pub path: PathBuf,
+    pub framerate: u32,
+    /// The VP9 target bitrate, in kbit/s.
+    pub bitrate: u32,
+    /// The GStreamer encoder, in gst-launch syntax, instead of vp9enc.
+    pub encoder: Option<String>,
+}
+
+impl RecordConfig {
+    // the path of a segment, the following ones are numbered
+    fn segment_path(&self, index: u32) -> PathBuf {
+        if index == 0 {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, index, ext.to_string_lossy()),
+            None => format!("{}-{}", stem, index),
+        };
+        self.path.with_file_name(name)
+    }
+
+    // the gst-launch arguments, encoding the y4m stream of the standard input, with the
+    // encoder split like a shell would
+    fn pipeline(&self, path: &Path) -> io::Result<Vec<String>> {
+        let encoder = self.encoder.clone().unwrap_or_else(|| {
+            format!(
+                "vp9enc deadline=1 cpu-used=8 target-bitrate={}",
+                self.bitrate * 1000
+            )
+        });
+        let encoder = shell_words::split(&encoder).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid encoder pipeline: {}", e),
+            )
+        })?;
+        let args = [
+            "-q",
+            "fdsrc",
+            "fd=0",
+            "!",
+            "y4mdec",
+            "!",
+            "videoconvert",
+            "!",
+        ];
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        args.extend(encoder);
+        args.extend(
+            ["!", "webmmux", "!", "filesink"]
+                .iter()
+                .map(|arg| arg.to_string()),
+        );
+        args.push(format!("location={}", path.display()));
+        Ok(args)
+    }
+}
+
+// a recording of a given size
+struct Segment {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    framerate: u32,
+    out: BufWriter<Box<dyn Write>>,
+    encoder: Option<Child>,
+    start: Instant,
+    // the frames written, the next one is pending
+    frames: u64,
+    // the last converted frame
+    frame: Vec<u8>,
+}
+
+impl Segment {
+    fn new(
+        config: &RecordConfig,
+        path: PathBuf,
+        fb: &Framebuffer,
+        start: Instant,
+    ) -> io::Result<Self> {
+        let (out, encoder): (Box<dyn Write>, _) = match config.format {
+            Format::Y4m => (Box::new(File::create(&path)?), None),
+            Format::Webm => {
+                let mut child = Command::new(GST_LAUNCH)
+                    .args(config.pipeline(&path)?)
+                    .stdin(Stdio::piped())
+                    // Ctrl-C is for the recorder, the encoder must finish the file
+                    .process_group(0)
+                    .spawn()
+                    .map_err(|e| {
+                        io::Error::new(e.kind(), format!("Failed to run {}: {}", GST_LAUNCH, e))
+                    })?;
+                (Box::new(child.stdin.take().unwrap()), Some(child))
+            }
+        };
+        let mut out = BufWriter::new(out);
+        out.write_all(y4m::header(fb.width(), fb.height(), config.framerate).as_bytes())?;
+        Ok(Self {
+            path,
+            width: fb.width(),
+            height: fb.height(),
+            framerate: config.framerate,
+            out,
+            encoder,
+            start,
+            frames: 0,
+            frame: y4m::frame(fb.data(), fb.width(), fb.height(), fb.stride()),
+        })
+    }
+
+    // convert the changed region of the last frame
+    fn convert(&mut self, fb: &Framebuffer, rect: Rect) {
+        let (width, height, stride) = (fb.width(), fb.height(), fb.stride());
+        y4m::convert(fb.data(), width, height, stride, rect, &mut self.frame);
+    }
+
+    // the frame period of an instant
+    fn slot(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.start).as_secs_f64() * self.framerate as f64) as u64
+    }
+
+    // the end of the pending frame period
+    fn deadline(&self) -> Instant {
+        self.start + Duration::from_secs(self.frames + 1) / self.framerate
+    }
+
+    // write the last frame, until the given period
+    fn fill(&mut self, until: u64) -> io::Result<()> {
+        while self.frames < until {
+            self.out.write_all(&self.frame)?;
+            self.frames += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.out.flush()?;
+        drop(self.out);
+        if let Some(mut encoder) = self.encoder {
+            let status = encoder.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "{} exited with {}",
+                    GST_LAUNCH, status
+                )));
+            }
+        }
+        eprintln!("Recorded {} frames to {}", self.frames, self.path.display());
+        Ok(())
+    }
+}
+
+/// Records the frames of a console.
+pub struct Recorder {
+    config: RecordConfig,
+    segments: u32,
+    segment: Option<Segment>,
+    // the region changed during the pending frame period
+    damage: Rect,
+}
+
+impl Recorder {
+    pub fn new(config: RecordConfig) -> Result<Self, String> {
+        if config.framerate == 0 {
+            return Err("Invalid framerate 0".into());
+        }
+        Ok(Self {
+            config,
+            segments: 0,
+            segment: None,
+            damage: Rect::default(),
+        })
+    }
+
+    /// The time to call [`Recorder::flush`], when the pending frame period ends.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.segment
+            .as_ref()
+            .filter(|_| !self.damage.is_empty())
+            .map(Segment::deadline)
+    }
+
+    /// Write the frames of the periods ended before `now`.
+    pub fn flush(&mut self, fb: &Framebuffer, now: Instant) -> io::Result<()> {
+        let segment = match &mut self.segment {
+            Some(segment) => segment,
+            None => return Ok(()),
+        };
+        let slot = segment.slot(now);
+        if slot > segment.frames && !self.damage.is_empty() {
+            segment.convert(fb, std::mem::take(&mut self.damage));
+        }
+        segment.fill(slot)
+    }
+
+    /// The `damage` region of the console was updated at `now`, or the console resized.
+    pub fn update(&mut self, fb: &Framebuffer, damage: Rect, now: Instant) -> io::Result<()> {
+        let size = (fb.width(), fb.height());
+        match &self.segment {
+            Some(segment) if (segment.width, segment.height) == size => {
+                self.flush(fb, now)?;
+                self.damage = self.damage.union(&damage);
+                Ok(())
+            }
+            _ => {
+                self.finish(None, now)?;
+                if size.0 == 0 || size.1 == 0 {
+                    return Ok(());
+                }
+                let path = self.config.segment_path(self.segments);
+                eprintln!("Recording {}x{} to {}", size.0, size.1, path.display());
+                self.segment = Some(Segment::new(&self.config, path, fb, now)?);
+                self.segments += 1;
+                // the first frame is written at the end of its period
+                self.damage = fb.rect();
+                Ok(())
+            }
+        }
+    }
+
+    /// End the current segment at `now`, with the last frame of the framebuffer if it has
+    /// the segment size.
+    pub fn finish(&mut self, fb: Option<&Framebuffer>, now: Instant) -> io::Result<()> {
+        let mut segment = match self.segment.take() {
+            Some(segment) => segment,
+            None => return Ok(()),
+        };
+        if let Some(fb) =
+            fb.filter(|fb| (fb.width(), fb.height()) == (segment.width, segment.height))
+        {
+            if !self.damage.is_empty() {
+                segment.convert(fb, self.damage);
+            }
+        }
+        self.damage = Rect::default();
+        // the pending period is included
+        let slot = segment.slot(now);
+        segment.fill(slot + 1)?;
+        segment.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let config = RecordConfig {
+            format: Format::Webm,
+            path: "/tmp/vm.webm".into(),
+            framerate: 30,
+            bitrate: 2000,
+            encoder: None,
+        };
+        assert_eq!(config.segment_path(0), Path::new("/tmp/vm.webm"));
+        assert_eq!(config.segment_path(2), Path::new("/tmp/vm-2.webm"));
+        let args = config.pipeline(Path::new("/tmp/a b.webm")).unwrap();
+        assert_eq!(
+            args[..9].join(" "),
+            "-q fdsrc fd=0 ! y4mdec ! videoconvert ! vp9enc"
+        );
+        assert_eq!(args.last().unwrap(), "location=/tmp/a b.webm");
+
+        let config = RecordConfig {
+            encoder: Some(r#"vp8enc name="my  encoder""#.into()),
+            ..config
+        };
+        let args = config.pipeline(Path::new("/tmp/vm.webm")).unwrap();
+        assert_eq!(args[8..10], ["vp8enc", "name=my  encoder"]);
+    }
+
+    #[test]
+    fn segments() {
+        let dir = std::env::temp_dir();
+        let config = RecordConfig {
+            format: Format::Y4m,
+            path: dir.join("qemu-record-test.y4m"),
+            framerate: 30,
+            bitrate: 0,
+            encoder: None,
+        };
+        let mut recorder = Recorder::new(config).unwrap();
+        let ms = |ms| Duration::from_millis(ms);
+        let fb = Framebuffer::new(4, 2).unwrap();
+        let start = Instant::now();
+        recorder.update(&fb, fb.rect(), start).unwrap();
+        assert_eq!(recorder.deadline(), Some(start + ms(1000) / 30));
+        recorder.flush(&fb, start + ms(40)).unwrap();
+        assert_eq!(recorder.deadline(), None);
+        recorder
+            .update(&fb, Rect::new(0, 0, 1, 1), start + ms(110))
+            .unwrap();
+        // resized, in the 7th frame period
+        let resized = Framebuffer::new(2, 2).unwrap();
+        recorder
+            .update(&resized, resized.rect(), start + ms(210))
+            .unwrap();
+        recorder.finish(Some(&resized), start + ms(220)).unwrap();
+
+        let first = std::fs::read(dir.join("qemu-record-test.y4m")).unwrap();
+        let header = y4m::header(4, 2, 30);
+        assert_eq!(first.len(), header.len() + 7 * 18);
+        let second = std::fs::read(dir.join("qemu-record-test-1.y4m")).unwrap();
+        assert_eq!(second.len(), y4m::header(2, 2, 30).len() + 12);
+        let _ = std::fs::remove_file(dir.join("qemu-record-test.y4m"));
+        let _ = std::fs::remove_file(dir.join("qemu-record-test-1.y4m"));
+    }
+}
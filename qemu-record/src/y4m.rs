@@ -0,0 +1,110 @@
+//! The YUV4MPEG2 stream of the recorded frames, in 4:2:0 with the BT.601 limited range.
+
+use qemu_display::Rect;
+
+const FRAME: &[u8] = b"FRAME\n";
+
+/// The stream header, for frames of width x height at a constant frame rate.
+pub fn header(width: u32, height: u32, framerate: u32) -> String {
+    format!(
+        "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg\n",
+        width, height, framerate
+    )
+}
+
+fn luma(r: i32, g: i32, b: i32) -> u8 {
+    (16 + ((66 * r + 129 * g + 25 * b + 128) >> 8)) as u8
+}
+
+fn chroma(r: i32, g: i32, b: i32) -> (u8, u8) {
+    let u = 128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8);
+    let v = 128 + ((112 * r - 94 * g - 18 * b + 128) >> 8);
+    (u as u8, v as u8)
+}
+
+/// A frame of the stream, converted from the pixman x8r8g8b8 pixels.
+///
+/// The chroma is averaged over 2x2 pixels, the odd sizes are rounded up.
+pub fn frame(data: &[u8], width: u32, height: u32, stride: u32) -> Vec<u8> {
+    let (chroma_width, chroma_height) = (width.div_ceil(2) as usize, height.div_ceil(2) as usize);
+    let mut frame =
+        vec![0; FRAME.len() + width as usize * height as usize + chroma_width * chroma_height * 2];
+    frame[..FRAME.len()].copy_from_slice(FRAME);
+    convert(
+        data,
+        width,
+        height,
+        stride,
+        Rect::new(0, 0, width, height),
+        &mut frame,
+    );
+    frame
+}
+
+/// Convert a region of the pixels to a frame of the same size, see [`frame`].
+///
+/// The chroma of the 2x2 blocks overlapping the region is converted again.
+pub fn convert(data: &[u8], width: u32, height: u32, stride: u32, rect: Rect, frame: &mut [u8]) {
+    let rect = rect.intersect(&Rect::new(0, 0, width, height));
+    let (width, height, stride) = (width as usize, height as usize, stride as usize);
+    let chroma_width = width.div_ceil(2);
+    let (y_plane, chroma_planes) = frame[FRAME.len()..].split_at_mut(width * height);
+    let (u_plane, v_plane) = chroma_planes.split_at_mut(chroma_planes.len() / 2);
+    // the B, G and R bytes of a pixel
+    let rgb = |x: usize, y: usize| {
+        let p = &data[y * stride + x * 4..];
+        (p[2] as i32, p[1] as i32, p[0] as i32)
+    };
+    let (left, right) = (rect.x as usize, rect.right() as usize);
+    let (top, bottom) = (rect.y as usize, rect.bottom() as usize);
+    for y in top..bottom {
+        for x in left..right {
+            let (r, g, b) = rgb(x, y);
+            y_plane[y * width + x] = luma(r, g, b);
+        }
+    }
+    for cy in top / 2..bottom.div_ceil(2) {
+        for cx in left / 2..right.div_ceil(2) {
+            let (mut r, mut g, mut b, mut n) = (0, 0, 0, 0);
+            for y in cy * 2..(cy * 2 + 2).min(height) {
+                for x in cx * 2..(cx * 2 + 2).min(width) {
+                    let p = rgb(x, y);
+                    r += p.0;
+                    g += p.1;
+                    b += p.2;
+                    n += 1;
+                }
+            }
+            let (u, v) = chroma(r / n, g / n, b / n);
+            u_plane[cy * chroma_width + cx] = u;
+            v_plane[cy * chroma_width + cx] = v;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        assert_eq!(header(3, 2, 30), "YUV4MPEG2 W3 H2 F30:1 Ip A1:1 C420jpeg\n");
+
+        // white, black and red on the first line, with a padded stride
+        let mut data = vec![0; 16 * 2];
+        data[..4].copy_from_slice(&[0xff, 0xff, 0xff, 0]);
+        data[8..12].copy_from_slice(&[0, 0, 0xff, 0]);
+        let frame = frame(&data, 3, 2, 16);
+        assert_eq!(frame.len(), 6 + 6 + 2 * 2);
+        assert_eq!(frame[6..12], [235, 16, 82, 16, 16, 16]);
+        // the red column is alone in its chroma block
+        assert_eq!(frame[12..], [128, 109, 128, 184]);
+
+        // the region of a changed pixel, with its chroma block
+        let mut region = frame.clone();
+        data[16..20].copy_from_slice(&[0xff, 0, 0, 0]);
+        convert(&data, 3, 2, 16, Rect::new(0, 1, 1, 1), &mut region);
+        assert_eq!(region, super::frame(&data, 3, 2, 16));
+        assert_ne!(region, frame);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    borrow::Borrow,
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::Parser;
+use image::{png::PngEncoder, ColorType};
+use qemu_display::{Console, Display, RgbaImage, SshTunnel};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Png,
+    Ppm,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(Self::Png),
+            "ppm" => Ok(Self::Ppm),
+            _ => Err(format!("Invalid format '{}' (png or ppm)", s)),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+struct Cli {
+    #[clap(short, long)]
+    dbus_address: Option<String>,
+    /// Connect to the session bus of a remote host ([user@]host), through ssh
+    #[clap(long, conflicts_with = "dbus-address")]
+    ssh: Option<String>,
+    /// VM name
+    #[clap(long)]
+    vm_name: Option<String>,
+    /// Wait for the VM to be available
+    #[clap(short, long)]
+    wait: bool,
+    /// Console index
+    #[clap(short, long, conflicts_with = "head")]
+    console: Option<u32>,
+    /// Grab the graphical console of this head
+    #[clap(long)]
+    head: Option<u32>,
+    /// Image format, from the file extension by default: png or ppm
+    #[clap(short, long)]
+    format: Option<Format>,
+    /// Output file, or stdout if "-"
+    #[clap(default_value = "-")]
+    output: PathBuf,
+}
+
+impl Cli {
+    fn format(&self) -> Format {
+        self.format
+            .unwrap_or_else(|| match self.output.extension().and_then(|e| e.to_str()) {
+                Some("ppm") => Format::Ppm,
+                _ => Format::Png,
+            })
+    }
+}
+
+async fn console_id(display: &Display<'_>, head: u32) -> Result<u32, Box<dyn Error>> {
+    let consoles = display.consoles().await?;
+    consoles
+        .iter()
+        .find(|c| c.type_ == "Graphic" && c.head == head)
+        .map(|c| c.id)
+        .ok_or_else(|| format!("No graphical console for head {}", head).into())
+}
+
+fn write_ppm<W: Write>(mut w: W, image: &RgbaImage) -> io::Result<()> {
+    write!(w, "P6\n{} {}\n255\n", image.width, image.height)?;
+    for px in image.data.chunks_exact(4) {
+        w.write_all(&px[..3])?;
+    }
+    w.flush()
+}
+
+fn write_png<W: Write>(w: W, image: &RgbaImage) -> Result<(), Box<dyn Error>> {
+    PngEncoder::new(w).encode(&image.data, image.width, image.height, ColorType::Rgba8)?;
+    Ok(())
+}
+
+fn write_image(path: &Path, format: Format, image: &RgbaImage) -> Result<(), Box<dyn Error>> {
+    let out: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(path)?)
+    };
+    let out = BufWriter::new(out);
+    match format {
+        Format::Png => write_png(out, image),
+        Format::Ppm => Ok(write_ppm(out, image)?),
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::parse();
+    let tunnel = match &args.ssh {
+        Some(destination) => Some(SshTunnel::session_bus(destination).await?),
+        None => None,
+    };
+    let dbus_address = tunnel
+        .as_ref()
+        .map(SshTunnel::address)
+        .or_else(|| args.dbus_address.clone());
+    let conn = if let Some(addr) = &dbus_address {
+        zbus::ConnectionBuilder::address(addr.borrow())?
+            .build()
+            .await
+    } else {
+        zbus::Connection::session().await
+    }?;
+
+    let dest = Display::lookup(&conn, args.wait, args.vm_name.as_deref())
+        .await?
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| "org.qemu".into());
+    let display = Display::new(&conn, Some(dest)).await?;
+    let id = match (args.console, args.head) {
+        (Some(id), _) => id,
+        (None, Some(head)) => console_id(&display, head).await?,
+        (None, None) => 0,
+    };
+    let console = Console::new(display.connection(), id).await?;
+    let image = console.screenshot().await?;
+    write_image(&args.output, args.format(), &image)
+}
+
+fn main() {
+    if let Err(e) = async_io::block_on(run()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,205 @@
+//! The keys typed in the terminal, from its input bytes.
+//!
+//! A terminal only sends characters and escape sequences, without key releases: each key
+//! is pressed and released at once, with its modifiers. The characters are mapped to the
+//! keys of a US layout, the guest should use the same.
+
+/// The key ending the session, Ctrl-] like telnet and virsh console.
+pub const QUIT: u8 = 0x1d;
+
+const XK_BACKSPACE: u32 = 0xff08;
+const XK_TAB: u32 = 0xff09;
+const XK_RETURN: u32 = 0xff0d;
+const XK_ESCAPE: u32 = 0xff1b;
+const XK_HOME: u32 = 0xff50;
+const XK_LEFT: u32 = 0xff51;
+const XK_UP: u32 = 0xff52;
+const XK_RIGHT: u32 = 0xff53;
+const XK_DOWN: u32 = 0xff54;
+const XK_PAGE_UP: u32 = 0xff55;
+const XK_PAGE_DOWN: u32 = 0xff56;
+const XK_END: u32 = 0xff57;
+const XK_INSERT: u32 = 0xff63;
+const XK_F1: u32 = 0xffbe;
+const XK_DELETE: u32 = 0xffff;
+
+// the characters typed with Shift, on a US layout
+const SHIFTED: &str = "~!@#$%^&*()_+{}|:\"<>?";
+
+/// A key typed in the terminal, as an X11 keysym.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub keysym: u32,
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Key {
+    fn new(keysym: u32) -> Self {
+        Self {
+            keysym,
+            shift: false,
+            ctrl: false,
+            alt: false,
+        }
+    }
+
+    fn char(c: char) -> Self {
+        Self {
+            shift: c.is_ascii_uppercase() || SHIFTED.contains(c),
+            ..Self::new(c as u32)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Key(Key),
+    Quit,
+}
+
+/// The keys of the terminal input. The escape sequences are expected in a single read.
+pub fn parse(data: &[u8]) -> Vec<Input> {
+    let mut res = vec![];
+    let text = String::from_utf8_lossy(data);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\x1b' => match chars.peek() {
+                None => Key::new(XK_ESCAPE),
+                Some('[') | Some('O') => {
+                    let mut seq = String::new();
+                    seq.push(chars.next().unwrap());
+                    for c in chars.by_ref() {
+                        seq.push(c);
+                        if c.is_ascii_alphabetic() || c == '~' {
+                            break;
+                        }
+                    }
+                    match escape_sequence(&seq) {
+                        Some(key) => key,
+                        None => continue,
+                    }
+                }
+                // Meta sends an escape before the key
+                Some(_) => {
+                    let c = chars.next().unwrap();
+                    match parse(c.to_string().as_bytes()).pop() {
+                        Some(Input::Key(key)) => Key { alt: true, ..key },
+                        _ => continue,
+                    }
+                }
+            },
+            c if c as u32 == QUIT as u32 => {
+                res.push(Input::Quit);
+                continue;
+            }
+            '\r' | '\n' => Key::new(XK_RETURN),
+            '\t' => Key::new(XK_TAB),
+            '\x7f' | '\x08' => Key::new(XK_BACKSPACE),
+            '\x00' => Key {
+                ctrl: true,
+                ..Key::new(' ' as u32)
+            },
+            // Ctrl-A to Ctrl-Z, then Ctrl-\ to Ctrl-_
+            c if (c as u32) < 0x1b => Key {
+                ctrl: true,
+                ..Key::new(c as u32 + 0x60)
+            },
+            c if (c as u32) < 0x20 => Key {
+                ctrl: true,
+                ..Key::new(c as u32 + 0x40)
+            },
+            c if (c as u32) < 0x100 => Key::char(c),
+            // no keysym with a US layout
+            _ => continue,
+        };
+        res.push(Input::Key(key));
+    }
+    res
+}
+
+// the key of a CSI or SS3 sequence, without the escape
+fn escape_sequence(seq: &str) -> Option<Key> {
+    let keysym = match seq {
+        "[A" | "OA" => XK_UP,
+        "[B" | "OB" => XK_DOWN,
+        "[C" | "OC" => XK_RIGHT,
+        "[D" | "OD" => XK_LEFT,
+        "[H" | "OH" | "[1~" | "[7~" => XK_HOME,
+        "[F" | "OF" | "[4~" | "[8~" => XK_END,
+        "[2~" => XK_INSERT,
+        "[3~" => XK_DELETE,
+        "[5~" => XK_PAGE_UP,
+        "[6~" => XK_PAGE_DOWN,
+        "OP" => XK_F1,
+        "OQ" => XK_F1 + 1,
+        "OR" => XK_F1 + 2,
+        "OS" => XK_F1 + 3,
+        "[15~" => XK_F1 + 4,
+        "[17~" => XK_F1 + 5,
+        "[18~" => XK_F1 + 6,
+        "[19~" => XK_F1 + 7,
+        "[20~" => XK_F1 + 8,
+        "[21~" => XK_F1 + 9,
+        "[23~" => XK_F1 + 10,
+        "[24~" => XK_F1 + 11,
+        _ => return None,
+    };
+    Some(Key::new(keysym))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(keysym: u32) -> Input {
+        Input::Key(Key::new(keysym))
+    }
+
+    #[test]
+    fn characters() {
+        assert_eq!(
+            parse(b"aA!\r"),
+            vec![
+                key('a' as u32),
+                Input::Key(Key {
+                    shift: true,
+                    ..Key::new('A' as u32)
+                }),
+                Input::Key(Key {
+                    shift: true,
+                    ..Key::new('!' as u32)
+                }),
+                key(XK_RETURN),
+            ]
+        );
+        assert_eq!(
+            parse(b"\x03\x1d"),
+            vec![
+                Input::Key(Key {
+                    ctrl: true,
+                    ..Key::new('c' as u32)
+                }),
+                Input::Quit
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            parse(b"\x1b[A\x1b[15~\x1b"),
+            vec![key(XK_UP), key(XK_F1 + 4), key(XK_ESCAPE)]
+        );
+        assert_eq!(
+            parse(b"\x1bx"),
+            vec![Input::Key(Key {
+                alt: true,
+                ..Key::new('x' as u32)
+            })]
+        );
+        assert_eq!(parse(b"\x1b[99~a"), vec![key('a' as u32)]);
+    }
+}
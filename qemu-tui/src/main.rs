@@ -0,0 +1,213 @@
+use std::{
+    borrow::Borrow,
+    error::Error,
+    io::{self, Read, Write},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use keycodemap::Keymap;
+use qemu_display::{
+    AdaptiveSink, Console, Display, FramePathMode, FramePathPolicy, FrameSinkListener,
+    FramebufferEvent, SharedFramebuffer, SshTunnel, VMProxy,
+};
+
+mod input;
+mod render;
+mod term;
+
+use input::{Input, Key};
+use render::HalfBlocks;
+use term::RawTerminal;
+
+// the shortest delay between two redraws, the terminal is slow to draw
+const FRAME_INTERVAL: Duration = Duration::from_millis(66);
+// the delay between two checks of the terminal size
+const RESIZE_INTERVAL: Duration = Duration::from_millis(250);
+
+// the qnum of the left modifiers
+const QNUM_CTRL: u32 = 0x1d;
+const QNUM_SHIFT: u32 = 0x2a;
+const QNUM_ALT: u32 = 0x38;
+
+/// Show a console in the terminal, and type in it. Ctrl-] quits.
+#[derive(Parser, Debug)]
+struct Cli {
+    #[clap(short, long)]
+    dbus_address: Option<String>,
+    /// Connect to the session bus of a remote host ([user@]host), through ssh
+    #[clap(long, conflicts_with = "dbus-address")]
+    ssh: Option<String>,
+    /// VM name
+    #[clap(long)]
+    vm_name: Option<String>,
+    /// Wait for the VM to be available
+    #[clap(short, long)]
+    wait: bool,
+    /// Console index
+    #[clap(short, long, default_value = "0")]
+    console: u32,
+}
+
+#[derive(Debug)]
+enum Event {
+    Frame,
+    Disconnected,
+    Input(Input),
+    InputClosed,
+}
+
+// press the key with its modifiers, then release them all
+async fn type_key(console: &Console, key: Key) -> Result<(), Box<dyn Error>> {
+    let qnum = match Keymap::X11.qnum(key.keysym) {
+        Some(qnum) => qnum,
+        None => return Ok(()),
+    };
+    let modifiers: Vec<_> = [
+        (key.ctrl, QNUM_CTRL),
+        (key.shift, QNUM_SHIFT),
+        (key.alt, QNUM_ALT),
+    ]
+    .iter()
+    .filter(|(pressed, _)| *pressed)
+    .map(|(_, qnum)| *qnum)
+    .collect();
+    for m in &modifiers {
+        console.keyboard.press(*m).await?;
+    }
+    console.keyboard.press(qnum).await?;
+    console.keyboard.release(qnum).await?;
+    for m in modifiers.iter().rev() {
+        console.keyboard.release(*m).await?;
+    }
+    Ok(())
+}
+
+fn read_input(tx: mpsc::Sender<Event>) {
+    let mut stdin = io::stdin();
+    let mut buf = [0; 256];
+    loop {
+        let n = match stdin.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        for input in input::parse(&buf[..n]) {
+            if tx.send(Event::Input(input)).is_err() {
+                return;
+            }
+        }
+    }
+    let _ = tx.send(Event::InputClosed);
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::parse();
+    let tunnel = match &args.ssh {
+        Some(destination) => Some(SshTunnel::session_bus(destination).await?),
+        None => None,
+    };
+    let dbus_address = tunnel
+        .as_ref()
+        .map(SshTunnel::address)
+        .or_else(|| args.dbus_address.clone());
+    let conn = if let Some(addr) = &dbus_address {
+        zbus::ConnectionBuilder::address(addr.borrow())?
+            .build()
+            .await
+    } else {
+        zbus::Connection::session().await
+    }?;
+
+    let dest = Display::lookup(&conn, args.wait, args.vm_name.as_deref())
+        .await?
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| "org.qemu".into());
+    let vm_name = VMProxy::builder(&conn)
+        .destination(dest.as_str())?
+        .build()
+        .await?
+        .name()
+        .await?;
+    let display = Display::new(&conn, Some(dest)).await?;
+    let mut console = Console::new(display.connection(), args.console).await?;
+
+    let (tx, rx) = mpsc::channel();
+    let framebuffer = SharedFramebuffer::new(console.width().await?, console.height().await?)?;
+    let frame_tx = tx.clone();
+    let sink = framebuffer.sink(move |event| {
+        let _ = frame_tx.send(match event {
+            FramebufferEvent::Disconnected => Event::Disconnected,
+            _ => Event::Frame,
+        });
+    });
+    // the GL displays are read back, there is no GPU to import them
+    let (sink, _control) = AdaptiveSink::new(
+        sink,
+        FramePathPolicy {
+            mode: FramePathMode::Copy,
+            ..Default::default()
+        },
+    );
+    console
+        .register_listener(FrameSinkListener::new(sink))
+        .await?;
+
+    let terminal = RawTerminal::new()?;
+    terminal.set_title(&vm_name)?;
+    thread::spawn(move || read_input(tx));
+
+    let mut renderer = HalfBlocks::default();
+    let mut size = term::size()?;
+    let mut dirty = true;
+    let mut last_frame = Instant::now() - FRAME_INTERVAL;
+    let res = loop {
+        let timeout = if dirty {
+            FRAME_INTERVAL.saturating_sub(last_frame.elapsed())
+        } else {
+            RESIZE_INTERVAL
+        };
+        match rx.recv_timeout(timeout) {
+            Ok(Event::Frame) => dirty = true,
+            Ok(Event::Disconnected) => break Err("The console was disconnected".into()),
+            Ok(Event::Input(Input::Quit)) | Ok(Event::InputClosed) => break Ok(()),
+            Ok(Event::Input(Input::Key(key))) => {
+                if let Err(e) = type_key(&console, key).await {
+                    break Err(e);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break Ok(()),
+        }
+
+        let new_size = term::size()?;
+        if new_size != size {
+            size = new_size;
+            dirty = true;
+        }
+        if !dirty || last_frame.elapsed() < FRAME_INTERVAL {
+            continue;
+        }
+        let out = {
+            let state = framebuffer.lock();
+            let fb = &state.framebuffer;
+            renderer.render(fb.data(), fb.width(), fb.height(), size.0, size.1)
+        };
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()?;
+        dirty = false;
+        last_frame = Instant::now();
+    };
+    console.unregister_listener();
+    drop(terminal);
+    res
+}
+
+fn main() {
+    if let Err(e) = async_io::block_on(run()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
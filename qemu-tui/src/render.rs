@@ -0,0 +1,133 @@
+use std::fmt::Write;
+
+// the colors of the upper and lower halves of a cell
+type Cell = ([u8; 3], [u8; 3]);
+
+/// Renders the frames with the Unicode upper half block, two pixels per cell.
+///
+/// The frame is scaled down to the terminal, keeping its aspect ratio (the cells are about
+/// twice as high as wide, their halves are square). Only the cells that changed since the
+/// last frame are drawn, for the slow terminals and ssh connections.
+#[derive(Debug, Default)]
+pub struct HalfBlocks {
+    size: (u16, u16),
+    cells: Vec<Option<Cell>>,
+}
+
+impl HalfBlocks {
+    /// The escape sequences drawing a x8r8g8b8 frame, on a terminal of `cols` x `rows`.
+    pub fn render(
+        &mut self,
+        frame: &[u8],
+        width: u32,
+        height: u32,
+        cols: u16,
+        rows: u16,
+    ) -> String {
+        let mut out = String::new();
+        if self.size != (cols, rows) || self.cells.is_empty() {
+            self.size = (cols, rows);
+            self.cells = vec![None; cols as usize * rows as usize];
+            out.push_str("\x1b[0m\x1b[2J");
+        }
+        if width == 0 || height == 0 || cols == 0 || rows == 0 {
+            return out;
+        }
+        let scale = (cols as f64 / width as f64).min(rows as f64 * 2.0 / height as f64);
+        let (w, h) = (
+            ((width as f64 * scale) as u32).max(1),
+            ((height as f64 * scale) as u32).max(1),
+        );
+        let (x0, y0) = (
+            (cols as u32 - w.min(cols as u32)) / 2,
+            (rows as u32 * 2 - h) / 4,
+        );
+
+        let pixel = |x: u32, y: u32| -> [u8; 3] {
+            if x < x0 || y < y0 * 2 || x >= x0 + w || y >= y0 * 2 + h {
+                return [0; 3];
+            }
+            average(frame, width, height, (x - x0, y - y0 * 2), (w, h))
+        };
+        let mut cursor = None;
+        let mut colors = None;
+        for row in 0..rows as u32 {
+            for col in 0..cols as u32 {
+                let cell = (pixel(col, row * 2), pixel(col, row * 2 + 1));
+                let drawn = &mut self.cells[(row * cols as u32 + col) as usize];
+                if *drawn == Some(cell) {
+                    continue;
+                }
+                *drawn = Some(cell);
+                if cursor != Some((col, row)) {
+                    let _ = write!(out, "\x1b[{};{}H", row + 1, col + 1);
+                }
+                if colors != Some(cell) {
+                    let ((r, g, b), (br, bg, bb)) = (
+                        (cell.0[0], cell.0[1], cell.0[2]),
+                        (cell.1[0], cell.1[1], cell.1[2]),
+                    );
+                    let _ = write!(
+                        out,
+                        "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                        r, g, b, br, bg, bb
+                    );
+                    colors = Some(cell);
+                }
+                out.push('▀');
+                cursor = Some((col + 1, row));
+            }
+        }
+        out
+    }
+}
+
+// the average color of the frame area of a scaled pixel, from a few samples
+fn average(
+    frame: &[u8],
+    width: u32,
+    height: u32,
+    (x, y): (u32, u32),
+    (w, h): (u32, u32),
+) -> [u8; 3] {
+    const SAMPLES: u32 = 2;
+    let mut sum = [0u32; 3];
+    for sy in 0..SAMPLES {
+        for sx in 0..SAMPLES {
+            let fx = ((x * SAMPLES + sx) as u64 * width as u64 / (w * SAMPLES) as u64) as usize;
+            let fy = ((y * SAMPLES + sy) as u64 * height as u64 / (h * SAMPLES) as u64) as usize;
+            let i = (fy * width as usize + fx) * 4;
+            // the little-endian x8r8g8b8 pixels are BGRX
+            if let Some(px) = frame.get(i..i + 3) {
+                sum[0] += px[2] as u32;
+                sum[1] += px[1] as u32;
+                sum[2] += px[0] as u32;
+            }
+        }
+    }
+    let n = SAMPLES * SAMPLES;
+    [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redraw_changes() {
+        // a red top half, a blue bottom half
+        let mut frame = vec![0; 2 * 2 * 4];
+        frame[..8].copy_from_slice(&[0, 0, 255, 0, 0, 0, 255, 0]);
+        frame[8..].copy_from_slice(&[255, 0, 0, 0, 255, 0, 0, 0]);
+        let mut r = HalfBlocks::default();
+        let out = r.render(&frame, 2, 2, 2, 1);
+        assert!(out.starts_with("\x1b[0m\x1b[2J\x1b[1;1H\x1b[38;2;255;0;0;48;2;0;0;255m"));
+        assert_eq!(out.matches('▀').count(), 2);
+        assert_eq!(r.render(&frame, 2, 2, 2, 1), "");
+
+        frame[4..8].copy_from_slice(&[0, 255, 0, 0]);
+        let out = r.render(&frame, 2, 2, 2, 1);
+        assert!(out.starts_with("\x1b[1;2H"));
+        assert_eq!(out.matches('▀').count(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+/// The terminal in raw mode, on the alternate screen, restored when dropped.
+pub struct RawTerminal {
+    termios: libc::termios,
+}
+
+impl RawTerminal {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: termios is plain data, filled by tcgetattr
+        let termios = unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = termios;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            termios
+        };
+        // alternate screen, hidden cursor
+        let mut out = io::stdout();
+        out.write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        out.flush()?;
+        Ok(Self { termios })
+    }
+
+    pub fn set_title(&self, title: &str) -> io::Result<()> {
+        let mut out = io::stdout();
+        write!(out, "\x1b]0;{}\x07", title)?;
+        out.flush()
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        let _ = out.write_all(b"\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = out.flush();
+        // SAFETY: restores the attributes read in new()
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.termios);
+        }
+    }
+}
+
+/// The terminal size, in columns and rows.
+pub fn size() -> io::Result<(u16, u16)> {
+    // SAFETY: winsize is plain data, filled by the ioctl
+    unsafe {
+        let mut ws: libc::winsize = mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((ws.ws_col, ws.ws_row))
+    }
+}
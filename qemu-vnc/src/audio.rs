@@ -0,0 +1,174 @@
+//! The guest audio playback, streamed to the browser clients on a side WebSocket.
+//!
+//! The RFB audio messages of QEMU can't be used: the vnc crate parses the client messages,
+//! and doesn't know about them. The browser console opens a WebSocket at [`PATH`] instead,
+//! and sends `enable` or `disable` text messages to start or pause the playback. The format
+//! of the samples is sent in a JSON text message, before the binary messages of the PCM
+//! samples, and again when it changes.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+use qemu_display::{Audio, AudioOutHandler, PCMInfo, Volume};
+
+use crate::ws::{self, OP_BINARY, OP_CLOSE, OP_PING, OP_PONG, OP_TEXT};
+
+/// The path of the audio WebSocket.
+pub const PATH: &str = "/audio";
+// the buffers queued for a client, the next ones are dropped while it is slow
+const MAX_QUEUED: usize = 32;
+
+type Subscribers = Arc<Mutex<Vec<mpsc::SyncSender<(PCMInfo, Arc<Vec<u8>>)>>>>;
+
+/// The guest audio output, shared by the audio clients.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct VncAudio {
+    // kept registered with QEMU
+    _audio: Audio,
+    #[derivative(Debug = "ignore")]
+    subscribers: Subscribers,
+}
+
+impl VncAudio {
+    /// Register the output listener of `audio`, replacing the one of another frontend.
+    pub async fn new(mut audio: Audio) -> qemu_display::Result<Self> {
+        let subscribers = Subscribers::default();
+        let handler = Handler {
+            formats: HashMap::new(),
+            subscribers: subscribers.clone(),
+        };
+        audio.register_out_listener(handler).await?;
+        Ok(Self {
+            _audio: audio,
+            subscribers,
+        })
+    }
+
+    /// Stream the audio to a WebSocket client, until it leaves.
+    pub fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let (tx, rx) = mpsc::sync_channel(MAX_QUEUED);
+        self.subscribers.lock().unwrap().push(tx);
+        let enabled = Arc::new(AtomicBool::new(false));
+        let closed = Arc::new(AtomicBool::new(false));
+        let ws = Arc::new(Mutex::new(stream.try_clone()?));
+        {
+            let (ws, enabled, closed) = (ws.clone(), enabled.clone(), closed.clone());
+            thread::spawn(move || {
+                let mut format = None;
+                for (info, data) in rx {
+                    if closed.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if !enabled.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let res = match &format {
+                        Some(f) if *f == info => Ok(()),
+                        _ => ws::write_frame(&ws, OP_TEXT, format_json(&info).as_bytes()),
+                    }
+                    .and_then(|_| ws::write_frame(&ws, OP_BINARY, &data));
+                    if res.is_err() {
+                        return;
+                    }
+                    format = Some(info);
+                }
+            });
+        }
+
+        let mut stream = stream;
+        let res = loop {
+            let (opcode, payload) = match ws::read_frame(&mut stream) {
+                Ok(frame) => frame,
+                Err(e) => break Err(e),
+            };
+            match (opcode, payload.as_slice()) {
+                (OP_TEXT, b"enable") => enabled.store(true, Ordering::Relaxed),
+                (OP_TEXT, b"disable") => enabled.store(false, Ordering::Relaxed),
+                (OP_PING, _) => ws::write_frame(&ws, OP_PONG, &payload)?,
+                (OP_CLOSE, _) => {
+                    let _ = ws::write_frame(&ws, OP_CLOSE, &payload[..payload.len().min(2)]);
+                    break Ok(());
+                }
+                _ => {}
+            }
+        };
+        closed.store(true, Ordering::Relaxed);
+        let _ = stream.shutdown(Shutdown::Both);
+        res
+    }
+}
+
+// the sample format, for the Web Audio API
+fn format_json(info: &PCMInfo) -> String {
+    format!(
+        r#"{{"bits":{},"signed":{},"float":{},"be":{},"channels":{},"rate":{}}}"#,
+        info.bits, info.is_signed, info.is_float, info.be, info.nchannels, info.freq
+    )
+}
+
+#[derive(Debug)]
+struct Handler {
+    formats: HashMap<u64, PCMInfo>,
+    subscribers: Subscribers,
+}
+
+#[async_trait::async_trait]
+impl AudioOutHandler for Handler {
+    async fn init(&mut self, id: u64, info: PCMInfo) {
+        self.formats.insert(id, info);
+    }
+
+    async fn fini(&mut self, id: u64) {
+        self.formats.remove(&id);
+    }
+
+    async fn set_enabled(&mut self, _id: u64, _enabled: bool) {}
+
+    async fn set_volume(&mut self, _id: u64, _volume: Volume) {}
+
+    async fn write(&mut self, id: u64, data: Vec<u8>) {
+        let info = match self.formats.get(&id) {
+            Some(info) => info,
+            None => return,
+        };
+        let data = Arc::new(data);
+        self.subscribers.lock().unwrap().retain(|s| {
+            match s.try_send((info.clone(), data.clone())) {
+                Ok(_) | Err(mpsc::TrySendError::Full(_)) => true,
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format() {
+        let info = PCMInfo {
+            bits: 16,
+            is_signed: true,
+            is_float: false,
+            freq: 44100,
+            nchannels: 2,
+            bytes_per_frame: 4,
+            bytes_per_second: 176400,
+            be: false,
+        };
+        assert_eq!(
+            format_json(&info),
+            r#"{"bits":16,"signed":true,"float":false,"be":false,"channels":2,"rate":44100}"#
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fmt::Debug,
+    io::{self, prelude::*},
+};
+
+use des::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Des,
+};
+
+pub const SECURITY_VNC_AUTH: u8 = 2;
+pub const VENCRYPT_X509_VNC: u32 = 261;
+
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// An RFB authentication scheme.
+pub trait Authenticator: Debug + Send + Sync {
+    /// The RFB security type advertised to the client.
+    fn security_type(&self) -> u8;
+
+    /// The matching VeNCrypt x509 sub-type, if the scheme can run over TLS.
+    fn vencrypt_subtype(&self) -> Option<u32> {
+        None
+    }
+
+    /// Run the authentication exchange, and return whether the client is authenticated.
+    ///
+    /// The security result is sent by the caller.
+    fn authenticate(&self, stream: &mut dyn Stream) -> io::Result<bool>;
+}
+
+/// The standard VNC authentication (DES challenge-response).
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct VncAuth {
+    #[derivative(Debug = "ignore")]
+    key: [u8; 8],
+}
+
+impl VncAuth {
+    /// Only the first 8 bytes of the password are significant.
+    pub fn new(password: &str) -> Self {
+        let mut key = [0; 8];
+        for (k, b) in key.iter_mut().zip(password.bytes()) {
+            // VNC uses the key bits in reverse order
+            *k = b.reverse_bits();
+        }
+        Self { key }
+    }
+
+    fn response(&self, challenge: &[u8; 16]) -> [u8; 16] {
+        let des = Des::new(GenericArray::from_slice(&self.key));
+        let mut response = *challenge;
+        for block in response.chunks_exact_mut(8) {
+            des.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+        response
+    }
+}
+
+impl Authenticator for VncAuth {
+    fn security_type(&self) -> u8 {
+        SECURITY_VNC_AUTH
+    }
+
+    fn vencrypt_subtype(&self) -> Option<u32> {
+        Some(VENCRYPT_X509_VNC)
+    }
+
+    fn authenticate(&self, stream: &mut dyn Stream) -> io::Result<bool> {
+        let mut challenge = [0; 16];
+        getrandom::getrandom(&mut challenge)?;
+        stream.write_all(&challenge)?;
+        stream.flush()?;
+
+        let mut response = [0; 16];
+        stream.read_exact(&mut response)?;
+        let expected = self.response(&challenge);
+        let diff = expected
+            .iter()
+            .zip(response.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        Ok(diff == 0)
+    }
+}
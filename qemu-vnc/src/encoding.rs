@@ -0,0 +1,391 @@
+use flate2::{Compress, Compression, FlushCompress};
+use vnc::Rect;
+
+use crate::{pixel::ClientFormat, BgraImage, BgraView};
+
+const ENCODING_TIGHT: i32 = 7;
+const ENCODING_ZRLE: i32 = 16;
+/// The QEMU pointer type change pseudo-encoding: the x of the rectangle is 1 for absolute
+/// pointer events, 0 for relative.
+pub const ENCODING_POINTER_TYPE_CHANGE: i32 = -257;
+/// The QEMU LED state pseudo-encoding: a byte follows the rectangle, with the Scroll Lock
+/// (bit 0), Num Lock (bit 1) and Caps Lock (bit 2) states.
+pub const ENCODING_LED_STATE: i32 = -261;
+/// The pointer position pseudo-encoding: the position of the rectangle is the cursor
+/// position, set by the server.
+pub const ENCODING_POINTER_POS: i32 = -232;
+// the Cursor (RichCursor) pseudo-encoding
+const ENCODING_CURSOR: i32 = -239;
+
+const ZRLE_TILE: u16 = 64;
+const TIGHT_MAX_WIDTH: u16 = 2048;
+const TIGHT_MAX_PIXELS: u32 = 65536;
+// the largest rectangle side sent at once, larger rectangles are split
+pub const MAX_RECT_SIDE: u16 = 4096;
+// below this size, Tight data is sent uncompressed
+const TIGHT_MIN_COMPRESS: usize = 12;
+
+/// The encoding used for framebuffer updates, the first supported from the client list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RectEncoding {
+    Raw,
+    Zrle,
+    Tight,
+}
+
+impl RectEncoding {
+    pub fn from_client(encodings: &[vnc::Encoding]) -> Self {
+        encodings
+            .iter()
+            .find_map(|e| match e {
+                vnc::Encoding::Raw => Some(Self::Raw),
+                vnc::Encoding::Zrle => Some(Self::Zrle),
+                // the vnc crate doesn't know about Tight
+                vnc::Encoding::Unknown(ENCODING_TIGHT) => Some(Self::Tight),
+                _ => None,
+            })
+            .unwrap_or(Self::Raw)
+    }
+}
+
+/// A FramebufferUpdate message with a single pseudo-encoding rectangle.
+pub fn pseudo_rect(encoding: i32, rect: &Rect) -> Vec<u8> {
+    let mut msg = vec![0, 0];
+    msg.extend_from_slice(&1u16.to_be_bytes());
+    msg.extend_from_slice(&rect.left.to_be_bytes());
+    msg.extend_from_slice(&rect.top.to_be_bytes());
+    msg.extend_from_slice(&rect.width.to_be_bytes());
+    msg.extend_from_slice(&rect.height.to_be_bytes());
+    msg.extend_from_slice(&encoding.to_be_bytes());
+    msg
+}
+
+/// A ServerCutText message. The text is sent in Latin-1, the other characters are replaced,
+/// with the RFB line feeds.
+///
+/// The UTF-8 text of the extended clipboard pseudo-encoding (0xC0A1E5CE) isn't advertised:
+/// the clients answer the server capabilities with a ClientCutText of negative length,
+/// which the vnc crate reads as an unsigned length of about 4 GiB.
+pub fn server_cut_text(text: &str) -> Vec<u8> {
+    let text: Vec<u8> = text
+        .replace("\r\n", "\n")
+        .chars()
+        .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+        .collect();
+    let mut msg = vec![3, 0, 0, 0];
+    msg.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    msg.extend_from_slice(&text);
+    msg
+}
+
+/// A FramebufferUpdate message with the Cursor pseudo-encoding, for a premultiplied cursor
+/// `image` with its hot-spot. The cursor is hidden without image.
+///
+/// The shape is sent in the client pixel `format`, with a bitmask of the opaque pixels.
+pub fn cursor_update(
+    image: Option<&BgraImage>,
+    hot_x: u16,
+    hot_y: u16,
+    format: &ClientFormat,
+) -> Vec<u8> {
+    let (width, height) = image.map_or((0, 0), |i| i.dimensions());
+    let rect = Rect {
+        left: hot_x,
+        top: hot_y,
+        width: width as u16,
+        height: height as u16,
+    };
+    let mut msg = pseudo_rect(ENCODING_CURSOR, &rect);
+    let image = match image {
+        Some(image) => image,
+        None => return msg,
+    };
+    let mask_row = (width as usize).div_ceil(8);
+    let mut mask = vec![0u8; mask_row * height as usize];
+    for (x, y, px) in image.enumerate_pixels() {
+        let alpha = px[3] as u32;
+        let color = |c: u8| {
+            (c as u32 * 255 + alpha / 2)
+                .checked_div(alpha)
+                .map_or(0, |c| c.min(255) as u8)
+        };
+        format.push_pixel([color(px[0]), color(px[1]), color(px[2]), 0], &mut msg);
+        // no partial transparency, the pixels are opaque from half alpha
+        if alpha >= 128 {
+            mask[y as usize * mask_row + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    msg.extend_from_slice(&mask);
+    msg
+}
+
+/// Compressed rectangle encoders, with the per-connection zlib streams.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Encoder {
+    #[derivative(Debug = "ignore")]
+    zrle: Compress,
+    #[derivative(Debug = "ignore")]
+    tight: Compress,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self {
+            zrle: Compress::new(Compression::default(), true),
+            tight: Compress::new(Compression::default(), true),
+        }
+    }
+}
+
+impl Encoder {
+    /// Encode FramebufferUpdate messages for the `rects` of `image`, in the client `format`.
+    ///
+    /// Panics on `RectEncoding::Raw`, which is handled by the vnc crate.
+    pub fn framebuffer_update(
+        &mut self,
+        image: &BgraView,
+        rects: &[Rect],
+        encoding: RectEncoding,
+        format: &ClientFormat,
+    ) -> Vec<u8> {
+        let rects: Vec<_> = match encoding {
+            RectEncoding::Zrle => rects
+                .iter()
+                .flat_map(|rect| split_rect(rect, MAX_RECT_SIDE))
+                .map(|r| {
+                    let data = self.zrle_rect(image, &r, format);
+                    (r, ENCODING_ZRLE, data)
+                })
+                .collect(),
+            RectEncoding::Tight => rects
+                .iter()
+                .flat_map(tight_subrects)
+                .map(|r| {
+                    let data = self.tight_rect(image, &r, format);
+                    (r, ENCODING_TIGHT, data)
+                })
+                .collect(),
+            RectEncoding::Raw => unreachable!(),
+        };
+
+        // the rectangle count is 16-bit, send several messages if needed
+        let mut msg = vec![];
+        for rects in rects.chunks(u16::MAX as usize) {
+            msg.extend_from_slice(&[0, 0]);
+            msg.extend_from_slice(&(rects.len() as u16).to_be_bytes());
+            for (r, encoding, data) in rects {
+                msg.extend_from_slice(&r.left.to_be_bytes());
+                msg.extend_from_slice(&r.top.to_be_bytes());
+                msg.extend_from_slice(&r.width.to_be_bytes());
+                msg.extend_from_slice(&r.height.to_be_bytes());
+                msg.extend_from_slice(&encoding.to_be_bytes());
+                msg.extend_from_slice(data);
+            }
+        }
+        msg
+    }
+
+    fn zrle_rect(&mut self, image: &BgraView, rect: &Rect, format: &ClientFormat) -> Vec<u8> {
+        let mut tiles = Vec::new();
+        for tile in split_rect(rect, ZRLE_TILE) {
+            zrle_tile(image, &tile, format, &mut tiles);
+        }
+
+        let data = deflate(&mut self.zrle, &tiles);
+        let mut out = Vec::with_capacity(data.len() + 4);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&data);
+        out
+    }
+
+    fn tight_rect(&mut self, image: &BgraView, rect: &Rect, format: &ClientFormat) -> Vec<u8> {
+        let pixels = pixels(image, rect);
+        let first = pixels.clone().next().unwrap_or_default();
+        let mut out = Vec::new();
+        if pixels.clone().all(|p| p == first) {
+            // fill compression
+            out.push(0x80);
+            format.push_tpixel(first, &mut out);
+            return out;
+        }
+
+        // basic compression, zlib stream 0, no filter
+        out.push(0x00);
+        let mut data = Vec::new();
+        for p in pixels {
+            format.push_tpixel(p, &mut data);
+        }
+        if data.len() < TIGHT_MIN_COMPRESS {
+            out.extend_from_slice(&data);
+        } else {
+            let data = deflate(&mut self.tight, &data);
+            compact_len(data.len(), &mut out);
+            out.extend_from_slice(&data);
+        }
+        out
+    }
+}
+
+// the xrgb pixels of the rectangle, row by row
+fn pixels<'a>(image: &'a BgraView<'a>, rect: &Rect) -> impl Iterator<Item = [u8; 4]> + Clone + 'a {
+    let (left, top) = (rect.left as u32, rect.top as u32);
+    let (width, height) = (rect.width as u32, rect.height as u32);
+    (top..top + height)
+        .flat_map(move |y| (left..left + width).map(move |x| image.get_pixel(x, y).0))
+}
+
+fn zrle_tile(image: &BgraView, tile: &Rect, format: &ClientFormat, out: &mut Vec<u8>) {
+    let mut palette: Vec<[u8; 4]> = Vec::with_capacity(16);
+    for p in pixels(image, tile) {
+        if !palette.contains(&p) {
+            if palette.len() == 16 {
+                palette.clear();
+                break;
+            }
+            palette.push(p);
+        }
+    }
+
+    match palette.len() {
+        // raw
+        0 => {
+            out.push(0);
+            for p in pixels(image, tile) {
+                format.push_cpixel(p, out);
+            }
+        }
+        // solid
+        1 => {
+            out.push(1);
+            format.push_cpixel(palette[0], out);
+        }
+        // packed palette
+        n => {
+            out.push(n as u8);
+            for p in &palette {
+                format.push_cpixel(*p, out);
+            }
+            let bits = match n {
+                2 => 1,
+                3..=4 => 2,
+                _ => 4,
+            };
+            let (left, top) = (tile.left as u32, tile.top as u32);
+            for y in top..top + tile.height as u32 {
+                let mut byte = 0u8;
+                let mut used = 0;
+                for x in left..left + tile.width as u32 {
+                    let p = image.get_pixel(x, y).0;
+                    let idx = palette.iter().position(|c| *c == p).unwrap() as u8;
+                    byte = (byte << bits) | idx;
+                    used += bits;
+                    if used == 8 {
+                        out.push(byte);
+                        byte = 0;
+                        used = 0;
+                    }
+                }
+                // rows are padded to a byte boundary
+                if used > 0 {
+                    out.push(byte << (8 - used));
+                }
+            }
+        }
+    }
+}
+
+/// Split a rectangle in tiles of at most `max_width` x `max_height`, row by row.
+///
+/// The rectangle must be within the 16-bit protocol coordinates.
+pub fn split_tiles(rect: &Rect, max_width: u16, max_height: u16) -> Vec<Rect> {
+    if rect.width == 0 || rect.height == 0 {
+        return vec![];
+    }
+    // computed in 32-bit, the rectangle may end at 65536
+    let (left, top) = (rect.left as u32, rect.top as u32);
+    let (right, bottom) = (left + rect.width as u32, top + rect.height as u32);
+    let mut rects = Vec::new();
+    for y in (top..bottom).step_by(max_height as usize) {
+        for x in (left..right).step_by(max_width as usize) {
+            rects.push(Rect {
+                left: x as u16,
+                top: y as u16,
+                width: (max_width as u32).min(right - x) as u16,
+                height: (max_height as u32).min(bottom - y) as u16,
+            });
+        }
+    }
+    rects
+}
+
+pub fn split_rect(rect: &Rect, max_side: u16) -> Vec<Rect> {
+    split_tiles(rect, max_side, max_side)
+}
+
+fn tight_subrects(rect: &Rect) -> Vec<Rect> {
+    let width = rect.width.clamp(1, TIGHT_MAX_WIDTH);
+    let height = (TIGHT_MAX_PIXELS / width as u32).min(u16::MAX as u32) as u16;
+    split_tiles(rect, width, height)
+}
+
+fn compact_len(mut len: usize, out: &mut Vec<u8>) {
+    for i in 0..3 {
+        let mut b = (len & 0x7f) as u8;
+        len >>= 7;
+        if len > 0 && i < 2 {
+            b |= 0x80;
+            out.push(b);
+        } else {
+            out.push(b | (len as u8) << 7);
+            break;
+        }
+    }
+}
+
+// Compress on the persistent stream, flushing so the client can decode the rectangle.
+fn deflate(z: &mut Compress, input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 64);
+    let start = z.total_in();
+    loop {
+        let consumed = (z.total_in() - start) as usize;
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity().max(64));
+        }
+        z.compress_vec(&input[consumed..], &mut out, FlushCompress::Sync)
+            .expect("zlib compression failed");
+        if (z.total_in() - start) as usize == input.len() && out.len() < out.capacity() {
+            break;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_mask() {
+        let mut image = BgraImage::new(9, 1);
+        image.put_pixel(0, 0, image::Bgra([0x40, 0x20, 0x10, 0x80]));
+        image.put_pixel(8, 0, image::Bgra([0xff, 0xff, 0xff, 0xff]));
+        let msg = cursor_update(Some(&image), 1, 0, &ClientFormat::default());
+        // header, rectangle, 9 pixels and a 2-byte mask row
+        assert_eq!(msg.len(), 4 + 12 + 9 * 4 + 2);
+        assert_eq!(&msg[16..20], &[0x80, 0x40, 0x20, 0]);
+        assert_eq!(&msg[msg.len() - 2..], &[0x80, 0x80]);
+        assert_eq!(
+            cursor_update(None, 0, 0, &ClientFormat::default()).len(),
+            16
+        );
+    }
+
+    #[test]
+    fn cut_text() {
+        assert_eq!(
+            server_cut_text("é\r\n€"),
+            vec![3, 0, 0, 0, 0, 0, 0, 3, 0xe9, b'\n', b'?']
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    fs, io,
+    net::{Shutdown, TcpListener, TcpStream},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    thread,
+};
+
+use qemu_display::loopback_pair;
+
+/// A listening socket of the server.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Listen on the unix socket at `path`, accessible with the permission `mode`.
+    ///
+    /// A socket left at `path` by a previous server is removed, unless a server still
+    /// listens on it.
+    pub fn bind_unix(path: &Path, mode: u32) -> io::Result<Self> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use", path.display()),
+                    ));
+                }
+                fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists, and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        Ok(Self::Unix(listener))
+    }
+
+    /// Wait for a client.
+    ///
+    /// The VNC server only handles TCP streams: the unix clients are relayed to the local
+    /// end of a loopback connection.
+    pub fn accept(&self) -> io::Result<TcpStream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| stream),
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                bridge(stream)
+            }
+        }
+    }
+}
+
+/// Parse an octal permission mode, like chmod.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|m| *m <= 0o777)
+        .ok_or_else(|| format!("Invalid mode {:?}", s))
+}
+
+fn bridge(unix: UnixStream) -> io::Result<TcpStream> {
+    let (local, relay) = loopback_pair()?;
+    {
+        let mut unix = unix.try_clone()?;
+        let mut relay = relay.try_clone()?;
+        thread::spawn(move || {
+            let _ = io::copy(&mut relay, &mut unix);
+            let _ = unix.shutdown(Shutdown::Write);
+        });
+    }
+    let (mut unix, mut relay) = (unix, relay);
+    thread::spawn(move || {
+        let _ = io::copy(&mut unix, &mut relay);
+        let _ = relay.shutdown(Shutdown::Write);
+    });
+    Ok(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_socket() {
+        let dir = std::env::temp_dir().join(format!("qemu-vnc-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vnc.sock");
+        let listener = Listener::bind_unix(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(Listener::bind_unix(&path, 0o600).is_err());
+        drop(listener);
+        // nobody listens anymore
+        assert!(Listener::bind_unix(&path, 0o660).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("8").is_err());
+    }
+}
@@ -1,22 +1,60 @@
 use std::{
-    borrow::Borrow,
+    borrow::{Borrow, Cow},
     collections::HashSet,
     error::Error,
-    io,
+    io::{self, prelude::*},
     iter::FromIterator,
-    net::{TcpListener, TcpStream},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    str::FromStr,
     sync::{mpsc, Arc, Mutex},
     thread, time,
 };
 
+use audio::VncAudio;
+use auth::{Authenticator, VncAuth};
 use clap::Parser;
-use image::GenericImage;
-use keycodemap::*;
-use qemu_display::{Console, ConsoleListenerHandler, MouseButton, VMProxy};
+use encoding::{
+    Encoder, RectEncoding, ENCODING_LED_STATE, ENCODING_POINTER_POS, ENCODING_POINTER_TYPE_CHANGE,
+    MAX_RECT_SIDE,
+};
+use enumflags2::BitFlags;
+use futures_util::StreamExt;
+use keycodemap::Keymap;
+use listener::Listener;
+use pacing::FramePacer;
+use pixel::{pixman_xrgb, ClientFormat};
+use policy::{Feature, ListenArg, Policy};
+use qemu_display::{
+    Console, DamageTracker, Display, FrameHandoff, FrameSink, FrameSinkListener, FramebufferEvent,
+    GuestDefaults, GuestOs, KeyTranslation, KeyboardLeds, KeyboardModifiers, ListenerOptions,
+    ModifierTracker, MouseButton, Session, SessionOptions, SharedFramebuffer, SshTunnel,
+    TextClipboard, VMProxy, WakeMethod,
+};
+use readback::DmabufReadback;
+use scale::{Scale, ScaledCursor};
+use security::Security;
+use tls::TlsConfig;
 use vnc::{
     server::{Event as VncEvent, FramebufferUpdate},
-    Encoding, Error as VncError, PixelFormat, Rect, Screen, Server as VncServer,
+    Encoding, Error as VncError, Rect, Screen, Server as VncServer,
 };
+use web::WebRoot;
+
+mod audio;
+mod auth;
+mod encoding;
+mod listener;
+mod pacing;
+mod pixel;
+mod policy;
+mod readback;
+mod scale;
+mod security;
+mod systemd;
+mod tls;
+mod web;
+mod ws;
 
 #[derive(Parser, Debug)]
 pub struct SocketAddrArgs {
@@ -26,6 +64,12 @@ pub struct SocketAddrArgs {
     /// IP port number
     #[clap(short, long, default_value = "5900")]
     port: u16,
+    /// Listen on this unix socket, instead of the IP address and port
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["address", "port"])]
+    unix: Option<PathBuf>,
+    /// The permissions of the unix socket, in octal
+    #[clap(long, default_value = "600", value_parser = listener::parse_mode)]
+    unix_mode: u32,
 }
 
 impl From<SocketAddrArgs> for std::net::SocketAddr {
@@ -40,17 +84,153 @@ struct Cli {
     address: SocketAddrArgs,
     #[clap(short, long)]
     dbus_address: Option<String>,
+    /// Connect to the session bus of a remote host ([user@]host), through ssh
+    #[clap(long, conflicts_with = "dbus-address")]
+    ssh: Option<String>,
+    /// VM name
+    #[clap(long)]
+    vm_name: Option<String>,
+    /// Wait for the VM to be available
+    #[clap(short, long)]
+    wait: bool,
+    /// List the available VMs, and exit
+    #[clap(long)]
+    list: bool,
+    /// Console index
+    #[clap(short, long, default_value = "0")]
+    console: u32,
+    /// TLS certificate (PEM), enables VeNCrypt
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// TLS private key (PEM)
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// CA certificates (PEM) to verify client certificates
+    #[clap(long, requires = "tls-cert")]
+    tls_ca: Option<PathBuf>,
+    /// File containing the VNC password, enables VNC authentication
+    #[clap(long)]
+    password_file: Option<PathBuf>,
+    /// Scale the display by this factor
+    #[clap(long, default_value = "1.0")]
+    scale: f64,
+    /// Listen on ADDRESS:PORT, with options to restrict the clients: view-only, no-input,
+    /// no-clipboard, no-audio, no-resize, max-fps=N (ex: 127.0.0.1:5901,view-only). The
+    /// websocket option accepts WebSocket clients instead, like --ws-port. Replaces --address
+    /// and --port, can be repeated
+    #[clap(long)]
+    listen: Vec<ListenArg>,
+    /// Accept WebSocket clients (noVNC) on this port, at the --address
+    #[clap(long)]
+    ws_port: Option<u16>,
+    /// Serve a browser console on the WebSocket port (6080 by default), with the noVNC
+    /// client installed in DIR (/usr/share/novnc by default)
+    #[clap(long, value_name = "DIR")]
+    web: Option<Option<PathBuf>>,
+    /// Accept the WebSocket clients from the pages of this origin (ex: https://host:port),
+    /// besides the server ones. Can be repeated
+    #[clap(long, value_name = "ORIGIN")]
+    allow_origin: Vec<String>,
+    /// Share the frames with the local viewers, on this unix socket
+    #[clap(long)]
+    handoff: Option<PathBuf>,
+    /// Report the key translations
+    #[clap(long)]
+    debug_keys: bool,
+    /// Wake the guest display when a client connects: none, mouse or key
+    #[clap(long, default_value = "none")]
+    wake: WakeMethod,
+    /// The guest OS (linux, windows, macos...), for the keyboard and mouse defaults. By
+    /// default, it is asked to the guest agent
+    #[clap(long)]
+    guest_os: Option<GuestOs>,
+    /// The keycodes of the extended key events: qnum (QEMU scancodes), win32 (virtual-key
+    /// codes, from the RDP bridges), or auto to detect them from the first keys
+    #[clap(long, default_value = "auto")]
+    ext_keycodes: ExtKeycodes,
+    /// The maximum framebuffer updates per second of a client, 0 for no limit. The updates
+    /// are slowed down further for the clients reading them slowly
+    #[clap(long, default_value = "60")]
+    max_fps: u32,
+    /// Draw the guest cursor in the frames, for the clients without cursor support. It is
+    /// always drawn in the scaled frames
+    #[clap(long)]
+    software_cursor: bool,
+    /// Stream the guest audio to the browser console, on the WebSocket port. It replaces the
+    /// audio output of the other frontends, and requires no VNC security
+    #[clap(long)]
+    audio: bool,
+    /// Accept the clients on the sockets passed by systemd (socket activation), instead of
+    /// --address and --port. They are used by default when passed
+    #[clap(long)]
+    systemd: bool,
+    /// Serve the listener metrics to Prometheus, over HTTP at ADDRESS:PORT
+    #[cfg(feature = "prometheus")]
+    #[clap(long)]
+    metrics_address: Option<SocketAddr>,
 }
 
 #[derive(Debug)]
 enum Event {
-    ConsoleUpdate(Rect),
+    ConsoleUpdate(qemu_display::Rect),
+    // the cursor shape changed, with the damage of the frames drawing the cursor
+    Cursor(qemu_display::Rect),
+    // the cursor moved, or was shown or hidden
+    Mouse(qemu_display::Rect),
+    Leds(KeyboardLeds),
+    // the guest clipboard text
+    CutText(String),
     Vnc(VncEvent),
     Disconnected,
 }
 
-const PIXMAN_X8R8G8B8: u32 = 0x20020888;
+const QNUM_LSHIFT: u32 = 0x2a;
+const QNUM_RSHIFT: u32 = 0x36;
+
+// The Caps Lock state implied by a letter keysym, with the shift state.
+fn caps_lock_state(keysym: u32, shift: bool) -> Option<bool> {
+    let c = char::from_u32(keysym).filter(char::is_ascii_alphabetic)?;
+    Some(c.is_ascii_uppercase() != shift)
+}
+
+/// The keycodes sent by a client with the extended key events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtKeycodes {
+    Auto,
+    Qnum,
+    Win32,
+}
+
+impl FromStr for ExtKeycodes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "qnum" => Ok(Self::Qnum),
+            "win32" => Ok(Self::Win32),
+            _ => Err(format!("Invalid keycodes '{}' (auto, qnum or win32)", s)),
+        }
+    }
+}
+
+impl ExtKeycodes {
+    // the keycodes of a key with a known keysym, if they can be told apart
+    fn detect(keysym: u32, keycode: u32) -> Option<Self> {
+        let qnum = Keymap::X11.qnum(keysym)?;
+        if keycode == qnum {
+            Some(Self::Qnum)
+        } else if Keymap::Win32.qnum(keycode) == Some(qnum) {
+            Some(Self::Win32)
+        } else {
+            None
+        }
+    }
+}
+
 type BgraImage = image::ImageBuffer<image::Bgra<u8>, Vec<u8>>;
+// the framebuffer pixels, in the little-endian pixman x8r8g8b8 layout
+type BgraView<'a> = image::ImageBuffer<image::Bgra<u8>, &'a [u8]>;
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
@@ -58,61 +238,270 @@ struct Client {
     #[derivative(Debug = "ignore")]
     server: Server,
     vnc_server: VncServer,
+    // for the updates not handled by the vnc crate
+    stream: TcpStream,
     share: bool,
-    last_update: Option<time::Instant>,
-    has_update: bool,
+    policy: Policy,
+    pacer: FramePacer,
+    // the updated regions since the last framebuffer update
+    damage: DamageTracker,
     req_update: bool,
     last_buttons: HashSet<MouseButton>,
+    last_pointer: Option<(u16, u16)>,
+    // the pointer type announced with the pointer type change pseudo-encoding
+    pointer_type: Option<bool>,
+    // the guest LEDs sent with the LED state pseudo-encoding
+    leds: Option<KeyboardLeds>,
+    // the cursor visibility sent with the Cursor pseudo-encoding
+    cursor_visible: Option<bool>,
+    shift: bool,
+    ext_keycodes: ExtKeycodes,
     encodings: HashSet<Encoding>,
+    encoding: RectEncoding,
+    encoder: Encoder,
+    // the pixel format requested by the client
+    format: ClientFormat,
     dimensions: (u16, u16),
 }
 
 impl Client {
-    fn new(server: Server, vnc_server: VncServer, share: bool) -> Self {
+    fn new(
+        server: Server,
+        vnc_server: VncServer,
+        stream: TcpStream,
+        share: bool,
+        policy: Policy,
+    ) -> Self {
+        let mut damage = DamageTracker::new();
+        damage.add(server.framebuffer.lock().framebuffer.rect());
+        let ext_keycodes = server.ext_keycodes;
+        let pacer = FramePacer::new(policy.max_fps.unwrap_or(server.max_fps));
         Self {
             server,
             vnc_server,
+            stream,
             share,
-            last_update: None,
-            has_update: false,
+            policy,
+            pacer,
+            damage,
             req_update: false,
             last_buttons: HashSet::new(),
+            last_pointer: None,
+            pointer_type: None,
+            leds: None,
+            cursor_visible: None,
+            shift: false,
+            ext_keycodes,
             encodings: HashSet::new(),
+            encoding: RectEncoding::Raw,
+            encoder: Encoder::default(),
+            format: ClientFormat::default(),
             dimensions: (0, 0),
         }
     }
 
     fn update_pending(&self) -> bool {
-        self.has_update && self.req_update
+        !self.damage.is_empty() && self.req_update
     }
 
-    async fn key_event(&self, qnum: u32, down: bool) -> Result<(), Box<dyn Error>> {
-        let inner = self.server.inner.lock().unwrap();
+    // the time to wait before sending the pending update
+    fn update_delay(&self) -> Option<time::Duration> {
+        self.pacer.delay(time::Instant::now())
+    }
+
+    async fn key_event(
+        &mut self,
+        qnum: u32,
+        keysym: u32,
+        down: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let qnum = self.server.guest.map_qnum(qnum);
+        if qnum == QNUM_LSHIFT || qnum == QNUM_RSHIFT {
+            self.shift = down;
+        }
+        // not to hold the lock across the D-Bus calls
+        let (keyboard, modifiers) = {
+            let inner = self.server.inner.lock().unwrap();
+            (inner.console.keyboard.clone(), inner.modifiers.clone())
+        };
         if down {
-            inner.console.keyboard.press(qnum).await?;
+            // the client Caps Lock state is only known from the case of the letters
+            if let Some(caps) = caps_lock_state(keysym, self.shift) {
+                let mask = BitFlags::from(KeyboardModifiers::Caps);
+                let host = if caps { mask } else { BitFlags::empty() };
+                modifiers.sync(host, mask).await?;
+            }
+            keyboard.press(qnum).await?;
         } else {
-            inner.console.keyboard.release(qnum).await?;
+            keyboard.release(qnum).await?;
+        }
+        Ok(())
+    }
+
+    // the relative motion of a pointer event, in client pixels
+    fn pointer_motion(&self, x: u16, y: u16) -> Option<(i32, i32)> {
+        let (dx, dy) = match (self.pointer_type, self.last_pointer) {
+            // the client sends the motion, offset by 0x7fff
+            (Some(false), _) => (x as i32 - 0x7fff, y as i32 - 0x7fff),
+            (_, Some((last_x, last_y))) => (x as i32 - last_x as i32, y as i32 - last_y as i32),
+            _ => return None,
+        };
+        Some((dx, dy)).filter(|d| *d != (0, 0))
+    }
+
+    // announce the guest pointer type, if the client supports it
+    fn set_pointer_type(&mut self, absolute: bool) -> Result<(), Box<dyn Error>> {
+        let supported = self
+            .encodings
+            .contains(&Encoding::Unknown(ENCODING_POINTER_TYPE_CHANGE));
+        if !supported || self.pointer_type == Some(absolute) {
+            return Ok(());
+        }
+        let (width, height) = self.dimensions;
+        let rect = Rect {
+            left: absolute as u16,
+            top: 0,
+            width,
+            height,
+        };
+        self.stream
+            .write_all(&encoding::pseudo_rect(ENCODING_POINTER_TYPE_CHANGE, &rect))?;
+        self.pointer_type = Some(absolute);
+        self.last_pointer = None;
+        Ok(())
+    }
+
+    // send the guest lock LEDs, if the client supports it
+    fn set_leds(&mut self, leds: KeyboardLeds) -> Result<(), Box<dyn Error>> {
+        let supported = self
+            .encodings
+            .contains(&Encoding::Unknown(ENCODING_LED_STATE));
+        if !supported || self.leds == Some(leds) {
+            return Ok(());
+        }
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 0,
+            height: 0,
+        };
+        let mut msg = encoding::pseudo_rect(ENCODING_LED_STATE, &rect);
+        msg.push(leds.scroll_lock as u8 | (leds.num_lock as u8) << 1 | (leds.caps_lock as u8) << 2);
+        self.stream.write_all(&msg)?;
+        self.leds = Some(leds);
+        Ok(())
+    }
+
+    // whether the client draws the cursor, instead of the server
+    fn client_cursor(&self) -> bool {
+        self.encodings.contains(&Encoding::Cursor)
+    }
+
+    // send the guest cursor, if the client draws it: the shape when it changed or when the
+    // cursor is shown or hidden, and the position if the client supports it
+    fn send_cursor(&mut self, shape_changed: bool) -> Result<(), Box<dyn Error>> {
+        if !self.client_cursor() {
+            return Ok(());
+        }
+        let inner = self.server.inner.lock().unwrap();
+        let state = self.server.framebuffer.lock();
+        let cursor = inner.cursor.as_ref().filter(|_| state.mouse.is_some());
+        let visible = cursor.is_some();
+        if shape_changed || self.cursor_visible != Some(visible) {
+            let msg = match cursor {
+                Some(c) => encoding::cursor_update(
+                    Some(&c.image),
+                    c.hot_x as _,
+                    c.hot_y as _,
+                    &self.format,
+                ),
+                None => encoding::cursor_update(None, 0, 0, &self.format),
+            };
+            self.stream.write_all(&msg)?;
+            self.cursor_visible = Some(visible);
+        }
+        let supported = self
+            .encodings
+            .contains(&Encoding::Unknown(ENCODING_POINTER_POS));
+        if let (true, Some((x, y))) = (supported, state.mouse) {
+            let fb = &state.framebuffer;
+            let (x, y) = self
+                .server
+                .scale
+                .transform((fb.width(), fb.height()))
+                .to_view(x, y);
+            let rect = Rect {
+                left: x.clamp(0, u16::MAX as i32) as u16,
+                top: y.clamp(0, u16::MAX as i32) as u16,
+                width: 0,
+                height: 0,
+            };
+            self.stream
+                .write_all(&encoding::pseudo_rect(ENCODING_POINTER_POS, &rect))?;
         }
         Ok(())
     }
 
+    async fn trace_key(&self, translation: KeyTranslation) {
+        let keyboard = self.server.inner.lock().unwrap().console.keyboard.clone();
+        if let Some(report) = keyboard.trace_key(&translation).await {
+            eprintln!("{}", report);
+        }
+    }
+
     async fn handle_vnc_event(&mut self, event: VncEvent) -> Result<(), Box<dyn Error>> {
         match event {
             VncEvent::FramebufferUpdateRequest { .. } => {
                 self.req_update = true;
                 self.send_framebuffer_update()?;
             }
+            VncEvent::KeyEvent { .. }
+            | VncEvent::ExtendedKeyEvent { .. }
+            | VncEvent::PointerEvent { .. }
+                if !self.policy.allows(Feature::Input) => {}
+            VncEvent::SetDesktopSize { .. } if !self.policy.allows(Feature::Resize) => {}
+            VncEvent::CutText(_) if !self.policy.allows(Feature::Clipboard) => {}
             VncEvent::KeyEvent { key, down } => {
-                if let Some(qnum) = KEYMAP_X112QNUM.get(key as usize) {
-                    self.key_event(*qnum as u32, down).await?;
+                let qnum = Keymap::X11.qnum(key);
+                self.trace_key(KeyTranslation {
+                    press: down,
+                    keyval: Some(key),
+                    keycode: key,
+                    keymap: Keymap::X11.name(),
+                    qnum,
+                })
+                .await;
+                if let Some(qnum) = qnum {
+                    self.key_event(qnum, key, down).await?;
                 }
             }
             VncEvent::ExtendedKeyEvent {
                 down,
-                keysym: _,
+                keysym,
                 keycode,
             } => {
-                self.key_event(keycode as u32, down).await?;
+                let (keysym, keycode) = (keysym as u32, keycode as u32);
+                if self.ext_keycodes == ExtKeycodes::Auto {
+                    if let Some(detected) = ExtKeycodes::detect(keysym, keycode) {
+                        println!("Using extended keycodes: {:?}", detected);
+                        self.ext_keycodes = detected;
+                    }
+                }
+                let (keymap, qnum) = match self.ext_keycodes {
+                    ExtKeycodes::Win32 => (Keymap::Win32.name(), Keymap::Win32.qnum(keycode)),
+                    _ => ("qnum", Some(keycode)),
+                };
+                self.trace_key(KeyTranslation {
+                    press: down,
+                    keyval: Some(keysym),
+                    keycode,
+                    keymap,
+                    qnum,
+                })
+                .await;
+                if let Some(qnum) = qnum {
+                    self.key_event(qnum, keysym, down).await?;
+                }
             }
             VncEvent::PointerEvent {
                 button_mask,
@@ -120,32 +509,67 @@ impl Client {
                 y_position,
             } => {
                 let buttons = button_mask_to_set(button_mask);
-                let inner = self.server.inner.lock().unwrap();
-
+                let scale = self.server.scale;
+                let mouse = self.server.inner.lock().unwrap().console.mouse.clone();
+                let absolute = mouse.is_absolute().await.unwrap_or(true);
                 for b in buttons.difference(&self.last_buttons) {
-                    inner.console.mouse.press(*b).await?;
+                    mouse.press(*b).await?;
                 }
                 for b in self.last_buttons.difference(&buttons) {
-                    inner.console.mouse.release(*b).await?;
+                    mouse.release(*b).await?;
                 }
-                if let Err(err) = inner
-                    .console
-                    .mouse
-                    .set_abs_position(x_position as _, y_position as _)
-                    .await
-                {
-                    eprintln!("Error setting mouse position: {}", err);
+                if absolute {
+                    let (width, height) = {
+                        let state = self.server.framebuffer.lock();
+                        (state.framebuffer.width(), state.framebuffer.height())
+                    };
+                    let pos = (x_position as u32, y_position as u32);
+                    if let Some((x, y)) = scale.guest_pos(pos, (width, height)) {
+                        if let Err(err) = mouse.set_abs_position(x, y).await {
+                            eprintln!("Error setting mouse position: {}", err);
+                        }
+                    }
+                } else if let Some((dx, dy)) = self.pointer_motion(x_position, y_position) {
+                    let (dx, dy) = scale.transform((1, 1)).to_guest_delta(dx as _, dy as _);
+                    if let Err(err) = mouse.rel_motion(dx, dy).await {
+                        eprintln!("Error moving the mouse: {}", err);
+                    }
                 }
+                self.last_pointer = Some((x_position, y_position));
                 self.last_buttons = buttons;
+                self.set_pointer_type(absolute)?;
             }
             VncEvent::SetPixelFormat(p) => {
-                if p != pixman_xrgb() {
-                    todo!("Unsupported client requested format: {:?}", p);
+                let format = ClientFormat::new(p)?;
+                if format != self.format {
+                    println!("Using pixel format: {:?}", format.pixel_format());
+                    self.format = format;
+                    // the client may have dropped the pixels of the previous format
+                    let rect = self.server.framebuffer.lock().framebuffer.rect();
+                    self.damage.add_all(rect);
+                    self.cursor_visible = None;
+                    self.send_cursor(true)?;
                 }
             }
             VncEvent::SetEncodings(e) => {
+                self.encoding = RectEncoding::from_client(&e);
                 self.encodings = HashSet::from_iter(e);
                 println!("Supported encodings: {:?}", &self.encodings);
+                println!("Using encoding: {:?}", self.encoding);
+
+                self.pointer_type = None;
+                let mouse = self.server.inner.lock().unwrap().console.mouse.clone();
+                let absolute = mouse.is_absolute().await.unwrap_or(true);
+                self.set_pointer_type(absolute)?;
+
+                self.leds = None;
+                let keyboard = self.server.inner.lock().unwrap().console.keyboard.clone();
+                if let Ok(leds) = keyboard.leds().await {
+                    self.set_leds(leds)?;
+                }
+
+                self.cursor_visible = None;
+                self.send_cursor(true)?;
 
                 if self.encodings.contains(&Encoding::ExtendedKeyEvent) {
                     let mut fbu = FramebufferUpdate::new(None);
@@ -158,16 +582,24 @@ impl Client {
                 height,
                 screens: _,
             } => {
-                let inner = self.server.inner.lock().unwrap();
-                inner
-                    .console
+                let console = self.server.inner.lock().unwrap().console.clone();
+                let scale = self.server.scale;
+                console
                     .proxy
-                    .set_ui_info(0, 0, 0, 0, width as _, height as _)
+                    .set_ui_info(
+                        0,
+                        0,
+                        0,
+                        0,
+                        scale.guest_length(width as _),
+                        scale.guest_length(height as _),
+                    )
                     .await?;
             }
-            // VncEvent::CutText(_) => {}
-            e => {
-                dbg!(e);
+            VncEvent::CutText(text) => {
+                if let Some(clipboard) = &self.server.clipboard {
+                    clipboard.client_text(text).await?;
+                }
             }
         }
         Ok(())
@@ -179,6 +611,8 @@ impl Client {
             return Ok(());
         }
         self.dimensions = (width, height);
+        let rect = self.server.framebuffer.lock().framebuffer.rect();
+        self.damage.add_all(rect);
 
         let mut fbu = FramebufferUpdate::new(None);
         let screens = &[Screen {
@@ -203,15 +637,21 @@ impl Client {
 
     fn send_framebuffer_update(&mut self) -> Result<(), Box<dyn Error>> {
         self.desktop_resize()?;
-        if self.has_update && self.req_update {
-            if let Some(last_update) = self.last_update {
-                if last_update.elapsed().as_millis() < 10 {
-                    println!("TODO: <10ms, could delay update..")
-                }
-            }
-            self.server.send_framebuffer_update(&self.vnc_server)?;
-            self.last_update = Some(time::Instant::now());
-            self.has_update = false;
+        if self.update_pending() && self.update_delay().is_none() {
+            let damage = self.damage.take();
+            let draw_cursor = !self.client_cursor();
+            let start = time::Instant::now();
+            self.server.send_framebuffer_update(
+                &self.vnc_server,
+                &mut self.stream,
+                &mut self.encoder,
+                self.encoding,
+                &self.format,
+                &damage,
+                draw_cursor,
+            )?;
+            // the socket blocks while the client is slow to read
+            self.pacer.sent(start, start.elapsed());
             self.req_update = false;
         }
         Ok(())
@@ -220,8 +660,17 @@ impl Client {
     async fn handle_event(&mut self, event: Option<Event>) -> Result<bool, Box<dyn Error>> {
         match event {
             Some(Event::Vnc(e)) => self.handle_vnc_event(e).await?,
-            Some(Event::ConsoleUpdate(_)) => {
-                self.has_update = true;
+            Some(Event::ConsoleUpdate(rect)) => self.damage.add(rect),
+            Some(Event::Cursor(_)) | Some(Event::Mouse(_)) if self.client_cursor() => {
+                let shape_changed = matches!(event, Some(Event::Cursor(_)));
+                self.send_cursor(shape_changed)?;
+            }
+            Some(Event::Cursor(rect)) | Some(Event::Mouse(rect)) => self.damage.add(rect),
+            Some(Event::Leds(leds)) => self.set_leds(leds)?,
+            Some(Event::CutText(text)) => {
+                if self.policy.allows(Feature::Clipboard) {
+                    self.stream.write_all(&encoding::server_cut_text(&text))?;
+                }
             }
             Some(Event::Disconnected) => {
                 return Ok(false);
@@ -235,123 +684,285 @@ impl Client {
     }
 }
 
-#[derive(Debug)]
-struct ConsoleListener {
-    server: Server,
-}
-
-#[async_trait::async_trait]
-impl ConsoleListenerHandler for ConsoleListener {
-    async fn scanout(&mut self, s: qemu_display::Scanout) {
-        let mut inner = self.server.inner.lock().unwrap();
-        inner.image = image_from_vec(s.format, s.width, s.height, s.stride, s.data);
-    }
-
-    async fn update(&mut self, u: qemu_display::Update) {
-        let mut inner = self.server.inner.lock().unwrap();
-        let update = image_from_vec(u.format, u.w as _, u.h as _, u.stride, u.data);
-        if (u.x, u.y) == (0, 0) && update.dimensions() == inner.image.dimensions() {
-            inner.image = update;
-        } else {
-            inner.image.copy_from(&update, u.x as _, u.y as _).unwrap();
-        }
-        let rect = Rect {
-            left: u.x as _,
-            top: u.y as _,
-            width: u.w as _,
-            height: u.h as _,
-        };
-        inner.tx.send(Event::ConsoleUpdate(rect)).unwrap();
-    }
-
-    async fn scanout_dmabuf(&mut self, _scanout: qemu_display::ScanoutDMABUF) {
-        unimplemented!()
-    }
-
-    async fn update_dmabuf(&mut self, _update: qemu_display::UpdateDMABUF) {
-        unimplemented!()
-    }
-
-    async fn mouse_set(&mut self, set: qemu_display::MouseSet) {
-        dbg!(set);
-    }
-
-    async fn cursor_define(&mut self, cursor: qemu_display::Cursor) {
-        dbg!(cursor);
-    }
-
-    fn disconnected(&mut self) {
-        dbg!();
-    }
-}
-
 #[derive(Debug)]
 struct ServerInner {
-    console: Console,
-    image: BgraImage,
+    console: Arc<Console>,
+    cursor: Option<ScaledCursor>,
+    // the client area where the cursor was last drawn
+    cursor_rect: qemu_display::Rect,
+    modifiers: Arc<ModifierTracker>,
+    // the console listener is kept registered for the local viewers
+    handoff: Option<FrameHandoff>,
     tx: mpsc::Sender<Event>,
+    _leds_task: zbus::Task<()>,
 }
 
 #[derive(Clone, Debug)]
 struct Server {
     vm_name: String,
+    session: Session,
+    scale: Scale,
+    ext_keycodes: ExtKeycodes,
+    software_cursor: bool,
+    max_fps: u32,
+    guest: GuestDefaults,
+    clipboard: Option<Arc<TextClipboard>>,
+    framebuffer: SharedFramebuffer,
     rx: Arc<Mutex<mpsc::Receiver<Event>>>,
     inner: Arc<Mutex<ServerInner>>,
 }
 
 impl Server {
-    async fn new(vm_name: String, console: Console) -> Result<Self, Box<dyn Error>> {
+    async fn new(
+        vm_name: String,
+        session: Session,
+        console: Console,
+        scale: Scale,
+        ext_keycodes: ExtKeycodes,
+        software_cursor: bool,
+        max_fps: u32,
+        handoff: Option<FrameHandoff>,
+    ) -> Result<Self, Box<dyn Error>> {
         let width = console.width().await?;
         let height = console.height().await?;
-        let image = BgraImage::new(width as _, height as _);
+        let framebuffer = SharedFramebuffer::new(width, height)?;
+        let modifiers = Arc::new(ModifierTracker::new(&console.keyboard).await?);
+        let guest = session.guest_defaults().await;
         let (tx, rx) = mpsc::channel();
+        let mut leds = console.keyboard.receive_leds_changed().await;
+        let leds_tx = tx.clone();
+        let leds_task = console
+            .keyboard
+            .inner()
+            .connection()
+            .executor()
+            .spawn(async move {
+                while let Some(leds) = leds.next().await {
+                    if leds_tx.send(Event::Leds(leds)).is_err() {
+                        return;
+                    }
+                }
+            });
+        let clipboard = match session.display().clipboard().await {
+            Ok(Some(clipboard)) => {
+                let tx = tx.clone();
+                let guest_text = move |text| {
+                    let _ = tx.send(Event::CutText(text));
+                };
+                match TextClipboard::new(clipboard, guest, guest_text).await {
+                    Ok(clipboard) => Some(Arc::new(clipboard)),
+                    Err(e) => {
+                        eprintln!("Failed to register the clipboard: {}", e);
+                        None
+                    }
+                }
+            }
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Failed to get the clipboard: {}", e);
+                None
+            }
+        };
         Ok(Self {
             vm_name,
+            session,
+            scale,
+            ext_keycodes,
+            software_cursor,
+            max_fps,
+            guest,
+            clipboard,
+            framebuffer,
             rx: Arc::new(Mutex::new(rx)),
-            inner: Arc::new(Mutex::new(ServerInner { console, image, tx })),
+            inner: Arc::new(Mutex::new(ServerInner {
+                console: Arc::new(console),
+                cursor: None,
+                cursor_rect: Default::default(),
+                modifiers,
+                handoff,
+                tx,
+                _leds_task: leds_task,
+            })),
         })
     }
 
     fn stop_console(&self) -> Result<(), Box<dyn Error>> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.console.unregister_listener();
+        let inner = self.inner.lock().unwrap();
+        if inner.handoff.is_none() {
+            inner.console.unregister_listener();
+        }
         Ok(())
     }
 
     async fn run_console(&self) -> Result<(), Box<dyn Error>> {
-        let inner = self.inner.lock().unwrap();
-        inner
-            .console
-            .register_listener(ConsoleListener {
-                server: self.clone(),
-            })
+        let (console, handoff) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.console.clone(), inner.handoff.clone())
+        };
+        let server = self.clone();
+        // the GL displays are read back, the clients get regular frames
+        let mut sinks: Vec<Box<dyn FrameSink>> = vec![Box::new(DmabufReadback::new(
+            self.framebuffer
+                .sink(move |event| server.framebuffer_event(event)),
+        ))];
+        if let Some(handoff) = handoff {
+            sinks.push(Box::new(handoff));
+        }
+        // coalesce the mouse positions of the software cursor while the clients are busy
+        let opts = ListenerOptions { coalesce: true };
+        console
+            .register_listener_with_opts(FrameSinkListener::new(sinks), opts)
             .await?;
         Ok(())
     }
 
+    fn framebuffer_event(&self, event: FramebufferEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        let rect = match event {
+            FramebufferEvent::Resized { width, height } => {
+                qemu_display::Rect::new(0, 0, width, height)
+            }
+            FramebufferEvent::Damage(rect) => rect,
+            FramebufferEvent::Cursor => {
+                let state = self.framebuffer.lock();
+                inner.cursor = state
+                    .cursor
+                    .as_ref()
+                    .and_then(|c| ScaledCursor::new(c, self.scale));
+                state.framebuffer.rect()
+            }
+            FramebufferEvent::Mouse => self.framebuffer.lock().framebuffer.rect(),
+            FramebufferEvent::Disconnected => return,
+        };
+        let rect = if !matches!(event, FramebufferEvent::Cursor | FramebufferEvent::Mouse) {
+            rect
+        } else if !self.draws_cursor() {
+            Default::default()
+        } else if !self.scale.is_identity() {
+            rect
+        } else {
+            // redraw where the cursor was, and where it is now
+            let state = self.framebuffer.lock();
+            let now = match (&inner.cursor, state.mouse) {
+                (Some(cursor), Some(pos)) => cursor.rect(pos),
+                _ => Default::default(),
+            };
+            let damage = inner.cursor_rect.union(&now);
+            inner.cursor_rect = now;
+            damage
+        };
+        // the clients drawing the cursor get its changes, the others the damage
+        let event = match event {
+            FramebufferEvent::Cursor => Event::Cursor(rect),
+            FramebufferEvent::Mouse => Event::Mouse(rect),
+            _ if rect.is_empty() => return,
+            _ => Event::ConsoleUpdate(rect),
+        };
+        inner.tx.send(event).unwrap();
+    }
+
+    // the cursor is drawn in the scaled frames, and on request in the others
+    fn draws_cursor(&self) -> bool {
+        self.software_cursor || !self.scale.is_identity()
+    }
+
     fn dimensions(&self) -> (u16, u16) {
-        let inner = self.inner.lock().unwrap();
-        (inner.image.width() as u16, inner.image.height() as u16)
+        let state = self.framebuffer.lock();
+        let fb = &state.framebuffer;
+        let rect = frame_rect(self.scale.size((fb.width(), fb.height())));
+        (rect.width, rect.height)
     }
 
-    fn send_framebuffer_update(&self, server: &VncServer) -> Result<(), Box<dyn Error>> {
+    fn send_framebuffer_update(
+        &self,
+        server: &VncServer,
+        stream: &mut TcpStream,
+        encoder: &mut Encoder,
+        encoding: RectEncoding,
+        format: &ClientFormat,
+        damage: &[qemu_display::Rect],
+        draw_cursor: bool,
+    ) -> Result<(), Box<dyn Error>> {
         let inner = self.inner.lock().unwrap();
-        let mut fbu = FramebufferUpdate::new(Some(&pixman_xrgb()));
-        let pixel_data = inner.image.as_raw();
-        let rect = Rect {
-            left: 0,
-            top: 0,
-            width: inner.image.width() as u16,
-            height: inner.image.height() as u16,
+        let state = self.framebuffer.lock();
+        let fb = &state.framebuffer;
+        let frame = BgraView::from_raw(fb.width(), fb.height(), fb.data()).unwrap();
+        let scaled;
+        let cursor = match (&inner.cursor, state.mouse) {
+            (Some(cursor), Some(pos)) if draw_cursor && self.draws_cursor() => Some((cursor, pos)),
+            _ => None,
         };
-        fbu.add_raw_pixels(rect, &pixel_data);
-        server.send(&fbu)?;
+        let (image, rects) = if self.scale.is_identity() {
+            let damage: Vec<_> = damage
+                .iter()
+                .map(|r| r.intersect(&fb.rect()))
+                .filter(|r| !r.is_empty())
+                .collect();
+            let rects = damage.iter().map(|r| vnc_rect(*r)).collect();
+            match cursor {
+                // the frame is shared, the cursor is drawn on a copy
+                Some((cursor, pos))
+                    if damage
+                        .iter()
+                        .any(|r| !cursor.rect(pos).intersect(r).is_empty()) =>
+                {
+                    let mut image =
+                        BgraImage::from_raw(fb.width(), fb.height(), fb.data().to_vec()).unwrap();
+                    cursor.composite(&mut image, pos);
+                    scaled = image;
+                    (
+                        BgraView::from_raw(fb.width(), fb.height(), scaled.as_raw()).unwrap(),
+                        rects,
+                    )
+                }
+                _ => (frame, rects),
+            }
+        } else {
+            // the scaled frame is sent whole
+            let mut image = self.scale.resize(&frame);
+            if let Some((cursor, (x, y))) = cursor {
+                let transform = self.scale.transform((fb.width(), fb.height()));
+                cursor.composite(&mut image, transform.to_view(x, y));
+            }
+            scaled = image;
+            let (width, height) = scaled.dimensions();
+            (
+                BgraView::from_raw(width, height, scaled.as_raw()).unwrap(),
+                vec![frame_rect((width, height))],
+            )
+        };
+        let rects: Vec<Rect> = rects
+            .into_iter()
+            .filter(|r: &Rect| r.width != 0 && r.height != 0)
+            .collect();
+        if rects.is_empty() {
+            return Ok(());
+        }
+
+        if encoding == RectEncoding::Raw {
+            let mut fbu = FramebufferUpdate::new(Some(format.pixel_format()));
+            for rect in rects
+                .iter()
+                .flat_map(|r| encoding::split_rect(r, MAX_RECT_SIDE))
+            {
+                let pixels = raw_pixels(&image, rect);
+                if format.is_xrgb() {
+                    fbu.add_raw_pixels(rect, &pixels);
+                } else {
+                    fbu.add_raw_pixels(rect, &format.convert(&pixels));
+                }
+            }
+            server.send(&fbu)?;
+        } else {
+            stream.write_all(&encoder.framebuffer_update(&image, &rects, encoding, format))?;
+        }
         Ok(())
     }
 
-    async fn handle_client(&self, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    async fn handle_client(&self, stream: TcpStream, policy: Policy) -> Result<(), Box<dyn Error>> {
         let (width, height) = self.dimensions();
 
+        let client_stream = stream.try_clone()?;
         let (vnc_server, share) =
             VncServer::from_tcp_stream(stream, width, height, pixman_xrgb(), self.vm_name.clone())?;
 
@@ -375,14 +986,21 @@ impl Server {
             tx.send(Event::Vnc(event)).unwrap();
         });
 
-        let mut client = Client::new(self.clone(), vnc_server, share);
+        let mut client = Client::new(self.clone(), vnc_server, client_stream, share, policy);
         self.run_console().await?;
+        let console = self.inner.lock().unwrap().console.clone();
+        if let Err(e) = self.session.viewer_connected(&console).await {
+            eprintln!("Failed to wake the display: {}", e);
+        }
         let rx = self.rx.lock().unwrap();
         loop {
             let ev = if client.update_pending() {
-                match rx.try_recv() {
+                // the update is deferred to the pace of the client, the events are handled
+                // in the meantime
+                let delay = client.update_delay().unwrap_or_default();
+                match rx.recv_timeout(delay) {
                     Ok(e) => Some(e),
-                    Err(mpsc::TryRecvError::Empty) => None,
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
                     Err(e) => {
                         return Err(e.into());
                     }
@@ -399,6 +1017,37 @@ impl Server {
     }
 }
 
+// The protocol rectangle, limited to the 16-bit coordinates.
+fn vnc_rect(rect: qemu_display::Rect) -> Rect {
+    let max = u16::MAX as u32;
+    let (left, top) = (rect.x.min(max), rect.y.min(max));
+    Rect {
+        left: left as u16,
+        top: top as u16,
+        width: (rect.right().min(max) - left) as u16,
+        height: (rect.bottom().min(max) - top) as u16,
+    }
+}
+
+fn frame_rect((width, height): (u32, u32)) -> Rect {
+    vnc_rect(qemu_display::Rect::new(0, 0, width, height))
+}
+
+fn raw_pixels<'a>(image: &'a BgraView<'_>, rect: Rect) -> Cow<'a, [u8]> {
+    let width = image.width() as usize;
+    if (rect.left, rect.width as usize) == (0, width) {
+        let start = rect.top as usize * width * 4;
+        let end = start + rect.height as usize * width * 4;
+        return Cow::Borrowed(&image.as_raw()[start..end]);
+    }
+    let mut data = Vec::with_capacity(rect.width as usize * rect.height as usize * 4);
+    for y in rect.top..rect.top + rect.height {
+        let start = (y as usize * width + rect.left as usize) * 4;
+        data.extend_from_slice(&image.as_raw()[start..start + rect.width as usize * 4]);
+    }
+    Cow::Owned(data)
+}
+
 fn button_mask_to_set(mask: u8) -> HashSet<MouseButton> {
     let mut set = HashSet::new();
     if mask & 0b0000_0001 != 0 {
@@ -416,60 +1065,105 @@ fn button_mask_to_set(mask: u8) -> HashSet<MouseButton> {
     if mask & 0b0001_0000 != 0 {
         set.insert(MouseButton::WheelDown);
     }
+    if mask & 0b0010_0000 != 0 {
+        set.insert(MouseButton::WheelLeft);
+    }
+    if mask & 0b0100_0000 != 0 {
+        set.insert(MouseButton::WheelRight);
+    }
     set
 }
 
-pub fn pixman_xrgb() -> PixelFormat {
-    PixelFormat {
-        bits_per_pixel: 32,
-        depth: 24,
-        big_endian: false,
-        true_colour: true,
-        red_max: 255,
-        green_max: 255,
-        blue_max: 255,
-        red_shift: 16,
-        green_shift: 8,
-        blue_shift: 0,
-    }
+// the negotiation of the clients of a listener, before they are served
+#[derive(Clone)]
+struct Handshake {
+    websocket: bool,
+    policy: Policy,
+    web: Option<WebRoot>,
+    audio: Option<Arc<VncAudio>>,
+    origins: Arc<Vec<String>>,
+    security: Arc<Security>,
 }
 
-fn image_from_vec(format: u32, width: u32, height: u32, stride: u32, data: Vec<u8>) -> BgraImage {
-    if format != PIXMAN_X8R8G8B8 {
-        todo!("unhandled pixman format: {}", format)
-    }
-    if cfg!(target_endian = "big") {
-        todo!("pixman/image in big endian")
+impl Handshake {
+    // upgrade a WebSocket client, and negotiate the security, for an RFB session
+    fn negotiate(&self, stream: TcpStream) -> Option<TcpStream> {
+        let stream = if self.websocket {
+            let (web, audio) = (self.web.as_ref(), self.audio.as_ref());
+            match ws::accept(stream, web, audio, &self.policy, &self.origins) {
+                Ok(Some(stream)) => stream,
+                Ok(None) => return None,
+                Err(e) => {
+                    eprintln!("WebSocket handshake failed: {}", e);
+                    return None;
+                }
+            }
+        } else {
+            stream
+        };
+        if self.security.is_none() {
+            return Some(stream);
+        }
+        match self.security.accept(stream) {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                eprintln!("Security negotiation failed: {}", e);
+                None
+            }
+        }
     }
-    let layout = image::flat::SampleLayout {
-        channels: 4,
-        channel_stride: 1,
-        width,
-        width_stride: 4,
-        height,
-        height_stride: stride as _,
-    };
-    let samples = image::flat::FlatSamples {
-        samples: data,
-        layout,
-        color_hint: None,
-    };
-    samples
-        .try_into_buffer::<image::Bgra<u8>>()
-        .or_else::<&str, _>(|(_err, samples)| {
-            let view = samples.as_view::<image::Bgra<u8>>().unwrap();
-            let mut img = BgraImage::new(width, height);
-            img.copy_from(&view, 0, 0).unwrap();
-            Ok(img)
-        })
-        .unwrap()
 }
 
 async fn run() -> Result<(), Box<dyn Error>> {
     let args = Cli::parse();
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsConfig::new(cert, key, args.tls_ca.as_deref())?),
+        _ => None,
+    };
+    let auth = match &args.password_file {
+        Some(path) => {
+            let password = std::fs::read_to_string(path)?;
+            let password = password.lines().next().unwrap_or_default();
+            Some(Box::new(VncAuth::new(password)) as Box<dyn Authenticator>)
+        }
+        None => None,
+    };
+    let security = Arc::new(Security::new(tls, auth)?);
+    qemu_display::set_key_debug(args.debug_keys);
+    #[cfg(feature = "prometheus")]
+    if let Some(address) = args.metrics_address {
+        let address = qemu_display::serve_prometheus(address)?;
+        println!("Metrics at http://{}/metrics", address);
+    }
+    let scale = Scale::new(args.scale).ok_or("Invalid scale factor")?;
 
-    let listener = TcpListener::bind::<std::net::SocketAddr>(args.address.into()).unwrap();
-    let dbus = if let Some(addr) = args.dbus_address {
+    let ws_port = match args.web {
+        Some(_) => Some(args.ws_port.unwrap_or(web::DEFAULT_PORT)),
+        None => args.ws_port,
+    };
+    let ws_address = ws_port.map(|port| (args.address.address, port));
+    if args.audio && ws_address.is_none() && !args.listen.iter().any(|l| l.websocket) {
+        return Err("Audio requires --ws-port, --web or a websocket listener".into());
+    }
+    if args.audio && !security.is_none() {
+        return Err(
+            "The audio stream isn't authenticated, it can't be used with the VNC security".into(),
+        );
+    }
+    let activated = systemd::listeners()?;
+    if args.systemd && activated.is_empty() {
+        return Err("No socket passed by systemd".into());
+    }
+    // the tunnel is kept open until the server exits
+    let tunnel = match &args.ssh {
+        Some(destination) => Some(SshTunnel::session_bus(destination).await?),
+        None => None,
+    };
+    let dbus_address = tunnel
+        .as_ref()
+        .map(SshTunnel::address)
+        .or(args.dbus_address);
+    let dbus = if let Some(addr) = dbus_address {
         zbus::ConnectionBuilder::address(addr.borrow())?
             .build()
             .await
@@ -478,14 +1172,134 @@ async fn run() -> Result<(), Box<dyn Error>> {
     }
     .expect("Failed to connect to DBus");
 
-    let vm_name = VMProxy::new(&dbus).await?.name().await?;
+    if args.list {
+        for (name, dest) in Display::by_name(&dbus).await? {
+            println!("{} (at {})", name, dest);
+        }
+        return Ok(());
+    }
+    let dest = Display::lookup(&dbus, args.wait, args.vm_name.as_deref())
+        .await?
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| "org.qemu".into());
+    let vm_name = VMProxy::builder(&dbus)
+        .destination(dest.as_str())?
+        .build()
+        .await?
+        .name()
+        .await?;
 
-    let console = Console::new(&dbus.into(), 0)
-        .await
-        .expect("Failed to get the console");
-    let server = Server::new(format!("qemu-vnc ({})", vm_name), console).await?;
-    for stream in listener.incoming() {
-        server.handle_client(stream?).await?;
+    let mut listeners: Vec<_> = activated
+        .into_iter()
+        .map(|l| (l, Policy::default(), false))
+        .collect();
+    if let Some(path) = &args.address.unix {
+        let listener = Listener::bind_unix(path, args.address.unix_mode)?;
+        listeners.push((listener, Policy::default(), false));
+    }
+    let listen = if !args.listen.is_empty() {
+        args.listen
+    } else if listeners.is_empty() {
+        vec![ListenArg {
+            address: args.address.into(),
+            policy: Policy::default(),
+            websocket: false,
+        }]
+    } else {
+        vec![]
+    };
+    for l in listen {
+        let listener = Listener::Tcp(TcpListener::bind(l.address)?);
+        listeners.push((listener, l.policy, l.websocket));
+    }
+    if let Some(address) = ws_address {
+        let listener = Listener::Tcp(TcpListener::bind(address)?);
+        listeners.push((listener, Policy::default(), true));
+    }
+
+    let display = Display::new(&dbus, Some(dest)).await?;
+    let opts = SessionOptions {
+        wake: args.wake,
+        guest_os: args.guest_os,
+    };
+    let session = Session::new(display, opts);
+    let audio = if args.audio {
+        let audio = session
+            .display()
+            .audio()
+            .await?
+            .ok_or("The VM has no audio")?;
+        Some(Arc::new(VncAudio::new(audio).await?))
+    } else {
+        None
+    };
+    let console = session.console(args.console).await?;
+    // the clients show the head in the desktop name
+    let info = console.info().await?;
+    let handoff = args.handoff.as_ref().map(FrameHandoff::bind).transpose()?;
+    let server = Server::new(
+        format!("qemu-vnc ({} - {})", vm_name, info.label),
+        session,
+        console,
+        scale,
+        args.ext_keycodes,
+        args.software_cursor,
+        args.max_fps,
+        handoff,
+    )
+    .await?;
+    if args.handoff.is_some() {
+        server.run_console().await?;
+    }
+    // the clients of all the listeners share the console, and are served one at a time
+    let web = match args.web {
+        Some(root) => {
+            let root = root.unwrap_or_else(|| web::DEFAULT_ROOT.into());
+            let web = WebRoot::new(root, &vm_name)?;
+            if let Some((address, port)) = ws_address {
+                println!(
+                    "Browser console at http://{}/",
+                    SocketAddr::from((address, port))
+                );
+            }
+            Some(web)
+        }
+        None => None,
+    };
+    let origins = Arc::new(args.allow_origin);
+    let (tx, rx) = mpsc::channel();
+    for (listener, policy, websocket) in listeners {
+        let tx = tx.clone();
+        let handshake = Handshake {
+            websocket,
+            policy,
+            web: web.clone(),
+            audio: audio.clone(),
+            origins: origins.clone(),
+            security: security.clone(),
+        };
+        thread::spawn(move || loop {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) => {
+                    if tx.send((Err(e), handshake.policy.clone())).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            // the negotiation is done on a thread per client, not to hold the other ones
+            let (tx, handshake) = (tx.clone(), handshake.clone());
+            thread::spawn(move || {
+                if let Some(stream) = handshake.negotiate(stream) {
+                    let _ = tx.send((Ok(stream), handshake.policy));
+                }
+            });
+        });
+    }
+    drop(tx);
+    for (stream, policy) in rx {
+        server.handle_client(stream?, policy).await?;
     }
 
     Ok(())
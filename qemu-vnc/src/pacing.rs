@@ -0,0 +1,93 @@
+use std::time::{Duration, Instant};
+
+// the slowest pace, whatever the client backpressure
+const MAX_INTERVAL: Duration = Duration::from_secs(1);
+// the interval is this many times the last write duration, when the socket is slow
+const BACKPRESSURE_FACTOR: u32 = 2;
+
+/// The pace of the framebuffer updates of a client.
+///
+/// The updates are at most `max_fps` per second. The socket writes block while the client
+/// (or the network) is slow to read, the interval grows with their duration, and shrinks
+/// back when they are fast again. The damage accumulates in between, so a slow client gets
+/// fewer, larger updates instead of a backlog.
+#[derive(Debug, Clone)]
+pub struct FramePacer {
+    min_interval: Duration,
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl FramePacer {
+    /// A pacer limited to `max_fps` updates per second, or only by the client with 0.
+    pub fn new(max_fps: u32) -> Self {
+        let min_interval = if max_fps == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(1) / max_fps
+        };
+        Self {
+            min_interval,
+            interval: min_interval,
+            last: None,
+        }
+    }
+
+    /// The time to wait before the next update, none if it can be sent at `now`.
+    pub fn delay(&self, now: Instant) -> Option<Duration> {
+        let next = self.last? + self.interval;
+        next.checked_duration_since(now).filter(|d| !d.is_zero())
+    }
+
+    /// Account an update sent at `start`, which took `write` to be written to the socket.
+    pub fn sent(&mut self, start: Instant, write: Duration) {
+        self.last = Some(start);
+        let target = (write * BACKPRESSURE_FACTOR).min(MAX_INTERVAL);
+        self.interval = if target > self.interval {
+            target
+        } else {
+            // recover progressively, a single fast write may be luck
+            (self.interval * 3 + target) / 4
+        }
+        .max(self.min_interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_fps() {
+        let mut p = FramePacer::new(50);
+        let t = Instant::now();
+        assert_eq!(p.delay(t), None);
+        p.sent(t, Duration::ZERO);
+        assert_eq!(p.delay(t), Some(Duration::from_millis(20)));
+        assert_eq!(
+            p.delay(t + Duration::from_millis(15)),
+            Some(Duration::from_millis(5))
+        );
+        assert_eq!(p.delay(t + Duration::from_millis(20)), None);
+
+        let mut p = FramePacer::new(0);
+        p.sent(t, Duration::ZERO);
+        assert_eq!(p.delay(t), None);
+    }
+
+    #[test]
+    fn backpressure() {
+        let mut p = FramePacer::new(50);
+        let t = Instant::now();
+        // the next update is delayed by the interval, from the previous one
+        p.sent(t, Duration::from_millis(100));
+        assert_eq!(p.delay(t), Some(Duration::from_millis(200)));
+        p.sent(t, Duration::from_secs(10));
+        assert_eq!(p.delay(t), Some(MAX_INTERVAL));
+        // back to the limit, after a few fast writes
+        for _ in 0..30 {
+            p.sent(t, Duration::ZERO);
+        }
+        assert_eq!(p.delay(t), Some(Duration::from_millis(20)));
+    }
+}
@@ -0,0 +1,215 @@
+use vnc::PixelFormat;
+
+/// The format of the framebuffer, sent without conversion.
+pub fn pixman_xrgb() -> PixelFormat {
+    PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    }
+}
+
+/// The pixel format requested by a client, the framebuffer pixels are converted to it.
+///
+/// Only the true-colour formats are supported, of 8, 16 or 32 bits per pixel, in either byte
+/// order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientFormat {
+    pf: PixelFormat,
+    // the bytes of the ZRLE compressed pixels, in the client pixels
+    cpixel: (usize, usize),
+}
+
+impl Default for ClientFormat {
+    fn default() -> Self {
+        Self::new(pixman_xrgb()).unwrap()
+    }
+}
+
+impl ClientFormat {
+    pub fn new(pf: PixelFormat) -> Result<Self, String> {
+        let unsupported = |reason| Err(format!("Unsupported pixel format {:?}: {}", pf, reason));
+        if !pf.true_colour {
+            return unsupported("colour maps aren't supported");
+        }
+        if ![8, 16, 32].contains(&pf.bits_per_pixel) {
+            return unsupported("invalid bits per pixel");
+        }
+        let channels = [
+            (pf.red_max, pf.red_shift),
+            (pf.green_max, pf.green_shift),
+            (pf.blue_max, pf.blue_shift),
+        ];
+        let fits = |bits: u32| {
+            channels
+                .iter()
+                .all(|&(max, shift)| (shift as u32) < bits && (max as u64) << shift < 1u64 << bits)
+        };
+        if channels.iter().any(|&(max, _)| max == 0) || !fits(pf.bits_per_pixel as u32) {
+            return unsupported("invalid colour channels");
+        }
+
+        // CPIXEL is 3 bytes when the colours fit in the least or the most significant ones
+        let bpp = pf.bits_per_pixel as usize / 8;
+        let low = pf.big_endian as usize;
+        let cpixel = if bpp == 4 && pf.depth <= 24 && fits(24) {
+            (low, 3)
+        } else if bpp == 4 && pf.depth <= 24 && channels.iter().all(|&(_, shift)| shift >= 8) {
+            (1 - low, 3)
+        } else {
+            (0, bpp)
+        };
+        Ok(Self { pf, cpixel })
+    }
+
+    pub fn pixel_format(&self) -> &PixelFormat {
+        &self.pf
+    }
+
+    pub fn is_xrgb(&self) -> bool {
+        self.pf == pixman_xrgb()
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.pf.bits_per_pixel as usize / 8
+    }
+
+    // the client pixel value of a framebuffer pixel
+    fn value(&self, p: [u8; 4]) -> u32 {
+        let sample = |c: u8, max: u16, shift: u8| ((c as u32 * max as u32 + 127) / 255) << shift;
+        let pf = &self.pf;
+        sample(p[2], pf.red_max, pf.red_shift)
+            | sample(p[1], pf.green_max, pf.green_shift)
+            | sample(p[0], pf.blue_max, pf.blue_shift)
+    }
+
+    /// Append a framebuffer pixel, in the B, G, R, X byte order, in the client format.
+    pub fn push_pixel(&self, p: [u8; 4], out: &mut Vec<u8>) {
+        let v = self.value(p);
+        match (self.pf.bits_per_pixel, self.pf.big_endian) {
+            (8, _) => out.push(v as u8),
+            (16, false) => out.extend_from_slice(&(v as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(v as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&v.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&v.to_be_bytes()),
+        }
+    }
+
+    /// Convert framebuffer pixels to the client format.
+    pub fn convert(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() / 4 * self.bytes_per_pixel());
+        for p in data.chunks_exact(4) {
+            self.push_pixel([p[0], p[1], p[2], p[3]], &mut out);
+        }
+        out
+    }
+
+    /// Append a ZRLE compressed pixel (CPIXEL).
+    pub fn push_cpixel(&self, p: [u8; 4], out: &mut Vec<u8>) {
+        let start = out.len();
+        self.push_pixel(p, out);
+        let (offset, len) = self.cpixel;
+        out.copy_within(start + offset..start + offset + len, start);
+        out.truncate(start + len);
+    }
+
+    /// Append a Tight pixel (TPIXEL): R, G, B for the 24-bit depth true-colour formats.
+    pub fn push_tpixel(&self, p: [u8; 4], out: &mut Vec<u8>) {
+        let pf = &self.pf;
+        if pf.bits_per_pixel == 32
+            && pf.depth == 24
+            && [pf.red_max, pf.green_max, pf.blue_max] == [255; 3]
+        {
+            let v = self.value(p);
+            out.extend_from_slice(&[
+                (v >> pf.red_shift) as u8,
+                (v >> pf.green_shift) as u8,
+                (v >> pf.blue_shift) as u8,
+            ]);
+        } else {
+            self.push_pixel(p, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_formats() {
+        // a B, G, R, X pixel
+        let p = [0x10, 0x80, 0xff, 0];
+        let xrgb = ClientFormat::default();
+        assert!(xrgb.is_xrgb());
+        assert_eq!(xrgb.convert(&p), p);
+
+        let mut out = vec![];
+        xrgb.push_cpixel(p, &mut out);
+        xrgb.push_tpixel(p, &mut out);
+        assert_eq!(out, [0x10, 0x80, 0xff, 0xff, 0x80, 0x10]);
+
+        let bgr_be = ClientFormat::new(PixelFormat {
+            big_endian: true,
+            red_shift: 0,
+            blue_shift: 16,
+            ..pixman_xrgb()
+        })
+        .unwrap();
+        assert_eq!(bgr_be.convert(&p), [0, 0x10, 0x80, 0xff]);
+        let mut out = vec![];
+        bgr_be.push_cpixel(p, &mut out);
+        assert_eq!(out, [0x10, 0x80, 0xff]);
+
+        let rgb565 = ClientFormat::new(PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..pixman_xrgb()
+        })
+        .unwrap();
+        let v: u16 = 31 << 11 | 32 << 5 | 2;
+        assert_eq!(rgb565.convert(&p), v.to_le_bytes());
+        let mut out = vec![];
+        rgb565.push_cpixel(p, &mut out);
+        rgb565.push_tpixel(p, &mut out);
+        assert_eq!(out, [v.to_le_bytes(), v.to_le_bytes()].concat());
+
+        let bgr233 = ClientFormat::new(PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            red_max: 7,
+            green_max: 7,
+            blue_max: 3,
+            red_shift: 0,
+            green_shift: 3,
+            blue_shift: 6,
+            ..pixman_xrgb()
+        })
+        .unwrap();
+        assert_eq!(bgr233.convert(&p), [7 | 4 << 3]);
+
+        assert!(ClientFormat::new(PixelFormat {
+            true_colour: false,
+            ..pixman_xrgb()
+        })
+        .is_err());
+        assert!(ClientFormat::new(PixelFormat {
+            bits_per_pixel: 16,
+            ..pixman_xrgb()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::{collections::HashSet, net::SocketAddr, str::FromStr};
+
+/// A feature that can be denied to the clients of a listener.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Feature {
+    /// Keyboard and pointer events.
+    Input,
+    /// Clipboard sharing.
+    Clipboard,
+    /// Audio playback and capture.
+    Audio,
+    /// Guest display resize requests.
+    Resize,
+}
+
+/// The features allowed to the clients of a listener.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    denied: HashSet<Feature>,
+    /// The update rate limit of the clients, instead of the server one.
+    pub max_fps: Option<u32>,
+}
+
+impl Policy {
+    pub fn deny(&mut self, feature: Feature) {
+        self.denied.insert(feature);
+    }
+
+    pub fn allows(&self, feature: Feature) -> bool {
+        !self.denied.contains(&feature)
+    }
+}
+
+/// A listening address, with the policy of its clients.
+///
+/// Parsed from `ADDRESS:PORT[,OPTION...]`, with the options `websocket`, `view-only`,
+/// `no-input`, `no-clipboard`, `no-audio`, `no-resize` and `max-fps=N`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenArg {
+    pub address: SocketAddr,
+    pub policy: Policy,
+    /// Accept WebSocket clients, which may also get the audio stream.
+    pub websocket: bool,
+}
+
+impl FromStr for ListenArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let address = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|e| format!("Invalid listen address {:?}: {}", s, e))?;
+        let mut policy = Policy::default();
+        let mut websocket = false;
+        for opt in parts {
+            if opt == "websocket" {
+                websocket = true;
+                continue;
+            }
+            if let Some(fps) = opt.strip_prefix("max-fps=") {
+                let fps = fps
+                    .parse()
+                    .map_err(|e| format!("Invalid max-fps {:?}: {}", fps, e))?;
+                policy.max_fps = Some(fps);
+                continue;
+            }
+            let denied: &[Feature] = match opt {
+                "view-only" => &[Feature::Input, Feature::Clipboard, Feature::Resize],
+                "no-input" => &[Feature::Input],
+                "no-clipboard" => &[Feature::Clipboard],
+                "no-audio" => &[Feature::Audio],
+                "no-resize" => &[Feature::Resize],
+                _ => return Err(format!("Unknown listen option {:?}", opt)),
+            };
+            for f in denied {
+                policy.deny(*f);
+            }
+        }
+        Ok(Self {
+            address,
+            policy,
+            websocket,
+        })
+    }
+}
@@ -0,0 +1,527 @@
+//! Read the DMABUF scanouts of the GL displays, for the VNC clients.
+//!
+//! With the `egl` feature, the DMABUF is imported in a surfaceless EGL context, and read
+//! with glReadPixels: any layout the GPU driver can import works, tiled ones included.
+//! Otherwise, or if EGL isn't usable, only the linear DMABUFs are read, with a mapping.
+
+use qemu_display::{
+    Cursor, FrameSink, MouseSet, RgbaImage, Scanout, ScanoutDMABUF, Update, UpdateDMABUF,
+    PIXMAN_X8R8G8B8,
+};
+
+#[cfg(feature = "egl")]
+use egl::EglReadback;
+
+/// A [`FrameSink`] adapter, handing regular x8r8g8b8 frames to the sink for the DMABUF
+/// scanouts and updates.
+pub struct DmabufReadback<S> {
+    sink: S,
+    scanout: Option<ScanoutDMABUF>,
+    // the sink has the current DMABUF content, else it is read again on the next update
+    sent: bool,
+    #[cfg(feature = "egl")]
+    egl: Option<EglReadback>,
+}
+
+impl<S: FrameSink> DmabufReadback<S> {
+    pub fn new(sink: S) -> Self {
+        #[cfg(feature = "egl")]
+        let egl = match EglReadback::new() {
+            Ok(egl) => Some(egl),
+            Err(e) => {
+                eprintln!(
+                    "EGL readback unavailable, only linear DMABUFs are read: {}",
+                    e
+                );
+                None
+            }
+        };
+        Self {
+            sink,
+            scanout: None,
+            sent: false,
+            #[cfg(feature = "egl")]
+            egl,
+        }
+    }
+
+    // the frame rows, top-down, in the little-endian x8r8g8b8 layout
+    fn read(&mut self, x: u32, y: u32, w: u32, h: u32) -> Result<Vec<u8>, String> {
+        let scanout = self.scanout.as_ref().ok_or("no DMABUF scanout")?;
+        #[cfg(feature = "egl")]
+        if let Some(egl) = &mut self.egl {
+            return egl.read(scanout, x, y, w, h);
+        }
+        let image = RgbaImage::from_dmabuf(scanout).map_err(|e| e.to_string())?;
+        let mut data = Vec::with_capacity(w as usize * h as usize * 4);
+        for row in y..y + h {
+            let start = (row * image.width + x) as usize * 4;
+            data.extend_from_slice(&image.data[start..start + w as usize * 4]);
+        }
+        for px in data.chunks_exact_mut(4) {
+            px.swap(0, 2);
+        }
+        Ok(data)
+    }
+
+    // hand the whole DMABUF to the sink, as a regular scanout
+    async fn scanout(&mut self) -> Result<(), String> {
+        let (width, height) = match &self.scanout {
+            Some(s) => (s.width, s.height),
+            None => return Ok(()),
+        };
+        let data = self.read(0, 0, width, height)?;
+        self.sent = true;
+        self.sink
+            .on_scanout(Scanout {
+                width,
+                height,
+                stride: width * 4,
+                format: PIXMAN_X8R8G8B8,
+                data,
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn update(&mut self, update: UpdateDMABUF) -> Result<(), String> {
+        let (width, height) = match &self.scanout {
+            Some(s) => (s.width, s.height),
+            None => return Ok(()),
+        };
+        if !self.sent {
+            return self.scanout().await;
+        }
+        let (x, y) = (update.x.max(0) as u32, update.y.max(0) as u32);
+        let w = (update.w.max(0) as u32).min(width.saturating_sub(x));
+        let h = (update.h.max(0) as u32).min(height.saturating_sub(y));
+        if w == 0 || h == 0 {
+            return Ok(());
+        }
+        let data = self.read(x, y, w, h)?;
+        self.sink
+            .on_update(Update {
+                x: x as _,
+                y: y as _,
+                w: w as _,
+                h: h as _,
+                stride: w * 4,
+                format: PIXMAN_X8R8G8B8,
+                data,
+            })
+            .await;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: FrameSink> FrameSink for DmabufReadback<S> {
+    async fn on_resize(&mut self, width: u32, height: u32) {
+        self.sink.on_resize(width, height).await;
+    }
+
+    async fn on_scanout(&mut self, scanout: Scanout) {
+        self.scanout = None;
+        self.sink.on_scanout(scanout).await;
+    }
+
+    async fn on_update(&mut self, update: Update) {
+        self.sink.on_update(update).await;
+    }
+
+    async fn on_scanout_dmabuf(&mut self, scanout: ScanoutDMABUF) {
+        #[cfg(feature = "egl")]
+        if let Some(egl) = &mut self.egl {
+            egl.release();
+        }
+        self.scanout = Some(scanout);
+        self.sent = false;
+        // a static display may not be updated for a while
+        if let Err(e) = self.scanout().await {
+            eprintln!("Failed to read the DMABUF scanout: {}", e);
+        }
+    }
+
+    async fn on_update_dmabuf(&mut self, update: UpdateDMABUF) {
+        if let Err(e) = self.update(update).await {
+            eprintln!("Failed to read the DMABUF: {}", e);
+        }
+    }
+
+    async fn on_cursor(&mut self, cursor: Cursor) {
+        self.sink.on_cursor(cursor).await;
+    }
+
+    async fn on_mouse_set(&mut self, set: MouseSet) {
+        self.sink.on_mouse_set(set).await;
+    }
+
+    fn on_disable(&mut self) {
+        self.sink.on_disable();
+    }
+
+    fn on_disconnected(&mut self) {
+        self.sink.on_disconnected();
+    }
+}
+
+#[cfg(feature = "egl")]
+mod egl {
+    //! The minimal EGL and GLES2 bindings of the readback.
+
+    use qemu_display::ScanoutDMABUF;
+    use std::{
+        ffi::{c_void, CStr},
+        os::raw::{c_char, c_int, c_uint},
+        ptr,
+    };
+
+    type EGLDisplay = *mut c_void;
+    type EGLContext = *mut c_void;
+    type EGLImage = *mut c_void;
+    type EGLint = i32;
+    type GLuint = c_uint;
+    type GLenum = c_uint;
+
+    const EGL_NONE: EGLint = 0x3038;
+    const EGL_WIDTH: EGLint = 0x3057;
+    const EGL_HEIGHT: EGLint = 0x3056;
+    const EGL_CONTEXT_CLIENT_VERSION: EGLint = 0x3098;
+    const EGL_OPENGL_ES_API: c_uint = 0x30A0;
+    const EGL_PLATFORM_SURFACELESS_MESA: c_uint = 0x31DD;
+    const EGL_LINUX_DMA_BUF_EXT: c_uint = 0x3270;
+    const EGL_LINUX_DRM_FOURCC_EXT: EGLint = 0x3271;
+    const EGL_DMA_BUF_PLANE0_FD_EXT: EGLint = 0x3272;
+    const EGL_DMA_BUF_PLANE0_OFFSET_EXT: EGLint = 0x3273;
+    const EGL_DMA_BUF_PLANE0_PITCH_EXT: EGLint = 0x3274;
+    const EGL_DMA_BUF_PLANE0_MODIFIER_LO_EXT: EGLint = 0x3443;
+    const EGL_DMA_BUF_PLANE0_MODIFIER_HI_EXT: EGLint = 0x3444;
+    // the modifier is implicit, with the older drivers
+    const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+    const GL_TEXTURE_2D: GLenum = 0x0DE1;
+    const GL_FRAMEBUFFER: GLenum = 0x8D40;
+    const GL_COLOR_ATTACHMENT0: GLenum = 0x8CE0;
+    const GL_FRAMEBUFFER_COMPLETE: GLenum = 0x8CD5;
+    const GL_RGBA: GLenum = 0x1908;
+    const GL_UNSIGNED_BYTE: GLenum = 0x1401;
+    const GL_PACK_ALIGNMENT: GLenum = 0x0D05;
+
+    #[link(name = "EGL")]
+    extern "C" {
+        fn eglGetProcAddress(name: *const c_char) -> *mut c_void;
+        fn eglInitialize(dpy: EGLDisplay, major: *mut EGLint, minor: *mut EGLint) -> c_uint;
+        fn eglTerminate(dpy: EGLDisplay) -> c_uint;
+        fn eglBindAPI(api: c_uint) -> c_uint;
+        fn eglCreateContext(
+            dpy: EGLDisplay,
+            config: *mut c_void,
+            share: EGLContext,
+            attribs: *const EGLint,
+        ) -> EGLContext;
+        fn eglDestroyContext(dpy: EGLDisplay, ctx: EGLContext) -> c_uint;
+        fn eglMakeCurrent(
+            dpy: EGLDisplay,
+            draw: *mut c_void,
+            read: *mut c_void,
+            ctx: EGLContext,
+        ) -> c_uint;
+        fn eglGetError() -> EGLint;
+    }
+
+    #[link(name = "GLESv2")]
+    extern "C" {
+        fn glGenTextures(n: c_int, textures: *mut GLuint);
+        fn glDeleteTextures(n: c_int, textures: *const GLuint);
+        fn glBindTexture(target: GLenum, texture: GLuint);
+        fn glGenFramebuffers(n: c_int, fbs: *mut GLuint);
+        fn glDeleteFramebuffers(n: c_int, fbs: *const GLuint);
+        fn glBindFramebuffer(target: GLenum, fb: GLuint);
+        fn glFramebufferTexture2D(
+            target: GLenum,
+            attachment: GLenum,
+            textarget: GLenum,
+            texture: GLuint,
+            level: c_int,
+        );
+        fn glCheckFramebufferStatus(target: GLenum) -> GLenum;
+        fn glPixelStorei(pname: GLenum, param: c_int);
+        fn glReadPixels(
+            x: c_int,
+            y: c_int,
+            w: c_int,
+            h: c_int,
+            format: GLenum,
+            ty: GLenum,
+            data: *mut c_void,
+        );
+        fn glGetError() -> GLenum;
+    }
+
+    type GetPlatformDisplayFn = unsafe extern "C" fn(
+        platform: c_uint,
+        native: *mut c_void,
+        attribs: *const EGLint,
+    ) -> EGLDisplay;
+    type CreateImageFn = unsafe extern "C" fn(
+        dpy: EGLDisplay,
+        ctx: EGLContext,
+        target: c_uint,
+        buffer: *mut c_void,
+        attribs: *const EGLint,
+    ) -> EGLImage;
+    type DestroyImageFn = unsafe extern "C" fn(dpy: EGLDisplay, image: EGLImage) -> c_uint;
+    type ImageTargetTextureFn = unsafe extern "C" fn(target: GLenum, image: EGLImage);
+
+    fn proc_address<T>(name: &CStr) -> Result<T, String> {
+        let f = unsafe { eglGetProcAddress(name.as_ptr()) };
+        if f.is_null() {
+            return Err(format!("{:?} is not supported", name));
+        }
+        Ok(unsafe { std::mem::transmute_copy(&f) })
+    }
+
+    fn egl_error(what: &str) -> String {
+        format!("{} failed: EGL error 0x{:x}", what, unsafe {
+            eglGetError()
+        })
+    }
+
+    // the GL objects of the current DMABUF
+    struct Imported {
+        image: EGLImage,
+        texture: GLuint,
+        fb: GLuint,
+    }
+
+    /// A surfaceless EGL context, reading the DMABUFs.
+    pub struct EglReadback {
+        dpy: EGLDisplay,
+        ctx: EGLContext,
+        create_image: CreateImageFn,
+        destroy_image: DestroyImageFn,
+        image_target_texture: ImageTargetTextureFn,
+        imported: Option<Imported>,
+    }
+
+    // the context is only current during a read, under &mut self
+    unsafe impl Send for EglReadback {}
+    unsafe impl Sync for EglReadback {}
+
+    impl EglReadback {
+        pub fn new() -> Result<Self, String> {
+            let get_platform_display: GetPlatformDisplayFn =
+                proc_address(CStr::from_bytes_with_nul(b"eglGetPlatformDisplayEXT\0").unwrap())?;
+            let dpy = unsafe {
+                get_platform_display(EGL_PLATFORM_SURFACELESS_MESA, ptr::null_mut(), ptr::null())
+            };
+            if dpy.is_null() {
+                return Err(egl_error("eglGetPlatformDisplay"));
+            }
+            if unsafe { eglInitialize(dpy, ptr::null_mut(), ptr::null_mut()) } == 0 {
+                return Err(egl_error("eglInitialize"));
+            }
+            let attribs = [EGL_CONTEXT_CLIENT_VERSION, 2, EGL_NONE];
+            // EGL_KHR_no_config_context: no config is needed without surface
+            let ctx = unsafe {
+                eglBindAPI(EGL_OPENGL_ES_API);
+                eglCreateContext(dpy, ptr::null_mut(), ptr::null_mut(), attribs.as_ptr())
+            };
+            if ctx.is_null() {
+                let e = egl_error("eglCreateContext");
+                unsafe { eglTerminate(dpy) };
+                return Err(e);
+            }
+            let lookup = || -> Result<_, String> {
+                Ok((
+                    proc_address(CStr::from_bytes_with_nul(b"eglCreateImageKHR\0").unwrap())?,
+                    proc_address(CStr::from_bytes_with_nul(b"eglDestroyImageKHR\0").unwrap())?,
+                    proc_address(
+                        CStr::from_bytes_with_nul(b"glEGLImageTargetTexture2DOES\0").unwrap(),
+                    )?,
+                ))
+            };
+            let (create_image, destroy_image, image_target_texture) = match lookup() {
+                Ok(f) => f,
+                Err(e) => {
+                    unsafe {
+                        eglDestroyContext(dpy, ctx);
+                        eglTerminate(dpy);
+                    }
+                    return Err(e);
+                }
+            };
+            Ok(Self {
+                dpy,
+                ctx,
+                create_image,
+                destroy_image,
+                image_target_texture,
+                imported: None,
+            })
+        }
+
+        fn make_current(&self, current: bool) -> Result<(), String> {
+            let ctx = if current { self.ctx } else { ptr::null_mut() };
+            if unsafe { eglMakeCurrent(self.dpy, ptr::null_mut(), ptr::null_mut(), ctx) } == 0 {
+                return Err(egl_error("eglMakeCurrent"));
+            }
+            Ok(())
+        }
+
+        fn import(&self, scanout: &ScanoutDMABUF) -> Result<Imported, String> {
+            let mut attribs = vec![
+                EGL_WIDTH,
+                scanout.width as _,
+                EGL_HEIGHT,
+                scanout.height as _,
+                EGL_LINUX_DRM_FOURCC_EXT,
+                scanout.fourcc as _,
+                EGL_DMA_BUF_PLANE0_FD_EXT,
+                scanout.fd,
+                EGL_DMA_BUF_PLANE0_OFFSET_EXT,
+                0,
+                EGL_DMA_BUF_PLANE0_PITCH_EXT,
+                scanout.stride as _,
+            ];
+            if scanout.modifier != DRM_FORMAT_MOD_INVALID {
+                attribs.extend_from_slice(&[
+                    EGL_DMA_BUF_PLANE0_MODIFIER_LO_EXT,
+                    scanout.modifier as u32 as _,
+                    EGL_DMA_BUF_PLANE0_MODIFIER_HI_EXT,
+                    (scanout.modifier >> 32) as u32 as _,
+                ]);
+            }
+            attribs.push(EGL_NONE);
+            let image = unsafe {
+                (self.create_image)(
+                    self.dpy,
+                    ptr::null_mut(),
+                    EGL_LINUX_DMA_BUF_EXT,
+                    ptr::null_mut(),
+                    attribs.as_ptr(),
+                )
+            };
+            if image.is_null() {
+                return Err(egl_error("eglCreateImage"));
+            }
+            let (mut texture, mut fb) = (0, 0);
+            unsafe {
+                glGenTextures(1, &mut texture);
+                glBindTexture(GL_TEXTURE_2D, texture);
+                (self.image_target_texture)(GL_TEXTURE_2D, image);
+                glGenFramebuffers(1, &mut fb);
+                glBindFramebuffer(GL_FRAMEBUFFER, fb);
+                glFramebufferTexture2D(
+                    GL_FRAMEBUFFER,
+                    GL_COLOR_ATTACHMENT0,
+                    GL_TEXTURE_2D,
+                    texture,
+                    0,
+                );
+            }
+            let imported = Imported { image, texture, fb };
+            if unsafe { glCheckFramebufferStatus(GL_FRAMEBUFFER) } != GL_FRAMEBUFFER_COMPLETE {
+                self.delete(imported);
+                return Err("Incomplete framebuffer for the DMABUF".into());
+            }
+            Ok(imported)
+        }
+
+        // with the context current
+        fn delete(&self, imported: Imported) {
+            unsafe {
+                glDeleteFramebuffers(1, &imported.fb);
+                glDeleteTextures(1, &imported.texture);
+                (self.destroy_image)(self.dpy, imported.image);
+            }
+        }
+
+        /// Forget the current DMABUF, before the next scanout.
+        pub fn release(&mut self) {
+            if let Some(imported) = self.imported.take() {
+                if self.make_current(true).is_ok() {
+                    self.delete(imported);
+                    let _ = self.make_current(false);
+                }
+            }
+        }
+
+        /// Read a region of the DMABUF, in top-down rows of little-endian x8r8g8b8.
+        pub fn read(
+            &mut self,
+            scanout: &ScanoutDMABUF,
+            x: u32,
+            y: u32,
+            w: u32,
+            h: u32,
+        ) -> Result<Vec<u8>, String> {
+            self.make_current(true)?;
+            let res = self.read_current(scanout, x, y, w, h);
+            let _ = self.make_current(false);
+            res
+        }
+
+        fn read_current(
+            &mut self,
+            scanout: &ScanoutDMABUF,
+            x: u32,
+            y: u32,
+            w: u32,
+            h: u32,
+        ) -> Result<Vec<u8>, String> {
+            if self.imported.is_none() {
+                self.imported = Some(self.import(scanout)?);
+            }
+            let fb = self.imported.as_ref().unwrap().fb;
+            // the framebuffer rows are in the DMABUF memory order
+            let mem_y = if scanout.y0_top {
+                y
+            } else {
+                scanout.height - y - h
+            };
+            let row = w as usize * 4;
+            let mut data = vec![0u8; row * h as usize];
+            unsafe {
+                glBindFramebuffer(GL_FRAMEBUFFER, fb);
+                glPixelStorei(GL_PACK_ALIGNMENT, 1);
+                glReadPixels(
+                    x as _,
+                    mem_y as _,
+                    w as _,
+                    h as _,
+                    GL_RGBA,
+                    GL_UNSIGNED_BYTE,
+                    data.as_mut_ptr() as _,
+                );
+                let e = glGetError();
+                if e != 0 {
+                    return Err(format!("glReadPixels failed: GL error 0x{:x}", e));
+                }
+            }
+            if !scanout.y0_top {
+                let rows = h as usize;
+                for r in 0..rows / 2 {
+                    let (top, bottom) = data.split_at_mut((rows - 1 - r) * row);
+                    top[r * row..(r + 1) * row].swap_with_slice(&mut bottom[..row]);
+                }
+            }
+            for px in data.chunks_exact_mut(4) {
+                // RGBA to BGRX
+                px.swap(0, 2);
+                px[3] = 0xff;
+            }
+            Ok(data)
+        }
+    }
+
+    impl Drop for EglReadback {
+        fn drop(&mut self) {
+            self.release();
+            unsafe {
+                eglDestroyContext(self.dpy, self.ctx);
+                eglTerminate(self.dpy);
+            }
+        }
+    }
+}
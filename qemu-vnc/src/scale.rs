@@ -0,0 +1,277 @@
+use image::{
+    imageops::{self, FilterType},
+    Bgra, ImageBuffer,
+};
+use qemu_display::{Cursor, ScaleMode, ViewTransform};
+use std::ops::Deref;
+
+use crate::BgraImage;
+
+/// Server-side scaling: the client sees the guest display resized by a factor.
+///
+/// Positions are mapped from pixel center to pixel center, so that the pixel under the
+/// pointer is the same on both sides, and the mapping round-trips when upscaling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale {
+    factor: f64,
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self { factor: 1.0 }
+    }
+}
+
+impl Scale {
+    pub fn new(factor: f64) -> Option<Self> {
+        if factor.is_finite() && factor > 0.0 {
+            Some(Self { factor })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        (self.factor - 1.0).abs() < f64::EPSILON
+    }
+
+    /// The client length of a guest length, rounded to the nearest pixel, and never empty.
+    pub fn length(&self, len: u32) -> u32 {
+        ((len as f64 * self.factor).round() as u32).max(1)
+    }
+
+    /// The guest length of a client length, rounded to the nearest pixel, and never empty.
+    pub fn guest_length(&self, len: u32) -> u32 {
+        ((len as f64 / self.factor).round() as u32).max(1)
+    }
+
+    pub fn size(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        (self.length(width), self.length(height))
+    }
+
+    /// The mapping of a guest display of `size` to the client.
+    pub fn transform(&self, size: (u32, u32)) -> ViewTransform {
+        ViewTransform::new(size, self.size(size)).with_scale_mode(ScaleMode::Factor(self.factor))
+    }
+
+    /// Map a guest pixel position to the client.
+    pub fn client_pos(&self, pos: i32) -> i32 {
+        self.transform((1, 1)).to_view(pos, 0).0
+    }
+
+    /// Map a client pixel position to a guest display of `size`, clamped to the display.
+    pub fn guest_pos(&self, (x, y): (u32, u32), size: (u32, u32)) -> Option<(u32, u32)> {
+        self.transform(size)
+            .to_guest_clamped(x as f64 + 0.5, y as f64 + 0.5)
+    }
+
+    pub fn resize<C: Deref<Target = [u8]>>(&self, image: &ImageBuffer<Bgra<u8>, C>) -> BgraImage {
+        let (width, height) = self.size(image.dimensions());
+        imageops::resize(image, width, height, FilterType::Triangle)
+    }
+}
+
+/// A cursor shape resized for the client, with premultiplied alpha, and its hot-spot.
+#[derive(Debug, Clone)]
+pub struct ScaledCursor {
+    pub image: BgraImage,
+    pub hot_x: i32,
+    pub hot_y: i32,
+}
+
+impl ScaledCursor {
+    pub fn new(cursor: &Cursor, scale: Scale) -> Option<Self> {
+        if cursor.width <= 0 || cursor.height <= 0 {
+            return None;
+        }
+        // premultiplied, so that resizing doesn't bleed the color of transparent pixels
+        let image = BgraImage::from_raw(
+            cursor.width as _,
+            cursor.height as _,
+            cursor.premultiplied_data().into_owned(),
+        )?;
+        let image = if scale.is_identity() {
+            image
+        } else {
+            scale.resize(&image)
+        };
+        // the hot-spot is a pixel position, it must stay within the shape
+        let hot_x = scale
+            .client_pos(cursor.hot_x)
+            .clamp(0, image.width() as i32 - 1);
+        let hot_y = scale
+            .client_pos(cursor.hot_y)
+            .clamp(0, image.height() as i32 - 1);
+        Some(Self {
+            image,
+            hot_x,
+            hot_y,
+        })
+    }
+
+    /// The client area covered by the cursor, with the hot-spot at `(x, y)`.
+    pub fn rect(&self, (x, y): (i32, i32)) -> qemu_display::Rect {
+        let (left, top) = (x - self.hot_x, y - self.hot_y);
+        let (right, bottom) = (
+            left + self.image.width() as i32,
+            top + self.image.height() as i32,
+        );
+        let (left, top) = (left.max(0), top.max(0));
+        qemu_display::Rect::new(
+            left as u32,
+            top as u32,
+            (right - left).max(0) as u32,
+            (bottom - top).max(0) as u32,
+        )
+    }
+
+    /// Alpha-blend the cursor on the frame, with the hot-spot at the client position `(x, y)`.
+    pub fn composite(&self, frame: &mut BgraImage, (x, y): (i32, i32)) {
+        let (left, top) = (x - self.hot_x, y - self.hot_y);
+        for (cx, cy, src) in self.image.enumerate_pixels() {
+            let (fx, fy) = (left + cx as i32, top + cy as i32);
+            if fx < 0 || fy < 0 || fx >= frame.width() as i32 || fy >= frame.height() as i32 {
+                continue;
+            }
+            let dst = frame.get_pixel_mut(fx as _, fy as _);
+            let alpha = src[3] as u32;
+            for c in 0..3 {
+                let blended = src[c] as u32 + (dst[c] as u32 * (255 - alpha) + 127) / 255;
+                dst[c] = blended.min(255) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(width: i32, height: i32, hot_x: i32, hot_y: i32) -> Cursor {
+        Cursor {
+            width,
+            height,
+            hot_x,
+            hot_y,
+            data: vec![255; (width * height * 4) as usize],
+        }
+    }
+
+    #[test]
+    fn cursor_rect() {
+        let s = Scale::new(1.0).unwrap();
+        let c = ScaledCursor::new(&cursor(8, 8, 2, 3), s).unwrap();
+        assert_eq!(c.rect((10, 10)), qemu_display::Rect::new(8, 7, 8, 8));
+        assert_eq!(c.rect((0, 0)), qemu_display::Rect::new(0, 0, 6, 5));
+        assert!(c.rect((-20, 0)).is_empty());
+    }
+
+    #[test]
+    fn length_rounding() {
+        let s = Scale::new(1.5).unwrap();
+        assert_eq!(s.length(1), 2);
+        assert_eq!(s.length(3), 5);
+        assert_eq!(s.guest_length(5), 3);
+        let s = Scale::new(0.5).unwrap();
+        assert_eq!(s.length(3), 2);
+        assert_eq!(s.length(1), 1);
+        assert_eq!(s.size((1024, 768)), (512, 384));
+        let s = Scale::new(0.001).unwrap();
+        assert_eq!(s.length(10), 1);
+        assert!(Scale::new(0.0).is_none());
+        assert!(Scale::new(f64::NAN).is_none());
+    }
+
+    // the guest x of a client x
+    fn guest_x(s: Scale, pos: u32, guest_len: u32) -> u32 {
+        s.guest_pos((pos, 0), (guest_len, 1)).unwrap().0
+    }
+
+    #[test]
+    fn position_round_trip() {
+        for factor in &[1.0, 1.25, 1.5, 2.0, 3.0, 7.0 / 3.0] {
+            let s = Scale::new(*factor).unwrap();
+            let len = 1000;
+            for g in 0..len {
+                let c = s.client_pos(g as i32);
+                assert!(c >= 0 && (c as u32) < s.length(len));
+                assert_eq!(guest_x(s, c as u32, len), g, "factor {}", factor);
+            }
+        }
+    }
+
+    #[test]
+    fn position_downscale() {
+        let s = Scale::new(0.5).unwrap();
+        assert_eq!(guest_x(s, 0, 100), 1);
+        assert_eq!(guest_x(s, 1, 100), 3);
+        assert_eq!(s.client_pos(0), 0);
+        assert_eq!(s.client_pos(1), 0);
+        assert_eq!(s.client_pos(2), 1);
+        // out of range positions are clamped
+        assert_eq!(guest_x(s, 50, 100), 99);
+        assert_eq!(guest_x(s, 1000, 100), 99);
+        assert_eq!(s.guest_pos((1000, 10), (100, 50)), Some((99, 21)));
+
+        let s = Scale::new(2.0 / 3.0).unwrap();
+        let mut last = 0;
+        for c in 0..200 {
+            let g = guest_x(s, c, 300);
+            assert!(g >= last && g < 300);
+            last = g;
+        }
+    }
+
+    #[test]
+    fn cursor_hotspot() {
+        let c = ScaledCursor::new(&cursor(32, 32, 0, 0), Scale::new(2.0).unwrap()).unwrap();
+        assert_eq!(c.image.dimensions(), (64, 64));
+        assert_eq!((c.hot_x, c.hot_y), (1, 1));
+
+        let c = ScaledCursor::new(&cursor(32, 32, 31, 16), Scale::new(2.0).unwrap()).unwrap();
+        assert_eq!((c.hot_x, c.hot_y), (63, 33));
+
+        let c = ScaledCursor::new(&cursor(32, 32, 31, 5), Scale::new(0.5).unwrap()).unwrap();
+        assert_eq!(c.image.dimensions(), (16, 16));
+        assert_eq!((c.hot_x, c.hot_y), (15, 2));
+
+        let c = ScaledCursor::new(&cursor(3, 3, 2, 1), Scale::new(0.1).unwrap()).unwrap();
+        assert_eq!(c.image.dimensions(), (1, 1));
+        assert_eq!((c.hot_x, c.hot_y), (0, 0));
+
+        assert!(ScaledCursor::new(&cursor(0, 4, 0, 0), Scale::default()).is_none());
+    }
+
+    #[test]
+    fn cursor_straight_alpha() {
+        let mut cursor = cursor(1, 1, 0, 0);
+        cursor.data = vec![255, 255, 255, 128];
+        let c = ScaledCursor::new(&cursor, Scale::default()).unwrap();
+        assert_eq!(c.image.get_pixel(0, 0).0, [128, 128, 128, 128]);
+
+        let mut frame = BgraImage::from_pixel(1, 1, image::Bgra([255, 0, 0, 0]));
+        c.composite(&mut frame, (0, 0));
+        assert_eq!(frame.get_pixel(0, 0).0, [255, 128, 128, 0]);
+    }
+
+    #[test]
+    fn cursor_composite() {
+        let mut c = ScaledCursor::new(&cursor(2, 2, 1, 1), Scale::default()).unwrap();
+        c.image.get_pixel_mut(0, 0).0 = [128, 128, 128, 128];
+        let mut frame = BgraImage::new(4, 4);
+
+        // the hot-spot lands on the pointer
+        c.composite(&mut frame, (1, 1));
+        assert_eq!(frame.get_pixel(0, 0).0, [128, 128, 128, 0]);
+        assert_eq!(frame.get_pixel(1, 1).0, [255, 255, 255, 0]);
+        assert_eq!(frame.get_pixel(2, 2).0, [0, 0, 0, 0]);
+
+        // clipped at the edges
+        let mut frame = BgraImage::new(4, 4);
+        c.composite(&mut frame, (0, 0));
+        assert_eq!(frame.get_pixel(0, 0).0, [255, 255, 255, 0]);
+        c.composite(&mut frame, (4, 4));
+        assert_eq!(frame.get_pixel(3, 3).0, [128, 128, 128, 0]);
+        c.composite(&mut frame, (-10, 10));
+    }
+}
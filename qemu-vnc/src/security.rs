@@ -0,0 +1,190 @@
+use std::{
+    error::Error,
+    io::{self, prelude::*},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use qemu_display::{loopback_pair, relay_plain, relay_tls};
+
+use crate::{
+    auth::{Authenticator, Stream},
+    tls::TlsConfig,
+};
+
+const RFB_VERSION: &[u8; 12] = b"RFB 003.008\n";
+const SECURITY_NONE: u8 = 1;
+const SECURITY_VENCRYPT: u8 = 19;
+const VENCRYPT_X509_NONE: u32 = 260;
+// don't keep the negotiation thread of a stalled client forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// the clients may only ask the user for the password once challenged
+const AUTH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The RFB security negotiation, done before handing the client to the VNC server.
+#[derive(Debug, Default)]
+pub struct Security {
+    tls: Option<TlsConfig>,
+    auth: Option<Box<dyn Authenticator>>,
+}
+
+impl Security {
+    pub fn new(
+        tls: Option<TlsConfig>,
+        auth: Option<Box<dyn Authenticator>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if let (Some(_), Some(auth)) = (&tls, &auth) {
+            if auth.vencrypt_subtype().is_none() {
+                return Err(format!("{:?} can't be used with TLS", auth).into());
+            }
+        }
+        Ok(Self { tls, auth })
+    }
+
+    /// Whether the client can connect without any security.
+    pub fn is_none(&self) -> bool {
+        self.tls.is_none() && self.auth.is_none()
+    }
+
+    /// Negotiate security with the client, and return a plain stream carrying the rest of
+    /// the RFB session. This blocks until the client is authenticated, the listeners call it
+    /// on a thread per client.
+    ///
+    /// The returned stream is the local end of a loopback connection, relayed to the client.
+    /// Its peer replays the RFB handshake (with no security), so it can be handed to the VNC
+    /// server as if the client connected directly.
+    pub fn accept(&self, mut stream: TcpStream) -> Result<TcpStream, Box<dyn Error>> {
+        set_timeouts(&stream, Some(HANDSHAKE_TIMEOUT))?;
+        stream.write_all(RFB_VERSION)?;
+        let mut version = [0; 12];
+        stream.read_exact(&mut version)?;
+        if &version != RFB_VERSION {
+            return Err(format!(
+                "Unsupported client version: {:?}",
+                String::from_utf8_lossy(&version)
+            )
+            .into());
+        }
+
+        let sec_type = match (&self.tls, &self.auth) {
+            (Some(_), _) => SECURITY_VENCRYPT,
+            (None, Some(auth)) => auth.security_type(),
+            (None, None) => SECURITY_NONE,
+        };
+        stream.write_all(&[1, sec_type])?;
+        let mut sec = [0; 1];
+        stream.read_exact(&mut sec)?;
+        if sec[0] != sec_type {
+            return Err(format!("Unsupported security type: {}", sec[0]).into());
+        }
+
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => {
+                if let Some(auth) = &self.auth {
+                    set_timeouts(&stream, Some(AUTH_TIMEOUT))?;
+                    if !auth.authenticate(&mut stream)? {
+                        security_failure(&mut stream, "Authentication failed")?;
+                        return Err("Client authentication failed".into());
+                    }
+                }
+                set_timeouts(&stream, None)?;
+                let (local, mut relay) = loopback_pair()?;
+                thread::spawn(move || {
+                    if let Err(e) =
+                        relay_handshake(&mut relay).and_then(|_| relay_plain(stream, relay))
+                    {
+                        eprintln!("Relay error: {}", e);
+                    }
+                });
+                return Ok(local);
+            }
+        };
+
+        let subtype = match &self.auth {
+            // checked in new()
+            Some(auth) => auth.vencrypt_subtype().unwrap(),
+            None => VENCRYPT_X509_NONE,
+        };
+        vencrypt_negotiate(&mut stream, subtype)?;
+
+        let mut conn = tls.server_connection()?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        if let Some(auth) = &self.auth {
+            set_timeouts(&stream, Some(AUTH_TIMEOUT))?;
+            let mut tls_stream = rustls::Stream::new(&mut conn, &mut stream);
+            if !auth.authenticate(&mut tls_stream)? {
+                security_failure(&mut tls_stream, "Authentication failed")?;
+                return Err("Client authentication failed".into());
+            }
+        }
+
+        set_timeouts(&stream, None)?;
+        let (local, mut relay) = loopback_pair()?;
+        thread::spawn(move || {
+            if let Err(e) = relay_handshake(&mut relay).and_then(|_| relay_tls(conn, stream, relay))
+            {
+                eprintln!("TLS relay error: {}", e);
+            }
+        });
+        Ok(local)
+    }
+}
+
+fn vencrypt_negotiate(stream: &mut TcpStream, subtype: u32) -> Result<(), Box<dyn Error>> {
+    // VeNCrypt version 0.2
+    stream.write_all(&[0, 2])?;
+    let mut version = [0; 2];
+    stream.read_exact(&mut version)?;
+    if version != [0, 2] {
+        stream.write_all(&[1])?;
+        return Err(format!("Unsupported VeNCrypt version: {:?}", version).into());
+    }
+    stream.write_all(&[0])?;
+
+    stream.write_all(&[1])?;
+    stream.write_all(&subtype.to_be_bytes())?;
+    let mut client_subtype = [0; 4];
+    stream.read_exact(&mut client_subtype)?;
+    if u32::from_be_bytes(client_subtype) != subtype {
+        stream.write_all(&[0])?;
+        return Err(format!("Unsupported VeNCrypt subtype: {:?}", client_subtype).into());
+    }
+    stream.write_all(&[1])?;
+    Ok(())
+}
+
+fn set_timeouts(stream: &TcpStream, timeout: Option<Duration>) -> io::Result<()> {
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)
+}
+
+fn security_failure(stream: &mut dyn Stream, reason: &str) -> io::Result<()> {
+    stream.write_all(&1u32.to_be_bytes())?;
+    stream.write_all(&(reason.len() as u32).to_be_bytes())?;
+    stream.write_all(reason.as_bytes())?;
+    stream.flush()
+}
+
+// Play the client side of the handshake with the VNC server: the security
+// negotiation already happened with the real client.
+fn relay_handshake(relay: &mut TcpStream) -> io::Result<()> {
+    let mut version = [0; 12];
+    relay.read_exact(&mut version)?;
+    relay.write_all(RFB_VERSION)?;
+
+    let mut n = [0; 1];
+    relay.read_exact(&mut n)?;
+    let mut types = vec![0; n[0] as usize];
+    relay.read_exact(&mut types)?;
+    if !types.contains(&SECURITY_NONE) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "VNC server requires a security type",
+        ));
+    }
+    relay.write_all(&[SECURITY_NONE])
+}
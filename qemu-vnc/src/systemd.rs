@@ -0,0 +1,101 @@
+//! The socket activation of systemd, to start the server on demand (`sd_listen_fds`).
+//!
+//! The sockets of the `.socket` unit (with `Accept=no`) are passed already bound, from the
+//! file descriptor 3, with their number in `LISTEN_FDS`.
+
+use std::{
+    env,
+    error::Error,
+    io, mem,
+    net::TcpListener,
+    ops::Range,
+    os::unix::{io::FromRawFd, io::RawFd, net::UnixListener},
+    process,
+};
+
+use crate::listener::Listener;
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The sockets passed by systemd, none if the server wasn't socket-activated.
+pub fn listeners() -> Result<Vec<Listener>, Box<dyn Error>> {
+    let fds = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        process::id(),
+    )?;
+    // the children (ssh) must not take them for theirs
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    fds.map(listener).collect()
+}
+
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Result<Range<RawFd>, String> {
+    let none = LISTEN_FDS_START..LISTEN_FDS_START;
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(none),
+    };
+    let pid: u32 = pid
+        .parse()
+        .map_err(|e| format!("Invalid LISTEN_PID {:?}: {}", pid, e))?;
+    // meant for another process
+    if pid != own_pid {
+        return Ok(none);
+    }
+    let n: RawFd = fds
+        .parse()
+        .map_err(|e| format!("Invalid LISTEN_FDS {:?}: {}", fds, e))?;
+    Ok(LISTEN_FDS_START..LISTEN_FDS_START + n.max(0))
+}
+
+fn listener(fd: RawFd) -> Result<Listener, Box<dyn Error>> {
+    // SAFETY: the descriptors of LISTEN_FDS are owned by the process, and only taken once
+    unsafe {
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut ty: libc::c_int = 0;
+        let mut len = mem::size_of_val(&ty) as libc::socklen_t;
+        if libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut ty as *mut _ as *mut libc::c_void,
+            &mut len,
+        ) < 0
+        {
+            return Err(format!("fd {}: {}", fd, io::Error::last_os_error()).into());
+        }
+        if ty != libc::SOCK_STREAM {
+            return Err(format!("fd {}: not a stream socket", fd).into());
+        }
+
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(TcpListener::from_raw_fd(fd))),
+            libc::AF_UNIX => Ok(Listener::Unix(UnixListener::from_raw_fd(fd))),
+            family => Err(format!("fd {}: unsupported socket family {}", fd, family).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_fds_env() {
+        assert_eq!(listen_fds(None, None, 42), Ok(3..3));
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), Ok(3..5));
+        // inherited from the parent
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), Ok(3..3));
+        assert!(listen_fds(Some("42"), Some("x"), 42).is_err());
+    }
+}
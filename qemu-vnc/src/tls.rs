@@ -0,0 +1,65 @@
+use std::{error::Error, fs::File, io::BufReader, path::Path, sync::Arc};
+
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    ServerConnection,
+};
+
+/// TLS settings for the VeNCrypt security type.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path.display()).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(format!("No private key found in {}", path.display()).into()),
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Load the server certificate and key. When `ca` is given, clients must present a
+    /// certificate signed by it.
+    pub fn new(cert: &Path, key: &Path, ca: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = if let Some(ca) = ca {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca)? {
+                roots.add(&cert)?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = builder.with_single_cert(read_certs(cert)?, read_key(key)?)?;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn server_connection(&self) -> Result<ServerConnection, rustls::Error> {
+        ServerConnection::new(self.config.clone())
+    }
+}
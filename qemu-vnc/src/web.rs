@@ -0,0 +1,239 @@
+//! A minimal HTTP server for the browser console, next to the WebSocket endpoint.
+//!
+//! The page is generated, and loads the noVNC client from a local installation (the
+//! `novnc` package of the distributions), served from the same port.
+
+use std::{
+    error::Error,
+    fs,
+    io::prelude::*,
+    net::TcpStream,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::ws::Request;
+
+/// The noVNC installation of the distributions.
+pub const DEFAULT_ROOT: &str = "/usr/share/novnc";
+/// The WebSocket port of the browser console, by default.
+pub const DEFAULT_PORT: u16 = 6080;
+
+const INDEX: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+html, body { margin: 0; height: 100%; background: #282828; }
+#screen { height: 100%; }
+#audio { position: absolute; top: 8px; right: 8px; z-index: 1; }
+</style>
+<script type="module">
+import RFB from './core/rfb.js';
+
+const scheme = location.protocol === 'https:' ? 'wss://' : 'ws://';
+const rfb = new RFB(document.getElementById('screen'), scheme + location.host + '/');
+rfb.scaleViewport = true;
+rfb.addEventListener('credentialsrequired', () => {
+    rfb.sendCredentials({ password: prompt('Password') });
+});
+rfb.addEventListener('disconnect', () => {
+    document.title += ' (disconnected)';
+});
+
+// the guest audio, on a side WebSocket
+const button = document.getElementById('audio');
+let audio = null;
+button.hidden = !{audio};
+button.onclick = () => {
+    if (audio) {
+        audio.enabled = !audio.enabled;
+        audio.ws.send(audio.enabled ? 'enable' : 'disable');
+    } else {
+        audio = playAudio(scheme + location.host + '{audio_path}');
+    }
+    button.textContent = audio.enabled ? 'Mute' : 'Audio';
+};
+
+function playAudio(url) {
+    const ctx = new AudioContext();
+    const ws = new WebSocket(url);
+    const audio = { ws, enabled: true };
+    let format = null;
+    let time = 0;
+    ws.binaryType = 'arraybuffer';
+    ws.onopen = () => ws.send('enable');
+    ws.onmessage = (e) => {
+        if (typeof e.data === 'string') {
+            format = JSON.parse(e.data);
+            return;
+        }
+        if (!format) {
+            return;
+        }
+        const view = new DataView(e.data);
+        const size = format.bits / 8;
+        const frames = Math.floor(e.data.byteLength / (size * format.channels));
+        if (frames === 0) {
+            return;
+        }
+        const buffer = ctx.createBuffer(format.channels, frames, format.rate);
+        for (let c = 0; c < format.channels; c++) {
+            const samples = buffer.getChannelData(c);
+            for (let i = 0; i < frames; i++) {
+                samples[i] = sample(view, (i * format.channels + c) * size, format);
+            }
+        }
+        const source = ctx.createBufferSource();
+        source.buffer = buffer;
+        source.connect(ctx.destination);
+        // a small latency, to not starve between the messages
+        time = Math.max(time, ctx.currentTime + 0.05);
+        source.start(time);
+        time += buffer.duration;
+    };
+    ws.onclose = () => {
+        ctx.close();
+        audio = null;
+        button.textContent = 'Audio';
+    };
+    return audio;
+}
+
+function sample(view, offset, format) {
+    const le = !format.be;
+    if (format.float) {
+        return format.bits === 64 ? view.getFloat64(offset, le) : view.getFloat32(offset, le);
+    }
+    switch (format.bits) {
+    case 8:
+        return format.signed ? view.getInt8(offset) / 128 : (view.getUint8(offset) - 128) / 128;
+    case 16:
+        return format.signed ? view.getInt16(offset, le) / 32768
+            : (view.getUint16(offset, le) - 32768) / 32768;
+    default:
+        return format.signed ? view.getInt32(offset, le) / 2147483648
+            : (view.getUint32(offset, le) - 2147483648) / 2147483648;
+    }
+}
+</script>
+</head>
+<body><button id="audio">Audio</button><div id="screen"></div></body>
+</html>
+"#;
+
+/// The files of the browser console.
+#[derive(Debug, Clone)]
+pub struct WebRoot {
+    root: PathBuf,
+    title: String,
+}
+
+impl WebRoot {
+    /// Serve the noVNC client at `root`, in a page titled `title`.
+    pub fn new(root: PathBuf, title: &str) -> Result<Self, Box<dyn Error>> {
+        if !root.join("core/rfb.js").is_file() {
+            return Err(format!("noVNC not found in {}", root.display()).into());
+        }
+        Ok(Self {
+            root,
+            title: html_escape(title),
+        })
+    }
+
+    /// Answer a plain HTTP request, and close the connection.
+    ///
+    /// The page has an audio button when the guest audio is streamed to the client.
+    pub fn serve(
+        &self,
+        mut stream: TcpStream,
+        request: &Request,
+        audio: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if request.method != "GET" && request.method != "HEAD" {
+            return respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                "text/plain",
+                b"",
+                true,
+            );
+        }
+        let path = request.path.split('?').next().unwrap_or_default();
+        let head = request.method == "HEAD";
+        if path == "/" || path == "/index.html" {
+            let page = INDEX
+                .replace("{title}", &self.title)
+                .replace("{audio_path}", crate::audio::PATH)
+                .replace("{audio}", if audio { "true" } else { "false" });
+            return respond(&mut stream, "200 OK", "text/html", page.as_bytes(), head);
+        }
+        match self
+            .file(path)
+            .and_then(|p| fs::read(&p).ok().map(|d| (p, d)))
+        {
+            Some((path, data)) => respond(&mut stream, "200 OK", content_type(&path), &data, head),
+            None => respond(&mut stream, "404 Not Found", "text/plain", b"", head),
+        }
+    }
+
+    // the file of a request path, within the root
+    fn file(&self, path: &str) -> Option<PathBuf> {
+        let relative = Path::new(path.trim_start_matches('/'));
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+        Some(self.root.join(relative)).filter(|p| p.is_file())
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+    head: bool,
+) -> Result<(), Box<dyn Error>> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    if !head {
+        stream.write_all(body)?;
+    }
+    Ok(())
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+    {
+        "html" => "text/html",
+        "js" => "text/javascript",
+        "css" => "text/css",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ogg" => "audio/ogg",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,349 @@
+//! The WebSocket transport (RFC 6455), for the browser clients like noVNC.
+//!
+//! The RFB stream is carried in binary frames. Once the HTTP upgrade is done, the frames
+//! are relayed to a loopback connection, handed to the VNC server like a regular client.
+
+use std::{
+    error::Error,
+    io::{self, prelude::*},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use qemu_display::loopback_pair;
+
+use crate::{
+    audio::VncAudio,
+    policy::{Feature, Policy},
+    web::WebRoot,
+};
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// the largest request headers and client frame accepted
+const MAX_REQUEST: usize = 8192;
+const MAX_PAYLOAD: u64 = 1 << 24;
+// the requests are read on the listener thread: don't wait forever for a stalled client
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xa;
+
+/// An HTTP request, as far as the server is concerned.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Read the request line and headers.
+    pub fn read(stream: &mut TcpStream) -> Result<Self, Box<dyn Error>> {
+        // byte by byte, to not consume the data following the headers
+        let mut data = vec![];
+        let mut byte = [0; 1];
+        while !data.ends_with(b"\r\n\r\n") {
+            if data.len() >= MAX_REQUEST {
+                return Err("HTTP request too large".into());
+            }
+            stream.read_exact(&mut byte)?;
+            data.push(byte[0]);
+        }
+        let text = String::from_utf8(data)?;
+        let mut lines = text.split("\r\n");
+        let mut request = lines.next().unwrap_or_default().split(' ');
+        let (method, path) = match (request.next(), request.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return Err("Invalid HTTP request".into()),
+        };
+        let headers = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+            .collect();
+        Ok(Self {
+            method,
+            path,
+            headers,
+        })
+    }
+
+    /// The value of a header, by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    }
+
+    /// Whether this is a WebSocket upgrade request.
+    pub fn is_websocket(&self) -> bool {
+        self.has_token("connection", "upgrade") && self.has_token("upgrade", "websocket")
+    }
+
+    /// Whether the page of the request is allowed, against cross-site WebSocket hijacking.
+    ///
+    /// The browsers send the origin of the page: it must be the server itself, or one of
+    /// the `allowed` origins. The other clients, without origin, are accepted.
+    pub fn origin_allowed(&self, allowed: &[String]) -> bool {
+        let origin = match self.header("origin") {
+            Some(origin) => origin,
+            None => return true,
+        };
+        if allowed.iter().any(|a| a.eq_ignore_ascii_case(origin)) {
+            return true;
+        }
+        let host = origin
+            .strip_prefix("http://")
+            .or_else(|| origin.strip_prefix("https://"));
+        matches!((host, self.header("host")), (Some(o), Some(h)) if o.eq_ignore_ascii_case(h))
+    }
+}
+
+/// The Sec-WebSocket-Accept value for the client key.
+fn accept_key(key: &str) -> String {
+    let digest = sha1::Sha1::from(format!("{}{}", key, WS_GUID)).digest();
+    base64::encode(digest.bytes())
+}
+
+/// Read the upgrade request of a WebSocket client, and relay its frames.
+///
+/// The returned stream carries the RFB session. The other requests are answered on a
+/// thread, without RFB session: the audio stream from `audio` if the `policy` allows it, and
+/// the files from `web`. The upgrades from the pages of other origins than the server and
+/// `allowed_origins` are refused. Reading the request times out.
+pub fn accept(
+    mut stream: TcpStream,
+    web: Option<&WebRoot>,
+    audio: Option<&Arc<VncAudio>>,
+    policy: &Policy,
+    allowed_origins: &[String],
+) -> Result<Option<TcpStream>, Box<dyn Error>> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = Request::read(&mut stream)?;
+    // the streams and the relays wait for the client as long as needed
+    stream.set_read_timeout(None)?;
+    if request.is_websocket() && !request.origin_allowed(allowed_origins) {
+        stream.write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n")?;
+        return Err(format!(
+            "WebSocket origin not allowed: {}",
+            request.header("origin").unwrap_or_default()
+        )
+        .into());
+    }
+    let audio = audio.filter(|_| policy.allows(Feature::Audio));
+    if request.is_websocket() && request.path == crate::audio::PATH {
+        let audio = match audio {
+            Some(audio) => audio.clone(),
+            None => {
+                stream.write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n")?;
+                return Err("Audio is not enabled".into());
+            }
+        };
+        handshake(&mut stream, &request)?;
+        thread::spawn(move || {
+            if let Err(e) = audio.serve(stream) {
+                eprintln!("Audio stream error: {}", e);
+            }
+        });
+        return Ok(None);
+    }
+    if request.is_websocket() {
+        return upgrade(stream, &request).map(Some);
+    }
+    match web {
+        Some(web) => {
+            let web = web.clone();
+            let audio = audio.is_some();
+            thread::spawn(move || {
+                if let Err(e) = web.serve(stream, &request, audio) {
+                    eprintln!("HTTP error: {}", e);
+                }
+            });
+            Ok(None)
+        }
+        None => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")?;
+            Err("Not a WebSocket request".into())
+        }
+    }
+}
+
+// complete the upgrade of a WebSocket request
+fn handshake(stream: &mut TcpStream, request: &Request) -> Result<(), Box<dyn Error>> {
+    let key = request
+        .header("sec-websocket-key")
+        .ok_or("Missing Sec-WebSocket-Key")?;
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n",
+        accept_key(key)
+    );
+    // noVNC may ask for the binary subprotocol
+    if request.has_token("sec-websocket-protocol", "binary") {
+        response.push_str("Sec-WebSocket-Protocol: binary\r\n");
+    }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+// complete the upgrade of a WebSocket request, and relay its frames
+fn upgrade(mut stream: TcpStream, request: &Request) -> Result<TcpStream, Box<dyn Error>> {
+    handshake(&mut stream, request)?;
+    let (local, relay) = loopback_pair()?;
+    let ws = Arc::new(Mutex::new(stream.try_clone()?));
+    {
+        let ws = ws.clone();
+        let relay = relay.try_clone()?;
+        thread::spawn(move || {
+            if let Err(e) = relay_to_client(relay, ws) {
+                eprintln!("WebSocket relay error: {}", e);
+            }
+        });
+    }
+    thread::spawn(move || {
+        if let Err(e) = relay_from_client(stream, relay, ws) {
+            eprintln!("WebSocket relay error: {}", e);
+        }
+    });
+    Ok(local)
+}
+
+/// Send a frame to the client.
+pub fn write_frame(ws: &Mutex<TcpStream>, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    ws.lock().unwrap().write_all(&frame)
+}
+
+fn relay_to_client(mut relay: TcpStream, ws: Arc<Mutex<TcpStream>>) -> io::Result<()> {
+    let mut buf = vec![0; 65536];
+    loop {
+        let n = relay.read(&mut buf)?;
+        if n == 0 {
+            let _ = write_frame(&ws, OP_CLOSE, &1000u16.to_be_bytes());
+            let _ = ws.lock().unwrap().shutdown(Shutdown::Write);
+            return Ok(());
+        }
+        write_frame(&ws, OP_BINARY, &buf[..n])?;
+    }
+}
+
+/// Read a frame of the client, and return its opcode and unmasked payload.
+pub fn read_frame(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut header = [0; 2];
+    stream.read_exact(&mut header)?;
+    let opcode = header[0] & 0x0f;
+    if header[1] & 0x80 == 0 {
+        return Err(invalid("Unmasked client frame"));
+    }
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        n => n as u64,
+    };
+    if len > MAX_PAYLOAD {
+        return Err(invalid("Client frame too large"));
+    }
+    let mut mask = [0; 4];
+    stream.read_exact(&mut mask)?;
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+fn relay_from_client(
+    mut stream: TcpStream,
+    mut relay: TcpStream,
+    ws: Arc<Mutex<TcpStream>>,
+) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    loop {
+        let (opcode, payload) = read_frame(&mut stream)?;
+        match opcode {
+            OP_BINARY | OP_CONTINUATION => relay.write_all(&payload)?,
+            OP_TEXT => return Err(invalid("Text frames are not supported")),
+            OP_PING => write_frame(&ws, OP_PONG, &payload)?,
+            OP_PONG => {}
+            OP_CLOSE => {
+                let _ = write_frame(&ws, OP_CLOSE, &payload[..payload.len().min(2)]);
+                let _ = relay.shutdown(Shutdown::Write);
+                return Ok(());
+            }
+            _ => return Err(invalid("Unknown frame opcode")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_accept_key() {
+        // the example of RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn origins() {
+        let request = |origin: Option<&str>| Request {
+            method: "GET".into(),
+            path: "/".into(),
+            headers: origin
+                .map(|o| ("origin".to_string(), o.to_string()))
+                .into_iter()
+                .chain(Some(("host".into(), "vm.example:6080".into())))
+                .collect(),
+        };
+        let allowed = ["https://console.example".to_string()];
+        assert!(request(None).origin_allowed(&[]));
+        assert!(request(Some("http://vm.example:6080")).origin_allowed(&[]));
+        assert!(request(Some("https://VM.example:6080")).origin_allowed(&[]));
+        assert!(!request(Some("http://vm.example")).origin_allowed(&[]));
+        assert!(!request(Some("https://evil.example")).origin_allowed(&allowed));
+        assert!(!request(Some("null")).origin_allowed(&allowed));
+        assert!(request(Some("https://console.example")).origin_allowed(&allowed));
+    }
+}
@@ -1,16 +1,227 @@
-use futures::prelude::*;
+use futures::{channel::mpsc::UnboundedSender, prelude::*};
 use glib::{clone, MainContext};
 use gtk::{gio, glib};
-use qemu_display::Chardev;
-use std::os::unix::{io::AsRawFd, net::UnixStream};
+use qemu_display::{Chardev, Display, Error};
+use std::{cell::RefCell, rc::Rc, time::Duration};
 use vte::{gtk, prelude::*};
 use zbus::Connection;
 
+const TITLE: &str = "D-Bus serial example";
+// the columns and rows of the size menu
+const SIZE_PRESETS: [(i64, i64); 2] = [(80, 24), (132, 43)];
+// wait for the end of a window resize, before telling the guest
+const RESIZE_DELAY: Duration = Duration::from_millis(300);
+
+struct ChardevInfo {
+    chardev: Chardev,
+    name: String,
+    fe_opened: bool,
+    owner: String,
+}
+
+async fn list_chardevs(conn: &Connection) -> qemu_display::Result<Vec<ChardevInfo>> {
+    let display = Display::new(conn, Option::<String>::None).await?;
+    let mut list = vec![];
+    for chardev in display.chardevs().await {
+        let name = chardev.proxy.name().await?;
+        let fe_opened = chardev.proxy.fe_opened().await.unwrap_or_default();
+        let owner = chardev.proxy.owner().await.unwrap_or_default();
+        list.push(ChardevInfo {
+            chardev,
+            name,
+            fe_opened,
+            owner,
+        });
+    }
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(list)
+}
+
+fn show_error(window: &gtk::ApplicationWindow, text: &str) {
+    let dialog = gtk::MessageDialog::builder()
+        .transient_for(window)
+        .modal(true)
+        .message_type(gtk::MessageType::Error)
+        .buttons(gtk::ButtonsType::Close)
+        .text(text)
+        .build();
+    dialog.connect_response(|dialog, _| dialog.close());
+    dialog.show();
+}
+
+// Replace the window content with a terminal connected to the chardev.
+async fn open_terminal(window: &gtk::ApplicationWindow, chardev: &Chardev, name: &str) {
+    let conn = match chardev.connect().await {
+        Ok(conn) => conn,
+        Err(Error::InUse(owner)) => {
+            show_error(
+                window,
+                &format!("The chardev {} is already used by {}.", name, owner),
+            );
+            return;
+        }
+        Err(e) => {
+            show_error(
+                window,
+                &format!("Failed to connect to the chardev {}: {}", name, e),
+            );
+            return;
+        }
+    };
+    window.set_title(Some(&format!("{} - {}", TITLE, name)));
+    let term = vte::Terminal::new();
+    window.set_child(Some(&term));
+    term.grab_focus();
+
+    let (mut read, mut write) = conn.split();
+    let (sender, mut receiver) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+    add_size_actions(window, &term, sender.clone());
+    term.connect_commit(move |_, text, _| {
+        let _res = sender.unbounded_send(text.as_bytes().to_vec());
+    });
+    MainContext::default().spawn_local(async move {
+        while let Some(text) = receiver.next().await {
+            if let Err(e) = write.write_all(&text).await {
+                log::warn!("{}", e);
+                break;
+            }
+        }
+    });
+
+    loop {
+        let mut buffer = [0u8; 8192];
+        match read.read(&mut buffer[..]).await {
+            Ok(0) => break,
+            Ok(len) => {
+                term.feed(&buffer[..len]);
+            }
+            Err(e) => {
+                log::warn!("{}", e);
+                break;
+            }
+        }
+    }
+}
+
+// The window menu, with the size presets and the sync of the guest size.
+fn menu_button() -> gtk::MenuButton {
+    let sizes = gio::Menu::new();
+    for (columns, rows) in SIZE_PRESETS {
+        let item = gio::MenuItem::new(Some(&format!("{}×{}", columns, rows)), None);
+        item.set_action_and_target_value(Some("win.size"), Some(&(columns, rows).to_variant()));
+        sizes.append_item(&item);
+    }
+    let menu = gio::Menu::new();
+    menu.append_section(None, &sizes);
+    menu.append(Some("_Sync the guest size (stty)"), Some("win.sync-size"));
+    gtk::MenuButton::builder()
+        .icon_name("open-menu-symbolic")
+        .menu_model(&menu)
+        .build()
+}
+
+// A serial line has no window size: when enabled, the size is set by typing a stty
+// command, which works at a guest shell prompt.
+fn add_size_actions(
+    window: &gtk::ApplicationWindow,
+    term: &vte::Terminal,
+    sender: UnboundedSender<Vec<u8>>,
+) {
+    let size = gio::SimpleAction::new("size", Some(glib::VariantTy::new("(xx)").unwrap()));
+    size.connect_activate(clone!(@weak window, @weak term => move |_, size| {
+        if let Some((columns, rows)) = size.and_then(|s| s.get::<(i64, i64)>()) {
+            window.unmaximize();
+            term.set_size(columns, rows);
+            // shrink to the new terminal size
+            window.set_default_size(-1, -1);
+        }
+    }));
+    window.add_action(&size);
+
+    let sync = gio::SimpleAction::new_stateful("sync-size", None, &false.to_variant());
+    window.add_action(&sync);
+
+    let last = RefCell::new((0, 0));
+    let pending: Rc<RefCell<Option<glib::SourceId>>> = Default::default();
+    term.add_tick_callback(move |term, _| {
+        let size = (term.column_count(), term.row_count());
+        if *last.borrow() == size || sync.state().and_then(|s| s.get()) != Some(true) {
+            return glib::Continue(true);
+        }
+        last.replace(size);
+        if let Some(source) = pending.take() {
+            source.remove();
+        }
+        let sender = sender.clone();
+        let source = glib::timeout_add_local_once(
+            RESIZE_DELAY,
+            clone!(@strong pending => move || {
+                pending.take();
+                let (columns, rows) = size;
+                let stty = format!("stty rows {} cols {}\r", rows, columns);
+                let _res = sender.unbounded_send(stty.into_bytes());
+            }),
+        );
+        pending.replace(Some(source));
+        glib::Continue(true)
+    });
+}
+
+// The list of the chardevs, a terminal is opened for the activated one.
+fn chooser(window: &gtk::ApplicationWindow, chardevs: Vec<ChardevInfo>) -> gtk::Widget {
+    let list = gtk::ListBox::builder()
+        .selection_mode(gtk::SelectionMode::None)
+        .build();
+    for c in &chardevs {
+        let owner = if c.owner.is_empty() {
+            "free".to_string()
+        } else {
+            format!("used by {}", c.owner)
+        };
+        let opened = if c.fe_opened { "opened" } else { "closed" };
+        let title = gtk::Label::builder().label(&c.name).xalign(0.0).build();
+        title.add_css_class("heading");
+        let subtitle = gtk::Label::builder()
+            .label(&format!("{} by the guest, {}", opened, owner))
+            .xalign(0.0)
+            .build();
+        subtitle.add_css_class("dim-label");
+        let vbox = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(2)
+            .margin_top(6)
+            .margin_bottom(6)
+            .margin_start(12)
+            .margin_end(12)
+            .build();
+        vbox.append(&title);
+        vbox.append(&subtitle);
+        list.append(&vbox);
+    }
+    if chardevs.is_empty() {
+        list.set_placeholder(Some(&gtk::Label::new(Some("The VM has no chardev"))));
+    }
+
+    let chardevs = Rc::new(chardevs);
+    list.connect_row_activated(clone!(@weak window => move |_, row| {
+        let chardevs = chardevs.clone();
+        let i = row.index() as usize;
+        MainContext::default().spawn_local(clone!(@weak window => async move {
+            let c = &chardevs[i];
+            open_terminal(&window, &c.chardev, &c.name).await;
+        }));
+    }));
+
+    gtk::ScrolledWindow::builder()
+        .child(&list)
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .build()
+        .upcast()
+}
+
 fn main() {
     pretty_env_logger::init();
-    let chardev_id = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "serial0".to_string());
+    let chardev_id = std::env::args().nth(1);
 
     let app = gtk::Application::new(Some("org.qemu.vte-example"), Default::default());
     app.add_main_option(
@@ -18,49 +229,35 @@ fn main() {
         glib::Char(0),
         glib::OptionFlags::NONE,
         glib::OptionArg::StringArray,
-        "ID",
+        "ID, or choose it from the list",
         Some("chardev-name/id"),
     );
     app.connect_handle_local_options(|_, _| -1);
     app.connect_activate(move |app| {
         let window = gtk::ApplicationWindow::new(app);
-        window.set_title(Some("D-Bus serial example"));
-        let term = vte::Terminal::new();
-        window.set_child(Some(&term));
+        window.set_title(Some(TITLE));
+        window.set_default_size(640, 480);
+        let header = gtk::HeaderBar::new();
+        header.pack_end(&menu_button());
+        window.set_titlebar(Some(&header));
 
         let id = chardev_id.clone();
         MainContext::default().spawn_local(clone!(@strong window => async move {
             let conn = Connection::session().await
                 .expect("Failed to connect to session D-Bus");
 
-            let c = Chardev::new(&conn, &id).await.unwrap();
-            c.proxy.name().await.expect("Chardev not found");
-
-            let (p0, p1) = UnixStream::pair().unwrap();
-            if c.proxy.register(p1.as_raw_fd().into()).await.is_ok() {
-                let ostream = unsafe { gio::UnixOutputStream::with_fd(p0.as_raw_fd()) };
-                let istream = unsafe { gio::UnixInputStream::take_fd(p0) }
-                    .dynamic_cast::<gio::PollableInputStream>()
-                    .unwrap();
-
-                let mut read = istream.into_async_read().unwrap();
-                term.connect_commit(move |_, text, _| {
-                    let _res = ostream.write(text.as_bytes(), gio::Cancellable::NONE); // TODO cancellable and error
-                });
-
-                loop {
-                    let mut buffer = [0u8; 8192];
-                    match read.read(&mut buffer[..]).await {
-                        Ok(0) => break,
-                        Ok(len) => {
-                            term.feed(&buffer[..len]);
-                        }
-                        Err(e) => {
-                            log::warn!("{}", e);
-                            break;
-                        }
+            match id {
+                Some(id) => {
+                    let c = Chardev::new(&conn, &id).await.unwrap();
+                    match c.proxy.name().await {
+                        Ok(name) => open_terminal(&window, &c, &name).await,
+                        Err(_) => show_error(&window, &format!("Chardev {} not found", id)),
                     }
                 }
+                None => match list_chardevs(&conn).await {
+                    Ok(chardevs) => window.set_child(Some(&chooser(&window, chardevs))),
+                    Err(e) => show_error(&window, &format!("Failed to list the chardevs: {}", e)),
+                },
             }
         }));
 
@@ -0,0 +1,201 @@
+//! The guest audio playback, in G.711 µ-law for the audio track.
+//!
+//! The browsers decode PCMU without any library on our side: the samples are mixed to mono
+//! and resampled to 8 kHz, a phone quality that is enough for the guest sounds.
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+
+use qemu_display::{Audio, AudioOutHandler, PCMInfo, Volume};
+
+/// The sample rate of PCMU.
+pub const RATE: u32 = 8000;
+/// The samples of a packet, 20 ms.
+pub const PACKET_SAMPLES: usize = 160;
+// the packets queued for a peer, the older ones are dropped while it is slow
+const MAX_QUEUED: usize = 16;
+
+/// The guest audio output, shared by the peers.
+#[derive(Debug)]
+pub struct PcmuAudio {
+    // kept registered with QEMU
+    _audio: Audio,
+    sender: broadcast::Sender<Bytes>,
+}
+
+impl PcmuAudio {
+    /// Register the output listener of `audio`, replacing the one of another frontend.
+    pub async fn new(mut audio: Audio) -> qemu_display::Result<Self> {
+        let (sender, _) = broadcast::channel(MAX_QUEUED);
+        let handler = Handler {
+            streams: HashMap::new(),
+            sender: sender.clone(),
+        };
+        audio.register_out_listener(handler).await?;
+        Ok(Self {
+            _audio: audio,
+            sender,
+        })
+    }
+
+    /// The packets of [`PACKET_SAMPLES`] samples.
+    pub fn subscribe(&self) -> broadcast::Receiver<Bytes> {
+        self.sender.subscribe()
+    }
+}
+
+#[derive(Debug)]
+struct Handler {
+    streams: HashMap<u64, Encoder>,
+    sender: broadcast::Sender<Bytes>,
+}
+
+#[async_trait::async_trait]
+impl AudioOutHandler for Handler {
+    async fn init(&mut self, id: u64, info: PCMInfo) {
+        match Encoder::new(info.clone()) {
+            Some(encoder) => {
+                self.streams.insert(id, encoder);
+            }
+            None => eprintln!("Unsupported audio format: {:?}", info),
+        }
+    }
+
+    async fn fini(&mut self, id: u64) {
+        self.streams.remove(&id);
+    }
+
+    async fn set_enabled(&mut self, _id: u64, _enabled: bool) {}
+
+    async fn set_volume(&mut self, _id: u64, _volume: Volume) {}
+
+    async fn write(&mut self, id: u64, data: Vec<u8>) {
+        if let Some(encoder) = self.streams.get_mut(&id) {
+            for packet in encoder.encode(&data) {
+                // no peer is listening
+                let _ = self.sender.send(packet);
+            }
+        }
+    }
+}
+
+/// Converts the PCM of a stream to PCMU packets.
+#[derive(Debug)]
+struct Encoder {
+    info: PCMInfo,
+    // the resampling position, in 1/RATE of input frames
+    phase: u32,
+    sum: i64,
+    count: u32,
+    packet: Vec<u8>,
+}
+
+impl Encoder {
+    // the signed 16-bit and float formats, the ones of the QEMU audio backends
+    fn new(info: PCMInfo) -> Option<Self> {
+        let supported = match (info.bits, info.is_float) {
+            (16, false) => info.is_signed,
+            (32, true) => true,
+            _ => false,
+        };
+        if !supported || info.nchannels == 0 || info.freq == 0 {
+            return None;
+        }
+        Some(Self {
+            info,
+            phase: 0,
+            sum: 0,
+            count: 0,
+            packet: Vec::with_capacity(PACKET_SAMPLES),
+        })
+    }
+
+    fn sample(&self, bytes: &[u8]) -> i32 {
+        if self.info.is_float {
+            let v = if self.info.be {
+                f32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            } else {
+                f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            };
+            (v.clamp(-1.0, 1.0) * 32767.0) as i32
+        } else if self.info.be {
+            i16::from_be_bytes([bytes[0], bytes[1]]) as i32
+        } else {
+            i16::from_le_bytes([bytes[0], bytes[1]]) as i32
+        }
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<Bytes> {
+        let size = self.info.bits as usize / 8;
+        let channels = self.info.nchannels as usize;
+        let mut packets = vec![];
+        for frame in data.chunks_exact(size * channels) {
+            let mono: i32 = frame.chunks_exact(size).map(|s| self.sample(s)).sum();
+            self.sum += (mono / channels as i32) as i64;
+            self.count += 1;
+            self.phase += RATE;
+            // the average of the input frames of each output sample
+            while self.phase >= self.info.freq {
+                self.phase -= self.info.freq;
+                self.packet
+                    .push(ulaw((self.sum / self.count as i64) as i16));
+                if self.packet.len() == PACKET_SAMPLES {
+                    packets.push(Bytes::from(std::mem::take(&mut self.packet)));
+                }
+            }
+            if self.phase < RATE {
+                self.sum = 0;
+                self.count = 0;
+            }
+        }
+        packets
+    }
+}
+
+// G.711 µ-law
+fn ulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+
+    let (sign, magnitude) = if sample < 0 {
+        (0x80, -(sample as i32))
+    } else {
+        (0, sample as i32)
+    };
+    let magnitude = magnitude.min(CLIP) + BIAS;
+    let exponent = 31 - (magnitude >> 7).leading_zeros();
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0f;
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcmu() {
+        assert_eq!(ulaw(0), 0xff);
+        assert_eq!(ulaw(i16::MAX), 0x80);
+        assert_eq!(ulaw(i16::MIN), 0x00);
+        assert_eq!(ulaw(-1000), 0x4e);
+
+        let info = PCMInfo {
+            bits: 16,
+            is_signed: true,
+            is_float: false,
+            freq: 48000,
+            nchannels: 2,
+            bytes_per_frame: 4,
+            bytes_per_second: 192000,
+            be: false,
+        };
+        let mut encoder = Encoder::new(info).unwrap();
+        // 30 ms of silence
+        let packets = encoder.encode(&[0; 48 * 30 * 4]);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), PACKET_SAMPLES);
+        assert!(packets[0].iter().all(|&s| s == 0xff));
+        assert_eq!(encoder.packet.len(), 80);
+    }
+}
@@ -0,0 +1,96 @@
+//! The access units of an H.264 byte-stream, the samples of the video track.
+
+// the NAL unit types starting an access unit, before its first slice
+const NAL_SEI: u8 = 6;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+// the coded slices
+const NAL_SLICE: u8 = 1;
+const NAL_IDR: u8 = 5;
+
+/// Splits an Annex B byte-stream in access units, the frames.
+///
+/// The bitstream comes in chunks of any size: an access unit is complete once the header of
+/// the first NAL unit of the next one is received.
+#[derive(Debug, Default)]
+pub struct AccessUnits {
+    // from the start of the current access unit
+    buf: Vec<u8>,
+    // where to look for the next start code
+    scan: usize,
+    // the current access unit has a slice
+    has_slice: bool,
+}
+
+impl AccessUnits {
+    /// Add the next bytes of the stream, returning the completed access units, with their
+    /// start codes.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buf.extend_from_slice(data);
+        let mut units = vec![];
+        while let Some(nal) = start_code(&self.buf, self.scan) {
+            let mut header = if self.buf[nal + 2] == 1 {
+                nal + 3
+            } else {
+                nal + 4
+            };
+            let (kind, next) = match self.buf.get(header..header + 2) {
+                Some(h) => (h[0] & 0x1f, h[1]),
+                None => break,
+            };
+            let slice = kind == NAL_SLICE || kind == NAL_IDR;
+            // first_mb_in_slice is 0, its Exp-Golomb code is a single 1 bit
+            let first_slice = slice && next & 0x80 != 0;
+            if (first_slice || matches!(kind, NAL_SEI | NAL_SPS | NAL_PPS | NAL_AUD))
+                && self.has_slice
+            {
+                units.push(self.buf.drain(..nal).collect());
+                self.has_slice = false;
+                header -= nal;
+            }
+            self.has_slice |= slice;
+            self.scan = header + 2;
+        }
+        units
+    }
+}
+
+// the position of the next 3 or 4-byte start code
+fn start_code(buf: &[u8], from: usize) -> Option<usize> {
+    let pos = from + buf.get(from..)?.windows(3).position(|w| w == [0, 0, 1])?;
+    Some(if pos > from && buf[pos - 1] == 0 {
+        pos - 1
+    } else {
+        pos
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_units() {
+        let aud = [0, 0, 0, 1, 0x09, 0xf0];
+        let sps = [0, 0, 0, 1, 0x67, 0x42];
+        let idr = [0, 0, 1, 0x65, 0x88, 0x84];
+        let slice = [0, 0, 1, 0x41, 0x9a, 0x02];
+        // a second slice of the frame, first_mb_in_slice isn't 0
+        let slice2 = [0, 0, 1, 0x41, 0x40, 0x02];
+
+        let mut units = AccessUnits::default();
+        let first = [&aud[..], &sps, &idr].concat();
+        assert!(units.push(&first).is_empty());
+        // split in the middle of a start code
+        assert!(units.push(&aud[..2]).is_empty());
+        assert_eq!(units.push(&aud[2..]), vec![first]);
+        assert!(units.push(&slice).is_empty());
+        assert!(units.push(&slice2).is_empty());
+        // without delimiter
+        assert_eq!(
+            units.push(&slice),
+            vec![[&aud[..], &slice, &slice2].concat()]
+        );
+    }
+}
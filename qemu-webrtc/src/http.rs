@@ -0,0 +1,271 @@
+//! A minimal HTTP server, for the page and the signaling.
+//!
+//! The page posts its offer, with all its ICE candidates, to [`OFFER_PATH`] and gets the
+//! answer of the gateway, with all its candidates: there is no trickle ICE, and no
+//! connection kept for the signaling.
+//!
+//! With a token, the offers must be authorized with it: the page is opened with the token
+//! in the fragment of its URL (`/#token=...`), which isn't sent to the server, and posts
+//! it in the `Authorization` header of its offer.
+
+use std::error::Error;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+/// The path of the signaling endpoint.
+pub const OFFER_PATH: &str = "/offer";
+
+// the largest request headers and body
+const MAX_HEADERS: usize = 16 * 1024;
+const MAX_BODY: usize = 256 * 1024;
+
+const INDEX: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+html, body { margin: 0; height: 100%; background: #282828; overflow: hidden; }
+video { width: 100%; height: 100%; object-fit: contain; outline: none; }
+</style>
+</head>
+<body>
+<video id="screen" autoplay playsinline muted tabindex="0"></video>
+<script>
+const video = document.getElementById('screen');
+const token = new URLSearchParams(location.hash.slice(1)).get('token');
+const pc = new RTCPeerConnection({ iceServers: {ice_servers} });
+const input = pc.createDataChannel('input');
+const send = (event) => {
+    if (input.readyState === 'open') {
+        input.send(JSON.stringify(event));
+    }
+};
+
+pc.addTransceiver('video', { direction: 'recvonly' });
+pc.addTransceiver('audio', { direction: 'recvonly' });
+pc.ontrack = (e) => { video.srcObject = e.streams[0]; };
+pc.onconnectionstatechange = () => {
+    if (pc.connectionState === 'failed' || pc.connectionState === 'closed') {
+        document.title += ' (disconnected)';
+    }
+};
+
+async function connect() {
+    await pc.setLocalDescription(await pc.createOffer());
+    await new Promise((resolve) => {
+        if (pc.iceGatheringState === 'complete') {
+            return resolve();
+        }
+        pc.onicegatheringstatechange = () => {
+            if (pc.iceGatheringState === 'complete') {
+                resolve();
+            }
+        };
+    });
+    const headers = { 'Content-Type': 'application/json' };
+    if (token) {
+        headers['Authorization'] = 'Bearer ' + token;
+    }
+    const res = await fetch('{offer_path}', {
+        method: 'POST',
+        headers,
+        body: JSON.stringify(pc.localDescription),
+    });
+    if (!res.ok) {
+        throw new Error(await res.text());
+    }
+    await pc.setRemoteDescription(await res.json());
+}
+connect().catch((e) => { document.title += ' (' + e.message + ')'; });
+
+// the guest position of a pointer event, in the letterboxed video
+function position(e) {
+    const rect = video.getBoundingClientRect();
+    const scale = Math.min(rect.width / video.videoWidth, rect.height / video.videoHeight);
+    const x = (e.clientX - rect.left - (rect.width - video.videoWidth * scale) / 2) / scale;
+    const y = (e.clientY - rect.top - (rect.height - video.videoHeight * scale) / 2) / scale;
+    return {
+        x: Math.round(Math.min(Math.max(x, 0), video.videoWidth - 1)),
+        y: Math.round(Math.min(Math.max(y, 0), video.videoHeight - 1)),
+    };
+}
+
+video.addEventListener('mousemove', (e) => {
+    if (video.videoWidth) {
+        send({ type: 'move', ...position(e) });
+    }
+});
+video.addEventListener('mousedown', (e) => {
+    // the audio can play once the user interacted
+    video.muted = false;
+    video.focus();
+    send({ type: 'button', button: e.button, down: true });
+    e.preventDefault();
+});
+video.addEventListener('mouseup', (e) => {
+    send({ type: 'button', button: e.button, down: false });
+    e.preventDefault();
+});
+video.addEventListener('wheel', (e) => {
+    send({ type: 'wheel', dx: Math.sign(e.deltaX), dy: Math.sign(e.deltaY) });
+    e.preventDefault();
+}, { passive: false });
+video.addEventListener('contextmenu', (e) => e.preventDefault());
+video.addEventListener('keydown', (e) => {
+    send({ type: 'key', code: e.keyCode, down: true });
+    e.preventDefault();
+});
+video.addEventListener('keyup', (e) => {
+    send({ type: 'key', code: e.keyCode, down: false });
+    e.preventDefault();
+});
+</script>
+</body>
+</html>
+"#;
+
+/// An HTTP request, with its body.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub authorization: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Read a request, the connection is not reused.
+    pub async fn read(stream: &mut TcpStream) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let mut request = line.split_whitespace();
+        let (method, path) = match (request.next(), request.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return Err("Invalid HTTP request".into()),
+        };
+
+        let mut len = 0;
+        let mut authorization = None;
+        let mut size = line.len();
+        loop {
+            line.clear();
+            size += reader.read_line(&mut line).await?;
+            if size > MAX_HEADERS {
+                return Err("HTTP request too large".into());
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                let name = name.trim();
+                if name.eq_ignore_ascii_case("content-length") {
+                    len = value.trim().parse()?;
+                } else if name.eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_string());
+                }
+            }
+        }
+        if len > MAX_BODY {
+            return Err("HTTP request too large".into());
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).await?;
+        Ok(Self {
+            method,
+            path,
+            authorization,
+            body,
+        })
+    }
+
+    /// Whether the request is authorized with the bearer `token`.
+    pub fn authorized(&self, token: &str) -> bool {
+        let bearer = self
+            .authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "));
+        matches!(bearer, Some(bearer) if same_token(bearer.trim(), token))
+    }
+}
+
+// compare all the bytes, to not tell how much of the token is right
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Send a response, and close the connection.
+pub async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    let headers = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(headers.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+/// The page of the console, titled `title`, with the ICE servers of the gateway.
+pub fn index(title: &str, ice_servers: &[String]) -> String {
+    let urls: Vec<_> = ice_servers
+        .iter()
+        .map(|url| serde_json::json!({ "urls": url }))
+        .collect();
+    INDEX
+        .replace("{title}", &html_escape(title))
+        .replace("{ice_servers}", &serde_json::Value::from(urls).to_string())
+        .replace("{offer_path}", OFFER_PATH)
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page() {
+        let page = index("<vm>", &["stun:stun.example.com".into()]);
+        assert!(page.contains("<title>&lt;vm&gt;</title>"));
+        assert!(page.contains(r#"iceServers: [{"urls":"stun:stun.example.com"}]"#));
+        assert!(page.contains("fetch('/offer'"));
+    }
+
+    #[test]
+    fn authorized() {
+        let mut request = Request {
+            method: "POST".into(),
+            path: OFFER_PATH.into(),
+            authorization: None,
+            body: vec![],
+        };
+        assert!(!request.authorized("secret"));
+        request.authorization = Some("Bearer secret".into());
+        assert!(request.authorized("secret"));
+        assert!(!request.authorized("secret2"));
+        request.authorization = Some("Basic secret".into());
+        assert!(!request.authorized("secret"));
+    }
+}
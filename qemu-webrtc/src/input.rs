@@ -0,0 +1,112 @@
+//! The input events of the data channel.
+//!
+//! The page sends JSON messages, with the DOM key codes (the Windows virtual-key codes) and
+//! mouse buttons. The pointer positions are in the guest coordinates.
+
+use keycodemap::Keymap;
+use qemu_display::{KeyboardProxy, MouseButton, MouseProxy};
+use serde::Deserialize;
+
+/// The label of the input data channel.
+pub const CHANNEL: &str = "input";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum InputEvent {
+    Key { code: u32, down: bool },
+    Move { x: u32, y: u32 },
+    Button { button: u8, down: bool },
+    Wheel { dx: i32, dy: i32 },
+}
+
+// the MouseEvent.button values
+fn button(button: u8) -> Option<MouseButton> {
+    Some(match button {
+        0 => MouseButton::Left,
+        1 => MouseButton::Middle,
+        2 => MouseButton::Right,
+        3 => MouseButton::Side,
+        4 => MouseButton::Extra,
+        _ => return None,
+    })
+}
+
+/// Sends the input events of a peer to the console.
+#[derive(Debug, Clone)]
+pub struct Input {
+    keyboard: KeyboardProxy<'static>,
+    mouse: MouseProxy<'static>,
+}
+
+impl Input {
+    pub fn new(keyboard: KeyboardProxy<'static>, mouse: MouseProxy<'static>) -> Self {
+        Self { keyboard, mouse }
+    }
+
+    pub async fn handle(
+        &self,
+        message: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match serde_json::from_slice(message)? {
+            InputEvent::Key { code, down } => {
+                if let Some(qnum) = Keymap::Win32.qnum(code) {
+                    if down {
+                        self.keyboard.press(qnum).await?;
+                    } else {
+                        self.keyboard.release(qnum).await?;
+                    }
+                }
+            }
+            InputEvent::Move { x, y } => self.mouse.set_abs_position(x, y).await?,
+            InputEvent::Button { button: b, down } => {
+                if let Some(b) = button(b) {
+                    if down {
+                        self.mouse.press(b).await?;
+                    } else {
+                        self.mouse.release(b).await?;
+                    }
+                }
+            }
+            InputEvent::Wheel { dx, dy } => {
+                let buttons = [
+                    (dy < 0, MouseButton::WheelUp),
+                    (dy > 0, MouseButton::WheelDown),
+                    (dx < 0, MouseButton::WheelLeft),
+                    (dx > 0, MouseButton::WheelRight),
+                ];
+                for (_, b) in buttons.iter().filter(|(scroll, _)| *scroll) {
+                    self.mouse.press(*b).await?;
+                    self.mouse.release(*b).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events() {
+        let event = |json: &str| serde_json::from_str::<InputEvent>(json).unwrap();
+        assert_eq!(
+            event(r#"{"type":"key","code":65,"down":true}"#),
+            InputEvent::Key {
+                code: 65,
+                down: true
+            }
+        );
+        assert_eq!(
+            event(r#"{"type":"move","x":10,"y":20}"#),
+            InputEvent::Move { x: 10, y: 20 }
+        );
+        assert_eq!(
+            event(r#"{"type":"wheel","dx":0,"dy":-1}"#),
+            InputEvent::Wheel { dx: 0, dy: -1 }
+        );
+        assert!(serde_json::from_str::<InputEvent>(r#"{"type":"touch"}"#).is_err());
+        assert_eq!(Keymap::Win32.qnum(65), Some(0x1e));
+    }
+}
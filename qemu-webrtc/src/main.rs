@@ -0,0 +1,248 @@
+use std::{borrow::Borrow, path::PathBuf, sync::Arc};
+
+use clap::Parser;
+use qemu_display::{Display, EncoderConfig, SshTunnel, VMProxy, VideoCodec};
+use tokio::net::{TcpListener, TcpStream};
+
+mod audio;
+mod h264;
+mod http;
+mod input;
+mod peer;
+
+use audio::PcmuAudio;
+use http::{respond, Request};
+use peer::{BoxError, Gateway};
+
+/// Serve a console to the browsers with WebRTC: H.264 video, G.711 audio, and the keyboard
+/// and mouse on a data channel.
+#[derive(Parser, Debug)]
+struct Cli {
+    /// IP address of the HTTP server (the page and the signaling), on loopback unless the
+    /// offers are authorized with --token-file
+    #[clap(short, long, default_value = "127.0.0.1")]
+    address: std::net::IpAddr,
+    /// IP port number of the HTTP server
+    #[clap(short, long, default_value = "8080")]
+    port: u16,
+    #[clap(short, long)]
+    dbus_address: Option<String>,
+    /// Connect to the session bus of a remote host ([user@]host), through ssh
+    #[clap(long, conflicts_with = "dbus-address")]
+    ssh: Option<String>,
+    /// VM name
+    #[clap(long)]
+    vm_name: Option<String>,
+    /// Wait for the VM to be available
+    #[clap(short, long)]
+    wait: bool,
+    /// Console index
+    #[clap(short, long, default_value = "0")]
+    console: u32,
+    /// STUN or TURN server URL, for the peers behind a NAT
+    #[clap(long, value_name = "URL")]
+    ice_server: Vec<String>,
+    /// Video frames per second
+    #[clap(long, default_value = "30")]
+    framerate: u32,
+    /// Video bitrate, in kbit/s
+    #[clap(long, default_value = "2000")]
+    bitrate: u32,
+    /// GStreamer H.264 encoder, in gst-launch syntax, instead of x264enc
+    #[clap(long)]
+    encoder: Option<String>,
+    /// Don't stream the guest audio
+    #[clap(long)]
+    no_audio: bool,
+    /// File containing the token authorizing the offers, the page is opened with it in
+    /// its URL: http://host:port/#token=...
+    #[clap(long)]
+    token_file: Option<PathBuf>,
+    /// The maximum number of peers
+    #[clap(long, default_value = "4")]
+    max_peers: usize,
+}
+
+impl Cli {
+    fn encoder(&self) -> EncoderConfig {
+        // the browsers decode the constrained baseline profile
+        let encoder = self.encoder.clone().unwrap_or_else(|| {
+            format!(
+                "x264enc tune=zerolatency speed-preset=ultrafast bitrate={} key-int-max={} \
+                 ! video/x-h264,profile=constrained-baseline",
+                self.bitrate,
+                self.framerate * 2
+            )
+        });
+        EncoderConfig {
+            codec: VideoCodec::H264,
+            framerate: self.framerate,
+            bitrate: self.bitrate,
+            encoder: Some(encoder),
+        }
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    gateway: &Gateway,
+    page: &str,
+    token: Option<&str>,
+) -> Result<(), BoxError> {
+    let request = Request::read(&mut stream).await?;
+    let path = request.path.split('?').next().unwrap_or_default();
+    match (request.method.as_str(), path) {
+        ("GET", "/") | ("GET", "/index.html") => {
+            respond(
+                &mut stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                page.as_bytes(),
+            )
+            .await?
+        }
+        ("POST", http::OFFER_PATH) => {
+            if !token.map_or(true, |token| request.authorized(token)) {
+                respond(
+                    &mut stream,
+                    "401 Unauthorized",
+                    "text/plain",
+                    b"Unauthorized",
+                )
+                .await?;
+                return Ok(());
+            }
+            let slot = match gateway.reserve() {
+                Some(slot) => slot,
+                None => {
+                    let body = b"Too many peers";
+                    respond(&mut stream, "503 Service Unavailable", "text/plain", body).await?;
+                    return Ok(());
+                }
+            };
+            let answer = match serde_json::from_slice(&request.body) {
+                Ok(offer) => gateway.answer(offer, slot).await,
+                Err(e) => Err(e.into()),
+            };
+            match answer {
+                Ok(answer) => {
+                    let body = serde_json::to_vec(&answer)?;
+                    respond(&mut stream, "200 OK", "application/json", &body).await?
+                }
+                Err(e) => {
+                    eprintln!("Failed to answer an offer: {}", e);
+                    let body = e.to_string();
+                    respond(
+                        &mut stream,
+                        "400 Bad Request",
+                        "text/plain",
+                        body.as_bytes(),
+                    )
+                    .await?
+                }
+            }
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"Not found").await?,
+    }
+    Ok(())
+}
+
+async fn run() -> Result<(), BoxError> {
+    let args = Cli::parse();
+    if args.framerate == 0 {
+        return Err("Invalid framerate 0".into());
+    }
+    let token: Option<Arc<str>> = match &args.token_file {
+        Some(path) => {
+            let token = std::fs::read_to_string(path)?;
+            let token = token.lines().next().unwrap_or_default().trim();
+            if token.is_empty() {
+                return Err(format!("No token in {}", path.display()).into());
+            }
+            Some(token.into())
+        }
+        None => None,
+    };
+    if token.is_none() && !args.address.is_loopback() {
+        return Err(format!(
+            "The offers aren't authorized, refusing to listen on {}: use a loopback address \
+             and tunnel the remote browsers (ex: with ssh -L), or --token-file",
+            args.address
+        )
+        .into());
+    }
+    let tunnel = match &args.ssh {
+        Some(destination) => Some(SshTunnel::session_bus(destination).await?),
+        None => None,
+    };
+    let dbus_address = tunnel
+        .as_ref()
+        .map(SshTunnel::address)
+        .or_else(|| args.dbus_address.clone());
+    let conn = if let Some(addr) = &dbus_address {
+        zbus::ConnectionBuilder::address(addr.borrow())?
+            .build()
+            .await
+    } else {
+        zbus::Connection::session().await
+    }?;
+
+    let dest = Display::lookup(&conn, args.wait, args.vm_name.as_deref())
+        .await?
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| "org.qemu".into());
+    let vm_name = VMProxy::builder(&conn)
+        .destination(dest.as_str())?
+        .build()
+        .await?
+        .name()
+        .await?;
+    let display = Display::new(&conn, Some(dest)).await?;
+    let audio = if args.no_audio {
+        None
+    } else {
+        match display.audio().await {
+            Ok(Some(audio)) => match PcmuAudio::new(audio).await {
+                Ok(audio) => Some(audio),
+                Err(e) => {
+                    eprintln!("Failed to register the audio listener: {}", e);
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Failed to get the audio: {}", e);
+                None
+            }
+        }
+    };
+    let gateway = Gateway::new(
+        display.connection().clone(),
+        args.console,
+        args.encoder(),
+        args.ice_server.clone(),
+        audio,
+        args.max_peers,
+    )?;
+    let page = http::index(&vm_name, &args.ice_server);
+
+    let listener = TcpListener::bind((args.address, args.port)).await?;
+    println!("Serving {} on http://{}", vm_name, listener.local_addr()?);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (gateway, page, token) = (gateway.clone(), page.clone(), token.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, &gateway, &page, token.as_deref()).await {
+                eprintln!("{}", e);
+            }
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,316 @@
+//! The peer connections of the browsers.
+
+use futures_util::StreamExt;
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tokio::sync::{broadcast, watch, OwnedSemaphorePermit, Semaphore};
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors,
+        media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_PCMU},
+        APIBuilder, API,
+    },
+    data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel},
+    ice_transport::ice_server::RTCIceServer,
+    interceptor::registry::Registry,
+    media::Sample,
+    peer_connection::{
+        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+    rtp_transceiver::{rtp_codec::RTCRtpCodecCapability, rtp_sender::RTCRtpSender},
+    track::track_local::{track_local_static_sample::TrackLocalStaticSample, TrackLocal},
+};
+
+use qemu_display::{Console, EncodedEvent, EncoderConfig};
+
+use crate::{
+    audio::{self, PcmuAudio},
+    h264::AccessUnits,
+    input::{self, Input},
+};
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+// the media stream of the tracks, played by the same element
+const STREAM_ID: &str = "qemu";
+
+// the delay for an answered peer to connect, before its slot is released
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answers the offers of the browsers, with the tracks of a console.
+#[derive(Clone)]
+pub struct Gateway {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    api: API,
+    config: RTCConfiguration,
+    conn: zbus::Connection,
+    console: u32,
+    encoder: EncoderConfig,
+    audio: Option<PcmuAudio>,
+    // a permit per peer, until its connection is closed
+    peers: Arc<Semaphore>,
+}
+
+/// The reservation of a peer, see [`Gateway::reserve`].
+pub struct Slot(OwnedSemaphorePermit);
+
+// the connection of a peer and its slot, until it is closed
+type Peer = Mutex<Option<(Arc<RTCPeerConnection>, Slot)>>;
+
+impl Gateway {
+    pub fn new(
+        conn: zbus::Connection,
+        console: u32,
+        encoder: EncoderConfig,
+        ice_servers: Vec<String>,
+        audio: Option<PcmuAudio>,
+        max_peers: usize,
+    ) -> Result<Self, BoxError> {
+        let mut media = MediaEngine::default();
+        media.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media)?;
+        let api = APIBuilder::new()
+            .with_media_engine(media)
+            .with_interceptor_registry(registry)
+            .build();
+        let mut config = RTCConfiguration::default();
+        if !ice_servers.is_empty() {
+            config.ice_servers = vec![RTCIceServer {
+                urls: ice_servers,
+                ..Default::default()
+            }];
+        }
+        Ok(Self {
+            inner: Arc::new(Inner {
+                api,
+                config,
+                conn,
+                console,
+                encoder,
+                audio,
+                peers: Arc::new(Semaphore::new(max_peers)),
+            }),
+        })
+    }
+
+    /// Reserve a peer, unless there are already as many peers as allowed.
+    pub fn reserve(&self) -> Option<Slot> {
+        self.inner.peers.clone().try_acquire_owned().ok().map(Slot)
+    }
+
+    /// Create the peer connection of an offer, with all the ICE candidates of the answer.
+    ///
+    /// The video is encoded for each peer, once connected, starting with a key frame. The
+    /// `slot` is released when the connection is closed, or fails.
+    pub async fn answer(
+        &self,
+        offer: RTCSessionDescription,
+        slot: Slot,
+    ) -> Result<RTCSessionDescription, BoxError> {
+        let inner = &self.inner;
+        let console = Console::new(&inner.conn, inner.console).await?;
+        let input = Input::new(console.keyboard.clone(), console.mouse.clone());
+        let pc = Arc::new(inner.api.new_peer_connection(inner.config.clone()).await?);
+
+        let video = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.into(),
+                clock_rate: 90000,
+                ..Default::default()
+            },
+            "video".into(),
+            STREAM_ID.into(),
+        ));
+        drain_rtcp(
+            pc.add_track(video.clone() as Arc<dyn TrackLocal + Send + Sync>)
+                .await?,
+        );
+        let audio = match &inner.audio {
+            Some(audio) => {
+                let track = Arc::new(TrackLocalStaticSample::new(
+                    RTCRtpCodecCapability {
+                        mime_type: MIME_TYPE_PCMU.into(),
+                        clock_rate: audio::RATE,
+                        channels: 1,
+                        ..Default::default()
+                    },
+                    "audio".into(),
+                    STREAM_ID.into(),
+                ));
+                drain_rtcp(
+                    pc.add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>)
+                        .await?,
+                );
+                Some((track, audio.subscribe()))
+            }
+            None => None,
+        };
+
+        pc.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            if channel.label() == input::CHANNEL {
+                let input = input.clone();
+                channel.on_message(Box::new(move |message: DataChannelMessage| {
+                    let input = input.clone();
+                    Box::pin(async move {
+                        if let Err(e) = input.handle(&message.data).await {
+                            eprintln!("Failed to handle an input event: {}", e);
+                        }
+                    })
+                }));
+            }
+            Box::pin(async {})
+        }));
+
+        // the state handler keeps the connection and its slot, until it is closed
+        let peer: Arc<Peer> = Arc::new(Mutex::new(Some((pc.clone(), slot))));
+        let media = Arc::new(Mutex::new(Some((console, audio))));
+        let (stop, stopped) = watch::channel(false);
+        let encoder = inner.encoder.clone();
+        let runtime = tokio::runtime::Handle::current();
+        let (handler_peer, handler_media) = (peer.clone(), media.clone());
+        pc.on_peer_connection_state_change(Box::new(move |state| {
+            let (peer, media) = (&handler_peer, &handler_media);
+            match state {
+                RTCPeerConnectionState::Connected => {
+                    if let Some((console, audio)) = media.lock().unwrap().take() {
+                        let (runtime, track) = (runtime.clone(), video.clone());
+                        let encoder = encoder.clone();
+                        if let Some((track, packets)) = audio {
+                            tokio::spawn(stream_audio(track, packets, stopped.clone()));
+                        }
+                        let stopped = stopped.clone();
+                        // the console isn't Sync, its futures can't be spawned on the runtime
+                        thread::spawn(move || {
+                            let res =
+                                runtime.block_on(stream_video(console, encoder, track, stopped));
+                            if let Err(e) = res {
+                                eprintln!("Video stream failed: {}", e);
+                            }
+                        });
+                    }
+                }
+                RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+                    let _ = stop.send(true);
+                    close(peer);
+                }
+                _ => {}
+            }
+            Box::pin(async {})
+        }));
+
+        let answer = negotiate(&pc, offer).await;
+        // the slot of an offer which isn't answered, or of a peer which doesn't connect,
+        // is released
+        if answer.is_err() {
+            close(&peer);
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(CONNECT_TIMEOUT).await;
+                if media.lock().unwrap().is_some() {
+                    close(&peer);
+                }
+            });
+        }
+        answer
+    }
+}
+
+async fn negotiate(
+    pc: &RTCPeerConnection,
+    offer: RTCSessionDescription,
+) -> Result<RTCSessionDescription, BoxError> {
+    pc.set_remote_description(offer).await?;
+    let answer = pc.create_answer(None).await?;
+    let mut gathered = pc.gathering_complete_promise().await;
+    pc.set_local_description(answer).await?;
+    let _ = gathered.recv().await;
+    pc.local_description()
+        .await
+        .ok_or_else(|| "No local description".into())
+}
+
+// close the connection of a peer once, and release its slot
+fn close(peer: &Peer) {
+    if let Some((pc, slot)) = peer.lock().unwrap().take() {
+        tokio::spawn(async move {
+            let _ = pc.close().await;
+            drop(slot);
+        });
+    }
+}
+
+// the RTCP packets must be read, for the interceptors (NACK, reports)
+fn drain_rtcp(sender: Arc<RTCRtpSender>) {
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1500];
+        while sender.read(&mut buf).await.is_ok() {}
+    });
+}
+
+async fn stream_video(
+    console: Console,
+    config: EncoderConfig,
+    track: Arc<TrackLocalStaticSample>,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<(), BoxError> {
+    let mut video = console.encode(config.clone()).await?;
+    let duration = Duration::from_secs(1) / config.framerate;
+    let mut units = AccessUnits::default();
+    loop {
+        let event = tokio::select! {
+            event = video.next() => event,
+            _ = stopped.changed() => return Ok(()),
+        };
+        match event {
+            Some(Ok(EncodedEvent::Started { .. })) => units = AccessUnits::default(),
+            Some(Ok(EncodedEvent::Data(data))) => {
+                for unit in units.push(&data) {
+                    let sample = Sample {
+                        data: unit.into(),
+                        duration,
+                        ..Default::default()
+                    };
+                    track.write_sample(&sample).await?;
+                }
+            }
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(()),
+        }
+    }
+}
+
+async fn stream_audio(
+    track: Arc<TrackLocalStaticSample>,
+    mut packets: broadcast::Receiver<bytes::Bytes>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let duration = Duration::from_secs(1) * audio::PACKET_SAMPLES as u32 / audio::RATE;
+    loop {
+        let packet = tokio::select! {
+            packet = packets.recv() => packet,
+            _ = stopped.changed() => return,
+        };
+        match packet {
+            Ok(data) => {
+                let sample = Sample {
+                    data,
+                    duration,
+                    ..Default::default()
+                };
+                if track.write_sample(&sample).await.is_err() {
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}